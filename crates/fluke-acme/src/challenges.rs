@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{pki_types::PrivateKeyDer, sign::CertifiedKey};
+
+/// The ALPN protocol TLS-ALPN-01 validation connections use, see
+/// [is_acme_tls_alpn]
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Whether a connection was opened by a CA to validate a TLS-ALPN-01
+/// challenge, given the protocol ALPN settled on. Those connections must be
+/// closed right after the handshake, without serving anything (RFC 8737,
+/// section 3).
+pub fn is_acme_tls_alpn(alpn_protocol: Option<&[u8]>) -> bool {
+    alpn_protocol == Some(ACME_TLS_ALPN)
+}
+
+/// The challenges currently being validated. Cloning it gives another
+/// handle to the same challenges.
+///
+/// An [crate::Issuer] sets them when the CA hands them out, and removes
+/// them once they're validated. [crate::Http01] and [crate::AcmeResolver]
+/// answer them.
+#[derive(Clone, Default)]
+pub struct Challenges {
+    inner: Arc<Mutex<ChallengesInner>>,
+}
+
+#[derive(Default)]
+struct ChallengesInner {
+    /// token => key authorization
+    http01: HashMap<String, String>,
+
+    /// domain => self-signed certificate with the `acmeIdentifier`
+    /// extension
+    tls_alpn01: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for Challenges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("Challenges")
+            .field("http01", &inner.http01.len())
+            .field("tls_alpn01", &inner.tls_alpn01.len())
+            .finish()
+    }
+}
+
+impl Challenges {
+    /// Serves `key_authorization` at
+    /// `/.well-known/acme-challenge/{token}`
+    pub fn set_http01(&self, token: impl Into<String>, key_authorization: impl Into<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.http01.insert(token.into(), key_authorization.into());
+    }
+
+    pub fn remove_http01(&self, token: &str) {
+        self.inner.lock().unwrap().http01.remove(token);
+    }
+
+    /// Presents a validation certificate for `domain` to connections that
+    /// negotiate [ACME_TLS_ALPN]
+    pub fn set_tls_alpn01(&self, domain: &str, key_authorization: &str) -> eyre::Result<()> {
+        let cert = tls_alpn01_cert(domain, key_authorization)?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.tls_alpn01.insert(domain.to_ascii_lowercase(), cert);
+        Ok(())
+    }
+
+    pub fn remove_tls_alpn01(&self, domain: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tls_alpn01.remove(&domain.to_ascii_lowercase());
+    }
+
+    pub(crate) fn http01(&self, token: &str) -> Option<String> {
+        self.inner.lock().unwrap().http01.get(token).cloned()
+    }
+
+    pub(crate) fn tls_alpn01(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        let inner = self.inner.lock().unwrap();
+        inner.tls_alpn01.get(&domain.to_ascii_lowercase()).cloned()
+    }
+}
+
+/// A self-signed certificate for `domain`, carrying the SHA-256 digest of
+/// the key authorization in its `acmeIdentifier` extension (RFC 8737,
+/// section 3)
+fn tls_alpn01_cert(domain: &str, key_authorization: &str) -> eyre::Result<Arc<CertifiedKey>> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_owned()]);
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest.as_ref())];
+    let cert = rcgen::Certificate::from_params(params)?;
+
+    let der = cert.serialize_der()?;
+    let key = PrivateKeyDer::Pkcs8(cert.serialize_private_key_der().into());
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+    Ok(Arc::new(CertifiedKey::new(vec![der.into()], key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_acme_tls_alpn, Challenges, ACME_TLS_ALPN};
+
+    #[test]
+    fn challenges() {
+        let challenges = Challenges::default();
+        challenges.set_http01("token", "token.thumbprint");
+        assert_eq!(
+            challenges.clone().http01("token").as_deref(),
+            Some("token.thumbprint")
+        );
+        challenges.remove_http01("token");
+        assert_eq!(challenges.http01("token"), None);
+
+        challenges
+            .set_tls_alpn01("Example.org", "token.thumbprint")
+            .unwrap();
+        assert!(challenges.tls_alpn01("example.org").is_some());
+        assert!(challenges.tls_alpn01("example.net").is_none());
+
+        assert!(is_acme_tls_alpn(Some(ACME_TLS_ALPN)));
+        assert!(!is_acme_tls_alpn(Some(b"h2")));
+        assert!(!is_acme_tls_alpn(None));
+    }
+}
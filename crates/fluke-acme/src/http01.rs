@@ -0,0 +1,76 @@
+use fluke::{
+    h2, http::header, Body, CloseReason, ConnInfo, Encoder, ExpectResponseHeaders, FieldDecision,
+    HeaderDecision, Request, Responder, Response, ResponseDone, ServerDriver,
+};
+
+use crate::Challenges;
+
+/// Where HTTP-01 challenges are served from
+pub const HTTP01_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Answers HTTP-01 challenges, and passes every other request on to the
+/// driver it wraps. Challenges are answered from
+/// [ServerDriver::on_headers], so the inner driver never sees them.
+///
+/// HTTP-01 validation happens over plaintext HTTP, on port 80: this goes
+/// around the driver of that listener, even if all it does is redirect to
+/// HTTPS.
+pub struct Http01<D> {
+    inner: D,
+    challenges: Challenges,
+}
+
+impl<D> Http01<D> {
+    pub fn new(inner: D, challenges: Challenges) -> Self {
+        Self { inner, challenges }
+    }
+}
+
+impl<D> ServerDriver for Http01<D>
+where
+    D: ServerDriver,
+{
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        self.inner.handle(req, req_body, respond).await
+    }
+
+    fn on_headers(&self, req: &Request) -> HeaderDecision {
+        let Some(token) = req.uri.path().strip_prefix(HTTP01_PREFIX) else {
+            return self.inner.on_headers(req);
+        };
+
+        match self.challenges.http01(token) {
+            Some(key_authorization) => {
+                let mut res = Response::default();
+                res.headers
+                    .insert(header::CONTENT_TYPE, "application/octet-stream".into());
+                HeaderDecision::Respond {
+                    res,
+                    body: key_authorization.into_bytes().into(),
+                }
+            }
+            None => HeaderDecision::reject(fluke::http::StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn on_header_field(&self, name: &fluke::http::HeaderName, value: &[u8]) -> FieldDecision {
+        self.inner.on_header_field(name, value)
+    }
+
+    fn on_h2_connection(&self, frames: h2::ExtensionFrames) {
+        self.inner.on_h2_connection(frames)
+    }
+
+    fn on_extension_frame(&self, frame: h2::ExtensionFrame, frames: &h2::ExtensionFrames) {
+        self.inner.on_extension_frame(frame, frames)
+    }
+
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        self.inner.on_connection_closed(conn, reason)
+    }
+}
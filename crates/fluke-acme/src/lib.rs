@@ -0,0 +1,213 @@
+//! Provisions TLS certificates for fluke servers with ACME
+//! (<https://www.rfc-editor.org/rfc/rfc8555>).
+//!
+//! An [Acme] looks after one certificate (which may cover several domains):
+//! it loads it from a [CertStore] on startup, orders a new one through an
+//! [Issuer] when there's none or it's about to expire, saves it, and serves
+//! it through an [AcmeResolver]. Renewals happen on a timer, on the buffet
+//! runtime.
+//!
+//! ```no_run
+//! # async fn f(issuer: impl fluke_acme::Issuer + 'static, driver: impl fluke::ServerDriver + 'static) -> eyre::Result<()> {
+//! use std::sync::Arc;
+//!
+//! use fluke_acme::{Acme, DirStore, Http01, ACME_TLS_ALPN};
+//!
+//! let acme = Acme::new(vec!["example.org".into()], DirStore::new("/var/lib/certs"), issuer);
+//!
+//! // TLS-ALPN-01 challenges are answered by the resolver...
+//! let mut tls = rustls::ServerConfig::builder()
+//!     .with_no_client_auth()
+//!     .with_cert_resolver(Arc::new(acme.resolver()));
+//! tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+//!
+//! // ...and HTTP-01 challenges by a driver, on port 80
+//! let _server = fluke::ServerBuilder::new("[::]:80".parse().unwrap())
+//!     .build(Http01::new(driver, acme.challenges()))
+//!     .await?;
+//!
+//! acme.spawn();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Talking to the CA (accounts, orders, authorizations, finalization) is
+//! left to the [Issuer], typically built on an ACME client library: this
+//! crate provides everything around it.
+
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+
+mod challenges;
+pub use challenges::*;
+
+mod http01;
+pub use http01::*;
+
+mod resolver;
+pub use resolver::*;
+
+mod store;
+pub use store::*;
+
+/// Gets certificates issued by a CA
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait Issuer {
+    /// Orders a certificate for `domains`, and waits for it to be issued.
+    ///
+    /// Authorizations are answered by setting the challenges the CA hands
+    /// out on `challenges` (HTTP-01 or TLS-ALPN-01, whichever the server
+    /// is set up to answer) before telling the CA they're ready, and
+    /// removing them once they're validated.
+    async fn issue(&self, domains: &[String], challenges: &Challenges) -> eyre::Result<IssuedCert>;
+}
+
+/// Looks after a certificate for some domains, see the [crate docs](crate)
+pub struct Acme<S, I> {
+    domains: Vec<String>,
+    store: S,
+    issuer: I,
+    challenges: Challenges,
+    certs: Certs,
+    renew_before: Duration,
+    retry_after: Duration,
+}
+
+impl<S, I> Acme<S, I>
+where
+    S: CertStore + 'static,
+    I: Issuer + 'static,
+{
+    pub fn new(domains: Vec<String>, store: S, issuer: I) -> Self {
+        Self {
+            domains,
+            store,
+            issuer,
+            challenges: Default::default(),
+            certs: Default::default(),
+            renew_before: Duration::from_secs(30 * 24 * 60 * 60),
+            retry_after: Duration::from_secs(60 * 60),
+        }
+    }
+
+    /// How long before the certificate expires to renew it. Defaults to 30
+    /// days, which is a third of a Let's Encrypt certificate's lifetime.
+    pub fn renew_before(mut self, renew_before: Duration) -> Self {
+        self.renew_before = renew_before;
+        self
+    }
+
+    /// How long to wait before trying again when ordering a certificate
+    /// fails. Defaults to an hour.
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// The challenges to answer, for [Http01]
+    pub fn challenges(&self) -> Challenges {
+        self.challenges.clone()
+    }
+
+    /// The certificates issued so far. Several [Acme]s may share them, see
+    /// [Acme::with_certs].
+    pub fn certs(&self) -> Certs {
+        self.certs.clone()
+    }
+
+    /// Serves the certificate alongside those of other [Acme]s, so that a
+    /// single [AcmeResolver] covers them all. They should share
+    /// [Challenges] too, see [Acme::with_challenges].
+    pub fn with_certs(mut self, certs: Certs) -> Self {
+        self.certs = certs;
+        self
+    }
+
+    /// Answers challenges alongside other [Acme]s
+    pub fn with_challenges(mut self, challenges: Challenges) -> Self {
+        self.challenges = challenges;
+        self
+    }
+
+    /// A certificate resolver for the TLS acceptor, see [AcmeResolver]
+    pub fn resolver(&self) -> AcmeResolver {
+        AcmeResolver::new(self.challenges(), self.certs())
+    }
+
+    /// Runs [Acme::run] in the background
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        fluke::buffet::spawn(self.run())
+    }
+
+    /// Keeps the certificate issued and fresh, forever
+    pub async fn run(self) {
+        let mut current = match self.store.load(&self.domains).await {
+            Ok(cert) => cert,
+            Err(e) => {
+                warn!(domains = ?self.domains, "could not load certificate: {e}");
+                None
+            }
+        };
+
+        loop {
+            if let Some(cert) = &current {
+                match cert.certified_key() {
+                    Ok(key) => self.certs.insert(&self.domains, key),
+                    Err(e) => warn!(domains = ?self.domains, "unusable certificate: {e}"),
+                }
+            }
+
+            let renew_in = match &current {
+                Some(cert) => renew_in(cert.not_after, SystemTime::now(), self.renew_before),
+                None => Duration::ZERO,
+            };
+            if !renew_in.is_zero() {
+                info!(domains = ?self.domains, "renewing certificate in {renew_in:?}");
+                tokio::time::sleep(renew_in).await;
+            }
+
+            match self.issuer.issue(&self.domains, &self.challenges).await {
+                Ok(cert) => {
+                    info!(domains = ?self.domains, not_after = ?cert.not_after, "certificate issued");
+                    if let Err(e) = self.store.save(&self.domains, &cert).await {
+                        warn!(domains = ?self.domains, "could not save certificate: {e}");
+                    }
+                    current = Some(cert);
+                }
+                Err(e) => {
+                    warn!(
+                        domains = ?self.domains,
+                        "could not get a certificate, retrying in {:?}: {e}",
+                        self.retry_after
+                    );
+                    tokio::time::sleep(self.retry_after).await;
+                }
+            }
+        }
+    }
+}
+
+/// How long until a certificate that expires at `not_after` needs renewing
+fn renew_in(not_after: SystemTime, now: SystemTime, renew_before: Duration) -> Duration {
+    let renew_at = not_after.checked_sub(renew_before).unwrap_or(not_after);
+    renew_at.duration_since(now).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::renew_in;
+
+    #[test]
+    fn renewal_timing() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = UNIX_EPOCH + 1000 * day;
+
+        assert_eq!(renew_in(now + 90 * day, now, 30 * day), 60 * day);
+        // due, or past due
+        assert_eq!(renew_in(now + 30 * day, now, 30 * day), Duration::ZERO);
+        assert_eq!(renew_in(now - day, now, 30 * day), Duration::ZERO);
+    }
+}
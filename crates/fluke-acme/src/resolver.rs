@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use crate::{Challenges, ACME_TLS_ALPN};
+
+/// The certificates issued so far, by domain. Cloning it gives another
+/// handle to the same certificates.
+#[derive(Clone, Default)]
+pub struct Certs {
+    inner: Arc<RwLock<HashMap<String, Arc<CertifiedKey>>>>,
+}
+
+impl fmt::Debug for Certs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.read().unwrap();
+        f.debug_set().entries(inner.keys()).finish()
+    }
+}
+
+impl Certs {
+    /// Serves `cert` for all of `domains`, replacing whatever was served
+    /// for them before
+    pub fn insert(&self, domains: &[String], cert: Arc<CertifiedKey>) {
+        let mut inner = self.inner.write().unwrap();
+        for domain in domains {
+            inner.insert(domain.to_ascii_lowercase(), cert.clone());
+        }
+    }
+
+    /// The certificate for `server_name`, issued either for it or for a
+    /// wildcard covering it
+    pub fn get(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+        let inner = self.inner.read().unwrap();
+        if let Some(cert) = inner.get(&server_name) {
+            return Some(cert.clone());
+        }
+        let (_label, parent) = server_name.split_once('.')?;
+        inner.get(&format!("*.{parent}")).cloned()
+    }
+}
+
+/// Picks the certificate for TLS handshakes: validation certificates for
+/// TLS-ALPN-01 challenges, the issued certificate for the server name
+/// otherwise, falling back to another resolver for server names ACME
+/// doesn't know about.
+///
+/// The acceptor must offer [ACME_TLS_ALPN] in its ALPN protocols for
+/// TLS-ALPN-01 validation to work, and close connections that negotiate it
+/// right after the handshake, see [crate::is_acme_tls_alpn].
+pub struct AcmeResolver {
+    challenges: Challenges,
+    certs: Certs,
+    fallback: Option<Arc<dyn ResolvesServerCert>>,
+}
+
+impl AcmeResolver {
+    pub fn new(challenges: Challenges, certs: Certs) -> Self {
+        Self {
+            challenges,
+            certs,
+            fallback: None,
+        }
+    }
+
+    /// Resolves handshakes for server names that have no issued
+    /// certificate (yet), or no server name at all
+    pub fn with_fallback(mut self, fallback: Arc<dyn ResolvesServerCert>) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+}
+
+impl fmt::Debug for AcmeResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AcmeResolver")
+            .field("challenges", &self.challenges)
+            .field("certs", &self.certs)
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for AcmeResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello.server_name().map(|name| name.to_owned());
+
+        // validation connections offer that protocol and nothing else
+        let validating = client_hello.alpn().map_or(false, |mut protocols| {
+            protocols.next() == Some(ACME_TLS_ALPN) && protocols.next().is_none()
+        });
+        if validating {
+            return self.challenges.tls_alpn01(server_name.as_deref()?);
+        }
+
+        if let Some(cert) = server_name.as_deref().and_then(|name| self.certs.get(name)) {
+            return Some(cert);
+        }
+        self.fallback.as_ref()?.resolve(client_hello)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{pki_types::PrivateKeyDer, sign::CertifiedKey};
+
+    use super::Certs;
+
+    fn cert(name: &str) -> Arc<CertifiedKey> {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(cert.serialize_private_key_der().into());
+        let key = rustls::crypto::ring::sign::any_supported_type(&key).unwrap();
+        Arc::new(CertifiedKey::new(
+            vec![cert.serialize_der().unwrap().into()],
+            key,
+        ))
+    }
+
+    #[test]
+    fn certs() {
+        let certs = Certs::default();
+        let exact = cert("example.org");
+        let wildcard = cert("*.example.org");
+        certs.insert(&["Example.org".to_owned()], exact.clone());
+        certs
+            .clone()
+            .insert(&["*.example.org".to_owned()], wildcard.clone());
+
+        assert!(Arc::ptr_eq(&certs.get("example.ORG").unwrap(), &exact));
+        assert!(Arc::ptr_eq(
+            &certs.get("www.example.org").unwrap(),
+            &wildcard
+        ));
+        assert!(certs.get("a.www.example.org").is_none());
+        assert!(certs.get("example.net").is_none());
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    sign::CertifiedKey,
+};
+use tokio::io::AsyncWriteExt;
+
+/// A certificate the CA issued, and its key
+#[derive(Clone)]
+pub struct IssuedCert {
+    /// DER-encoded, end-entity certificate first
+    pub chain: Vec<Vec<u8>>,
+
+    /// DER-encoded, PKCS#8
+    pub key: Vec<u8>,
+
+    /// When the end-entity certificate expires
+    pub not_after: SystemTime,
+}
+
+impl fmt::Debug for IssuedCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never the key
+        f.debug_struct("IssuedCert")
+            .field("chain", &self.chain.len())
+            .field("not_after", &self.not_after)
+            .finish()
+    }
+}
+
+impl IssuedCert {
+    pub(crate) fn certified_key(&self) -> eyre::Result<Arc<CertifiedKey>> {
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key.clone()));
+        let key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+        let chain = self
+            .chain
+            .iter()
+            .map(|der| CertificateDer::from(der.clone()))
+            .collect();
+        Ok(Arc::new(CertifiedKey::new(chain, key)))
+    }
+
+    /// `u64` expiry (seconds since the epoch), then the key, then each
+    /// certificate, all prefixed with their `u32` length, big-endian
+    fn encode(&self) -> Vec<u8> {
+        let not_after = self
+            .not_after
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut out = not_after.to_be_bytes().to_vec();
+        for item in std::iter::once(&self.key).chain(&self.chain) {
+            out.extend_from_slice(&(item.len() as u32).to_be_bytes());
+            out.extend_from_slice(item);
+        }
+        out
+    }
+
+    fn decode(mut input: &[u8]) -> eyre::Result<Self> {
+        fn take<'a>(input: &mut &'a [u8], n: usize) -> eyre::Result<&'a [u8]> {
+            if input.len() < n {
+                return Err(eyre::eyre!("truncated certificate file"));
+            }
+            let (head, rest) = input.split_at(n);
+            *input = rest;
+            Ok(head)
+        }
+
+        let not_after = u64::from_be_bytes(take(&mut input, 8)?.try_into().unwrap());
+        let mut items = vec![];
+        while !input.is_empty() {
+            let len = u32::from_be_bytes(take(&mut input, 4)?.try_into().unwrap());
+            items.push(take(&mut input, len as usize)?.to_vec());
+        }
+        if items.len() < 2 {
+            return Err(eyre::eyre!("certificate file has no certificate"));
+        }
+        let key = items.remove(0);
+
+        Ok(Self {
+            chain: items,
+            key,
+            not_after: UNIX_EPOCH + Duration::from_secs(not_after),
+        })
+    }
+}
+
+/// Where issued certificates are kept, so that restarts don't order new
+/// ones (CAs rate-limit orders). Certificates are keyed by the domains
+/// they're for.
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait CertStore {
+    /// The certificate last saved for `domains`, if any
+    async fn load(&self, domains: &[String]) -> eyre::Result<Option<IssuedCert>>;
+
+    async fn save(&self, domains: &[String], cert: &IssuedCert) -> eyre::Result<()>;
+}
+
+/// Keeps certificates in memory only, for tests
+#[derive(Default)]
+pub struct MemoryStore {
+    certs: RefCell<HashMap<Vec<String>, IssuedCert>>,
+}
+
+impl CertStore for MemoryStore {
+    async fn load(&self, domains: &[String]) -> eyre::Result<Option<IssuedCert>> {
+        Ok(self.certs.borrow().get(domains).cloned())
+    }
+
+    async fn save(&self, domains: &[String], cert: &IssuedCert) -> eyre::Result<()> {
+        self.certs
+            .borrow_mut()
+            .insert(domains.to_vec(), cert.clone());
+        Ok(())
+    }
+}
+
+/// Keeps each certificate in a file of its own, in a directory, named after
+/// one of the domains it's for and a hash of the whole set, so that
+/// certificates for overlapping sets don't replace each other. The files
+/// hold private keys: they're only readable by their owner.
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, domains: &[String]) -> eyre::Result<PathBuf> {
+        // the same set in another order is the same certificate
+        let mut set: Vec<String> = domains.iter().map(|d| d.to_ascii_lowercase()).collect();
+        set.sort();
+        set.dedup();
+        let Some(first) = set.first() else {
+            return Err(eyre::eyre!("no domains"));
+        };
+
+        // `*.example.org` => `_.example.org`
+        let name: String = first
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' | '-' | '.' => c,
+                _ => '_',
+            })
+            .collect();
+        let digest = ring::digest::digest(&ring::digest::SHA256, set.join("\n").as_bytes());
+        let hash: String = digest.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        Ok(self.dir.join(format!("{name}-{hash}.cert")))
+    }
+}
+
+impl CertStore for DirStore {
+    async fn load(&self, domains: &[String]) -> eyre::Result<Option<IssuedCert>> {
+        match tokio::fs::read(self.path(domains)?).await {
+            Ok(contents) => Ok(Some(IssuedCert::decode(&contents)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save(&self, domains: &[String], cert: &IssuedCert) -> eyre::Result<()> {
+        let path = self.path(domains)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        // never leave a half-written file behind
+        let tmp = path.with_extension("cert.tmp");
+        // a leftover from a crash may have been created with other
+        // permissions: the mode only applies to new files
+        match tokio::fs::remove_file(&tmp).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .await?;
+        file.write_all(&cert.encode()).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{DirStore, IssuedCert};
+
+    #[test]
+    fn encoding() {
+        let cert = IssuedCert {
+            chain: vec![b"leaf".to_vec(), b"intermediate".to_vec()],
+            key: b"key".to_vec(),
+            not_after: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        let decoded = IssuedCert::decode(&cert.encode()).unwrap();
+        assert_eq!(decoded.chain, cert.chain);
+        assert_eq!(decoded.key, cert.key);
+        assert_eq!(decoded.not_after, cert.not_after);
+
+        let encoded = cert.encode();
+        assert!(IssuedCert::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(IssuedCert::decode(&encoded[..8]).is_err());
+    }
+
+    #[test]
+    fn dir_store_keys_on_the_whole_set() {
+        let store = DirStore::new("/certs");
+        let path = |domains: &[&str]| {
+            let domains: Vec<String> = domains.iter().map(|d| d.to_string()).collect();
+            store.path(&domains).unwrap()
+        };
+
+        assert_ne!(
+            path(&["example.org"]),
+            path(&["example.org", "www.example.org"])
+        );
+        assert_eq!(
+            path(&["example.org", "www.example.org"]),
+            path(&["www.example.org", "Example.org"])
+        );
+        assert!(path(&["*.example.org"])
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("_.example.org-"));
+        assert!(store.path(&[]).is_err());
+    }
+}
@@ -1,11 +1,14 @@
 use std::{
-    cell::{RefCell, RefMut},
+    cell::{Cell, RefCell, RefMut},
     collections::VecDeque,
     marker::PhantomData,
     ops::{self, Bound, RangeBounds},
+    rc::Rc,
+    time::Duration,
 };
 
 use memmap2::MmapMut;
+use tokio::sync::Notify;
 
 pub type BufResult<T, B> = (std::io::Result<T>, B);
 
@@ -20,6 +23,10 @@ pub const NUM_BUF: u32 = 64;
 thread_local! {
     pub static BUF_POOL: BufPool = const { BufPool::new_empty(BUF_SIZE, NUM_BUF) };
     static BUF_POOL_DESTRUCTOR: RefCell<Option<MmapMut>> = const { RefCell::new(None) };
+
+    /// Notified when a block goes back to the pool while someone is waiting
+    /// for that, see [BufMut::alloc_wait] and [block_freed]
+    static BUF_POOL_FREED: Rc<Notify> = Rc::new(Notify::new());
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -41,6 +48,109 @@ pub(crate) struct BufPool {
     buf_size: u16,
     num_buf: u32,
     inner: RefCell<Option<BufPoolInner>>,
+
+    /// see [set_exhaustion_wait]
+    wait: Cell<Option<Duration>>,
+
+    /// allocations (or [block_freed] calls) currently waiting for a block
+    waiters: Cell<u32>,
+
+    /// requested with [set_huge_pages], then what we actually got once the
+    /// pool is mapped
+    huge_pages: Cell<HugePages>,
+
+    exhausted: Cell<u64>,
+    waited: Cell<u64>,
+    timed_out: Cell<u64>,
+}
+
+/// Buffer pool counters for the current thread, see [pool_stats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// How many blocks the pool has
+    pub capacity: u32,
+
+    /// How many blocks are currently handed out
+    pub in_use: u32,
+
+    /// How many times an allocation found the pool empty
+    pub exhausted: u64,
+
+    /// How many allocations waited for a block and got one, see
+    /// [set_exhaustion_wait]
+    pub waited: u64,
+
+    /// How many allocations waited for a block and gave up
+    pub timed_out: u64,
+
+    /// What backs the pool: what [set_huge_pages] asked for, unless the
+    /// system couldn't provide it
+    pub huge_pages: HugePages,
+}
+
+/// What kind of pages back the buffer pool, see [set_huge_pages]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HugePages {
+    /// Regular pages
+    #[default]
+    Off,
+
+    /// Regular pages, with a hint that the kernel should back them with
+    /// transparent huge pages (`madvise(MADV_HUGEPAGE)`). Only has an effect
+    /// if transparent huge pages are enabled in `madvise` or `always` mode.
+    Transparent,
+
+    /// Pages from the pre-allocated huge page pool (`MAP_HUGETLB`). Needs
+    /// `vm.nr_hugepages` to leave room for the whole buffer pool, otherwise
+    /// this falls back to [HugePages::Transparent].
+    Explicit,
+}
+
+/// Returns the buffer pool counters for the current thread (each thread
+/// running [crate::start] has its own pool)
+pub fn pool_stats() -> PoolStats {
+    BUF_POOL.with(|bp| PoolStats {
+        capacity: bp.num_buf,
+        in_use: bp
+            .inner
+            .borrow()
+            .as_ref()
+            .map_or(0, |inner| bp.num_buf - inner.free.len() as u32),
+        exhausted: bp.exhausted.get(),
+        waited: bp.waited.get(),
+        timed_out: bp.timed_out.get(),
+        huge_pages: bp.huge_pages.get(),
+    })
+}
+
+/// Asks for the buffer pool of the current thread to be backed by huge
+/// pages, which cuts down on TLB misses for large pools. Linux only: this
+/// does nothing elsewhere.
+///
+/// The pool is mapped the first time a buffer is allocated, so this must
+/// be called before that: first thing in the future given to
+/// [crate::start]. Returns false (and changes nothing) if it's too late.
+/// Whether the system honored it shows in [PoolStats::huge_pages].
+pub fn set_huge_pages(huge_pages: HugePages) -> bool {
+    BUF_POOL.with(|bp| {
+        if bp.inner.borrow().is_some() {
+            return false;
+        }
+        bp.huge_pages.set(huge_pages);
+        true
+    })
+}
+
+/// Sets how long allocations that can wait ([BufMut::alloc_wait],
+/// [crate::RollMut::reserve_wait], etc.) wait for a block to be freed when
+/// the pool is exhausted, on the current thread. With `None`, the default,
+/// they fail right away with [Error::OutOfMemory], like the others.
+///
+/// Waiting applies backpressure (the connection stops reading until memory
+/// frees up) rather than failing the request, which is what transient bursts
+/// call for.
+pub fn set_exhaustion_wait(wait: Option<Duration>) {
+    BUF_POOL.with(|bp| bp.wait.set(wait));
 }
 
 struct BufPoolInner {
@@ -53,6 +163,9 @@ struct BufPoolInner {
 
     // ref counts start as all zeroes, get incremented when a block is borrowed
     ref_counts: Vec<i16>,
+
+    // bumped every time a block goes back to the pool, see [BlockId]
+    generations: Vec<u32>,
 }
 
 impl BufPool {
@@ -61,23 +174,37 @@ impl BufPool {
             buf_size,
             num_buf,
             inner: RefCell::new(None),
+            wait: Cell::new(None),
+            waiters: Cell::new(0),
+            huge_pages: Cell::new(HugePages::Off),
+            exhausted: Cell::new(0),
+            waited: Cell::new(0),
+            timed_out: Cell::new(0),
         }
     }
 
     pub(crate) fn alloc(&self) -> Result<BufMut> {
+        match self.try_alloc()? {
+            Some(buf) => Ok(buf),
+            None => {
+                self.exhausted.set(self.exhausted.get() + 1);
+                Err(Error::OutOfMemory)
+            }
+        }
+    }
+
+    fn try_alloc(&self) -> Result<Option<BufMut>> {
         let mut inner = self.borrow_mut()?;
 
-        if let Some(index) = inner.free.pop_front() {
+        Ok(inner.free.pop_front().map(|index| {
             inner.ref_counts[index as usize] += 1;
-            Ok(BufMut {
+            BufMut {
                 index,
                 off: 0,
                 len: self.buf_size as _,
                 _non_send: PhantomData,
-            })
-        } else {
-            Err(Error::OutOfMemory)
-        }
+            }
+        }))
     }
 
     fn inc(&self, index: u32) {
@@ -94,6 +221,11 @@ impl BufPool {
         inner.ref_counts[index as usize] -= 1;
         if inner.ref_counts[index as usize] == 0 {
             inner.free.push_back(index);
+            inner.generations[index as usize] = inner.generations[index as usize].wrapping_add(1);
+            if self.waiters.get() > 0 {
+                // blocks can get freed while thread-locals are torn down
+                _ = BUF_POOL_FREED.try_with(|freed| freed.notify_waiters());
+            }
         }
     }
 
@@ -118,7 +250,7 @@ impl BufPool {
 
             #[cfg(not(feature = "miri"))]
             {
-                let mut map = memmap2::MmapOptions::new().len(len).map_anon()?;
+                let mut map = self.map(len)?;
                 ptr = map.as_mut_ptr();
                 BUF_POOL_DESTRUCTOR.with(|destructor| {
                     *destructor.borrow_mut() = Some(map);
@@ -130,11 +262,13 @@ impl BufPool {
                 free.push_back(i);
             }
             let ref_counts = vec![0; self.num_buf as usize];
+            let generations = vec![0; self.num_buf as usize];
 
             *inner = Some(BufPoolInner {
                 ptr,
                 free,
                 ref_counts,
+                generations,
             });
         }
 
@@ -142,6 +276,44 @@ impl BufPool {
         Ok(r)
     }
 
+    /// Maps the pool's memory, with huge pages if they were asked for and
+    /// the system has them. Records what it got in `self.huge_pages`.
+    #[cfg(not(feature = "miri"))]
+    fn map(&self, len: usize) -> std::io::Result<MmapMut> {
+        #[cfg(target_os = "linux")]
+        {
+            let mut huge_pages = self.huge_pages.get();
+            if huge_pages == HugePages::Explicit {
+                match memmap2::MmapOptions::new().len(len).huge(None).map_anon() {
+                    Ok(map) => return Ok(map),
+                    Err(e) => {
+                        tracing::warn!(
+                            "could not map buffer pool with explicit huge pages ({e}), \
+                             falling back to transparent huge pages"
+                        );
+                        huge_pages = HugePages::Transparent;
+                    }
+                }
+            }
+
+            let map = memmap2::MmapOptions::new().len(len).map_anon()?;
+            if huge_pages == HugePages::Transparent {
+                if let Err(e) = map.advise(memmap2::Advice::HugePage) {
+                    tracing::warn!("transparent huge pages unavailable for buffer pool ({e})");
+                    huge_pages = HugePages::Off;
+                }
+            }
+            self.huge_pages.set(huge_pages);
+            Ok(map)
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            self.huge_pages.set(HugePages::Off);
+            memmap2::MmapOptions::new().len(len).map_anon()
+        }
+    }
+
     /// Returns the base pointer for a block
     ///
     /// # Safety
@@ -154,6 +326,60 @@ impl BufPool {
     }
 }
 
+/// Counts towards [BufPool::waiters] while alive
+struct Waiting;
+
+impl Waiting {
+    fn new() -> Self {
+        BUF_POOL.with(|bp| bp.waiters.set(bp.waiters.get() + 1));
+        Waiting
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        _ = BUF_POOL.try_with(|bp| bp.waiters.set(bp.waiters.get() - 1));
+    }
+}
+
+/// Identifies a block of the pool for as long as it's in use: once the last
+/// buffer pointing into it is dropped, the block may get handed out again,
+/// but under a different id. Lets callers keep track of which blocks they
+/// lent out, without keeping them alive. See [Buf::block].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockId {
+    index: u32,
+    generation: u32,
+}
+
+impl BlockId {
+    /// Whether some buffer still points into this block
+    pub fn is_live(&self) -> bool {
+        BUF_POOL.with(|bp| {
+            bp.inner.borrow().as_ref().is_some_and(|inner| {
+                inner.generations[self.index as usize] == self.generation
+                    && inner.ref_counts[self.index as usize] > 0
+            })
+        })
+    }
+
+    fn of(index: u32) -> Self {
+        BUF_POOL.with(|bp| BlockId {
+            index,
+            generation: bp.inner.borrow().as_ref().unwrap().generations[index as usize],
+        })
+    }
+}
+
+/// Waits until some block goes back to the pool (any block, not a
+/// particular one: check the ones you care about with
+/// [BlockId::is_live] afterwards).
+pub async fn block_freed() {
+    let _waiting = Waiting::new();
+    let freed = BUF_POOL_FREED.with(|freed| freed.clone());
+    freed.notified().await;
+}
+
 /// A mutable buffer. Cannot be cloned, but can be written to
 pub struct BufMut {
     pub(crate) index: u32,
@@ -170,6 +396,36 @@ impl BufMut {
         BUF_POOL.with(|bp| bp.alloc())
     }
 
+    /// Like [BufMut::alloc], but if the pool is exhausted, waits for a block
+    /// to be freed, for as long as [set_exhaustion_wait] allows.
+    pub async fn alloc_wait() -> Result<BufMut, Error> {
+        let Some(wait) = BUF_POOL.with(|bp| bp.wait.get()) else {
+            return Self::alloc();
+        };
+        if let Some(buf) = BUF_POOL.with(|bp| bp.try_alloc())? {
+            return Ok(buf);
+        }
+
+        BUF_POOL.with(|bp| bp.exhausted.set(bp.exhausted.get() + 1));
+        let _waiting = Waiting::new();
+
+        let freed = BUF_POOL_FREED.with(|freed| freed.clone());
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            // created before trying, so that a block freed in between
+            // isn't missed
+            let notified = freed.notified();
+            if let Some(buf) = BUF_POOL.with(|bp| bp.try_alloc())? {
+                BUF_POOL.with(|bp| bp.waited.set(bp.waited.get() + 1));
+                return Ok(buf);
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                BUF_POOL.with(|bp| bp.timed_out.set(bp.timed_out.get() + 1));
+                return Err(Error::OutOfMemory);
+            }
+        }
+    }
+
     #[inline(always)]
     pub fn len(&self) -> usize {
         self.len as _
@@ -294,7 +550,10 @@ mod iobufmut {
 /// If the address returned by `io_buf_mut_stable_mut_ptr` is not actually stable
 /// and moves while an io_uring operation is in-flight, the kernel might write
 /// to the wrong memory location.
-pub unsafe trait IoBufMut: iobufmut::Sealed {
+///
+/// Buffers are `'static`: an operation that's given up on keeps its buffer
+/// around until the kernel is done with it.
+pub unsafe trait IoBufMut: iobufmut::Sealed + Unpin + 'static {
     /// Gets a pointer to the start of the buffer
     fn io_buf_mut_stable_mut_ptr(&mut self) -> *mut u8;
 
@@ -353,6 +612,11 @@ impl Buf {
         self.len as _
     }
 
+    /// The pool block this buffer points into
+    pub fn block(&self) -> BlockId {
+        BlockId::of(self.index)
+    }
+
     #[inline(always)]
     pub fn is_empty(&self) -> bool {
         self.len == 0
@@ -517,4 +781,88 @@ mod tests {
 
         Ok(())
     }
+    #[test]
+    #[cfg(not(feature = "miri"))]
+    fn exhaustion_wait() {
+        use crate::bufpool::{pool_stats, set_exhaustion_wait};
+        use std::time::Duration;
+
+        crate::start(async move {
+            let mut held = Vec::new();
+            while let Ok(bm) = BufMut::alloc() {
+                held.push(bm);
+            }
+            let stats = pool_stats();
+            assert_eq!(stats.in_use, stats.capacity);
+            assert_eq!(stats.exhausted, 1);
+
+            // without a wait, it fails right away
+            assert!(BufMut::alloc_wait().await.is_err());
+
+            set_exhaustion_wait(Some(Duration::from_millis(20)));
+            assert!(BufMut::alloc_wait().await.is_err());
+            assert_eq!(pool_stats().timed_out, 1);
+
+            set_exhaustion_wait(Some(Duration::from_secs(5)));
+            crate::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                drop(held.pop());
+                held
+            });
+            BufMut::alloc_wait().await.unwrap();
+
+            let stats = pool_stats();
+            assert_eq!(stats.waited, 1);
+            assert_eq!(stats.exhausted, 4);
+        });
+    }
+    #[test]
+    #[cfg(not(feature = "miri"))]
+    fn huge_pages() {
+        use crate::bufpool::{pool_stats, set_huge_pages, HugePages};
+
+        // each test gets its own thread, and so its own pool: this one
+        // hasn't been mapped yet
+        assert!(set_huge_pages(HugePages::Explicit));
+        let bm = BufMut::alloc().unwrap();
+
+        // whatever the system gave us, the pool works
+        let huge_pages = pool_stats().huge_pages;
+        if !cfg!(target_os = "linux") {
+            assert_eq!(huge_pages, HugePages::Off);
+        }
+        assert!(!set_huge_pages(HugePages::Off));
+        assert_eq!(pool_stats().huge_pages, huge_pages);
+        assert_eq!(bm.len(), 4096);
+    }
+
+    #[test]
+    fn block_ids() {
+        use crate::bufpool::block_freed;
+        use std::time::Duration;
+
+        crate::start(async move {
+            let buf = BufMut::alloc().unwrap().freeze();
+            let block = buf.block();
+            let (left, right) = buf.split_at(10);
+            assert_eq!(left.block(), block);
+            drop(left);
+            assert!(block.is_live());
+
+            crate::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                drop(right);
+            });
+            block_freed().await;
+            assert!(!block.is_live());
+
+            // the block can be handed out again, but not under the same id
+            let mut held = Vec::new();
+            while let Ok(bm) = BufMut::alloc() {
+                held.push(bm.freeze());
+            }
+            assert!(held.iter().all(|buf| buf.block() != block));
+            assert!(!block.is_live());
+        });
+    }
 }
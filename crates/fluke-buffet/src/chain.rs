@@ -0,0 +1,166 @@
+//! A cheap way to build up a message head out of many small fragments
+//! (method, separators, header names and values, CRLFs) before writing it
+//! with a single vectored write.
+
+use std::fmt;
+
+use crate::{Piece, PieceCore, PieceList, INLINE_CAP};
+
+/// A sequence of bytes made of [Piece]s, for serializing message heads.
+///
+/// Pieces are kept as they are, without copying their bytes, except for
+/// runs of small ones, which get coalesced into [PieceCore::Inline] pieces:
+/// a header line like `x: 1\r\n` then takes up a single iovec rather than
+/// four, which keeps responses with many headers well under `IOV_MAX`.
+///
+/// Turns into a [PieceList] for [crate::WriteOwned::writev_all_owned].
+#[derive(Default)]
+pub struct PieceChain {
+    list: PieceList,
+
+    /// small fragments that haven't been pushed to `list` yet
+    tail: [u8; INLINE_CAP],
+    tail_len: u8,
+
+    len: usize,
+}
+
+impl PieceChain {
+    /// Appends a piece. Small pieces are copied, big ones are not.
+    pub fn push(&mut self, piece: impl Into<Piece>) {
+        let piece = piece.into();
+        if piece.len() <= INLINE_CAP {
+            self.push_copy(&piece);
+        } else {
+            self.flush_tail();
+            self.len += piece.len();
+            self.list.push_back(piece);
+        }
+    }
+
+    /// Appends a copy of `bytes`
+    pub fn push_copy(&mut self, bytes: &[u8]) {
+        if bytes.len() > INLINE_CAP {
+            self.flush_tail();
+            self.len += bytes.len();
+            self.list.push_back(bytes.to_vec());
+            return;
+        }
+
+        if self.tail_len as usize + bytes.len() > INLINE_CAP {
+            self.flush_tail();
+        }
+        let start = self.tail_len as usize;
+        self.tail[start..][..bytes.len()].copy_from_slice(bytes);
+        self.tail_len += bytes.len() as u8;
+        self.len += bytes.len();
+    }
+
+    /// Appends everything in `other`
+    pub fn append(&mut self, other: PieceChain) {
+        self.flush_tail();
+        let PieceChain {
+            list,
+            tail,
+            tail_len,
+            len,
+        } = other;
+        for piece in list.into_vec_deque() {
+            self.list.push_back(piece);
+        }
+        self.tail = tail;
+        self.tail_len = tail_len;
+        self.len += len;
+    }
+
+    /// Total length, in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many pieces (and so iovecs) this would take once turned into a
+    /// [PieceList]
+    pub fn num_pieces(&self) -> usize {
+        self.list.num_pieces() + usize::from(self.tail_len > 0)
+    }
+
+    pub fn into_list(mut self) -> PieceList {
+        self.flush_tail();
+        self.list
+    }
+
+    fn flush_tail(&mut self) {
+        if self.tail_len == 0 {
+            return;
+        }
+        self.list.push_back(PieceCore::Inline {
+            len: self.tail_len,
+            data: self.tail,
+        });
+        self.tail_len = 0;
+    }
+}
+
+impl From<PieceChain> for PieceList {
+    fn from(chain: PieceChain) -> Self {
+        chain.into_list()
+    }
+}
+
+impl fmt::Write for PieceChain {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_copy(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use super::PieceChain;
+    use crate::Piece;
+
+    fn bytes(chain: PieceChain) -> Vec<u8> {
+        chain
+            .into_list()
+            .into_vec_deque()
+            .iter()
+            .flat_map(|p| p.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut chain = PieceChain::default();
+        for (name, value) in [("x-a", "1"), ("x-b", "2"), ("x-c", "3")] {
+            chain.push(name);
+            chain.push(": ");
+            chain.push(value);
+            chain.push("\r\n");
+        }
+        // 3 * 8 bytes, coalesced into inline pieces of at most 22 bytes
+        assert_eq!(chain.len(), 24);
+        assert_eq!(chain.num_pieces(), 2);
+
+        let big = Piece::from(vec![b'v'; 100]);
+        chain.push(big);
+        write!(chain, "{}\r\n", 404).unwrap();
+        assert_eq!(chain.num_pieces(), 4);
+        assert_eq!(chain.len(), 24 + 100 + 5);
+
+        let mut head = PieceChain::default();
+        head.push("HTTP/1.1 200 OK\r\n");
+        head.append(chain);
+        assert_eq!(head.len(), 17 + 24 + 100 + 5);
+
+        let mut expected = b"HTTP/1.1 200 OK\r\nx-a: 1\r\nx-b: 2\r\nx-c: 3\r\n".to_vec();
+        expected.extend(vec![b'v'; 100]);
+        expected.extend(b"404\r\n");
+        assert_eq!(bytes(head), expected);
+    }
+}
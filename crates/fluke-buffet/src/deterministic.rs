@@ -0,0 +1,138 @@
+//! A test-mode executor: timers advance virtually (as soon as every task is
+//! idle, the clock jumps to the next timer), and the order in which ready
+//! tasks get polled is perturbed by a seeded PRNG, so that a failing
+//! interleaving can be reproduced by re-running with the same seed.
+//!
+//! Only in-memory I/O (like [crate::pipe]) should be used under this
+//! executor: real sockets would complete while the clock is being
+//! fast-forwarded.
+
+use std::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+thread_local! {
+    static RNG_STATE: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Builds a current-thread runtime with a paused clock, and runs the
+/// provided future on it. Tasks spawned with [crate::spawn] from within
+/// get their polls shuffled around according to `seed`.
+pub fn start_deterministic<F: Future>(seed: u64, task: F) -> F::Output {
+    use tokio::task::LocalSet;
+
+    struct ResetOnDrop;
+    impl Drop for ResetOnDrop {
+        fn drop(&mut self) {
+            RNG_STATE.with(|s| s.set(None));
+        }
+    }
+
+    // splitmix64 doesn't like zero much
+    RNG_STATE.with(|s| s.set(Some(seed ^ 0x9e37_79b9_7f4a_7c15)));
+    let _reset = ResetOnDrop;
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap()
+        .block_on(async move {
+            let local = LocalSet::new();
+            local.run_until(Jitter { inner: task }).await
+        })
+}
+
+/// Returns true if the current thread is running [start_deterministic]
+pub(crate) fn is_active() -> bool {
+    RNG_STATE.with(|s| s.get().is_some())
+}
+
+/// Returns the next pseudo-random number (splitmix64), if the current
+/// thread is running [start_deterministic].
+fn next_rand() -> Option<u64> {
+    RNG_STATE.with(|s| {
+        let state = s.get()?.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        s.set(Some(state));
+
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Some(z ^ (z >> 31))
+    })
+}
+
+/// Wraps a future so that, when polled, it sometimes yields back to the
+/// scheduler instead (waking itself up right away), which lets other ready
+/// tasks go first.
+pub(crate) struct Jitter<F> {
+    pub(crate) inner: F,
+}
+
+impl<F: Future> Future for Jitter<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if next_rand().is_some_and(|r| r % 4 == 0) {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        // SAFETY: we never move `inner` out of the pinned struct
+        let inner = unsafe { self.map_unchecked_mut(|s| &mut s.inner) };
+        inner.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+
+    use super::start_deterministic;
+
+    #[test]
+    fn timers_advance_virtually() {
+        let elapsed = start_deterministic(0, async {
+            let before = std::time::Instant::now();
+            let virtual_before = tokio::time::Instant::now();
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            assert!(virtual_before.elapsed() >= Duration::from_secs(3600));
+            before.elapsed()
+        });
+        assert!(elapsed < Duration::from_secs(60));
+    }
+
+    fn interleaving(seed: u64) -> Vec<usize> {
+        start_deterministic(seed, async {
+            let order: Rc<RefCell<Vec<usize>>> = Default::default();
+            let mut handles = vec![];
+            for i in 0..8 {
+                let order = order.clone();
+                handles.push(crate::spawn(async move {
+                    for _ in 0..4 {
+                        order.borrow_mut().push(i);
+                        tokio::task::yield_now().await;
+                    }
+                }));
+            }
+            for h in handles {
+                h.await.unwrap();
+            }
+            let order = order.borrow().clone();
+            order
+        })
+    }
+
+    #[test]
+    fn same_seed_same_interleaving() {
+        assert_eq!(interleaving(42), interleaving(42));
+
+        // not a hard guarantee for any two seeds, but with 32 polls, it'd be
+        // quite the coincidence if all of these agreed.
+        let orders: Vec<_> = (0..4).map(interleaving).collect();
+        assert!(orders.iter().any(|o| o != &orders[0]));
+    }
+}
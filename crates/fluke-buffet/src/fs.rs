@@ -0,0 +1,138 @@
+//! File I/O with owned buffers, like [crate::net]: with the `uring` feature
+//! on Linux, every operation is an io_uring submission. Elsewhere, they run
+//! on [crate::spawn_blocking]'s thread pool.
+//!
+//! Reads and writes are positional, there's no cursor: this is meant for
+//! serving files and spilling bodies to disk, not for general-purpose I/O.
+
+use std::{ffi::OsString, fs::FileType, io, path::Path, time::SystemTime};
+
+use crate::Piece;
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod fs_uring;
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub use fs_uring::*;
+
+#[cfg(not(all(target_os = "linux", feature = "uring")))]
+mod fs_noring;
+
+#[cfg(not(all(target_os = "linux", feature = "uring")))]
+pub use fs_noring::*;
+
+/// What [File::metadata] returns
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    /// Size of the file, in bytes
+    pub len: u64,
+
+    /// Last modification time, if the filesystem records it
+    pub modified: Option<SystemTime>,
+
+    pub is_dir: bool,
+}
+
+/// An entry returned by [read_dir]
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// The file name, without the directory
+    pub name: OsString,
+
+    pub file_type: FileType,
+}
+
+impl File {
+    /// Writes the whole buffer at `pos`, retrying partial writes
+    pub async fn write_all_at(&self, buf: impl Into<Piece>, mut pos: u64) -> io::Result<()> {
+        let mut buf = buf.into();
+        while !buf.is_empty() {
+            let (res, slice) = self.write_at(buf, pos).await;
+            let n = res?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "write zero"));
+            }
+            (_, buf) = slice.split_at(n);
+            pos += n as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Lists a directory, in no particular order. io_uring has no `getdents`,
+/// so this always runs on the thread pool.
+pub async fn read_dir(path: impl AsRef<Path>) -> io::Result<Vec<DirEntry>> {
+    let path = path.as_ref().to_owned();
+    blocking(move || {
+        std::fs::read_dir(path)?
+            .map(|entry| {
+                let entry = entry?;
+                Ok(DirEntry {
+                    name: entry.file_name(),
+                    file_type: entry.file_type()?,
+                })
+            })
+            .collect()
+    })
+    .await
+}
+
+/// Runs `f` on the thread pool, flattening join errors
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    crate::spawn_blocking(f).await.map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{read_dir, File};
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("buffet-fs-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn file_roundtrip() {
+        let dir = scratch_dir("roundtrip");
+        let path = dir.join("spill");
+
+        crate::start(async {
+            let file = File::create(&path).await.unwrap();
+            file.allocate(0, 64 * 1024).await.unwrap();
+            file.write_all_at("hello", 0).await.unwrap();
+            file.write_all_at(" world", 5).await.unwrap();
+            file.sync_data().await.unwrap();
+            drop(file);
+
+            let file = File::open(&path).await.unwrap();
+            let meta = file.metadata().await.unwrap();
+            assert_eq!(meta.len, 64 * 1024);
+            assert!(!meta.is_dir);
+            assert!(meta.modified.is_some());
+
+            let (res, buf) = file.read_at(vec![0u8; 5], 6).await;
+            assert_eq!(res.unwrap(), 5);
+            assert_eq!(&buf[..], b"world");
+
+            // reads past the end come back empty
+            let (res, _) = file.read_at(vec![0u8; 5], 1 << 20).await;
+            assert_eq!(res.unwrap(), 0);
+
+            let err = File::open(dir.join("missing")).await.err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+            let entries = read_dir(&dir).await.unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].name, "spill");
+            assert!(entries[0].file_type.is_file());
+        });
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    io,
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::fs::FileExt,
+    },
+    path::Path,
+    sync::Arc,
+};
+
+use super::{blocking, Metadata};
+use crate::{BufResult, IoBufMut, Piece};
+
+/// A file whose operations run on the thread pool, closed when dropped.
+///
+/// Buffers can't cross threads, so reads and writes go through a copy.
+pub struct File {
+    std: Arc<std::fs::File>,
+}
+
+impl File {
+    /// Opens an existing file, read-only
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let std = blocking(move || std::fs::File::open(path)).await?;
+        Ok(Self { std: Arc::new(std) })
+    }
+
+    /// Opens a file for reading and writing, creating it if it doesn't
+    /// exist, truncating it if it does
+    pub async fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let std = blocking(move || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+        })
+        .await?;
+        Ok(Self { std: Arc::new(std) })
+    }
+
+    /// Reads up to `buf`'s capacity, starting at `pos`. Returns 0 at the end
+    /// of the file.
+    pub async fn read_at<B: IoBufMut>(&self, mut buf: B, pos: u64) -> BufResult<usize, B> {
+        let std = self.std.clone();
+        let len = buf.io_buf_mut_capacity();
+        let res = blocking(move || {
+            let mut tmp = vec![0u8; len];
+            let n = std.read_at(&mut tmp, pos)?;
+            tmp.truncate(n);
+            Ok(tmp)
+        })
+        .await;
+
+        match res {
+            Ok(tmp) => {
+                unsafe { buf.slice_mut()[..tmp.len()].copy_from_slice(&tmp) };
+                (Ok(tmp.len()), buf)
+            }
+            Err(e) => (Err(e), buf),
+        }
+    }
+
+    /// Writes `buf` at `pos`. Might perform a partial write, see
+    /// [File::write_all_at]
+    pub async fn write_at(&self, buf: impl Into<Piece>, pos: u64) -> BufResult<usize, Piece> {
+        let buf = buf.into();
+        let std = self.std.clone();
+        let tmp = buf.to_vec();
+        let res = blocking(move || std.write_at(&tmp, pos)).await;
+        (res, buf)
+    }
+
+    pub async fn metadata(&self) -> io::Result<Metadata> {
+        let std = self.std.clone();
+        let meta = blocking(move || std.metadata()).await?;
+        Ok(Metadata {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            is_dir: meta.is_dir(),
+        })
+    }
+
+    /// Makes sure `len` bytes starting at `offset` are allocated on disk,
+    /// growing the file if needed
+    pub async fn allocate(&self, offset: u64, len: u64) -> io::Result<()> {
+        let std = self.std.clone();
+        blocking(move || {
+            // not every platform can reserve blocks, but they can all grow
+            // the file
+            let end = offset + len;
+            if std.metadata()?.len() < end {
+                std.set_len(end)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Flushes data and metadata to disk (`fsync(2)`)
+    pub async fn sync_all(&self) -> io::Result<()> {
+        let std = self.std.clone();
+        blocking(move || std.sync_all()).await
+    }
+
+    /// Flushes data to disk, and only the metadata needed to read it back
+    /// (`fdatasync(2)`)
+    pub async fn sync_data(&self) -> io::Result<()> {
+        let std = self.std.clone();
+        blocking(move || std.sync_data()).await
+    }
+}
+
+impl AsFd for File {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.std.as_fd()
+    }
+}
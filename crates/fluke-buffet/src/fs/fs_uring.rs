@@ -0,0 +1,155 @@
+use std::{
+    ffi::CString,
+    io,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use io_uring::{
+    opcode::{Fallocate, Fsync, OpenAt, Read, Statx, Write},
+    types::{Fd, FsyncFlags},
+};
+
+use super::Metadata;
+use crate::{
+    get_ring,
+    uring::{CqueueExt, InFlight},
+    BufResult, IoBufMut, Piece,
+};
+
+/// A file opened with io_uring, closed when dropped
+pub struct File {
+    fd: OwnedFd,
+}
+
+impl File {
+    /// Opens an existing file, read-only
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with(path.as_ref(), libc::O_RDONLY).await
+    }
+
+    /// Opens a file for reading and writing, creating it if it doesn't
+    /// exist, truncating it if it does
+    pub async fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with(path.as_ref(), libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC).await
+    }
+
+    async fn open_with(path: &Path, flags: i32) -> io::Result<Self> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+
+        let sqe = OpenAt::new(Fd(libc::AT_FDCWD), path.as_ptr())
+            .flags(flags | libc::O_CLOEXEC)
+            .mode(0o644)
+            .build();
+        // the kernel reads the path after submission, and if nobody's
+        // waiting for the file anymore, it's closed right away
+        let (cqe, _path) = InFlight::new(get_ring().push(sqe), path)
+            .if_orphaned(close_opened)
+            .await;
+
+        let fd = cqe.error_for_errno()?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Reads up to `buf`'s capacity, starting at `pos`. Returns 0 at the end
+    /// of the file.
+    pub async fn read_at<B: IoBufMut>(&self, mut buf: B, pos: u64) -> BufResult<usize, B> {
+        let sqe = Read::new(
+            Fd(self.fd.as_raw_fd()),
+            buf.io_buf_mut_stable_mut_ptr(),
+            buf.io_buf_mut_capacity() as u32,
+        )
+        .offset(pos)
+        .build();
+        let (cqe, buf) = InFlight::new(get_ring().push(sqe), buf).await;
+        match cqe.error_for_errno() {
+            Ok(n) => (Ok(n as usize), buf),
+            Err(e) => (Err(e.into()), buf),
+        }
+    }
+
+    /// Writes `buf` at `pos`. Might perform a partial write, see
+    /// [File::write_all_at]
+    pub async fn write_at(&self, buf: impl Into<Piece>, pos: u64) -> BufResult<usize, Piece> {
+        let buf = buf.into();
+        let sqe = Write::new(Fd(self.fd.as_raw_fd()), buf.as_ptr(), buf.len() as u32)
+            .offset(pos)
+            .build();
+        let (cqe, buf) = InFlight::new(get_ring().push(sqe), buf).await;
+        match cqe.error_for_errno() {
+            Ok(n) => (Ok(n as usize), buf),
+            Err(e) => (Err(e.into()), buf),
+        }
+    }
+
+    pub async fn metadata(&self) -> io::Result<Metadata> {
+        let mut statx = Box::new(unsafe { std::mem::zeroed::<libc::statx>() });
+        let sqe = Statx::new(
+            Fd(self.fd.as_raw_fd()),
+            b"\0".as_ptr() as *const libc::c_char,
+            &mut *statx as *mut libc::statx as *mut io_uring::types::statx,
+        )
+        .flags(libc::AT_EMPTY_PATH)
+        .mask(libc::STATX_TYPE | libc::STATX_SIZE | libc::STATX_MTIME)
+        .build();
+        let (cqe, statx) = InFlight::new(get_ring().push(sqe), statx).await;
+        cqe.error_for_errno()?;
+
+        let modified = (statx.stx_mask & libc::STATX_MTIME != 0 && statx.stx_mtime.tv_sec >= 0)
+            .then(|| {
+                SystemTime::UNIX_EPOCH
+                    + Duration::new(statx.stx_mtime.tv_sec as u64, statx.stx_mtime.tv_nsec)
+            });
+        Ok(Metadata {
+            len: statx.stx_size,
+            modified,
+            is_dir: (statx.stx_mode as u32 & libc::S_IFMT) == libc::S_IFDIR,
+        })
+    }
+
+    /// Makes sure `len` bytes starting at `offset` are allocated on disk,
+    /// growing the file if needed (`fallocate(2)`)
+    pub async fn allocate(&self, offset: u64, len: u64) -> io::Result<()> {
+        let sqe = Fallocate::new(Fd(self.fd.as_raw_fd()), len)
+            .offset(offset)
+            .build();
+        get_ring().push(sqe).await.error_for_errno()?;
+        Ok(())
+    }
+
+    /// Flushes data and metadata to disk (`fsync(2)`)
+    pub async fn sync_all(&self) -> io::Result<()> {
+        self.fsync(FsyncFlags::empty()).await
+    }
+
+    /// Flushes data to disk, and only the metadata needed to read it back
+    /// (`fdatasync(2)`)
+    pub async fn sync_data(&self) -> io::Result<()> {
+        self.fsync(FsyncFlags::DATASYNC).await
+    }
+
+    async fn fsync(&self, flags: FsyncFlags) -> io::Result<()> {
+        let sqe = Fsync::new(Fd(self.fd.as_raw_fd())).flags(flags).build();
+        get_ring().push(sqe).await.error_for_errno()?;
+        Ok(())
+    }
+}
+
+/// Closes a file that was opened for a caller that gave up on it
+fn close_opened(cqe: io_uring::cqueue::Entry) {
+    if let Ok(fd) = cqe.error_for_errno() {
+        drop(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+}
+
+impl AsFd for File {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
@@ -0,0 +1,164 @@
+//! Checksums and hashes that are fed one [Piece] at a time, as a body goes
+//! by, so it never has to be held in memory as a whole: for ETags, or
+//! integrity fields like `Content-Digest`.
+//!
+//! The hashing itself is done by the `crc32c`, `xxhash-rust` and `sha2`
+//! crates, these only adapt them to [StreamingHash].
+
+use crate::{Piece, PieceList};
+
+/// A hash computed incrementally
+pub trait StreamingHash {
+    type Digest;
+
+    fn update(&mut self, data: &[u8]);
+
+    fn finish(self) -> Self::Digest;
+
+    fn update_piece(&mut self, piece: &Piece) {
+        self.update(&piece[..]);
+    }
+
+    fn update_piece_list(&mut self, list: &PieceList) {
+        for piece in list.pieces.iter() {
+            self.update(&piece[..]);
+        }
+    }
+}
+
+/// CRC-32C (Castagnoli), as used by iSCSI, ext4, and cloud storage APIs
+#[derive(Debug, Clone, Default)]
+pub struct Crc32c {
+    crc: u32,
+}
+
+impl StreamingHash for Crc32c {
+    type Digest = u32;
+
+    fn update(&mut self, data: &[u8]) {
+        self.crc = crc32c::crc32c_append(self.crc, data);
+    }
+
+    fn finish(self) -> u32 {
+        self.crc
+    }
+}
+
+/// xxHash64: fast and well-distributed, but not cryptographic. Good for
+/// ETags of generated content.
+#[derive(Clone)]
+pub struct XxHash64 {
+    inner: xxhash_rust::xxh64::Xxh64,
+}
+
+impl Default for XxHash64 {
+    fn default() -> Self {
+        Self::with_seed(0)
+    }
+}
+
+impl XxHash64 {
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            inner: xxhash_rust::xxh64::Xxh64::new(seed),
+        }
+    }
+}
+
+impl std::fmt::Debug for XxHash64 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("XxHash64").finish_non_exhaustive()
+    }
+}
+
+impl StreamingHash for XxHash64 {
+    type Digest = u64;
+
+    fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    fn finish(self) -> u64 {
+        self.inner.digest()
+    }
+}
+
+/// SHA-256, for integrity checks that have to hold up against tampering
+/// (`Content-Digest: sha-256=...`)
+#[derive(Debug, Clone, Default)]
+pub struct Sha256 {
+    inner: sha2::Sha256,
+}
+
+impl StreamingHash for Sha256 {
+    type Digest = [u8; 32];
+
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.inner, data);
+    }
+
+    fn finish(self) -> [u8; 32] {
+        sha2::Digest::finalize(self.inner).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crc32c, Sha256, StreamingHash, XxHash64};
+
+    fn hash<H: StreamingHash + Default>(data: &[u8]) -> H::Digest {
+        let mut h = H::default();
+        h.update(data);
+        h.finish()
+    }
+
+    /// Same as [hash], but fed in uneven pieces
+    fn hash_split<H: StreamingHash + Default>(data: &[u8]) -> H::Digest {
+        let mut h = H::default();
+        let mut rest = data;
+        let mut n = 1;
+        while !rest.is_empty() {
+            let (chunk, tail) = rest.split_at(n.min(rest.len()));
+            h.update(chunk);
+            rest = tail;
+            n = n * 3 + 1;
+        }
+        h.finish()
+    }
+
+    fn hex(digest: &[u8]) -> String {
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn known_vectors() {
+        assert_eq!(hash::<Crc32c>(b"123456789"), 0xe306_9283);
+        assert_eq!(hash::<Crc32c>(b""), 0);
+
+        assert_eq!(hash::<XxHash64>(b""), 0xef46_db37_51d8_e999);
+        assert_eq!(hash::<XxHash64>(b"abc"), 0x44bc_2cf5_ad77_0999);
+
+        assert_eq!(
+            hex(&hash::<Sha256>(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&hash::<Sha256>(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(&hash::<Sha256>(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn chunking_does_not_matter() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        assert_eq!(hash::<Crc32c>(&data), hash_split::<Crc32c>(&data));
+        assert_eq!(hash::<XxHash64>(&data), hash_split::<XxHash64>(&data));
+        assert_eq!(hash::<Sha256>(&data), hash_split::<Sha256>(&data));
+    }
+}
@@ -0,0 +1,73 @@
+//! Hex encoding of sizes without going through `core::fmt`, for chunked
+//! transfer-encoding (and anything else that needs to announce a length in
+//! hex on the wire).
+
+use crate::{Piece, PieceCore};
+
+/// How many bytes [format_hex] may need
+pub const HEX_MAX_LEN: usize = std::mem::size_of::<usize>() * 2;
+
+/// Writes `n` as lowercase hex (no leading zeros) at the end of `buf`, and
+/// returns the part of `buf` that was written.
+pub fn format_hex(mut n: usize, buf: &mut [u8; HEX_MAX_LEN]) -> &[u8] {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = DIGITS[n & 0xf];
+        n >>= 4;
+        if n == 0 {
+            break;
+        }
+    }
+    &buf[i..]
+}
+
+/// Common chunk sizes, which don't need to be encoded at all
+const COMMON_CHUNK_SIZE_LINES: &[(usize, &str)] = &[
+    (1024, "400\r\n"),
+    (2048, "800\r\n"),
+    (4096, "1000\r\n"),
+    (8192, "2000\r\n"),
+    (16384, "4000\r\n"),
+    (32768, "8000\r\n"),
+    (65536, "10000\r\n"),
+];
+
+/// Returns `{n:x}\r\n`, the line preceding a chunk of size `n` in a chunked
+/// body. Common sizes are static, the rest fits in an inline piece.
+pub fn chunk_size_line(n: usize) -> Result<Piece, crate::Error> {
+    if let Some((_, line)) = COMMON_CHUNK_SIZE_LINES.iter().find(|(size, _)| *size == n) {
+        return Ok(PieceCore::Static(line.as_bytes()).into());
+    }
+
+    let mut buf = [0u8; HEX_MAX_LEN + 2];
+    let (digits, crlf) = buf.split_at_mut(HEX_MAX_LEN);
+    crlf.copy_from_slice(b"\r\n");
+    let len = format_hex(n, digits.try_into().unwrap()).len();
+    Ok(PieceCore::copy_from_slice(&buf[HEX_MAX_LEN - len..]).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_size_line, format_hex, HEX_MAX_LEN};
+
+    #[test]
+    fn test_format_hex() {
+        let mut buf = [0u8; HEX_MAX_LEN];
+        for n in [0, 1, 9, 10, 15, 16, 255, 256, 4095, 65535, usize::MAX] {
+            assert_eq!(format_hex(n, &mut buf), format!("{n:x}").as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_chunk_size_line() {
+        for n in [0, 5, 1024, 4096, 12345, 65536, 1 << 20] {
+            assert_eq!(
+                &chunk_size_line(n).unwrap()[..],
+                format!("{n:x}\r\n").as_bytes()
+            );
+        }
+    }
+}
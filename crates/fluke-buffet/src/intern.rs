@@ -0,0 +1,137 @@
+//! A per-runtime table of common header values (content types, encodings,
+//! methods...). Values found in it are turned into [PieceCore::Static]
+//! pieces instead of allocating an `Rc<Vec<u8>>` each time.
+//!
+//! Like the buffer pool, the table is thread-local: values registered with
+//! [register] are only visible to the runtime running on the current thread.
+
+use std::{cell::RefCell, collections::HashSet};
+
+use crate::{Piece, PieceCore};
+
+/// Interned by default on every thread
+const DEFAULT_VALUES: &[&str] = &[
+    // methods & schemes (h2 pseudo-headers)
+    "GET",
+    "HEAD",
+    "POST",
+    "PUT",
+    "DELETE",
+    "OPTIONS",
+    "PATCH",
+    "http",
+    "https",
+    "/",
+    // content types
+    "text/plain",
+    "text/plain; charset=utf-8",
+    "text/html",
+    "text/html; charset=utf-8",
+    "text/css",
+    "text/javascript",
+    "application/json",
+    "application/javascript",
+    "application/octet-stream",
+    "application/x-www-form-urlencoded",
+    "image/png",
+    "image/jpeg",
+    "image/webp",
+    "image/avif",
+    "image/svg+xml",
+    // encodings
+    "gzip",
+    "br",
+    "deflate",
+    "identity",
+    "gzip, deflate",
+    "gzip, deflate, br",
+    "gzip, deflate, br, zstd",
+    "chunked",
+    // misc
+    "*/*",
+    "0",
+    "close",
+    "keep-alive",
+    "trailers",
+    "no-cache",
+    "no-store",
+    "max-age=0",
+    "bytes",
+    "en-US,en;q=0.9",
+];
+
+struct Table {
+    values: HashSet<&'static [u8]>,
+    /// values longer than this can't be in the table, so we don't even
+    /// bother hashing them
+    max_len: usize,
+}
+
+impl Table {
+    fn insert(&mut self, value: &'static [u8]) {
+        self.max_len = self.max_len.max(value.len());
+        self.values.insert(value);
+    }
+}
+
+thread_local! {
+    static TABLE: RefCell<Table> = RefCell::new({
+        let mut table = Table {
+            values: HashSet::with_capacity(DEFAULT_VALUES.len()),
+            max_len: 0,
+        };
+        for value in DEFAULT_VALUES {
+            table.insert(value.as_bytes());
+        }
+        table
+    });
+}
+
+/// Adds a value to the current thread's table
+pub fn register(value: &'static [u8]) {
+    TABLE.with(|t| t.borrow_mut().insert(value))
+}
+
+/// Returns the interned copy of `value`, if there is one
+pub fn lookup(value: &[u8]) -> Option<&'static [u8]> {
+    TABLE.with(|t| {
+        let t = t.borrow();
+        if value.len() > t.max_len {
+            return None;
+        }
+        t.values.get(value).copied()
+    })
+}
+
+/// Makes a piece out of `value`, only allocating if it's not interned
+pub fn piece(value: &[u8]) -> Piece {
+    match lookup(value) {
+        Some(value) => PieceCore::Static(value).into(),
+        None => value.to_vec().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Piece, PieceCore};
+
+    fn is_static(p: &Piece) -> bool {
+        matches!(
+            p,
+            Piece::Full {
+                core: PieceCore::Static(_)
+            }
+        )
+    }
+
+    #[test]
+    fn known_values_are_static() {
+        assert!(is_static(&super::piece(b"application/json")));
+        assert!(!is_static(&super::piece(b"application/x-fluke-test")));
+
+        super::register(b"application/x-fluke-test");
+        let p = super::piece(b"application/x-fluke-test");
+        assert!(is_static(&p));
+        assert_eq!(&p[..], b"application/x-fluke-test");
+    }
+}
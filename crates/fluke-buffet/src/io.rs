@@ -8,6 +8,22 @@ mod non_uring;
 #[allow(async_fn_in_trait)] // we never require Send
 pub trait ReadOwned {
     async fn read_owned<B: IoBufMut>(&mut self, buf: B) -> BufResult<usize, B>;
+
+    /// Read into several buffers, filling them in order, taking ownership
+    /// for the duration of the read. Might stop before filling all of them,
+    /// like [ReadOwned::read_owned] might stop before filling its buffer.
+    ///
+    /// The default implementation only reads into the first buffer:
+    /// transports that can do better (a vectored read) override it.
+    async fn readv_owned<B: IoBufMut>(&mut self, mut bufs: Vec<B>) -> BufResult<usize, Vec<B>> {
+        if bufs.is_empty() {
+            return (Ok(0), bufs);
+        }
+        let first = bufs.remove(0);
+        let (res, first) = self.read_owned(first).await;
+        bufs.insert(0, first);
+        (res, bufs)
+    }
 }
 
 #[allow(async_fn_in_trait)] // we never require Send
@@ -105,6 +121,26 @@ pub trait WriteOwned {
         Ok(())
     }
 
+    /// Lets the transport hold back what gets written from now on, until
+    /// [WriteOwned::flush_owned] or [WriteOwned::uncork], so that a message
+    /// written in several pieces leaves in as few packets (or TLS records)
+    /// as possible. Does nothing by default.
+    async fn cork(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Stops holding back writes, and sends whatever was held back.
+    async fn uncork(&mut self) -> std::io::Result<()> {
+        self.flush_owned().await
+    }
+
+    /// Sends whatever the transport is holding back, staying corked if it
+    /// was. Does nothing by default. Not named `flush`, so it doesn't get
+    /// mixed up with `AsyncWriteExt::flush` for types that have both.
+    async fn flush_owned(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
     /// Shuts down the write end of this socket. This flushes
     /// any data that may not have been send.
     async fn shutdown(&mut self) -> std::io::Result<()>;
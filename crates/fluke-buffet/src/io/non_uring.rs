@@ -27,6 +27,12 @@ where
     // everything in `IoSlice`, advancing correctly, etc. It's not fun, but it
     // should yield a boost for non-uring codepaths.
 
+    // there's no corking through `AsyncWrite`, but wrappers that buffer
+    // (TLS streams, `BufWriter`) still need flushing.
+    async fn flush_owned(&mut self) -> std::io::Result<()> {
+        AsyncWriteExt::flush(self).await
+    }
+
     async fn shutdown(&mut self) -> std::io::Result<()> {
         AsyncWriteExt::shutdown(self).await
     }
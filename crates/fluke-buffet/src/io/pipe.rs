@@ -84,6 +84,41 @@ impl ReadOwned for PipeRead {
             return (Ok(read_size), buf);
         }
     }
+
+    /// Fills buffers from what was written so far, only waiting for a
+    /// write if there's nothing to read at all.
+    async fn readv_owned<B: crate::IoBufMut>(
+        &mut self,
+        bufs: Vec<B>,
+    ) -> crate::BufResult<usize, Vec<B>> {
+        let mut total = 0;
+        let mut done = Vec::with_capacity(bufs.len());
+        let mut bufs = bufs.into_iter();
+        for buf in bufs.by_ref() {
+            if total > 0 && self.remain.is_none() {
+                done.push(buf);
+                break;
+            }
+            let cap = buf.io_buf_mut_capacity();
+            let (res, buf) = self.read_owned(buf).await;
+            done.push(buf);
+            match res {
+                Ok(n) => {
+                    total += n;
+                    if n < cap {
+                        break;
+                    }
+                }
+                // only the first read can fail: the others don't wait
+                Err(e) => {
+                    done.extend(bufs);
+                    return (Err(e), done);
+                }
+            }
+        }
+        done.extend(bufs);
+        (Ok(total), done)
+    }
 }
 
 pub struct PipeWrite {
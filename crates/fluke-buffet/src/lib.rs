@@ -6,20 +6,38 @@ pub use roll::*;
 mod piece;
 pub use piece::*;
 
+mod chain;
+pub use chain::*;
+
 pub mod bufpool;
 use bufpool::*;
 
+pub mod intern;
+
+mod hex;
+pub use hex::*;
+
+pub mod hash;
+
 mod io;
 pub use io::*;
 
 pub mod net;
 
+pub mod fs;
+
 #[cfg(all(target_os = "linux", feature = "uring"))]
 mod uring;
 
 #[cfg(all(target_os = "linux", feature = "uring"))]
 pub use uring::get_ring;
 
+#[cfg(any(test, feature = "test-util"))]
+mod deterministic;
+
+#[cfg(any(test, feature = "test-util"))]
+pub use deterministic::start_deterministic;
+
 /// Spawns a new asynchronous task, returning a [tokio::task::JoinHandle] for it.
 ///
 /// Spawning a task enables the task to execute concurrently to other tasks.
@@ -29,9 +47,27 @@ pub use uring::get_ring;
 ///
 /// This must be executed from within a runtime created by [crate::start]
 pub fn spawn<T: Future + 'static>(task: T) -> tokio::task::JoinHandle<T::Output> {
+    #[cfg(any(test, feature = "test-util"))]
+    if deterministic::is_active() {
+        return tokio::task::spawn_local(deterministic::Jitter { inner: task });
+    }
+
     tokio::task::spawn_local(task)
 }
 
+/// Runs blocking code (CPU-heavy work, synchronous I/O, a synchronous DB
+/// driver...) on a thread pool, so it doesn't stall the reactor. The
+/// returned [tokio::task::JoinHandle] completes back on the runtime.
+///
+/// This must be executed from within a runtime created by [crate::start]
+pub fn spawn_blocking<F, R>(f: F) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+}
+
 /// Build a new current-thread runtime and runs the provided future on it
 #[cfg(all(target_os = "linux", feature = "uring"))]
 pub fn start<F: Future>(task: F) -> F::Output {
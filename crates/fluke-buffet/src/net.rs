@@ -1,5 +1,14 @@
 use crate::io::IntoHalves;
 
+mod sockopt;
+pub use sockopt::*;
+
+mod activation;
+pub use activation::*;
+
+mod handover;
+pub use handover::*;
+
 #[cfg(all(target_os = "linux", feature = "uring"))]
 mod net_uring;
 
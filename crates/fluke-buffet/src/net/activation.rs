@@ -0,0 +1,149 @@
+//! Listening sockets inherited from whoever started us: systemd socket
+//! activation (<https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html>),
+//! or a parent process that follows the same convention. Either way, the
+//! sockets stay open across restarts, and no connection is refused while
+//! the new process starts up.
+
+use std::{
+    net::TcpListener,
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+};
+
+/// The first inherited file descriptor, right after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket passed down through `LISTEN_FDS`, or handed over with
+/// [super::recv_listeners]
+#[derive(Debug)]
+pub struct ListenFd {
+    /// The socket's name, from `LISTEN_FDNAMES` (e.g. systemd's
+    /// `FileDescriptorName=`) or the sending process, if any
+    pub name: Option<String>,
+
+    pub listener: TcpListener,
+}
+
+/// Takes the listening sockets passed through the `LISTEN_PID`, `LISTEN_FDS`
+/// and `LISTEN_FDNAMES` environment variables, in order. Returns an empty
+/// list if there are none, or if they're meant for another process.
+///
+/// The variables are removed from the environment, so that child processes
+/// don't try to take the same sockets, which makes calls after the first one
+/// return an empty list. Call it early, before other threads may read the
+/// environment. The sockets are marked close-on-exec.
+///
+/// Fails if one of the file descriptors isn't a listening TCP socket.
+pub fn listen_fds() -> std::io::Result<Vec<ListenFd>> {
+    let var = |name: &str| std::env::var(name).ok();
+    let (pid, fds, names) = (var("LISTEN_PID"), var("LISTEN_FDS"), var("LISTEN_FDNAMES"));
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+
+    let fds = parse(
+        pid.as_deref(),
+        fds.as_deref(),
+        names.as_deref(),
+        std::process::id(),
+    )?;
+    fds.into_iter()
+        .map(|(fd, name)| {
+            // SAFETY: the file descriptor was passed to us to own, and the
+            // environment variables that told us about it are gone
+            let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+            ListenFd::new(socket, name)
+        })
+        .collect()
+}
+
+impl ListenFd {
+    /// Checks that an inherited socket is fit for [super::TcpListener::from_std]
+    pub(super) fn new(socket: socket2::Socket, name: Option<String>) -> std::io::Result<Self> {
+        if socket.r#type()? != socket2::Type::STREAM || !is_listener(&socket)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "inherited file descriptor {} isn't a listening TCP socket",
+                    socket.as_raw_fd()
+                ),
+            ));
+        }
+        Ok(Self {
+            name,
+            listener: socket.into(),
+        })
+    }
+}
+
+fn is_listener(socket: &socket2::Socket) -> std::io::Result<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        socket.is_listener()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        // assume it is, `accept` will tell
+        let _ = socket;
+        Ok(true)
+    }
+}
+
+/// Works out which file descriptors are ours from the environment
+/// variables' values
+fn parse(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> std::io::Result<Vec<(RawFd, Option<String>)>> {
+    let invalid = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, what);
+
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(vec![]);
+    };
+    let pid: u32 = pid.parse().map_err(|_| invalid("invalid LISTEN_PID"))?;
+    if pid != own_pid {
+        return Ok(vec![]);
+    }
+    let count: RawFd = fds.parse().map_err(|_| invalid("invalid LISTEN_FDS"))?;
+
+    let mut names = names.map(|names| names.split(':'));
+    Ok((0..count)
+        .map(|i| {
+            let name = names
+                .as_mut()
+                .and_then(|names| names.next())
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_owned());
+            (LISTEN_FDS_START + i, name)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn parse_env() {
+        assert_eq!(parse(None, None, None, 42).unwrap(), []);
+        // meant for someone else
+        assert_eq!(parse(Some("41"), Some("2"), None, 42).unwrap(), []);
+        assert!(parse(Some("42"), Some("two"), None, 42).is_err());
+
+        assert_eq!(
+            parse(Some("42"), Some("2"), None, 42).unwrap(),
+            [(3, None), (4, None)]
+        );
+        assert_eq!(
+            parse(Some("42"), Some("3"), Some("http::admin"), 42).unwrap(),
+            [
+                (3, Some("http".to_owned())),
+                (4, None),
+                (5, Some("admin".to_owned()))
+            ]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Passing listening sockets to another process over a unix socket
+//! (`SCM_RIGHTS`), so that a new version of a server can take over from a
+//! running one without refusing a single connection.
+//!
+//! This is only the transfer: who connects to whom, and when the old process
+//! stops accepting, is up to the caller.
+
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, RawFd};
+
+use tokio::{io::Interest, net::UnixStream};
+
+use super::ListenFd;
+
+/// How many sockets fit in a single handover
+pub const MAX_HANDOVER_FDS: usize = 64;
+
+/// Leads every handover message: `SCM_RIGHTS` needs at least one byte of
+/// regular data to ride along with, and this lets us tell garbage apart.
+const MAGIC: &[u8] = b"fluke-fds\0";
+
+/// Max length of the names, separated by `:`
+const MAX_NAMES_LEN: usize = 4096;
+
+/// Sends listening sockets, along with their names, over `stream`. The
+/// sockets stay open on our side: both processes can accept on them until
+/// one of them closes its copies.
+pub async fn send_listeners(
+    stream: &UnixStream,
+    listeners: &[(&str, BorrowedFd<'_>)],
+) -> std::io::Result<()> {
+    if listeners.len() > MAX_HANDOVER_FDS {
+        return Err(invalid("too many sockets for a single handover"));
+    }
+    if listeners.iter().any(|(name, _)| name.contains(':')) {
+        return Err(invalid("socket names can't contain ':'"));
+    }
+
+    let mut data = MAGIC.to_vec();
+    for (i, (name, _)) in listeners.iter().enumerate() {
+        if i > 0 {
+            data.push(b':');
+        }
+        data.extend_from_slice(name.as_bytes());
+    }
+    if data.len() > MAGIC.len() + MAX_NAMES_LEN {
+        return Err(invalid("socket names are too long"));
+    }
+    let fds: Vec<RawFd> = listeners.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+
+    stream
+        .async_io(Interest::WRITABLE, || {
+            let mut cmsg_buf = vec![0u8; cmsg_space(fds.len())];
+            let mut iov = libc::iovec {
+                iov_base: data.as_ptr() as *mut _,
+                iov_len: data.len(),
+            };
+            // SAFETY: every pointer in `msg` points into a buffer that
+            // outlives the call, with the matching length
+            let sent = unsafe {
+                let mut msg: libc::msghdr = std::mem::zeroed();
+                msg.msg_iov = &mut iov;
+                msg.msg_iovlen = 1;
+                if !fds.is_empty() {
+                    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+                    msg.msg_controllen = cmsg_buf.len() as _;
+
+                    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                    (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of_val(&fds[..]) as _) as _;
+                    std::ptr::copy_nonoverlapping(
+                        fds.as_ptr(),
+                        libc::CMSG_DATA(cmsg) as *mut RawFd,
+                        fds.len(),
+                    );
+                }
+
+                libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+            };
+            if sent < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if sent as usize != data.len() {
+                // it's a few bytes on a fresh stream, this doesn't happen
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "handover message was cut short",
+                ));
+            }
+            Ok(())
+        })
+        .await
+}
+
+/// Receives listening sockets sent with [send_listeners]. They're marked
+/// close-on-exec.
+pub async fn recv_listeners(stream: &UnixStream) -> std::io::Result<Vec<ListenFd>> {
+    let (data, fds) = stream
+        .async_io(Interest::READABLE, || {
+            let mut data = vec![0u8; MAGIC.len() + MAX_NAMES_LEN];
+            let mut cmsg_buf = vec![0u8; cmsg_space(MAX_HANDOVER_FDS)];
+            let mut iov = libc::iovec {
+                iov_base: data.as_mut_ptr() as *mut _,
+                iov_len: data.len(),
+            };
+            // SAFETY: same as in `send_listeners`, and the kernel only
+            // writes in bounds of the control buffer
+            let (received, fds) = unsafe {
+                let mut msg: libc::msghdr = std::mem::zeroed();
+                msg.msg_iov = &mut iov;
+                msg.msg_iovlen = 1;
+                msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+                msg.msg_controllen = cmsg_buf.len() as _;
+
+                let received = libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+                if received < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                let mut fds = vec![];
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+                while !cmsg.is_null() {
+                    if (*cmsg).cmsg_level == libc::SOL_SOCKET
+                        && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+                    {
+                        let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                        let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                        for i in 0..len / std::mem::size_of::<RawFd>() {
+                            // owned from here on, so they're closed if
+                            // anything goes wrong
+                            fds.push(socket2::Socket::from_raw_fd(data.add(i).read_unaligned()));
+                        }
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                }
+                if msg.msg_flags & (libc::MSG_TRUNC | libc::MSG_CTRUNC) != 0 {
+                    return Err(invalid("handover message was truncated"));
+                }
+                (received as usize, fds)
+            };
+            data.truncate(received);
+            Ok((data, fds))
+        })
+        .await?;
+
+    if data.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "peer hung up before handing sockets over",
+        ));
+    }
+    let Some(names) = data.strip_prefix(MAGIC) else {
+        return Err(invalid("not a handover message"));
+    };
+    let names = std::str::from_utf8(names).map_err(|_| invalid("socket names aren't utf-8"))?;
+    let mut names = names.split(':');
+    fds.into_iter()
+        .map(|socket| {
+            let name = names
+                .next()
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_owned());
+            ListenFd::new(socket, name)
+        })
+        .collect()
+}
+
+fn cmsg_space(fds: usize) -> usize {
+    // SAFETY: only computes a length
+    unsafe { libc::CMSG_SPACE((fds * std::mem::size_of::<RawFd>()) as _) as usize }
+}
+
+fn invalid(what: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, what)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsFd;
+
+    use super::{recv_listeners, send_listeners};
+
+    #[test]
+    fn hand_over_listeners() {
+        crate::start(async move {
+            let (a, b) = tokio::net::UnixStream::pair().unwrap();
+            let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let admin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+            send_listeners(&a, &[("http", http.as_fd()), ("", admin.as_fd())])
+                .await
+                .unwrap();
+            let received = recv_listeners(&b).await.unwrap();
+
+            assert_eq!(received.len(), 2);
+            assert_eq!(received[0].name.as_deref(), Some("http"));
+            assert_eq!(received[1].name, None);
+            assert_eq!(
+                received[0].listener.local_addr().unwrap(),
+                http.local_addr().unwrap()
+            );
+
+            // it's the same socket
+            drop(http);
+            let addr = received[0].listener.local_addr().unwrap();
+            let _client = std::net::TcpStream::connect(addr).unwrap();
+            let (_, peer) = received[0].listener.accept().unwrap();
+            assert_eq!(peer, _client.local_addr().unwrap());
+
+            // only listening sockets are accepted
+            let (x, _y) = std::os::unix::net::UnixStream::pair().unwrap();
+            send_listeners(&a, &[("x", x.as_fd())]).await.unwrap();
+            assert!(recv_listeners(&b).await.is_err());
+        });
+    }
+}
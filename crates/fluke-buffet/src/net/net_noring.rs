@@ -1,5 +1,13 @@
-use std::net::SocketAddr;
-use tokio::net::{TcpListener as TokListener, TcpStream as TokStream};
+use std::{
+    net::SocketAddr,
+    os::fd::{AsFd, BorrowedFd},
+};
+use tokio::{
+    net::{TcpListener as TokListener, TcpStream as TokStream},
+    sync::Notify,
+};
+
+use super::{ListenOptions, TcpOptions};
 
 pub type TcpStream = TokStream;
 
@@ -8,22 +16,80 @@ pub type TcpWriteHalf = tokio::net::tcp::OwnedWriteHalf;
 
 pub struct TcpListener {
     tok: TokListener,
+    accepted: TcpOptions,
+    cancel: Notify,
 }
 
 impl TcpListener {
     pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
-        let tok = TokListener::bind(addr).await?;
-        Ok(Self { tok })
+        Self::bind_with(addr, &Default::default()).await
+    }
+
+    pub async fn bind_with(addr: SocketAddr, options: &ListenOptions) -> std::io::Result<Self> {
+        let socket = options.bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let tok = TokListener::from_std(socket.into())?;
+        Ok(Self {
+            tok,
+            accepted: options.accepted.clone(),
+            cancel: Default::default(),
+        })
+    }
+
+    /// Accepts connections on a socket that's already bound and listening,
+    /// e.g. one inherited through [super::listen_fds]
+    pub fn from_std(
+        listener: std::net::TcpListener,
+        options: &ListenOptions,
+    ) -> std::io::Result<Self> {
+        let socket = socket2::Socket::from(listener);
+        options.adopt(&socket)?;
+        socket.set_nonblocking(true)?;
+        let tok = TokListener::from_std(socket.into())?;
+        Ok(Self {
+            tok,
+            accepted: options.accepted.clone(),
+            cancel: Default::default(),
+        })
     }
 
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
         self.tok.local_addr()
     }
 
+    /// Stops listening: the pending accept (if any) fails, and connection
+    /// attempts are refused until the socket is closed and bound again.
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        socket2::SockRef::from(&self.tok).shutdown(std::net::Shutdown::Read)
+    }
+
+    /// Makes the pending accept (if any) return: with `ECANCELED`, or with
+    /// the connection the kernel accepted just before the cancellation
+    /// reached it. Unlike [Self::shutdown], the socket keeps listening.
+    pub async fn cancel_accept(&self) -> std::io::Result<()> {
+        self.cancel.notify_waiters();
+        Ok(())
+    }
+
     pub async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
-        self.tok.accept().await.map(|tuple| {
-            tuple.0.set_nodelay(true).unwrap();
-            tuple
-        })
+        let (stream, addr) = tokio::select! {
+            biased;
+            res = self.tok.accept() => res?,
+            _ = self.cancel.notified() => {
+                return Err(std::io::Error::from_raw_os_error(libc::ECANCELED))
+            }
+        };
+        // the connection works without them, and failing here would read
+        // as the listener failing
+        if let Err(e) = self.accepted.apply(&stream) {
+            tracing::warn!(%addr, "couldn't set options on accepted connection: {e}");
+        }
+        Ok((stream, addr))
+    }
+}
+
+impl AsFd for TcpListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.tok.as_fd()
     }
 }
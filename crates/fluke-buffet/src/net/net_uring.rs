@@ -1,17 +1,23 @@
 use std::{
     mem::ManuallyDrop,
     net::SocketAddr,
-    os::fd::{AsRawFd, FromRawFd, RawFd},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd},
     rc::Rc,
 };
 
-use io_uring::opcode::{Accept, Read, Write};
+use io_uring::{
+    opcode::{Accept, AsyncCancel2, Read, Readv, Send, SendMsg},
+    types::CancelBuilder,
+};
+#[cfg(feature = "zerocopy")]
 use nix::errno::Errno;
 
+use super::{ListenOptions, TcpOptions};
 use crate::{
     get_ring,
     io::{IntoHalves, ReadOwned, WriteOwned},
-    BufResult, IoBufMut, Piece,
+    uring::{CqueueExt, InFlight},
+    BufResult, IoBufMut, Piece, PieceList,
 };
 
 pub struct TcpStream {
@@ -19,14 +25,18 @@ pub struct TcpStream {
 }
 
 impl TcpStream {
-    // TODO: nodelay
     pub async fn connect(addr: SocketAddr) -> std::io::Result<Self> {
+        Self::connect_with(addr, &Default::default()).await
+    }
+
+    pub async fn connect_with(addr: SocketAddr, options: &TcpOptions) -> std::io::Result<Self> {
         let addr: socket2::SockAddr = addr.into();
         let socket = ManuallyDrop::new(socket2::Socket::new(
             addr.domain(),
             socket2::Type::STREAM,
             None,
         )?);
+        options.apply(&*socket)?;
         let fd = socket.as_raw_fd();
 
         let u = get_ring();
@@ -42,6 +52,12 @@ impl TcpStream {
     }
 }
 
+impl AsFd for TcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
 impl Drop for TcpStream {
     fn drop(&mut self) {
         // TODO: rethink this.
@@ -54,21 +70,42 @@ impl Drop for TcpStream {
 
 pub struct TcpListener {
     fd: i32,
+    accepted: TcpOptions,
 }
 
 impl TcpListener {
     // note: this is only async to match tokio's API
     // TODO: investigate why tokio's TcpListener::bind is async
     pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
-        let addr: socket2::SockAddr = addr.into();
-        let socket = socket2::Socket::new(addr.domain(), socket2::Type::STREAM, None)?;
-        socket.bind(&addr)?;
-        // FIXME: magic values
-        socket.listen(16)?;
+        Self::bind_with(addr, &Default::default()).await
+    }
+
+    pub async fn bind_with(addr: SocketAddr, options: &ListenOptions) -> std::io::Result<Self> {
+        let socket = options.bind(addr)?;
         let fd = socket.as_raw_fd();
         std::mem::forget(socket);
 
-        Ok(Self { fd })
+        Ok(Self {
+            fd,
+            accepted: options.accepted.clone(),
+        })
+    }
+
+    /// Accepts connections on a socket that's already bound and listening,
+    /// e.g. one inherited through [super::listen_fds]
+    pub fn from_std(
+        listener: std::net::TcpListener,
+        options: &ListenOptions,
+    ) -> std::io::Result<Self> {
+        let socket = socket2::Socket::from(listener);
+        options.adopt(&socket)?;
+        // io_uring may fail accepts with EAGAIN on non-blocking sockets
+        // instead of waiting for a connection
+        socket.set_nonblocking(false)?;
+        Ok(Self {
+            fd: socket.into_raw_fd(),
+            accepted: options.accepted.clone(),
+        })
     }
 
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
@@ -77,34 +114,82 @@ impl TcpListener {
         Ok(addr.as_socket().unwrap())
     }
 
+    /// Stops listening: the pending accept (if any) fails, and connection
+    /// attempts are refused until the socket is closed and bound again.
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        let socket = ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(self.fd) });
+        socket.shutdown(std::net::Shutdown::Read)
+    }
+
+    /// Makes the pending accept (if any) return: with `ECANCELED`, or with
+    /// the connection the kernel accepted just before the cancellation
+    /// reached it. Unlike [Self::shutdown], the socket keeps listening.
+    pub async fn cancel_accept(&self) -> std::io::Result<()> {
+        let sqe = AsyncCancel2::new(CancelBuilder::fd(io_uring::types::Fd(self.fd)).all()).build();
+        let cqe = get_ring().push(sqe).await;
+        match cqe.error_for_errno() {
+            Ok(_) => Ok(()),
+            // nothing was pending
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
     pub async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
-        let u = get_ring();
         struct AcceptUserData {
             sockaddr_storage: libc::sockaddr_storage,
             sockaddr_len: libc::socklen_t,
         }
-        // FIXME: this currently leaks if the future is dropped
-        let udata = Box::into_raw(Box::new(AcceptUserData {
+        let mut udata = Box::new(AcceptUserData {
             sockaddr_storage: unsafe { std::mem::zeroed() },
             sockaddr_len: std::mem::size_of::<libc::sockaddr>() as libc::socklen_t,
-        }));
+        });
 
-        let sqe = unsafe {
-            Accept::new(
-                io_uring::types::Fd(self.fd),
-                &mut (*udata).sockaddr_storage as *mut _ as *mut _,
-                &mut (*udata).sockaddr_len,
-            )
-            .build()
-        };
-        let cqe = u.push(sqe).await;
+        let sqe = Accept::new(
+            io_uring::types::Fd(self.fd),
+            &mut udata.sockaddr_storage as *mut _ as *mut _,
+            &mut udata.sockaddr_len,
+        )
+        .build();
+        // if we're dropped, whatever connection comes in gets closed rather
+        // than leaked
+        let (cqe, udata) = InFlight::new(get_ring().push(sqe), udata)
+            .if_orphaned(close_accepted)
+            .await;
         let fd = cqe.error_for_errno()?;
 
-        let udata = unsafe { Box::from_raw(udata) };
         let addr = unsafe { socket2::SockAddr::new(udata.sockaddr_storage, udata.sockaddr_len) };
         let peer_addr = addr.as_socket().unwrap();
 
-        Ok((TcpStream { fd }, peer_addr))
+        let stream = TcpStream { fd };
+        // the connection works without them, and failing here would read
+        // as the listener failing
+        if let Err(e) = self.accepted.apply(&stream) {
+            tracing::warn!(%peer_addr, "couldn't set options on accepted connection: {e}");
+        }
+        Ok((stream, peer_addr))
+    }
+}
+
+fn close_accepted(cqe: io_uring::cqueue::Entry) {
+    if let Ok(fd) = cqe.error_for_errno() {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+impl AsFd for TcpListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
     }
 }
 
@@ -112,6 +197,12 @@ impl TcpListener {
 // the underlying fd, in-flight operations etc.
 pub struct TcpReadHalf(Rc<TcpStream>);
 
+impl AsFd for TcpReadHalf {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
 impl ReadOwned for TcpReadHalf {
     async fn read_owned<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
         let sqe = Read::new(
@@ -120,27 +211,150 @@ impl ReadOwned for TcpReadHalf {
             buf.io_buf_mut_capacity() as u32,
         )
         .build();
-        let cqe = get_ring().push(sqe).await;
+        let (cqe, buf) = InFlight::new(get_ring().push(sqe), buf).await;
         let ret = match cqe.error_for_errno() {
             Ok(ret) => ret,
             Err(e) => return (Err(std::io::Error::from(e)), buf),
         };
         (Ok(ret as usize), buf)
     }
+
+    /// Submits a single vectored read for all the buffers (up to
+    /// [MAX_IOVECS] of them)
+    async fn readv_owned<B: IoBufMut>(&mut self, mut bufs: Vec<B>) -> BufResult<usize, Vec<B>> {
+        let iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .take(MAX_IOVECS)
+            .map(|buf| libc::iovec {
+                iov_base: buf.io_buf_mut_stable_mut_ptr() as *mut _,
+                iov_len: buf.io_buf_mut_capacity(),
+            })
+            .collect();
+        let sqe = Readv::new(
+            io_uring::types::Fd(self.0.fd),
+            iovecs.as_ptr(),
+            iovecs.len().try_into().expect("usize -> u32"),
+        )
+        .build();
+        // the buffers and the iovecs pointing into them must outlive the op
+        let (cqe, (bufs, _iovecs)) = InFlight::new(get_ring().push(sqe), (bufs, iovecs)).await;
+        let ret = match cqe.error_for_errno() {
+            Ok(ret) => ret,
+            Err(e) => return (Err(std::io::Error::from(e)), bufs),
+        };
+        (Ok(ret as usize), bufs)
+    }
+}
+
+pub struct TcpWriteHalf {
+    stream: Rc<TcpStream>,
+
+    /// set between [WriteOwned::cork] and [WriteOwned::uncork]: sends carry
+    /// `MSG_MORE`, so the kernel holds back partial frames.
+    corked: bool,
+}
+
+impl TcpWriteHalf {
+    fn send_flags(&self) -> i32 {
+        if self.corked {
+            libc::MSG_MORE
+        } else {
+            0
+        }
+    }
+}
+
+impl AsFd for TcpWriteHalf {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.stream.as_fd()
+    }
+}
+
+/// Writes at least this big are sent with `IORING_OP_SEND_ZC` when the
+/// `zerocopy` feature is enabled. Below that, pinning the pages and waiting
+/// for the kernel to release them costs more than copying.
+#[cfg(feature = "zerocopy")]
+pub const ZEROCOPY_THRESHOLD: usize = 16 * 1024;
+
+/// What we know about zero-copy sends on this thread's ring
+#[cfg(feature = "zerocopy")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ZeroCopy {
+    Untried,
+    Works,
+    Unsupported,
+}
+
+#[cfg(feature = "zerocopy")]
+thread_local! {
+    /// Set to [ZeroCopy::Unsupported] once the kernel told us it doesn't do
+    /// zero-copy sends (pre-6.0 kernels, some socket types), so we stop
+    /// trying.
+    static ZEROCOPY: std::cell::Cell<ZeroCopy> = const { std::cell::Cell::new(ZeroCopy::Untried) };
+}
+
+#[cfg(feature = "zerocopy")]
+fn zerocopy_enabled() -> bool {
+    ZEROCOPY.get() != ZeroCopy::Unsupported
+}
+
+/// Whether a zero-copy send failed because it's not supported, rather than
+/// because of the socket or that particular send. An unknown opcode also
+/// shows up as `EINVAL`, but so does a bad send: it only counts as
+/// unsupported if no zero-copy send ever went through on this thread.
+#[cfg(feature = "zerocopy")]
+fn zerocopy_unsupported(e: Errno) -> bool {
+    let unsupported = match e {
+        Errno::EOPNOTSUPP => true,
+        Errno::EINVAL => ZEROCOPY.get() == ZeroCopy::Untried,
+        _ => false,
+    };
+    if unsupported {
+        tracing::debug!("zero-copy sends unsupported ({e}), falling back to copying");
+        ZEROCOPY.set(ZeroCopy::Unsupported);
+    }
+    unsupported
 }
 
-pub struct TcpWriteHalf(Rc<TcpStream>);
+/// Max number of buffers submitted in a single vectored write (`IOV_MAX`
+/// on Linux). Longer lists are written partially.
+const MAX_IOVECS: usize = 1024;
 
 impl WriteOwned for TcpWriteHalf {
     async fn write_owned(&mut self, buf: impl Into<Piece>) -> BufResult<usize, Piece> {
         let buf = buf.into();
-        let sqe = Write::new(
-            io_uring::types::Fd(self.0.fd),
+
+        #[cfg(feature = "zerocopy")]
+        let buf = if buf.len() >= ZEROCOPY_THRESHOLD && zerocopy_enabled() {
+            let sqe = io_uring::opcode::SendZc::new(
+                io_uring::types::Fd(self.stream.fd),
+                buf.as_ref().as_ptr(),
+                buf.len().try_into().expect("usize -> u32"),
+            )
+            .flags(self.send_flags())
+            .build();
+            // this only completes once the kernel is done with the pages
+            let (cqe, buf) = InFlight::new(get_ring().push(sqe), buf).await;
+            match cqe.error_for_errno() {
+                Ok(ret) => {
+                    ZEROCOPY.set(ZeroCopy::Works);
+                    return (Ok(ret as usize), buf);
+                }
+                Err(e) if zerocopy_unsupported(e) => buf,
+                Err(e) => return (Err(std::io::Error::from(e)), buf),
+            }
+        } else {
+            buf
+        };
+
+        let sqe = Send::new(
+            io_uring::types::Fd(self.stream.fd),
             buf.as_ref().as_ptr(),
             buf.len().try_into().expect("usize -> u32"),
         )
+        .flags(self.send_flags())
         .build();
-        let cqe = get_ring().push(sqe).await;
+        let (cqe, buf) = InFlight::new(get_ring().push(sqe), buf).await;
         let ret = match cqe.error_for_errno() {
             Ok(ret) => ret,
             Err(e) => return (Err(std::io::Error::from(e)), buf),
@@ -148,11 +362,78 @@ impl WriteOwned for TcpWriteHalf {
         (Ok(ret as usize), buf)
     }
 
-    // TODO: implement writev
+    /// Submits the whole list as a single vectored send (zero-copy if
+    /// it's big enough, see [ZEROCOPY_THRESHOLD]) rather than one write per
+    /// piece.
+    async fn writev_owned(&mut self, list: &PieceList) -> std::io::Result<usize> {
+        let pieces: Vec<Piece> = list.pieces.iter().take(MAX_IOVECS).cloned().collect();
+        let iovecs: Vec<libc::iovec> = pieces
+            .iter()
+            .map(|piece| libc::iovec {
+                iov_base: piece.as_ref().as_ptr() as *mut _,
+                iov_len: piece.len(),
+            })
+            .collect();
+        let mut msg: Box<libc::msghdr> = Box::new(unsafe { std::mem::zeroed() });
+        msg.msg_iov = iovecs.as_ptr() as *mut _;
+        msg.msg_iovlen = iovecs.len() as _;
+        let flags = self.send_flags() as u32;
+
+        #[cfg(feature = "zerocopy")]
+        let (pieces, iovecs, msg) = if list.len() >= ZEROCOPY_THRESHOLD && zerocopy_enabled() {
+            let sqe = io_uring::opcode::SendMsgZc::new(
+                io_uring::types::Fd(self.stream.fd),
+                &*msg as *const _,
+            )
+            .flags(flags)
+            .build();
+            // the pieces, iovecs and msghdr must all outlive the op
+            let (cqe, (pieces, iovecs, msg)) =
+                InFlight::new(get_ring().push(sqe), (pieces, iovecs, msg)).await;
+            match cqe.error_for_errno() {
+                Ok(ret) => {
+                    ZEROCOPY.set(ZeroCopy::Works);
+                    return Ok(ret as usize);
+                }
+                Err(e) if zerocopy_unsupported(e) => (pieces, iovecs, msg),
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            (pieces, iovecs, msg)
+        };
+
+        let sqe = SendMsg::new(io_uring::types::Fd(self.stream.fd), &*msg as *const _)
+            .flags(flags)
+            .build();
+        let (cqe, _) = InFlight::new(get_ring().push(sqe), (pieces, iovecs, msg)).await;
+        Ok(cqe.error_for_errno()? as usize)
+    }
+
+    /// Sends carry `MSG_MORE` until uncorked: unlike `TCP_CORK`, that
+    /// doesn't take a syscall of its own.
+    async fn cork(&mut self) -> std::io::Result<()> {
+        self.corked = true;
+        Ok(())
+    }
+
+    /// Clearing `TCP_CORK` pushes out any partial frame held back by
+    /// `MSG_MORE`, whether or not `TCP_CORK` was set.
+    async fn uncork(&mut self) -> std::io::Result<()> {
+        self.corked = false;
+        super::set_cork(&*self, false)
+    }
+
+    async fn flush_owned(&mut self) -> std::io::Result<()> {
+        if self.corked {
+            super::set_cork(&*self, false)?;
+        }
+        Ok(())
+    }
 
     async fn shutdown(&mut self) -> std::io::Result<()> {
         let sqe =
-            io_uring::opcode::Shutdown::new(io_uring::types::Fd(self.0.fd), libc::SHUT_WR).build();
+            io_uring::opcode::Shutdown::new(io_uring::types::Fd(self.stream.fd), libc::SHUT_WR)
+                .build();
         let cqe = get_ring().push(sqe).await;
         cqe.error_for_errno()?;
         Ok(())
@@ -165,7 +446,13 @@ impl IntoHalves for TcpStream {
 
     fn into_halves(self) -> (Self::Read, Self::Write) {
         let self_rc = Rc::new(self);
-        (TcpReadHalf(self_rc.clone()), TcpWriteHalf(self_rc))
+        (
+            TcpReadHalf(self_rc.clone()),
+            TcpWriteHalf {
+                stream: self_rc,
+                corked: false,
+            },
+        )
     }
 }
 
@@ -175,21 +462,6 @@ impl FromRawFd for TcpStream {
     }
 }
 
-trait CqueueExt {
-    fn error_for_errno(&self) -> Result<i32, Errno>;
-}
-
-impl CqueueExt for io_uring::cqueue::Entry {
-    fn error_for_errno(&self) -> Result<i32, Errno> {
-        let res = self.result();
-        if res < 0 {
-            Err(Errno::from_raw(-res))
-        } else {
-            Ok(res as _)
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use crate::io::{IntoHalves, ReadOwned, WriteOwned};
@@ -243,4 +515,88 @@ mod tests {
         }
         crate::start(async move { test_accept_inner().await.unwrap() });
     }
+
+    #[test]
+    fn test_large_writes() {
+        use crate::{Piece, PieceList};
+
+        async fn test_large_writes_inner() -> color_eyre::Result<()> {
+            let listener = super::TcpListener::bind("127.0.0.1:0".parse().unwrap()).await?;
+            let addr = listener.local_addr()?;
+
+            let payload: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+            let expected = [&payload[..], &payload[..]].concat();
+            let client = std::thread::spawn(move || {
+                use std::io::Read;
+
+                let mut sock = std::net::TcpStream::connect(addr).unwrap();
+                let mut received = vec![];
+                sock.read_to_end(&mut received).unwrap();
+                received
+            });
+
+            let (stream, _) = listener.accept().await?;
+            let (_r, mut w) = stream.into_halves();
+
+            // one big piece, then the same bytes split across many pieces
+            w.write_all_owned(payload.clone()).await?;
+            let mut list = PieceList::default();
+            for chunk in payload.chunks(3000) {
+                list.push_back(Piece::from(chunk.to_vec()));
+            }
+            w.writev_all_owned(list).await?;
+            w.shutdown().await?;
+
+            let received = client.join().unwrap();
+            assert!(received == expected, "received bytes differ");
+            Ok(())
+        }
+        crate::start(async move { test_large_writes_inner().await.unwrap() });
+    }
+
+    #[test]
+    fn test_cork() {
+        async fn test_cork_inner() -> color_eyre::Result<()> {
+            let listener = super::TcpListener::bind("127.0.0.1:0".parse().unwrap()).await?;
+            let addr = listener.local_addr()?;
+
+            let client = std::thread::spawn(move || {
+                use std::io::{Read, Write};
+
+                let mut sock = std::net::TcpStream::connect(addr).unwrap();
+                let mut buf = [0u8; 11];
+                sock.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf, b"hello world");
+                sock.write_all(b"ack").unwrap();
+
+                let mut rest = vec![];
+                sock.read_to_end(&mut rest).unwrap();
+                assert_eq!(rest, b"bye");
+            });
+
+            let (stream, _) = listener.accept().await?;
+            let (mut r, mut w) = stream.into_halves();
+
+            // held back with `MSG_MORE` until flushed: if flushing didn't
+            // push them out, the client would never ack.
+            w.cork().await?;
+            w.write_all_owned("hello").await?;
+            w.write_all_owned(" ").await?;
+            let mut list = crate::PieceList::default();
+            list.push_back("world");
+            w.writev_all_owned(list).await?;
+            w.flush_owned().await?;
+
+            let (res, buf) = r.read_owned(vec![0u8; 3]).await;
+            assert_eq!(&buf[..res?], b"ack");
+
+            w.write_all_owned("bye").await?;
+            w.uncork().await?;
+            w.shutdown().await?;
+
+            client.join().unwrap();
+            Ok(())
+        }
+        crate::start(async move { test_cork_inner().await.unwrap() });
+    }
 }
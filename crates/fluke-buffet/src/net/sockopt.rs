@@ -0,0 +1,234 @@
+//! Socket tuning, shared by the io_uring and tokio backends.
+
+use std::{
+    net::SocketAddr,
+    os::fd::{AsFd, AsRawFd},
+    time::Duration,
+};
+
+/// Options applied to TCP connections, see [TcpOptions::apply]
+#[derive(Debug, Clone)]
+pub struct TcpOptions {
+    /// Disables Nagle's algorithm (`TCP_NODELAY`). On by default: writes
+    /// are already coalesced before they reach the socket, so delaying them
+    /// further only adds latency.
+    pub nodelay: bool,
+
+    /// Enables TCP keepalive probes (`SO_KEEPALIVE`)
+    pub keepalive: Option<Keepalive>,
+
+    /// Kernel send buffer size (`SO_SNDBUF`), `None` keeps the system default
+    pub send_buffer_size: Option<usize>,
+
+    /// Kernel receive buffer size (`SO_RCVBUF`), `None` keeps the system default
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+/// TCP keepalive parameters
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// How long a connection must be idle before probes are sent
+    pub time: Duration,
+
+    /// Time between probes, `None` keeps the system default
+    pub interval: Option<Duration>,
+
+    /// How many unanswered probes before the connection is dropped, `None`
+    /// keeps the system default
+    pub retries: Option<u32>,
+}
+
+impl TcpOptions {
+    /// Sets these options on a connected (or connecting) socket
+    pub fn apply(&self, socket: &impl AsFd) -> std::io::Result<()> {
+        let sock = socket2::SockRef::from(socket);
+        sock.set_nodelay(self.nodelay)?;
+
+        if let Some(keepalive) = &self.keepalive {
+            let mut params = socket2::TcpKeepalive::new().with_time(keepalive.time);
+            if let Some(interval) = keepalive.interval {
+                params = params.with_interval(interval);
+            }
+            if let Some(retries) = keepalive.retries {
+                params = params.with_retries(retries);
+            }
+            sock.set_tcp_keepalive(&params)?;
+        }
+
+        if let Some(size) = self.send_buffer_size {
+            sock.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            sock.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Options for binding a listener, see `TcpListener::bind_with`
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+    /// Max number of connections waiting to be accepted (`listen(2)`'s
+    /// backlog)
+    pub backlog: i32,
+
+    /// Only report connections as accepted once the client has sent
+    /// something, or this much time has passed (`TCP_DEFER_ACCEPT`, Linux
+    /// only). Saves waking up for clients that connect and stay silent.
+    pub defer_accept: Option<Duration>,
+
+    /// Applied to every accepted connection. Failing to set them is logged,
+    /// the connection is still handed out.
+    pub accepted: TcpOptions,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        Self {
+            backlog: 1024,
+            defer_accept: None,
+            accepted: Default::default(),
+        }
+    }
+}
+
+impl ListenOptions {
+    /// Creates, configures, binds and starts listening on a socket
+    pub(crate) fn bind(&self, addr: SocketAddr) -> std::io::Result<socket2::Socket> {
+        let addr: socket2::SockAddr = addr.into();
+        let socket = socket2::Socket::new(addr.domain(), socket2::Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if let Some(timeout) = self.defer_accept {
+            set_defer_accept(&socket, timeout)?;
+        }
+        socket.bind(&addr)?;
+        socket.listen(self.backlog)?;
+        Ok(socket)
+    }
+
+    /// Configures a socket that's already bound and listening: only
+    /// `defer_accept` applies, the backlog was set by whoever called
+    /// `listen(2)`.
+    pub(crate) fn adopt(&self, socket: &socket2::Socket) -> std::io::Result<()> {
+        if let Some(timeout) = self.defer_accept {
+            set_defer_accept(socket, timeout)?;
+        }
+        Ok(())
+    }
+}
+
+/// Holds back partial frames until uncorked (`TCP_CORK`, Linux only), so
+/// that a response written in several pieces leaves in as few packets as
+/// possible. Uncorking sends whatever is pending right away.
+pub fn set_cork(socket: &impl AsFd, cork: bool) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        setsockopt_int(
+            socket,
+            libc::IPPROTO_TCP,
+            libc::TCP_CORK,
+            cork as libc::c_int,
+        )
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (socket, cork);
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+fn set_defer_accept(socket: &impl AsFd, timeout: Duration) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let secs = timeout.as_secs().clamp(1, libc::c_int::MAX as u64);
+        setsockopt_int(
+            socket,
+            libc::IPPROTO_TCP,
+            libc::TCP_DEFER_ACCEPT,
+            secs as libc::c_int,
+        )
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (socket, timeout);
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt_int(
+    socket: &impl AsFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_fd().as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Keepalive, ListenOptions, TcpOptions};
+
+    #[test]
+    fn options_are_applied() {
+        let listener = ListenOptions {
+            defer_accept: Some(Duration::from_secs(5)),
+            ..Default::default()
+        }
+        .bind("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let opts = TcpOptions {
+            nodelay: true,
+            keepalive: Some(Keepalive {
+                time: Duration::from_secs(30),
+                interval: Some(Duration::from_secs(5)),
+                retries: Some(3),
+            }),
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: None,
+        };
+        opts.apply(&stream).unwrap();
+
+        let sock = socket2::SockRef::from(&stream);
+        assert!(sock.nodelay().unwrap());
+        assert!(sock.keepalive().unwrap());
+        // the kernel doubles the requested size to account for bookkeeping
+        assert!(sock.send_buffer_size().unwrap() >= 64 * 1024);
+
+        #[cfg(target_os = "linux")]
+        {
+            super::set_cork(&stream, true).unwrap();
+            super::set_cork(&stream, false).unwrap();
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use http::header::HeaderName;
 use std::{
+    cell::RefCell,
     collections::VecDeque,
     fmt,
     hash::{Hash, Hasher},
@@ -10,10 +11,14 @@ use std::{
     str::Utf8Error,
 };
 
-use crate::{Roll, RollStr};
+use crate::{BlockId, Roll, RollStr};
 
 /// A piece of data (arbitrary bytes) with a stable address, suitable for
 /// passing to the kernel (io_uring writes).
+///
+/// Small pieces may hold their bytes inline (see [PieceCore::Inline]), in
+/// which case the address is only stable as long as the piece itself isn't
+/// moved: writes keep the pieces they're given in place until they complete.
 #[derive(Clone)]
 pub enum Piece {
     Full {
@@ -56,6 +61,35 @@ pub enum PieceCore {
     Vec(Rc<Vec<u8>>),
     Roll(Roll),
     HeaderName(HeaderName),
+
+    /// Up to [INLINE_CAP] bytes, stored in the piece itself: no allocation,
+    /// no reference count. Picked automatically for small vectors, see
+    /// [PieceCore::copy_from_slice].
+    Inline {
+        len: u8,
+        data: [u8; INLINE_CAP],
+    },
+}
+
+/// How many bytes fit in a [PieceCore::Inline], without making [PieceCore]
+/// any bigger than its other variants do
+pub const INLINE_CAP: usize = 22;
+
+impl PieceCore {
+    /// Copies `slice`: inline if it's small enough, into a reference-counted
+    /// `Vec` otherwise.
+    pub fn copy_from_slice(slice: &[u8]) -> Self {
+        if slice.len() <= INLINE_CAP {
+            let mut data = [0u8; INLINE_CAP];
+            data[..slice.len()].copy_from_slice(slice);
+            PieceCore::Inline {
+                len: slice.len() as u8,
+                data,
+            }
+        } else {
+            PieceCore::Vec(Rc::new(slice.to_vec()))
+        }
+    }
 }
 
 impl<T> From<T> for Piece
@@ -92,6 +126,10 @@ impl From<&'static str> for PieceCore {
 impl From<Vec<u8>> for PieceCore {
     #[inline(always)]
     fn from(vec: Vec<u8>) -> Self {
+        if vec.len() <= INLINE_CAP {
+            // cheaper than keeping the allocation around behind an `Rc`
+            return PieceCore::copy_from_slice(&vec);
+        }
         PieceCore::Vec(Rc::new(vec))
     }
 }
@@ -146,6 +184,7 @@ impl AsRef<[u8]> for PieceCore {
             PieceCore::Vec(vec) => vec.as_ref(),
             PieceCore::Roll(roll) => roll.as_ref(),
             PieceCore::HeaderName(name) => name.as_str().as_bytes(),
+            PieceCore::Inline { len, data } => &data[..*len as usize],
         }
     }
 }
@@ -158,6 +197,15 @@ impl Piece {
         }
     }
 
+    /// The pool block this piece points into, if any: such a piece keeps
+    /// the whole block from going back to the pool.
+    pub fn block(&self) -> Option<BlockId> {
+        match self.core() {
+            PieceCore::Roll(roll) => roll.block(),
+            _ => None,
+        }
+    }
+
     /// Split the piece into two at the given index.
     /// The original piece will be consumed.
     /// Returns a tuple of the two pieces.
@@ -234,22 +282,61 @@ impl Piece {
 }
 
 /// A list of [Piece], suitable for issuing vectored writes via io_uring.
-#[derive(Default)]
 pub struct PieceList {
     // note: we can't use smallvec here, because the address of
     // the piece list must be stable for the kernel to take
     // ownership of it.
     //
-    // we could however do our own memory pooling.
+    // instead, the backing storage is recycled through a per-runtime
+    // freelist, see `PIECE_LIST_POOL`.
     pub(crate) pieces: VecDeque<Piece>,
 }
 
+thread_local! {
+    static PIECE_LIST_POOL: RefCell<Vec<VecDeque<Piece>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// How many empty `VecDeque`s we keep around per thread
+const MAX_POOLED_LISTS: usize = 64;
+
+/// Lists that grew bigger than this aren't worth keeping
+const MAX_POOLED_LIST_CAPACITY: usize = 64;
+
+impl Default for PieceList {
+    fn default() -> Self {
+        let pieces = PIECE_LIST_POOL
+            .try_with(|pool| pool.borrow_mut().pop())
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        Self { pieces }
+    }
+}
+
+impl Drop for PieceList {
+    fn drop(&mut self) {
+        let mut pieces = std::mem::take(&mut self.pieces);
+        if pieces.capacity() == 0 || pieces.capacity() > MAX_POOLED_LIST_CAPACITY {
+            return;
+        }
+        pieces.clear();
+
+        // the pool might be gone already if we're being dropped at thread exit
+        _ = PIECE_LIST_POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < MAX_POOLED_LISTS {
+                pool.push(pieces);
+            }
+        });
+    }
+}
+
 impl PieceList {
     /// Create a new piece list with a single chunk
     pub fn single(piece: impl Into<Piece>) -> Self {
-        Self {
-            pieces: [piece.into()].into(),
-        }
+        let mut list = Self::default();
+        list.pieces.push_back(piece.into());
+        list
     }
 
     /// Add a single chunk to the back of the list
@@ -297,8 +384,8 @@ impl PieceList {
         self.pieces.clear();
     }
 
-    pub fn into_vec_deque(self) -> VecDeque<Piece> {
-        self.pieces
+    pub fn into_vec_deque(mut self) -> VecDeque<Piece> {
+        std::mem::take(&mut self.pieces)
     }
 }
 
@@ -309,7 +396,7 @@ impl From<VecDeque<Piece>> for PieceList {
 }
 impl From<PieceList> for VecDeque<Piece> {
     fn from(list: PieceList) -> Self {
-        list.pieces
+        list.into_vec_deque()
     }
 }
 
@@ -377,7 +464,7 @@ impl From<&'static str> for PieceStr {
 impl From<String> for PieceStr {
     fn from(s: String) -> Self {
         PieceStr {
-            piece: PieceCore::Vec(Rc::new(s.into_bytes())).into(),
+            piece: PieceCore::from(s.into_bytes()).into(),
         }
     }
 }
@@ -392,7 +479,22 @@ impl From<RollStr> for PieceStr {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Piece, PieceCore};
+    use crate::{Piece, PieceCore, PieceList};
+
+    #[test]
+    fn test_piece_list_storage_is_recycled() {
+        let mut list = PieceList::default();
+        for _ in 0..8 {
+            list.push_back("hi");
+        }
+        let addr = list.pieces.as_slices().0.as_ptr();
+        drop(list);
+
+        let list = PieceList::default();
+        assert!(list.pieces.is_empty());
+        assert!(list.pieces.capacity() >= 8);
+        assert_eq!(list.pieces.as_slices().0.as_ptr(), addr);
+    }
 
     #[test]
     fn test_slice() {
@@ -422,4 +524,19 @@ mod tests {
         assert_eq!(&first_name[..], "".as_bytes());
         assert_eq!(&last_name[..], "".as_bytes());
     }
+    #[test]
+    fn test_inline() {
+        let core = PieceCore::from(b"0123456789abcdefghijkl".to_vec());
+        assert!(matches!(core, PieceCore::Inline { len: 22, .. }));
+        assert_eq!(&core[..], b"0123456789abcdefghijkl");
+
+        let core = PieceCore::from(b"0123456789abcdefghijklm".to_vec());
+        assert!(matches!(core, PieceCore::Vec(_)));
+
+        let piece: Piece = PieceCore::copy_from_slice(b"hello world").into();
+        let (hello, world) = piece.split_at(5);
+        assert_eq!(&hello[..], b"hello");
+        assert_eq!(&world[..], b" world");
+        assert_eq!(Piece::from(PieceCore::copy_from_slice(b"")).len(), 0);
+    }
 }
@@ -1,9 +1,10 @@
 use std::{
     borrow::Cow,
-    cell::UnsafeCell,
+    cell::{RefCell, UnsafeCell},
     fmt::{Debug, Formatter},
     hash::{Hash, Hasher},
     iter::Enumerate,
+    mem::ManuallyDrop,
     ops::{Bound, Deref, RangeBounds},
     rc::Rc,
     str::Utf8Error,
@@ -16,10 +17,29 @@ use nom::{
 };
 use tracing::trace;
 
-use crate::{Buf, BufMut, BUF_SIZE};
+use crate::{BlockId, Buf, BufMut, BUF_SIZE};
 
 type Result<T, E = crate::Error> = std::result::Result<T, E>;
 
+thread_local! {
+    // never dropped: at thread exit, the buffer pool might be gone already
+    static SCRATCH: RefCell<Option<ManuallyDrop<RollMut>>> = const { RefCell::new(None) };
+}
+
+/// Serializes `len` bytes into a per-runtime scratch buffer with `f`, and
+/// returns them as a [Roll]. Small payloads end up sharing pooled buffers
+/// instead of getting a heap allocation each.
+pub fn scratch_roll(len: usize, f: impl FnOnce(&mut [u8]) -> Result<()>) -> Result<Roll> {
+    SCRATCH.with(|scratch| {
+        let mut scratch = scratch.borrow_mut();
+        let scratch = match scratch.as_mut() {
+            Some(scratch) => scratch,
+            None => scratch.insert(ManuallyDrop::new(RollMut::alloc()?)),
+        };
+        scratch.put_to_roll(len, f)
+    })
+}
+
 /// A "rolling buffer". Uses either one [BufMut] or a `Box<[u8]>` for storage.
 /// This buffer never grows, but it can be split, and it can be reallocated so
 /// it regains its initical capacity, minus the length of the filled part.
@@ -124,6 +144,15 @@ impl RollMut {
         })
     }
 
+    /// Allocate, using a single [BufMut] for storage, waiting for one if the
+    /// pool is exhausted, see [BufMut::alloc_wait].
+    pub async fn alloc_wait() -> Result<Self> {
+        Ok(Self {
+            storage: StorageMut::Buf(BufMut::alloc_wait().await?),
+            len: 0,
+        })
+    }
+
     /// Double the capacity of this buffer by reallocating it, copying the
     /// filled part into the new buffer. This method always uses a `Box<[u8]>`
     /// for storage.
@@ -151,35 +180,33 @@ impl RollMut {
     pub fn realloc(&mut self) -> Result<()> {
         assert!(self.len() != self.storage_size());
 
-        let next_storage = match &self.storage {
-            StorageMut::Buf(_) => {
-                let mut next_b = BufMut::alloc()?;
+        match &self.storage {
+            StorageMut::Box(b) if self.len() > BUF_SIZE as usize => {
+                // TODO: optimize via `MaybeUninit`?
+                let mut next_b = vec![0; b.cap()].into_boxed_slice();
                 next_b[..self.len()].copy_from_slice(&self[..]);
-                StorageMut::Buf(next_b)
+                self.storage = StorageMut::Box(BoxStorage {
+                    buf: Rc::new(UnsafeCell::new(next_b)),
+                    off: 0,
+                });
             }
-            StorageMut::Box(b) => {
-                if self.len() > BUF_SIZE as usize {
-                    // TODO: optimize via `MaybeUninit`?
-                    let mut next_b = vec![0; b.cap()].into_boxed_slice();
-                    next_b[..self.len()].copy_from_slice(&self[..]);
-                    let next_b = BoxStorage {
-                        buf: Rc::new(UnsafeCell::new(next_b)),
-                        off: 0,
-                    };
-                    StorageMut::Box(next_b)
-                } else {
-                    let mut next_b = BufMut::alloc()?;
-                    next_b[..self.len()].copy_from_slice(&self[..]);
-                    StorageMut::Buf(next_b)
-                }
-            }
-        };
-
-        self.storage = next_storage;
+            _ => self.realloc_into(BufMut::alloc()?),
+        }
 
         Ok(())
     }
 
+    /// Whether [RollMut::realloc] needs a block from the pool
+    fn realloc_needs_buf(&self) -> bool {
+        matches!(self.storage, StorageMut::Buf(_)) || self.len() <= BUF_SIZE as usize
+    }
+
+    /// Moves the filled portion into `next_b`, which becomes the storage
+    fn realloc_into(&mut self, mut next_b: BufMut) {
+        next_b[..self.len()].copy_from_slice(&self[..]);
+        self.storage = StorageMut::Buf(next_b);
+    }
+
     /// Reserve more capacity for this buffer if this buffer is full.
     /// If this buffer's size matches the underlying storage size,
     /// this is equivalent to `grow`. Otherwise, it's equivalent
@@ -201,6 +228,38 @@ impl RollMut {
         Ok(())
     }
 
+    /// Like [RollMut::reserve], but if that takes a block from the pool and
+    /// the pool is exhausted, waits for one, see [BufMut::alloc_wait].
+    pub async fn reserve_wait(&mut self) -> Result<()> {
+        if self.len() < self.cap() {
+            return Ok(());
+        }
+
+        if self.len() < self.storage_size() && self.realloc_needs_buf() {
+            let next_b = BufMut::alloc_wait().await?;
+            self.realloc_into(next_b);
+            return Ok(());
+        }
+        self.reserve()
+    }
+
+    /// If [RollMut::grow] took the storage past `max_storage_size`, moves
+    /// the filled part back into a buffer from the pool, provided it fits.
+    /// Returns whether it did.
+    pub fn shrink(&mut self, max_storage_size: usize) -> Result<bool> {
+        if matches!(self.storage, StorageMut::Buf(_))
+            || self.storage_size() <= max_storage_size
+            || self.len() > BUF_SIZE as usize
+        {
+            return Ok(false);
+        }
+
+        let mut next_b = BufMut::alloc()?;
+        next_b[..self.len()].copy_from_slice(&self[..]);
+        self.storage = StorageMut::Buf(next_b);
+        Ok(true)
+    }
+
     /// Make sure we can hold "request_len"
     pub fn reserve_at_least(&mut self, requested_len: usize) -> Result<()> {
         while self.cap() < requested_len {
@@ -271,6 +330,73 @@ impl RollMut {
         (res, read_into.buf)
     }
 
+    /// Like [RollMut::read_into], but also reads into up to `extra` fresh
+    /// buffers from the pool, in a single vectored read: for when a lot of
+    /// data is expected (a large request body), so that reads aren't capped
+    /// at what's left of this buffer.
+    ///
+    /// What's read into this buffer is appended to its filled part, as with
+    /// [RollMut::read_into]. What's read past it comes back as a list of
+    /// [Roll], in order. Fewer extra buffers are used if `limit` doesn't
+    /// call for them, or if the pool runs out.
+    ///
+    /// Panics if `cap` is zero
+    pub async fn readv_into(
+        self,
+        limit: usize,
+        extra: usize,
+        r: &mut impl ReadOwned,
+    ) -> (std::io::Result<usize>, Self, Vec<Roll>) {
+        let read_cap = std::cmp::min(limit, self.cap());
+        assert!(read_cap > 0, "refusing to do empty read");
+
+        let mut reads = vec![ReadInto {
+            off: self.len,
+            cap: read_cap.try_into().unwrap(),
+            buf: self,
+        }];
+        let mut left = limit - read_cap;
+        while left > 0 && reads.len() <= extra {
+            let Ok(buf) = RollMut::alloc() else {
+                trace!("buffer pool exhausted, reading into fewer buffers");
+                break;
+            };
+            let cap = std::cmp::min(left, buf.cap());
+            left -= cap;
+            reads.push(ReadInto {
+                buf,
+                off: 0,
+                cap: cap.try_into().unwrap(),
+            });
+        }
+
+        tracing::trace!(%read_cap, buffers = %reads.len(), "readv_into in progress...");
+        let (res, reads) = r.readv_owned(reads).await;
+        let mut reads = reads.into_iter();
+        let mut first = reads.next().unwrap();
+        let mut rolls = Vec::new();
+        match &res {
+            Ok(n) => {
+                tracing::trace!("readv_into got {} bytes", *n);
+                let mut n = *n;
+                let got = std::cmp::min(n, first.cap as usize);
+                first.buf.len += got as u32;
+                n -= got;
+                for mut read in reads {
+                    if n == 0 {
+                        break;
+                    }
+                    let got = std::cmp::min(n, read.cap as usize);
+                    read.buf.len += got as u32;
+                    n -= got;
+                    rolls.push(read.buf.take_all());
+                }
+            }
+            Err(e) => tracing::trace!("readv_into failed: {e:?}"),
+        }
+        (res, first.buf, rolls)
+    }
+
     /// Put a slice into this buffer, fails if the slice doesn't fit in the buffer's capacity
     pub fn put(&mut self, s: impl AsRef<[u8]>) -> Result<()> {
         let s = s.as_ref();
@@ -638,6 +764,15 @@ impl Roll {
         self.len() == 0
     }
 
+    /// The pool block this roll points into, if it's backed by one (rather
+    /// than a heap allocation)
+    pub fn block(&self) -> Option<BlockId> {
+        match &self.inner {
+            RollInner::Buf(b) => Some(b.block()),
+            _ => None,
+        }
+    }
+
     pub fn split_at(self, at: usize) -> (Roll, Roll) {
         let (left, right) = self.inner.split_at(at);
         (left.into(), right.into())
@@ -1007,6 +1142,29 @@ mod tests {
         assert_eq!(&rm[..], put.as_bytes());
     }
 
+    #[test]
+    fn test_roll_shrink() {
+        let mut rm = RollMut::alloc().unwrap();
+        assert!(!rm.shrink(0).unwrap(), "already the smallest storage");
+
+        rm.grow();
+        rm.grow();
+        rm.put("x".repeat(BUF_SIZE as usize * 3)).unwrap();
+        rm.skip(BUF_SIZE as usize * 3 - 5);
+        rm.put("hello").unwrap();
+        assert_eq!(rm.storage_size(), BUF_SIZE as usize * 4);
+
+        assert!(!rm.shrink(BUF_SIZE as usize * 4).unwrap());
+        assert!(rm.shrink(BUF_SIZE as usize * 2).unwrap());
+        assert_eq!(rm.storage_size(), BUF_SIZE as usize);
+        assert_eq!(&rm[..], b"xxxxxhello");
+
+        // too much filled to fit in a pool buffer
+        rm.grow();
+        rm.put("x".repeat(BUF_SIZE as usize)).unwrap();
+        assert!(!rm.shrink(0).unwrap());
+    }
+
     #[test]
     fn test_roll_reserve() {
         let mut rm = RollMut::alloc().unwrap();
@@ -1089,6 +1247,42 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_roll_readv_into() {
+        use crate::WriteOwned;
+
+        crate::start(async move {
+            let mut rm = RollMut::alloc().unwrap();
+            rm.put(vec![b'a'; BUF_SIZE as usize - 4]).unwrap();
+
+            let (mut send, mut read) = crate::pipe();
+            crate::spawn(async move {
+                let mut data = b"bbbb".to_vec();
+                data.extend(vec![b'c'; BUF_SIZE as usize]);
+                data.extend(b"dddd");
+                send.write_all_owned(data).await.unwrap();
+            });
+
+            // the limit leaves the last byte for later
+            let (res, rm, rolls) = rm.readv_into(BUF_SIZE as usize + 7, 4, &mut read).await;
+            assert_eq!(res.unwrap(), BUF_SIZE as usize + 7);
+            assert_eq!(rm.len(), BUF_SIZE as usize);
+            assert_eq!(&rm[rm.len() - 4..], b"bbbb");
+            assert_eq!(rolls.len(), 2);
+            assert_eq!(rolls[0].len(), BUF_SIZE as usize);
+            assert!(rolls[0][..].iter().all(|&b| b == b'c'));
+            assert_eq!(rolls[1], b"ddd");
+
+            // readers that don't do vectored reads fill the first buffer
+            let rm = RollMut::alloc().unwrap();
+            let mut reader: &[u8] = b"hello";
+            let (res, rm, rolls) = rm.readv_into(usize::MAX, 4, &mut reader).await;
+            assert_eq!(res.unwrap(), 5);
+            assert_eq!(&rm[..], b"hello");
+            assert!(rolls.is_empty());
+        });
+    }
+
     #[test]
     fn test_roll_keep() {
         fn test_roll_keep_inner(mut rm: RollMut) {
@@ -1336,4 +1530,20 @@ mod tests {
         let roll = rm.take_all();
         assert_eq!(std::str::from_utf8(&roll).unwrap(), "hello");
     }
+
+    #[test]
+    fn test_scratch_roll() {
+        let a = crate::scratch_roll(5, |slice| {
+            slice.copy_from_slice(b"hello");
+            Ok(())
+        })
+        .unwrap();
+        let b = crate::scratch_roll(6, |slice| {
+            slice.copy_from_slice(b" world");
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(&a[..], b"hello");
+        assert_eq!(&b[..], b" world");
+    }
 }
@@ -1,8 +1,87 @@
-use std::rc::Rc;
+use std::{
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
 
-use fluke_io_uring_async::IoUringAsync;
+use fluke_io_uring_async::{IoUringAsync, Op};
+use nix::errno::Errno;
 
 /// Returns the thread-local IoUringAsync instance
 pub fn get_ring() -> Rc<IoUringAsync> {
     fluke_io_uring_async::get_ring()
 }
+
+/// Turns completion results into errno errors
+pub(crate) trait CqueueExt {
+    fn error_for_errno(&self) -> Result<i32, Errno>;
+}
+
+impl CqueueExt for io_uring::cqueue::Entry {
+    fn error_for_errno(&self) -> Result<i32, Errno> {
+        let res = self.result();
+        if res < 0 {
+            Err(Errno::from_raw(-res))
+        } else {
+            Ok(res as _)
+        }
+    }
+}
+
+/// An io_uring op along with the buffers it points into. If it's dropped
+/// before completing, the op is left to finish in a separate task, which
+/// keeps the buffers alive until the kernel is done with them.
+pub(crate) struct InFlight<T: 'static> {
+    op: Option<Op<io_uring::cqueue::Entry>>,
+    keep: Option<T>,
+    orphaned: Option<fn(io_uring::cqueue::Entry)>,
+}
+
+impl<T: 'static> InFlight<T> {
+    pub(crate) fn new(op: Op<io_uring::cqueue::Entry>, keep: T) -> Self {
+        Self {
+            op: Some(op),
+            keep: Some(keep),
+            orphaned: None,
+        }
+    }
+
+    /// Gets the completion of an op that was dropped before completing,
+    /// for ops that hand out resources (like file descriptors) that would
+    /// otherwise leak.
+    pub(crate) fn if_orphaned(mut self, f: fn(io_uring::cqueue::Entry)) -> Self {
+        self.orphaned = Some(f);
+        self
+    }
+}
+
+impl<T: Unpin + 'static> Future for InFlight<T> {
+    type Output = (io_uring::cqueue::Entry, T);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let cqe = match Pin::new(this.op.as_mut().unwrap()).poll(cx) {
+            Poll::Ready(cqe) => cqe,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.op = None;
+        Poll::Ready((cqe, this.keep.take().unwrap()))
+    }
+}
+
+impl<T: 'static> Drop for InFlight<T> {
+    fn drop(&mut self) {
+        if let Some(op) = self.op.take() {
+            let keep = self.keep.take();
+            let orphaned = self.orphaned.take();
+            crate::spawn(async move {
+                let cqe = op.await;
+                drop(keep);
+                if let Some(f) = orphaned {
+                    f(cqe);
+                }
+            });
+        }
+    }
+}
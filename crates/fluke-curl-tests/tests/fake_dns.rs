@@ -0,0 +1,77 @@
+//! A DNS server for the proxy tests, that answers SRV queries from a fixed
+//! list of records, and A queries for their targets with `127.0.0.1`.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    proto::{
+        op::{Message, MessageType},
+        rr::{
+            rdata::{A, SRV},
+            Name, RData, Record, RecordType,
+        },
+    },
+    TokioAsyncResolver,
+};
+use tracing::debug;
+
+/// Serves `records` for `service` until the runtime is done, returns a
+/// resolver that asks it and nothing else
+pub async fn start(service: &str, records: Vec<SRV>) -> eyre::Result<TokioAsyncResolver> {
+    let service = Name::from_ascii(service)?;
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+
+    fluke::buffet::spawn(async move {
+        let mut buf = vec![0u8; 512];
+        while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+            let Ok(query) = Message::from_vec(&buf[..n]) else {
+                debug!("fake DNS server got garbage from {peer}");
+                continue;
+            };
+
+            let mut res = Message::new();
+            res.set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(query.op_code())
+                .set_recursion_desired(query.recursion_desired())
+                .set_recursion_available(true)
+                .add_queries(query.queries().to_vec());
+            for q in query.queries() {
+                match q.query_type() {
+                    RecordType::SRV if *q.name() == service => {
+                        for srv in &records {
+                            res.add_answer(Record::from_rdata(
+                                q.name().clone(),
+                                60,
+                                RData::SRV(srv.clone()),
+                            ));
+                        }
+                    }
+                    RecordType::A if records.iter().any(|srv| srv.target() == q.name()) => {
+                        res.add_answer(Record::from_rdata(
+                            q.name().clone(),
+                            60,
+                            RData::A(A(Ipv4Addr::LOCALHOST)),
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+
+            let Ok(res) = res.to_vec() else {
+                continue;
+            };
+            _ = socket.send_to(&res, peer).await;
+        }
+    });
+
+    Ok(resolver(addr))
+}
+
+fn resolver(addr: SocketAddr) -> TokioAsyncResolver {
+    let name_servers = NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+    let config = ResolverConfig::from_parts(None, vec![], name_servers);
+    TokioAsyncResolver::tokio(config, ResolverOpts::default())
+}
@@ -1,23 +1,35 @@
-mod helpers;
-
 use bytes::BytesMut;
 use fluke::buffet::{IntoHalves, ReadOwned, WriteOwned};
 use fluke::{
     buffet::{PieceCore, RollMut},
-    h1, h2, Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, HeadersExt, Method, Request,
-    Responder, Response, ResponseDone, ServerDriver,
+    h1, Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, HeadersExt, InterimResponse,
+    Method, Request, Responder, Response, ResponseDone, ServerDriver,
 };
+use fluke_testutils::{Proto, TestServer};
+use fluke_tls::{PinnedCerts, UpstreamTlsConf};
+use hickory_resolver::proto::rr::{rdata::SRV, Name};
 use http::{header, StatusCode};
 use httparse::{Status, EMPTY_HEADER};
 use pretty_assertions::assert_eq;
 use pretty_hex::PrettyHex;
-use std::{future::Future, net::SocketAddr, process::Command, rc::Rc, time::Duration};
+use proxy::UpstreamSource;
+use rcgen::ExtendedKeyUsagePurpose;
+use std::{
+    cell::{Cell, RefCell},
+    net::{Ipv4Addr, SocketAddr},
+    process::Command,
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tracing::debug;
 
+mod fake_dns;
 mod proxy;
 mod testbed;
+mod tls_upstream;
 
 // Test ideas:
 // headers too large (ups/dos)
@@ -40,7 +52,7 @@ mod testbed;
 
 #[test]
 fn serve_api() {
-    helpers::run(async move {
+    fluke_testutils::run(async move {
         let conf = h1::ServerConf::default();
         let conf = Rc::new(conf);
 
@@ -57,11 +69,8 @@ fn serve_api() {
 
                 buf.put(b"Continue")?;
 
-                res.write_interim_response(Response {
-                    status: StatusCode::CONTINUE,
-                    ..Default::default()
-                })
-                .await?;
+                res.write_interim_response(InterimResponse::continue_100())
+                    .await?;
 
                 buf.put(b"OK")?;
 
@@ -138,17 +147,79 @@ fn serve_api() {
     })
 }
 
+#[test]
+fn pipelined_after_large_head() {
+    fluke_testutils::run(async move {
+        let conf = Rc::new(h1::ServerConf {
+            max_retained_read_buf: 0,
+            ..Default::default()
+        });
+
+        struct TestDriver;
+
+        impl ServerDriver for TestDriver {
+            async fn handle<E: Encoder>(
+                &self,
+                req: fluke::Request,
+                _req_body: &mut impl Body,
+                res: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let body = req.uri.path().to_owned();
+                res.write_final_response_with_body(
+                    Response::default(),
+                    &mut fluke::body::once(body.into_bytes()),
+                )
+                .await
+            }
+        }
+
+        let (mut client_write, server_read) = fluke::buffet::pipe();
+        let (server_write, mut client_read) = fluke::buffet::pipe();
+        let serve_fut = fluke::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            conf,
+            RollMut::alloc()?,
+            TestDriver,
+        ));
+
+        // the first head makes the read buffer grow, the second one is
+        // already in it when the first response is out.
+        let large = format!(
+            "GET /large HTTP/1.1\r\nx-padding: {}\r\n\r\n",
+            "a".repeat(5000)
+        );
+        let mut requests = large.repeat(2);
+        requests.push_str("GET /small HTTP/1.1\r\nconnection: close\r\n\r\n");
+        client_write.write_all_owned(requests.into_bytes()).await?;
+
+        let mut res_buf = Vec::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+        let res = String::from_utf8(res_buf)?;
+        assert_eq!(res.matches("HTTP/1.1 200 OK").count(), 3, "{res}");
+        assert!(res.ends_with("/small"), "{res}");
+
+        tokio::time::timeout(Duration::from_secs(5), serve_fut).await???;
+
+        Ok(())
+    })
+}
+
 #[test]
 fn request_api() {
-    helpers::run(async move {
+    fluke_testutils::run(async move {
         let (mut server_write, client_read) = fluke::buffet::pipe();
         let (client_write, mut server_read) = fluke::buffet::pipe();
 
-        let req = Request {
-            method: Method::Get,
-            uri: "/".parse().unwrap(),
-            ..Default::default()
-        };
+        let (req, mut body) = Request::builder().method(Method::Get).uri("/").build()?;
 
         struct TestDriver;
 
@@ -170,18 +241,15 @@ fn request_api() {
                     res.headers.is_chunked_transfer_encoding(),
                 );
 
-                while let BodyChunk::Chunk(chunk) = body.next_chunk().await? {
-                    debug!("got a chunk: {:?}", chunk.hex_dump());
-                }
+                let body = body.collect(1024 * 1024).await?;
+                debug!("got a body: {:?}", body.hex_dump());
 
                 Ok(())
             }
         }
 
         let driver = TestDriver;
-        let request_fut = fluke::buffet::spawn(async {
-            #[allow(clippy::let_unit_value)]
-            let mut body = ();
+        let request_fut = fluke::buffet::spawn(async move {
             h1::request((client_read, client_write), req, &mut body, driver).await
         });
 
@@ -227,6 +295,105 @@ fn request_api() {
     })
 }
 
+#[test]
+fn request_expect_continue() {
+    struct StatusDriver;
+
+    impl h1::ClientDriver for StatusDriver {
+        type Return = StatusCode;
+
+        async fn on_informational_response(&mut self, res: Response) -> eyre::Result<()> {
+            assert_eq!(res.status, StatusCode::CONTINUE);
+            Ok(())
+        }
+
+        async fn on_final_response(
+            self,
+            res: Response,
+            body: &mut impl Body,
+        ) -> eyre::Result<Self::Return> {
+            body.collect(1024).await?;
+            Ok(res.status)
+        }
+    }
+
+    async fn read_head(read: &mut impl ReadOwned) -> eyre::Result<String> {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let (res, buf) = read.read_owned(vec![0u8; 1]).await;
+            match res? {
+                0 => eyre::bail!("eof before end of request head"),
+                _ => head.push(buf[0]),
+            }
+        }
+        Ok(String::from_utf8(head)?)
+    }
+
+    fluke_testutils::run(async move {
+        let conf = h1::ClientConf {
+            expect_continue: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+
+        for status in [StatusCode::OK, StatusCode::PAYLOAD_TOO_LARGE] {
+            let (mut server_write, client_read) = fluke::buffet::pipe();
+            let (client_write, mut server_read) = fluke::buffet::pipe();
+
+            let (req, mut body) = Request::builder()
+                .method(Method::Post)
+                .uri("/upload")
+                .body_piece("hello")
+                .build()?;
+            let conf = conf.clone();
+            let request_fut = fluke::buffet::spawn(async move {
+                let (transport, status) = h1::request_with(
+                    (client_read, client_write),
+                    req,
+                    &mut body,
+                    StatusDriver,
+                    &conf,
+                )
+                .await?;
+                Ok::<_, eyre::Report>((transport.is_some(), status))
+            });
+
+            let head = read_head(&mut server_read).await?;
+            assert!(head.contains("expect: 100-continue\r\n"), "{head}");
+
+            if status == StatusCode::OK {
+                server_write
+                    .write_all_owned("HTTP/1.1 100 Continue\r\n\r\n")
+                    .await?;
+                let mut body = vec![0u8; 5];
+                let res;
+                (res, body) = server_read.read_owned(body).await;
+                assert_eq!(res?, 5);
+                assert_eq!(&body[..], b"hello");
+            }
+
+            server_write
+                .write_all_owned(
+                    format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\n\r\n").into_bytes(),
+                )
+                .await?;
+
+            let (reusable, got) =
+                tokio::time::timeout(Duration::from_secs(5), request_fut).await???;
+            assert_eq!(got, status);
+            if status == StatusCode::OK {
+                assert!(reusable);
+            } else {
+                // the body was never sent, so the connection can't be reused
+                assert!(!reusable);
+                let (res, _) = server_read.read_owned(vec![0u8; 16]).await;
+                assert_eq!(res?, 0);
+            }
+        }
+
+        Ok(())
+    })
+}
+
 #[test]
 fn proxy_statuses() {
     #[allow(drop_bounds)]
@@ -271,7 +438,7 @@ fn proxy_statuses() {
         Ok(())
     }
 
-    helpers::run(async move {
+    fluke_testutils::run(async move {
         let (upstream_addr, _upstream_guard) = testbed::start().await?;
         let (ln_addr, guard, proxy_fut) = proxy::start(upstream_addr).await?;
         let client_fut = client(ln_addr, guard);
@@ -376,7 +543,7 @@ fn proxy_echo_body_content_len() {
         Ok(())
     }
 
-    helpers::run(async move {
+    fluke_testutils::run(async move {
         let (upstream_addr, _upstream_guard) = testbed::start().await?;
         let (ln_addr, guard, proxy_fut) = proxy::start(upstream_addr).await?;
         let client_fut = client(ln_addr, guard);
@@ -526,7 +693,7 @@ fn proxy_echo_body_chunked() {
         Ok(())
     }
 
-    helpers::run(async move {
+    fluke_testutils::run(async move {
         let (upstream_addr, _upstream_guard) = testbed::start().await?;
         let (ln_addr, guard, proxy_fut) = proxy::start(upstream_addr).await?;
         let client_fut = client(ln_addr, guard);
@@ -582,7 +749,7 @@ fn curl_echo_body(typ: BodyType) {
         Ok(())
     }
 
-    helpers::run(async move {
+    fluke_testutils::run(async move {
         let (upstream_addr, _upstream_guard) = testbed::start().await?;
         let (ln_addr, guard, proxy_fut) = proxy::start(upstream_addr).await?;
         let client_fut = async move {
@@ -609,8 +776,7 @@ fn curl_echo_body_noproxy_chunked() {
 }
 
 fn curl_echo_body_noproxy(typ: BodyType) {
-    #[allow(drop_bounds)]
-    fn client(typ: BodyType, ln_addr: SocketAddr, _guard: impl Drop) -> eyre::Result<()> {
+    fn client(typ: BodyType, ln_addr: SocketAddr) -> eyre::Result<()> {
         let req_body = "Please return to sender";
         let mut cmd = Command::new("curl");
 
@@ -637,114 +803,47 @@ fn curl_echo_body_noproxy(typ: BodyType) {
         Ok(())
     }
 
-    async fn start_server() -> eyre::Result<(
-        SocketAddr,
-        impl Drop,
-        impl Future<Output = eyre::Result<()>>,
-    )> {
-        let (tx, mut rx) = tokio::sync::oneshot::channel::<()>();
-
-        let ln = fluke::buffet::net::TcpListener::bind("127.0.0.1:0".parse()?).await?;
-        let ln_addr = ln.local_addr()?;
-
-        struct TestDriver;
-
-        impl ServerDriver for TestDriver {
-            async fn handle<E: Encoder>(
-                &self,
-                req: Request,
-                req_body: &mut impl Body,
-                mut respond: Responder<E, ExpectResponseHeaders>,
-            ) -> eyre::Result<Responder<E, ResponseDone>> {
-                if req.headers.expects_100_continue() {
-                    debug!("Sending 100-continue");
-                    let res = Response {
-                        status: StatusCode::CONTINUE,
-                        ..Default::default()
-                    };
-                    respond.write_interim_response(res).await?;
-                }
-
-                debug!("Writing final response");
-                let res = Response {
-                    status: StatusCode::OK,
-                    headers: {
-                        let mut headers = Headers::default();
-                        headers.insert(header::SERVER, "integration-test/1.0".into());
-                        headers
-                    },
-                    ..Default::default()
-                };
-                let respond = respond
-                    .write_final_response_with_body(res, req_body)
+    struct TestDriver;
+
+    impl ServerDriver for TestDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            req_body: &mut impl Body,
+            mut respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            if req.headers.expects_100_continue() {
+                debug!("Sending 100-continue");
+                respond
+                    .write_interim_response(InterimResponse::continue_100())
                     .await?;
-
-                debug!("Wrote final response");
-                Ok(respond)
             }
-        }
 
-        let server_fut = async move {
-            let conf = Rc::new(h1::ServerConf::default());
-
-            enum Event {
-                Accepted((fluke::buffet::net::TcpStream, SocketAddr)),
-                ShuttingDown,
-            }
-
-            loop {
-                let ev = tokio::select! {
-                    accept_res = ln.accept() => {
-                        Event::Accepted(accept_res?)
-                    },
-                    _ = &mut rx => {
-                        Event::ShuttingDown
-                    }
-                };
-
-                match ev {
-                    Event::Accepted((transport, remote_addr)) => {
-                        debug!("Accepted connection from {remote_addr}");
-
-                        let conf = conf.clone();
-
-                        fluke::buffet::spawn(async move {
-                            let driver = TestDriver;
-                            h1::serve(
-                                transport.into_halves(),
-                                conf,
-                                RollMut::alloc().unwrap(),
-                                driver,
-                            )
-                            .await
-                            .unwrap();
-                            debug!("Done serving h1 connection");
-                        });
-                    }
-                    Event::ShuttingDown => {
-                        debug!("Shutting down server");
-                        break;
-                    }
-                }
-            }
-
-            debug!("Server shutting down.");
-
-            Ok(())
-        };
+            debug!("Writing final response");
+            let res = Response {
+                status: StatusCode::OK,
+                headers: {
+                    let mut headers = Headers::default();
+                    headers.insert(header::SERVER, "integration-test/1.0".into());
+                    headers
+                },
+                ..Default::default()
+            };
+            let respond = respond
+                .write_final_response_with_body(res, req_body)
+                .await?;
 
-        Ok((ln_addr, tx, server_fut))
+            debug!("Wrote final response");
+            Ok(respond)
+        }
     }
 
-    helpers::run(async move {
-        let (ln_addr, guard, server_fut) = start_server().await?;
-        let client_fut = async move {
-            tokio::task::spawn_blocking(move || client(typ, ln_addr, guard))
-                .await
-                .unwrap()
-        };
+    fluke_testutils::run(async move {
+        let server = TestServer::start(Proto::H1, TestDriver).await?;
+        let ln_addr = server.addr();
+        tokio::task::spawn_blocking(move || client(typ, ln_addr)).await??;
 
-        tokio::try_join!(server_fut, client_fut)?;
+        server.shutdown().await?;
         debug!("everything has been joined");
 
         Ok(())
@@ -753,8 +852,7 @@ fn curl_echo_body_noproxy(typ: BodyType) {
 
 #[test]
 fn h2_basic_post() {
-    #[allow(drop_bounds)]
-    fn client(ln_addr: SocketAddr, _guard: impl Drop) -> eyre::Result<()> {
+    fn client(ln_addr: SocketAddr) -> eyre::Result<()> {
         let req_body = "Please return to sender";
         let mut cmd = Command::new("curl");
 
@@ -775,114 +873,42 @@ fn h2_basic_post() {
         Ok(())
     }
 
-    async fn start_server() -> eyre::Result<(
-        SocketAddr,
-        impl Drop,
-        impl Future<Output = eyre::Result<()>>,
-    )> {
-        let (tx, mut rx) = tokio::sync::oneshot::channel::<()>();
-
-        let listen_port: u16 = std::env::var("LISTEN_PORT")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or_default();
-        let ln = fluke::buffet::net::TcpListener::bind(format!("127.0.0.1:{listen_port}").parse()?)
-            .await?;
-        let ln_addr = ln.local_addr()?;
-
-        struct TestDriver;
-
-        impl ServerDriver for TestDriver {
-            async fn handle<E: Encoder>(
-                &self,
-                req: Request,
-                req_body: &mut impl Body,
-                respond: Responder<E, ExpectResponseHeaders>,
-            ) -> eyre::Result<Responder<E, ResponseDone>> {
-                debug!("Got request {req:#?}");
-
-                debug!("Writing final response");
-                let res = Response {
-                    status: StatusCode::OK,
-                    headers: {
-                        let mut headers = Headers::default();
-                        headers.insert(header::SERVER, "integration-test/1.0".into());
-                        headers
-                    },
-                    ..Default::default()
-                };
-                let respond = respond
-                    .write_final_response_with_body(res, req_body)
-                    .await?;
+    struct TestDriver;
+
+    impl ServerDriver for TestDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            debug!("Got request {req:#?}");
+
+            debug!("Writing final response");
+            let res = Response {
+                status: StatusCode::OK,
+                headers: {
+                    let mut headers = Headers::default();
+                    headers.insert(header::SERVER, "integration-test/1.0".into());
+                    headers
+                },
+                ..Default::default()
+            };
+            let respond = respond
+                .write_final_response_with_body(res, req_body)
+                .await?;
 
-                debug!("Wrote final response");
-                Ok(respond)
-            }
+            debug!("Wrote final response");
+            Ok(respond)
         }
-
-        let driver = Rc::new(TestDriver);
-
-        let server_fut = async move {
-            let conf = Rc::new(h2::ServerConf::default());
-
-            enum Event {
-                Accepted((fluke::buffet::net::TcpStream, SocketAddr)),
-                ShuttingDown,
-            }
-
-            loop {
-                let ev = tokio::select! {
-                    accept_res = ln.accept() => {
-                        Event::Accepted(accept_res?)
-                    },
-                    _ = &mut rx => {
-                        Event::ShuttingDown
-                    }
-                };
-
-                match ev {
-                    Event::Accepted((transport, remote_addr)) => {
-                        debug!("Accepted connection from {remote_addr}");
-
-                        let conf = conf.clone();
-                        let driver = driver.clone();
-
-                        fluke::buffet::spawn(async move {
-                            h2::serve(
-                                transport.into_halves(),
-                                conf,
-                                RollMut::alloc().unwrap(),
-                                driver,
-                            )
-                            .await
-                            .unwrap();
-                            debug!("Done serving h1 connection");
-                        });
-                    }
-                    Event::ShuttingDown => {
-                        debug!("Shutting down server");
-                        break;
-                    }
-                }
-            }
-
-            debug!("Server shutting down.");
-
-            Ok(())
-        };
-
-        Ok((ln_addr, tx, server_fut))
     }
 
-    helpers::run(async move {
-        let (ln_addr, guard, server_fut) = start_server().await?;
-        let client_fut = async move {
-            tokio::task::spawn_blocking(move || client(ln_addr, guard))
-                .await
-                .unwrap()
-        };
+    fluke_testutils::run(async move {
+        let server = TestServer::start(Proto::H2, TestDriver).await?;
+        let ln_addr = server.addr();
+        tokio::task::spawn_blocking(move || client(ln_addr)).await??;
 
-        tokio::try_join!(server_fut, client_fut)?;
+        server.shutdown().await?;
         debug!("everything has been joined");
 
         Ok(())
@@ -924,11 +950,9 @@ impl Body for SampleBody {
     }
 }
 
-// TODO: dedup with h2_basic_post
 #[test]
 fn h2_basic_get() {
-    #[allow(drop_bounds)]
-    fn client(ln_addr: SocketAddr, _guard: impl Drop) -> eyre::Result<()> {
+    fn client(ln_addr: SocketAddr) -> eyre::Result<()> {
         let mut cmd = Command::new("curl");
 
         cmd.arg("--silent");
@@ -944,116 +968,453 @@ fn h2_basic_get() {
         Ok(())
     }
 
-    async fn start_server() -> eyre::Result<(
-        SocketAddr,
-        impl Drop,
-        impl Future<Output = eyre::Result<()>>,
-    )> {
-        let (tx, mut rx) = tokio::sync::oneshot::channel::<()>();
-
-        let listen_port: u16 = std::env::var("LISTEN_PORT")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or_default();
-        let ln = fluke::buffet::net::TcpListener::bind(format!("127.0.0.1:{listen_port}").parse()?)
-            .await?;
-        let ln_addr = ln.local_addr()?;
+    struct TestDriver;
+
+    impl ServerDriver for TestDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            debug!("Got request {req:#?}");
+
+            debug!("Writing final response");
+            let res = Response {
+                status: StatusCode::OK,
+                headers: {
+                    let mut headers = Headers::default();
+                    headers.insert(header::SERVER, "integration-test/1.0".into());
+                    headers
+                },
+                ..Default::default()
+            };
+            let respond = respond
+                .write_final_response_with_body(res, &mut SampleBody::default())
+                .await?;
 
-        struct TestDriver;
+            debug!("Wrote final response");
+            Ok(respond)
+        }
+    }
 
-        impl ServerDriver for TestDriver {
-            async fn handle<E: Encoder>(
-                &self,
-                req: Request,
-                _req_body: &mut impl Body,
-                respond: Responder<E, ExpectResponseHeaders>,
-            ) -> eyre::Result<Responder<E, ResponseDone>> {
-                debug!("Got request {req:#?}");
-
-                debug!("Writing final response");
-                let res = Response {
-                    status: StatusCode::OK,
-                    headers: {
-                        let mut headers = Headers::default();
-                        headers.insert(header::SERVER, "integration-test/1.0".into());
-                        headers
-                    },
-                    ..Default::default()
-                };
-                let respond = respond
-                    .write_final_response_with_body(res, &mut SampleBody::default())
-                    .await?;
+    fluke_testutils::run(async move {
+        let server = TestServer::start(Proto::H2, TestDriver).await?;
+        let ln_addr = server.addr();
+        tokio::task::spawn_blocking(move || client(ln_addr)).await??;
 
-                debug!("Wrote final response");
-                Ok(respond)
-            }
-        }
+        server.shutdown().await?;
+        debug!("everything has been joined");
 
-        let driver = Rc::new(TestDriver);
+        Ok(())
+    });
+}
 
-        let server_fut = async move {
-            let conf = Rc::new(h2::ServerConf::default());
+#[test]
+fn proxy_hooks() {
+    struct Hooks;
 
-            enum Event {
-                Accepted((fluke::buffet::net::TcpStream, SocketAddr)),
-                ShuttingDown,
+    impl proxy::ProxyHooks for Hooks {
+        async fn on_request_head(&self, req: &mut Request) -> eyre::Result<()> {
+            if let Some(path) = req.uri.path().strip_prefix("/api") {
+                req.uri = path.parse()?;
             }
+            req.headers
+                .insert(header::AUTHORIZATION, "Bearer hunter2".into());
+            Ok(())
+        }
 
-            loop {
-                let ev = tokio::select! {
-                    accept_res = ln.accept() => {
-                        Event::Accepted(accept_res?)
-                    },
-                    _ = &mut rx => {
-                        Event::ShuttingDown
-                    }
-                };
+        async fn on_response_head(&self, res: &mut Response) -> eyre::Result<()> {
+            res.headers.remove("x-internal");
+            res.headers.insert(header::VIA, "1.1 fluke".into());
+            Ok(())
+        }
+    }
 
-                match ev {
-                    Event::Accepted((transport, remote_addr)) => {
-                        debug!("Accepted connection from {remote_addr}");
-
-                        let conf = conf.clone();
-                        let driver = driver.clone();
-
-                        fluke::buffet::spawn(async move {
-                            h2::serve(
-                                transport.into_halves(),
-                                conf,
-                                RollMut::alloc().unwrap(),
-                                driver,
-                            )
-                            .await
-                            .unwrap();
-                            debug!("Done serving h1 connection");
-                        });
-                    }
-                    Event::ShuttingDown => {
-                        debug!("Shutting down server");
-                        break;
-                    }
-                }
+    /// Replies with what it was asked for, and who asked
+    struct UpstreamDriver;
+
+    impl ServerDriver for UpstreamDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let mut headers = Headers::default();
+            headers.insert("x-internal", "secret".into());
+            if let Some(auth) = req.headers.get(header::AUTHORIZATION) {
+                headers.insert("x-seen-authorization", auth.clone());
             }
+            let res = Response {
+                headers,
+                ..Default::default()
+            };
+            let mut body = fluke::body::once(req.uri.path().to_owned().into_bytes());
+            respond.write_final_response_with_body(res, &mut body).await
+        }
+    }
+
+    fluke_testutils::run(async move {
+        let upstream = TestServer::start(Proto::H1, UpstreamDriver).await?;
+        let (ln_addr, guard, proxy_fut) = proxy::start_with_hooks(upstream.addr(), Hooks).await?;
+        let client_fut = async move {
+            let mut client = fluke_testutils::TestClient::connect(Proto::H1, ln_addr).await?;
+            let res = client.get("/api/users").await?;
+            drop(guard);
+
+            assert_eq!(res.status, StatusCode::OK);
+            assert_eq!(res.text(), "/users");
+            assert_eq!(res.headers["x-seen-authorization"], "Bearer hunter2");
+            assert_eq!(res.headers[header::VIA], "1.1 fluke");
+            assert!(!res.headers.contains_key("x-internal"));
+            Ok(())
+        };
 
-            debug!("Server shutting down.");
+        tokio::try_join!(proxy_fut, client_fut)?;
+        Ok(())
+    });
+}
 
+#[test]
+fn proxy_refreshes_upstreams() {
+    /// Replies with its name
+    struct NamedDriver(&'static str);
+
+    impl ServerDriver for NamedDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let mut body = fluke::body::once(self.0);
+            respond
+                .write_final_response_with_body(Response::default(), &mut body)
+                .await
+        }
+    }
+
+    fluke_testutils::run(async move {
+        let blue = TestServer::start(Proto::H1, NamedDriver("blue")).await?;
+        let green = TestServer::start(Proto::H1, NamedDriver("green")).await?;
+
+        // what the service registry currently says
+        let registry = Rc::new(RefCell::new(vec![blue.addr()]));
+        let upstreams = proxy::UpstreamSet::new([blue.addr()]);
+        fluke::buffet::spawn(proxy::refresh_upstreams(
+            upstreams.clone(),
+            {
+                let registry = registry.clone();
+                move || {
+                    let addrs = registry.borrow().clone();
+                    async move { Ok(addrs) }
+                }
+            },
+            Duration::from_millis(20),
+        ));
+
+        let (ln_addr, guard, proxy_fut) =
+            proxy::start_with_upstreams(upstreams, proxy::NoHooks).await?;
+        let client_fut = async move {
+            let mut client = fluke_testutils::TestClient::connect(Proto::H1, ln_addr).await?;
+            assert_eq!(client.get("/").await?.text(), "blue");
+
+            // an empty answer is a glitch, not a reason to stop forwarding
+            registry.borrow_mut().clear();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(client.get("/").await?.text(), "blue");
+
+            *registry.borrow_mut() = vec![green.addr()];
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(client.get("/").await?.text(), "green");
+            drop(guard);
             Ok(())
         };
 
-        Ok((ln_addr, tx, server_fut))
+        tokio::try_join!(proxy_fut, client_fut)?;
+        Ok(())
+    });
+}
+
+#[test]
+fn proxy_reloads_upstreams() {
+    /// Replies with its name
+    struct NamedDriver(&'static str);
+
+    impl ServerDriver for NamedDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let mut body = fluke::body::once(self.0);
+            respond
+                .write_final_response_with_body(Response::default(), &mut body)
+                .await
+        }
     }
 
-    helpers::run(async move {
-        let (ln_addr, guard, server_fut) = start_server().await?;
+    fluke_testutils::run(async move {
+        let blue = TestServer::start(Proto::H1, NamedDriver("blue")).await?;
+        let green = TestServer::start(Proto::H1, NamedDriver("green")).await?;
+
+        let upstreams = proxy::UpstreamSet::new([blue.addr()]);
+        let conf = upstreams.conf();
+        let (ln_addr, guard, proxy_fut) =
+            proxy::start_with_upstreams(upstreams, proxy::NoHooks).await?;
         let client_fut = async move {
-            tokio::task::spawn_blocking(move || client(ln_addr, guard))
+            let mut client = fluke_testutils::TestClient::connect(Proto::H1, ln_addr).await?;
+            assert_eq!(client.get("/").await?.text(), "blue");
+
+            // e.g. from a signal handler
+            let green = green.addr();
+            std::thread::spawn(move || conf.store(vec![green]))
+                .join()
+                .unwrap();
+            assert_eq!(client.get("/").await?.text(), "green");
+            drop(guard);
+            Ok(())
+        };
+
+        tokio::try_join!(proxy_fut, client_fut)?;
+        Ok(())
+    });
+}
+
+#[test]
+fn proxy_closes_connections_to_removed_upstreams() {
+    /// Counts the connections it's seen closed
+    #[derive(Default)]
+    struct CountingDriver {
+        closed: Rc<Cell<usize>>,
+    }
+
+    impl ServerDriver for CountingDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let mut body = fluke::body::once("ok");
+            respond
+                .write_final_response_with_body(Response::default(), &mut body)
                 .await
-                .unwrap()
+        }
+
+        fn on_connection_closed(&self, _conn: &fluke::ConnInfo, _reason: &fluke::CloseReason) {
+            self.closed.set(self.closed.get() + 1);
+        }
+    }
+
+    fluke_testutils::run(async move {
+        let blue_driver = CountingDriver::default();
+        let blue_closed = blue_driver.closed.clone();
+        let blue = TestServer::start(Proto::H1, blue_driver).await?;
+        let green_driver = CountingDriver::default();
+        let green_closed = green_driver.closed.clone();
+        let green = TestServer::start(Proto::H1, green_driver).await?;
+
+        let upstreams = proxy::UpstreamSet::new([blue.addr()]);
+        let conf = upstreams.conf();
+        let (ln_addr, guard, proxy_fut) =
+            proxy::start_with_upstreams(upstreams, proxy::NoHooks).await?;
+        let client_fut = async move {
+            let mut client = fluke_testutils::TestClient::connect(Proto::H1, ln_addr).await?;
+            client.get("/").await?;
+
+            // the connection to blue sits idle in the pool until the next
+            // request, which finds blue gone
+            conf.store(vec![green.addr()]);
+            client.get("/").await?;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(blue_closed.get(), 1);
+            assert_eq!(green_closed.get(), 0);
+            drop(guard);
+            Ok(())
         };
 
-        tokio::try_join!(server_fut, client_fut)?;
-        debug!("everything has been joined");
+        tokio::try_join!(proxy_fut, client_fut)?;
+        Ok(())
+    });
+}
 
+#[test]
+fn proxy_srv_upstreams() {
+    fluke_testutils::run(async move {
+        let service = "_http._tcp.fluke.test.";
+        let resolver = fake_dns::start(
+            service,
+            vec![
+                SRV::new(10, 1, 8002, Name::from_ascii("b.fluke.test.")?),
+                SRV::new(10, 1, 8001, Name::from_ascii("a.fluke.test.")?),
+                // a fallback: not used while the others are listed
+                SRV::new(20, 1, 8003, Name::from_ascii("c.fluke.test.")?),
+                // the service isn't available there
+                SRV::new(10, 1, 8004, Name::root()),
+            ],
+        )
+        .await?;
+
+        let source = proxy::SrvSource::with_resolver(service, resolver);
+        let localhost = |port| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        assert_eq!(source.resolve().await?, [localhost(8001), localhost(8002)]);
+        Ok(())
+    });
+}
+
+/// Forwards a `GET /` through a proxy to `upstreams`, returns the body
+async fn get_through_proxy(upstreams: proxy::UpstreamSet) -> eyre::Result<String> {
+    let (ln_addr, guard, proxy_fut) =
+        proxy::start_with_upstreams(upstreams, proxy::NoHooks).await?;
+    let client_fut = async move {
+        let mut client = fluke_testutils::TestClient::connect(Proto::H1, ln_addr).await?;
+        let res = client.get("/").await;
+        drop(guard);
+        Ok(res)
+    };
+
+    let (_, res) = tokio::try_join!(proxy_fut, client_fut)?;
+    let res = res?;
+    eyre::ensure!(res.status == StatusCode::OK, "got a {}", res.status);
+    Ok(res.text().to_owned())
+}
+
+/// Upstream TLS, as configured for `ca`'s `localhost`
+fn upstream_tls(ca: &tls_upstream::TestCa) -> UpstreamTlsConf {
+    UpstreamTlsConf {
+        roots: vec![ca.root()],
+        server_name: Some("localhost".into()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn proxy_upstream_tls() {
+    fluke_testutils::run(async move {
+        let ca = tls_upstream::TestCa::new()?;
+        let upstream = tls_upstream::start(&ca, vec![b"http/1.1".to_vec()], false).await?;
+
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], upstream_tls(&ca))?;
+        assert_eq!(
+            get_through_proxy(upstreams).await?,
+            "sni=localhost alpn=http/1.1 client_cert=no"
+        );
+
+        // the chain has to verify against the configured roots
+        let other_ca = tls_upstream::TestCa::new()?;
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], upstream_tls(&other_ca))?;
+        assert!(get_through_proxy(upstreams).await.is_err());
+
+        // ...and the certificate has to be for the configured name
+        let tls = UpstreamTlsConf {
+            server_name: Some("example.org".into()),
+            ..upstream_tls(&ca)
+        };
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], tls)?;
+        assert!(get_through_proxy(upstreams).await.is_err());
+        Ok(())
+    });
+}
+
+#[test]
+fn proxy_upstream_tls_without_sni() {
+    fluke_testutils::run(async move {
+        let ca = tls_upstream::TestCa::new()?;
+        let upstream = tls_upstream::start(&ca, vec![b"http/1.1".to_vec()], false).await?;
+
+        // the certificate is still checked against the server name
+        let tls = UpstreamTlsConf {
+            send_sni: false,
+            ..upstream_tls(&ca)
+        };
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], tls)?;
+        assert_eq!(
+            get_through_proxy(upstreams).await?,
+            "sni=- alpn=http/1.1 client_cert=no"
+        );
+        Ok(())
+    });
+}
+
+#[test]
+fn proxy_upstream_tls_alpn() {
+    fluke_testutils::run(async move {
+        let ca = tls_upstream::TestCa::new()?;
+        let offered = || vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let upstream = tls_upstream::start(&ca, vec![b"http/1.1".to_vec()], false).await?;
+        let tls = UpstreamTlsConf {
+            alpn: offered(),
+            ..upstream_tls(&ca)
+        };
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], tls)?;
+        assert_eq!(
+            get_through_proxy(upstreams).await?,
+            "sni=localhost alpn=http/1.1 client_cert=no"
+        );
+
+        // the proxy only speaks HTTP/1.1 to upstreams
+        let upstream = tls_upstream::start(&ca, offered(), false).await?;
+        let tls = UpstreamTlsConf {
+            alpn: offered(),
+            ..upstream_tls(&ca)
+        };
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], tls)?;
+        assert!(get_through_proxy(upstreams).await.is_err());
+        Ok(())
+    });
+}
+
+#[test]
+fn proxy_upstream_tls_pins() {
+    fluke_testutils::run(async move {
+        let ca = tls_upstream::TestCa::new()?;
+        let upstream = tls_upstream::start(&ca, vec![b"http/1.1".to_vec()], false).await?;
+
+        let tls = UpstreamTlsConf {
+            pin: Some(Arc::new(PinnedCerts(vec![upstream.cert.clone()]))),
+            ..upstream_tls(&ca)
+        };
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], tls)?;
+        assert_eq!(
+            get_through_proxy(upstreams).await?,
+            "sni=localhost alpn=http/1.1 client_cert=no"
+        );
+
+        // signed by the right CA, but not the one certificate expected
+        let (other, _) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth)?;
+        let tls = UpstreamTlsConf {
+            pin: Some(Arc::new(PinnedCerts(vec![other]))),
+            ..upstream_tls(&ca)
+        };
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], tls)?;
+        assert!(get_through_proxy(upstreams).await.is_err());
+        Ok(())
+    });
+}
+
+#[test]
+fn proxy_upstream_tls_client_auth() {
+    fluke_testutils::run(async move {
+        let ca = tls_upstream::TestCa::new()?;
+        let upstream = tls_upstream::start(&ca, vec![b"http/1.1".to_vec()], true).await?;
+
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], upstream_tls(&ca))?;
+        assert!(get_through_proxy(upstreams).await.is_err());
+
+        let (cert, key) = ca.issue("proxy", ExtendedKeyUsagePurpose::ClientAuth)?;
+        let tls = UpstreamTlsConf {
+            client_auth: Some((vec![cert], key)),
+            ..upstream_tls(&ca)
+        };
+        let upstreams = proxy::UpstreamSet::with_tls([upstream.addr], tls)?;
+        assert_eq!(
+            get_through_proxy(upstreams).await?,
+            "sni=localhost alpn=http/1.1 client_cert=yes"
+        );
         Ok(())
     });
 }
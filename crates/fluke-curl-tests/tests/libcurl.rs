@@ -0,0 +1,223 @@
+//! Interop tests driven through libcurl itself (via the `curl` crate) rather
+//! than the `curl` binary: every option is set explicitly, and the
+//! response head is available line by line, including interim responses
+//! and trailers.
+
+use std::{net::SocketAddr, time::Duration};
+
+use curl::easy::{Easy, HttpVersion, List};
+use fluke::{
+    http::{header, StatusCode},
+    Body, Encoder, ExpectResponseHeaders, Headers, HeadersExt, InterimResponse, Request, Responder,
+    Response, ResponseDone, ServerDriver,
+};
+use fluke_testutils::{Proto, TestServer};
+use pretty_assertions::assert_eq;
+
+struct InteropDriver;
+
+impl ServerDriver for InteropDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        mut respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        if req.headers.expects_100_continue() {
+            respond
+                .write_interim_response(InterimResponse::continue_100())
+                .await?;
+        }
+
+        let mut headers = Headers::default();
+        headers.insert(
+            "x-version",
+            format!("{:?}", req.version).into_bytes().into(),
+        );
+
+        match req.uri.path() {
+            "/trailers" => {
+                headers.insert(header::TRAILER, "x-checksum".into());
+                let res = Response {
+                    status: StatusCode::OK,
+                    headers,
+                    ..Default::default()
+                };
+                let mut respond = respond.write_final_response(res).await?;
+                respond.write_chunk("with trailers".into()).await?;
+
+                let mut trailers = Headers::default();
+                trailers.insert("x-checksum", "1b4f".into());
+                respond.finish_body(Some(Box::new(trailers))).await
+            }
+            _ => {
+                let res = Response {
+                    status: StatusCode::OK,
+                    headers,
+                    ..Default::default()
+                };
+                respond.write_final_response_with_body(res, req_body).await
+            }
+        }
+    }
+}
+
+/// What libcurl saw of a response
+#[derive(Debug, Default)]
+struct Exchange {
+    status: u32,
+
+    /// Every line of every response head (interim ones too) and of the
+    /// trailer section, without the line endings
+    head: Vec<String>,
+
+    body: Vec<u8>,
+}
+
+impl Exchange {
+    fn has_line(&self, line: &str) -> bool {
+        self.head.iter().any(|l| l.eq_ignore_ascii_case(line))
+    }
+}
+
+fn perform(mut easy: Easy) -> eyre::Result<Exchange> {
+    let mut exchange = Exchange::default();
+    {
+        let mut transfer = easy.transfer();
+        transfer.header_function(|line| {
+            let line = String::from_utf8_lossy(line).trim_end().to_string();
+            if !line.is_empty() {
+                exchange.head.push(line);
+            }
+            true
+        })?;
+        transfer.write_function(|chunk| {
+            exchange.body.extend_from_slice(chunk);
+            Ok(chunk.len())
+        })?;
+        transfer.perform()?;
+    }
+    exchange.status = easy.response_code()?;
+    Ok(exchange)
+}
+
+fn easy(addr: SocketAddr, path: &str, version: HttpVersion) -> eyre::Result<Easy> {
+    let mut easy = Easy::new();
+    easy.url(&fluke_testutils::url(addr, path))?;
+    easy.http_version(version)?;
+    easy.timeout(Duration::from_secs(10))?;
+    Ok(easy)
+}
+
+/// Runs a blocking libcurl client against a fresh test server
+fn run_client(proto: Proto, client: impl FnOnce(SocketAddr) -> eyre::Result<()> + Send + 'static) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, InteropDriver).await?;
+        let addr = server.addr();
+        tokio::task::spawn_blocking(move || client(addr)).await??;
+        server.shutdown().await?;
+        Ok(())
+    });
+}
+
+fn echo(proto: Proto) {
+    let (version, expected) = match proto {
+        Proto::H1 => (HttpVersion::V11, "x-version: HTTP/1.1"),
+        Proto::H2 => (HttpVersion::V2PriorKnowledge, "x-version: HTTP/2.0"),
+    };
+    run_client(proto, move |addr| {
+        let mut easy = easy(addr, "/echo", version)?;
+        easy.post(true)?;
+        easy.post_fields_copy(b"Please return to sender")?;
+
+        let exchange = perform(easy)?;
+        assert_eq!(exchange.status, 200);
+        assert!(exchange.has_line(expected), "{:?}", exchange.head);
+        assert_eq!(&exchange.body[..], b"Please return to sender");
+        Ok(())
+    });
+}
+
+#[test]
+fn h1_echo() {
+    echo(Proto::H1)
+}
+
+#[test]
+fn h2_echo() {
+    echo(Proto::H2)
+}
+
+#[test]
+fn h2c_upgrade_is_declined() {
+    // there's no h2c upgrade support: libcurl must carry on over HTTP/1.1
+    run_client(Proto::H1, |addr| {
+        let exchange = perform(easy(addr, "/echo", HttpVersion::V2)?)?;
+        assert_eq!(exchange.status, 200);
+        assert!(
+            exchange.head[0].starts_with("HTTP/1.1 200"),
+            "{:?}",
+            exchange.head
+        );
+        assert!(exchange.has_line("x-version: HTTP/1.1"));
+        Ok(())
+    });
+}
+
+#[test]
+fn h1_expect_continue() {
+    run_client(Proto::H1, |addr| {
+        let body = vec![b'a'; 64 * 1024];
+        let mut easy = easy(addr, "/echo", HttpVersion::V11)?;
+        let mut headers = List::new();
+        headers.append("expect: 100-continue")?;
+        easy.http_headers(headers)?;
+        // long enough that the test fails if libcurl gives up waiting and
+        // sends the body anyway
+        easy.expect_100_timeout(Duration::from_secs(30))?;
+        easy.post(true)?;
+        easy.post_fields_copy(&body)?;
+
+        let exchange = perform(easy)?;
+        assert!(
+            exchange.head[0].starts_with("HTTP/1.1 100"),
+            "{:?}",
+            exchange.head
+        );
+        assert_eq!(exchange.status, 200);
+        assert_eq!(exchange.body, body);
+        Ok(())
+    });
+}
+
+fn trailers(proto: Proto) {
+    let version = match proto {
+        Proto::H1 => HttpVersion::V11,
+        Proto::H2 => HttpVersion::V2PriorKnowledge,
+    };
+    run_client(proto, move |addr| {
+        let mut easy = easy(addr, "/trailers", version)?;
+        let mut headers = List::new();
+        headers.append("te: trailers")?;
+        easy.http_headers(headers)?;
+
+        let exchange = perform(easy)?;
+        assert_eq!(exchange.status, 200);
+        if proto == Proto::H1 {
+            assert!(exchange.has_line("transfer-encoding: chunked"));
+        }
+        assert_eq!(&exchange.body[..], b"with trailers");
+        assert_eq!(exchange.head.last().unwrap(), "x-checksum: 1b4f");
+        Ok(())
+    });
+}
+
+#[test]
+fn h1_trailers() {
+    trailers(Proto::H1)
+}
+
+#[test]
+fn h2_trailers() {
+    trailers(Proto::H2)
+}
@@ -3,39 +3,283 @@ use fluke::{
         net::{TcpReadHalf, TcpWriteHalf},
         IntoHalves, RollMut,
     },
-    h1, Body, BodyChunk, Encoder, ExpectResponseHeaders, HeadersExt, Responder, Response,
-    ResponseDone, ServerDriver,
+    h1, Body, BodyChunk, ConfHandle, Encoder, ExpectResponseHeaders, HeadersExt, InterimResponse,
+    Request, Responder, Response, ResponseDone, ServerDriver,
 };
-use http::StatusCode;
-use std::{cell::RefCell, future::Future, net::SocketAddr, rc::Rc};
-use tracing::debug;
+use fluke_tls::{AnyStream, Connector, TlsConnector, UpstreamTlsConf};
+use hickory_resolver::TokioAsyncResolver;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    rc::{Rc, Weak},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::io::{ReadHalf, WriteHalf};
+use tracing::{debug, warn};
+
+/// A connection to an upstream, in plaintext or over TLS
+pub enum Transport {
+    Plain((TcpReadHalf, TcpWriteHalf)),
+    Tls((ReadHalf<Box<dyn AnyStream>>, WriteHalf<Box<dyn AnyStream>>)),
+}
+
+/// Idle connections to upstreams, by address. Connections to upstreams that
+/// were removed from the set are closed the next time a request goes through.
+pub type TransportPool = Rc<RefCell<HashMap<SocketAddr, Vec<Transport>>>>;
+
+/// The backends requests are forwarded to, picked in turn. The whole set is
+/// swapped at once when it's refreshed: a request sees either the old
+/// addresses or the new ones, never a mix. The set lives in a [ConfHandle],
+/// so it can be reloaded from another thread too, see [UpstreamSet::conf].
+///
+/// Backends of a set are all talked to the same way: in plaintext, or over
+/// TLS, see [UpstreamSet::with_tls].
+#[derive(Clone)]
+pub struct UpstreamSet {
+    inner: Rc<UpstreamSetInner>,
+}
+
+struct UpstreamSetInner {
+    addrs: ConfHandle<Vec<SocketAddr>>,
+    next: Cell<usize>,
+    tls: Option<Connector>,
+}
+
+impl UpstreamSet {
+    pub fn new(addrs: impl Into<Vec<SocketAddr>>) -> Self {
+        Self {
+            inner: Rc::new(UpstreamSetInner {
+                addrs: ConfHandle::new(addrs.into()),
+                next: Cell::new(0),
+                tls: None,
+            }),
+        }
+    }
+
+    /// Backends talked to over TLS, with their own trust anchors, ALPN
+    /// list, client certificate and pins. The set only has addresses, so
+    /// [UpstreamTlsConf::server_name] must be set: it's what certificates
+    /// are checked against (and sent as SNI, unless that's turned off).
+    pub fn with_tls(addrs: impl Into<Vec<SocketAddr>>, tls: UpstreamTlsConf) -> eyre::Result<Self> {
+        let Some(server_name) = tls.server_name.clone() else {
+            return Err(eyre::eyre!("upstream TLS needs a server name"));
+        };
+        Ok(Self {
+            inner: Rc::new(UpstreamSetInner {
+                addrs: ConfHandle::new(addrs.into()),
+                next: Cell::new(0),
+                tls: Some(Connector::new(tls, &server_name)?),
+            }),
+        })
+    }
+
+    /// A handle to reload the set through, along with the rest of the
+    /// configuration: requests forwarded from then on use the new set.
+    pub fn conf(&self) -> ConfHandle<Vec<SocketAddr>> {
+        self.inner.addrs.clone()
+    }
+
+    /// The next upstream to forward to, if there are any
+    pub fn pick(&self) -> Option<SocketAddr> {
+        let addrs = self.addrs();
+        if addrs.is_empty() {
+            return None;
+        }
+        let next = self.inner.next.get();
+        self.inner.next.set(next.wrapping_add(1));
+        Some(addrs[next % addrs.len()])
+    }
+
+    pub fn addrs(&self) -> Arc<Vec<SocketAddr>> {
+        self.inner.addrs.load()
+    }
+
+    pub fn replace(&self, addrs: impl Into<Vec<SocketAddr>>) {
+        self.inner.addrs.store(addrs.into());
+    }
+
+    /// Opens a new connection to `addr`, doing the TLS handshake if the set
+    /// is talked to over TLS
+    pub async fn connect(&self, addr: SocketAddr) -> eyre::Result<Transport> {
+        let Some(connector) = &self.inner.tls else {
+            let stream = fluke::buffet::net::TcpStream::connect(addr).await?;
+            return Ok(Transport::Plain(stream.into_halves()));
+        };
+
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let (stream, alpn_proto) = connector.connect(stream).await?;
+        match alpn_proto.as_deref() {
+            Some(b"http/1.1") | None => {}
+            Some(other) => {
+                return Err(eyre::eyre!(
+                    "upstream picked unsupported ALPN protocol {:?}",
+                    String::from_utf8_lossy(other)
+                ))
+            }
+        }
+        Ok(Transport::Tls(stream.into_halves()))
+    }
+}
+
+/// Where an [UpstreamSet] gets refreshed from: a service registry, DNS SRV
+/// records (see [SrvSource]), a config file... Any `Fn() -> impl Future` returning addresses
+/// will do.
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait UpstreamSource {
+    async fn resolve(&self) -> eyre::Result<Vec<SocketAddr>>;
+}
+
+impl<F, Fut> UpstreamSource for F
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = eyre::Result<Vec<SocketAddr>>>,
+{
+    async fn resolve(&self) -> eyre::Result<Vec<SocketAddr>> {
+        self().await
+    }
+}
+
+/// Upstreams from DNS SRV records (RFC 2782), e.g.
+/// `_http._tcp.backend.internal`. Only the records with the lowest priority
+/// are used, since the others are fallbacks, and weights are ignored:
+/// upstreams are picked in turn, see [UpstreamSet::pick].
+pub struct SrvSource {
+    name: String,
+    resolver: TokioAsyncResolver,
+}
+
+impl SrvSource {
+    /// Looks up `name` with the system's resolver configuration
+    pub fn new(name: impl Into<String>) -> eyre::Result<Self> {
+        Ok(Self::with_resolver(
+            name,
+            TokioAsyncResolver::tokio_from_system_conf()?,
+        ))
+    }
+
+    pub fn with_resolver(name: impl Into<String>, resolver: TokioAsyncResolver) -> Self {
+        Self {
+            name: name.into(),
+            resolver,
+        }
+    }
+}
+
+impl UpstreamSource for SrvSource {
+    async fn resolve(&self) -> eyre::Result<Vec<SocketAddr>> {
+        let records = self.resolver.srv_lookup(self.name.as_str()).await?;
+        let Some(priority) = records.iter().map(|srv| srv.priority()).min() else {
+            return Ok(vec![]);
+        };
 
-pub type TransportPool = Rc<RefCell<Vec<(TcpReadHalf, TcpWriteHalf)>>>;
+        let mut addrs = vec![];
+        for srv in records.iter().filter(|srv| srv.priority() == priority) {
+            // a target of "." means the service isn't available there
+            if srv.target().is_root() {
+                continue;
+            }
+            let ips = self.resolver.lookup_ip(srv.target().clone()).await?;
+            addrs.extend(ips.iter().map(|ip| SocketAddr::new(ip, srv.port())));
+        }
+        // answers come in any order: an unchanged set shouldn't look like
+        // a new one
+        addrs.sort();
+        Ok(addrs)
+    }
+}
+
+/// Refreshes `upstreams` from `source` every `interval`, so a long-running
+/// proxy follows backends coming and going. Failed or empty lookups keep
+/// the current set. Returns once every other handle to the set is gone.
+pub async fn refresh_upstreams(
+    upstreams: UpstreamSet,
+    source: impl UpstreamSource,
+    interval: Duration,
+) {
+    let weak: Weak<UpstreamSetInner> = Rc::downgrade(&upstreams.inner);
+    drop(upstreams);
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(inner) = weak.upgrade() else {
+            return;
+        };
+        let upstreams = UpstreamSet { inner };
+
+        match source.resolve().await {
+            Ok(addrs) if addrs.is_empty() => {
+                warn!("upstream lookup came back empty, keeping the current set");
+            }
+            Ok(addrs) => {
+                if addrs[..] != upstreams.addrs()[..] {
+                    debug!(?addrs, "upstream set changed");
+                    upstreams.replace(addrs);
+                }
+            }
+            Err(e) => {
+                warn!("upstream lookup failed, keeping the current set: {e}");
+            }
+        }
+    }
+}
+
+/// Lets users of the proxy edit request and response heads as they go
+/// through: inject credentials, rewrite paths, strip internal headers...
+///
+/// Header values are [fluke::buffet::Piece]s, so adding a static or
+/// already-allocated value doesn't copy anything.
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait ProxyHooks {
+    /// Called before the request is sent upstream. Changing `req.uri`
+    /// changes the target.
+    async fn on_request_head(&self, req: &mut Request) -> eyre::Result<()> {
+        _ = req;
+        Ok(())
+    }
+
+    /// Called before the final response is sent downstream
+    async fn on_response_head(&self, res: &mut Response) -> eyre::Result<()> {
+        _ = res;
+        Ok(())
+    }
+}
 
-pub struct ProxyDriver {
-    pub upstream_addr: SocketAddr,
+/// Forwards everything as-is
+pub struct NoHooks;
+
+impl ProxyHooks for NoHooks {}
+
+pub struct ProxyDriver<H = NoHooks> {
+    pub upstreams: UpstreamSet,
     pub pool: TransportPool,
+    pub hooks: Rc<H>,
 }
 
-impl ServerDriver for ProxyDriver {
+impl<H: ProxyHooks> ServerDriver for ProxyDriver<H> {
     async fn handle<E: Encoder>(
         &self,
-        req: fluke::Request,
+        mut req: fluke::Request,
         req_body: &mut impl Body,
         mut respond: Responder<E, ExpectResponseHeaders>,
     ) -> eyre::Result<Responder<E, ResponseDone>> {
         if req.headers.expects_100_continue() {
             debug!("Sending 100-continue");
-            let res = Response {
-                status: StatusCode::CONTINUE,
-                ..Default::default()
-            };
-            respond.write_interim_response(res).await?;
+            respond
+                .write_interim_response(InterimResponse::continue_100())
+                .await?;
         }
 
+        let upstream_addr = self
+            .upstreams
+            .pick()
+            .ok_or_else(|| eyre::eyre!("no upstreams to forward to"))?;
         let transport = {
             let mut pool = self.pool.borrow_mut();
-            pool.pop()
+            let addrs = self.upstreams.addrs();
+            pool.retain(|addr, _| addrs.contains(addr));
+            pool.get_mut(&upstream_addr).and_then(|idle| idle.pop())
         };
 
         let transport = if let Some(transport) = transport {
@@ -43,36 +287,54 @@ impl ServerDriver for ProxyDriver {
             transport
         } else {
             debug!("making new connection to upstream!");
-            fluke::buffet::net::TcpStream::connect(self.upstream_addr)
-                .await?
-                .into_halves()
+            self.upstreams.connect(upstream_addr).await?
         };
 
-        let driver = ProxyClientDriver { respond };
+        self.hooks.on_request_head(&mut req).await?;
 
-        let (transport, res) = h1::request(transport, req, req_body, driver).await?;
+        let driver = ProxyClientDriver {
+            respond,
+            hooks: self.hooks.clone(),
+        };
+
+        let (transport, res) = match transport {
+            Transport::Plain(halves) => {
+                let (halves, res) = h1::request(halves, req, req_body, driver).await?;
+                (halves.map(Transport::Plain), res)
+            }
+            Transport::Tls(halves) => {
+                let (halves, res) = h1::request(halves, req, req_body, driver).await?;
+                (halves.map(Transport::Tls), res)
+            }
+        };
 
         if let Some(transport) = transport {
-            let mut pool = self.pool.borrow_mut();
-            // FIXME: leaky abstraction, `h1::request` returns both halves of the
-            // transport, which are both actually `Rc<TcpStream>`
-            pool.push(transport);
+            if self.upstreams.addrs().contains(&upstream_addr) {
+                let mut pool = self.pool.borrow_mut();
+                // FIXME: leaky abstraction, `h1::request` returns both halves of the
+                // transport, which are both actually `Rc<TcpStream>`
+                pool.entry(upstream_addr).or_default().push(transport);
+            } else {
+                debug!("upstream was removed while in use, closing its connection");
+            }
         }
 
         Ok(res)
     }
 }
 
-struct ProxyClientDriver<E>
+struct ProxyClientDriver<E, H>
 where
     E: Encoder,
 {
     respond: Responder<E, ExpectResponseHeaders>,
+    hooks: Rc<H>,
 }
 
-impl<E> h1::ClientDriver for ProxyClientDriver<E>
+impl<E, H> h1::ClientDriver for ProxyClientDriver<E, H>
 where
     E: Encoder,
+    H: ProxyHooks,
 {
     type Return = Responder<E, ResponseDone>;
 
@@ -83,9 +345,11 @@ where
 
     async fn on_final_response(
         self,
-        res: Response,
+        mut res: Response,
         body: &mut impl Body,
     ) -> eyre::Result<Self::Return> {
+        self.hooks.on_response_head(&mut res).await?;
+
         let respond = self.respond;
         let mut respond = respond.write_final_response(res).await?;
 
@@ -114,6 +378,28 @@ pub async fn start(
     SocketAddr,
     impl Drop,
     impl Future<Output = eyre::Result<()>>,
+)> {
+    start_with_hooks(upstream_addr, NoHooks).await
+}
+
+pub async fn start_with_hooks(
+    upstream_addr: SocketAddr,
+    hooks: impl ProxyHooks + 'static,
+) -> eyre::Result<(
+    SocketAddr,
+    impl Drop,
+    impl Future<Output = eyre::Result<()>>,
+)> {
+    start_with_upstreams(UpstreamSet::new([upstream_addr]), hooks).await
+}
+
+pub async fn start_with_upstreams(
+    upstreams: UpstreamSet,
+    hooks: impl ProxyHooks + 'static,
+) -> eyre::Result<(
+    SocketAddr,
+    impl Drop,
+    impl Future<Output = eyre::Result<()>>,
 )> {
     let (tx, mut rx) = tokio::sync::oneshot::channel::<()>();
 
@@ -123,6 +409,7 @@ pub async fn start(
     let proxy_fut = async move {
         let conf = Rc::new(h1::ServerConf::default());
         let pool: TransportPool = Default::default();
+        let hooks = Rc::new(hooks);
 
         enum Event {
             Accepted((fluke::buffet::net::TcpStream, SocketAddr)),
@@ -145,20 +432,26 @@ pub async fn start(
 
                     let pool = pool.clone();
                     let conf = conf.clone();
+                    let hooks = hooks.clone();
+                    let upstreams = upstreams.clone();
 
                     fluke::buffet::spawn(async move {
                         let driver = ProxyDriver {
-                            upstream_addr,
+                            upstreams,
                             pool,
+                            hooks,
                         };
-                        h1::serve(
+                        // upstreams failing is part of what gets tested
+                        if let Err(e) = h1::serve(
                             transport.into_halves(),
                             conf,
                             RollMut::alloc().unwrap(),
                             driver,
                         )
                         .await
-                        .unwrap();
+                        {
+                            debug!("Error serving h1 connection: {e}");
+                        }
                         debug!("Done serving h1 connection");
                     });
                 }
@@ -0,0 +1,163 @@
+//! An HTTPS upstream for the proxy tests, with certificates from its own CA,
+//! that replies with what it saw of the TLS handshake.
+
+use std::{net::SocketAddr, rc::Rc, sync::Arc};
+
+use fluke::{
+    buffet::{IntoHalves, RollMut},
+    h1, Body, ConnInfo, Encoder, ExpectResponseHeaders, Request, Responder, Response, ResponseDone,
+    ServerDriver, TlsInfo,
+};
+use fluke_tls::Userland;
+use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tracing::debug;
+
+/// Issues certificates for servers and clients
+pub struct TestCa {
+    ca: rcgen::Certificate,
+
+    /// Signatures are randomized: serialize once, so the trust anchor
+    /// handed out stays the same
+    root: CertificateDer<'static>,
+}
+
+impl TestCa {
+    pub fn new() -> eyre::Result<Self> {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params)?;
+        let root = CertificateDer::from(ca.serialize_der()?);
+        Ok(Self { ca, root })
+    }
+
+    pub fn root(&self) -> CertificateDer<'static> {
+        self.root.clone()
+    }
+
+    /// A certificate for `name`, good for `usage`, and its key
+    pub fn issue(
+        &self,
+        name: &str,
+        usage: ExtendedKeyUsagePurpose,
+    ) -> eyre::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+        let mut params = CertificateParams::new(vec![name.to_owned()]);
+        params.extended_key_usages = vec![usage];
+        let cert = rcgen::Certificate::from_params(params)?;
+        Ok((
+            CertificateDer::from(cert.serialize_der_with_signer(&self.ca)?),
+            PrivatePkcs8KeyDer::from(cert.serialize_private_key_der()).into(),
+        ))
+    }
+}
+
+pub struct TlsUpstream {
+    pub addr: SocketAddr,
+
+    /// The certificate it serves, for `localhost`
+    pub cert: CertificateDer<'static>,
+}
+
+/// Starts an upstream that picks one of `alpn` (in its own order of
+/// preference), and asks for a client certificate issued by `ca` if
+/// `client_auth` is set. It replies with the server name it was asked for,
+/// the protocol ALPN settled on and whether the client authenticated, e.g.
+/// `sni=localhost alpn=http/1.1 client_cert=no`.
+pub async fn start(
+    ca: &TestCa,
+    alpn: Vec<Vec<u8>>,
+    client_auth: bool,
+) -> eyre::Result<TlsUpstream> {
+    let (cert, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth)?;
+    let builder = ServerConfig::builder();
+    let builder = if client_auth {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.root())?;
+        builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+    } else {
+        builder.with_no_client_auth()
+    };
+    let mut config = builder.with_single_cert(vec![cert.clone()], key)?;
+    config.alpn_protocols = alpn;
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let ln = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = ln.local_addr()?;
+    let conf = Rc::new(h1::ServerConf::default());
+    fluke::buffet::spawn(async move {
+        while let Ok((stream, _)) = ln.accept().await {
+            let acceptor = acceptor.clone();
+            let conf = conf.clone();
+            fluke::buffet::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("upstream TLS handshake failed: {e}");
+                        return;
+                    }
+                };
+                let sc = stream.get_ref().1;
+                let info = TlsInfo {
+                    server_name: sc.server_name().map(|name| name.to_string()),
+                    alpn_protocol: sc.alpn_protocol().map(|p| p.to_vec()),
+                    peer_certificates: sc
+                        .peer_certificates()
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|cert| cert.to_vec())
+                        .collect(),
+                };
+                let Ok(buf) = RollMut::alloc() else {
+                    return;
+                };
+                if let Err(e) = h1::serve_with(
+                    Userland(stream).into_halves(),
+                    conf,
+                    buf,
+                    HandshakeDriver,
+                    ConnInfo::with_tls(info),
+                )
+                .await
+                {
+                    debug!("error serving upstream connection: {e}");
+                }
+            });
+        }
+    });
+
+    Ok(TlsUpstream { addr, cert })
+}
+
+/// Replies with what it saw of the TLS handshake
+struct HandshakeDriver;
+
+impl ServerDriver for HandshakeDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        _req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let tls = req.conn.tls().ok_or_else(|| eyre::eyre!("not over TLS"))?;
+        let seen = format!(
+            "sni={} alpn={} client_cert={}",
+            tls.server_name.as_deref().unwrap_or("-"),
+            tls.alpn_protocol
+                .as_deref()
+                .map_or("-".into(), String::from_utf8_lossy),
+            if tls.peer_certificate().is_some() {
+                "yes"
+            } else {
+                "no"
+            },
+        );
+        let mut body = fluke::body::once(seen.into_bytes());
+        respond
+            .write_final_response_with_body(Response::default(), &mut body)
+            .await
+    }
+}
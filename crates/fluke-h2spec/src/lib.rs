@@ -0,0 +1,2 @@
+// This crate exists only to run h2spec against fluke, see the `tests/`
+// directory.
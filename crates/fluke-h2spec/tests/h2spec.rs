@@ -0,0 +1,176 @@
+//! Runs [h2spec](https://github.com/summerwind/h2spec) against a fluke h2
+//! server, as a cross-check of the in-tree httpwg suite.
+//!
+//! The binary is looked up in `$H2SPEC`, then in `target/h2spec/`, where
+//! `just h2spec` downloads it before running this test: it's ignored by
+//! default so that plain `cargo test` works offline.
+
+use std::path::PathBuf;
+
+use fluke::{
+    http::{header, StatusCode},
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, Request, Responder, Response,
+    ResponseDone, ServerDriver,
+};
+use fluke_testutils::{Proto, TestServer};
+use tokio::process::Command;
+use tracing::debug;
+
+/// h2spec cases (`package: description`, as in the report) that are
+/// expected to fail, along with the reason why
+const KNOWN_FAILURES: &[(&str, &str)] = &[];
+
+/// Reads the whole request body, then responds with a short one: enough
+/// for every h2spec case.
+struct SinkDriver;
+
+impl ServerDriver for SinkDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        _req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        while let BodyChunk::Chunk(_) = req_body.next_chunk().await? {}
+
+        let body = "hello from fluke";
+        let mut headers = Headers::default();
+        headers.insert(
+            header::CONTENT_LENGTH,
+            body.len().to_string().into_bytes().into(),
+        );
+        let res = Response {
+            status: StatusCode::OK,
+            headers,
+            ..Default::default()
+        };
+        let mut respond = respond.write_final_response(res).await?;
+        respond.write_chunk(body.into()).await?;
+        respond.finish_body(None).await
+    }
+}
+
+fn h2spec_path() -> PathBuf {
+    if let Some(path) = std::env::var_os("H2SPEC") {
+        return path.into();
+    }
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let project_dir = manifest_dir.parent().unwrap().parent().unwrap();
+    project_dir.join("target").join("h2spec").join("h2spec")
+}
+
+/// One case from h2spec's JUnit report
+#[derive(Debug)]
+struct Case {
+    name: String,
+    failed: bool,
+}
+
+/// Extracts cases from h2spec's JUnit report: there's one `<testcase>`
+/// element per case, with a `<failure>` or `<error>` child if it didn't
+/// pass.
+fn parse_report(report: &str) -> Vec<Case> {
+    report
+        .split("<testcase ")
+        .skip(1)
+        .map(|element| {
+            let attr = |name: &str| {
+                let start = element.find(&format!("{name}=\""))? + name.len() + 2;
+                let len = element[start..].find('"')?;
+                Some(unescape(&element[start..start + len]))
+            };
+            Case {
+                name: format!(
+                    "{}: {}",
+                    attr("package").unwrap_or_default(),
+                    attr("classname").unwrap_or_default()
+                ),
+                failed: element.contains("<failure") || element.contains("<error"),
+            }
+        })
+        .collect()
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&#34;", "\"")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[test]
+#[ignore = "needs the h2spec binary, run it with `just h2spec`"]
+fn h2spec() {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(Proto::H2, SinkDriver).await?;
+        let report_path =
+            std::env::temp_dir().join(format!("fluke-h2spec-{}.xml", std::process::id()));
+
+        let h2spec = h2spec_path();
+        debug!("Running {}", h2spec.display());
+        let output = Command::new(&h2spec)
+            .arg("--host")
+            .arg("127.0.0.1")
+            .arg("--port")
+            .arg(server.addr().port().to_string())
+            .arg("--timeout")
+            .arg("2")
+            .arg("--junit-report")
+            .arg(&report_path)
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| eyre::eyre!("could not run {}: {e}", h2spec.display()))?;
+        debug!(
+            "h2spec output:\n{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+
+        let report = std::fs::read_to_string(&report_path)?;
+        _ = std::fs::remove_file(&report_path);
+        let cases = parse_report(&report);
+        assert!(!cases.is_empty(), "h2spec report has no cases:\n{report}");
+
+        let failures: Vec<_> = cases
+            .iter()
+            .filter(|case| case.failed)
+            .filter(|case| !KNOWN_FAILURES.iter().any(|(name, _)| *name == case.name))
+            .map(|case| case.name.as_str())
+            .collect();
+        assert!(
+            failures.is_empty(),
+            "{} of {} h2spec cases failed:\n{}",
+            failures.len(),
+            cases.len(),
+            failures.join("\n")
+        );
+
+        server.shutdown().await?;
+        Ok(())
+    });
+}
+
+#[test]
+fn parses_junit_reports() {
+    let report = r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="Starting HTTP/2" package="http2/3.5" id="3.5" tests="2" skipped="0" failures="1" errors="0">
+    <testcase package="http2/3.5" classname="Sends client connection preface" time="0.0010"></testcase>
+    <testcase package="http2/3.5" classname="Sends invalid &#34;connection preface&#34;" time="0.0010">
+      <failure>Expected: GOAWAY Frame</failure>
+    </testcase>
+  </testsuite>
+</testsuites>"#;
+
+    let cases = parse_report(report);
+    assert_eq!(cases.len(), 2);
+    assert_eq!(cases[0].name, "http2/3.5: Sends client connection preface");
+    assert!(!cases[0].failed);
+    assert_eq!(
+        cases[1].name,
+        r#"http2/3.5: Sends invalid "connection preface""#
+    );
+    assert!(cases[1].failed);
+}
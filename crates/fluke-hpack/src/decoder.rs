@@ -247,6 +247,10 @@ pub enum DecoderError {
     /// must be treating as a decoding error.
     #[error("Dynamic table size update at the end of a header block")]
     SizeUpdateAtEnd,
+    /// Dynamic table size updates must come at the beginning of a header
+    /// block, before any header field representation (RFC 7541, section 4.2)
+    #[error("Dynamic table size update after a header field")]
+    SizeUpdateAfterHeaderField,
 }
 
 /// Represents all errors that can be encountered while performing the decoding
@@ -370,6 +374,7 @@ impl<'a> Decoder<'a> {
         let mut current_octet_index = 0;
 
         let mut last_was_size_update = false;
+        let mut seen_header_field = false;
         while current_octet_index < buf.len() {
             // At this point we are always at the beginning of the next block
             // within the HPACK data.
@@ -379,6 +384,18 @@ impl<'a> Decoder<'a> {
             let buffer_leftover = &buf[current_octet_index..];
             let field_representation = FieldRepresentation::new(initial_octet);
             last_was_size_update = matches!(field_representation, FieldRepresentation::SizeUpdate);
+            if last_was_size_update {
+                #[cfg(test)]
+                let misplaced = seen_header_field && !self.allow_trailing_size_updates;
+                #[cfg(not(test))]
+                let misplaced = seen_header_field;
+
+                if misplaced {
+                    return Err(DecoderError::SizeUpdateAfterHeaderField);
+                }
+            } else {
+                seen_header_field = true;
+            }
 
             let consumed = match field_representation {
                 FieldRepresentation::Indexed => {
@@ -532,7 +549,7 @@ impl<'a> Decoder<'a> {
     ///
     /// Returns the number of octets consumed from the given buffer.
     fn update_max_dynamic_size(&mut self, buf: &[u8]) -> Result<usize, DecoderError> {
-        let (new_size, consumed) = decode_integer(buf, 5).ok().unwrap();
+        let (new_size, consumed) = decode_integer(buf, 5)?;
         if let Some(max_size) = self.max_allowed_table_size {
             if new_size > max_size {
                 return Err(DecoderError::InvalidMaxDynamicSize);
@@ -1141,6 +1158,21 @@ mod tests {
         }
     }
 
+    /// Tests that dynamic table size updates are only accepted at the
+    /// beginning of a header block.
+    #[test]
+    fn test_decoder_size_update_after_header_field() {
+        let mut decoder = Decoder::new();
+
+        // two size updates (0, then 4096), then `:method: GET`
+        assert!(decoder.decode(&[0x20, 0x3f, 0xe1, 0x1f, 0x82]).is_ok());
+        // `:method: GET`, a size update, then `:scheme: http`
+        assert_eq!(
+            decoder.decode(&[0x82, 0x20, 0x86]),
+            Err(DecoderError::SizeUpdateAfterHeaderField)
+        );
+    }
+
     /// Tests that when the decoder receives an update of the max dynamic table
     /// size as 0, all entries are cleared from the dynamic table.
     #[test]
@@ -170,6 +170,8 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 pub struct Encoder<'a> {
     /// The header table represents the encoder's context
     header_table: HeaderTable<'a>,
+    /// Bumped every time the dynamic table changes
+    generation: u64,
 }
 
 impl<'a> Default for Encoder<'a> {
@@ -184,14 +186,26 @@ impl<'a> Encoder<'a> {
     pub fn new() -> Encoder<'a> {
         Encoder {
             header_table: HeaderTable::with_static_table(STATIC_TABLE),
+            generation: 0,
         }
     }
 
+    /// Returns a counter that changes every time the encoder's dynamic table
+    /// is modified (by inserting a header, or by resizing it).
+    ///
+    /// A header block produced while the generation did not change only
+    /// refers to table entries that already existed, so it may be replayed
+    /// verbatim as long as the generation stays the same.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     /// Sets a new maximum dynamic table size for the encoder.
     pub fn set_max_table_size(&mut self, new_max_size: usize) {
         self.header_table
             .dynamic_table
             .set_max_table_size(new_max_size);
+        self.generation += 1;
     }
 
     /// Encodes the given headers using the HPACK rules and returns a newly
@@ -245,6 +259,7 @@ impl<'a> Encoder<'a> {
                 self.encode_literal(&header, true, writer)?;
                 self.header_table
                     .add_header(header.0.to_vec(), header.1.to_vec());
+                self.generation += 1;
             }
             Some((index, false)) => {
                 // The name of the header is at the given index, but the
@@ -425,6 +440,25 @@ mod tests {
         );
     }
 
+    /// Tests that the generation only changes when the dynamic table does.
+    #[test]
+    fn test_generation_tracks_dynamic_table() {
+        let mut encoder: Encoder = Encoder::new();
+        let headers = [(b"custom-key".to_vec(), b"custom-value".to_vec())];
+        assert_eq!(encoder.generation(), 0);
+
+        let _ = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+        let after_insert = encoder.generation();
+        assert_ne!(after_insert, 0);
+
+        // Only indexed representations: the table is left untouched
+        let _ = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+        assert_eq!(encoder.generation(), after_insert);
+
+        encoder.set_max_table_size(0);
+        assert_ne!(encoder.generation(), after_insert);
+    }
+
     /// Tests that when a header name is indexed, but the value isn't, the
     /// header is represented by an index (for the name) and a literal (for
     /// the value).
@@ -33,6 +33,11 @@ enum Lifecycle<C: cqueue::Entry> {
     // received, the Waker can be used to trigger the Rust async runtime to poll
     // the Op.
     Waiting(std::task::Waker),
+    // The Op has received a completion queue entry flagged with
+    // IORING_CQE_F_MORE: the kernel will post a notification once it's done
+    // with the Op's buffers (e.g. for zero-copy sends). The Op becomes
+    // Completed, with the first entry, once that notification is received.
+    Notifying(C, Option<std::task::Waker>),
     // The Op has received a submission queue entry. The Op will
     // be Ready the next time that it is polled.
     Completed(C),
@@ -105,6 +110,10 @@ impl<C: cqueue::Entry> Future for OpInner<C> {
                 *lifecycle = Lifecycle::Waiting(cx.waker().clone());
                 std::task::Poll::Pending
             }
+            Lifecycle::Notifying(_, waker) => {
+                *waker = Some(cx.waker().clone());
+                std::task::Poll::Pending
+            }
             Lifecycle::Completed(cqe) => std::task::Poll::Ready(cqe.clone()),
         }
     }
@@ -189,21 +198,41 @@ impl<S: squeue::Entry, C: cqueue::Entry> IoUringAsync<S, C> {
         let mut guard = self.slab.borrow_mut();
         while let Some(cqe) = unsafe { self.uring.completion_shared() }.next() {
             let index = cqe.user_data();
+            let more = io_uring::cqueue::more(cqe.flags());
             let lifecycle = &mut guard[index.try_into().unwrap()];
-            match lifecycle {
+            match std::mem::replace(lifecycle, Lifecycle::Submitted) {
+                Lifecycle::Submitted if more => {
+                    *lifecycle = Lifecycle::Notifying(cqe, None);
+                }
+                Lifecycle::Waiting(waker) if more => {
+                    *lifecycle = Lifecycle::Notifying(cqe, Some(waker));
+                }
                 Lifecycle::Submitted => {
                     *lifecycle = Lifecycle::Completed(cqe);
                 }
                 Lifecycle::Waiting(waker) => {
-                    waker.wake_by_ref();
+                    waker.wake();
                     *lifecycle = Lifecycle::Completed(cqe);
                 }
-                Lifecycle::Completed(cqe) => {
+                Lifecycle::Notifying(first, waker) if io_uring::cqueue::notif(cqe.flags()) => {
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                    *lifecycle = Lifecycle::Completed(first);
+                }
+                Lifecycle::Notifying(first, waker) => {
+                    // zero-copy sends only ever post a notification after
+                    // the first completion, anything else is ignored until
+                    // it shows up
+                    *lifecycle = Lifecycle::Notifying(first, waker);
+                }
+                Lifecycle::Completed(first) => {
                     println!(
                         "multishot operations not implemented: {}, {}",
                         cqe.user_data(),
                         cqe.result()
                     );
+                    *lifecycle = Lifecycle::Completed(first);
                 }
             }
         }
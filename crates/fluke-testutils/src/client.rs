@@ -0,0 +1,130 @@
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use http::{HeaderMap, Method, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Incoming,
+    client::conn::{http1, http2},
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tracing::debug;
+
+use crate::Proto;
+
+/// A hyper client connection to a test server. Requests sent through the
+/// same client share the same connection.
+pub struct TestClient {
+    addr: SocketAddr,
+    sender: Sender,
+}
+
+enum Sender {
+    H1(http1::SendRequest<Full<Bytes>>),
+    H2(http2::SendRequest<Full<Bytes>>),
+}
+
+/// A fully-buffered response
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    /// The response body, as UTF-8. Panics if it isn't.
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body[..]).expect("response body should be utf-8")
+    }
+}
+
+impl TestClient {
+    /// Connects to `addr` and performs the protocol handshake
+    pub async fn connect(proto: Proto, addr: SocketAddr) -> eyre::Result<Self> {
+        let io = TokioIo::new(tokio::net::TcpStream::connect(addr).await?);
+
+        let sender = match proto {
+            Proto::H1 => {
+                let (sender, conn) = http1::handshake(io).await?;
+                fluke::buffet::spawn(async move {
+                    if let Err(e) = conn.await {
+                        debug!("h1 client connection errored: {e}");
+                    }
+                });
+                Sender::H1(sender)
+            }
+            Proto::H2 => {
+                let (sender, conn) = http2::handshake(TokioExecutor::new(), io).await?;
+                fluke::buffet::spawn(async move {
+                    if let Err(e) = conn.await {
+                        debug!("h2 client connection errored: {e}");
+                    }
+                });
+                Sender::H2(sender)
+            }
+        };
+
+        Ok(Self { addr, sender })
+    }
+
+    /// Sends a GET request with an empty body
+    pub async fn get(&mut self, path: &str) -> eyre::Result<TestResponse> {
+        self.send(Method::GET, path, Bytes::new()).await
+    }
+
+    /// Sends a POST request with the given body
+    pub async fn post(&mut self, path: &str, body: impl Into<Bytes>) -> eyre::Result<TestResponse> {
+        self.send(Method::POST, path, body.into()).await
+    }
+
+    /// Sends a request with the given method, path and body
+    pub async fn send(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Bytes,
+    ) -> eyre::Result<TestResponse> {
+        let req = http::Request::builder()
+            .method(method)
+            .uri(crate::url(self.addr, path))
+            .header(http::header::HOST, self.addr.to_string())
+            .body(Full::new(body))?;
+        self.request(req).await
+    }
+
+    /// Sends an arbitrary request and buffers the whole response
+    pub async fn request(&mut self, req: http::Request<Full<Bytes>>) -> eyre::Result<TestResponse> {
+        let (parts, body) = self.request_streaming(req).await?.into_parts();
+        let body = body.collect().await?.to_bytes();
+
+        Ok(TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        })
+    }
+
+    /// Sends an arbitrary request and returns as soon as the response
+    /// headers are in: the body is left for the caller to read (or not).
+    pub async fn request_streaming(
+        &mut self,
+        mut req: http::Request<Full<Bytes>>,
+    ) -> eyre::Result<http::Response<Incoming>> {
+        let res = match &mut self.sender {
+            Sender::H1(sender) => {
+                // h1 wants origin-form
+                if let Some(pq) = req.uri().path_and_query() {
+                    *req.uri_mut() = pq.as_str().parse()?;
+                }
+                sender.ready().await?;
+                sender.send_request(req).await?
+            }
+            Sender::H2(sender) => {
+                sender.ready().await?;
+                sender.send_request(req).await?
+            }
+        };
+        Ok(res)
+    }
+}
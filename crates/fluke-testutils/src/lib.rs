@@ -0,0 +1,43 @@
+//! Helpers to run end-to-end tests against fluke: spin up an h1/h2 server
+//! on an ephemeral port, drive it with a real (hyper) client, and look at
+//! what comes back.
+
+use std::{future::Future, net::SocketAddr};
+
+mod client;
+pub use client::*;
+
+mod server;
+pub use server::*;
+
+pub mod replay;
+pub mod split;
+
+mod tracing_common;
+pub use tracing_common::setup_tracing;
+
+/// Which protocol to speak, for both [TestServer] and [TestClient]
+/// (h2 is always negotiated with prior knowledge, there's no TLS here).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Proto {
+    H1,
+    H2,
+}
+
+/// Runs an async test inside [fluke::buffet::start], with error reporting
+/// and tracing set up. Panics if the test returns an error.
+pub fn run(test: impl Future<Output = eyre::Result<()>>) {
+    color_eyre::install().unwrap();
+    fluke::buffet::start(async {
+        setup_tracing();
+
+        if let Err(e) = test.await {
+            panic!("Error: {e:?}");
+        }
+    });
+}
+
+/// Returns a `http://` URL for the given address and path
+pub fn url(addr: SocketAddr, path: &str) -> String {
+    format!("http://{addr}{path}")
+}
@@ -0,0 +1,257 @@
+//! Recorded client sessions, replayed verbatim against `h1::serve` or
+//! `h2::serve` over an in-memory pipe: a byte stream that made a server
+//! misbehave in the field becomes a regression test, without having to
+//! re-express it as httpwg frames or hyper requests.
+//!
+//! A recording is a small binary file:
+//!
+//! ```text
+//! "FLUKEREC"          magic, 8 bytes
+//! version: u8         always 1
+//! proto: u8           1 for HTTP/1.1, 2 for HTTP/2
+//! then, until the end of the file, records made of a tag and a payload:
+//!   'D' len: u32 (BE) bytes   the client sent `len` bytes, in one write
+//!   'P' ms: u32 (BE)          the client waited that many milliseconds
+//!   'S'                       the client shut down its write half
+//!   'R'                       the client reset the connection
+//! ```
+//!
+//! Records are self-delimiting, so a recorder can append to a file as it
+//! goes (and pick up again after a restart), and a file cut short by a
+//! recorder that died mid-write still replays up to its last complete
+//! record. Converting from a packet capture is a matter of emitting one
+//! `D` record per client-to-server TCP segment.
+
+use std::{path::Path, rc::Rc, time::Duration};
+
+use fluke::{
+    buffet::{ReadOwned, RollMut, WriteOwned},
+    h1, h2, ServerDriver,
+};
+use tracing::{debug, warn};
+
+use crate::Proto;
+
+const MAGIC: &[u8; 8] = b"FLUKEREC";
+const VERSION: u8 = 1;
+
+/// Something the client did, see the [module docs](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Data(Vec<u8>),
+    Pause(Duration),
+    Shutdown,
+    Reset,
+}
+
+/// A recorded client session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub proto: Proto,
+    pub events: Vec<Event>,
+}
+
+/// What the server did with a [Session]
+#[derive(Debug)]
+pub struct Replay {
+    /// Everything the server wrote back
+    pub output: Vec<u8>,
+
+    /// How serving the connection ended
+    pub outcome: Result<(), fluke::ServeError>,
+}
+
+impl Replay {
+    /// [Replay::output] as text, for HTTP/1.1 sessions
+    pub fn output_text(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+impl Session {
+    pub fn new(proto: Proto) -> Self {
+        Self {
+            proto,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn data(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.events.push(Event::Data(data.into()));
+        self
+    }
+
+    pub fn pause(mut self, duration: Duration) -> Self {
+        self.events.push(Event::Pause(duration));
+        self
+    }
+
+    pub fn shutdown(mut self) -> Self {
+        self.events.push(Event::Shutdown);
+        self
+    }
+
+    pub fn reset(mut self) -> Self {
+        self.events.push(Event::Reset);
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.push(match self.proto {
+            Proto::H1 => 1,
+            Proto::H2 => 2,
+        });
+        for event in &self.events {
+            match event {
+                Event::Data(data) => {
+                    out.push(b'D');
+                    out.extend_from_slice(&u32::try_from(data.len()).unwrap().to_be_bytes());
+                    out.extend_from_slice(data);
+                }
+                Event::Pause(duration) => {
+                    let ms = u32::try_from(duration.as_millis()).unwrap_or(u32::MAX);
+                    out.push(b'P');
+                    out.extend_from_slice(&ms.to_be_bytes());
+                }
+                Event::Shutdown => out.push(b'S'),
+                Event::Reset => out.push(b'R'),
+            }
+        }
+        out
+    }
+
+    pub fn decode(input: &[u8]) -> eyre::Result<Self> {
+        let Some(rest) = input.strip_prefix(MAGIC) else {
+            eyre::bail!("not a fluke session recording");
+        };
+        let [version, proto, mut rest @ ..] = rest else {
+            eyre::bail!("truncated session recording header");
+        };
+        if *version != VERSION {
+            eyre::bail!("unsupported session recording version {version}");
+        }
+        let proto = match proto {
+            1 => Proto::H1,
+            2 => Proto::H2,
+            _ => eyre::bail!("unknown protocol {proto} in session recording"),
+        };
+
+        fn u32_at(input: &[u8]) -> Option<(u32, &[u8])> {
+            let n = input.get(..4)?;
+            Some((u32::from_be_bytes(n.try_into().unwrap()), &input[4..]))
+        }
+
+        let mut events = Vec::new();
+        while let Some((&tag, after_tag)) = rest.split_first() {
+            let decoded = match tag {
+                b'D' => u32_at(after_tag).and_then(|(len, after_len)| {
+                    let len = len as usize;
+                    (after_len.len() >= len)
+                        .then(|| (Event::Data(after_len[..len].to_vec()), &after_len[len..]))
+                }),
+                b'P' => u32_at(after_tag)
+                    .map(|(ms, rest)| (Event::Pause(Duration::from_millis(ms as u64)), rest)),
+                b'S' => Some((Event::Shutdown, after_tag)),
+                b'R' => Some((Event::Reset, after_tag)),
+                _ => eyre::bail!("unknown record tag {tag:#04x} in session recording"),
+            };
+            let Some((event, after)) = decoded else {
+                warn!("session recording ends with a truncated record, ignoring it");
+                break;
+            };
+            events.push(event);
+            rest = after;
+        }
+
+        Ok(Self { proto, events })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        Self::decode(&std::fs::read(path)?)
+    }
+
+    /// Feeds the session to a fresh server connection with default
+    /// settings, see [Session::replay_with]
+    pub async fn replay<D>(&self, driver: D) -> eyre::Result<Replay>
+    where
+        D: ServerDriver + 'static,
+    {
+        self.replay_with(Default::default(), Default::default(), driver)
+            .await
+    }
+
+    /// Feeds the session to a fresh server connection, and collects
+    /// everything the server writes until it's done with the connection. A
+    /// session that doesn't end with a shutdown or reset is shut down after
+    /// its last event.
+    pub async fn replay_with<D>(
+        &self,
+        h1_conf: h1::ServerConf,
+        h2_conf: h2::ServerConf,
+        driver: D,
+    ) -> eyre::Result<Replay>
+    where
+        D: ServerDriver + 'static,
+    {
+        let (mut client_write, server_read) = fluke::buffet::pipe();
+        let (server_write, mut client_read) = fluke::buffet::pipe();
+
+        let events = self.events.clone();
+        let feed = fluke::buffet::spawn(async move {
+            for event in events {
+                match event {
+                    Event::Data(data) => client_write.write_all_owned(data).await?,
+                    Event::Pause(duration) => tokio::time::sleep(duration).await,
+                    Event::Shutdown => break,
+                    Event::Reset => {
+                        client_write.reset().await;
+                        return Ok(());
+                    }
+                }
+            }
+            client_write.shutdown().await
+        });
+
+        let collect = fluke::buffet::spawn(async move {
+            let mut output = Vec::new();
+            let mut buf = vec![0u8; 4096];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                match res {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => output.extend_from_slice(&buf[..n]),
+                }
+            }
+            output
+        });
+
+        let client_buf = RollMut::alloc()?;
+        let outcome = match self.proto {
+            Proto::H1 => h1::serve(
+                (server_read, server_write),
+                Rc::new(h1_conf),
+                client_buf,
+                driver,
+            )
+            .await
+            .map(|_| ()),
+            Proto::H2 => h2::serve(
+                (server_read, server_write),
+                Rc::new(h2_conf),
+                client_buf,
+                Rc::new(driver),
+            )
+            .await
+            .map(|_| ()),
+        };
+        debug!(?outcome, "replayed {} events", self.events.len());
+
+        // the server may have stopped reading before the end of the session
+        feed.abort();
+        let output = collect.await?;
+        Ok(Replay { output, outcome })
+    }
+}
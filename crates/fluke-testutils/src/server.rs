@@ -0,0 +1,142 @@
+use std::{net::SocketAddr, rc::Rc};
+
+use fluke::{
+    buffet::{
+        net::{TcpListener, TcpStream},
+        IntoHalves, RollMut,
+    },
+    h1, h2, ServerDriver,
+};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tracing::debug;
+
+use crate::Proto;
+
+/// A fluke server listening on an ephemeral port on localhost, serving
+/// every connection it accepts with the same driver.
+///
+/// Must be started from within [fluke::buffet::start] (see [crate::run]).
+pub struct TestServer {
+    addr: SocketAddr,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    join: JoinHandle<eyre::Result<()>>,
+}
+
+impl TestServer {
+    /// Binds to an ephemeral port on `127.0.0.1` (or to `$LISTEN_PORT`, if
+    /// set) and starts accepting connections in the background.
+    pub async fn start<D>(proto: Proto, driver: D) -> eyre::Result<Self>
+    where
+        D: ServerDriver + 'static,
+    {
+        let listen_port: u16 = std::env::var("LISTEN_PORT")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_default();
+        let ln = TcpListener::bind(format!("127.0.0.1:{listen_port}").parse()?).await?;
+        let addr = ln.local_addr()?;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let join = fluke::buffet::spawn(accept_loop(proto, ln, Rc::new(driver), shutdown_rx));
+
+        Ok(Self {
+            addr,
+            shutdown_tx: Some(shutdown_tx),
+            join,
+        })
+    }
+
+    /// The address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A `http://` URL pointing at this server
+    pub fn url(&self, path: &str) -> String {
+        crate::url(self.addr, path)
+    }
+
+    /// Stops accepting connections and waits for the accept loop to exit.
+    /// Connections that are already established are left alone.
+    pub async fn shutdown(mut self) -> eyre::Result<()> {
+        if let Some(tx) = self.shutdown_tx.take() {
+            _ = tx.send(());
+        }
+        (&mut self.join).await?
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if self.shutdown_tx.is_some() {
+            self.join.abort();
+        }
+    }
+}
+
+async fn accept_loop<D>(
+    proto: Proto,
+    ln: TcpListener,
+    driver: Rc<D>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+) -> eyre::Result<()>
+where
+    D: ServerDriver + 'static,
+{
+    let h1_conf = Rc::new(h1::ServerConf::default());
+    let h2_conf = Rc::new(h2::ServerConf::default());
+
+    enum Event {
+        Accepted((TcpStream, SocketAddr)),
+        ShuttingDown,
+    }
+
+    loop {
+        let ev = tokio::select! {
+            accept_res = ln.accept() => {
+                Event::Accepted(accept_res?)
+            },
+            _ = &mut shutdown_rx => {
+                Event::ShuttingDown
+            }
+        };
+
+        match ev {
+            Event::Accepted((transport, remote_addr)) => {
+                debug!("Accepted connection from {remote_addr}");
+
+                let driver = driver.clone();
+                let h1_conf = h1_conf.clone();
+                let h2_conf = h2_conf.clone();
+
+                fluke::buffet::spawn(async move {
+                    let client_buf = RollMut::alloc().unwrap();
+                    let res = match proto {
+                        Proto::H1 => {
+                            h1::serve(transport.into_halves(), h1_conf, client_buf, driver)
+                                .await
+                                .map(|_| ())
+                        }
+                        Proto::H2 => {
+                            h2::serve(transport.into_halves(), h2_conf, client_buf, driver)
+                                .await
+                                .map(|_| ())
+                        }
+                    };
+                    match res {
+                        Ok(()) => debug!("Done serving {proto:?} connection from {remote_addr}"),
+                        Err(e) => {
+                            debug!("Error serving {proto:?} connection from {remote_addr}: {e}")
+                        }
+                    }
+                });
+            }
+            Event::ShuttingDown => {
+                debug!("Shutting down test server");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! Split-read checks for the HTTP/1.1 server: a request must parse the
+//! same however the bytes are cut up on their way in. Input is fed over
+//! the in-memory pipe in two writes, split at every offset (or a sample of
+//! them), and what the driver sees each time is compared to what it sees
+//! when everything comes in a single write.
+//!
+//! That's where bugs at `Roll` boundaries hide: a CRLF straddling two
+//! reads, a chunk size cut in half, a header name that ends right where a
+//! read does.
+
+use std::{cell::RefCell, rc::Rc};
+
+use fluke::{
+    http::{Method, StatusCode, Uri, Version},
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, Request, Responder, Response,
+    ResponseDone, ServerDriver,
+};
+
+use crate::{replay::Session, Proto};
+
+/// What a driver got to see of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Parsed {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+
+    /// Header fields in the order they were stored, values as raw bytes
+    pub headers: Vec<(String, Vec<u8>)>,
+
+    /// The whole body, however it was chunked
+    pub body: Vec<u8>,
+}
+
+/// Which split points [check_splits] tries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Splits {
+    /// Every offset: one replay per byte of input
+    All,
+
+    /// About that many offsets, evenly spaced, for large inputs
+    Sample(usize),
+}
+
+impl Splits {
+    fn offsets(self, len: usize) -> Vec<usize> {
+        match self {
+            Splits::All => (1..len).collect(),
+            Splits::Sample(n) => {
+                let step = (len / n.max(1)).max(1);
+                (1..len).step_by(step).collect()
+            }
+        }
+    }
+}
+
+/// Feeds `input` to a fresh HTTP/1.1 connection in one write, then in two
+/// writes for each split point, and fails with the first split point for
+/// which the driver saw something different. Returns the requests parsed
+/// from the single write, so that callers can check those too.
+pub async fn check_splits(input: &[u8], splits: Splits) -> eyre::Result<Vec<Parsed>> {
+    let expected = parse(Session::new(Proto::H1).data(input)).await?;
+    if expected.is_empty() {
+        eyre::bail!("no request could be parsed from the input, even in a single write");
+    }
+
+    for at in splits.offsets(input.len()) {
+        let (before, after) = input.split_at(at);
+        let session = Session::new(Proto::H1).data(before).data(after);
+        let actual = parse(session).await?;
+        if actual != expected {
+            eyre::bail!(
+                "split at offset {at} ({:?} | {:?}) changes what the driver sees:\n\
+                 single write: {expected:#?}\n\
+                 split: {actual:#?}",
+                String::from_utf8_lossy(&before[before.len().saturating_sub(16)..]),
+                String::from_utf8_lossy(&after[..after.len().min(16)]),
+            );
+        }
+    }
+    Ok(expected)
+}
+
+async fn parse(session: Session) -> eyre::Result<Vec<Parsed>> {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    session
+        .replay(RecordingDriver { seen: seen.clone() })
+        .await?;
+    Ok(seen.take())
+}
+
+/// Writes down every request (and its body), then responds with an empty
+/// 204
+struct RecordingDriver {
+    seen: Rc<RefCell<Vec<Parsed>>>,
+}
+
+impl ServerDriver for RecordingDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut body = Vec::new();
+        while let BodyChunk::Chunk(chunk) = req_body.next_chunk().await? {
+            body.extend_from_slice(&chunk[..]);
+        }
+        self.seen.borrow_mut().push(Parsed {
+            method: req.method,
+            uri: req.uri,
+            version: req.version,
+            headers: fields(&req.headers),
+            body,
+        });
+
+        let res = Response {
+            status: StatusCode::NO_CONTENT,
+            ..Default::default()
+        };
+        let respond = respond.write_final_response(res).await?;
+        respond.finish_body(None).await
+    }
+}
+
+fn fields(headers: &Headers) -> Vec<(String, Vec<u8>)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_vec()))
+        .collect()
+}
@@ -5,7 +5,7 @@ use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::Subscriber
 ///
 /// This won't play well outside of cargo-nextest or running a single
 /// test, which is a limitation we accept.
-pub(crate) fn setup_tracing() {
+pub fn setup_tracing() {
     let targets = if let Ok(rust_log) = std::env::var("RUST_LOG") {
         rust_log.parse::<Targets>().unwrap()
     } else {
@@ -0,0 +1,1343 @@
+use bytes::Bytes;
+use fluke::{
+    body::{NdJsonStream, NDJSON_CONTENT_TYPE},
+    capture::{Capture, CaptureDriver, Outcome},
+    h2::{ErrorCode, KnownErrorCode, StreamClosed},
+    Protocol, ServerBuilder,
+};
+use fluke::{
+    http::{header, HeaderName, Method, StatusCode},
+    Body, Encoder, ExpectResponseHeaders, FieldDecision, HeaderDecision, Headers, Request,
+    Responder, Response, ResponseDone, ServerDriver,
+};
+use fluke_testutils::{replay::Session, Proto, TestClient, TestServer};
+use futures_util::StreamExt;
+
+struct EchoDriver;
+
+const LARGE_BODY_CHUNKS: usize = 64;
+
+fn large_chunk(i: usize) -> Vec<u8> {
+    vec![(i % 251) as u8; 64 * 1024]
+}
+
+impl ServerDriver for EchoDriver {
+    fn on_headers(&self, req: &Request) -> HeaderDecision {
+        if req.uri.path() == "/private" {
+            return HeaderDecision::Respond {
+                res: Response {
+                    status: StatusCode::UNAUTHORIZED,
+                    ..Default::default()
+                },
+                body: "nope".into(),
+            };
+        }
+        HeaderDecision::Continue
+    }
+
+    fn on_header_field(&self, name: &HeaderName, value: &[u8]) -> FieldDecision {
+        match name.as_str() {
+            "x-sso-token" if value.len() > 1024 => {
+                FieldDecision::Reject(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+            }
+            "x-ignored" => FieldDecision::Skip,
+            _ => FieldDecision::Keep,
+        }
+    }
+
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        match req.uri.path() {
+            "/panic" => panic!("driver panicked on purpose"),
+            "/slow" => {
+                // way past any deadline the tests set
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            }
+            "/reset" => {
+                let respond = respond.write_final_response(Response::default()).await?;
+                return respond.reset(KnownErrorCode::Cancel).await;
+            }
+            "/no-content" => {
+                let mut respond = respond
+                    .write_final_response(Response {
+                        status: StatusCode::NO_CONTENT,
+                        ..Default::default()
+                    })
+                    .await?;
+                // a 204 can't have a body, the encoder must refuse to send it
+                assert!(respond.write_chunk("oops".into()).await.is_err());
+                return respond.finish_body(None).await;
+            }
+            "/ndjson" => {
+                let items = futures_util::stream::iter(1..=3)
+                    .map(|i| serde_json::json!({ "id": i, "name": format!("item {i}") }));
+                let mut res = Response::default();
+                res.headers
+                    .insert(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE.into());
+                return respond
+                    .write_final_response_with_body(res, &mut NdJsonStream::new(items))
+                    .await;
+            }
+            "/buffered" => {
+                let mut respond = respond.write_final_response(Response::default()).await?;
+                for i in 0..100 {
+                    respond
+                        .write_chunk_buffered(format!("{i},").into_bytes().into())
+                        .await?;
+                }
+                respond.write_chunk("flushed,".into()).await?;
+                respond.write_chunk_buffered("done".into()).await?;
+                return respond.finish_body(None).await;
+            }
+            "/protocol" => {
+                let body = format!("{:?} {:?}", req.protocol(), req.stream_id.map(|id| id.0));
+                let res = Response::default();
+                let mut respond = respond.write_final_response(res).await?;
+                respond.write_chunk(body.into_bytes().into()).await?;
+                return respond.finish_body(None).await;
+            }
+            "/rtt" => {
+                // the first PING goes out with the server's settings, its
+                // ack may not be in yet
+                for _ in 0..100 {
+                    if req.conn.rtt().is_some() {
+                        break;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                let body = format!("{:?}", req.conn.rtt());
+                let mut respond = respond.write_final_response(Response::default()).await?;
+                respond.write_chunk(body.into_bytes().into()).await?;
+                return respond.finish_body(None).await;
+            }
+            "/fields" => {
+                let body = format!(
+                    "{} {}",
+                    req.headers.contains_key("x-kept"),
+                    req.headers.contains_key("x-ignored")
+                );
+                let mut respond = respond.write_final_response(Response::default()).await?;
+                respond.write_chunk(body.into_bytes().into()).await?;
+                return respond.finish_body(None).await;
+            }
+            "/progress" => {
+                let before = req_body.bytes_read();
+                req_body.collect(1024).await?;
+                let body = format!(
+                    "{before:?} {:?} {:?}",
+                    req_body.bytes_read(),
+                    req_body.expected_len()
+                );
+                let mut respond = respond.write_final_response(Response::default()).await?;
+                respond.write_chunk(body.into_bytes().into()).await?;
+                return respond.finish_body(None).await;
+            }
+            "/streams" => {
+                let body = format!("{} {:?}", req.conn.open_streams(), req.conn.max_streams());
+                let mut respond = respond.write_final_response(Response::default()).await?;
+                respond.write_chunk(body.into_bytes().into()).await?;
+                return respond.finish_body(None).await;
+            }
+            "/short" => {
+                // announces more than it sends
+                let mut respond = respond
+                    .write_final_response(Response {
+                        headers: {
+                            let mut headers = Headers::default();
+                            headers.insert(header::CONTENT_LENGTH, "10".into());
+                            headers
+                        },
+                        ..Default::default()
+                    })
+                    .await?;
+                respond.write_chunk("short".into()).await?;
+                return respond.finish_body(None).await;
+            }
+            "/large" => {
+                let mut respond = respond.write_final_response(Response::default()).await?;
+                for i in 0..LARGE_BODY_CHUNKS {
+                    respond.write_chunk(large_chunk(i).into()).await?;
+                }
+                return respond.finish_body(None).await;
+            }
+            _ => {}
+        }
+
+        let res = Response {
+            status: StatusCode::OK,
+            headers: {
+                let mut headers = Headers::default();
+                headers.insert(header::SERVER, "fluke-testutils/1.0".into());
+                headers
+            },
+            ..Default::default()
+        };
+        respond.write_final_response_with_body(res, req_body).await
+    }
+}
+
+fn echo(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        let res = client.get("/").await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers[header::SERVER], "fluke-testutils/1.0");
+        assert!(res.body.is_empty());
+
+        // same connection, second request
+        let res = client.post("/echo", "Please return to sender").await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.text(), "Please return to sender");
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_echo() {
+    echo(Proto::H1)
+}
+
+#[test]
+fn h2_echo() {
+    echo(Proto::H2)
+}
+
+fn capture(proto: Proto) {
+    fluke_testutils::run(async move {
+        let capture = Capture::new(2);
+        let server =
+            TestServer::start(proto, CaptureDriver::new(EchoDriver, capture.clone())).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        assert_eq!(client.get("/").await?.status, StatusCode::OK);
+        let res = client.post("/echo", "hello").await?;
+        assert_eq!(res.text(), "hello");
+        let res = client.get("/private").await?;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+        // the handler may still be wrapping up after the client got its
+        // response
+        for _ in 0..100 {
+            if capture.recorded() == 3 && capture.recent().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let recent = capture.recent();
+        assert_eq!(recent.len(), 2, "{recent:#?}");
+
+        let echo = &recent.iter().find(|t| t.uri.path() == "/echo").unwrap();
+        assert_eq!(echo.method, fluke::Method::Post);
+        assert_eq!(echo.status, Some(StatusCode::OK));
+        assert_eq!(echo.response_body_len, 5);
+        assert_eq!(echo.outcome, Outcome::Completed);
+
+        let private = &recent.iter().find(|t| t.uri.path() == "/private").unwrap();
+        assert_eq!(private.status, Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(private.outcome, Outcome::AnsweredEarly);
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_capture() {
+    capture(Proto::H1)
+}
+
+#[test]
+fn h2_capture() {
+    capture(Proto::H2)
+}
+
+fn driver_panic(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        let res = client.get("/panic").await?;
+        assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        // h1 connections get closed after a panic, h2 ones stay usable
+        if proto == Proto::H1 {
+            client = TestClient::connect(proto, server.addr()).await?;
+        }
+        let res = client.post("/echo", "still alive").await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.text(), "still alive");
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_driver_panic() {
+    driver_panic(Proto::H1)
+}
+
+#[test]
+fn h2_driver_panic() {
+    driver_panic(Proto::H2)
+}
+
+fn driver_reset(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        // h2 resets just the stream, h1 can only close the connection
+        assert!(client.get("/reset").await.is_err());
+        if proto == Proto::H1 {
+            client = TestClient::connect(proto, server.addr()).await?;
+        }
+        let res = client.post("/echo", "still alive").await?;
+        assert_eq!(res.text(), "still alive");
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_driver_reset() {
+    driver_reset(Proto::H1)
+}
+
+#[test]
+fn h2_driver_reset() {
+    driver_reset(Proto::H2)
+}
+
+fn bodyless_responses(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        let res = client.send(Method::HEAD, "/echo", Bytes::new()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(res.headers.get(header::TRANSFER_ENCODING).is_none());
+        assert!(res.body.is_empty());
+
+        // nothing may have leaked onto the connection after the HEAD response
+        let res = client.post("/echo", "still alive").await?;
+        assert_eq!(res.text(), "still alive");
+
+        let res = client.get("/no-content").await?;
+        assert_eq!(res.status, StatusCode::NO_CONTENT);
+        assert!(res.body.is_empty());
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_bodyless_responses() {
+    bodyless_responses(Proto::H1)
+}
+
+#[test]
+fn h2_bodyless_responses() {
+    bodyless_responses(Proto::H2)
+}
+
+fn ndjson_body(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        let res = client.get("/ndjson").await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers[header::CONTENT_TYPE], NDJSON_CONTENT_TYPE);
+        assert_eq!(
+            res.text(),
+            concat!(
+                "{\"id\":1,\"name\":\"item 1\"}\n",
+                "{\"id\":2,\"name\":\"item 2\"}\n",
+                "{\"id\":3,\"name\":\"item 3\"}\n",
+            )
+        );
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_ndjson_body() {
+    ndjson_body(Proto::H1)
+}
+
+#[test]
+fn h2_ndjson_body() {
+    ndjson_body(Proto::H2)
+}
+
+fn buffered_writes(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        let res = client.get("/buffered").await?;
+        let expected = (0..100).map(|i| format!("{i},")).collect::<String>() + "flushed,done";
+        assert_eq!(res.text(), expected);
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_buffered_writes() {
+    buffered_writes(Proto::H1)
+}
+
+#[test]
+fn h2_buffered_writes() {
+    buffered_writes(Proto::H2)
+}
+
+fn request_protocol(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        let expected = match proto {
+            Proto::H1 => ["H1_1 None", "H1_1 None"],
+            Proto::H2 => ["H2 Some(1)", "H2 Some(3)"],
+        };
+        for expected in expected {
+            let res = client.get("/protocol").await?;
+            assert_eq!(res.text(), expected);
+        }
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_request_protocol() {
+    request_protocol(Proto::H1)
+}
+
+#[test]
+fn h2_request_protocol() {
+    request_protocol(Proto::H2)
+}
+
+fn body_progress(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        let body = "Please return to sender";
+        let req = http::Request::builder()
+            .method(Method::POST)
+            .uri(server.url("/progress"))
+            .header(header::HOST, server.addr().to_string())
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Bytes::from_static(body.as_bytes()).into())?;
+        let res = client.request(req).await?;
+        assert_eq!(res.text(), "Some(0) Some(23) Some(23)");
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_body_progress() {
+    body_progress(Proto::H1)
+}
+
+#[test]
+fn h2_body_progress() {
+    body_progress(Proto::H2)
+}
+
+fn content_length_mismatch(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        // a truncated body must not look complete: h2 resets the stream, h1
+        // closes the connection
+        assert!(client.get("/short").await.is_err());
+        if proto == Proto::H1 {
+            client = TestClient::connect(proto, server.addr()).await?;
+        }
+        let res = client.post("/echo", "still alive").await?;
+        assert_eq!(res.text(), "still alive");
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_content_length_mismatch() {
+    content_length_mismatch(Proto::H1)
+}
+
+#[test]
+fn h2_content_length_mismatch() {
+    content_length_mismatch(Proto::H2)
+}
+
+/// Writes an endless response body, and remembers why it had to stop
+#[derive(Default)]
+struct EndlessDriver {
+    stopped: std::cell::Cell<Option<ErrorCode>>,
+}
+
+impl ServerDriver for EndlessDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        _req: Request,
+        _req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut respond = respond.write_final_response(Response::default()).await?;
+        loop {
+            if let Err(e) = respond.write_chunk(large_chunk(0).into()).await {
+                let closed = e.downcast_ref::<StreamClosed>().copied();
+                self.stopped.set(closed.map(|c| c.error_code));
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[test]
+fn h2_client_reset() {
+    fluke_testutils::run(async move {
+        let driver = std::rc::Rc::new(EndlessDriver::default());
+        let server = TestServer::start(Proto::H2, driver.clone()).await?;
+        let mut client = TestClient::connect(Proto::H2, server.addr()).await?;
+
+        let req = http::Request::builder()
+            .uri(server.url("/endless"))
+            .body(Default::default())?;
+        let res = client.request_streaming(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        // hyper cancels the stream when the response is dropped
+        drop(res);
+
+        for _ in 0..100 {
+            if driver.stopped.get().is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let code = driver.stopped.get().map(|c| c.as_repr());
+        assert_eq!(code, Some(KnownErrorCode::Cancel as u32));
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+fn large_response(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        // way bigger than the h2 per-stream backlog and the initial window
+        let res = client.get("/large").await?;
+        assert_eq!(res.status, StatusCode::OK);
+        let expected: Vec<u8> = (0..LARGE_BODY_CHUNKS).flat_map(large_chunk).collect();
+        assert_eq!(res.body, expected);
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_large_response() {
+    large_response(Proto::H1)
+}
+
+#[test]
+fn h2_large_response() {
+    large_response(Proto::H2)
+}
+
+fn large_upload(proto: Proto, adaptive_window: Option<u32>) {
+    fluke_testutils::run(async move {
+        let h2_conf = fluke::h2::ServerConf {
+            adaptive_window,
+            ..Default::default()
+        };
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .h2_conf(h2_conf)
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let running = fluke::buffet::spawn(server.run());
+
+        // way bigger than the initial receive windows
+        let body: Vec<u8> = (0..LARGE_BODY_CHUNKS).flat_map(large_chunk).collect();
+        let mut client = TestClient::connect(proto, addr).await?;
+        let res = client.post("/echo", body.clone()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(res.body == body, "echoed body doesn't match");
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_large_upload() {
+    large_upload(Proto::H1, None)
+}
+
+#[test]
+fn h2_large_upload() {
+    large_upload(Proto::H2, None)
+}
+
+#[test]
+fn h2_large_upload_adaptive_window() {
+    large_upload(Proto::H2, Some(4 * 1024 * 1024))
+}
+
+fn early_reject(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        let res = client.get("/private").await?;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res.text(), "nope");
+
+        if proto == Proto::H2 {
+            // more than the initial window: the server resets the stream
+            // with NO_ERROR once it has replied, the connection lives on
+            let body = [large_chunk(0), large_chunk(1)].concat();
+            let res = client.post("/private", body).await?;
+            assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+            let res = client.post("/echo", "still alive").await?;
+            assert_eq!(res.text(), "still alive");
+        } else {
+            // the body is never read, so the connection can't be reused
+            let addr = server.addr();
+            let res = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+                use std::io::{Read, Write};
+
+                let mut sock = std::net::TcpStream::connect(addr)?;
+                sock.write_all(b"POST /private HTTP/1.1\r\ncontent-length: 1000000\r\n\r\n")?;
+                let mut res = String::new();
+                sock.read_to_string(&mut res)?;
+                Ok(res)
+            })
+            .await??;
+            assert!(
+                res.starts_with("HTTP/1.1 401 ") && res.contains("connection: close"),
+                "unexpected response: {res:?}"
+            );
+            assert!(res.ends_with("nope"), "unexpected response: {res:?}");
+        }
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_early_reject() {
+    early_reject(Proto::H1)
+}
+
+#[test]
+fn h2_early_reject() {
+    early_reject(Proto::H2)
+}
+
+fn header_fields(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(proto, EchoDriver).await?;
+        let mut client = TestClient::connect(proto, server.addr()).await?;
+
+        let req = |token: String| {
+            http::Request::builder()
+                .uri(server.url("/fields"))
+                .header(header::HOST, server.addr().to_string())
+                .header("x-kept", "1")
+                .header("x-ignored", "1")
+                .header("x-sso-token", token)
+                .body(Default::default())
+        };
+
+        let res = client.request(req("a".repeat(512))?).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.text(), "true false");
+
+        let res = client.request(req("a".repeat(4096))?).await?;
+        assert_eq!(res.status, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_header_fields() {
+    header_fields(Proto::H1)
+}
+
+#[test]
+fn h2_header_fields() {
+    header_fields(Proto::H2)
+}
+
+#[test]
+fn h1_oversized_headers() {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(Proto::H1, EchoDriver).await?;
+        let addr = server.addr();
+
+        // the server replies once it has read 64KiB of headers, but the
+        // client is still writing: unless the server lets it finish, the
+        // client gets a RST instead of the response.
+        let res = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+            use std::io::{Read, Write};
+
+            let mut sock = std::net::TcpStream::connect(addr)?;
+            sock.write_all(b"GET / HTTP/1.1\r\n")?;
+            for i in 0..512 {
+                // the server may stop reading as soon as it has replied
+                if sock
+                    .write_all(format!("x-filler-{i}: {}\r\n", "x".repeat(4000)).as_bytes())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            let mut res = String::new();
+            sock.read_to_string(&mut res)?;
+            Ok(res)
+        })
+        .await??;
+        assert!(
+            res.starts_with("HTTP/1.1 431 "),
+            "unexpected response: {res:?}"
+        );
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_http10_requests() {
+    fluke_testutils::run(async move {
+        let server = TestServer::start(Proto::H1, EchoDriver).await?;
+        let addr = server.addr();
+
+        let res = tokio::task::spawn_blocking(move || -> std::io::Result<Vec<String>> {
+            use std::io::{Read, Write};
+
+            let roundtrip = |req: &[u8]| -> std::io::Result<String> {
+                let mut sock = std::net::TcpStream::connect(addr)?;
+                sock.write_all(req)?;
+                // only returns if the server closes the connection
+                let mut res = String::new();
+                sock.read_to_string(&mut res)?;
+                Ok(res)
+            };
+
+            let mut responses = vec![
+                // no chunked encoding for 1.0 clients: the body ends when
+                // the connection closes
+                roundtrip(b"GET /buffered HTTP/1.0\r\n\r\n")?,
+                // a 1.0 request can't be framed with transfer-encoding
+                roundtrip(b"POST /echo HTTP/1.0\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n")?,
+            ];
+
+            // keep-alive has to be asked for, and is honored if the
+            // response length is known
+            let mut sock = std::net::TcpStream::connect(addr)?;
+            sock.write_all(b"GET / HTTP/1.0\r\nconnection: keep-alive\r\n\r\n")?;
+            sock.write_all(b"GET / HTTP/1.0\r\n\r\n")?;
+            let mut res = String::new();
+            sock.read_to_string(&mut res)?;
+            responses.push(res);
+
+            Ok(responses)
+        })
+        .await??;
+
+        let body = res[0].split_once("\r\n\r\n").map(|(head, body)| {
+            assert!(
+                !head.contains("transfer-encoding"),
+                "unexpected response: {head:?}"
+            );
+            assert!(
+                head.contains("connection: close"),
+                "unexpected response: {head:?}"
+            );
+            body
+        });
+        let expected = (0..100).map(|i| format!("{i},")).collect::<String>() + "flushed,done";
+        assert_eq!(body, Some(expected.as_str()));
+        assert!(
+            res[1].starts_with("HTTP/1.1 400 "),
+            "unexpected response: {:?}",
+            res[1]
+        );
+        assert_eq!(
+            res[2].matches("HTTP/1.1 200 ").count(),
+            2,
+            "unexpected response: {:?}",
+            res[2]
+        );
+        assert!(res[2].contains("connection: keep-alive"));
+
+        server.shutdown().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_options_asterisk() {
+    fluke_testutils::run(async move {
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .protocol(Protocol::H1)
+            .options_allow(Some("GET, HEAD, OPTIONS".into()))
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let running = fluke::buffet::spawn(server.run());
+
+        let res = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+            use std::io::{Read, Write};
+
+            let mut sock = std::net::TcpStream::connect(addr)?;
+            sock.write_all(b"OPTIONS * HTTP/1.1\r\nhost: localhost\r\n\r\n")?;
+            sock.write_all(b"GET * HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")?;
+            let mut res = String::new();
+            sock.read_to_string(&mut res)?;
+            Ok(res)
+        })
+        .await??;
+
+        let (first, second) = res.split_once("\r\n\r\n").unwrap();
+        assert!(
+            first.starts_with("HTTP/1.1 200 "),
+            "unexpected response: {res:?}"
+        );
+        assert!(
+            first.contains("allow: GET, HEAD, OPTIONS"),
+            "unexpected response: {res:?}"
+        );
+        assert!(
+            second.starts_with("HTTP/1.1 400 "),
+            "unexpected response: {res:?}"
+        );
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn builder_auto_protocol() {
+    fluke_testutils::run(async move {
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .protocol(Protocol::Auto)
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let stats = server.stats();
+        let running = fluke::buffet::spawn(server.run());
+
+        for proto in [Proto::H1, Proto::H2] {
+            let mut client = TestClient::connect(proto, addr).await?;
+            let res = client.post("/echo", format!("hi from {proto:?}")).await?;
+            assert_eq!(res.status, StatusCode::OK);
+            assert_eq!(res.text(), format!("hi from {proto:?}"));
+        }
+        assert_eq!(stats.accepted(), 2);
+
+        running.abort();
+        Ok(())
+    })
+}
+
+fn request_deadline(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .request_timeout(Some(std::time::Duration::from_millis(100)))
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let running = fluke::buffet::spawn(server.run());
+
+        let mut client = TestClient::connect(proto, addr).await?;
+        let res = client.get("/slow").await?;
+        assert_eq!(res.status, StatusCode::GATEWAY_TIMEOUT);
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_request_deadline() {
+    request_deadline(Proto::H1)
+}
+
+#[test]
+fn h2_request_deadline() {
+    request_deadline(Proto::H2)
+}
+
+fn ping_rtt(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .ping_interval(Some(std::time::Duration::from_millis(20)))
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let running = fluke::buffet::spawn(server.run());
+
+        let mut client = TestClient::connect(proto, addr).await?;
+        let res = client.get("/rtt").await?;
+        assert_eq!(res.status, StatusCode::OK);
+        match proto {
+            // no PING frames in HTTP/1.1
+            Proto::H1 => assert_eq!(res.text(), "None"),
+            Proto::H2 => assert!(res.text().starts_with("Some("), "{}", res.text()),
+        }
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_ping_rtt() {
+    ping_rtt(Proto::H1)
+}
+
+#[test]
+fn h2_ping_rtt() {
+    ping_rtt(Proto::H2)
+}
+
+fn error_pages(proto: Proto) {
+    fluke_testutils::run(async move {
+        let mut pages = fluke::ErrorPages::default();
+        pages.insert_template(
+            [StatusCode::INTERNAL_SERVER_ERROR],
+            "text/html",
+            "<h1>{status} {reason}</h1>",
+        );
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .error_pages(pages)
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let running = fluke::buffet::spawn(server.run());
+        let page = "<h1>500 Internal Server Error</h1>";
+
+        let mut client = TestClient::connect(proto, addr).await?;
+        let res = client.get("/panic").await?;
+        assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.headers[header::CONTENT_TYPE], "text/html");
+        assert_eq!(res.text(), page);
+
+        // h1 connections get closed after a panic
+        let mut client = TestClient::connect(proto, addr).await?;
+        let res = client.send(Method::HEAD, "/panic", Bytes::new()).await?;
+        assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(res.body.is_empty(), "HEAD responses have no body");
+        if proto == Proto::H1 {
+            assert_eq!(
+                res.headers[header::CONTENT_LENGTH],
+                page.len().to_string().as_str()
+            );
+        }
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_error_pages() {
+    error_pages(Proto::H1)
+}
+
+#[test]
+fn h2_error_pages() {
+    error_pages(Proto::H2)
+}
+
+fn stream_counts(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .max_streams(Some(8))
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let running = fluke::buffet::spawn(server.run());
+
+        let mut client = TestClient::connect(proto, addr).await?;
+        let res = client.get("/streams").await?;
+        assert_eq!(res.status, StatusCode::OK);
+        match proto {
+            Proto::H1 => assert_eq!(res.text(), "0 None"),
+            Proto::H2 => assert_eq!(res.text(), "1 Some(8)"),
+        }
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_stream_counts() {
+    stream_counts(Proto::H1)
+}
+
+#[test]
+fn h2_stream_counts() {
+    stream_counts(Proto::H2)
+}
+
+fn uri_too_long(proto: Proto) {
+    fluke_testutils::run(async move {
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .max_uri_len(64)
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let running = fluke::buffet::spawn(server.run());
+
+        let mut client = TestClient::connect(proto, addr).await?;
+        let res = client.get(&format!("/{}", "a".repeat(63))).await?;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let res = client.get(&format!("/{}", "a".repeat(64))).await?;
+        assert_eq!(res.status, StatusCode::URI_TOO_LONG);
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_uri_too_long() {
+    uri_too_long(Proto::H1)
+}
+
+#[test]
+fn h2_uri_too_long() {
+    uri_too_long(Proto::H2)
+}
+
+#[test]
+fn builder_reload_conf() {
+    fluke_testutils::run(async move {
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .protocol(Protocol::H1)
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let conf = server.conf();
+        let running = fluke::buffet::spawn(server.run());
+
+        // established connections keep the settings they were accepted with
+        let mut old_client = TestClient::connect(Proto::H1, addr).await?;
+        assert_eq!(old_client.get("/").await?.status, StatusCode::OK);
+
+        // no connection can fit in that
+        conf.update(|c| c.h1.memory_budget = Some(1));
+
+        let mut new_client = TestClient::connect(Proto::H1, addr).await?;
+        assert!(new_client.get("/").await.is_err());
+
+        assert_eq!(old_client.get("/").await?.status, StatusCode::OK);
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn builder_listener_pause() {
+    fluke_testutils::run(async move {
+        let server = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .protocol(Protocol::H1)
+            .build(EchoDriver)
+            .await?;
+        let addr = server.local_addr();
+        let listener = server.listener();
+        let running = fluke::buffet::spawn(server.run());
+
+        let mut old_client = TestClient::connect(Proto::H1, addr).await?;
+        assert_eq!(old_client.get("/").await?.status, StatusCode::OK);
+
+        // paused: clients can connect, but wait to be served
+        listener.pause();
+        let waiting = fluke::buffet::spawn(async move {
+            let mut client = TestClient::connect(Proto::H1, addr).await?;
+            client.get("/").await
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        // already accepted connections are still served
+        assert_eq!(old_client.get("/").await?.status, StatusCode::OK);
+
+        listener.resume();
+        assert_eq!(waiting.await??.status, StatusCode::OK);
+
+        // closed: clients are refused
+        listener.pause_and_close();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+        assert_eq!(old_client.get("/").await?.status, StatusCode::OK);
+
+        listener.resume();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let mut client = TestClient::connect(Proto::H1, addr).await?;
+        assert_eq!(client.get("/").await?.status, StatusCode::OK);
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn builder_from_listener() {
+    fluke_testutils::run(async move {
+        // as if inherited from a parent process
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = ServerBuilder::from_listener(listener)
+            .protocol(Protocol::Auto)
+            .build(EchoDriver)
+            .await?;
+        assert_eq!(server.local_addr(), addr);
+        let running = fluke::buffet::spawn(server.run());
+
+        for proto in [Proto::H1, Proto::H2] {
+            let mut client = TestClient::connect(proto, addr).await?;
+            assert_eq!(client.get("/").await?.status, StatusCode::OK);
+        }
+
+        running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn builder_handover() {
+    fluke_testutils::run(async move {
+        let path = std::env::temp_dir().join(format!("fluke-handover-{}.sock", std::process::id()));
+
+        let old = ServerBuilder::new("127.0.0.1:0".parse()?)
+            .protocol(Protocol::Auto)
+            .build(EchoDriver)
+            .await?;
+        let addr = old.local_addr();
+        let old_stats = old.stats();
+        let listener = old.listener();
+        let old_running = fluke::buffet::spawn(old.run());
+
+        // a connection that's still open when the new process takes over
+        let mut old_client = TestClient::connect(Proto::H1, addr).await?;
+        assert_eq!(old_client.get("/").await?.status, StatusCode::OK);
+
+        let handing_over = fluke::buffet::spawn({
+            let path = path.clone();
+            async move { fluke::handover::hand_over(&path, &[("http", &listener)]).await }
+        });
+        let mut takeover = loop {
+            match fluke::handover::take_over(&path).await {
+                Ok(takeover) => break takeover,
+                // not listening just yet
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let socket = takeover.take("http").expect("no http socket");
+        assert!(takeover.take_all().is_empty());
+        let new = ServerBuilder::from_listener(socket)
+            .protocol(Protocol::Auto)
+            .build(EchoDriver)
+            .await?;
+        assert_eq!(new.local_addr(), addr);
+        let new_stats = new.stats();
+        let new_running = fluke::buffet::spawn(new.run());
+        takeover.ready().await?;
+        handing_over.await.unwrap()?;
+        assert!(!path.exists());
+
+        // the old server is draining, but still serves what it accepted
+        assert_eq!(old_client.get("/").await?.status, StatusCode::OK);
+        for proto in [Proto::H1, Proto::H2] {
+            let mut client = TestClient::connect(proto, addr).await?;
+            assert_eq!(client.get("/").await?.status, StatusCode::OK);
+        }
+        assert_eq!(old_stats.accepted(), 1);
+        assert_eq!(new_stats.accepted(), 2);
+
+        drop(old_client);
+        old_running.await.unwrap()?;
+
+        new_running.abort();
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_recovers_from_malformed_heads() {
+    fluke_testutils::run(async move {
+        let session = Session::new(Proto::H1)
+            .data("GET /a b c HTTP/1.1\r\nhost: a\r\n\r\n")
+            .data("POST /echo HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello")
+            // no telling where this one's body ends: no recovering from it
+            .data("GET /a b c HTTP/1.1\r\ncontent-length: 5\r\n\r\n")
+            .data("GET /echo HTTP/1.1\r\n\r\n");
+        let conf = fluke::h1::ServerConf {
+            max_recovered_errors: 8,
+            ..Default::default()
+        };
+        let replay = session
+            .replay_with(conf, Default::default(), EchoDriver)
+            .await?;
+        assert!(replay.outcome.is_ok());
+        let output = replay.output_text();
+        let (first, second) = output.split_once("\r\n\r\n").unwrap();
+        assert!(first.starts_with("HTTP/1.1 400 "), "{output:?}");
+        assert!(!first.contains("connection: close"), "{output:?}");
+        assert!(second.starts_with("HTTP/1.1 200 "), "{output:?}");
+        assert!(second.ends_with("hello"), "{output:?}");
+
+        // off by default
+        let replay = session.replay(EchoDriver).await?;
+        assert_eq!(replay.output_text(), "");
+        Ok(())
+    })
+}
+
+/// A chunked upload using `le` as its line terminator everywhere, with
+/// terminators split across reads: in the head, where the first read buffer
+/// (4096 bytes) ends, and in the chunked framing, across writes.
+fn split_line_endings(le: &str) -> Session {
+    let (first, second) = le.split_at(1);
+    let prefix = format!("POST /echo HTTP/1.1{le}transfer-encoding: chunked{le}x-pad: ");
+    let pad = "a".repeat(4095 - prefix.len());
+    Session::new(Proto::H1)
+        .data(format!("{prefix}{pad}{le}host: a{le}{le}"))
+        .data(format!("5{first}"))
+        .data(format!("{second}hello{first}"))
+        .data(format!("{second}0{le}{le}"))
+}
+
+#[test]
+fn h1_crlf_split_across_reads() {
+    fluke_testutils::run(async move {
+        let replay = split_line_endings("\r\n").replay(EchoDriver).await?;
+        let output = replay.output_text();
+        assert!(output.starts_with("HTTP/1.1 200 "), "{output:?}");
+        assert!(output.contains("\r\n5\r\nhello\r\n"), "{output:?}");
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_bare_lf() {
+    fluke_testutils::run(async move {
+        let session = split_line_endings("\n");
+
+        let replay = session.replay(EchoDriver).await?;
+        assert_eq!(replay.output_text(), "");
+
+        let conf = fluke::h1::ServerConf {
+            line_endings: fluke::h1::LineEndings::Lenient,
+            ..Default::default()
+        };
+        let replay = session
+            .replay_with(conf, Default::default(), EchoDriver)
+            .await?;
+        let output = replay.output_text();
+        assert!(output.starts_with("HTTP/1.1 200 "), "{output:?}");
+        assert!(output.contains("\r\n5\r\nhello\r\n"), "{output:?}");
+        Ok(())
+    })
+}
+
+/// Keeps every chunk of the request body until it's all in, then responds
+/// with how many distinct pool blocks they point into, and the body length
+struct HoardingDriver;
+
+impl ServerDriver for HoardingDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        _req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut chunks = Vec::new();
+        while let fluke::BodyChunk::Chunk(chunk) = req_body.next_chunk().await? {
+            chunks.push(chunk);
+        }
+        let blocks: std::collections::HashSet<_> =
+            chunks.iter().filter_map(|c| c.block()).collect();
+        let len: usize = chunks.iter().map(|c| c.len()).sum();
+
+        let mut respond = respond
+            .write_final_response(Response {
+                status: StatusCode::OK,
+                ..Default::default()
+            })
+            .await?;
+        respond
+            .write_chunk(format!("{} {len}", blocks.len()).into_bytes().into())
+            .await?;
+        respond.finish_body(None).await
+    }
+}
+
+#[test]
+fn h1_body_loans_are_bounded() {
+    fluke_testutils::run(async move {
+        // small writes, so the body takes many reads (and blocks)
+        let mut session =
+            Session::new(Proto::H1).data("POST / HTTP/1.1\r\ncontent-length: 65536\r\n\r\n");
+        for _ in 0..64 {
+            session = session.data(vec![b'a'; 1024]);
+        }
+
+        let conf = fluke::h1::ServerConf {
+            loan_limit: Some(fluke::LoanLimit {
+                max_blocks: 4,
+                max_wait: std::time::Duration::from_millis(10),
+            }),
+            ..Default::default()
+        };
+        let replay = session
+            .replay_with(conf, Default::default(), HoardingDriver)
+            .await?;
+        let output = replay.output_text();
+        let (_, body) = output.split_once("\r\n\r\n").unwrap();
+        // past the limit, the driver got copies: it still got everything
+        assert!(body.contains("4 65536"), "{output:?}");
+        Ok(())
+    })
+}
@@ -0,0 +1,91 @@
+use std::{path::PathBuf, time::Duration};
+
+use fluke::{
+    http::StatusCode, Body, Encoder, ExpectResponseHeaders, Request, Responder, Response,
+    ResponseDone, ServerDriver,
+};
+use fluke_testutils::{
+    replay::{Event, Session},
+    Proto,
+};
+
+/// Responds with the request path
+struct PathDriver;
+
+impl ServerDriver for PathDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        req_body.collect(1024).await?;
+        let res = Response {
+            status: StatusCode::OK,
+            ..Default::default()
+        };
+        let mut respond = respond.write_final_response(res).await?;
+        respond
+            .write_chunk(req.uri.path().to_string().into_bytes().into())
+            .await?;
+        respond.finish_body(None).await
+    }
+}
+
+/// Every recording in `tests/sessions/` must be served without errors:
+/// drop a session that made the server misbehave in there, and it's
+/// replayed from then on.
+#[test]
+fn recorded_sessions() {
+    fluke_testutils::run(async {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/sessions");
+        let mut replayed = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let session = Session::load(&path)?;
+            let replay = session.replay(PathDriver).await?;
+            if let Err(e) = &replay.outcome {
+                panic!("{}: {e}", path.display());
+            }
+            replayed += 1;
+        }
+        assert!(replayed > 0);
+        Ok(())
+    });
+}
+
+#[test]
+fn h1_pipelined_split() {
+    fluke_testutils::run(async {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/sessions/h1-pipelined-split.flukerec");
+        let session = Session::load(path)?;
+        assert_eq!(session.proto, Proto::H1);
+        assert_eq!(session.events.len(), 5);
+
+        let replay = session.replay(PathDriver).await?;
+        let output = replay.output_text();
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 2, "{output}");
+        let first = output.find("/first").unwrap();
+        let second = output.find("/second").unwrap();
+        assert!(first < second, "{output}");
+        Ok(())
+    });
+}
+
+#[test]
+fn encoding_round_trips() {
+    let session = Session::new(Proto::H2)
+        .data(&b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"[..])
+        .pause(Duration::from_millis(250))
+        .reset();
+    let encoded = session.encode();
+    assert_eq!(Session::decode(&encoded).unwrap(), session);
+
+    // a recorder that died mid-record: the complete records are kept
+    let truncated = Session::decode(&encoded[..encoded.len() - 4]).unwrap();
+    assert_eq!(truncated.events.len(), 1);
+    assert!(matches!(truncated.events[0], Event::Data(_)));
+
+    assert!(Session::decode(b"GET / HTTP/1.1\r\n\r\n").is_err());
+}
@@ -0,0 +1,63 @@
+use fluke_testutils::split::{check_splits, Splits};
+
+#[test]
+fn split_simple_get() {
+    fluke_testutils::run(async {
+        let parsed = check_splits(
+            b"GET /hello?name=world HTTP/1.1\r\nhost: example.org\r\nuser-agent: split\r\n\r\n",
+            Splits::All,
+        )
+        .await?;
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].uri, "/hello?name=world");
+        assert_eq!(parsed[0].headers.len(), 2);
+        Ok(())
+    });
+}
+
+#[test]
+fn split_pipelined() {
+    fluke_testutils::run(async {
+        let parsed = check_splits(
+            b"POST /a HTTP/1.1\r\nhost: a\r\ncontent-length: 5\r\n\r\nhello\
+              GET /b HTTP/1.1\r\nhost: a\r\nconnection: close\r\n\r\n",
+            Splits::All,
+        )
+        .await?;
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].body, b"hello");
+        assert_eq!(parsed[1].uri, "/b");
+        Ok(())
+    });
+}
+
+#[test]
+fn split_chunked() {
+    fluke_testutils::run(async {
+        let parsed = check_splits(
+            b"POST /upload HTTP/1.1\r\nhost: a\r\ntransfer-encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n1A\r\nabcdefghijklmnopqrstuvwxyz\r\n0\r\n\r\n",
+            Splits::All,
+        )
+        .await?;
+        assert_eq!(parsed[0].body, b"helloabcdefghijklmnopqrstuvwxyz");
+        Ok(())
+    });
+}
+
+#[test]
+fn split_large_head() {
+    fluke_testutils::run(async {
+        // long enough for the head to span two read buffers
+        let mut input = b"GET / HTTP/1.1\r\nhost: a\r\n".to_vec();
+        for i in 0..64 {
+            input.extend_from_slice(format!("x-header-{i}: {}\r\n", "v".repeat(80)).as_bytes());
+        }
+        input.extend_from_slice(b"\r\n");
+        assert!(input.len() > 4096);
+
+        let parsed = check_splits(&input, Splits::Sample(256)).await?;
+        assert_eq!(parsed[0].headers.len(), 65);
+        Ok(())
+    });
+}
@@ -1,27 +1,25 @@
-use std::{
-    mem::ManuallyDrop,
-    net::ToSocketAddrs,
-    os::unix::prelude::{AsRawFd, FromRawFd},
-    rc::Rc,
-    sync::Arc,
-};
+use std::{net::ToSocketAddrs, rc::Rc, sync::Arc};
 
 use color_eyre::eyre;
 use fluke::{
     buffet::{net::TcpStream, IntoHalves, RollMut},
-    h1, h2, Body, Encoder, ExpectResponseHeaders, Method, Request, Responder, ResponseDone,
-    ServerDriver,
+    h1, h2, Body, ConnInfo, Encoder, ExpectResponseHeaders, Method, Request, Responder,
+    ResponseDone, ServerDriver,
 };
+use fluke::{trace_context::Traced, vhost::VirtualHosts};
+use fluke_tls::{Accepted, Backend, ClientAuth, RustlsAcceptor, TlsAcceptor, ToUringTcpStream};
 use http::Version;
-use ktls::CorkStream;
-use rustls::{
-    pki_types::{CertificateDer, PrivatePkcs8KeyDer},
-    ServerConfig,
-};
+use rustls::{pki_types::CertificateDer, server::WebPkiClientVerifier, ServerConfig};
 use tokio::net::TcpListener;
 use tracing::{debug, info};
 use tracing_subscriber::EnvFilter;
 
+mod certs;
+use certs::SniResolver;
+
+mod upstream;
+use upstream::Upstream;
+
 pub(crate) fn main() -> eyre::Result<()> {
     fluke::buffet::start(async_main())
 }
@@ -39,24 +37,12 @@ async fn async_main() -> eyre::Result<()> {
         return Ok(());
     }
 
-    let pair = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
-    let crt = pair.serialize_der()?;
-    let key = pair.serialize_private_key_der();
-
-    let mut server_config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(
-            vec![CertificateDer::from(crt)],
-            PrivatePkcs8KeyDer::from(key).into(),
-        )
-        .unwrap();
-
-    server_config.key_log = Arc::new(rustls::KeyLogFile::new());
-    server_config.enable_secret_extraction = true;
-    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
-
-    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
-    let acceptor = Rc::new(acceptor);
+    // each host gets its own certificate, and its own driver
+    let hosts = std::env::var("TLS_HOSTS").unwrap_or_else(|_| "localhost".to_string());
+    let hosts: Vec<&str> = hosts.split(',').collect();
+    let backend = backend_from_env()?;
+    let client_auth = client_auth_from_env()?;
+    info!("Using the {backend:?} TLS backend");
 
     let pt_h1_ln = TcpListener::bind("[::]:7080").await?;
     info!("Serving plaintext HTTP/1.1 on {}", pt_h1_ln.local_addr()?);
@@ -69,17 +55,31 @@ async fn async_main() -> eyre::Result<()> {
 
     let h1_conf = Rc::new(h1::ServerConf::default());
     let h2_conf = Rc::new(h2::ServerConf::default());
+    let upstream = Rc::new(Upstream::from_env()?);
+    let driver = {
+        let sdriver = || SDriver {
+            upstream: upstream.clone(),
+        };
+        let mut vhosts = VirtualHosts::new().fallback(sdriver());
+        for host in &hosts {
+            vhosts = vhosts.host(host, sdriver());
+        }
+        Rc::new(Traced::new(vhosts))
+    };
 
     let pt_h1_loop = {
         let h1_conf = h1_conf.clone();
+        let driver = driver.clone();
 
         async move {
             while let Ok((stream, remote_addr)) = pt_h1_ln.accept().await {
                 fluke::buffet::spawn({
                     let h1_conf = h1_conf.clone();
+                    let driver = driver.clone();
                     async move {
                         if let Err(e) =
-                            handle_plaintext_conn(stream, remote_addr, Proto::H1(h1_conf)).await
+                            handle_plaintext_conn(stream, remote_addr, Proto::H1(h1_conf), driver)
+                                .await
                         {
                             tracing::error!(%e, "Error handling connection");
                         }
@@ -93,14 +93,17 @@ async fn async_main() -> eyre::Result<()> {
 
     let pt_h2_loop = {
         let h2_conf = h2_conf.clone();
+        let driver = driver.clone();
 
         async move {
             while let Ok((stream, remote_addr)) = pt_h2_ln.accept().await {
                 fluke::buffet::spawn({
                     let h2_conf = h2_conf.clone();
+                    let driver = driver.clone();
                     async move {
                         if let Err(e) =
-                            handle_plaintext_conn(stream, remote_addr, Proto::H2(h2_conf)).await
+                            handle_plaintext_conn(stream, remote_addr, Proto::H2(h2_conf), driver)
+                                .await
                         {
                             tracing::error!(%e, "Error handling connection");
                         }
@@ -113,28 +116,149 @@ async fn async_main() -> eyre::Result<()> {
     };
 
     let tls_loop = async move {
-        while let Ok((stream, remote_addr)) = tls_ln.accept().await {
-            fluke::buffet::spawn({
-                let acceptor = acceptor.clone();
-                let h1_conf = h1_conf.clone();
-                let h2_conf = h2_conf.clone();
-                async move {
-                    if let Err(e) =
-                        handle_tls_conn(acceptor, stream, remote_addr, h1_conf, h2_conf).await
-                    {
-                        tracing::error!(%e, "Error handling connection");
-                    }
-                }
-            });
+        match backend {
+            Backend::Rustls => {
+                let acceptor = rustls_acceptor(&hosts, client_auth)?;
+                serve_tls(tls_ln, acceptor, h1_conf, h2_conf, driver).await
+            }
+            #[cfg(feature = "boring")]
+            Backend::Boring => {
+                let acceptor = boring_acceptor(hosts[0], client_auth)?;
+                serve_tls(tls_ln, acceptor, h1_conf, h2_conf, driver).await
+            }
         }
-
-        Ok::<_, color_eyre::Report>(())
     };
 
     tokio::try_join!(pt_h1_loop, pt_h2_loop, tls_loop)?;
     Ok(())
 }
 
+/// `TLS_BACKEND` picks the TLS implementation, see [fluke_tls]: `rustls`
+/// (the default) or `boring` (with the `boring` feature)
+pub(crate) fn backend_from_env() -> eyre::Result<Backend> {
+    match std::env::var("TLS_BACKEND") {
+        Ok(name) => name.parse(),
+        Err(_) => Ok(Backend::Rustls),
+    }
+}
+
+/// Serves a certificate per host, see [certs]
+fn rustls_acceptor(
+    hosts: &[&str],
+    client_auth: Option<ClientAuth>,
+) -> eyre::Result<RustlsAcceptor> {
+    let resolver = SniResolver::from_env(hosts)?;
+
+    // reloading swaps the certificates the resolver hands out, the acceptor
+    // stays the same
+    fluke::buffet::spawn({
+        let resolver = resolver.clone();
+        async move {
+            if let Err(e) = resolver.watch().await {
+                tracing::error!(%e, "Stopped watching certificates");
+            }
+        }
+    });
+
+    let verifier = match client_auth {
+        Some(client_auth) => client_auth.rustls_verifier()?,
+        None => WebPkiClientVerifier::no_client_auth(),
+    };
+    let mut server_config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(Arc::new(resolver));
+
+    server_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    server_config.enable_secret_extraction = true;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(RustlsAcceptor::new(server_config))
+}
+
+async fn serve_tls<A>(
+    ln: TcpListener,
+    acceptor: A,
+    h1_conf: Rc<h1::ServerConf>,
+    h2_conf: Rc<h2::ServerConf>,
+    driver: Driver,
+) -> eyre::Result<()>
+where
+    A: TlsAcceptor + 'static,
+{
+    let acceptor = Rc::new(acceptor);
+    while let Ok((stream, remote_addr)) = ln.accept().await {
+        fluke::buffet::spawn({
+            let acceptor = acceptor.clone();
+            let h1_conf = h1_conf.clone();
+            let h2_conf = h2_conf.clone();
+            let driver = driver.clone();
+            async move {
+                if let Err(e) =
+                    handle_tls_conn(acceptor, stream, remote_addr, h1_conf, h2_conf, driver).await
+                {
+                    tracing::error!(%e, "Error handling connection");
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Serves the certificate of `host` with BoringSSL, read from
+/// `TLS_CERT_DIR` like [certs] does (without reloading it), or self-signed
+#[cfg(feature = "boring")]
+fn boring_acceptor(
+    host: &str,
+    client_auth: Option<ClientAuth>,
+) -> eyre::Result<fluke_tls::BoringAcceptor> {
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    let (chain, key) = match std::env::var_os("TLS_CERT_DIR") {
+        Some(dir) => {
+            let dir = std::path::PathBuf::from(dir);
+            let host = host.to_ascii_lowercase();
+            (
+                certs::split_der(&std::fs::read(dir.join(format!("{host}.crt")))?)?,
+                std::fs::read(dir.join(format!("{host}.key")))?,
+            )
+        }
+        None => {
+            let pair = rcgen::generate_simple_self_signed(vec![host.to_string()])?;
+            (
+                vec![CertificateDer::from(pair.serialize_der()?)],
+                pair.serialize_private_key_der(),
+            )
+        }
+    };
+    fluke_tls::BoringAcceptor::new(&chain, &PrivatePkcs8KeyDer::from(key).into(), client_auth)
+}
+
+/// Client certificates are verified against the DER-encoded trust anchors
+/// listed (comma-separated) in `CLIENT_CA`. They're optional unless
+/// `REQUIRE_CLIENT_CERT` is set, in which case the handshake fails without
+/// one. Without `CLIENT_CA`, clients aren't asked for a certificate,
+/// whichever the backend.
+fn client_auth_from_env() -> eyre::Result<Option<ClientAuth>> {
+    let Ok(paths) = std::env::var("CLIENT_CA") else {
+        return Ok(None);
+    };
+
+    let roots = paths
+        .split(',')
+        .map(|path| Ok(CertificateDer::from(std::fs::read(path)?)))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    Ok(Some(ClientAuth {
+        roots,
+        required: std::env::var_os("REQUIRE_CLIENT_CERT").is_some(),
+    }))
+}
+
+/// Dispatches requests by host, see [fluke::vhost]: every host proxies to
+/// the same upstream here, but they don't have to. Forwarded requests carry
+/// the trace context, see [fluke::trace_context].
+type Driver = Rc<Traced<VirtualHosts<SDriver>>>;
+
 enum Proto {
     H1(Rc<h1::ServerConf>),
     H2(Rc<h2::ServerConf>),
@@ -144,74 +268,81 @@ async fn handle_plaintext_conn(
     stream: tokio::net::TcpStream,
     remote_addr: std::net::SocketAddr,
     proto: Proto,
+    driver: Driver,
 ) -> Result<(), color_eyre::Report> {
     info!("Accepted connection from {remote_addr}");
     let buf = RollMut::alloc()?;
 
     let stream = stream.to_uring_tcp_stream()?;
-    let driver = SDriver {};
 
-    match proto {
+    let reason = match proto {
         Proto::H1(h1_conf) => {
             info!("Using HTTP/1.1");
-            fluke::h1::serve(stream.into_halves(), h1_conf, buf, driver).await?;
+            fluke::h1::serve(stream.into_halves(), h1_conf, buf, driver)
+                .await?
+                .close_reason()
         }
         Proto::H2(h2_conf) => {
             info!("Using HTTP/2");
-            fluke::h2::serve(stream.into_halves(), h2_conf, buf, Rc::new(driver)).await?;
+            fluke::h2::serve(stream.into_halves(), h2_conf, buf, driver).await?
         }
-    }
+    };
+    info!(close_reason = %reason, "Closed connection from {remote_addr}");
 
     Ok(())
 }
 
 async fn handle_tls_conn(
-    acceptor: Rc<tokio_rustls::TlsAcceptor>,
+    acceptor: Rc<impl TlsAcceptor>,
     stream: tokio::net::TcpStream,
     remote_addr: std::net::SocketAddr,
     h1_conf: Rc<h1::ServerConf>,
     h2_conf: Rc<h2::ServerConf>,
+    driver: Driver,
 ) -> Result<(), color_eyre::Report> {
     info!("Accepted connection from {remote_addr}");
-    let stream = CorkStream::new(stream);
-    let stream = acceptor.accept(stream).await?;
-
-    let sc = stream.get_ref().1;
-    let alpn_proto = sc
-        .alpn_protocol()
+    let Accepted {
+        stream,
+        drained,
+        info,
+    } = acceptor.accept(stream).await?;
+
+    let alpn_proto = info
+        .alpn_protocol
+        .as_deref()
         .and_then(|p| std::str::from_utf8(p).ok().map(|s| s.to_string()));
-    debug!(?alpn_proto, "Performed TLS handshake");
-
-    let stream = ktls::config_ktls_server(stream).await?;
-
-    debug!("Set up kTLS");
-    let (drained, stream) = stream.into_raw();
-    let drained = drained.unwrap_or_default();
-    debug!("{} bytes already decoded by rustls", drained.len());
-
-    let stream = stream.to_uring_tcp_stream()?;
+    debug!(
+        ?alpn_proto,
+        client_certs = info.peer_certificates.len(),
+        "Performed TLS handshake"
+    );
+    debug!("{} bytes already decoded by the TLS backend", drained.len());
+    let conn = ConnInfo::with_tls(info);
 
     let mut buf = RollMut::alloc()?;
     buf.put(&drained[..])?;
 
-    let driver = SDriver {};
-
-    match alpn_proto.as_deref() {
+    let reason = match alpn_proto.as_deref() {
         Some("h2") => {
             info!("Using HTTP/2");
-            fluke::h2::serve(stream.into_halves(), h2_conf, buf, Rc::new(driver)).await?;
+            fluke::h2::serve_with(stream.into_halves(), h2_conf, buf, driver, conn).await?
         }
         Some("http/1.1") | None => {
             info!("Using HTTP/1.1");
-            fluke::h1::serve(stream.into_halves(), h1_conf, buf, driver).await?;
+            fluke::h1::serve_with(stream.into_halves(), h1_conf, buf, driver, conn)
+                .await?
+                .close_reason()
         }
         Some(other) => return Err(eyre::eyre!("Unsupported ALPN protocol: {}", other)),
-    }
+    };
+    info!(close_reason = %reason, "Closed connection from {remote_addr}");
 
     Ok(())
 }
 
-struct SDriver {}
+struct SDriver {
+    upstream: Rc<Upstream>,
+}
 
 impl ServerDriver for SDriver {
     async fn handle<E: Encoder>(
@@ -221,25 +352,17 @@ impl ServerDriver for SDriver {
         respond: Responder<E, ExpectResponseHeaders>,
     ) -> eyre::Result<Responder<E, ResponseDone>> {
         info!("Handling {:?} {}", req.method, req.uri);
-
-        let addr = "httpbingo.org:80"
-            .to_socket_addrs()?
-            .next()
-            .expect("http bingo should be up");
-        let transport = TcpStream::connect(addr).await?;
-        debug!("Connected to httpbingo");
+        if let Some(cert) = req.conn.tls().and_then(|tls| tls.peer_certificate()) {
+            debug!(
+                "Client authenticated with a {}-byte certificate",
+                cert.len()
+            );
+        }
 
         let driver = CDriver { respond };
 
         req.version = Version::HTTP_11;
-        req.headers.insert("host", "httpbingo.org".into());
-        let (transport, respond) =
-            h1::request(transport.into_halves(), req, req_body, driver).await?;
-
-        // don't re-use transport for now
-        drop(transport);
-
-        Ok(respond)
+        self.upstream.request(req, req_body, driver).await
     }
 }
 
@@ -308,17 +431,9 @@ impl h1::ClientDriver for SampleCDriver {
     ) -> eyre::Result<Self::Return> {
         info!("Client got final response: {}", res.status);
 
-        loop {
-            debug!("Reading from body {body:?}");
-            match body.next_chunk().await? {
-                fluke::BodyChunk::Chunk(chunk) => {
-                    debug!("Client got chunk of len {}", chunk.len());
-                }
-                fluke::BodyChunk::Done { .. } => {
-                    break;
-                }
-            }
-        }
+        debug!("Reading from body {body:?}");
+        let body = body.collect(1024 * 1024).await?;
+        debug!("Client got body of len {}", body.len());
         Ok(())
     }
 }
@@ -335,34 +450,14 @@ async fn sample_http_request() -> color_eyre::Result<()> {
 
     let driver = SampleCDriver {};
 
-    let req = Request {
-        method: Method::Get,
-        uri: "http://httpbingo.org/image/jpeg".parse().unwrap(),
-        version: Version::HTTP_11,
-        headers: Default::default(),
-    };
+    let (req, mut body) = Request::builder()
+        .method(Method::Get)
+        .uri("http://httpbingo.org/image/jpeg")
+        .build()?;
 
-    let (transport, _) = h1::request(transport.into_halves(), req, &mut (), driver).await?;
+    let (transport, _) = h1::request(transport.into_halves(), req, &mut body, driver).await?;
     // don't re-use transport for now
     drop(transport);
 
     Ok(())
 }
-
-pub trait ToUringTcpStream {
-    fn to_uring_tcp_stream(self) -> std::io::Result<TcpStream>;
-}
-
-impl ToUringTcpStream for tokio::net::TcpStream {
-    fn to_uring_tcp_stream(self) -> std::io::Result<TcpStream> {
-        {
-            let sock = ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(self.as_raw_fd()) });
-            // tokio needs the socket to be non-blocking but tokio-uring
-            // needs it to be "blocking" (but it won't be, because io_uring)
-            sock.set_nonblocking(false)?;
-        }
-        let stream = unsafe { TcpStream::from_raw_fd(self.as_raw_fd()) };
-        std::mem::forget(self);
-        Ok(stream)
-    }
-}
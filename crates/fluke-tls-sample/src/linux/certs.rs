@@ -0,0 +1,245 @@
+//! The acceptor's certificates: self-signed ones generated on startup, or
+//! ones read from disk, that can be swapped without a restart.
+//!
+//! With `TLS_CERT_DIR` set, each host in `TLS_HOSTS` gets its certificate
+//! from that directory:
+//!
+//!   - `{host}.crt`: DER-encoded certificates, end-entity first, then
+//!     intermediates, concatenated
+//!   - `{host}.key`: the DER-encoded PKCS#8 key
+//!   - `{host}.ocsp`: a DER-encoded OCSP response to staple (optional)
+//!
+//! Files are read again on `SIGHUP`, and when one of them changes on disk,
+//! which is how renewed certificates and fresh OCSP responses get picked
+//! up. Only new handshakes see them: established connections keep going
+//! with the certificate they negotiated. Files should be replaced
+//! atomically (written elsewhere, then renamed), lest a reload sees half
+//! of them.
+
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::eyre;
+use fluke::ConfHandle;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+/// How often certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+type HostCerts = Vec<(String, Arc<CertifiedKey>)>;
+
+/// Picks the certificate for the server name the client asked for (SNI),
+/// or the first one if it didn't ask for any we have. Cloning it gives
+/// another handle to the same certificates.
+#[derive(Clone)]
+pub(crate) struct SniResolver {
+    certs: ConfHandle<HostCerts>,
+    dir: Option<CertDir>,
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let certs = self.certs.load();
+        f.debug_struct("SniResolver")
+            .field(
+                "hosts",
+                &certs.iter().map(|(host, _)| host).collect::<Vec<_>>(),
+            )
+            .field("dir", &self.dir.as_ref().map(|dir| &dir.path))
+            .finish()
+    }
+}
+
+impl SniResolver {
+    /// Reads certificates from `TLS_CERT_DIR` if it's set, see the module
+    /// docs, and generates self-signed ones otherwise
+    pub(crate) fn from_env(hosts: &[&str]) -> eyre::Result<Self> {
+        if hosts.is_empty() {
+            return Err(eyre::eyre!("need at least one TLS host"));
+        }
+
+        let (certs, dir) = match std::env::var_os("TLS_CERT_DIR") {
+            Some(path) => {
+                let dir = CertDir {
+                    path: path.into(),
+                    hosts: hosts.iter().map(|host| host.to_ascii_lowercase()).collect(),
+                };
+                (dir.load()?, Some(dir))
+            }
+            None => (self_signed(hosts)?, None),
+        };
+
+        Ok(Self {
+            certs: ConfHandle::new(certs),
+            dir,
+        })
+    }
+
+    /// Reads the certificates from disk again. If any of them can't be
+    /// read, all of the current ones are kept.
+    pub(crate) fn reload(&self) -> eyre::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        self.certs.store(dir.load()?);
+        info!("Reloaded certificates from {}", dir.path.display());
+        Ok(())
+    }
+
+    /// Reloads certificates on `SIGHUP`, and whenever their files change,
+    /// until the process exits. Does nothing for self-signed certificates.
+    pub(crate) async fn watch(self) -> eyre::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        let mut last_modified = dir.modified();
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    info!("Got SIGHUP");
+                }
+                _ = interval.tick() => {
+                    let modified = dir.modified();
+                    if modified == last_modified {
+                        continue;
+                    }
+                    last_modified = modified;
+                    info!("Certificate files changed");
+                }
+            }
+
+            if let Err(e) = self.reload() {
+                warn!("Could not reload certificates, keeping the current ones: {e}");
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.load();
+        let cert = client_hello.server_name().and_then(|name| {
+            certs
+                .iter()
+                .find(|(host, _)| host.eq_ignore_ascii_case(name))
+        });
+        Some(cert.unwrap_or(&certs[0]).1.clone())
+    }
+}
+
+fn self_signed(hosts: &[&str]) -> eyre::Result<HostCerts> {
+    let mut certs = vec![];
+    for host in hosts {
+        let pair = rcgen::generate_simple_self_signed(vec![host.to_string()])?;
+        let crt = CertificateDer::from(pair.serialize_der()?);
+        let key = PrivatePkcs8KeyDer::from(pair.serialize_private_key_der()).into();
+        let key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+        certs.push((
+            host.to_ascii_lowercase(),
+            Arc::new(CertifiedKey::new(vec![crt], key)),
+        ));
+    }
+    Ok(certs)
+}
+
+#[derive(Clone)]
+struct CertDir {
+    path: PathBuf,
+    hosts: Vec<String>,
+}
+
+impl CertDir {
+    fn files(&self, host: &str) -> [PathBuf; 3] {
+        ["crt", "key", "ocsp"].map(|ext| self.path.join(format!("{host}.{ext}")))
+    }
+
+    fn load(&self) -> eyre::Result<HostCerts> {
+        let mut certs = vec![];
+        for host in &self.hosts {
+            let [crt, key, ocsp] = self.files(host);
+            let read = |path: &PathBuf| {
+                std::fs::read(path)
+                    .map_err(|e| eyre::eyre!("could not read {}: {e}", path.display()))
+            };
+
+            let chain = split_der(&read(&crt)?)?;
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(read(&key)?));
+            let key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+
+            let mut certified = CertifiedKey::new(chain, key);
+            certified.ocsp = match std::fs::read(&ocsp) {
+                Ok(response) => Some(response),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(eyre::eyre!("could not read {}: {e}", ocsp.display())),
+            };
+            certs.push((host.clone(), Arc::new(certified)));
+        }
+        Ok(certs)
+    }
+
+    /// When each file was last modified (`None` for files that can't be
+    /// stat'ed), so that files being replaced by older ones, or going
+    /// away, count as changes too
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.hosts
+            .iter()
+            .flat_map(|host| self.files(host))
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// Splits concatenated DER-encoded certificates: each of them is a single
+/// SEQUENCE, which says how long it is.
+pub(crate) fn split_der(mut input: &[u8]) -> eyre::Result<Vec<CertificateDer<'static>>> {
+    let mut certs = vec![];
+    while !input.is_empty() {
+        let (tag, first) = match input {
+            [tag, first, ..] => (*tag, *first),
+            _ => return Err(eyre::eyre!("truncated certificate")),
+        };
+        if tag != 0x30 {
+            return Err(eyre::eyre!("not a DER-encoded certificate"));
+        }
+
+        // short form, or long form with 1 to 4 length bytes
+        let (header_len, len) = if first < 0x80 {
+            (2, first as usize)
+        } else {
+            let n = (first & 0x7f) as usize;
+            if !(1..=4).contains(&n) || input.len() < 2 + n {
+                return Err(eyre::eyre!("bad certificate length"));
+            }
+            let len = input[2..2 + n]
+                .iter()
+                .fold(0usize, |len, b| (len << 8) | *b as usize);
+            (2 + n, len)
+        };
+
+        let total = header_len + len;
+        if input.len() < total {
+            return Err(eyre::eyre!("truncated certificate"));
+        }
+        certs.push(CertificateDer::from(input[..total].to_vec()));
+        input = &input[total..];
+    }
+
+    if certs.is_empty() {
+        return Err(eyre::eyre!("no certificate"));
+    }
+    Ok(certs)
+}
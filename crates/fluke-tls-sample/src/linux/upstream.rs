@@ -0,0 +1,150 @@
+//! Where the proxy forwards requests to, and how it talks to it: in
+//! plaintext, or over TLS with its own trust anchors, server name, ALPN
+//! list, client certificate and pins.
+
+use std::{net::ToSocketAddrs, sync::Arc};
+
+use color_eyre::eyre::{self, WrapErr};
+use fluke::{
+    buffet::{net::TcpStream, IntoHalves},
+    h1, Body, Request,
+};
+use fluke_tls::{Connector, PinnedCerts, TlsConnector, UpstreamTlsConf};
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use tracing::debug;
+
+/// The server requests get forwarded to
+pub(crate) struct Upstream {
+    /// `host:port` to connect to
+    addr: String,
+
+    /// What to send in the `host` header: the whole of `addr`, since the
+    /// port may not be the scheme's default
+    host: String,
+
+    tls: Option<Connector>,
+}
+
+impl Upstream {
+    /// `addr` is `host:port`. With `tls` set, the upstream is talked to
+    /// over TLS.
+    pub(crate) fn new(addr: String, tls: Option<UpstreamTlsConf>) -> eyre::Result<Self> {
+        let Some((host, _port)) = addr.rsplit_once(':') else {
+            return Err(eyre::eyre!("upstream address {addr:?} has no port"));
+        };
+        let tls = tls.map(|tls| Connector::new(tls, host)).transpose()?;
+        Ok(Self {
+            host: addr.clone(),
+            addr,
+            tls,
+        })
+    }
+
+    /// Reads the upstream configuration from the environment:
+    ///
+    /// - `TLS_BACKEND`: see [super::backend_from_env]
+    /// - `UPSTREAM`: `host:port`, plaintext, or `https://host:port`. Defaults
+    ///   to `httpbingo.org:80`.
+    /// - `UPSTREAM_ROOTS`: comma-separated paths to DER-encoded trust anchors
+    /// - `UPSTREAM_SERVER_NAME`: see [UpstreamTlsConf::server_name], the
+    ///   empty string disables SNI
+    /// - `UPSTREAM_ALPN`: comma-separated protocols, see
+    ///   [UpstreamTlsConf::alpn]
+    /// - `UPSTREAM_CLIENT_CERT`, `UPSTREAM_CLIENT_KEY`: paths to a
+    ///   DER-encoded certificate and PKCS#8 key, for mTLS
+    /// - `UPSTREAM_PINS`: comma-separated paths to DER-encoded certificates,
+    ///   see [PinnedCerts]
+    pub(crate) fn from_env() -> eyre::Result<Self> {
+        let var = |name: &str| std::env::var(name).ok();
+        let ders = |paths: &str| -> eyre::Result<Vec<CertificateDer<'static>>> {
+            paths
+                .split(',')
+                .map(|path| {
+                    let der = std::fs::read(path).wrap_err_with(|| format!("reading {path}"))?;
+                    Ok(CertificateDer::from(der))
+                })
+                .collect()
+        };
+
+        let upstream = var("UPSTREAM").unwrap_or_else(|| "httpbingo.org:80".to_string());
+        let Some(addr) = upstream.strip_prefix("https://") else {
+            return Self::new(upstream, None);
+        };
+
+        let mut tls = UpstreamTlsConf {
+            backend: super::backend_from_env()?,
+            ..Default::default()
+        };
+        if let Some(roots) = var("UPSTREAM_ROOTS") {
+            tls.roots = ders(&roots)?;
+        }
+        match var("UPSTREAM_SERVER_NAME").as_deref() {
+            Some("") => tls.send_sni = false,
+            Some(name) => tls.server_name = Some(name.to_string()),
+            None => {}
+        }
+        if let Some(alpn) = var("UPSTREAM_ALPN") {
+            tls.alpn = alpn.split(',').map(|p| p.as_bytes().to_vec()).collect();
+        }
+        if let (Some(cert), Some(key)) = (var("UPSTREAM_CLIENT_CERT"), var("UPSTREAM_CLIENT_KEY")) {
+            let key = std::fs::read(&key).wrap_err_with(|| format!("reading {key}"))?;
+            tls.client_auth = Some((ders(&cert)?, PrivatePkcs8KeyDer::from(key).into()));
+        }
+        if let Some(pins) = var("UPSTREAM_PINS") {
+            tls.pin = Some(Arc::new(PinnedCerts(ders(&pins)?)));
+        }
+
+        Self::new(addr.to_string(), Some(tls))
+    }
+
+    /// Forwards `req` to the upstream, over a fresh connection
+    pub(crate) async fn request<D: h1::ClientDriver>(
+        &self,
+        mut req: Request,
+        req_body: &mut impl Body,
+        driver: D,
+    ) -> eyre::Result<D::Return> {
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| eyre::eyre!("upstream {} didn't resolve", self.addr))?;
+        req.headers
+            .insert("host", self.host.clone().into_bytes().into());
+
+        // don't re-use transports for now
+        let Some(tls) = &self.tls else {
+            let transport = TcpStream::connect(addr).await?;
+            debug!(%addr, "Connected to upstream");
+            let (_transport, ret) =
+                h1::request(transport.into_halves(), req, req_body, driver).await?;
+            return Ok(ret);
+        };
+
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        request_tls(tls, stream, req, req_body, driver).await
+    }
+}
+
+async fn request_tls<D: h1::ClientDriver>(
+    connector: &Connector,
+    stream: tokio::net::TcpStream,
+    req: Request,
+    req_body: &mut impl Body,
+    driver: D,
+) -> eyre::Result<D::Return> {
+    let (stream, alpn_proto) = connector.connect(stream).await?;
+    match alpn_proto.as_deref() {
+        Some(b"http/1.1") | None => {}
+        Some(other) => {
+            return Err(eyre::eyre!(
+                "upstream picked unsupported ALPN protocol {:?}",
+                String::from_utf8_lossy(other)
+            ))
+        }
+    }
+    debug!("Connected to upstream over TLS");
+
+    let (_transport, ret) = h1::request(stream.into_halves(), req, req_body, driver).await?;
+    Ok(ret)
+}
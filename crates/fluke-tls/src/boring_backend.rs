@@ -0,0 +1,182 @@
+use std::sync::Arc;
+
+use boring::{
+    pkey::PKey,
+    ssl::{
+        select_next_proto, AlpnError, NameType, SslAcceptor, SslConnector, SslMethod, SslVerifyMode,
+    },
+    x509::X509,
+};
+use fluke::TlsInfo;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_boring::SslStream;
+
+use crate::{Accepted, CertPin, ClientAuth, TlsAcceptor, TlsConnector, UpstreamTlsConf, Userland};
+
+/// Accepts connections with BoringSSL, serving a single certificate
+pub struct BoringAcceptor {
+    acceptor: SslAcceptor,
+}
+
+impl BoringAcceptor {
+    /// Serves `chain` (the end-entity certificate first) with `key`. Without
+    /// `client_auth`, clients aren't asked for a certificate.
+    pub fn new(
+        chain: &[CertificateDer<'_>],
+        key: &PrivateKeyDer<'_>,
+        client_auth: Option<ClientAuth>,
+    ) -> eyre::Result<Self> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        let mut chain = chain.iter();
+        if let Some(leaf) = chain.next() {
+            builder.set_certificate(&X509::from_der(leaf)?)?;
+        }
+        for intermediate in chain {
+            builder.add_extra_chain_cert(X509::from_der(intermediate)?)?;
+        }
+        builder.set_private_key(&PKey::private_key_from_der(key.secret_der())?)?;
+        builder.check_private_key()?;
+        builder.set_alpn_select_callback(|_ssl, offered| {
+            select_next_proto(b"\x02h2\x08http/1.1", offered).ok_or(AlpnError::NOACK)
+        });
+
+        if let Some(client_auth) = client_auth {
+            if client_auth.roots.is_empty() {
+                return Err(eyre::eyre!(
+                    "client authentication needs at least one trust anchor"
+                ));
+            }
+            for root in &client_auth.roots {
+                builder.cert_store_mut().add_cert(X509::from_der(root)?)?;
+            }
+            let mut mode = SslVerifyMode::PEER;
+            if client_auth.required {
+                mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+            }
+            builder.set_verify(mode);
+        }
+
+        Ok(Self {
+            acceptor: builder.build(),
+        })
+    }
+}
+
+impl TlsAcceptor for BoringAcceptor {
+    type Stream = Userland<SslStream<tokio::net::TcpStream>>;
+
+    async fn accept(&self, stream: tokio::net::TcpStream) -> eyre::Result<Accepted<Self::Stream>> {
+        let stream = tokio_boring::accept(&self.acceptor, stream)
+            .await
+            .map_err(|e| eyre::eyre!("TLS handshake failed: {e}"))?;
+
+        let ssl = stream.ssl();
+        let info = TlsInfo {
+            server_name: ssl.servername(NameType::HOST_NAME).map(|s| s.to_string()),
+            alpn_protocol: ssl.selected_alpn_protocol().map(|p| p.to_vec()),
+            peer_certificates: match ssl.peer_certificate() {
+                Some(cert) => vec![cert.to_der()?],
+                None => vec![],
+            },
+        };
+
+        Ok(Accepted {
+            stream: Userland(stream),
+            drained: vec![],
+            info,
+        })
+    }
+}
+
+/// Connects to an upstream with BoringSSL
+pub struct BoringConnector {
+    connector: SslConnector,
+    server_name: String,
+    send_sni: bool,
+    pin: Option<Arc<dyn CertPin>>,
+}
+
+impl BoringConnector {
+    /// `host` is the host part of the upstream address, IPv6 addresses in
+    /// brackets
+    pub fn new(conf: UpstreamTlsConf, host: &str) -> eyre::Result<Self> {
+        if conf.roots.is_empty() {
+            return Err(eyre::eyre!("upstream TLS needs at least one trust anchor"));
+        }
+
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        for root in &conf.roots {
+            builder.cert_store_mut().add_cert(X509::from_der(root)?)?;
+        }
+        builder.set_verify(SslVerifyMode::PEER);
+
+        let mut wire = vec![];
+        for proto in &conf.alpn {
+            wire.push(proto.len() as u8);
+            wire.extend_from_slice(proto);
+        }
+        builder.set_alpn_protos(&wire)?;
+
+        if let Some((chain, key)) = &conf.client_auth {
+            let mut chain = chain.iter();
+            if let Some(leaf) = chain.next() {
+                builder.set_certificate(&X509::from_der(leaf)?)?;
+            }
+            for intermediate in chain {
+                builder.add_extra_chain_cert(X509::from_der(intermediate)?)?;
+            }
+            builder.set_private_key(&PKey::private_key_from_der(key.secret_der())?)?;
+        }
+
+        let server_name = match &conf.server_name {
+            Some(name) => name.clone(),
+            None => host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+        };
+
+        Ok(Self {
+            connector: builder.build(),
+            server_name,
+            send_sni: conf.send_sni,
+            pin: conf.pin,
+        })
+    }
+}
+
+impl TlsConnector for BoringConnector {
+    type Stream = Userland<SslStream<tokio::net::TcpStream>>;
+
+    async fn connect(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> eyre::Result<(Self::Stream, Option<Vec<u8>>)> {
+        let mut config = self.connector.configure()?;
+        config.set_use_server_name_indication(self.send_sni);
+        let stream = tokio_boring::connect(config, &self.server_name, stream)
+            .await
+            .map_err(|e| eyre::eyre!("TLS handshake failed: {e}"))?;
+
+        let ssl = stream.ssl();
+        if let Some(pin) = &self.pin {
+            // the chain verified already, the pin only narrows it down
+            let Some(end_entity) = ssl.peer_certificate() else {
+                return Err(eyre::eyre!("upstream sent no certificate"));
+            };
+            let end_entity = CertificateDer::from(end_entity.to_der()?);
+            let intermediates = match ssl.peer_cert_chain() {
+                Some(chain) => chain
+                    .iter()
+                    .skip(1)
+                    .map(|cert| Ok(CertificateDer::from(cert.to_der()?)))
+                    .collect::<eyre::Result<Vec<_>>>()?,
+                None => vec![],
+            };
+            pin.check(&end_entity, &intermediates)?;
+        }
+
+        let alpn_proto = ssl.selected_alpn_protocol().map(|p| p.to_vec());
+        Ok((Userland(stream), alpn_proto))
+    }
+}
@@ -0,0 +1,135 @@
+//! How to talk TLS to an upstream: its own trust anchors, server name, ALPN
+//! list, client certificate and pins.
+
+use std::{fmt, sync::Arc};
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+#[cfg(feature = "boring")]
+use crate::BoringConnector;
+use crate::{Backend, RustlsConnector, TlsConnector, Userland};
+
+/// How to talk TLS to an upstream
+pub struct UpstreamTlsConf {
+    /// Which TLS implementation to connect with
+    pub backend: Backend,
+
+    /// Trust anchors for the upstream's certificate chain, DER-encoded.
+    /// There's no default set: a proxy usually talks to a handful of known
+    /// upstreams, signed by a private CA.
+    pub roots: Vec<CertificateDer<'static>>,
+
+    /// The name the upstream's certificate is checked against, and sent as
+    /// SNI. Defaults to the host part of the upstream address.
+    pub server_name: Option<String>,
+
+    /// Whether to send SNI at all: some upstreams serve a different
+    /// certificate when they get one.
+    pub send_sni: bool,
+
+    /// Protocols to offer through ALPN, most preferred first. Only
+    /// `http/1.1` can be used for now, since fluke only has an HTTP/1.1
+    /// client: upstreams that pick anything else get their connection
+    /// closed.
+    pub alpn: Vec<Vec<u8>>,
+
+    /// A certificate chain and its key, for upstreams that authenticate the
+    /// client (mTLS)
+    pub client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+
+    /// Checked after the certificate chain has been verified, see [CertPin]
+    pub pin: Option<Arc<dyn CertPin>>,
+}
+
+impl Default for UpstreamTlsConf {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Rustls,
+            roots: Default::default(),
+            server_name: None,
+            send_sni: true,
+            alpn: vec![b"http/1.1".to_vec()],
+            client_auth: None,
+            pin: None,
+        }
+    }
+}
+
+/// An extra check on upstream certificates, e.g. that the end-entity
+/// certificate (or one of the intermediates) is one the client was told to
+/// expect. Only ever called on chains that verified against the trust
+/// anchors: a pin narrows down what's accepted, it can't widen it.
+pub trait CertPin: fmt::Debug + Send + Sync {
+    fn check(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+    ) -> Result<(), rustls::Error>;
+}
+
+/// Accepts upstreams whose end-entity certificate is one of these
+#[derive(Debug)]
+pub struct PinnedCerts(pub Vec<CertificateDer<'static>>);
+
+impl CertPin for PinnedCerts {
+    fn check(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+    ) -> Result<(), rustls::Error> {
+        if self.0.iter().any(|pinned| pinned[..] == end_entity[..]) {
+            Ok(())
+        } else {
+            Err(rustls::Error::General(
+                "upstream certificate doesn't match any pin".into(),
+            ))
+        }
+    }
+}
+
+/// Whichever [TlsConnector] [UpstreamTlsConf::backend] asked for
+pub enum Connector {
+    Rustls(RustlsConnector),
+    #[cfg(feature = "boring")]
+    Boring(BoringConnector),
+}
+
+impl Connector {
+    /// `host` is the host part of the upstream address, IPv6 addresses in
+    /// brackets
+    pub fn new(conf: UpstreamTlsConf, host: &str) -> eyre::Result<Self> {
+        Ok(match conf.backend {
+            Backend::Rustls => Self::Rustls(RustlsConnector::new(conf, host)?),
+            #[cfg(feature = "boring")]
+            Backend::Boring => Self::Boring(BoringConnector::new(conf, host)?),
+        })
+    }
+}
+
+/// The stream of any backend's [TlsConnector], see [Connector]
+pub trait AnyStream: AsyncRead + AsyncWrite + Unpin {}
+
+impl<S> AnyStream for S where S: AsyncRead + AsyncWrite + Unpin {}
+
+impl TlsConnector for Connector {
+    type Stream = Userland<Box<dyn AnyStream>>;
+
+    async fn connect(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> eyre::Result<(Self::Stream, Option<Vec<u8>>)> {
+        let (stream, alpn_proto): (Box<dyn AnyStream>, _) = match self {
+            Connector::Rustls(connector) => {
+                let (Userland(stream), alpn_proto) = connector.connect(stream).await?;
+                (Box::new(stream), alpn_proto)
+            }
+            #[cfg(feature = "boring")]
+            Connector::Boring(connector) => {
+                let (Userland(stream), alpn_proto) = connector.connect(stream).await?;
+                (Box::new(stream), alpn_proto)
+            }
+        };
+        Ok((Userland(stream), alpn_proto))
+    }
+}
@@ -0,0 +1,148 @@
+//! TLS backends for fluke: what does handshakes, for clients connecting to a
+//! server ([TlsAcceptor]) and for a client (e.g. a proxy) connecting to an
+//! upstream ([TlsConnector]).
+//!
+//! Backends only do handshakes: they hand back something that splits into
+//! buffet read and write halves, and fluke does the rest the same way
+//! whatever the backend. That's either a kernel TLS socket (reads and
+//! writes are plaintext, and go through io_uring), or the backend's own
+//! stream, through buffet's `AsyncRead` / `AsyncWrite` support.
+//!
+//! There are two of them, see [Backend]:
+//!
+//!   - rustls: [RustlsAcceptor] hands accepted connections over to the
+//!     kernel (kTLS, Linux only), and takes a `rustls::ServerConfig`, so
+//!     certificates per host, reloading, OCSP stapling and client
+//!     certificates are all up to it.
+//!   - BoringSSL, with the `boring` feature, e.g. for its FIPS build:
+//!     `BoringAcceptor` serves a single certificate and doesn't offload to
+//!     kTLS.
+//!
+//! Either can connect to upstreams, as configured by an [UpstreamTlsConf],
+//! see [Connector].
+
+use std::str::FromStr;
+
+use fluke::{buffet::IntoHalves, TlsInfo};
+use rustls::pki_types::CertificateDer;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+
+mod connector;
+pub use connector::*;
+
+mod rustls_backend;
+pub use rustls_backend::*;
+
+#[cfg(feature = "boring")]
+mod boring_backend;
+#[cfg(feature = "boring")]
+pub use boring_backend::*;
+
+/// Which TLS implementation to use, see the [crate docs](crate). Parses
+/// from `rustls` or `boring`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Rustls,
+    #[cfg(feature = "boring")]
+    Boring,
+}
+
+impl FromStr for Backend {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rustls" => Ok(Self::Rustls),
+            #[cfg(feature = "boring")]
+            "boring" => Ok(Self::Boring),
+            other => Err(eyre::eyre!(
+                "unknown TLS backend {other:?} (is the feature for it enabled?)"
+            )),
+        }
+    }
+}
+
+/// A connection, once the server side of the handshake is done
+pub struct Accepted<S> {
+    pub stream: S,
+
+    /// Application data the backend already decrypted while finishing the
+    /// handshake: it comes before anything read from `stream`.
+    pub drained: Vec<u8>,
+
+    pub info: TlsInfo,
+}
+
+/// Which client certificates an acceptor asks for, see
+/// [ClientAuth::rustls_verifier] and `BoringAcceptor::new`
+pub struct ClientAuth {
+    /// Trust anchors client certificates are verified against, DER-encoded
+    pub roots: Vec<CertificateDer<'static>>,
+
+    /// Whether the handshake fails without a client certificate, rather
+    /// than going on unauthenticated
+    pub required: bool,
+}
+
+/// Does the server side of TLS handshakes
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait TlsAcceptor {
+    type Stream: IntoHalves;
+
+    async fn accept(&self, stream: tokio::net::TcpStream) -> eyre::Result<Accepted<Self::Stream>>;
+}
+
+/// Does the client side of TLS handshakes, to a single upstream
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait TlsConnector {
+    type Stream: IntoHalves;
+
+    /// Returns the stream, and the protocol ALPN settled on, if any
+    async fn connect(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> eyre::Result<(Self::Stream, Option<Vec<u8>>)>;
+}
+
+/// A stream TLS is done in userland for, by the backend: it's read from
+/// and written to through `AsyncRead` and `AsyncWrite`.
+pub struct Userland<S>(pub S);
+
+impl<S> IntoHalves for Userland<S>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    type Read = ReadHalf<S>;
+    type Write = WriteHalf<S>;
+
+    fn into_halves(self) -> (Self::Read, Self::Write) {
+        tokio::io::split(self.0)
+    }
+}
+
+/// Turns a tokio socket into a buffet one, to read from and write to it
+/// through io_uring
+#[cfg(target_os = "linux")]
+pub trait ToUringTcpStream {
+    fn to_uring_tcp_stream(self) -> std::io::Result<fluke::buffet::net::TcpStream>;
+}
+
+#[cfg(target_os = "linux")]
+impl ToUringTcpStream for tokio::net::TcpStream {
+    fn to_uring_tcp_stream(self) -> std::io::Result<fluke::buffet::net::TcpStream> {
+        use std::{
+            mem::ManuallyDrop,
+            os::unix::prelude::{AsRawFd, FromRawFd},
+        };
+
+        {
+            let sock = ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(self.as_raw_fd()) });
+            // tokio needs the socket to be non-blocking but tokio-uring
+            // needs it to be "blocking" (but it won't be, because io_uring)
+            sock.set_nonblocking(false)?;
+        }
+        let stream = unsafe { fluke::buffet::net::TcpStream::from_raw_fd(self.as_raw_fd()) };
+        std::mem::forget(self);
+        Ok(stream)
+    }
+}
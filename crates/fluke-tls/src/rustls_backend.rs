@@ -0,0 +1,206 @@
+use std::sync::Arc;
+
+use eyre::WrapErr;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use crate::{CertPin, ClientAuth, TlsConnector, UpstreamTlsConf, Userland};
+#[cfg(target_os = "linux")]
+pub use acceptor::RustlsAcceptor;
+
+#[cfg(target_os = "linux")]
+mod acceptor {
+    use std::sync::Arc;
+
+    use fluke::{buffet::net::TcpStream, TlsInfo};
+    use ktls::CorkStream;
+    use rustls::ServerConfig;
+    use tracing::debug;
+
+    use crate::{Accepted, TlsAcceptor, ToUringTcpStream};
+
+    /// Accepts connections with rustls, then hands the session over to the
+    /// kernel (kTLS): `ServerConfig::enable_secret_extraction` must be set.
+    pub struct RustlsAcceptor {
+        acceptor: tokio_rustls::TlsAcceptor,
+    }
+
+    impl RustlsAcceptor {
+        pub fn new(config: ServerConfig) -> Self {
+            Self {
+                acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+            }
+        }
+    }
+
+    impl TlsAcceptor for RustlsAcceptor {
+        type Stream = TcpStream;
+
+        async fn accept(&self, stream: tokio::net::TcpStream) -> eyre::Result<Accepted<TcpStream>> {
+            let stream = CorkStream::new(stream);
+            let stream = self.acceptor.accept(stream).await?;
+
+            let sc = stream.get_ref().1;
+            let info = TlsInfo {
+                server_name: sc.server_name().map(|name| name.to_string()),
+                alpn_protocol: sc.alpn_protocol().map(|p| p.to_vec()),
+                peer_certificates: sc
+                    .peer_certificates()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|cert| cert.to_vec())
+                    .collect(),
+            };
+
+            let stream = ktls::config_ktls_server(stream).await?;
+            debug!("Set up kTLS");
+            let (drained, stream) = stream.into_raw();
+
+            Ok(Accepted {
+                stream: stream.to_uring_tcp_stream()?,
+                drained: drained.unwrap_or_default(),
+                info,
+            })
+        }
+    }
+}
+
+impl ClientAuth {
+    /// Verifies client certificates for a `rustls::ServerConfig`
+    pub fn rustls_verifier(&self) -> eyre::Result<Arc<dyn ClientCertVerifier>> {
+        let mut roots = RootCertStore::empty();
+        for root in &self.roots {
+            roots.add(root.clone())?;
+        }
+        let builder = WebPkiClientVerifier::builder(Arc::new(roots));
+        let verifier = if self.required {
+            builder.build()?
+        } else {
+            builder.allow_unauthenticated().build()?
+        };
+        Ok(verifier)
+    }
+}
+
+/// Connects to an upstream with rustls
+pub struct RustlsConnector {
+    connector: tokio_rustls::TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl RustlsConnector {
+    /// `host` is the host part of the upstream address, IPv6 addresses in
+    /// brackets
+    pub fn new(conf: UpstreamTlsConf, host: &str) -> eyre::Result<Self> {
+        if conf.roots.is_empty() {
+            return Err(eyre::eyre!("upstream TLS needs at least one trust anchor"));
+        }
+        let mut roots = RootCertStore::empty();
+        for root in conf.roots {
+            roots.add(root)?;
+        }
+        let verifier: Arc<dyn ServerCertVerifier> = {
+            let webpki = WebPkiServerVerifier::builder(Arc::new(roots)).build()?;
+            match conf.pin {
+                Some(pin) => Arc::new(PinningVerifier { webpki, pin }),
+                None => webpki,
+            }
+        };
+
+        let builder = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+        let mut config = match conf.client_auth {
+            Some((chain, key)) => builder.with_client_auth_cert(chain, key)?,
+            None => builder.with_no_client_auth(),
+        };
+        config.enable_sni = conf.send_sni;
+        config.alpn_protocols = conf.alpn;
+
+        let server_name = match &conf.server_name {
+            Some(name) => name.as_str(),
+            None => host.trim_start_matches('[').trim_end_matches(']'),
+        };
+        let server_name = ServerName::try_from(server_name)
+            .wrap_err("invalid upstream TLS server name")?
+            .to_owned();
+
+        Ok(Self {
+            connector: tokio_rustls::TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+}
+
+impl TlsConnector for RustlsConnector {
+    type Stream = Userland<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>;
+
+    async fn connect(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> eyre::Result<(Self::Stream, Option<Vec<u8>>)> {
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        let alpn_proto = stream.get_ref().1.alpn_protocol().map(|p| p.to_vec());
+        Ok((Userland(stream), alpn_proto))
+    }
+}
+
+/// Verifies the chain the usual way, then checks it against a [CertPin]
+#[derive(Debug)]
+struct PinningVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    pin: Arc<dyn CertPin>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        self.pin.check(end_entity, intermediates)?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
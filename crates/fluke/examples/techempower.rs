@@ -0,0 +1,139 @@
+//! A TechEmpower-style benchmark server: `/plaintext` and `/json`, over
+//! HTTP/1.1 or HTTP/2 with prior knowledge.
+//!
+//! It's meant both as a performance regression target and as a reference
+//! for the cheap way of doing things with fluke:
+//!
+//!   * header names come from [fluke::http::header] and static values are
+//!     `&'static str` pieces, so nothing gets copied or allocated for them,
+//!   * the `date` header is formatted at most once per second and shared by
+//!     every response sent during that second,
+//!   * JSON bodies are serialized straight into a buffer from the pool
+//!     ([fluke::buffet::RollMut]) and sent without copying.
+//!
+//! Run it with:
+//!
+//! ```text
+//! cargo run --release --example techempower -- 127.0.0.1:8080
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use fluke::{
+    body::once,
+    buffet::{Piece, RollMut},
+    http::{header, StatusCode},
+    Body, Encoder, ExpectResponseHeaders, Headers, Request, Responder, Response, ResponseDone,
+    ServerBuilder, ServerDriver,
+};
+
+const PLAINTEXT_BODY: &str = "Hello, World!";
+const SERVER_NAME: &str = "fluke";
+
+fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let addr: SocketAddr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8080".to_owned())
+        .parse()?;
+
+    fluke::buffet::start(async move {
+        let server = ServerBuilder::new(addr)
+            // benchmark clients never stop to think
+            .handshake_timeout(None)
+            .build(BenchDriver::default())
+            .await?;
+        eprintln!("listening on {}", server.local_addr());
+        server.run().await?;
+        Ok(())
+    })
+}
+
+#[derive(Default)]
+struct BenchDriver {
+    date: DateCache,
+}
+
+impl BenchDriver {
+    fn headers(&self, content_type: &'static str) -> Headers {
+        let mut headers = Headers::default();
+        headers.insert(header::SERVER, SERVER_NAME.into());
+        headers.insert(header::DATE, self.date.get());
+        headers.insert(header::CONTENT_TYPE, content_type.into());
+        headers
+    }
+}
+
+impl ServerDriver for BenchDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        _req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let (res, body) = match req.uri.path() {
+            "/plaintext" => (
+                Response {
+                    headers: self.headers("text/plain"),
+                    ..Default::default()
+                },
+                Piece::from(PLAINTEXT_BODY),
+            ),
+            "/json" => {
+                let mut buf = RollMut::alloc()?;
+                serde_json::to_writer(&mut buf, &serde_json::json!({ "message": PLAINTEXT_BODY }))?;
+                (
+                    Response {
+                        headers: self.headers("application/json"),
+                        ..Default::default()
+                    },
+                    buf.take_all().into(),
+                )
+            }
+            _ => (
+                Response {
+                    status: StatusCode::NOT_FOUND,
+                    headers: self.headers("text/plain"),
+                    ..Default::default()
+                },
+                Piece::from("not found"),
+            ),
+        };
+
+        respond
+            .write_final_response_with_body(res, &mut once(body))
+            .await
+    }
+}
+
+/// The `date` header value, reformatted when the second changes
+struct DateCache {
+    secs: Cell<u64>,
+    value: RefCell<Piece>,
+}
+
+impl Default for DateCache {
+    fn default() -> Self {
+        Self {
+            secs: Cell::new(0),
+            value: RefCell::new(Piece::empty()),
+        }
+    }
+}
+
+impl DateCache {
+    fn get(&self) -> Piece {
+        let now = SystemTime::now();
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if secs != self.secs.get() {
+            self.secs.set(secs);
+            *self.value.borrow_mut() = httpdate::fmt_http_date(now).into_bytes().into();
+        }
+        self.value.borrow().clone()
+    }
+}
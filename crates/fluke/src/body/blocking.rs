@@ -0,0 +1,207 @@
+use std::{fmt, io};
+
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{Body, BodyChunk};
+
+/// How much [BlockingWriter] buffers before handing a chunk over
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How many chunks may be in flight before the producer blocks
+const MAX_CHUNKS_IN_FLIGHT: usize = 4;
+
+/// A body produced by synchronous code (a template engine, a blocking DB
+/// driver...) running on [fluke_buffet::spawn_blocking]'s thread pool.
+///
+/// `produce` writes to a [BlockingWriter], which blocks when the peer
+/// isn't reading fast enough, and fails once the body is dropped, so
+/// producers stop early if the connection goes away. If `produce` fails
+/// or panics, so does the body.
+///
+/// ```no_run
+/// # async fn example<E: fluke::Encoder>(
+/// #     respond: fluke::Responder<E, fluke::ExpectResponseHeaders>,
+/// # ) -> eyre::Result<fluke::Responder<E, fluke::ResponseDone>> {
+/// use std::io::Write;
+///
+/// let mut body = fluke::body::from_blocking(|w| {
+///     for i in 0..1000 {
+///         writeln!(w, "<li>item {i}</li>")?;
+///     }
+///     Ok(())
+/// });
+/// respond
+///     .write_final_response_with_body(Default::default(), &mut body)
+///     .await
+/// # }
+/// ```
+pub fn from_blocking<F>(produce: F) -> BlockingBody
+where
+    F: FnOnce(&mut BlockingWriter) -> eyre::Result<()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(MAX_CHUNKS_IN_FLIGHT);
+    let producer = fluke_buffet::spawn_blocking(move || {
+        let mut w = BlockingWriter {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+        let res = produce(&mut w).and_then(|()| Ok(w.send_buffered()?));
+        if let Err(e) = res {
+            // nobody's listening anymore if that fails, which is fine
+            _ = w.tx.blocking_send(Err(e));
+        }
+    });
+
+    BlockingBody {
+        rx,
+        producer: Some(producer),
+    }
+}
+
+/// See [from_blocking]
+pub struct BlockingBody {
+    rx: mpsc::Receiver<eyre::Result<Vec<u8>>>,
+
+    /// `None` once the producer is done and joined
+    producer: Option<JoinHandle<()>>,
+}
+
+impl fmt::Debug for BlockingBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockingBody")
+            .field("done", &self.producer.is_none())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Body for BlockingBody {
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.producer.is_none()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        let Some(producer) = self.producer.as_mut() else {
+            return Ok(BodyChunk::Done { trailers: None });
+        };
+
+        match self.rx.recv().await {
+            Some(Ok(chunk)) => Ok(BodyChunk::Chunk(chunk.into())),
+            Some(Err(e)) => {
+                self.producer = None;
+                Err(e)
+            }
+            None => {
+                // the producer returned: find out whether it panicked
+                let res = producer.await;
+                self.producer = None;
+                match res {
+                    Ok(()) => Ok(BodyChunk::Done { trailers: None }),
+                    Err(e) => Err(eyre::eyre!("blocking body producer failed: {e}")),
+                }
+            }
+        }
+    }
+}
+
+/// Where [from_blocking] producers write the body. Writes are buffered,
+/// and sent as chunks of up to 16KiB, or when flushed.
+pub struct BlockingWriter {
+    tx: mpsc::Sender<eyre::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+}
+
+impl fmt::Debug for BlockingWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockingWriter")
+            .field("buffered", &self.buf.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl BlockingWriter {
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "body was dropped"))
+    }
+}
+
+impl io::Write for BlockingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.send_buffered()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffered()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        sync::mpsc,
+    };
+
+    use super::{from_blocking, CHUNK_SIZE};
+    use crate::{Body, BodyChunk};
+
+    #[test]
+    fn blocking_body() {
+        fluke_buffet::start(async move {
+            let mut body = from_blocking(|w| {
+                w.write_all(&vec![b'a'; CHUNK_SIZE + 10])?;
+                w.flush()?;
+                write!(w, "done")?;
+                Ok(())
+            });
+            let mut chunks = vec![];
+            while let BodyChunk::Chunk(chunk) = body.next_chunk().await.unwrap() {
+                chunks.push(chunk.len());
+            }
+            assert_eq!(chunks, [CHUNK_SIZE, 10, 4]);
+            assert!(body.eof());
+
+            let mut body = from_blocking(|w| {
+                write!(w, "partial")?;
+                Err(eyre::eyre!("db went away"))
+            });
+            assert!(body.collect(1024).await.is_err());
+
+            let mut body = from_blocking(|_| panic!("template typo"));
+            assert!(body.next_chunk().await.is_err());
+        });
+    }
+
+    #[test]
+    fn producer_stops_when_body_is_dropped() {
+        let (tx, rx) = mpsc::channel();
+        fluke_buffet::start(async move {
+            let body = from_blocking(move |w| {
+                let res = loop {
+                    if let Err(e) = w.write_all(b"forever") {
+                        break e;
+                    }
+                };
+                tx.send(res.kind()).unwrap();
+                Ok(())
+            });
+            drop(body);
+        });
+        assert_eq!(rx.recv().unwrap(), io::ErrorKind::BrokenPipe);
+    }
+}
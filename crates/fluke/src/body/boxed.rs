@@ -0,0 +1,80 @@
+use std::fmt;
+
+use futures_util::future::LocalBoxFuture;
+
+use crate::{Body, BodyChunk};
+
+/// A type-erased [Body], for when bodies of different types need to be
+/// stored or returned together. Not `Send`, like everything else here.
+pub struct BoxBody {
+    inner: Box<dyn DynBody>,
+}
+
+impl BoxBody {
+    /// Erases the type of `body`
+    pub fn new(body: impl Body + 'static) -> Self {
+        Self {
+            inner: Box::new(body),
+        }
+    }
+}
+
+impl fmt::Debug for BoxBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BoxBody").field(&self.inner).finish()
+    }
+}
+
+impl Body for BoxBody {
+    fn content_len(&self) -> Option<u64> {
+        self.inner.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        self.inner.next_chunk_boxed().await
+    }
+
+    fn bytes_read(&self) -> Option<u64> {
+        self.inner.bytes_read()
+    }
+
+    fn expected_len(&self) -> Option<u64> {
+        self.inner.expected_len()
+    }
+}
+
+/// [Body] has async methods, which rules out `dyn Body`: this is the same
+/// thing with a boxed future.
+trait DynBody: fmt::Debug {
+    fn content_len(&self) -> Option<u64>;
+    fn eof(&self) -> bool;
+    fn next_chunk_boxed(&mut self) -> LocalBoxFuture<'_, eyre::Result<BodyChunk>>;
+    fn bytes_read(&self) -> Option<u64>;
+    fn expected_len(&self) -> Option<u64>;
+}
+
+impl<B: Body> DynBody for B {
+    fn content_len(&self) -> Option<u64> {
+        Body::content_len(self)
+    }
+
+    fn eof(&self) -> bool {
+        Body::eof(self)
+    }
+
+    fn next_chunk_boxed(&mut self) -> LocalBoxFuture<'_, eyre::Result<BodyChunk>> {
+        Box::pin(self.next_chunk())
+    }
+
+    fn bytes_read(&self) -> Option<u64> {
+        Body::bytes_read(self)
+    }
+
+    fn expected_len(&self) -> Option<u64> {
+        Body::expected_len(self)
+    }
+}
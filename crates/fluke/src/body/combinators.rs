@@ -0,0 +1,339 @@
+use std::fmt;
+
+use fluke_buffet::{hash::StreamingHash, Piece};
+
+use super::BoxBody;
+use crate::{Body, BodyChunk};
+
+/// Adapters for any [Body], in the spirit of `StreamExt`
+pub trait BodyExt: Body {
+    /// Transforms each chunk with `f`. Since `f` may change their length,
+    /// the resulting body doesn't announce a content-length.
+    fn map_chunk<F>(self, f: F) -> MapChunk<Self, F>
+    where
+        F: FnMut(Piece) -> Piece,
+    {
+        MapChunk { body: self, f }
+    }
+
+    /// Yields the chunks of `self`, then those of `next`. Only the trailers
+    /// of `next` are kept.
+    fn chain<B: Body>(self, next: B) -> Chain<Self, B> {
+        Chain {
+            first: Some(self),
+            second: next,
+        }
+    }
+
+    /// Fails with [LimitExceeded] once the body goes over `limit` bytes,
+    /// for bodies that come from untrusted peers.
+    fn limited(self, limit: u64) -> Limited<Self> {
+        Limited {
+            body: self,
+            limit,
+            read: 0,
+        }
+    }
+
+    /// Feeds every chunk to `hasher` as it goes by, see
+    /// [fluke_buffet::hash]. Once the body is done, [Hashed::finish] gives
+    /// the digest, to send in trailers for example.
+    fn hashed<H: StreamingHash>(self, hasher: H) -> Hashed<Self, H> {
+        Hashed { body: self, hasher }
+    }
+
+    /// Erases the type of the body, see [BoxBody]
+    fn boxed(self) -> BoxBody
+    where
+        Self: 'static,
+    {
+        BoxBody::new(self)
+    }
+}
+
+impl<B: Body> BodyExt for B {}
+
+/// A body with no chunks at all
+pub fn empty() -> Empty {
+    Empty
+}
+
+/// A body made of a single chunk
+pub fn once(piece: impl Into<Piece>) -> Once {
+    Once {
+        piece: Some(piece.into()),
+    }
+}
+
+/// See [empty]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Empty;
+
+impl Body for Empty {
+    fn content_len(&self) -> Option<u64> {
+        Some(0)
+    }
+
+    fn eof(&self) -> bool {
+        true
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        Ok(BodyChunk::Done { trailers: None })
+    }
+}
+
+/// See [once]
+pub struct Once {
+    piece: Option<Piece>,
+}
+
+impl fmt::Debug for Once {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug_struct = f.debug_struct("Once");
+        match &self.piece {
+            Some(piece) => match std::str::from_utf8(piece.as_ref()) {
+                Ok(utf8_str) => debug_struct.field("piece", &utf8_str),
+                Err(_) => debug_struct.field("piece", &"(non-utf8 string)"),
+            },
+            None => debug_struct.field("piece", &"(none)"),
+        };
+        debug_struct.finish()
+    }
+}
+
+impl Body for Once {
+    fn content_len(&self) -> Option<u64> {
+        Some(self.piece.as_ref().map_or(0, |piece| piece.len() as u64))
+    }
+
+    fn eof(&self) -> bool {
+        self.piece.is_none()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        match self.piece.take() {
+            Some(piece) => Ok(BodyChunk::Chunk(piece)),
+            None => Ok(BodyChunk::Done { trailers: None }),
+        }
+    }
+}
+
+/// See [BodyExt::map_chunk]
+pub struct MapChunk<B, F> {
+    body: B,
+    f: F,
+}
+
+impl<B: fmt::Debug, F> fmt::Debug for MapChunk<B, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MapChunk")
+            .field("body", &self.body)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B, F> Body for MapChunk<B, F>
+where
+    B: Body,
+    F: FnMut(Piece) -> Piece,
+{
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.body.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        Ok(match self.body.next_chunk().await? {
+            BodyChunk::Chunk(piece) => BodyChunk::Chunk((self.f)(piece)),
+            done => done,
+        })
+    }
+}
+
+/// See [BodyExt::chain]
+#[derive(Debug)]
+pub struct Chain<A, B> {
+    /// `None` once it's done
+    first: Option<A>,
+    second: B,
+}
+
+impl<A: Body, B: Body> Body for Chain<A, B> {
+    fn content_len(&self) -> Option<u64> {
+        let first = match &self.first {
+            Some(first) => first.content_len()?,
+            None => 0,
+        };
+        Some(first + self.second.content_len()?)
+    }
+
+    fn eof(&self) -> bool {
+        self.first.is_none() && self.second.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        if let Some(first) = self.first.as_mut() {
+            match first.next_chunk().await? {
+                BodyChunk::Chunk(piece) => return Ok(BodyChunk::Chunk(piece)),
+                BodyChunk::Done { .. } => self.first = None,
+            }
+        }
+        self.second.next_chunk().await
+    }
+}
+
+/// See [BodyExt::hashed]
+pub struct Hashed<B, H> {
+    body: B,
+    hasher: H,
+}
+
+impl<B: fmt::Debug, H> fmt::Debug for Hashed<B, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hashed")
+            .field("body", &self.body)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B, H: StreamingHash> Hashed<B, H> {
+    /// The digest of everything read so far
+    pub fn finish(self) -> H::Digest {
+        self.hasher.finish()
+    }
+}
+
+impl<B: Body, H: StreamingHash> Body for Hashed<B, H> {
+    fn content_len(&self) -> Option<u64> {
+        self.body.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.body.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        let chunk = self.body.next_chunk().await?;
+        if let BodyChunk::Chunk(piece) = &chunk {
+            self.hasher.update_piece(piece);
+        }
+        Ok(chunk)
+    }
+
+    fn bytes_read(&self) -> Option<u64> {
+        self.body.bytes_read()
+    }
+
+    fn expected_len(&self) -> Option<u64> {
+        self.body.expected_len()
+    }
+}
+
+/// See [BodyExt::limited]
+#[derive(Debug)]
+pub struct Limited<B> {
+    body: B,
+    limit: u64,
+    read: u64,
+}
+
+/// Returned by [Limited] bodies that go over their limit
+#[derive(Debug, thiserror::Error)]
+#[error("body is larger than the limit of {limit} bytes")]
+pub struct LimitExceeded {
+    pub limit: u64,
+}
+
+impl<B: Body> Body for Limited<B> {
+    fn content_len(&self) -> Option<u64> {
+        self.body.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.body.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        // no need to read a body we know is too large
+        if self.read == 0 && self.body.content_len().is_some_and(|len| len > self.limit) {
+            return Err(LimitExceeded { limit: self.limit }.into());
+        }
+
+        let chunk = self.body.next_chunk().await?;
+        if let BodyChunk::Chunk(piece) = &chunk {
+            self.read += piece.len() as u64;
+            if self.read > self.limit {
+                return Err(LimitExceeded { limit: self.limit }.into());
+            }
+        }
+        Ok(chunk)
+    }
+
+    fn bytes_read(&self) -> Option<u64> {
+        self.body.bytes_read()
+    }
+
+    fn expected_len(&self) -> Option<u64> {
+        self.body.expected_len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fluke_buffet::{hash::Crc32c, Piece};
+
+    use super::{empty, once, BodyExt, LimitExceeded};
+    use crate::Body;
+
+    async fn collect(mut body: impl Body) -> eyre::Result<Vec<u8>> {
+        Ok(body.collect(u64::MAX).await?.to_vec())
+    }
+
+    #[test]
+    fn combinators() {
+        fluke_buffet::start(async move {
+            let body = once("hello").chain(empty()).chain(once(" world"));
+            assert_eq!(body.content_len(), Some(11));
+            assert_eq!(collect(body).await.unwrap(), b"hello world");
+
+            let body = once("hello").map_chunk(|piece| Piece::from(piece.to_ascii_uppercase()));
+            assert_eq!(body.content_len(), None);
+            assert_eq!(collect(body.boxed()).await.unwrap(), b"HELLO");
+
+            let body = once("hello").chain(once(" world")).limited(8);
+            let err = collect(body).await.unwrap_err();
+            assert!(err.downcast_ref::<LimitExceeded>().is_some());
+
+            let body = once("hello").map_chunk(|p| p).chain(once("!")).limited(6);
+            assert_eq!(collect(body).await.unwrap(), b"hello!");
+        });
+    }
+
+    #[test]
+    fn hashed() {
+        fluke_buffet::start(async move {
+            let mut body = once("1234").chain(once("56789")).hashed(Crc32c::default());
+            assert_eq!(&body.collect(100).await.unwrap()[..], b"123456789");
+            assert_eq!(body.finish(), 0xe306_9283);
+        });
+    }
+
+    #[test]
+    fn collect_with_limit() {
+        fluke_buffet::start(async move {
+            assert_eq!(&empty().collect(0).await.unwrap()[..], b"");
+            assert_eq!(&once("hello").collect(5).await.unwrap()[..], b"hello");
+
+            // content-length is checked upfront, the actual size as we go
+            let err = once("hello").collect(4).await.err().unwrap();
+            assert!(err.downcast_ref::<LimitExceeded>().is_some());
+            let mut body = once("hel").chain(once("lo")).map_chunk(|p| p);
+            let err = body.collect(4).await.err().unwrap();
+            assert!(err.downcast_ref::<LimitExceeded>().is_some());
+        });
+    }
+}
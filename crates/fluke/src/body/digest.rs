@@ -0,0 +1,322 @@
+//! `Content-Digest` fields (<https://www.rfc-editor.org/rfc/rfc9530>):
+//! a hash of the body as it's sent, in a header or in trailers, so the
+//! recipient can check it wasn't damaged (or tampered with) on the way.
+//!
+//! Only `sha-256` is supported. Digests with other algorithms are ignored,
+//! as the RFC allows.
+
+use std::fmt;
+
+use fluke_buffet::hash::{Sha256, StreamingHash};
+use http::header::HeaderName;
+
+use crate::{Body, BodyChunk, Headers};
+
+/// The `content-digest` header name
+pub const CONTENT_DIGEST: HeaderName = HeaderName::from_static("content-digest");
+
+const SHA_256: &str = "sha-256";
+
+/// Returned when a `content-digest` field can't be used
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum DigestError {
+    /// The field isn't a dictionary of byte sequences
+    #[error("malformed content-digest field")]
+    Malformed,
+
+    /// The body doesn't hash to what the field says
+    #[error("body doesn't match its {algorithm} content-digest")]
+    Mismatch { algorithm: &'static str },
+}
+
+/// Formats a `content-digest` field value for a SHA-256 digest
+pub fn content_digest_value(sha256: &[u8; 32]) -> String {
+    format!("{SHA_256}=:{}:", base64_encode(sha256))
+}
+
+/// Finds the SHA-256 digest in `content-digest` fields, if any.
+pub fn parse_content_digest(headers: &Headers) -> Result<Option<[u8; 32]>, DigestError> {
+    let mut found = None;
+    for value in headers.get_all(CONTENT_DIGEST) {
+        let value = std::str::from_utf8(&value[..]).map_err(|_| DigestError::Malformed)?;
+        for member in value.split(',') {
+            let (key, value) = member
+                .trim()
+                .split_once('=')
+                .ok_or(DigestError::Malformed)?;
+            // parameters don't change the meaning of the digest
+            let value = value.split(';').next().unwrap_or_default();
+            let bytes = value
+                .strip_prefix(':')
+                .and_then(|v| v.strip_suffix(':'))
+                .and_then(base64_decode)
+                .ok_or(DigestError::Malformed)?;
+            if key == SHA_256 {
+                found = Some(bytes.try_into().map_err(|_| DigestError::Malformed)?);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Checks the body against its `content-digest`, taken from the request
+/// (or response) `headers` or, failing that, from the trailers. The last
+/// chunk is only returned once it's verified, and the body fails with
+/// [DigestError::Mismatch] otherwise. Bodies without a SHA-256 digest go
+/// through unchecked.
+pub fn verify_content_digest<B: Body>(
+    body: B,
+    headers: &Headers,
+) -> Result<VerifyDigest<B>, DigestError> {
+    Ok(VerifyDigest {
+        body,
+        expected: parse_content_digest(headers)?,
+        hasher: Sha256::default(),
+    })
+}
+
+/// See [verify_content_digest]
+pub struct VerifyDigest<B> {
+    body: B,
+    expected: Option<[u8; 32]>,
+    hasher: Sha256,
+}
+
+impl<B: fmt::Debug> fmt::Debug for VerifyDigest<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerifyDigest")
+            .field("body", &self.body)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Body> Body for VerifyDigest<B> {
+    fn content_len(&self) -> Option<u64> {
+        self.body.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.body.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        let chunk = self.body.next_chunk().await?;
+        match &chunk {
+            BodyChunk::Chunk(piece) => self.hasher.update_piece(piece),
+            BodyChunk::Done { trailers } => {
+                let expected = match (self.expected, trailers) {
+                    (Some(expected), _) => Some(expected),
+                    (None, Some(trailers)) => parse_content_digest(trailers)?,
+                    (None, None) => None,
+                };
+                let actual = std::mem::take(&mut self.hasher).finish();
+                if expected.is_some_and(|expected| expected != actual) {
+                    return Err(DigestError::Mismatch { algorithm: SHA_256 }.into());
+                }
+            }
+        }
+        Ok(chunk)
+    }
+
+    fn bytes_read(&self) -> Option<u64> {
+        self.body.bytes_read()
+    }
+
+    fn expected_len(&self) -> Option<u64> {
+        self.body.expected_len()
+    }
+}
+
+/// Sends a `content-digest` trailer after `body`, computed as it's
+/// written. Trailers need chunked transfer-encoding in HTTP/1.1, so the
+/// resulting body never announces a content-length.
+pub fn with_content_digest<B: Body>(body: B) -> DigestTrailer<B> {
+    DigestTrailer {
+        body,
+        hasher: Sha256::default(),
+    }
+}
+
+/// See [with_content_digest]
+pub struct DigestTrailer<B> {
+    body: B,
+    hasher: Sha256,
+}
+
+impl<B: fmt::Debug> fmt::Debug for DigestTrailer<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DigestTrailer")
+            .field("body", &self.body)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Body> Body for DigestTrailer<B> {
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.body.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        Ok(match self.body.next_chunk().await? {
+            BodyChunk::Chunk(piece) => {
+                self.hasher.update_piece(&piece);
+                BodyChunk::Chunk(piece)
+            }
+            BodyChunk::Done { trailers } => {
+                let digest = std::mem::take(&mut self.hasher).finish();
+                let mut trailers = trailers.unwrap_or_default();
+                trailers.insert(
+                    CONTENT_DIGEST,
+                    content_digest_value(&digest).into_bytes().into(),
+                );
+                BodyChunk::Done {
+                    trailers: Some(trailers),
+                }
+            }
+        })
+    }
+
+    fn bytes_read(&self) -> Option<u64> {
+        self.body.bytes_read()
+    }
+
+    fn expected_len(&self) -> Option<u64> {
+        self.body.expected_len()
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(input: &[u8]) -> String {
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.as_bytes();
+    if input.len() % 4 != 0 {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    for (i, quad) in input.chunks(4).enumerate() {
+        let last = i == input.len() / 4 - 1;
+        let padding = quad.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut n = 0u32;
+        for &c in &quad[..4 - padding] {
+            let v = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            n = n << 6 | v;
+        }
+        n <<= 6 * padding as u32;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        base64_decode, base64_encode, parse_content_digest, verify_content_digest,
+        with_content_digest, DigestError, CONTENT_DIGEST,
+    };
+    use crate::{
+        body::{once, BodyExt},
+        Body, BodyChunk, Headers,
+    };
+
+    // from RFC 9530, appendix D.1
+    const HELLO: &str = r#"{"hello": "world"}"#;
+    const HELLO_DIGEST: &str = "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:";
+
+    fn headers(digest: &'static str) -> Headers {
+        let mut headers = Headers::default();
+        headers.insert(CONTENT_DIGEST, digest.into());
+        headers
+    }
+
+    #[test]
+    fn base64() {
+        for input in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            let encoded = base64_encode(input);
+            assert_eq!(base64_decode(&encoded).unwrap(), input, "{encoded}");
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert!(base64_decode("Zm9vYg=").is_none());
+        assert!(base64_decode("Zg==Zg==").is_none());
+        assert!(base64_decode("Zm9v!g==").is_none());
+    }
+
+    #[test]
+    fn parse() {
+        let digest = parse_content_digest(&headers(HELLO_DIGEST)).unwrap();
+        assert!(digest.is_some());
+
+        // unsupported algorithms are skipped
+        let digest = parse_content_digest(&headers("sha-512=:AAAA:, unixsum=:AAAA:")).unwrap();
+        assert!(digest.is_none());
+
+        for malformed in ["sha-256", "sha-256=abc", "sha-256=:!!:", "sha-256=:AAAA:"] {
+            assert!(
+                parse_content_digest(&headers(malformed)).is_err(),
+                "{malformed}"
+            );
+        }
+    }
+
+    #[test]
+    fn generate_and_verify() {
+        fluke_buffet::start(async move {
+            let mut body = with_content_digest(once("{\"hello\": ").chain(once("\"world\"}")));
+            assert_eq!(body.content_len(), None);
+            let trailers = loop {
+                if let BodyChunk::Done { trailers } = body.next_chunk().await.unwrap() {
+                    break trailers.unwrap();
+                }
+            };
+            assert_eq!(&trailers[CONTENT_DIGEST][..], HELLO_DIGEST.as_bytes());
+
+            let mut body = verify_content_digest(once(HELLO), &headers(HELLO_DIGEST)).unwrap();
+            assert_eq!(&body.collect(100).await.unwrap()[..], HELLO.as_bytes());
+
+            // the digest may also come in trailers
+            let mut body =
+                verify_content_digest(with_content_digest(once(HELLO)), &Headers::default())
+                    .unwrap();
+            body.collect(100).await.unwrap();
+
+            let mut body =
+                verify_content_digest(once("{\"hello\": \"there\"}"), &headers(HELLO_DIGEST))
+                    .unwrap();
+            let err = body.collect(100).await.err().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<DigestError>(),
+                Some(DigestError::Mismatch { .. })
+            ));
+        });
+    }
+}
@@ -0,0 +1,19 @@
+//! Ready-made [Body] implementations, for responses that are generated
+//! on the fly, and adapters to compose them.
+
+mod blocking;
+pub use blocking::*;
+
+mod boxed;
+pub use boxed::*;
+
+mod combinators;
+pub use combinators::*;
+
+mod digest;
+pub use digest::*;
+
+#[cfg(feature = "json")]
+mod ndjson;
+#[cfg(feature = "json")]
+pub use ndjson::*;
@@ -0,0 +1,87 @@
+use std::{fmt, pin::Pin};
+
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+
+use crate::{Body, BodyChunk};
+
+/// The media type for newline-delimited JSON, for the `content-type` header
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Newline-delimited JSON (<https://github.com/ndjson/ndjson-spec>): each
+/// item of a stream is serialized on its own line, and sent as its own
+/// chunk, so the client sees it as soon as it's produced.
+///
+/// ```no_run
+/// # async fn example<E: fluke::Encoder>(
+/// #     respond: fluke::Responder<E, fluke::ExpectResponseHeaders>,
+/// # ) -> eyre::Result<fluke::Responder<E, fluke::ResponseDone>> {
+/// use fluke::{body::{NdJsonStream, NDJSON_CONTENT_TYPE}, http::header, Response};
+///
+/// let items = futures_util::stream::iter([1, 2, 3]);
+/// let mut res = Response::default();
+/// res.headers
+///     .insert(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE.into());
+/// respond
+///     .write_final_response_with_body(res, &mut NdJsonStream::new(items))
+///     .await
+/// # }
+/// ```
+pub struct NdJsonStream<S> {
+    items: Pin<Box<S>>,
+    done: bool,
+}
+
+impl<S> NdJsonStream<S>
+where
+    S: Stream,
+    S::Item: Serialize,
+{
+    /// Serializes the items of `items` as they come
+    pub fn new(items: S) -> Self {
+        Self {
+            items: Box::pin(items),
+            done: false,
+        }
+    }
+}
+
+impl<S> fmt::Debug for NdJsonStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NdJsonStream")
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Body for NdJsonStream<S>
+where
+    S: Stream,
+    S::Item: Serialize,
+{
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.done
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        if self.done {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        match self.items.next().await {
+            Some(item) => {
+                let mut line = serde_json::to_vec(&item)?;
+                line.push(b'\n');
+                Ok(BodyChunk::Chunk(line.into()))
+            }
+            None => {
+                self.done = true;
+                Ok(BodyChunk::Done { trailers: None })
+            }
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Accounting of the bytes a connection holds onto in buffers (read
+//! buffers, header blocks, queued response data), so that a single
+//! connection can't grab an unbounded share of the buffer pool.
+
+use std::{
+    cell::Cell,
+    fmt::Write,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+static BYTES_HELD: AtomicU64 = AtomicU64::new(0);
+static PEAK_BYTES_HELD: AtomicU64 = AtomicU64::new(0);
+static BUDGETS_EXCEEDED: AtomicU64 = AtomicU64::new(0);
+
+/// Totals across every connection served by this process, for metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Bytes currently held by all connections
+    pub bytes_held: u64,
+
+    /// Highest value `bytes_held` ever reached
+    pub peak_bytes_held: u64,
+
+    /// How many times a connection went over its budget
+    pub budgets_exceeded: u64,
+}
+
+/// Returns process-wide buffer accounting totals
+pub fn memory_stats() -> MemoryStats {
+    MemoryStats {
+        bytes_held: BYTES_HELD.load(Ordering::Relaxed),
+        peak_bytes_held: PEAK_BYTES_HELD.load(Ordering::Relaxed),
+        budgets_exceeded: BUDGETS_EXCEEDED.load(Ordering::Relaxed),
+    }
+}
+
+/// Tracks how many bytes a single connection holds in buffers, against an
+/// optional limit. Cloning it gives another handle to the same budget.
+#[derive(Clone, Default)]
+pub struct MemoryBudget {
+    inner: Rc<BudgetInner>,
+}
+
+#[derive(Default)]
+struct BudgetInner {
+    limit: Option<usize>,
+    used: Cell<usize>,
+    peak: Cell<usize>,
+    back_under: Notify,
+}
+
+impl MemoryBudget {
+    /// Creates a budget: `None` means unlimited (bytes are still counted)
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            inner: Rc::new(BudgetInner {
+                limit,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// The configured limit, if any
+    pub fn limit(&self) -> Option<usize> {
+        self.inner.limit
+    }
+
+    /// Bytes currently held by this connection
+    pub fn used(&self) -> usize {
+        self.inner.used.get()
+    }
+
+    /// Highest value `used` ever reached for this connection
+    pub fn peak(&self) -> usize {
+        self.inner.peak.get()
+    }
+
+    /// Returns true if the connection is holding more than its limit
+    pub fn is_exceeded(&self) -> bool {
+        self.inner.limit.is_some_and(|limit| self.used() > limit)
+    }
+
+    /// Resolves once the connection goes back under its limit, after having
+    /// gone over it
+    pub(crate) async fn back_under(&self) {
+        self.inner.back_under.notified().await
+    }
+
+    /// Starts accounting for some bytes: they're released when the returned
+    /// [Charge] is dropped.
+    pub(crate) fn charge(&self, n: usize) -> Charge {
+        let mut charge = Charge {
+            budget: self.clone(),
+            amount: 0,
+        };
+        charge.grow(n);
+        charge
+    }
+
+    /// Why a connection is being closed for resource reasons, followed by
+    /// where its budget stands, as space-separated `key=value` pairs, e.g.
+    /// `header_block_over_budget stream=3 mem_used=4198400 mem_peak=4198400
+    /// mem_limit=4194304 age_ms=812`. Sent as GOAWAY debug data over
+    /// HTTP/2, and logged when closing HTTP/1.1 connections, so that errors
+    /// seen by clients can be matched with what the server saw.
+    pub(crate) fn close_summary(&self, reason: &str, age: Duration) -> String {
+        let mut summary = format!("{reason} mem_used={} mem_peak={}", self.used(), self.peak());
+        if let Some(limit) = self.limit() {
+            _ = write!(summary, " mem_limit={limit}");
+        }
+        _ = write!(summary, " age_ms={}", age.as_millis());
+        summary
+    }
+
+    fn add(&self, n: usize) {
+        let was_exceeded = self.is_exceeded();
+
+        let used = self.inner.used.get() + n;
+        self.inner.used.set(used);
+        if used > self.inner.peak.get() {
+            self.inner.peak.set(used);
+        }
+
+        let total = BYTES_HELD.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        PEAK_BYTES_HELD.fetch_max(total, Ordering::Relaxed);
+
+        if !was_exceeded && self.is_exceeded() {
+            BUDGETS_EXCEEDED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn sub(&self, n: usize) {
+        let was_exceeded = self.is_exceeded();
+
+        self.inner.used.set(self.inner.used.get() - n);
+        BYTES_HELD.fetch_sub(n as u64, Ordering::Relaxed);
+
+        if was_exceeded && !self.is_exceeded() {
+            self.inner.back_under.notify_one();
+        }
+    }
+}
+
+/// A number of bytes accounted against a [MemoryBudget], which can change
+/// over time. Whatever is left is released on drop.
+pub(crate) struct Charge {
+    budget: MemoryBudget,
+    amount: usize,
+}
+
+impl Charge {
+    pub(crate) fn grow(&mut self, n: usize) {
+        self.budget.add(n);
+        self.amount += n;
+    }
+
+    pub(crate) fn shrink(&mut self, n: usize) {
+        let n = n.min(self.amount);
+        self.budget.sub(n);
+        self.amount -= n;
+    }
+
+    /// Grows or shrinks the charge so that it accounts for exactly `n` bytes
+    pub(crate) fn set(&mut self, n: usize) {
+        if n > self.amount {
+            self.grow(n - self.amount);
+        } else {
+            self.shrink(self.amount - n);
+        }
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.sub(self.amount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::MemoryBudget;
+
+    #[test]
+    fn charges_are_released_on_drop() {
+        let budget = MemoryBudget::new(Some(100));
+
+        let mut a = budget.charge(60);
+        assert_eq!(budget.used(), 60);
+        assert!(!budget.is_exceeded());
+
+        let b = budget.charge(50);
+        assert_eq!(budget.used(), 110);
+        assert!(budget.is_exceeded());
+
+        a.shrink(20);
+        assert_eq!(budget.used(), 90);
+        assert!(!budget.is_exceeded());
+
+        drop(b);
+        a.set(10);
+        assert_eq!(budget.used(), 10);
+
+        drop(a);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.peak(), 110);
+    }
+
+    #[test]
+    fn close_summary() {
+        let budget = MemoryBudget::new(Some(100));
+        let _charge = budget.charge(150);
+        assert_eq!(
+            budget.close_summary(
+                "header_block_over_budget stream=3",
+                Duration::from_millis(812)
+            ),
+            "header_block_over_budget stream=3 mem_used=150 mem_peak=150 mem_limit=100 age_ms=812"
+        );
+
+        let budget = MemoryBudget::new(None);
+        assert_eq!(
+            budget.close_summary("idle timeout_ms=5000", Duration::from_secs(5)),
+            "idle timeout_ms=5000 mem_used=0 mem_peak=0 age_ms=5000"
+        );
+    }
+
+    #[test]
+    fn unlimited_budget_is_never_exceeded() {
+        let budget = MemoryBudget::new(None);
+        let _charge = budget.charge(usize::MAX / 2);
+        assert!(!budget.is_exceeded());
+    }
+}
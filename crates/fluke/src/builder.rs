@@ -0,0 +1,764 @@
+//! A one-stop shop for running a server: binds a listener, picks the
+//! protocol for each connection, and forwards limits to [h1::ServerConf] and
+//! [h2::ServerConf].
+//!
+//! TLS isn't handled here: terminate it in front of fluke, or drive
+//! [h1::serve] / [h2::serve] yourself with a TLS stream.
+//!
+//! Metrics and access logs come from wrapping the driver, see
+//! [ServerBuilder::build_with_metrics]. Everything else is logged through
+//! `tracing`.
+//!
+//! Per-connection settings live in a [ConnConf], which can be swapped while
+//! the server runs through [Server::conf]. Accepting connections can be
+//! paused and resumed through [Server::listener], or handed over to another
+//! process with [crate::handover].
+
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    future::Future,
+    net::SocketAddr,
+    os::fd::AsFd,
+    pin::Pin,
+    rc::Rc,
+    time::Duration,
+};
+
+use fluke_buffet::{
+    net::{ListenOptions, TcpListener, TcpStream},
+    IntoHalves, RollMut,
+};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::{
+    h1, h2,
+    metrics::{Metrics, Timed},
+    CloseReason, ConfHandle, ConnInfo, LoanLimit, ServeError, ServerDriver,
+};
+
+/// Which protocol to speak on accepted connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// HTTP/1.1 only
+    H1,
+
+    /// HTTP/2 with prior knowledge only
+    H2,
+
+    /// HTTP/2 if the client starts with the connection preface,
+    /// HTTP/1.1 otherwise
+    #[default]
+    Auto,
+}
+
+/// Fluent configuration for a fluke server.
+///
+/// ```no_run
+/// # async fn example(driver: impl fluke::ServerDriver + 'static) -> std::io::Result<()> {
+/// let server = fluke::ServerBuilder::new("127.0.0.1:8080".parse().unwrap())
+///     .protocol(fluke::Protocol::Auto)
+///     .max_streams(Some(64))
+///     .build(driver)
+///     .await?;
+/// server.run().await
+/// # }
+/// ```
+pub struct ServerBuilder {
+    bind: Bind,
+    listen: ListenOptions,
+    conf: ConnConf,
+    buffer_wait: Option<Duration>,
+}
+
+enum Bind {
+    Addr(SocketAddr),
+    Listener(std::net::TcpListener),
+}
+
+/// Settings read by every connection when it's accepted
+#[derive(Clone)]
+pub struct ConnConf {
+    /// Which protocol(s) to speak, see [Protocol]
+    pub protocol: Protocol,
+
+    /// HTTP/1.1 limits
+    pub h1: h1::ServerConf,
+
+    /// HTTP/2 limits
+    pub h2: h2::ServerConf,
+
+    /// How long a freshly accepted connection may stay silent before it's
+    /// closed. `None` waits forever.
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl Default for ConnConf {
+    fn default() -> Self {
+        Self {
+            protocol: Default::default(),
+            h1: Default::default(),
+            h2: Default::default(),
+            handshake_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Starts configuring a server that will listen on `addr`
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            bind: Bind::Addr(addr),
+            listen: Default::default(),
+            conf: Default::default(),
+            buffer_wait: None,
+        }
+    }
+
+    /// Starts configuring a server that will accept connections on a socket
+    /// that's already bound and listening, e.g. one inherited through
+    /// [crate::buffet::net::listen_fds]. Of the [ListenOptions], only those
+    /// about accepted connections apply.
+    pub fn from_listener(listener: std::net::TcpListener) -> Self {
+        Self {
+            bind: Bind::Listener(listener),
+            listen: Default::default(),
+            conf: Default::default(),
+            buffer_wait: None,
+        }
+    }
+
+    /// Socket options for the listener and accepted connections (nodelay,
+    /// keepalive, buffer sizes...)
+    pub fn listen_options(mut self, options: ListenOptions) -> Self {
+        self.listen = options;
+        self
+    }
+
+    /// Which protocol(s) to speak, see [Protocol]
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.conf.protocol = protocol;
+        self
+    }
+
+    /// Replaces the whole HTTP/1.1 configuration
+    pub fn h1_conf(mut self, conf: h1::ServerConf) -> Self {
+        self.conf.h1 = conf;
+        self
+    }
+
+    /// Replaces the whole HTTP/2 configuration
+    pub fn h2_conf(mut self, conf: h2::ServerConf) -> Self {
+        self.conf.h2 = conf;
+        self
+    }
+
+    /// Max length of the HTTP/1.1 request line + headers
+    pub fn max_http_header_len(mut self, len: usize) -> Self {
+        self.conf.h1.max_http_header_len = len;
+        self
+    }
+
+    /// Max length of the request target, for both protocols
+    pub fn max_uri_len(mut self, len: usize) -> Self {
+        self.conf.h1.max_uri_len = len;
+        self.conf.h2.max_uri_len = len;
+        self
+    }
+
+    /// Max number of concurrent HTTP/2 streams per connection
+    pub fn max_streams(mut self, max_streams: Option<u32>) -> Self {
+        self.conf.h2.max_streams = max_streams;
+        self
+    }
+
+    /// Max number of bytes a single connection may hold in buffers, for
+    /// both protocols. See [crate::MemoryBudget].
+    pub fn memory_budget(mut self, budget: Option<usize>) -> Self {
+        self.conf.h1.memory_budget = budget;
+        self.conf.h2.memory_budget = budget;
+        self
+    }
+
+    /// Bodies for the error responses the server sends on its own, for both
+    /// protocols. See [crate::ErrorPages].
+    pub fn error_pages(mut self, pages: crate::ErrorPages) -> Self {
+        self.conf.h1.error_pages = pages.clone();
+        self.conf.h2.error_pages = pages;
+        self
+    }
+
+    /// The `allow` header to answer `OPTIONS *` requests with, for both
+    /// protocols. See [h1::ServerConf::options_allow].
+    pub fn options_allow(mut self, allow: Option<Cow<'static, str>>) -> Self {
+        self.conf.h1.options_allow = allow.clone();
+        self.conf.h2.options_allow = allow;
+        self
+    }
+
+    /// How many buffer pool blocks drivers may pin by holding on to request
+    /// body chunks, for both protocols. See [crate::LoanLimit].
+    pub fn loan_limit(mut self, limit: Option<LoanLimit>) -> Self {
+        self.conf.h1.loan_limit = limit;
+        self.conf.h2.loan_limit = limit;
+        self
+    }
+
+    /// How often to PING HTTP/2 peers, see [h2::ServerConf::ping_interval]
+    pub fn ping_interval(mut self, interval: Option<Duration>) -> Self {
+        self.conf.h2.ping_interval = interval;
+        self
+    }
+
+    /// How long HTTP/2 peers have to acknowledge a PING, see
+    /// [h2::ServerConf::ping_timeout]
+    pub fn ping_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.conf.h2.ping_timeout = timeout;
+        self
+    }
+
+    /// How large HTTP/2 receive windows may grow, see
+    /// [h2::ServerConf::adaptive_window]
+    pub fn adaptive_window(mut self, max_window: Option<u32>) -> Self {
+        self.conf.h2.adaptive_window = max_window;
+        self
+    }
+
+    /// Whether to serve HTTP/2 clients that open streams before sending
+    /// their SETTINGS, see [h2::ServerConf::lenient_settings]
+    pub fn lenient_h2_settings(mut self, lenient: bool) -> Self {
+        self.conf.h2.lenient_settings = lenient;
+        self
+    }
+
+    /// How long an HTTP/2 connection may stay idle before it's closed, see
+    /// [h2::ServerConf::idle_timeout]
+    pub fn h2_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.conf.h2.idle_timeout = timeout;
+        self
+    }
+
+    /// Whether to join the `cookie` headers of HTTP/2 requests into one, see
+    /// [h2::ServerConf::join_cookies]
+    pub fn join_h2_cookies(mut self, join: bool) -> Self {
+        self.conf.h2.join_cookies = join;
+        self
+    }
+
+    /// How long the driver has to respond to each request, for both
+    /// protocols. See [crate::Deadline].
+    pub fn request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.conf.h1.request_timeout = timeout;
+        self.conf.h2.request_timeout = timeout;
+        self
+    }
+
+    /// How long a freshly accepted connection may stay silent before it's
+    /// closed. `None` waits forever.
+    pub fn handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.conf.handshake_timeout = timeout;
+        self
+    }
+
+    /// How long reads wait for a buffer when the buffer pool is exhausted,
+    /// instead of failing the connection, see
+    /// [crate::buffet::bufpool::set_exhaustion_wait]. Applies to the thread
+    /// the server runs on, from [Server::run] on.
+    pub fn buffer_wait(mut self, wait: Duration) -> Self {
+        self.buffer_wait = Some(wait);
+        self
+    }
+
+    /// Like [Self::build], but reports every request and connection to
+    /// `metrics`, see [crate::metrics]. Pass [crate::metrics::AccessLog]
+    /// (alone, or in a tuple along with other metrics) for an access log.
+    pub async fn build_with_metrics<D, M>(
+        self,
+        driver: D,
+        metrics: M,
+    ) -> std::io::Result<Server<Timed<D, M>>>
+    where
+        D: ServerDriver + 'static,
+        M: Metrics + 'static,
+    {
+        self.build(Timed::new(driver, metrics)).await
+    }
+
+    /// Binds the listener. Must be called from within [crate::buffet::start].
+    pub async fn build<D>(self, driver: D) -> std::io::Result<Server<D>>
+    where
+        D: ServerDriver + 'static,
+    {
+        let listener = match self.bind {
+            Bind::Addr(addr) => TcpListener::bind_with(addr, &self.listen).await?,
+            Bind::Listener(listener) => TcpListener::from_std(listener, &self.listen)?,
+        };
+        let local_addr = listener.local_addr()?;
+
+        Ok(Server {
+            listen: self.listen,
+            buffer_wait: self.buffer_wait,
+            local_addr,
+            control: Listener::new(listener),
+            driver: Rc::new(driver),
+            conf: ConfHandle::new(self.conf),
+            stats: Default::default(),
+        })
+    }
+}
+
+/// What a connection task gets out of [ConnConf]: `h1::serve` and
+/// `h2::serve` want their configuration behind an `Rc`.
+struct ConnSnapshot {
+    protocol: Protocol,
+    h1: Rc<h1::ServerConf>,
+    h2: Rc<h2::ServerConf>,
+    handshake_timeout: Option<Duration>,
+}
+
+impl ConnSnapshot {
+    fn new(conf: &ConnConf) -> Self {
+        Self {
+            protocol: conf.protocol,
+            h1: Rc::new(conf.h1.clone()),
+            h2: Rc::new(conf.h2.clone()),
+            handshake_timeout: conf.handshake_timeout,
+        }
+    }
+}
+
+/// A bound server, ready to accept connections with [Server::run]
+pub struct Server<D> {
+    listen: ListenOptions,
+    buffer_wait: Option<Duration>,
+    local_addr: SocketAddr,
+    control: Listener,
+    driver: Rc<D>,
+    conf: ConfHandle<ConnConf>,
+    stats: ServerStats,
+}
+
+impl<D> Server<D>
+where
+    D: ServerDriver + 'static,
+{
+    /// The address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// A handle to the per-connection settings: values stored through it
+    /// apply to connections accepted from then on.
+    pub fn conf(&self) -> ConfHandle<ConnConf> {
+        self.conf.clone()
+    }
+
+    /// Connection counters, which keep updating while the server runs
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    /// A handle to pause and resume accepting connections
+    pub fn listener(&self) -> Listener {
+        self.control.clone()
+    }
+
+    /// Accepts connections, serving each of them in its own task, until the
+    /// listener gets detached (see [Listener::detach]) and the last
+    /// connection is done. Failing to accept a connection, or to bind the
+    /// socket again when resuming after it was closed, is logged and retried
+    /// with a backoff: it doesn't stop the server.
+    pub async fn run(self) -> std::io::Result<()> {
+        if let Some(wait) = self.buffer_wait {
+            crate::buffet::bufpool::set_exhaustion_wait(Some(wait));
+        }
+
+        // only rebuilt when the configuration gets reloaded
+        let mut generation = self.conf.generation();
+        let mut snapshot = Rc::new(ConnSnapshot::new(&self.conf.load()));
+
+        // an accept may race with whatever stops it and still come back with
+        // a connection, so it's never just dropped: one that's in flight when
+        // we pause is kept around (but not polled) until we resume, woken up
+        // by shutting down the socket if it gets closed, or cancelled and
+        // awaited if the listener gets detached.
+        type Accept = Pin<Box<dyn Future<Output = std::io::Result<(TcpStream, SocketAddr)>>>>;
+        let socket = &self.control.inner.socket;
+        let mut accept: Option<Accept> = None;
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+
+        loop {
+            let accepted = match self.control.inner.state.get() {
+                ListenerState::Accepting => {
+                    let current = socket.borrow().clone();
+                    let l = match current {
+                        Some(l) => l,
+                        None => match TcpListener::bind_with(self.local_addr, &self.listen).await {
+                            Ok(l) => {
+                                backoff = ACCEPT_BACKOFF_MIN;
+                                debug!(local_addr = %self.local_addr, "listening again");
+                                socket.borrow_mut().insert(Rc::new(l)).clone()
+                            }
+                            Err(e) => {
+                                // e.g. someone else took the port meanwhile:
+                                // connections that are still open keep
+                                // being served, and we try again later
+                                warn!(
+                                    local_addr = %self.local_addr,
+                                    ?backoff,
+                                    "couldn't listen again: {e}"
+                                );
+                                let wait = backoff;
+                                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                                tokio::select! {
+                                    _ = tokio::time::sleep(wait) => {}
+                                    _ = self.control.inner.changed.notified() => {}
+                                }
+                                continue;
+                            }
+                        },
+                    };
+                    let fut =
+                        accept.get_or_insert_with(|| Box::pin(async move { l.accept().await }));
+                    tokio::select! {
+                        res = fut => {
+                            accept = None;
+                            match res {
+                                Ok(accepted) => {
+                                    backoff = ACCEPT_BACKOFF_MIN;
+                                    accepted
+                                }
+                                Err(e) => {
+                                    warn!(
+                                        local_addr = %self.local_addr,
+                                        ?backoff,
+                                        "error accepting connection: {e}"
+                                    );
+                                    // errors that keep coming back would
+                                    // otherwise have us spin
+                                    let mut wait = backoff;
+                                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                                    if is_fd_exhaustion(&e) {
+                                        // only connections finishing free some up
+                                        wait = wait.max(FD_EXHAUSTION_BACKOFF);
+                                    }
+                                    tokio::select! {
+                                        _ = tokio::time::sleep(wait) => {}
+                                        _ = self.control.inner.changed.notified() => {}
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+                        _ = self.control.inner.changed.notified() => continue,
+                    }
+                }
+                ListenerState::Paused => {
+                    self.control.inner.changed.notified().await;
+                    continue;
+                }
+                ListenerState::Detached => {
+                    // the socket may be shared with another process, it's
+                    // theirs to shut down: only stop our own accept, and
+                    // serve the connection it may have raced with.
+                    if let Some(fut) = accept.take() {
+                        let l = socket.borrow().clone();
+                        if let Some(l) = l {
+                            if let Err(e) = l.cancel_accept().await {
+                                warn!(local_addr = %self.local_addr, "couldn't cancel accept: {e}");
+                            }
+                        }
+                        match fut.await {
+                            Ok(accepted) => accepted,
+                            Err(_) => continue,
+                        }
+                    } else {
+                        socket.borrow_mut().take();
+                        debug!(
+                            local_addr = %self.local_addr,
+                            active = %self.stats.active(),
+                            "detached listener, draining connections"
+                        );
+                        while self.stats.active() > 0 {
+                            self.stats.inner.idle.notified().await;
+                        }
+                        return Ok(());
+                    }
+                }
+                ListenerState::Closed => {
+                    let Some(l) = socket.borrow_mut().take() else {
+                        self.control.inner.changed.notified().await;
+                        continue;
+                    };
+                    l.shutdown()?;
+                    debug!(local_addr = %self.local_addr, "closed listener");
+                    match accept.take() {
+                        // the client got in just before the socket closed
+                        Some(fut) => match fut.await {
+                            Ok(accepted) => accepted,
+                            Err(_) => continue,
+                        },
+                        None => continue,
+                    }
+                }
+            };
+
+            let (stream, remote_addr) = accepted;
+            debug!(%remote_addr, "accepted connection");
+            self.stats.inner.accepted.set(self.stats.accepted() + 1);
+            self.stats.inner.active.set(self.stats.active() + 1);
+
+            if self.conf.generation() != generation {
+                generation = self.conf.generation();
+                snapshot = Rc::new(ConnSnapshot::new(&self.conf.load()));
+                debug!(%generation, "picked up reloaded configuration");
+            }
+
+            let driver = self.driver.clone();
+            let conf = snapshot.clone();
+            let stats = self.stats.clone();
+            crate::buffet::spawn(async move {
+                let reason = match serve_conn(stream, &conf, driver).await {
+                    Ok(reason) => reason,
+                    Err(e) => {
+                        warn!(%remote_addr, "error serving connection: {e}");
+                        stats.inner.errored.set(stats.errored() + 1);
+                        e.close_reason()
+                    }
+                };
+                stats.inner.active.set(stats.active() - 1);
+                if stats.active() == 0 {
+                    stats.inner.idle.notify_one();
+                }
+                debug!(%remote_addr, close_reason = %reason, "done serving connection");
+            });
+        }
+    }
+}
+
+/// How long to stop accepting for after an error, doubling with each error in
+/// a row, up to [ACCEPT_BACKOFF_MAX]
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// How long to stop accepting for, at least, when the process runs out of
+/// file descriptors (or socket buffers)
+const FD_EXHAUSTION_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether accepting failed because the process or the system ran out of
+/// resources, rather than because of that one connection
+fn is_fd_exhaustion(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
+async fn serve_conn<D>(
+    stream: TcpStream,
+    conf: &ConnSnapshot,
+    driver: Rc<D>,
+) -> Result<CloseReason, ServeError>
+where
+    D: ServerDriver + 'static,
+{
+    let conn = ConnInfo::default();
+    let (mut transport_r, transport_w) = stream.into_halves();
+
+    // the first read tells us whether the client speaks h2 with prior
+    // knowledge, and doubles as the handshake timeout.
+    let first_read = async { sniff(RollMut::alloc_wait().await?, &mut transport_r).await };
+    let sniffed = match conf.handshake_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, first_read).await {
+            Ok(res) => res,
+            Err(_) => {
+                debug!("client stayed silent for {timeout:?}, closing connection");
+                let reason = CloseReason::IdleTimeout;
+                crate::close::connection_closed(&driver, &conn, reason.clone());
+                return Ok(reason);
+            }
+        },
+        None => first_read.await,
+    };
+    let (client_buf, is_h2) = match sniffed {
+        Ok(sniffed) => sniffed,
+        Err(e) => {
+            crate::close::connection_closed(&driver, &conn, e.close_reason());
+            return Err(e);
+        }
+    };
+
+    let h2 = match conf.protocol {
+        Protocol::H1 => false,
+        Protocol::H2 => true,
+        Protocol::Auto => is_h2,
+    };
+    if h2 {
+        h2::serve_with(
+            (transport_r, transport_w),
+            conf.h2.clone(),
+            client_buf,
+            driver,
+            conn,
+        )
+        .await
+    } else {
+        h1::serve_with(
+            (transport_r, transport_w),
+            conf.h1.clone(),
+            client_buf,
+            driver,
+            conn,
+        )
+        .await
+        .map(|outcome| outcome.close_reason())
+    }
+}
+
+/// Reads until we can tell whether this is the HTTP/2 connection preface
+/// (or the peer hangs up).
+async fn sniff(
+    mut buf: RollMut,
+    r: &mut impl fluke_buffet::ReadOwned,
+) -> Result<(RollMut, bool), ServeError> {
+    const SNIFF_LEN: usize = 3; // "PRI", not a method any h1 client would use
+
+    while buf.len() < SNIFF_LEN {
+        if buf.cap() == 0 {
+            buf.reserve_wait().await?;
+        }
+        let res;
+        let limit = SNIFF_LEN - buf.len();
+        (res, buf) = buf.read_into(limit, r).await;
+        if res? == 0 {
+            break;
+        }
+    }
+
+    let is_h2 = buf[..].starts_with(&fluke_h2_parse::PREFACE[..SNIFF_LEN]);
+    Ok((buf, is_h2))
+}
+
+/// Pauses and resumes accepting connections on a [Server], e.g. to shed
+/// load or to hand the socket over to another process. Connections that
+/// were already accepted are served either way. Cloning it gives another
+/// handle to the same listener.
+#[derive(Clone, Default)]
+pub struct Listener {
+    inner: Rc<ListenerInner>,
+}
+
+#[derive(Default)]
+struct ListenerInner {
+    state: Cell<ListenerState>,
+    changed: Notify,
+    // `None` while closed
+    socket: RefCell<Option<Rc<TcpListener>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ListenerState {
+    #[default]
+    Accepting,
+    Paused,
+    Closed,
+    Detached,
+}
+
+impl Listener {
+    fn new(socket: TcpListener) -> Self {
+        let listener = Self::default();
+        *listener.inner.socket.borrow_mut() = Some(Rc::new(socket));
+        listener
+    }
+
+    /// Stops accepting connections, but keeps the socket open: clients can
+    /// still connect, and wait in the kernel's backlog until [Listener::resume]
+    /// is called.
+    pub fn pause(&self) {
+        if self.inner.state.get() == ListenerState::Accepting {
+            self.set(ListenerState::Paused);
+        }
+    }
+
+    /// Stops accepting connections and closes the socket: clients get
+    /// "connection refused", and the address is free for someone else to
+    /// listen on. [Listener::resume] binds it again.
+    pub fn pause_and_close(&self) {
+        if self.inner.state.get() != ListenerState::Detached {
+            self.set(ListenerState::Closed);
+        }
+    }
+
+    /// Accepts connections again, binding a new socket to the same address
+    /// if it was closed: if that fails, it's retried with a backoff until it
+    /// works, or the listener is paused again. Does nothing once detached.
+    pub fn resume(&self) {
+        if self.inner.state.get() != ListenerState::Detached {
+            self.set(ListenerState::Accepting);
+        }
+    }
+
+    /// Stops accepting connections for good, and closes our copy of the
+    /// socket without shutting it down, since other processes may be
+    /// accepting on it: see [crate::handover]. [Server::run] returns once
+    /// the connections already accepted are done.
+    pub fn detach(&self) {
+        self.set(ListenerState::Detached);
+    }
+
+    /// Whether connections are currently not being accepted
+    pub fn is_paused(&self) -> bool {
+        self.inner.state.get() != ListenerState::Accepting
+    }
+
+    /// A duplicate of the listening socket, e.g. to hand it over to another
+    /// process. `None` while it's closed.
+    pub fn socket(&self) -> std::io::Result<Option<std::net::TcpListener>> {
+        match &*self.inner.socket.borrow() {
+            Some(socket) => Ok(Some(socket.as_fd().try_clone_to_owned()?.into())),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, state: ListenerState) {
+        self.inner.state.set(state);
+        self.inner.changed.notify_one();
+    }
+}
+
+/// Connection counters for a [Server]. Cloning it gives another handle to
+/// the same counters. See also [crate::memory_stats] and
+/// [crate::buffet::bufpool::pool_stats].
+#[derive(Clone, Default)]
+pub struct ServerStats {
+    inner: Rc<StatsInner>,
+}
+
+#[derive(Default)]
+struct StatsInner {
+    accepted: Cell<u64>,
+    active: Cell<u64>,
+    errored: Cell<u64>,
+    // notified when the last active connection is done
+    idle: Notify,
+}
+
+impl ServerStats {
+    /// Connections accepted so far
+    pub fn accepted(&self) -> u64 {
+        self.inner.accepted.get()
+    }
+
+    /// Connections currently being served
+    pub fn active(&self) -> u64 {
+        self.inner.active.get()
+    }
+
+    /// Connections that ended with an error
+    pub fn errored(&self) -> u64 {
+        self.inner.errored.get()
+    }
+}
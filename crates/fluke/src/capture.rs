@@ -0,0 +1,447 @@
+//! Keeps the heads of the last few requests and responses around, to debug
+//! production issues after the fact without logging every request.
+//!
+//! Wrap a driver in a [CaptureDriver], keep a clone of its [Capture], and
+//! look at [Capture::recent] from wherever is convenient (an admin endpoint,
+//! a signal handler...):
+//!
+//! ```no_run
+//! # async fn f(driver: impl fluke::ServerDriver + 'static) -> std::io::Result<()> {
+//! use fluke::capture::{Capture, CaptureDriver};
+//!
+//! let capture = Capture::new(128);
+//! let server = fluke::ServerBuilder::new("[::]:8080".parse().unwrap())
+//!     .build(CaptureDriver::new(driver, capture.clone()))
+//!     .await?;
+//! // later on
+//! for transaction in capture.recent() {
+//!     println!("{transaction}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only heads are kept, never bodies. The values of headers that carry
+//! credentials (`authorization`, `cookie`, etc.) are replaced with
+//! `[redacted]`.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use fluke_buffet::{Piece, PieceList};
+use http::{header, StatusCode, Uri};
+
+use crate::{
+    h1::body::BodyWriteMode, h2, h2::KnownErrorCode, Body, CloseReason, ConnInfo, Encoder,
+    ExpectResponseHeaders, FieldDecision, HeaderDecision, Headers, Method, Request,
+    RequestProtocol, Responder, Response, ResponseDone, ServerDriver,
+};
+
+/// Headers whose values are never captured
+const REDACTED: [header::HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+
+/// A ring buffer of recent transactions. Cloning it gives another handle to
+/// the same buffer.
+#[derive(Clone)]
+pub struct Capture {
+    inner: Rc<RefCell<Ring>>,
+}
+
+struct Ring {
+    capacity: usize,
+    transactions: VecDeque<Transaction>,
+    recorded: u64,
+}
+
+impl Capture {
+    /// Keeps up to `capacity` transactions, dropping the oldest ones first
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(Ring {
+                capacity,
+                transactions: VecDeque::with_capacity(capacity),
+                recorded: 0,
+            })),
+        }
+    }
+
+    /// The transactions still in the buffer, oldest first
+    pub fn recent(&self) -> Vec<Transaction> {
+        self.inner.borrow().transactions.iter().cloned().collect()
+    }
+
+    /// The transaction with the given id, if it's still in the buffer
+    pub fn get(&self, id: u64) -> Option<Transaction> {
+        let ring = self.inner.borrow();
+        ring.transactions.iter().find(|t| t.id == id).cloned()
+    }
+
+    /// How many transactions were recorded since the buffer was created,
+    /// including the ones that fell out of it
+    pub fn recorded(&self) -> u64 {
+        self.inner.borrow().recorded
+    }
+
+    /// Empties the buffer
+    pub fn clear(&self) {
+        self.inner.borrow_mut().transactions.clear();
+    }
+
+    fn start(&self, req: &Request) -> Transaction {
+        let mut ring = self.inner.borrow_mut();
+        ring.recorded += 1;
+        Transaction {
+            id: ring.recorded,
+            started: SystemTime::now(),
+            duration: Duration::ZERO,
+            protocol: req.protocol(),
+            method: req.method.clone(),
+            uri: req.uri.clone(),
+            request_headers: redacted(&req.headers),
+            status: None,
+            response_headers: Default::default(),
+            response_body_len: 0,
+            outcome: Outcome::Cancelled,
+        }
+    }
+
+    fn push(&self, transaction: Transaction) {
+        let mut ring = self.inner.borrow_mut();
+        if ring.capacity == 0 {
+            return;
+        }
+        if ring.transactions.len() == ring.capacity {
+            ring.transactions.pop_front();
+        }
+        ring.transactions.push_back(transaction);
+    }
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ring = self.inner.borrow();
+        f.debug_struct("Capture")
+            .field("capacity", &ring.capacity)
+            .field("len", &ring.transactions.len())
+            .field("recorded", &ring.recorded)
+            .finish()
+    }
+}
+
+/// A request and what became of it
+#[derive(Clone)]
+pub struct Transaction {
+    /// Counts up from 1 in the order requests came in
+    pub id: u64,
+
+    /// When the request head was read
+    pub started: SystemTime,
+
+    /// How long it took until the handler was done (or dropped)
+    pub duration: Duration,
+
+    pub protocol: RequestProtocol,
+    pub method: Method,
+    pub uri: Uri,
+    pub request_headers: Headers,
+
+    /// The final response's status, `None` if none was sent
+    pub status: Option<StatusCode>,
+    pub response_headers: Headers,
+
+    /// How many bytes of response body were handed to the connection
+    pub response_body_len: u64,
+
+    pub outcome: Outcome,
+}
+
+/// How a transaction ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The handler returned successfully
+    Completed,
+
+    /// Answered by [ServerDriver::on_headers], before its body was read
+    AnsweredEarly,
+
+    /// The handler returned an error
+    Failed(String),
+
+    /// The handler was dropped before it was done: the client went away,
+    /// the deadline passed, or the connection errored out
+    Cancelled,
+}
+
+impl fmt::Debug for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transaction")
+            .field("id", &self.id)
+            .field("started", &self.started)
+            .field("duration", &self.duration)
+            .field("protocol", &self.protocol)
+            .field("method", &self.method)
+            .field("uri", &self.uri)
+            .field("request_headers", &DebugHeaders(&self.request_headers))
+            .field("status", &self.status)
+            .field("response_headers", &DebugHeaders(&self.response_headers))
+            .field("response_body_len", &self.response_body_len)
+            .field("outcome", &self.outcome)
+            .finish()
+    }
+}
+
+struct DebugHeaders<'a>(&'a Headers);
+
+impl fmt::Debug for DebugHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.0
+                    .iter()
+                    .map(|(name, value)| (name, String::from_utf8_lossy(value))),
+            )
+            .finish()
+    }
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} {} {} {:?} -> ",
+            self.id, self.method, self.uri, self.protocol
+        )?;
+        match self.status {
+            Some(status) => write!(f, "{status}")?,
+            None => write!(f, "no response")?,
+        }
+        write!(
+            f,
+            " ({:?}, {} body bytes, ",
+            self.duration, self.response_body_len
+        )?;
+        match &self.outcome {
+            Outcome::Completed => write!(f, "completed)")?,
+            Outcome::AnsweredEarly => write!(f, "answered early)")?,
+            Outcome::Failed(e) => write!(f, "failed: {e})")?,
+            Outcome::Cancelled => write!(f, "cancelled)")?,
+        }
+        for (prefix, headers) in [
+            ("> ", &self.request_headers),
+            ("< ", &self.response_headers),
+        ] {
+            for (name, value) in headers {
+                write!(f, "\n{prefix}{name}: {}", String::from_utf8_lossy(value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn redacted(headers: &Headers) -> Headers {
+    let mut headers = headers.clone();
+    for name in &REDACTED {
+        if let header::Entry::Occupied(mut entry) = headers.entry(name) {
+            for value in entry.iter_mut() {
+                *value = "[redacted]".into();
+            }
+        }
+    }
+    headers
+}
+
+/// Records every request the wrapped driver handles into a [Capture]
+pub struct CaptureDriver<D> {
+    inner: D,
+    capture: Capture,
+}
+
+impl<D> CaptureDriver<D> {
+    pub fn new(inner: D, capture: Capture) -> Self {
+        Self { inner, capture }
+    }
+
+    pub fn capture(&self) -> &Capture {
+        &self.capture
+    }
+}
+
+impl<D> ServerDriver for CaptureDriver<D>
+where
+    D: ServerDriver,
+{
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut in_flight = InFlight {
+            capture: self.capture.clone(),
+            started: Instant::now(),
+            transaction: Some(Rc::new(RefCell::new(self.capture.start(&req)))),
+        };
+        let transaction = in_flight.transaction.clone().unwrap();
+        let respond = respond.map_encoder(|inner| CaptureEncoder { inner, transaction });
+
+        let res = self.inner.handle(req, req_body, respond).await;
+        in_flight.finish(match &res {
+            Ok(_) => Outcome::Completed,
+            Err(e) => Outcome::Failed(e.to_string()),
+        });
+        res.map(|respond| respond.map_encoder(|encoder| encoder.inner))
+    }
+
+    fn on_headers(&self, req: &Request) -> HeaderDecision {
+        let decision = self.inner.on_headers(req);
+        if let HeaderDecision::Respond { res, .. } = &decision {
+            let mut transaction = self.capture.start(req);
+            transaction.status = Some(res.status);
+            transaction.response_headers = redacted(&res.headers);
+            transaction.outcome = Outcome::AnsweredEarly;
+            self.capture.push(transaction);
+        }
+        decision
+    }
+
+    fn on_header_field(&self, name: &http::HeaderName, value: &[u8]) -> FieldDecision {
+        self.inner.on_header_field(name, value)
+    }
+
+    fn on_h2_connection(&self, frames: h2::ExtensionFrames) {
+        self.inner.on_h2_connection(frames)
+    }
+
+    fn on_extension_frame(&self, frame: h2::ExtensionFrame, frames: &h2::ExtensionFrames) {
+        self.inner.on_extension_frame(frame, frames)
+    }
+
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        self.inner.on_connection_closed(conn, reason)
+    }
+}
+
+/// Pushes the transaction into the buffer when dropped, whether or not the
+/// handler got to finish
+struct InFlight {
+    capture: Capture,
+    started: Instant,
+    transaction: Option<Rc<RefCell<Transaction>>>,
+}
+
+impl InFlight {
+    fn finish(&mut self, outcome: Outcome) {
+        if let Some(transaction) = &self.transaction {
+            transaction.borrow_mut().outcome = outcome;
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let Some(transaction) = self.transaction.take() else {
+            return;
+        };
+        let mut transaction = Rc::try_unwrap(transaction)
+            .map(RefCell::into_inner)
+            .unwrap_or_else(|transaction| transaction.borrow().clone());
+        transaction.duration = self.started.elapsed();
+        self.capture.push(transaction);
+    }
+}
+
+struct CaptureEncoder<E> {
+    inner: E,
+    transaction: Rc<RefCell<Transaction>>,
+}
+
+impl<E> CaptureEncoder<E> {
+    fn count(&self, len: usize) {
+        self.transaction.borrow_mut().response_body_len += len as u64;
+    }
+}
+
+impl<E> Encoder for CaptureEncoder<E>
+where
+    E: Encoder,
+{
+    async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
+        if !res.status.is_informational() {
+            let mut transaction = self.transaction.borrow_mut();
+            transaction.status = Some(res.status);
+            transaction.response_headers = redacted(&res.headers);
+        }
+        self.inner.write_response(res).await
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()> {
+        self.count(chunk.len());
+        self.inner.write_body_chunk(chunk, mode).await
+    }
+
+    async fn write_body_chunks(
+        &mut self,
+        chunks: PieceList,
+        mode: BodyWriteMode,
+    ) -> eyre::Result<()> {
+        self.count(chunks.len());
+        self.inner.write_body_chunks(chunks, mode).await
+    }
+
+    async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()> {
+        self.inner.write_body_end(mode).await
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()> {
+        self.inner.write_trailers(trailers).await
+    }
+
+    async fn reset(&mut self, code: KnownErrorCode) -> eyre::Result<()> {
+        self.inner.reset(code).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, StatusCode};
+
+    use super::Capture;
+    use crate::Request;
+
+    #[test]
+    fn ring() {
+        let capture = Capture::new(2);
+        let mut req = Request::default();
+        req.headers.insert(header::COOKIE, "session=hunter2".into());
+        req.headers.insert(header::ACCEPT, "*/*".into());
+
+        for _ in 0..3 {
+            let mut transaction = capture.start(&req);
+            transaction.status = Some(StatusCode::OK);
+            capture.push(transaction);
+        }
+
+        let recent = capture.recent();
+        assert_eq!(capture.recorded(), 3);
+        assert_eq!(recent.iter().map(|t| t.id).collect::<Vec<_>>(), [2, 3]);
+        assert!(capture.get(1).is_none());
+
+        let headers = &recent[0].request_headers;
+        assert_eq!(&headers[header::COOKIE][..], b"[redacted]");
+        assert_eq!(&headers[header::ACCEPT][..], b"*/*");
+        assert!(!recent[0].to_string().contains("hunter2"));
+
+        capture.clear();
+        assert!(capture.recent().is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+//! Why a connection was closed. Every connection served by
+//! [crate::h1::serve_with] or [crate::h2::serve_with] ends with one
+//! [CloseReason]: it's returned by `h2::serve` (and derived from what
+//! `h1::serve` returns, see [crate::h1::ServeOutcome::close_reason]),
+//! logged, recorded in [crate::ConnInfo::close_reason], and handed to
+//! [crate::ServerDriver::on_connection_closed] so access logs and metrics
+//! can tell a client hanging up from a flood being cut short.
+
+use std::fmt;
+
+use tracing::debug;
+
+use crate::{h1::ServeOutcome, h2::H2ConnectionError, ConnInfo, ServeError, ServerDriver};
+
+/// Why a connection was closed, see the [module docs](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// The client hung up: between requests, before saying anything, or in
+    /// the middle of a frame
+    ClientEof,
+
+    /// The client asked for it, with `connection: close` (or by not asking
+    /// for keep-alive over HTTP/1.0)
+    ClientRequested,
+
+    /// Nothing happened on the connection for too long, see
+    /// [crate::h2::ServerConf::idle_timeout] and
+    /// [crate::ServerBuilder::handshake_timeout]
+    IdleTimeout,
+
+    /// The client stopped acknowledging PINGs, see
+    /// [crate::h2::ServerConf::ping_timeout]
+    PeerUnresponsive,
+
+    /// A request's [crate::Deadline] passed before its response was done
+    RequestTimeout,
+
+    /// The client broke the protocol, or didn't speak it at all
+    ProtocolError { detail: String },
+
+    /// The client made the connection hold more than it's allowed to, see
+    /// [crate::MemoryBudget]
+    FloodMitigation,
+
+    /// The server chose to close the connection after a response: the
+    /// response had `connection: close`, or a request body was turned away
+    ServerRequested,
+
+    /// The server is shutting down and stopped serving the connection.
+    /// Nothing closes connections on shutdown yet (listeners drain them),
+    /// this is for the graceful shutdown to come.
+    ServerShutdown,
+
+    /// Reading from the client failed
+    ReadError,
+
+    /// Writing to the client failed
+    WriteError,
+
+    /// The [crate::ServerDriver] failed or panicked, or left a request body
+    /// unread
+    DriverError,
+
+    /// Something went wrong on the server's side, e.g. running out of buffers
+    Internal { detail: String },
+}
+
+impl CloseReason {
+    /// A short, machine-readable name for the reason, without details:
+    /// suitable as a metrics label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::ClientRequested => "client_requested",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::PeerUnresponsive => "peer_unresponsive",
+            CloseReason::RequestTimeout => "request_timeout",
+            CloseReason::ProtocolError { .. } => "protocol_error",
+            CloseReason::FloodMitigation => "flood_mitigation",
+            CloseReason::ServerRequested => "server_requested",
+            CloseReason::ServerShutdown => "server_shutdown",
+            CloseReason::ReadError => "read_error",
+            CloseReason::WriteError => "write_error",
+            CloseReason::DriverError => "driver_error",
+            CloseReason::Internal { .. } => "internal",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::ProtocolError { detail } | CloseReason::Internal { detail } => {
+                write!(f, "{}: {detail}", self.as_str())
+            }
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+impl ServeOutcome {
+    pub fn close_reason(&self) -> CloseReason {
+        match self {
+            ServeOutcome::ClientRequestedConnectionClose => CloseReason::ClientRequested,
+            ServeOutcome::ServerRequestedConnectionClose => CloseReason::ServerRequested,
+            ServeOutcome::ClientClosedConnectionBetweenRequests => CloseReason::ClientEof,
+            ServeOutcome::ClientDidntSpeakHttp11 => CloseReason::ProtocolError {
+                detail: "client didn't speak HTTP/1.1".into(),
+            },
+            ServeOutcome::UnsupportedMethod => CloseReason::ProtocolError {
+                detail: "unsupported request method".into(),
+            },
+            ServeOutcome::FaultyFraming => CloseReason::ProtocolError {
+                detail: "request body framing is ambiguous".into(),
+            },
+        }
+    }
+}
+
+impl ServeError {
+    pub fn close_reason(&self) -> CloseReason {
+        match self {
+            ServeError::BufferAlloc(e) => CloseReason::Internal {
+                detail: e.to_string(),
+            },
+            ServeError::Read(e) => {
+                if e.root_cause().downcast_ref::<std::io::Error>().is_some() {
+                    CloseReason::ReadError
+                } else {
+                    CloseReason::ProtocolError {
+                        detail: e.to_string(),
+                    }
+                }
+            }
+            ServeError::Write(_) => CloseReason::WriteError,
+            ServeError::Driver(_)
+            | ServeError::DriverPanicked(_)
+            | ServeError::RequestBodyNotDrained => CloseReason::DriverError,
+            ServeError::DeadlineExceeded => CloseReason::RequestTimeout,
+            ServeError::MemoryBudgetExceeded { .. } => CloseReason::FloodMitigation,
+            ServeError::H2Connection { source, .. } => source.close_reason(),
+        }
+    }
+}
+
+impl H2ConnectionError {
+    pub fn close_reason(&self) -> CloseReason {
+        match self {
+            H2ConnectionError::IncompleteFrame { .. } => CloseReason::ClientEof,
+            H2ConnectionError::Idle { .. } => CloseReason::IdleTimeout,
+            H2ConnectionError::PingTimeout { .. } => CloseReason::PeerUnresponsive,
+            H2ConnectionError::HeaderBlockOverBudget { .. } => CloseReason::FloodMitigation,
+            H2ConnectionError::ReadError(_) => CloseReason::ReadError,
+            H2ConnectionError::WriteError(_) => CloseReason::WriteError,
+            H2ConnectionError::Internal(e) => CloseReason::Internal {
+                detail: e.to_string(),
+            },
+            e => CloseReason::ProtocolError {
+                detail: e.to_string(),
+            },
+        }
+    }
+}
+
+/// Records why `conn` is closing, and lets the driver know
+pub(crate) fn connection_closed(driver: &impl ServerDriver, conn: &ConnInfo, reason: CloseReason) {
+    debug!(close_reason = %reason, "connection closed");
+    conn.set_close_reason(reason.clone());
+    driver.on_connection_closed(conn, &reason);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::CloseReason;
+    use crate::{
+        h1::ServeOutcome,
+        h2::{H2ConnectionError, StreamId},
+        ServeError,
+    };
+
+    #[test]
+    fn close_reasons() {
+        assert_eq!(
+            ServeOutcome::ClientClosedConnectionBetweenRequests.close_reason(),
+            CloseReason::ClientEof
+        );
+        assert_eq!(
+            ServeOutcome::ServerRequestedConnectionClose.close_reason(),
+            CloseReason::ServerRequested
+        );
+        assert_eq!(
+            ServeOutcome::UnsupportedMethod.close_reason().as_str(),
+            "protocol_error"
+        );
+        assert_eq!(
+            ServeOutcome::FaultyFraming.close_reason().to_string(),
+            "protocol_error: request body framing is ambiguous"
+        );
+        assert_eq!(
+            ServeError::MemoryBudgetExceeded { used: 2, limit: 1 }.close_reason(),
+            CloseReason::FloodMitigation
+        );
+        assert_eq!(
+            ServeError::from(H2ConnectionError::HeaderBlockOverBudget {
+                stream_id: StreamId(1),
+                limit: 1,
+            })
+            .close_reason(),
+            CloseReason::FloodMitigation
+        );
+        assert_eq!(
+            ServeError::from(H2ConnectionError::Idle {
+                timeout: Duration::from_secs(1)
+            })
+            .close_reason(),
+            CloseReason::IdleTimeout
+        );
+        assert_eq!(
+            ServeError::from(H2ConnectionError::PingTimeout {
+                timeout: Duration::from_secs(1)
+            })
+            .close_reason(),
+            CloseReason::PeerUnresponsive
+        );
+        assert_eq!(
+            ServeError::Write(std::io::ErrorKind::BrokenPipe.into()).close_reason(),
+            CloseReason::WriteError
+        );
+
+        let reason = ServeError::from(H2ConnectionError::ClientSentPushPromise).close_reason();
+        assert_eq!(reason.as_str(), "protocol_error");
+        assert!(reason
+            .to_string()
+            .starts_with("protocol_error: client sent"));
+    }
+}
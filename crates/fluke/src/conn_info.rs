@@ -0,0 +1,208 @@
+//! What the server knows about the connection a request came in on, see
+//! [crate::Request::conn].
+//!
+//! For HTTP/2, the round-trip time is measured with PING frames when
+//! `ping_interval` is set in [crate::h2::ServerConf]: it's useful to size
+//! flow-control windows, or for proxies to pick the closest upstream.
+//!
+//! HTTP/2 connections also report how many streams are open, next to the
+//! limit advertised in `SETTINGS_MAX_CONCURRENT_STREAMS`: handlers can use
+//! that to shed load before the connection starts refusing streams.
+//!
+//! fluke doesn't do TLS itself, but whoever terminated it can pass what the
+//! handshake established along, see [ConnInfo::with_tls]: that's how
+//! handlers get to see client certificates.
+//!
+//! Once the connection is closed, [ConnInfo::close_reason] says why.
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    rc::Rc,
+    time::Duration,
+};
+
+use crate::CloseReason;
+
+/// A handle to a connection's statistics. Cloning it gives another handle
+/// to the same connection, and the values keep updating while it's open.
+#[derive(Clone, Default)]
+pub struct ConnInfo {
+    inner: Rc<ConnInfoInner>,
+}
+
+#[derive(Default)]
+struct ConnInfoInner {
+    tls: Option<TlsInfo>,
+    srtt: Cell<Option<Duration>>,
+    rtt_samples: Cell<u64>,
+    open_streams: Cell<u32>,
+    max_streams: Cell<Option<u32>>,
+    refused_streams: Cell<u64>,
+    close_reason: RefCell<Option<CloseReason>>,
+}
+
+impl fmt::Debug for ConnInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnInfo")
+            .field("tls", &self.tls())
+            .field("rtt", &self.rtt())
+            .field("open_streams", &self.open_streams())
+            .field("max_streams", &self.max_streams())
+            .field("close_reason", &self.close_reason())
+            .finish()
+    }
+}
+
+/// What the TLS handshake established, as reported by whoever terminated
+/// TLS, see [ConnInfo::with_tls]
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    /// The server name the client asked for (SNI)
+    pub server_name: Option<String>,
+
+    /// The protocol negotiated through ALPN, e.g. `h2`
+    pub alpn_protocol: Option<Vec<u8>>,
+
+    /// The certificate chain the client authenticated with, DER-encoded,
+    /// end-entity certificate first. Only ever set once the TLS stack has
+    /// verified it: empty if the client didn't present a certificate.
+    pub peer_certificates: Vec<Vec<u8>>,
+}
+
+impl TlsInfo {
+    /// The client's own certificate, DER-encoded, if it presented one
+    pub fn peer_certificate(&self) -> Option<&[u8]> {
+        self.peer_certificates.first().map(|cert| &cert[..])
+    }
+}
+
+impl ConnInfo {
+    /// For a connection that came in over TLS: pass it to
+    /// [crate::h1::serve_with] or [crate::h2::serve_with]
+    pub fn with_tls(tls: TlsInfo) -> Self {
+        Self {
+            inner: Rc::new(ConnInfoInner {
+                tls: Some(tls),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// What the TLS handshake established, `None` for plaintext
+    /// connections (or if whoever terminated TLS didn't say)
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.inner.tls.as_ref()
+    }
+
+    /// Smoothed round-trip time to the peer, `None` until it's been
+    /// measured at least once
+    pub fn rtt(&self) -> Option<Duration> {
+        self.inner.srtt.get()
+    }
+
+    /// How many round trips were measured so far
+    pub fn rtt_samples(&self) -> u64 {
+        self.inner.rtt_samples.get()
+    }
+
+    /// How many HTTP/2 streams are open on the connection, counting the
+    /// one this request came in on. Always 0 for HTTP/1.1.
+    pub fn open_streams(&self) -> u32 {
+        self.inner.open_streams.get()
+    }
+
+    /// The most streams the peer may open at once, as advertised in our
+    /// settings. `None` for HTTP/1.1, or when there's no limit.
+    pub fn max_streams(&self) -> Option<u32> {
+        self.inner.max_streams.get()
+    }
+
+    /// How many streams were refused with `REFUSED_STREAM` because the
+    /// connection was at [ConnInfo::max_streams]. Clients may retry those
+    /// safely: no handler ever saw them.
+    pub fn refused_streams(&self) -> u64 {
+        self.inner.refused_streams.get()
+    }
+
+    /// Why the connection was closed, `None` while it's still open
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.inner.close_reason.borrow().clone()
+    }
+
+    pub(crate) fn set_open_streams(&self, open_streams: u32) {
+        self.inner.open_streams.set(open_streams);
+    }
+
+    pub(crate) fn set_max_streams(&self, max_streams: Option<u32>) {
+        self.inner.max_streams.set(max_streams);
+    }
+
+    pub(crate) fn record_refused_stream(&self) {
+        self.inner.refused_streams.set(self.refused_streams() + 1);
+    }
+
+    pub(crate) fn set_close_reason(&self, reason: CloseReason) {
+        *self.inner.close_reason.borrow_mut() = Some(reason);
+    }
+
+    /// Folds a new measurement into the estimate, the way TCP does it
+    /// (<https://www.rfc-editor.org/rfc/rfc6298#section-2>)
+    pub(crate) fn record_rtt(&self, sample: Duration) {
+        let srtt = match self.inner.srtt.get() {
+            Some(srtt) => srtt * 7 / 8 + sample / 8,
+            None => sample,
+        };
+        self.inner.srtt.set(Some(srtt));
+        self.inner.rtt_samples.set(self.rtt_samples() + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ConnInfo, TlsInfo};
+
+    #[test]
+    fn smoothed_rtt() {
+        let info = ConnInfo::default();
+        assert_eq!(info.rtt(), None);
+
+        info.record_rtt(Duration::from_millis(80));
+        assert_eq!(info.rtt(), Some(Duration::from_millis(80)));
+
+        // a single outlier only moves the estimate by an eighth
+        info.clone().record_rtt(Duration::from_millis(160));
+        assert_eq!(info.rtt(), Some(Duration::from_millis(90)));
+        assert_eq!(info.rtt_samples(), 2);
+    }
+
+    #[test]
+    fn stream_counts() {
+        let info = ConnInfo::default();
+        assert_eq!((info.open_streams(), info.max_streams()), (0, None));
+
+        let handle = info.clone();
+        handle.set_max_streams(Some(2));
+        handle.set_open_streams(2);
+        handle.record_refused_stream();
+        assert_eq!(info.open_streams(), 2);
+        assert_eq!(info.max_streams(), Some(2));
+        assert_eq!(info.refused_streams(), 1);
+    }
+
+    #[test]
+    fn tls_info() {
+        assert!(ConnInfo::default().tls().is_none());
+
+        let info = ConnInfo::with_tls(TlsInfo {
+            server_name: Some("example.org".into()),
+            peer_certificates: vec![b"leaf".to_vec(), b"intermediate".to_vec()],
+            ..Default::default()
+        });
+        let tls = info.clone().tls().cloned().unwrap();
+        assert_eq!(tls.server_name.as_deref(), Some("example.org"));
+        assert_eq!(tls.peer_certificate(), Some(&b"leaf"[..]));
+    }
+}
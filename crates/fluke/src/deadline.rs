@@ -0,0 +1,112 @@
+//! Per-request deadlines: the server sets one from its configuration (see
+//! `request_timeout` in [crate::h1::ServerConf] and [crate::h2::ServerConf]),
+//! drivers can read it from [crate::Request::deadline] to pass it upstream,
+//! or move it with [crate::Responder::set_deadline].
+//!
+//! When it passes, the handler is dropped: if it hadn't started its response
+//! yet, the client gets a 504, otherwise the response is cut short (the
+//! stream is reset for HTTP/2, the connection is closed for HTTP/1.1).
+
+use std::{
+    cell::Cell,
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+/// When the response to a request must be complete. Cloning it gives
+/// another handle to the same deadline.
+#[derive(Clone, Default)]
+pub struct Deadline {
+    inner: Rc<DeadlineInner>,
+}
+
+#[derive(Default)]
+struct DeadlineInner {
+    at: Cell<Option<Instant>>,
+    changed: Notify,
+}
+
+impl fmt::Debug for Deadline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Deadline").field(&self.get()).finish()
+    }
+}
+
+impl Deadline {
+    /// A deadline `timeout` from now, or no deadline at all
+    pub fn after(timeout: Option<Duration>) -> Self {
+        let deadline = Self::default();
+        deadline.set(timeout.map(|timeout| Instant::now() + timeout));
+        deadline
+    }
+
+    /// The current deadline, if any
+    pub fn get(&self) -> Option<Instant> {
+        self.inner.at.get()
+    }
+
+    /// Time left until the deadline (zero if it already passed), `None` if
+    /// there's no deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.get()
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns true if the deadline has passed
+    pub fn is_expired(&self) -> bool {
+        self.get().is_some_and(|at| at <= Instant::now())
+    }
+
+    /// Moves (or removes) the deadline, for every handle
+    pub fn set(&self, at: Option<Instant>) {
+        self.inner.at.set(at);
+        self.inner.changed.notify_waiters();
+    }
+
+    /// Completes once the deadline has passed, taking into account any
+    /// change made while waiting. Never completes if there's no deadline.
+    pub(crate) async fn expired(&self) {
+        loop {
+            let changed = self.inner.changed.notified();
+            match self.get() {
+                Some(at) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(at.into()) => return,
+                        _ = changed => {}
+                    }
+                }
+                None => changed.await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Deadline;
+
+    #[test]
+    fn moving_the_deadline_wakes_waiters() {
+        fluke_buffet::start(async move {
+            let deadline = Deadline::after(None);
+            assert!(deadline.remaining().is_none());
+
+            let waiter = fluke_buffet::spawn({
+                let deadline = deadline.clone();
+                async move { deadline.expired().await }
+            });
+            tokio::task::yield_now().await;
+
+            let before = Instant::now();
+            deadline.set(Some(Instant::now() + Duration::from_millis(20)));
+            waiter.await.unwrap();
+            assert!(before.elapsed() >= Duration::from_millis(20));
+            assert!(deadline.is_expired());
+        });
+    }
+}
@@ -0,0 +1,98 @@
+use fluke_buffet::bufpool;
+use fluke_h2_parse::KnownErrorCode;
+
+use crate::h2::H2ConnectionError;
+
+/// Returned by [crate::h1::serve] and [crate::h2::serve] when serving a
+/// connection fails.
+///
+/// It implements [std::error::Error], so applications that don't care
+/// about the details can still `?` it into an `eyre::Report`.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ServeError {
+    /// We couldn't get a buffer from the pool
+    #[error("buffer allocation failed: {0}")]
+    BufferAlloc(#[from] bufpool::Error),
+
+    /// Reading from the peer failed, or what we read made no sense
+    #[error("error reading from peer: {0:?}")]
+    Read(eyre::Report),
+
+    /// Writing to the peer failed
+    #[error("error writing to peer: {0}")]
+    Write(#[from] std::io::Error),
+
+    /// The [crate::ServerDriver] returned an error while handling a request
+    #[error("error handling request: {0:?}")]
+    Driver(eyre::Report),
+
+    /// The [crate::ServerDriver] panicked while handling a request. The
+    /// connection is closed, after replying with a 500 if no response had
+    /// been started yet.
+    #[error("driver panicked while handling request: {0}")]
+    DriverPanicked(String),
+
+    /// The driver returned without reading the whole request body, so the
+    /// connection cannot be re-used for another request.
+    #[error("request body not drained, have to close connection")]
+    RequestBodyNotDrained,
+
+    /// The request's [crate::Deadline] passed before the driver was done
+    /// responding. The connection is closed, after replying with a 504 if no
+    /// response had been started yet.
+    #[error("request deadline exceeded")]
+    DeadlineExceeded,
+
+    /// The connection held onto more buffer memory than its budget allows
+    #[error("memory budget exceeded: holding {used} bytes, limit is {limit}")]
+    MemoryBudgetExceeded { used: usize, limit: usize },
+
+    /// An HTTP/2 connection error, along with the error code it maps to
+    #[error("h2 connection error ({code:?}): {source}")]
+    H2Connection {
+        code: KnownErrorCode,
+        source: H2ConnectionError,
+    },
+}
+
+impl From<H2ConnectionError> for ServeError {
+    fn from(source: H2ConnectionError) -> Self {
+        Self::H2Connection {
+            code: source.as_known_error_code(),
+            source,
+        }
+    }
+}
+
+/// Returned by [crate::h1::request] when performing a request fails.
+///
+/// Like [ServeError], it converts into an `eyre::Report` with `?`.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum RequestError {
+    /// We couldn't get a buffer from the pool
+    #[error("buffer allocation failed: {0}")]
+    BufferAlloc(#[from] bufpool::Error),
+
+    /// The request headers could not be encoded
+    #[error("error encoding request: {0:?}")]
+    Encode(eyre::Report),
+
+    /// Writing the request to the server failed
+    #[error("error writing request: {0}")]
+    Write(#[from] std::io::Error),
+
+    /// Reading the response headers from the server failed, or they
+    /// could not be parsed
+    #[error("error reading response headers from server: {0:?}")]
+    ReadResponse(eyre::Report),
+
+    /// The server closed the connection before sending response headers
+    #[error("server went away before sending response headers")]
+    ServerClosedBeforeResponse,
+
+    /// The [crate::h1::ClientDriver] returned an error
+    #[error("error handling response: {0:?}")]
+    Driver(eyre::Report),
+}
@@ -0,0 +1,117 @@
+//! Bodies for the responses the server generates on its own: malformed
+//! requests (400, 414, 431...), handlers that panicked (500) or went over
+//! their deadline (504). Without a page for their status, those go out with
+//! an empty body.
+
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use fluke_buffet::Piece;
+use http::{header, StatusCode};
+
+use crate::Response;
+
+/// A body and its content type
+#[derive(Debug, Clone)]
+pub struct ErrorPage {
+    pub content_type: Cow<'static, str>,
+    pub body: Cow<'static, [u8]>,
+}
+
+/// Error pages keyed by status, see the [module docs](self). Cloning is
+/// cheap: clones share the pages.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    pages: Arc<HashMap<StatusCode, ErrorPage>>,
+}
+
+impl ErrorPages {
+    /// Sets the page for `status`
+    pub fn insert(
+        &mut self,
+        status: StatusCode,
+        content_type: impl Into<Cow<'static, str>>,
+        body: impl Into<Cow<'static, [u8]>>,
+    ) -> &mut Self {
+        let page = ErrorPage {
+            content_type: content_type.into(),
+            body: body.into(),
+        };
+        Arc::make_mut(&mut self.pages).insert(status, page);
+        self
+    }
+
+    /// Sets a page for each of `statuses`, rendered from `template`:
+    /// `{status}` is replaced with the status code (`404`) and `{reason}`
+    /// with its reason phrase (`Not Found`).
+    pub fn insert_template(
+        &mut self,
+        statuses: impl IntoIterator<Item = StatusCode>,
+        content_type: &'static str,
+        template: &str,
+    ) -> &mut Self {
+        for status in statuses {
+            let body = template
+                .replace("{status}", status.as_str())
+                .replace("{reason}", status.canonical_reason().unwrap_or_default());
+            self.insert(status, content_type, body.into_bytes());
+        }
+        self
+    }
+
+    pub fn get(&self, status: StatusCode) -> Option<&ErrorPage> {
+        self.pages.get(&status)
+    }
+
+    /// A response with the page for `status`, if any, or an empty body
+    pub(crate) fn response(&self, status: StatusCode) -> (Response, Piece) {
+        let mut res = Response {
+            status,
+            ..Default::default()
+        };
+        let Some(page) = self.get(status) else {
+            return (res, Piece::empty());
+        };
+
+        let content_type = match &page.content_type {
+            Cow::Borrowed(s) => Piece::from(*s),
+            Cow::Owned(s) => Piece::from(s.clone().into_bytes()),
+        };
+        res.headers.insert(header::CONTENT_TYPE, content_type);
+        let body = match &page.body {
+            Cow::Borrowed(b) => Piece::from(*b),
+            Cow::Owned(b) => Piece::from(b.clone()),
+        };
+        (res, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, StatusCode};
+
+    use super::ErrorPages;
+
+    #[test]
+    fn templates() {
+        let mut pages = ErrorPages::default();
+        pages
+            .insert_template(
+                [StatusCode::BAD_REQUEST, StatusCode::GATEWAY_TIMEOUT],
+                "text/html",
+                "<h1>{status} {reason}</h1>",
+            )
+            .insert(StatusCode::BAD_REQUEST, "text/plain", &b"bad"[..]);
+
+        let (res, body) = pages.response(StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(res.status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(&res.headers[header::CONTENT_TYPE][..], b"text/html");
+        assert_eq!(&body[..], b"<h1>504 Gateway Timeout</h1>");
+
+        let (_, body) = pages.response(StatusCode::BAD_REQUEST);
+        assert_eq!(&body[..], b"bad");
+
+        let (res, body) = pages.response(StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(res.headers.is_empty());
+        assert!(body.is_empty());
+    }
+}
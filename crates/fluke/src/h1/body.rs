@@ -1,15 +1,26 @@
-use std::fmt;
+use std::{collections::VecDeque, fmt};
 
 use tracing::debug;
 
-use crate::{util::read_and_parse, Body, BodyChunk, BodyErrorReason};
-use fluke_buffet::{Piece, PieceList, ReadOwned, RollMut, WriteOwned};
+use super::LineEndings;
+use crate::{loans::Loans, util::read_and_parse, Body, BodyChunk, BodyError, BodyErrorReason};
+use fluke_buffet::{chunk_size_line, Piece, PieceList, ReadOwned, Roll, RollMut, WriteOwned};
 
 /// An HTTP/1.1 body, either chunked or content-length.
 pub(crate) struct H1Body<T> {
     transport_r: T,
     buf: Option<RollMut>,
     state: Decoder,
+
+    /// Whether chunked framing may use bare LFs, see
+    /// [super::LineEndings::Lenient]
+    lenient: bool,
+
+    /// see [Body::bytes_read]
+    read: u64,
+
+    /// Chunks go through it on their way to the driver
+    loans: Loans,
 }
 
 #[derive(Debug)]
@@ -31,11 +42,20 @@ enum ChunkedDecoder {
 struct ContentLengthDecoder {
     len: u64,
     read: u64,
+
+    /// Body bytes read past the connection buffer by a vectored read, see
+    /// [MAX_READV_BUFFERS]
+    pending: VecDeque<Roll>,
 }
 
+/// How many extra pool buffers a large content-length body may be read
+/// into at once, on top of the connection buffer: with 4KiB buffers, that's
+/// up to 68KiB per read instead of 4KiB.
+const MAX_READV_BUFFERS: usize = 16;
+
 #[derive(Debug)]
 pub(crate) enum H1BodyKind {
-    Chunked,
+    Chunked(LineEndings),
     ContentLength(u64),
 }
 
@@ -48,17 +68,28 @@ impl<T> fmt::Debug for H1Body<T> {
 }
 
 impl<T: ReadOwned> H1Body<T> {
-    pub(crate) fn new(transport_r: T, buf: RollMut, kind: H1BodyKind) -> Self {
-        let state = match kind {
-            H1BodyKind::Chunked => Decoder::Chunked(ChunkedDecoder::ReadingChunkHeader),
-            H1BodyKind::ContentLength(len) => {
-                Decoder::ContentLength(ContentLengthDecoder { len, read: 0 })
-            }
+    pub(crate) fn new(transport_r: T, buf: RollMut, kind: H1BodyKind, loans: Loans) -> Self {
+        let (state, lenient) = match kind {
+            H1BodyKind::Chunked(line_endings) => (
+                Decoder::Chunked(ChunkedDecoder::ReadingChunkHeader),
+                line_endings == LineEndings::Lenient,
+            ),
+            H1BodyKind::ContentLength(len) => (
+                Decoder::ContentLength(ContentLengthDecoder {
+                    len,
+                    read: 0,
+                    pending: Default::default(),
+                }),
+                false,
+            ),
         };
         H1Body {
             transport_r,
             buf: Some(buf),
             state,
+            lenient,
+            read: 0,
+            loans,
         }
     }
 
@@ -71,6 +102,11 @@ impl<T: ReadOwned> H1Body<T> {
         let buf = self.buf?;
         Some((buf, self.transport_r))
     }
+
+    /// Returns the transport, whether or not the body has been fully read
+    pub(crate) fn into_transport(self) -> T {
+        self.transport_r
+    }
 }
 
 impl<T: ReadOwned> Body for H1Body<T> {
@@ -86,12 +122,29 @@ impl<T: ReadOwned> Body for H1Body<T> {
             return Ok(BodyChunk::Done { trailers: None });
         }
 
-        match &mut self.state {
-            Decoder::Chunked(state) => state.next_chunk(&mut self.buf, &mut self.transport_r).await,
+        let chunk = match &mut self.state {
+            Decoder::Chunked(state) => {
+                state
+                    .next_chunk(&mut self.buf, &mut self.transport_r, self.lenient)
+                    .await?
+            }
             Decoder::ContentLength(state) => {
-                state.next_chunk(&mut self.buf, &mut self.transport_r).await
+                state
+                    .next_chunk(&mut self.buf, &mut self.transport_r, &self.loans)
+                    .await?
             }
-        }
+        };
+        Ok(match chunk {
+            BodyChunk::Chunk(piece) => {
+                self.read += piece.len() as u64;
+                BodyChunk::Chunk(self.loans.lend(piece))
+            }
+            done => done,
+        })
+    }
+
+    fn bytes_read(&self) -> Option<u64> {
+        Some(self.read)
     }
 
     fn eof(&self) -> bool {
@@ -107,7 +160,13 @@ impl ContentLengthDecoder {
         &mut self,
         buf_slot: &mut Option<RollMut>,
         transport: &mut impl ReadOwned,
+        loans: &Loans,
     ) -> eyre::Result<BodyChunk> {
+        if let Some(chunk) = self.pending.pop_front() {
+            self.read += chunk.len() as u64;
+            return Ok(BodyChunk::Chunk(chunk.into()));
+        }
+
         let remain = self.len - self.read;
         if remain == 0 {
             return Ok(BodyChunk::Done { trailers: None });
@@ -120,10 +179,28 @@ impl ContentLengthDecoder {
             .ok_or_else(|| BodyErrorReason::CalledNextChunkAfterError.as_err())?;
 
         if buf.is_empty() {
-            buf.reserve()?;
+            // no waiting for the driver to drop chunks here: it's the one
+            // reading, past the loan limit it gets copies.
+            buf.reserve_wait().await?;
 
             let res;
-            (res, buf) = buf.read_into(usize::MAX, transport).await;
+            if remain > buf.cap() as u64 {
+                // more than fits: read straight into extra buffers, up to
+                // the end of the body so that they hold nothing else. Those
+                // are lent out too, so there's no reading ahead past the
+                // loan limit.
+                let rolls;
+                (res, buf, rolls) = buf
+                    .readv_into(
+                        usize::try_from(remain).unwrap_or(usize::MAX),
+                        MAX_READV_BUFFERS.min(loans.room()),
+                        transport,
+                    )
+                    .await;
+                self.pending.extend(rolls);
+            } else {
+                (res, buf) = buf.read_into(usize::MAX, transport).await;
+            }
             res.map_err(|e| BodyErrorReason::ErrorWhileReadingChunkData.with_cx(e))?;
         }
 
@@ -145,7 +222,11 @@ impl ChunkedDecoder {
         &mut self,
         buf_slot: &mut Option<RollMut>,
         transport: &mut impl ReadOwned,
+        lenient: bool,
     ) -> eyre::Result<BodyChunk> {
+        let chunk_size = move |i| super::parse::chunk_size(i, lenient);
+        let line_end = move |i| super::parse::line_end(i, lenient);
+
         loop {
             let mut buf = buf_slot
                 .take()
@@ -159,16 +240,15 @@ impl ChunkedDecoder {
             }
 
             if let ChunkedDecoder::ReadingChunkHeader = self {
-                let (next_buf, chunk_size) =
-                    read_and_parse(super::parse::chunk_size, transport, buf, 16)
-                        .await
-                        .map_err(|e| BodyErrorReason::InvalidChunkSize.with_cx(e))?
-                        .ok_or_else(|| BodyErrorReason::ClosedWhileReadingChunkSize.as_err())?;
+                let (next_buf, chunk_size) = read_and_parse(chunk_size, transport, buf, 16)
+                    .await
+                    .map_err(|e| BodyErrorReason::InvalidChunkSize.with_cx(e))?
+                    .ok_or_else(|| BodyErrorReason::ClosedWhileReadingChunkSize.as_err())?;
                 buf = next_buf;
 
                 if chunk_size == 0 {
                     // that's the final chunk, look for the final CRLF
-                    let (next_buf, _) = read_and_parse(super::parse::crlf, transport, buf, 2)
+                    let (next_buf, _) = read_and_parse(line_end, transport, buf, 2)
                         .await
                         .map_err(|e| BodyErrorReason::InvalidChunkTerminator.with_cx(e))?
                         .ok_or_else(|| {
@@ -188,7 +268,7 @@ impl ChunkedDecoder {
             if let ChunkedDecoder::ReadingChunk { remain } = self {
                 if *remain == 0 {
                     // look for CRLF terminator
-                    let (next_buf, _) = read_and_parse(super::parse::crlf, transport, buf, 2)
+                    let (next_buf, _) = read_and_parse(line_end, transport, buf, 2)
                         .await
                         .map_err(|e| BodyErrorReason::InvalidChunkTerminator.with_cx(e))?
                         .ok_or_else(|| {
@@ -201,7 +281,7 @@ impl ChunkedDecoder {
                 }
 
                 if buf.is_empty() {
-                    buf.reserve()?;
+                    buf.reserve_wait().await?;
 
                     let res;
                     (res, buf) = buf.read_into(*remain as usize, transport).await;
@@ -243,17 +323,76 @@ pub enum BodyWriteMode {
     Empty,
 }
 
+/// Keeps track of how much of an announced content-length was written, so
+/// that a body that's too long or too short is caught instead of
+/// desynchronizing (or silently truncating) the stream.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ContentLengthTracker {
+    announced: Option<u64>,
+    written: u64,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)] // only read through `Debug`
+struct ContentLengthMismatch {
+    announced: u64,
+    written: u64,
+}
+
+impl ContentLengthTracker {
+    pub(crate) fn new(announced: Option<u64>) -> Self {
+        Self {
+            announced,
+            written: 0,
+        }
+    }
+
+    /// Accounts for a chunk that's about to be written, errors out if it
+    /// would go past the announced length.
+    pub(crate) fn on_chunk(&mut self, len: usize) -> Result<(), BodyError> {
+        let written = self.written + len as u64;
+        if let Some(announced) = self.announced {
+            if written > announced {
+                return Err(BodyErrorReason::WroteMoreThanContentLength
+                    .with_cx(ContentLengthMismatch { announced, written }));
+            }
+        }
+        self.written = written;
+        Ok(())
+    }
+
+    /// Errors out if less than the announced length was written
+    pub(crate) fn on_end(&self) -> Result<(), BodyError> {
+        match self.announced {
+            Some(announced) if self.written < announced => Err(
+                BodyErrorReason::WroteLessThanContentLength.with_cx(ContentLengthMismatch {
+                    announced,
+                    written: self.written,
+                }),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
 pub(crate) async fn write_h1_body(
     transport: &mut impl WriteOwned,
     body: &mut impl Body,
     mode: BodyWriteMode,
 ) -> eyre::Result<()> {
+    let mut tracker = ContentLengthTracker::new(match mode {
+        BodyWriteMode::ContentLength => body.content_len(),
+        _ => None,
+    });
+
     loop {
         match body.next_chunk().await? {
-            BodyChunk::Chunk(chunk) => write_h1_body_chunk(transport, chunk, mode).await?,
+            BodyChunk::Chunk(chunk) => {
+                tracker.on_chunk(chunk.len())?;
+                write_h1_body_chunk(transport, chunk, mode).await?
+            }
             BodyChunk::Done { .. } => {
-                // TODO: check that we've sent what we announced in terms of
-                // content length
+                tracker.on_end()?;
                 write_h1_body_end(transport, mode).await?;
                 break;
             }
@@ -273,7 +412,7 @@ pub(crate) async fn write_h1_body_chunk(
             transport
                 .writev_all_owned(
                     PieceList::default()
-                        .followed_by(format!("{:x}\r\n", chunk.len()).into_bytes())
+                        .followed_by(chunk_size_line(chunk.len())?)
                         .followed_by(chunk)
                         .followed_by("\r\n"),
                 )
@@ -291,6 +430,32 @@ pub(crate) async fn write_h1_body_chunk(
     Ok(())
 }
 
+/// Writes several chunks at once: for chunked transfer-encoding, they're
+/// framed as a single chunk.
+pub(crate) async fn write_h1_body_chunks(
+    transport: &mut impl WriteOwned,
+    mut chunks: PieceList,
+    mode: BodyWriteMode,
+) -> eyre::Result<()> {
+    match mode {
+        BodyWriteMode::Chunked => {
+            let len = chunks.len();
+            chunks.push_front(chunk_size_line(len)?);
+            chunks.push_back("\r\n");
+            transport.writev_all_owned(chunks).await?;
+        }
+        BodyWriteMode::ContentLength => {
+            transport.writev_all_owned(chunks).await?;
+        }
+        BodyWriteMode::Empty => {
+            return Err(BodyErrorReason::CalledWriteBodyChunkWhenNoBodyWasExpected
+                .as_err()
+                .into());
+        }
+    }
+    Ok(())
+}
+
 pub(crate) async fn write_h1_body_end(
     transport: &mut impl WriteOwned,
     mode: BodyWriteMode,
@@ -1,18 +1,42 @@
-use eyre::Context;
-use http::header;
+use std::time::Duration;
+
+use http::{header, StatusCode};
+use tokio::sync::oneshot;
 use tracing::debug;
 
-use crate::{types::Request, util::read_and_parse, Body, HeadersExt, Response};
+use crate::{
+    types::Request, util::read_and_parse, Body, HeaderCaseMap, HeadersExt, RequestError, Response,
+};
 use fluke_buffet::{
-    PieceList, RollMut, {ReadOwned, WriteOwned},
+    PieceChain, RollMut, {ReadOwned, WriteOwned},
 };
 
 use super::{
     body::{write_h1_body, BodyWriteMode, H1Body, H1BodyKind},
     encode::encode_request,
+    LineEndings,
 };
 
-pub struct ClientConf {}
+#[derive(Clone, Default)]
+pub struct ClientConf {
+    /// How to spell header names in requests, see [HeaderCaseMap]. `None`
+    /// writes them lowercase.
+    pub header_case: Option<HeaderCaseMap>,
+
+    /// When set, requests with a body are sent with `expect: 100-continue`,
+    /// and their body is held back until the server answers with a `100
+    /// Continue`, or until this much time has passed without an answer
+    /// (some servers never send one). A server that rejects the request
+    /// early, with a 4xx or 5xx, saves sending the body at all: that's
+    /// worth it for large uploads. `None` sends bodies right away, unless
+    /// the request already carries `expect: 100-continue`, which then waits
+    /// for [DEFAULT_EXPECT_CONTINUE_TIMEOUT].
+    pub expect_continue: Option<Duration>,
+}
+
+/// How long to wait for a `100 Continue` when the request asked for one,
+/// and [ClientConf::expect_continue] isn't set
+pub const DEFAULT_EXPECT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[allow(async_fn_in_trait)] // we never require Send
 pub trait ClientDriver {
@@ -31,11 +55,27 @@ pub trait ClientDriver {
 /// The transport halves will be returned unless the server requested connection
 /// close or the request body wasn't fully drained
 pub async fn request<R, W, D>(
+    transport: (R, W),
+    req: Request,
+    body: &mut impl Body,
+    driver: D,
+) -> Result<(Option<(R, W)>, D::Return), RequestError>
+where
+    R: ReadOwned,
+    W: WriteOwned,
+    D: ClientDriver,
+{
+    request_with(transport, req, body, driver, &Default::default()).await
+}
+
+/// Like [request], with a [ClientConf]
+pub async fn request_with<R, W, D>(
     (mut transport_r, mut transport_w): (R, W),
     mut req: Request,
     body: &mut impl Body,
-    driver: D,
-) -> eyre::Result<(Option<(R, W)>, D::Return)>
+    mut driver: D,
+    conf: &ClientConf,
+) -> Result<(Option<(R, W)>, D::Return), RequestError>
 where
     R: ReadOwned,
     W: WriteOwned,
@@ -53,19 +93,51 @@ where
         None => BodyWriteMode::Chunked,
     };
 
-    let mut buf = RollMut::alloc()?;
+    // RFC 9110, section 10.1.1: no `100-continue` expectation without
+    // content to send
+    let continue_timeout = match mode {
+        BodyWriteMode::Empty => {
+            req.headers.remove(header::EXPECT);
+            None
+        }
+        _ if req.headers.expects_100_continue() => Some(
+            conf.expect_continue
+                .unwrap_or(DEFAULT_EXPECT_CONTINUE_TIMEOUT),
+        ),
+        _ => conf.expect_continue.map(|timeout| {
+            req.headers.insert(header::EXPECT, "100-continue".into());
+            timeout
+        }),
+    };
+    let (continue_tx, continue_rx) = match continue_timeout {
+        Some(timeout) => {
+            let (tx, rx) = oneshot::channel();
+            (Some(tx), Some((timeout, rx)))
+        }
+        None => (None, None),
+    };
 
-    let mut list = PieceList::default();
-    encode_request(req, &mut list, &mut buf)?;
-    transport_w
-        .writev_all_owned(list)
-        .await
-        .wrap_err("writing request headers")?;
+    let buf = RollMut::alloc()?;
 
-    // TODO: handle `expect: 100-continue` (don't start sending body until we get a 100 response)
+    let mut head = PieceChain::default();
+    encode_request(req, conf.header_case.as_ref(), &mut head).map_err(RequestError::Encode)?;
+    transport_w.writev_all_owned(head.into()).await?;
 
     let send_body_fut = {
         async move {
+            if let Some((timeout, continue_rx)) = continue_rx {
+                match tokio::time::timeout(timeout, continue_rx).await {
+                    Ok(Ok(BodyGo::Send)) => debug!("server wants the request body"),
+                    Err(_) => debug!("no 100 Continue in {timeout:?}, sending request body anyway"),
+                    // the server rejected the request, or we failed to read
+                    // its response: the body stays unsent
+                    Ok(Ok(BodyGo::Abort)) | Ok(Err(_)) => {
+                        debug!("not sending request body");
+                        return Ok((transport_w, false));
+                    }
+                }
+            }
+
             match write_h1_body(&mut transport_w, body, mode).await {
                 Err(err) => {
                     // TODO: find way to report this error to the driver without
@@ -74,7 +146,7 @@ where
                 }
                 Ok(_) => {
                     debug!("done writing request body");
-                    Ok::<_, eyre::Report>(transport_w)
+                    Ok::<_, RequestError>((transport_w, true))
                 }
             }
         }
@@ -82,21 +154,48 @@ where
 
     let recv_res_fut = {
         async move {
-            let (buf, res) = read_and_parse(
-                super::parse::response,
-                &mut transport_r,
-                buf,
-                // TODO: make this configurable
-                64 * 1024,
-            )
-            .await
-            .map_err(|e| eyre::eyre!("error reading response headers from server: {e:?}"))?
-            .ok_or_else(|| eyre::eyre!("server went away before sending response headers"))?;
-            debug!("client received response");
-            res.debug_print();
-
-            if res.status.is_informational() {
-                todo!("handle informational responses");
+            let mut continue_tx = continue_tx;
+            let mut buf = buf;
+            let res = loop {
+                let res;
+                (buf, res) = read_and_parse(
+                    super::parse::response,
+                    &mut transport_r,
+                    buf,
+                    // TODO: make this configurable
+                    64 * 1024,
+                )
+                .await
+                .map_err(RequestError::ReadResponse)?
+                .ok_or(RequestError::ServerClosedBeforeResponse)?;
+                debug!("client received response");
+                res.debug_print();
+
+                // TODO: handle `101 Switching Protocols`
+                if !res.status.is_informational() || res.status == StatusCode::SWITCHING_PROTOCOLS {
+                    break res;
+                }
+
+                if res.status == StatusCode::CONTINUE {
+                    if let Some(tx) = continue_tx.take() {
+                        _ = tx.send(BodyGo::Send);
+                    }
+                }
+                driver
+                    .on_informational_response(res)
+                    .await
+                    .map_err(RequestError::Driver)?;
+            };
+
+            // a final response that comes before the `100 Continue` tells
+            // whether the server wants the body after all
+            if let Some(tx) = continue_tx.take() {
+                let go = if res.status.is_client_error() || res.status.is_server_error() {
+                    BodyGo::Abort
+                } else {
+                    BodyGo::Send
+                };
+                _ = tx.send(go);
             }
 
             let chunked = res.headers.is_chunked_transfer_encoding();
@@ -110,15 +209,20 @@ where
                 if chunked {
                     // TODO: even with chunked transfer-encoding, we can announce
                     // a content length - we should probably detect errors there?
-                    H1BodyKind::Chunked
+                    H1BodyKind::Chunked(LineEndings::Strict)
                 } else {
                     H1BodyKind::ContentLength(content_len)
                 },
+                // the caller reads the body: nothing to bound
+                Default::default(),
             );
 
             let conn_close = res.headers.is_connection_close();
 
-            let ret = driver.on_final_response(res, &mut res_body).await?;
+            let ret = driver
+                .on_final_response(res, &mut res_body)
+                .await
+                .map_err(RequestError::Driver)?;
 
             let transport_r = match (conn_close, res_body.into_inner()) {
                 // can only re-use the body if conn_close is false and the body was fully draided
@@ -132,9 +236,20 @@ where
 
     // TODO: cancel sending the body if we get a response early?
     let (send_res, recv_res) = tokio::try_join!(send_body_fut, recv_res_fut)?;
-    let transport_w = send_res;
+    let (transport_w, body_sent) = send_res;
     let (transport_r, ret) = recv_res;
 
-    let transport = transport_r.map(|transport_r| (transport_r, transport_w));
+    // the server can't tell where the next request starts if the body it
+    // turned down is missing
+    let transport = transport_r
+        .filter(|_| body_sent)
+        .map(|transport_r| (transport_r, transport_w));
     Ok((transport, ret))
 }
+
+/// Whether to send the request body, for requests that expect a `100
+/// Continue`
+enum BodyGo {
+    Send,
+    Abort,
+}
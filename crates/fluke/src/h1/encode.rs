@@ -1,76 +1,133 @@
-use std::io::Write;
+use std::{cell::Cell, fmt::Write};
 
 use eyre::Context;
-use http::{StatusCode, Version};
+use http::{header, StatusCode, Version};
 
 use crate::{
-    types::{Headers, Request, Response},
-    Encoder,
+    types::{HeaderCaseMap, Headers, Request, Response},
+    BodyErrorReason, Encoder, HeadersExt,
 };
-use fluke_buffet::{Piece, PieceList, RollMut, WriteOwned};
+use fluke_buffet::{Piece, PieceChain, PieceList, WriteOwned};
 
-use super::body::{write_h1_body_chunk, write_h1_body_end, BodyWriteMode};
+use super::body::{
+    write_h1_body_chunk, write_h1_body_chunks, write_h1_body_end, BodyWriteMode,
+    ContentLengthTracker,
+};
 
 pub(crate) fn encode_request(
-    req: Request,
-    list: &mut PieceList,
-    out_scratch: &mut RollMut,
+    mut req: Request,
+    header_case: Option<&HeaderCaseMap>,
+    head: &mut PieceChain,
 ) -> eyre::Result<()> {
-    list.push_back(req.method.into_chunk());
-    list.push_back(" ");
+    // requests coming from an h2 client have an absolute URI and no `host`
+    // header, cf. RFC 9113, section 8.3.1
+    let target = req.target()?;
+    if !req.headers.contains_key(header::HOST) {
+        if let Some(host) = req.host() {
+            req.headers.insert(header::HOST, host.into_bytes().into());
+        }
+    }
 
-    assert_eq!(out_scratch.len(), 0);
-    out_scratch.write_all(req.uri.path().as_bytes())?;
-    list.push_back(out_scratch.take_all());
+    head.push(req.method.into_chunk());
+    head.push(" ");
+    write!(head, "{target}")?;
 
     match req.version {
-        Version::HTTP_10 => list.push_back(" HTTP/1.0\r\n"),
-        Version::HTTP_11 => list.push_back(" HTTP/1.1\r\n"),
+        Version::HTTP_10 => head.push(" HTTP/1.0\r\n"),
+        Version::HTTP_11 => head.push(" HTTP/1.1\r\n"),
         _ => return Err(eyre::eyre!("unsupported HTTP version {:?}", req.version)),
     }
 
-    // TODO: if `host` isn't set, set from request uri? which should
-    // take precedence here?
-    encode_headers(req.headers, list)?;
-    list.push_back("\r\n");
+    encode_headers(req.headers, header_case, head)?;
+    head.push("\r\n");
     Ok(())
 }
 
-fn encode_response(res: Response, list: &mut PieceList) -> eyre::Result<()> {
-    match res.version {
-        Version::HTTP_10 => list.push_back(&b"HTTP/1.0 "[..]),
-        Version::HTTP_11 => list.push_back(&b"HTTP/1.1 "[..]),
-        _ => return Err(eyre::eyre!("unsupported HTTP version {:?}", res.version)),
+/// `connection` is a pre-serialized `connection` header line, which takes
+/// the place of any `connection` header in `res`.
+fn encode_response(
+    mut res: Response,
+    connection: Option<&'static str>,
+    header_case: Option<&HeaderCaseMap>,
+    head: &mut PieceChain,
+) -> eyre::Result<()> {
+    if let Some(line) = common_status_line(res.version, res.status) {
+        head.push(line);
+    } else {
+        match res.version {
+            Version::HTTP_10 => head.push(&b"HTTP/1.0 "[..]),
+            Version::HTTP_11 => head.push(&b"HTTP/1.1 "[..]),
+            _ => return Err(eyre::eyre!("unsupported HTTP version {:?}", res.version)),
+        }
+
+        head.push(encode_status_code(res.status));
+        head.push(" ");
+        head.push(res.status.canonical_reason().unwrap_or("Unknown"));
+        head.push("\r\n");
     }
 
-    list.push_back(encode_status_code(res.status));
-    list.push_back(" ");
-    list.push_back(res.status.canonical_reason().unwrap_or("Unknown"));
-    list.push_back("\r\n");
-    encode_headers(res.headers, list)?;
-    list.push_back("\r\n");
+    if let Some(connection) = connection {
+        res.headers.remove(header::CONNECTION);
+        match header_case {
+            Some(header_case) => {
+                // respelled, whatever the line spelled it like
+                let (name, rest) = connection.split_at(connection.find(':').unwrap_or(0));
+                debug_assert!(name.eq_ignore_ascii_case(header::CONNECTION.as_str()));
+                head.push_copy(header_case.spell(&header::CONNECTION).as_bytes());
+                head.push(rest);
+            }
+            None => head.push(connection),
+        }
+    }
+    encode_headers(res.headers, header_case, head)?;
+    head.push("\r\n");
     Ok(())
 }
 
-pub(crate) fn encode_headers(headers: Headers, list: &mut PieceList) -> eyre::Result<()> {
+const CONNECTION_CLOSE: &str = "connection: close\r\n";
+const CONNECTION_KEEP_ALIVE: &str = "connection: keep-alive\r\n";
+
+/// The whole status line for the statuses most responses have, as a
+/// single static piece
+fn common_status_line(version: Version, status: StatusCode) -> Option<&'static str> {
+    Some(match (version, status.as_u16()) {
+        (Version::HTTP_11, 200) => "HTTP/1.1 200 OK\r\n",
+        (Version::HTTP_11, 204) => "HTTP/1.1 204 No Content\r\n",
+        (Version::HTTP_11, 301) => "HTTP/1.1 301 Moved Permanently\r\n",
+        (Version::HTTP_11, 304) => "HTTP/1.1 304 Not Modified\r\n",
+        (Version::HTTP_11, 404) => "HTTP/1.1 404 Not Found\r\n",
+        (Version::HTTP_11, 500) => "HTTP/1.1 500 Internal Server Error\r\n",
+        (Version::HTTP_10, 200) => "HTTP/1.0 200 OK\r\n",
+        (Version::HTTP_10, 404) => "HTTP/1.0 404 Not Found\r\n",
+        _ => return None,
+    })
+}
+
+/// Names are written lowercase, unless `header_case` says otherwise
+pub(crate) fn encode_headers(
+    headers: Headers,
+    header_case: Option<&HeaderCaseMap>,
+    head: &mut PieceChain,
+) -> eyre::Result<()> {
     let mut last_header_name = None;
     for (name, value) in headers {
-        match name {
+        let name = match name {
             Some(name) => {
                 last_header_name = Some(name.clone());
-                list.push_back(name);
-            }
-            None => {
-                let name = match last_header_name {
-                    Some(ref name) => name.clone(),
-                    None => unreachable!("HeaderMap's IntoIter violated its contract"),
-                };
-                list.push_back(name);
+                name
             }
+            None => match last_header_name {
+                Some(ref name) => name.clone(),
+                None => unreachable!("HeaderMap's IntoIter violated its contract"),
+            },
+        };
+        match header_case {
+            Some(header_case) => head.push_copy(header_case.spell(&name).as_bytes()),
+            None => head.push(name),
         }
-        list.push_back(": ");
-        list.push_back(value);
-        list.push_back("\r\n");
+        head.push(": ");
+        head.push(value);
+        head.push("\r\n");
     }
 
     Ok(())
@@ -143,23 +200,79 @@ const CODE_DIGITS: &str = "\
 960961962963964965966967968969970971972973974975976977978979\
 980981982983984985986987988989990991992993994995996997998999";
 
-pub struct H1Encoder<T>
+pub struct H1Encoder<'a, T>
 where
     T: WriteOwned,
 {
-    pub(crate) transport_w: T,
+    pub(crate) transport_w: &'a mut T,
+
+    /// Set once a final (non-1xx) response head has been written: past that
+    /// point, the server can no longer reply with a 500 of its own.
+    pub(crate) response_started: &'a Cell<bool>,
+
+    /// Checks the body against the content-length of the final response
+    pub(crate) content_length: ContentLengthTracker,
+
+    /// Whether we're responding to a HEAD request
+    pub(crate) head_request: bool,
+
+    /// Set when the final response can't have a body, see
+    /// [Response::forbids_body]
+    pub(crate) body_forbidden: bool,
+
+    /// Whether we're responding to an HTTP/1.0 request: those don't know
+    /// about chunked transfer-encoding.
+    pub(crate) http10: bool,
+
+    /// Whether the client is fine with reusing the connection after this
+    /// response
+    pub(crate) keep_alive: bool,
+
+    /// Set when the connection must be closed after this response, either
+    /// because one of the peers asked for it, or because the body is
+    /// delimited by the end of the connection.
+    pub(crate) must_close: &'a Cell<bool>,
+
+    /// Set when the response body is written as-is, and ends when the
+    /// connection is closed (HTTP/1.0 responses of unknown length)
+    pub(crate) close_delimited: bool,
+
+    /// see [super::ServerConf::header_case]
+    pub(crate) header_case: Option<&'a HeaderCaseMap>,
 }
 
-impl<T> Encoder for H1Encoder<T>
+impl<T> Encoder for H1Encoder<'_, T>
 where
     T: WriteOwned,
 {
-    async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
-        let mut list = PieceList::default();
-        encode_response(res, &mut list)?;
+    async fn write_response(&mut self, mut res: Response) -> eyre::Result<()> {
+        res.check_bodyless(self.head_request)?;
+        let mut connection = None;
+        if !res.status.is_informational() {
+            self.response_started.set(true);
+            self.content_length = ContentLengthTracker::new(res.headers.content_length());
+            self.body_forbidden = res.forbids_body(self.head_request);
+
+            if self.http10 && res.headers.is_chunked_transfer_encoding() {
+                res.headers.remove(header::TRANSFER_ENCODING);
+                self.close_delimited = true;
+            }
+
+            let must_close =
+                !self.keep_alive || self.close_delimited || res.headers.is_connection_close();
+            self.must_close.set(must_close);
+            if must_close {
+                connection = Some(CONNECTION_CLOSE);
+            } else if self.http10 {
+                connection = Some(CONNECTION_KEEP_ALIVE);
+            }
+        }
+
+        let mut head = PieceChain::default();
+        encode_response(res, connection, self.header_case, &mut head)?;
 
         self.transport_w
-            .writev_all_owned(list)
+            .writev_all_owned(head.into())
             .await
             .wrap_err("writing response headers upstream")?;
 
@@ -168,25 +281,163 @@ where
 
     // TODO: move `mode` into `H1Encoder`? we don't need it for h2
     async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()> {
+        if self.body_forbidden {
+            return Err(BodyErrorReason::CalledWriteBodyChunkWhenNoBodyWasExpected
+                .as_err()
+                .into());
+        }
+        if mode == BodyWriteMode::ContentLength {
+            // going over would desync the connection, so don't write anything
+            self.content_length.on_chunk(chunk.len())?;
+        }
+        if self.close_delimited {
+            return Ok(self.transport_w.write_all_owned(chunk).await?);
+        }
         // TODO: inline
-        write_h1_body_chunk(&mut self.transport_w, chunk, mode).await
+        write_h1_body_chunk(self.transport_w, chunk, mode).await
+    }
+
+    async fn write_body_chunks(
+        &mut self,
+        chunks: PieceList,
+        mode: BodyWriteMode,
+    ) -> eyre::Result<()> {
+        if self.body_forbidden {
+            return Err(BodyErrorReason::CalledWriteBodyChunkWhenNoBodyWasExpected
+                .as_err()
+                .into());
+        }
+        if mode == BodyWriteMode::ContentLength {
+            self.content_length.on_chunk(chunks.len())?;
+        }
+        if self.close_delimited {
+            return Ok(self.transport_w.writev_all_owned(chunks).await?);
+        }
+        write_h1_body_chunks(self.transport_w, chunks, mode).await
     }
 
     async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()> {
+        if self.body_forbidden || self.close_delimited {
+            // not even the last chunk of a chunked body: closing the
+            // connection is what ends close-delimited ones
+            return Ok(());
+        }
+        if mode == BodyWriteMode::ContentLength {
+            self.content_length.on_end()?;
+        }
         // TODO: inline
-        write_h1_body_end(&mut self.transport_w, mode).await
+        write_h1_body_end(self.transport_w, mode).await
     }
 
     async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()> {
-        // TODO: check all preconditions
-        let mut list = PieceList::default();
-        encode_headers(*trailers, &mut list)?;
+        if self.body_forbidden {
+            return Err(BodyErrorReason::CalledWriteBodyChunkWhenNoBodyWasExpected
+                .as_err()
+                .into());
+        }
+        if self.close_delimited {
+            // HTTP/1.0 has nowhere to put them, and recipients are free to
+            // discard trailers anyway
+            return Ok(());
+        }
+
+        // the last chunk, then the trailer section
+        let mut head = PieceChain::default();
+        head.push("0\r\n");
+        encode_headers(*trailers, self.header_case, &mut head)?;
+        head.push("\r\n");
 
         self.transport_w
-            .writev_all_owned(list)
+            .writev_all_owned(head.into())
             .await
-            .wrap_err("writing response headers upstream")?;
+            .wrap_err("writing response trailers upstream")?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http::{header, StatusCode, Version};
+
+    use super::{common_status_line, encode_response, CONNECTION_CLOSE};
+    use crate::{HeaderCaseMap, Response};
+
+    fn encode(res: Response, connection: Option<&'static str>) -> String {
+        encode_cased(res, connection, None)
+    }
+
+    fn encode_cased(
+        res: Response,
+        connection: Option<&'static str>,
+        header_case: Option<&HeaderCaseMap>,
+    ) -> String {
+        let mut head = fluke_buffet::PieceChain::default();
+        encode_response(res, connection, header_case, &mut head).unwrap();
+        let bytes: Vec<u8> = head
+            .into_list()
+            .into_vec_deque()
+            .iter()
+            .flat_map(|p| p.to_vec())
+            .collect();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn common_status_lines_match_the_slow_path() {
+        for version in [Version::HTTP_10, Version::HTTP_11] {
+            for code in 100..600 {
+                let status = StatusCode::from_u16(code).unwrap();
+                let Some(line) = common_status_line(version, status) else {
+                    continue;
+                };
+                let expected = format!(
+                    "{version:?} {code} {}\r\n",
+                    status.canonical_reason().unwrap()
+                );
+                assert_eq!(line, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn connection_header_is_replaced() {
+        let mut res = Response {
+            status: StatusCode::IM_A_TEAPOT,
+            ..Default::default()
+        };
+        res.headers.insert(header::CONNECTION, "keep-alive".into());
+        res.headers.insert(header::SERVER, "fluke".into());
+        assert_eq!(
+            encode(res, Some(CONNECTION_CLOSE)),
+            "HTTP/1.1 418 I'm a teapot\r\nconnection: close\r\nserver: fluke\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn header_case() {
+        let mut res = Response::default();
+        res.headers.insert(header::SERVER, "fluke".into());
+        res.headers
+            .append(header::HeaderName::from_static("x-api-key"), "1".into());
+        res.headers
+            .append(header::HeaderName::from_static("x-api-key"), "2".into());
+
+        let mut header_case = HeaderCaseMap::title_case();
+        header_case.insert("X-API-Key").unwrap();
+        assert_eq!(
+            encode_cased(res, Some(CONNECTION_CLOSE), Some(&header_case)),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nServer: fluke\r\nX-API-Key: 1\r\nX-API-Key: 2\r\n\r\n"
+        );
+
+        // however the pre-serialized line spells it
+        assert_eq!(
+            encode_cased(
+                Response::default(),
+                Some("CONNECTION: keep-alive\r\n"),
+                Some(&header_case)
+            ),
+            "HTTP/1.1 200 OK\r\nConnection: keep-alive\r\n\r\n"
+        );
+    }
+}
@@ -7,34 +7,82 @@ use http::{header::HeaderName, StatusCode, Version};
 use nom::{
     bytes::streaming::{tag, take, take_until, take_while1},
     combinator::{map_res, opt},
+    error::ErrorKind,
     sequence::{preceded, terminated},
     IResult,
 };
 
+use tracing::debug;
+
 use crate::{
     types::{Headers, Request, Response},
-    Method,
+    util::SemanticError,
+    DuplicateHeaderPolicy, Method,
 };
+
+use super::{LineEndings, ServerConf};
 use fluke_buffet::{PieceStr, Roll, RollStr};
 
 const CRLF: &[u8] = b"\r\n";
 
-/// Parses a chunked transfer coding chunk size (hex text followed by CRLF)
-pub fn chunk_size(i: Roll) -> IResult<Roll, u64> {
-    terminated(u64_text_hex, tag(CRLF))(i)
+/// Parses a chunked transfer coding chunk size (hex text followed by a line
+/// terminator, see [line_end])
+pub fn chunk_size(i: Roll, lenient: bool) -> IResult<Roll, u64> {
+    terminated(u64_text_hex, |i| line_end(i, lenient))(i)
 }
 
-pub fn crlf(i: Roll) -> IResult<Roll, ()> {
-    let (i, _) = tag(CRLF)(i)?;
-    Ok((i, ()))
+/// Parses a line terminator: CRLF, or also a bare LF if `lenient`, as RFC
+/// 9112 section 2.2 allows recipients to
+pub fn line_end(i: Roll, lenient: bool) -> IResult<Roll, ()> {
+    if let (i, Some(_)) = opt(tag(CRLF))(i.clone())? {
+        return Ok((i, ()));
+    }
+    if lenient {
+        if let (i, Some(_)) = opt(tag(&b"\n"[..]))(i.clone())? {
+            debug!("accepted a bare LF line terminator");
+            return Ok((i, ()));
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(i, ErrorKind::CrLf)))
+}
+
+/// Takes a line, without its terminator (see [line_end]). Lines with a CR
+/// anywhere else are rejected: RFC 9112 section 2.2 doesn't allow bare CRs.
+fn line(i: Roll, lenient: bool) -> IResult<Roll, Roll> {
+    let Some(lf) = memchr::memchr(b'\n', &i[..]) else {
+        return Err(nom::Err::Incomplete(nom::Needed::Unknown));
+    };
+    let (line, rest) = i.clone().split_at(lf);
+    let (_, rest) = rest.split_at(1);
+    let line = if line.last() == Some(&b'\r') {
+        let len = line.len();
+        line.split_at(len - 1).0
+    } else if lenient {
+        debug!("accepted a bare LF line terminator");
+        line
+    } else {
+        return Err(nom::Err::Error(nom::error::Error::new(i, ErrorKind::CrLf)));
+    };
+    if memchr::memchr(b'\r', &line[..]).is_some() {
+        return Err(nom::Err::Error(nom::error::Error::new(i, ErrorKind::CrLf)));
+    }
+    Ok((rest, line))
 }
 
-// Looks like `GET /path HTTP/1.1\r\n`, then headers
-pub fn request(i: Roll) -> IResult<Roll, Request> {
+// Looks like `GET /path HTTP/1.1\r\n`, then headers. Fails as soon as the
+// request goes over one of the limits in `conf`, without waiting for the rest
+// of it: see [rejection] for why.
+pub fn request(i: Roll, conf: &ServerConf) -> IResult<Roll, Request> {
     let (i, method) = terminated(method, space1)(i)?;
-    let (i, path) = terminated(path, space1)(i)?;
-    let (i, version) = terminated(http_version, tag(CRLF))(i)?;
-    let (i, headers) = headers_and_crlf(i)?;
+    let (i, path) = terminated(|i| path(i, conf.max_uri_len), space1)(i)?;
+    let lenient = conf.line_endings == LineEndings::Lenient;
+    let (i, version) = terminated(http_version, |i| line_end(i, lenient))(i)?;
+    let (i, headers) = limited_headers_and_crlf(
+        i,
+        conf.max_header_records,
+        Some(conf.duplicate_headers),
+        lenient,
+    )?;
 
     let request = Request {
         method,
@@ -43,6 +91,10 @@ pub fn request(i: Roll) -> IResult<Roll, Request> {
         uri: path.parse().unwrap(),
         version,
         headers,
+        deadline: Default::default(),
+        stream_id: None,
+        conn: Default::default(),
+        timings: Default::default(),
     };
     Ok((i, request))
 }
@@ -62,7 +114,7 @@ pub fn token(i: Roll) -> IResult<Roll, RollStr> {
 }
 
 /// cf. https://httpwg.org/specs/rfc9110.html#rule.token.separators
-fn is_tchar(c: u8) -> bool {
+pub(crate) fn is_tchar(c: u8) -> bool {
     c.is_ascii_graphic() && !is_delimiter(c)
 }
 
@@ -71,10 +123,17 @@ fn is_delimiter(c: u8) -> bool {
     memchr::memchr(c, br#"(),/:;<=>?@[\]{}""#).is_some()
 }
 
-fn path(i: Roll) -> IResult<Roll, RollStr> {
-    let (i, path) = take_while1(is_uri_char)(i)?;
+fn path(i: Roll, max_len: usize) -> IResult<Roll, RollStr> {
+    let too_large = |i| nom::Err::Failure(nom::error::Error::new(i, ErrorKind::TooLarge));
+    let (rest, path) = match take_while1(is_uri_char)(i.clone()) {
+        Err(nom::Err::Incomplete(_)) if i.len() > max_len => return Err(too_large(i)),
+        res => res?,
+    };
+    if path.len() > max_len {
+        return Err(too_large(i));
+    }
     let path = unsafe { path.to_string_unchecked() };
-    Ok((i, path))
+    Ok((rest, path))
 }
 
 /// Returns true if `c` is a character that can be found in an URI
@@ -136,26 +195,78 @@ pub fn http_version(i: Roll) -> IResult<Roll, Version> {
     Ok((i, version))
 }
 
-pub fn headers_and_crlf(mut i: Roll) -> IResult<Roll, Headers> {
+pub fn headers_and_crlf(i: Roll) -> IResult<Roll, Headers> {
+    limited_headers_and_crlf(i, usize::MAX, None, false)
+}
+
+// Fails with `ErrorKind::Count` past `max_records` headers, and with
+// `ErrorKind::Verify` (pointing at the offending header) on duplicates that
+// `duplicates` doesn't allow.
+fn limited_headers_and_crlf(
+    mut i: Roll,
+    max_records: usize,
+    duplicates: Option<DuplicateHeaderPolicy>,
+    lenient: bool,
+) -> IResult<Roll, Headers> {
     let mut headers = Headers::default();
+    let mut records = 0;
     loop {
-        if let (i, Some(_)) = opt(tag(CRLF))(i.clone())? {
+        if let (i, Some(_)) = opt(|i| line_end(i, lenient))(i.clone())? {
             // end of headers
             return Ok((i, headers));
         }
 
-        let (i_next, (name, value)) = header(i)?;
-        headers.append(name, value.into());
+        if records == max_records {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                i,
+                ErrorKind::Count,
+            )));
+        }
+        records += 1;
+
+        let (i_next, (name, value)) = header(i.clone(), lenient)?;
+        let singleton = DuplicateHeaderPolicy::SINGLETONS.contains(&name);
+        match (duplicates, headers.get(&name)) {
+            (Some(policy), Some(prev)) if singleton => {
+                let conflicting = name == http::header::CONTENT_LENGTH && prev[..] != value[..];
+                if conflicting || policy == DuplicateHeaderPolicy::Reject {
+                    return Err(nom::Err::Failure(nom::error::Error::new(
+                        i,
+                        ErrorKind::Verify,
+                    )));
+                }
+                if policy == DuplicateHeaderPolicy::LastWins {
+                    headers.insert(name, value.into());
+                }
+            }
+            _ => {
+                headers.append(name, value.into());
+            }
+        }
         i = i_next;
     }
 }
 
+/// Why the request parser gave up on a request, for the failures it bails
+/// out with early
+pub(crate) fn rejection(e: &nom::error::Error<Roll>) -> Option<SemanticError> {
+    match e.code {
+        ErrorKind::TooLarge => Some(SemanticError::UriTooLong),
+        ErrorKind::Count => Some(SemanticError::TooManyHeaders),
+        ErrorKind::Verify => {
+            let (_, (name, _)) = header(e.input.clone(), true).ok()?;
+            Some(SemanticError::DuplicateHeader(name))
+        }
+        _ => None,
+    }
+}
+
 /// Parse a single header line
-fn header(i: Roll) -> IResult<Roll, (HeaderName, Roll)> {
+fn header(i: Roll, lenient: bool) -> IResult<Roll, (HeaderName, Roll)> {
     let (i, name) = map_res(take_until_and_consume(b":"), |s: Roll| {
         HeaderName::from_bytes(&s[..])
     })(i)?;
-    let (i, value) = preceded(space1, take_until_and_consume(CRLF))(i)?;
+    let (i, value) = preceded(space1, |i| line(i, lenient))(i)?;
 
     Ok((i, (name, value)))
 }
@@ -171,9 +282,64 @@ fn take_until_and_consume(needle: &[u8]) -> impl FnMut(Roll) -> IResult<Roll, Ro
     terminated(take_until(needle), tag(needle))
 }
 
+/// Where the next request may start after a malformed request head, see
+/// [ServerConf::max_recovered_errors]: right after the blank line that ends
+/// it, with or without CRs. `None` if there's no telling, or if the head
+/// mentions a body: its framing is unknown, so what follows could be a
+/// smuggled request.
+pub(crate) fn resync(input: &[u8]) -> Option<usize> {
+    let contains = |haystack: &[u8], needle: &[u8]| {
+        haystack
+            .windows(needle.len())
+            .any(|w| w.eq_ignore_ascii_case(needle))
+    };
+
+    // the first empty line: a head ending in bare LFs mustn't swallow the
+    // request after it
+    let end = input
+        .iter()
+        .enumerate()
+        .filter(|&(_, &b)| b == b'\n')
+        .find_map(|(i, _)| match &input[i + 1..] {
+            [b'\n', ..] => Some(i + 2),
+            [b'\r', b'\n', ..] => Some(i + 3),
+            _ => None,
+        })?;
+    let head = &input[..end];
+    if contains(head, b"content-length") || contains(head, b"transfer-encoding") {
+        return None;
+    }
+    // if anything follows, it must at least start like a request line
+    match input[end..].first() {
+        Some(b) if !b.is_ascii_uppercase() => None,
+        _ => Some(end),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::h1::parse::is_delimiter;
+    use fluke_buffet::{Roll, RollMut};
+    use http::header;
+
+    use crate::{
+        h1::{
+            parse::{chunk_size, is_delimiter, rejection, request, resync},
+            LineEndings, ServerConf,
+        },
+        util::SemanticError,
+        DuplicateHeaderPolicy, Request,
+    };
+
+    fn parse(conf: &ServerConf, head: &str) -> Result<Request, Option<SemanticError>> {
+        let mut buf = RollMut::alloc().unwrap();
+        buf.put(head).unwrap();
+        let i: Roll = buf.filled();
+        match request(i, conf) {
+            Ok((_, req)) => Ok(req),
+            Err(nom::Err::Failure(e)) => Err(rejection(&e)),
+            Err(_) => Err(None),
+        }
+    }
 
     #[test]
     fn test_h1_parse_various_lowlevel_functions() {
@@ -182,4 +348,118 @@ mod tests {
         assert!(is_delimiter(b'\\'));
         assert!(!is_delimiter(b'B'));
     }
+
+    #[test]
+    fn header_limits() {
+        let conf = ServerConf {
+            max_header_records: 2,
+            ..Default::default()
+        };
+        assert!(parse(&conf, "GET / HTTP/1.1\r\na: 1\r\nb: 2\r\n\r\n").is_ok());
+        assert!(matches!(
+            parse(&conf, "GET / HTTP/1.1\r\na: 1\r\nb: 2\r\nc: 3\r\n"),
+            Err(Some(SemanticError::TooManyHeaders))
+        ));
+
+        let head = "GET / HTTP/1.1\r\nhost: a\r\nx: 1\r\nx: 2\r\nhost: b\r\n\r\n";
+        let host = |policy| {
+            let conf = ServerConf {
+                duplicate_headers: policy,
+                ..Default::default()
+            };
+            parse(&conf, head).map(|req| {
+                assert_eq!(req.headers.get_all("x").iter().count(), 2);
+                let hosts = req.headers.get_all(header::HOST);
+                assert_eq!(hosts.iter().count(), 1);
+                hosts.iter().next().unwrap().to_vec()
+            })
+        };
+        assert!(matches!(
+            host(DuplicateHeaderPolicy::Reject),
+            Err(Some(SemanticError::DuplicateHeader(name))) if name == header::HOST
+        ));
+        assert_eq!(host(DuplicateHeaderPolicy::FirstWins).unwrap(), b"a");
+        assert_eq!(host(DuplicateHeaderPolicy::LastWins).unwrap(), b"b");
+
+        let lenient = ServerConf {
+            duplicate_headers: DuplicateHeaderPolicy::FirstWins,
+            ..Default::default()
+        };
+        let same = "POST / HTTP/1.1\r\ncontent-length: 3\r\ncontent-length: 3\r\n\r\n";
+        assert!(parse(&lenient, same).is_ok());
+        let different = "POST / HTTP/1.1\r\ncontent-length: 3\r\ncontent-length: 4\r\n\r\n";
+        assert!(matches!(
+            parse(&lenient, different),
+            Err(Some(SemanticError::DuplicateHeader(_)))
+        ));
+    }
+
+    #[test]
+    fn resync_after_malformed_head() {
+        let head = b"GET /a b c HTTP/1.1\r\nhost: a\r\n\r\n";
+        assert_eq!(resync(head), Some(head.len()));
+        assert_eq!(
+            resync(&[&head[..], b"GET / HTTP/1.1\r\n"].concat()),
+            Some(head.len())
+        );
+
+        // bare LFs end the head just as well
+        let bare = b"GET /a b c HTTP/1.1\nhost: a\n\n";
+        assert_eq!(
+            resync(&[&bare[..], b"GET / HTTP/1.1\r\n\r\n"].concat()),
+            Some(bare.len())
+        );
+        let mixed = b"GET /a b c HTTP/1.1\r\n\n";
+        assert_eq!(resync(mixed), Some(mixed.len()));
+
+        // no end in sight, or no request after it
+        assert_eq!(resync(b"GET /a b c HTTP/1.1\r\nhost: a\r\n"), None);
+        assert_eq!(resync(&[&head[..], b"\x16\x03\x01"].concat()), None);
+
+        // the body can't be told apart from the next request
+        assert_eq!(
+            resync(b"GET /a b c HTTP/1.1\r\nContent-Length: 5\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            resync(b"POST /a b HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn line_endings() {
+        let strict = ServerConf::default();
+        let lenient = ServerConf {
+            line_endings: LineEndings::Lenient,
+            ..Default::default()
+        };
+
+        let bare = "GET / HTTP/1.1\nhost: a\r\nx: 1\n\n";
+        assert!(matches!(parse(&strict, bare), Err(None)));
+        let req = parse(&lenient, bare).unwrap();
+        assert_eq!(&req.headers[header::HOST][..], b"a");
+        assert_eq!(&req.headers["x"][..], b"1");
+
+        // a bare LF used to end up in the header value
+        let folded = "GET / HTTP/1.1\r\nx: 1\nhost: a\r\n\r\n";
+        assert!(matches!(parse(&strict, folded), Err(None)));
+        assert_eq!(parse(&lenient, folded).unwrap().headers.len(), 2);
+
+        // never fine
+        let bare_cr = "GET / HTTP/1.1\r\nx: 1\rhost: a\r\n\r\n";
+        assert!(matches!(parse(&strict, bare_cr), Err(None)));
+        assert!(matches!(parse(&lenient, bare_cr), Err(None)));
+
+        let size = |input: &str, lenient| {
+            let mut buf = RollMut::alloc().unwrap();
+            buf.put(input).unwrap();
+            chunk_size(buf.filled(), lenient).map(|(_, size)| size).ok()
+        };
+        assert_eq!(size("1a\r\n", false), Some(26));
+        assert_eq!(size("1a\n", false), None);
+        assert_eq!(size("1a\n", true), Some(26));
+        // the LF isn't there yet
+        assert_eq!(size("1a\r", true), None);
+    }
 }
@@ -1,17 +1,30 @@
-use std::rc::Rc;
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    panic::AssertUnwindSafe,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
-use eyre::Context;
-use tracing::debug;
+use futures_util::FutureExt;
+use http::{header, StatusCode, Version};
+use tracing::{debug, error, warn};
 
 use crate::{
+    body::once,
     h1::body::{H1Body, H1BodyKind},
-    util::{read_and_parse, SemanticError},
-    HeadersExt, Responder, ServerDriver,
+    loans::Loans,
+    types::{asterisk_decision, filter_fields},
+    util::{panic_message, read_and_parse_timed, ParseFailure, SemanticError},
+    Body, ConnInfo, Deadline, DuplicateHeaderPolicy, ErrorPages, HeaderCaseMap, HeaderDecision,
+    HeadersExt, LoanLimit, MemoryBudget, Method, MethodPolicy, RequestTimings, Responder,
+    ServeError, ServerDriver,
 };
-use fluke_buffet::{ReadOwned, RollMut, WriteOwned};
+use fluke_buffet::{PieceList, ReadOwned, RollMut, WriteOwned};
 
 use super::encode::H1Encoder;
 
+#[derive(Clone)]
 pub struct ServerConf {
     /// Max length of the request line + HTTP headers
     pub max_http_header_len: usize,
@@ -19,8 +32,82 @@ pub struct ServerConf {
     /// Max length of a single header record, e.g. `user-agent: foobar`
     pub max_header_record_len: usize,
 
-    /// Max number of header records
+    /// Max length of the request target, e.g. `/path?query`. Longer ones
+    /// get a 414 response as soon as they go over it.
+    pub max_uri_len: usize,
+
+    /// Max number of header records. Requests with more get a 431 response
+    /// as soon as they go over it.
     pub max_header_records: usize,
+
+    /// What to do with duplicate `host`, `content-length` and
+    /// `authorization` headers, see [DuplicateHeaderPolicy]
+    pub duplicate_headers: DuplicateHeaderPolicy,
+
+    /// How large a connection's read buffer may stay between requests. It
+    /// grows to fit large request heads, and goes back to a regular-sized
+    /// buffer from the pool afterwards if it's bigger than this. Bytes of
+    /// pipelined requests are kept either way.
+    pub max_retained_read_buf: usize,
+
+    /// Max number of bytes a single connection may hold in buffers. Going
+    /// over it closes the connection. `None` means no limit.
+    pub memory_budget: Option<usize>,
+
+    /// How many read buffer blocks the driver may keep alive by holding on
+    /// to request body chunks, see [LoanLimit]. `None` means no limit.
+    pub loan_limit: Option<LoanLimit>,
+
+    /// After replying with an error and before closing the connection, how
+    /// long to keep reading (and discarding) what the client still sends.
+    /// See [lingering_close]. `None` closes right away.
+    pub lingering_close_timeout: Option<Duration>,
+
+    /// How long the driver has to respond to each request, counting from
+    /// when its headers were read. See [Deadline]. `None` means no limit.
+    pub request_timeout: Option<Duration>,
+
+    /// Which request methods are accepted, see [MethodPolicy]
+    pub methods: MethodPolicy,
+
+    /// Bodies for the error responses the server sends on its own, see
+    /// [ErrorPages]
+    pub error_pages: ErrorPages,
+
+    /// The `allow` header the server answers `OPTIONS *` requests with, e.g.
+    /// `GET, HEAD, OPTIONS`, without involving the driver. `None` passes them
+    /// on, see [crate::RequestTarget::Asterisk]. Either way, a `*` target
+    /// with any other method gets a 400.
+    pub options_allow: Option<Cow<'static, str>>,
+
+    /// How many malformed request heads a connection may recover from. Each
+    /// gets a 400, then parsing resumes after the blank line that ends it,
+    /// if what follows looks like a request. Heads that mention
+    /// `content-length` or `transfer-encoding` always close the connection:
+    /// there's no telling where their body ends. `0` closes the connection
+    /// on the first malformed head.
+    pub max_recovered_errors: usize,
+
+    /// Which line terminators the request head and chunked framing may use,
+    /// see [LineEndings]
+    pub line_endings: LineEndings,
+
+    /// How to spell header names in responses, see [HeaderCaseMap]. `None`
+    /// writes them lowercase.
+    pub header_case: Option<HeaderCaseMap>,
+}
+
+/// Which line terminators the request parser accepts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineEndings {
+    /// CRLF only: a request that uses a bare LF is malformed
+    #[default]
+    Strict,
+
+    /// CRLF or a bare LF, as RFC 9112 section 2.2 allows recipients to (some
+    /// hand-written clients send them). Bare LFs that get accepted are
+    /// logged. A bare CR is malformed either way.
+    Lenient,
 }
 
 impl Default for ServerConf {
@@ -28,7 +115,20 @@ impl Default for ServerConf {
         Self {
             max_http_header_len: 64 * 1024,
             max_header_record_len: 4 * 1024,
+            max_uri_len: 8 * 1024,
             max_header_records: 128,
+            duplicate_headers: Default::default(),
+            max_retained_read_buf: 16 * 1024,
+            memory_budget: Some(1024 * 1024),
+            loan_limit: Some(Default::default()),
+            lingering_close_timeout: Some(Duration::from_secs(2)),
+            request_timeout: None,
+            methods: Default::default(),
+            error_pages: Default::default(),
+            options_allow: None,
+            max_recovered_errors: 0,
+            line_endings: Default::default(),
+            header_case: None,
         }
     }
 }
@@ -40,21 +140,69 @@ pub enum ServeOutcome {
     ClientClosedConnectionBetweenRequests,
     // TODO: return buffer there so we can see what they did write?
     ClientDidntSpeakHttp11,
+
+    /// The request method was malformed, or not one the server accepts (see
+    /// [crate::MethodPolicy]): the client got a 400 or a 501
+    UnsupportedMethod,
+
+    /// There was no telling where the request body ended, e.g. an HTTP/1.0
+    /// request with a `transfer-encoding` header (RFC 9112, section 6.1):
+    /// the client got a 400
+    FaultyFraming,
 }
 
 pub async fn serve(
+    transport: (impl ReadOwned, impl WriteOwned),
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: impl ServerDriver,
+) -> Result<ServeOutcome, ServeError> {
+    serve_with(transport, conf, client_buf, driver, Default::default()).await
+}
+
+/// Like [serve], for a connection the caller knows more about, e.g. what
+/// its TLS handshake established: see [ConnInfo::with_tls]. Requests get a
+/// handle to `conn`, which also records why the connection was closed, see
+/// [crate::CloseReason].
+pub async fn serve_with(
+    transport: (impl ReadOwned, impl WriteOwned),
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: impl ServerDriver,
+    conn: ConnInfo,
+) -> Result<ServeOutcome, ServeError> {
+    let res = serve_requests(transport, conf, client_buf, &driver, &conn).await;
+    let reason = match &res {
+        Ok(outcome) => outcome.close_reason(),
+        Err(e) => e.close_reason(),
+    };
+    crate::close::connection_closed(&driver, &conn, reason);
+    res
+}
+
+async fn serve_requests(
     (mut transport_r, mut transport_w): (impl ReadOwned, impl WriteOwned),
     conf: Rc<ServerConf>,
     mut client_buf: RollMut,
-    driver: impl ServerDriver,
-) -> eyre::Result<ServeOutcome> {
+    driver: &impl ServerDriver,
+    conn: &ConnInfo,
+) -> Result<ServeOutcome, ServeError> {
+    let budget = MemoryBudget::new(conf.memory_budget);
+    let opened = Instant::now();
+    let mut accepted = Some(opened);
+    let mut read_buf_charge = budget.charge(client_buf.storage_size());
+    let loans = Loans::new(conf.loan_limit);
+    let mut recovered = 0;
+
     loop {
-        let req;
-        (client_buf, req) = match read_and_parse(
-            super::parse::request,
+        let mut req;
+        let mut head_started = None;
+        (client_buf, req) = match read_and_parse_timed(
+            |i| super::parse::request(i, &conf),
             &mut transport_r,
             client_buf,
             conf.max_http_header_len,
+            &mut head_started,
         )
         .await
         {
@@ -65,51 +213,318 @@ pub async fn serve(
                     return Ok(ServeOutcome::ClientClosedConnectionBetweenRequests);
                 }
             },
-            Err(e) => {
-                if let Some(se) = e.downcast_ref::<SemanticError>() {
-                    transport_w
-                        .write_all_owned(se.as_http_response())
-                        .await
-                        .wrap_err("writing error response downstream")?;
+            Err(ParseFailure { err, buf }) => {
+                if let Some(se) = err.downcast_ref::<SemanticError>() {
+                    write_error_response(
+                        &mut transport_w,
+                        &conf.error_pages,
+                        se.status(),
+                        false,
+                        true,
+                    )
+                    .await?;
+                    lingering_close(
+                        &mut transport_r,
+                        &mut transport_w,
+                        conf.lingering_close_timeout,
+                    )
+                    .await;
+                } else if let Some(mut buf) = buf.filter(|_| recovered < conf.max_recovered_errors)
+                {
+                    if let Some(skip) = super::parse::resync(&buf[..]) {
+                        recovered += 1;
+                        debug!(?err, %recovered, "malformed request head, resuming after it");
+                        buf.skip(skip);
+                        write_error_response(
+                            &mut transport_w,
+                            &conf.error_pages,
+                            StatusCode::BAD_REQUEST,
+                            false,
+                            false,
+                        )
+                        .await?;
+                        client_buf = buf;
+                        continue;
+                    }
                 }
 
-                debug!(?e, "error reading request header from downstream");
+                debug!(?err, "error reading request header from downstream");
                 return Ok(ServeOutcome::ClientDidntSpeakHttp11);
             }
         };
         debug!("got request {req:?}");
+        let deadline = Deadline::after(conf.request_timeout);
+        req.deadline = deadline.clone();
+        req.conn = conn.clone();
+        req.timings = RequestTimings {
+            accepted: accepted.take(),
+            head_started,
+            head_read: Some(Instant::now()),
+        };
+
+        // the read buffer may have been reallocated to fit the request head
+        read_buf_charge.set(client_buf.storage_size());
+        if let Some(limit) = budget.limit().filter(|_| budget.is_exceeded()) {
+            let summary = budget.close_summary("memory_budget", opened.elapsed());
+            warn!(%summary, "closing connection");
+            return Err(ServeError::MemoryBudgetExceeded {
+                used: budget.used(),
+                limit,
+            });
+        }
 
         let chunked = req.headers.is_chunked_transfer_encoding();
-        let connection_close = req.headers.is_connection_close();
+        let http10 = req.version == Version::HTTP_10;
+        let checked = if http10 && req.headers.contains_key(header::TRANSFER_ENCODING) {
+            Err((
+                SemanticError::TransferEncodingInHttp10,
+                ServeOutcome::FaultyFraming,
+            ))
+        } else {
+            conf.methods
+                .apply(req.method)
+                .map_err(|se| (se, ServeOutcome::UnsupportedMethod))
+        };
+        req.method = match checked {
+            Ok(method) => method,
+            Err((se, outcome)) => {
+                debug!("{se}");
+                write_error_response(
+                    &mut transport_w,
+                    &conf.error_pages,
+                    se.status(),
+                    req.method == Method::Head,
+                    true,
+                )
+                .await?;
+                lingering_close(
+                    &mut transport_r,
+                    &mut transport_w,
+                    conf.lingering_close_timeout,
+                )
+                .await;
+                return Ok(outcome);
+            }
+        };
+
+        // HTTP/1.0 connections are closed after each response, unless the
+        // client asks otherwise
+        let keep_alive = if http10 {
+            req.headers.is_connection_keep_alive()
+        } else {
+            !req.headers.is_connection_close()
+        };
         let content_len = req.headers.content_length().unwrap_or_default();
 
+        let decision = match filter_fields(driver, &mut req.headers) {
+            Some(status) => HeaderDecision::reject(status),
+            None => asterisk_decision(&req, conf.options_allow.as_deref())
+                .unwrap_or_else(|| driver.on_headers(&req)),
+        };
+        let answered_early = matches!(decision, HeaderDecision::Respond { .. });
+        // an unread body would be taken for the next request
+        let keep_alive = keep_alive && !(answered_early && (chunked || content_len > 0));
+
         let mut req_body = H1Body::new(
             transport_r,
             client_buf,
             if chunked {
-                H1BodyKind::Chunked
+                H1BodyKind::Chunked(conf.line_endings)
             } else {
                 H1BodyKind::ContentLength(content_len)
             },
+            loans.clone(),
         );
 
-        let responder = Responder::new(H1Encoder { transport_w });
-
-        let resp = driver
-            .handle(req, &mut req_body, responder)
-            .await
-            .wrap_err("handling request")?;
+        // kept around so we can tell which request it was if the driver panics
+        let (method, uri) = (req.method.clone(), req.uri.clone());
+        let head_request = method == Method::Head;
+        let response_started = Cell::new(false);
+        let must_close = Cell::new(!keep_alive);
+        let responder = Responder::new(H1Encoder {
+            transport_w: &mut transport_w,
+            response_started: &response_started,
+            content_length: Default::default(),
+            head_request,
+            body_forbidden: false,
+            http10,
+            keep_alive,
+            must_close: &must_close,
+            close_delimited: false,
+            header_case: conf.header_case.as_ref(),
+        })
+        .with_deadline(deadline.clone())
+        .with_head_request(head_request);
 
-        // TODO: if we sent `connection: close` we should close now
-        transport_w = resp.into_inner().transport_w;
+        // a panicking driver shouldn't take down the whole runtime: the
+        // encoder only borrows `transport_w`, so we can still reply with a 500
+        // (if nothing was written yet) before closing the connection. Same
+        // goes for a driver that's too slow, with a 504.
+        let handled = tokio::select! {
+            res = AssertUnwindSafe(async {
+                match decision {
+                    HeaderDecision::Continue => driver.handle(req, &mut req_body, responder).await,
+                    HeaderDecision::Respond { res, body } => {
+                        debug!(%method, %uri, status = %res.status, "answered request before reading its body");
+                        responder.write_final_response_with_body(res, &mut once(body)).await
+                    }
+                }
+            })
+            .catch_unwind() => Some(res),
+            _ = deadline.expired() => None,
+        };
+        match handled {
+            None => {
+                debug!(%method, %uri, "request deadline exceeded, dropped handler");
+                if !response_started.get() {
+                    write_error_response(
+                        &mut transport_w,
+                        &conf.error_pages,
+                        StatusCode::GATEWAY_TIMEOUT,
+                        head_request,
+                        true,
+                    )
+                    .await?;
+                }
+                lingering_close(
+                    &mut req_body.into_transport(),
+                    &mut transport_w,
+                    conf.lingering_close_timeout,
+                )
+                .await;
+                let summary = budget.close_summary("request_timeout", opened.elapsed());
+                warn!(%summary, "closing connection");
+                return Err(ServeError::DeadlineExceeded);
+            }
+            Some(Ok(res)) => {
+                res.map_err(ServeError::Driver)?;
+            }
+            Some(Err(payload)) => {
+                let message = panic_message(&*payload);
+                error!(%method, %uri, panic = %message, "driver panicked while handling request");
+                if !response_started.get() {
+                    write_error_response(
+                        &mut transport_w,
+                        &conf.error_pages,
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        head_request,
+                        true,
+                    )
+                    .await?;
+                }
+                lingering_close(
+                    &mut req_body.into_transport(),
+                    &mut transport_w,
+                    conf.lingering_close_timeout,
+                )
+                .await;
+                return Err(ServeError::DriverPanicked(message));
+            }
+        }
 
+        if !req_body.eof() {
+            // the response is out, but the rest of the request body is still
+            // coming: don't let it turn into a RST that truncates the response.
+            lingering_close(
+                &mut req_body.into_transport(),
+                &mut transport_w,
+                conf.lingering_close_timeout,
+            )
+            .await;
+            if rejected {
+                return Ok(ServeOutcome::ServerRequestedConnectionClose);
+            }
+            return Err(ServeError::RequestBodyNotDrained);
+        }
         (client_buf, transport_r) = req_body
             .into_inner()
-            .ok_or_else(|| eyre::eyre!("request body not drained, have to close connection"))?;
+            .ok_or(ServeError::RequestBodyNotDrained)?;
+        if client_buf.shrink(conf.max_retained_read_buf)? {
+            debug!(pipelined = %client_buf.len(), "shrunk read buffer");
+            read_buf_charge.set(client_buf.storage_size());
+        }
 
-        if connection_close {
+        if !keep_alive {
             debug!("client requested connection close");
             return Ok(ServeOutcome::ClientRequestedConnectionClose);
         }
+        if must_close.get() {
+            debug!("closing connection after response");
+            return Ok(ServeOutcome::ServerRequestedConnectionClose);
+        }
+    }
+}
+
+/// Writes a response the server came up with on its own, usually right
+/// before closing the connection (`close`): with the page for `status` if
+/// there is one, without a body otherwise. Responses to HEAD requests
+/// (`head_request`) only announce the page's length.
+async fn write_error_response(
+    transport_w: &mut impl WriteOwned,
+    pages: &ErrorPages,
+    status: StatusCode,
+    head_request: bool,
+    close: bool,
+) -> std::io::Result<()> {
+    let (res, body) = pages.response(status);
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        status.as_str(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    for (name, value) in &res.headers {
+        head.push_str(&format!("{name}: {}\r\n", String::from_utf8_lossy(value)));
+    }
+    head.push_str(&format!("content-length: {}\r\n", body.len()));
+    if close {
+        head.push_str("connection: close\r\n");
+    }
+    head.push_str("\r\n");
+
+    let mut list = PieceList::single(head.into_bytes());
+    if !head_request && !body.is_empty() {
+        list.push_back(body);
+    }
+    transport_w.writev_all_owned(list).await
+}
+
+/// Shuts down our side of the connection, then reads and discards whatever
+/// the client still sends, until it closes its side or `timeout` elapses.
+///
+/// Closing a socket that still has unread data makes the kernel send a RST,
+/// and clients that get a RST may throw away the response we just wrote
+/// before they get to read it.
+async fn lingering_close(
+    transport_r: &mut impl ReadOwned,
+    transport_w: &mut impl WriteOwned,
+    timeout: Option<Duration>,
+) {
+    let Some(timeout) = timeout else {
+        return;
+    };
+    if let Err(e) = transport_w.shutdown().await {
+        debug!(
+            ?e,
+            "could not shut down write side, skipping lingering close"
+        );
+        return;
+    }
+
+    let discard = async {
+        let mut buf = vec![0u8; 4096];
+        let mut discarded = 0;
+        loop {
+            let res;
+            (res, buf) = transport_r.read_owned(buf).await;
+            match res {
+                Ok(0) | Err(_) => break,
+                Ok(n) => discarded += n,
+            }
+        }
+        discarded
+    };
+    match tokio::time::timeout(timeout, discard).await {
+        Ok(discarded) => debug!(%discarded, "client closed connection after error response"),
+        Err(_) => debug!("client still sending after {timeout:?}, closing anyway"),
     }
 }
@@ -1,22 +1,29 @@
-use core::fmt;
-
 use tokio::sync::mpsc;
 
-use crate::{Body, BodyChunk, Headers};
+use crate::{budget::Charge, loans::Loans, Body, BodyChunk, Headers};
 use fluke_buffet::Piece;
 
+use super::{events::StreamEvents, types::WriteCommand};
+
 pub(crate) enum PieceOrTrailers {
-    Piece(Piece),
+    /// Released from the connection's memory budget once the handler reads
+    /// it
+    Piece(Piece, Charge),
     Trailers(Box<Headers>),
 }
 
 pub(crate) struct StreamIncoming {
     // TODO: don't allow access to tx, check against capacity first?
-    pub(crate) tx: mpsc::Sender<StreamIncomingItem>,
+    pub(crate) tx: mpsc::UnboundedSender<StreamIncomingItem>,
 
     // incoming capacity (that we decide, we get to tell
     // the peer how much we can handle with window updates)
     pub(crate) capacity: i64,
+
+    /// Received, then read by the handler (or padding, which it never
+    /// sees), but not given back to the peer yet. Only with
+    /// [super::WindowUpdates::OnRead].
+    pub(crate) released: u32,
 }
 
 // FIXME: don't use eyre, do proper error handling
@@ -27,7 +34,20 @@ pub(crate) struct H2Body {
     pub(crate) content_length: Option<u64>,
     pub(crate) eof: bool,
     // TODO: more specific error handling
-    pub(crate) rx: mpsc::Receiver<StreamIncomingItem>,
+    pub(crate) rx: mpsc::UnboundedReceiver<StreamIncomingItem>,
+
+    /// Where to report what the handler read, with
+    /// [super::WindowUpdates::OnRead]
+    pub(crate) credit: Option<StreamEvents>,
+
+    /// The request's `content-length` header, see [Body::expected_len]
+    pub(crate) expected_len: Option<u64>,
+
+    /// see [Body::bytes_read]
+    pub(crate) read: u64,
+
+    /// Pieces go through it on their way to the handler
+    pub(crate) loans: Loans,
 }
 
 impl Body for H2Body {
@@ -45,7 +65,14 @@ impl Body for H2Body {
         } else {
             match self.rx.recv().await {
                 Some(maybe_piece_or_trailers) => match maybe_piece_or_trailers? {
-                    PieceOrTrailers::Piece(piece) => BodyChunk::Chunk(piece),
+                    PieceOrTrailers::Piece(piece, _charge) => {
+                        if let Some(events) = &self.credit {
+                            // if the connection is gone, so is the window
+                            _ = events.send_control(WriteCommand::Consumed(piece.len() as u32));
+                        }
+                        self.read += piece.len() as u64;
+                        BodyChunk::Chunk(self.loans.lend(piece))
+                    }
                     PieceOrTrailers::Trailers(trailers) => {
                         self.eof = true;
                         BodyChunk::Done {
@@ -53,7 +80,6 @@ impl Body for H2Body {
                         }
                     }
                 },
-                // TODO: handle trailers
                 None => {
                     self.eof = true;
                     BodyChunk::Done { trailers: None }
@@ -62,56 +88,12 @@ impl Body for H2Body {
         };
         Ok(chunk)
     }
-}
 
-pub(crate) struct SinglePieceBody {
-    content_len: u64,
-    piece: Option<Piece>,
-}
-
-impl fmt::Debug for SinglePieceBody {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut debug_struct = f.debug_struct("SinglePieceBody");
-        debug_struct.field("content_len", &self.content_len);
-
-        if let Some(piece) = &self.piece {
-            match std::str::from_utf8(piece.as_ref()) {
-                Ok(utf8_str) => debug_struct.field("piece", &utf8_str),
-                Err(_) => debug_struct.field("piece", &"(non-utf8 string)"),
-            };
-        } else {
-            debug_struct.field("piece", &"(none)");
-        }
-
-        debug_struct.finish()
+    fn bytes_read(&self) -> Option<u64> {
+        Some(self.read)
     }
-}
 
-impl SinglePieceBody {
-    pub(crate) fn new(piece: Piece) -> Self {
-        let content_len = piece.len() as u64;
-        Self {
-            content_len,
-            piece: Some(piece),
-        }
-    }
-}
-
-impl Body for SinglePieceBody {
-    fn content_len(&self) -> Option<u64> {
-        self.piece.as_ref().map(|piece| piece.len() as u64)
-    }
-
-    fn eof(&self) -> bool {
-        self.piece.is_none()
-    }
-
-    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
-        tracing::trace!( has_piece = %self.piece.is_some(), "SinglePieceBody::next_chunk");
-        if let Some(piece) = self.piece.take() {
-            Ok(BodyChunk::Chunk(piece))
-        } else {
-            Ok(BodyChunk::Done { trailers: None })
-        }
+    fn expected_len(&self) -> Option<u64> {
+        self.content_length.or(self.expected_len)
     }
 }
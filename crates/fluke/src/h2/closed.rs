@@ -0,0 +1,94 @@
+//! Bookkeeping for streams that were closed recently.
+//!
+//! Once a stream is closed it's gone from the stream map, but the peer may
+//! not know that yet: frames it sent before seeing our END_STREAM or
+//! RST_STREAM keep arriving for a while. RFC 9113, section 5.1 wants those
+//! handled differently depending on how the stream got closed, so we
+//! remember that for the last few streams.
+
+use std::collections::{HashMap, VecDeque};
+
+use fluke_h2_parse::StreamId;
+
+/// How many closed streams we remember. Streams that fell out of the list
+/// are treated like streams we know nothing about.
+const MAX_CLOSED_STREAMS: usize = 128;
+
+/// How a stream ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClosedBy {
+    /// Both sides sent END_STREAM. The peer knows the stream is over, so
+    /// frames other than WINDOW_UPDATE, RST_STREAM and PRIORITY are a
+    /// connection error.
+    EndStream,
+
+    /// We sent RST_STREAM (including when refusing the stream): whatever
+    /// the peer sends afterwards is ignored.
+    LocalReset,
+
+    /// The peer sent RST_STREAM: it has no business sending anything but
+    /// PRIORITY afterwards, that's a stream error.
+    PeerReset,
+}
+
+/// The last [MAX_CLOSED_STREAMS] closed streams, and how they ended
+#[derive(Default)]
+pub(crate) struct ClosedStreams {
+    order: VecDeque<StreamId>,
+    closed_by: HashMap<StreamId, ClosedBy>,
+}
+
+impl ClosedStreams {
+    /// Remembers that `stream_id` was closed. If it already was, the first
+    /// reason sticks.
+    pub(crate) fn insert(&mut self, stream_id: StreamId, closed_by: ClosedBy) {
+        if self.closed_by.contains_key(&stream_id) {
+            return;
+        }
+
+        if self.order.len() == MAX_CLOSED_STREAMS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.closed_by.remove(&oldest);
+            }
+        }
+        self.order.push_back(stream_id);
+        self.closed_by.insert(stream_id, closed_by);
+    }
+
+    /// How `stream_id` was closed, if it was recently
+    pub(crate) fn get(&self, stream_id: StreamId) -> Option<ClosedBy> {
+        self.closed_by.get(&stream_id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fluke_h2_parse::StreamId;
+
+    use super::{ClosedBy, ClosedStreams, MAX_CLOSED_STREAMS};
+
+    #[test]
+    fn oldest_streams_are_forgotten() {
+        let mut closed = ClosedStreams::default();
+        closed.insert(StreamId(1), ClosedBy::LocalReset);
+        closed.insert(StreamId(1), ClosedBy::EndStream);
+        assert_eq!(closed.get(StreamId(1)), Some(ClosedBy::LocalReset));
+
+        for i in 1..MAX_CLOSED_STREAMS as u32 {
+            closed.insert(StreamId(1 + 2 * i), ClosedBy::EndStream);
+        }
+        assert_eq!(closed.get(StreamId(1)), Some(ClosedBy::LocalReset));
+
+        closed.insert(
+            StreamId(1 + 2 * MAX_CLOSED_STREAMS as u32),
+            ClosedBy::PeerReset,
+        );
+        assert_eq!(closed.get(StreamId(1)), None);
+        assert_eq!(closed.get(StreamId(3)), Some(ClosedBy::EndStream));
+        assert_eq!(
+            closed.get(StreamId(1 + 2 * MAX_CLOSED_STREAMS as u32)),
+            Some(ClosedBy::PeerReset)
+        );
+        assert_eq!(closed.get(StreamId(7777)), None);
+    }
+}
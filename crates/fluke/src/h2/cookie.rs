@@ -0,0 +1,67 @@
+//! The `cookie` header over HTTP/2 (RFC 9113, section 8.2.3).
+//!
+//! Peers may split it into one field per cookie ("crumbs"), so that each of
+//! them gets its own HPACK table entry: a request that changes one cookie
+//! doesn't have to resend all the others. Applications expect a single
+//! `cookie` header, like they'd get over HTTP/1.1, so crumbs can be joined
+//! back with `"; "` on the way in, see [crate::h2::ServerConf::join_cookies].
+
+use http::header;
+
+use crate::Headers;
+
+/// Replaces several `cookie` headers with a single one, their values joined
+/// with `"; "`
+pub(crate) fn join(headers: &mut Headers) {
+    let crumbs = headers.get_all(header::COOKIE);
+    if crumbs.iter().nth(1).is_none() {
+        return;
+    }
+
+    let mut joined = Vec::new();
+    for crumb in crumbs {
+        if !joined.is_empty() {
+            joined.extend_from_slice(b"; ");
+        }
+        joined.extend_from_slice(crumb);
+    }
+    headers.insert(header::COOKIE, joined.into());
+}
+
+/// Splits a `cookie` header value into crumbs: the `name=value` pairs
+/// between `"; "` delimiters. Only request `cookie` fields get split (one
+/// field per cookie compresses better), so this is for sending requests,
+/// once there's an HTTP/2 client: responses don't carry that header.
+#[allow(dead_code)]
+pub(crate) fn split(value: &[u8]) -> impl Iterator<Item = &[u8]> {
+    value
+        .split(|&b| b == b';')
+        .map(|crumb| {
+            let start = crumb.iter().take_while(|&&b| b == b' ').count();
+            &crumb[start..]
+        })
+        .filter(|crumb| !crumb.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+
+    use crate::Headers;
+
+    #[test]
+    fn join_and_split() {
+        let mut headers = Headers::default();
+        headers.append(header::COOKIE, "a=b".into());
+        headers.append(header::ACCEPT, "*/*".into());
+        headers.append(header::COOKIE, "c=d".into());
+        headers.append(header::COOKIE, "e=f".into());
+        super::join(&mut headers);
+        assert_eq!(headers.get_all(header::COOKIE).iter().count(), 1);
+        assert_eq!(&headers[header::COOKIE][..], b"a=b; c=d; e=f");
+
+        let crumbs: Vec<_> = super::split(&headers[header::COOKIE]).collect();
+        assert_eq!(crumbs, [&b"a=b"[..], b"c=d", b"e=f"]);
+        assert_eq!(super::split(b"a=b;;  c=d; ").count(), 2);
+    }
+}
@@ -1,11 +1,16 @@
 use fluke_buffet::Piece;
-use http::{StatusCode, Version};
-use tokio::sync::mpsc;
+use http::StatusCode;
 use tracing::debug;
 
-use super::types::{H2Event, H2EventPayload};
-use crate::{h1::body::BodyWriteMode, Encoder, Response};
-use fluke_h2_parse::StreamId;
+use super::{
+    events::StreamEvents,
+    types::{H2StreamError, StreamBacklogHandle, WriteCommand},
+};
+use crate::{
+    h1::body::{BodyWriteMode, ContentLengthTracker},
+    BodyErrorReason, Deadline, Encoder, ErrorPages, HeadersExt, Response,
+};
+use fluke_h2_parse::KnownErrorCode;
 
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) enum EncoderState {
@@ -16,34 +21,69 @@ pub(crate) enum EncoderState {
 
 /// Encodes HTTP/2 responses and bodies
 pub(crate) struct H2Encoder {
-    stream_id: StreamId,
-    tx: mpsc::Sender<H2Event>,
+    events: StreamEvents,
+    backlog: StreamBacklogHandle,
+    deadline: Deadline,
     state: EncoderState,
+    content_length: ContentLengthTracker,
+    head_request: bool,
+    body_forbidden: bool,
+    error_pages: ErrorPages,
 }
 
 impl H2Encoder {
-    pub(crate) fn new(stream_id: StreamId, tx: mpsc::Sender<H2Event>) -> Self {
+    pub(crate) fn new(
+        events: StreamEvents,
+        backlog: StreamBacklogHandle,
+        deadline: Deadline,
+        head_request: bool,
+    ) -> Self {
         Self {
-            stream_id,
-            tx,
+            events,
+            backlog,
+            deadline,
             state: EncoderState::ExpectResponseHeaders,
+            content_length: Default::default(),
+            head_request,
+            body_forbidden: false,
+            error_pages: Default::default(),
         }
     }
 
-    fn event(&self, payload: H2EventPayload) -> H2Event {
-        H2Event {
-            payload,
-            stream_id: self.stream_id,
-        }
+    /// Pages for the 500/504 responses sent when the handler is dropped
+    /// before writing a response, see [ErrorPages]
+    pub(crate) fn with_error_pages(mut self, error_pages: ErrorPages) -> Self {
+        self.error_pages = error_pages;
+        self
     }
 
-    async fn send(&self, payload: H2EventPayload) -> eyre::Result<()> {
-        self.tx
-            .send(self.event(payload))
+    async fn send(&self, command: WriteCommand) -> eyre::Result<()> {
+        self.events
+            .send(command)
             .await
             .map_err(|_| eyre::eyre!("could not send event to h2 connection handler"))?;
         Ok(())
     }
+
+    /// Resets go ahead of whatever the stream still has queued
+    fn send_reset(&self, e: H2StreamError) -> eyre::Result<()> {
+        self.events
+            .send_control(WriteCommand::Reset(e))
+            .map_err(|_| eyre::eyre!("could not send event to h2 connection handler"))?;
+        Ok(())
+    }
+
+    /// Resets the stream after the body didn't match the announced
+    /// content-length: ending it cleanly would let the peer accept it.
+    fn reset_for_mismatch(&mut self) {
+        self.state = EncoderState::ResponseDone;
+        if self
+            .send_reset(H2StreamError::ContentLengthMismatch)
+            .is_err()
+        {
+            debug!("could not send event to h2 connection handler");
+        }
+    }
 }
 
 impl Encoder for H2Encoder {
@@ -57,7 +97,11 @@ impl Encoder for H2Encoder {
         // TODO: don't panic here
         assert_eq!(self.state, EncoderState::ExpectResponseHeaders);
 
-        self.send(H2EventPayload::Headers(res)).await?;
+        res.check_bodyless(self.head_request)?;
+        self.backlog.check_open()?;
+        self.content_length = ContentLengthTracker::new(res.headers.content_length());
+        self.body_forbidden = res.forbids_body(self.head_request);
+        self.send(WriteCommand::Headers(res)).await?;
         self.state = EncoderState::ExpectResponseBody;
 
         Ok(())
@@ -67,60 +111,122 @@ impl Encoder for H2Encoder {
     async fn write_body_chunk(&mut self, chunk: Piece, _mode: BodyWriteMode) -> eyre::Result<()> {
         assert!(matches!(self.state, EncoderState::ExpectResponseBody));
 
-        self.send(H2EventPayload::BodyChunk(chunk)).await?;
+        if self.body_forbidden {
+            return Err(BodyErrorReason::CalledWriteBodyChunkWhenNoBodyWasExpected
+                .as_err()
+                .into());
+        }
+        if let Err(e) = self.content_length.on_chunk(chunk.len()) {
+            self.reset_for_mismatch();
+            return Err(e.into());
+        }
+
+        // don't let slow readers make us buffer the whole body
+        self.backlog.reserve(chunk.len()).await?;
+        self.send(WriteCommand::Data {
+            piece: chunk,
+            end: false,
+        })
+        .await?;
         Ok(())
     }
 
     // TODO: BodyWriteMode is not relevant for h2
-    async fn write_body_end(&mut self, _mode: BodyWriteMode) -> eyre::Result<()> {
+    async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()> {
+        assert!(matches!(self.state, EncoderState::ExpectResponseBody));
+
+        if mode == BodyWriteMode::ContentLength {
+            if let Err(e) = self.content_length.on_end() {
+                self.reset_for_mismatch();
+                return Err(e.into());
+            }
+        }
+
+        self.backlog.check_open()?;
+        self.send(WriteCommand::Data {
+            piece: Piece::empty(),
+            end: true,
+        })
+        .await?;
+        self.state = EncoderState::ResponseDone;
+
+        Ok(())
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<crate::Headers>) -> eyre::Result<()> {
         assert!(matches!(self.state, EncoderState::ExpectResponseBody));
 
-        self.send(H2EventPayload::BodyEnd).await?;
+        if self.body_forbidden {
+            return Err(BodyErrorReason::CalledWriteBodyChunkWhenNoBodyWasExpected
+                .as_err()
+                .into());
+        }
+
+        // the trailers carry END_STREAM, no empty DATA frame needed
+        self.backlog.check_open()?;
+        self.send(WriteCommand::Trailers(trailers)).await?;
         self.state = EncoderState::ResponseDone;
 
         Ok(())
     }
 
-    // TODO: handle trailers
-    async fn write_trailers(&mut self, _trailers: Box<crate::Headers>) -> eyre::Result<()> {
-        assert!(matches!(self.state, EncoderState::ResponseDone));
+    async fn reset(&mut self, code: KnownErrorCode) -> eyre::Result<()> {
+        // whatever happens, there's nothing left for `Drop` to clean up
+        self.state = EncoderState::ResponseDone;
 
-        todo!("write trailers")
+        self.backlog.check_open()?;
+        self.send_reset(H2StreamError::ResetByHandler(code))
     }
 }
 
 impl Drop for H2Encoder {
     fn drop(&mut self) {
-        let mut evs = vec![];
-
         match self.state {
             EncoderState::ExpectResponseHeaders => {
-                evs.push(self.event(H2EventPayload::Headers(Response {
-                    version: Version::HTTP_11,
-                    status: StatusCode::INTERNAL_SERVER_ERROR,
-                    headers: Default::default(),
-                })));
-                evs.push(self.event(H2EventPayload::BodyEnd));
+                // we're either dropped along with a handler that went over
+                // its deadline, or one that failed
+                let status = if self.deadline.is_expired() {
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                let (res, body) = self.error_pages.response(status);
+                let body = if self.head_request {
+                    Piece::empty()
+                } else {
+                    body
+                };
+                let events = self.events.clone();
+                fluke_buffet::spawn(async move {
+                    for ev in [
+                        WriteCommand::Headers(res),
+                        WriteCommand::Data {
+                            piece: body,
+                            end: true,
+                        },
+                    ] {
+                        if events.send(ev).await.is_err() {
+                            debug!("could not send event to h2 connection handler");
+                            break;
+                        }
+                    }
+                });
             }
             EncoderState::ExpectResponseBody => {
-                // TODO: this should probably be RST_STREAM instead
-                evs.push(self.event(H2EventPayload::BodyEnd));
+                // ending the body cleanly would make a truncated response look
+                // complete, so reset the stream instead.
+                let e = if self.deadline.is_expired() {
+                    H2StreamError::DeadlineExceeded
+                } else {
+                    H2StreamError::ResponseAbandoned
+                };
+                if self.send_reset(e).is_err() {
+                    debug!("could not send event to h2 connection handler");
+                }
             }
             EncoderState::ResponseDone => {
                 // ah, good.
             }
         }
-
-        if !evs.is_empty() {
-            let tx = self.tx.clone();
-            fluke_buffet::spawn(async move {
-                for ev in evs {
-                    if tx.send(ev).await.is_err() {
-                        debug!("could not send event to h2 connection handler");
-                        break;
-                    }
-                }
-            });
-        }
     }
 }
@@ -0,0 +1,371 @@
+//! How handlers talk to the connection: each stream gets its own bounded
+//! queue of write commands, so that a stream with a lot to say (a big
+//! response, a handler that's faster than the peer) can't hold the others
+//! back. Resets go through a connection-wide control queue instead, ahead of
+//! any queued body chunk: they're what frees up memory when the connection
+//! is over its budget. Connection control (window updates, extension frames)
+//! goes through there too, ahead of resets. Streams whose next command is
+//! stream control (their response headers) are served before streams that
+//! only have data queued, see [WritePriority].
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    fmt,
+    rc::Rc,
+};
+
+use fluke_h2_parse::StreamId;
+use tokio::sync::Notify;
+
+use super::types::{StreamCommand, WriteCommand, WritePriority};
+
+/// How many events a stream may have queued before its handler has to wait
+pub(crate) const STREAM_QUEUE_LEN: usize = 16;
+
+/// The connection's end of the queues
+pub(crate) struct EventQueues {
+    inner: Rc<QueuesInner>,
+}
+
+/// A handler's end of the queues, for a single stream
+#[derive(Clone)]
+pub(crate) struct StreamEvents {
+    stream_id: StreamId,
+    inner: Rc<QueuesInner>,
+}
+
+struct QueuesInner {
+    state: RefCell<QueuesState>,
+
+    /// Set once the connection is gone
+    closed: Cell<bool>,
+
+    /// Wakes up the connection when an event is queued
+    pushed: Notify,
+
+    /// Wakes up handlers waiting for room in their stream's queue
+    popped: Notify,
+}
+
+#[derive(Default)]
+struct QueuesState {
+    /// Commands that skip the stream queues, connection control first
+    connection_control: VecDeque<StreamCommand>,
+    stream_control: VecDeque<StreamCommand>,
+
+    streams: HashMap<StreamId, VecDeque<WriteCommand>>,
+
+    /// Streams whose next command is stream control, served in turn
+    ready_control: VecDeque<StreamId>,
+
+    /// Streams whose next command is data, served in turn once
+    /// `ready_control` is empty
+    ready_data: VecDeque<StreamId>,
+}
+
+impl QueuesState {
+    /// Puts `stream_id` in line, according to the priority of its next command
+    fn mark_ready(&mut self, stream_id: StreamId, priority: WritePriority) {
+        match priority {
+            WritePriority::ConnectionControl | WritePriority::StreamControl => {
+                self.ready_control.push_back(stream_id)
+            }
+            WritePriority::Data => self.ready_data.push_back(stream_id),
+        }
+    }
+
+    /// Queues a command on the control queue matching its priority
+    fn push_control(&mut self, ev: StreamCommand) {
+        match ev.command.priority() {
+            WritePriority::ConnectionControl => self.connection_control.push_back(ev),
+            WritePriority::StreamControl | WritePriority::Data => self.stream_control.push_back(ev),
+        }
+    }
+}
+
+/// Returned when queueing an event for a connection that's gone
+#[derive(Debug)]
+pub(crate) struct QueueClosed;
+
+impl EventQueues {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Rc::new(QueuesInner {
+                state: Default::default(),
+                closed: Cell::new(false),
+                pushed: Notify::new(),
+                popped: Notify::new(),
+            }),
+        }
+    }
+
+    /// A handle to queue events for `stream_id`
+    pub(crate) fn stream(&self, stream_id: StreamId) -> StreamEvents {
+        StreamEvents {
+            stream_id,
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Waits for the next command: connection control and resets first, then
+    /// one command per stream in turn, streams with stream control queued
+    /// first. With `control_only`, data is left queued.
+    pub(crate) async fn recv(&self, control_only: bool) -> StreamCommand {
+        loop {
+            if let Some(ev) = self.try_recv(control_only) {
+                return ev;
+            }
+            self.inner.pushed.notified().await;
+        }
+    }
+
+    fn try_recv(&self, control_only: bool) -> Option<StreamCommand> {
+        let mut state = self.inner.state.borrow_mut();
+        if let Some(ev) = state
+            .connection_control
+            .pop_front()
+            .or_else(|| state.stream_control.pop_front())
+        {
+            return Some(ev);
+        }
+
+        let stream_id = match state.ready_control.pop_front() {
+            Some(stream_id) => stream_id,
+            None if control_only => return None,
+            None => state.ready_data.pop_front()?,
+        };
+        let queue = state
+            .streams
+            .get_mut(&stream_id)
+            .expect("ready streams have a queue");
+        let command = queue.pop_front().expect("ready streams have commands");
+        match queue.front() {
+            None => {
+                state.streams.remove(&stream_id);
+            }
+            Some(next) => {
+                let priority = next.priority();
+                state.mark_ready(stream_id, priority);
+            }
+        }
+        drop(state);
+
+        self.inner.popped.notify_waiters();
+        Some(StreamCommand { stream_id, command })
+    }
+
+    /// Drops whatever `stream_id` still had queued, for streams that were
+    /// reset: there's nowhere left to send it.
+    pub(crate) fn discard(&self, stream_id: StreamId) {
+        let mut state = self.inner.state.borrow_mut();
+        if state.streams.remove(&stream_id).is_some() {
+            state.ready_control.retain(|&id| id != stream_id);
+            state.ready_data.retain(|&id| id != stream_id);
+            drop(state);
+            self.inner.popped.notify_waiters();
+        }
+    }
+}
+
+impl Drop for EventQueues {
+    fn drop(&mut self) {
+        self.inner.closed.set(true);
+        // drop queued body chunks now, not when the last handler is done
+        *self.inner.state.borrow_mut() = Default::default();
+        self.inner.popped.notify_waiters();
+    }
+}
+
+impl fmt::Debug for StreamEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StreamEvents")
+            .field(&self.stream_id)
+            .finish()
+    }
+}
+
+impl StreamEvents {
+    /// Queues a command, waiting for room in the stream's queue if needed
+    pub(crate) async fn send(&self, command: WriteCommand) -> Result<(), QueueClosed> {
+        loop {
+            // created before checking, so that it can't miss a wakeup
+            let popped = self.inner.popped.notified();
+            if self.inner.closed.get() {
+                return Err(QueueClosed);
+            }
+
+            {
+                let mut state = self.inner.state.borrow_mut();
+                let queue = state.streams.entry(self.stream_id).or_default();
+                if queue.len() < STREAM_QUEUE_LEN {
+                    if queue.is_empty() {
+                        state.mark_ready(self.stream_id, command.priority());
+                    }
+                    state
+                        .streams
+                        .entry(self.stream_id)
+                        .or_default()
+                        .push_back(command);
+                    drop(state);
+                    self.inner.pushed.notify_one();
+                    return Ok(());
+                }
+            }
+
+            popped.await;
+        }
+    }
+
+    /// Queues a command on the control queue, ahead of anything the stream
+    /// already queued (connection control also goes ahead of resets). Never
+    /// waits.
+    pub(crate) fn send_control(&self, command: WriteCommand) -> Result<(), QueueClosed> {
+        if self.inner.closed.get() {
+            return Err(QueueClosed);
+        }
+
+        self.inner.state.borrow_mut().push_control(StreamCommand {
+            stream_id: self.stream_id,
+            command,
+        });
+        self.inner.pushed.notify_one();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use fluke_h2_parse::StreamId;
+
+    use fluke_buffet::Piece;
+
+    use super::{EventQueues, STREAM_QUEUE_LEN};
+    use crate::{
+        h2::types::{H2StreamError, WriteCommand},
+        Response,
+    };
+
+    fn data() -> WriteCommand {
+        WriteCommand::Data {
+            piece: Piece::empty(),
+            end: false,
+        }
+    }
+
+    #[test]
+    fn streams_take_turns_and_control_goes_first() {
+        fluke_buffet::start(async move {
+            let queues = EventQueues::new();
+            let (one, three) = (queues.stream(StreamId(1)), queues.stream(StreamId(3)));
+            for _ in 0..3 {
+                one.send(data()).await.unwrap();
+            }
+            three.send(data()).await.unwrap();
+            three
+                .send_control(WriteCommand::Reset(H2StreamError::ResponseAbandoned))
+                .unwrap();
+
+            let ev = queues.recv(false).await;
+            assert!(matches!(ev.command, WriteCommand::Reset(_)));
+            assert!(queues.try_recv(true).is_none());
+
+            let order: Vec<u32> = (0..4)
+                .map(|_| queues.try_recv(false).unwrap().stream_id.0)
+                .collect();
+            assert_eq!(order, [1, 3, 1, 1]);
+            assert!(queues.try_recv(false).is_none());
+        });
+    }
+
+    #[test]
+    fn connection_control_goes_before_resets() {
+        fluke_buffet::start(async move {
+            let queues = EventQueues::new();
+            let (one, three) = (queues.stream(StreamId(1)), queues.stream(StreamId(3)));
+            one.send_control(WriteCommand::Reset(H2StreamError::ResponseAbandoned))
+                .unwrap();
+            three.send_control(WriteCommand::Consumed(1024)).unwrap();
+
+            let ev = queues.try_recv(true).unwrap();
+            assert_eq!(ev.stream_id, StreamId(3));
+            assert!(matches!(ev.command, WriteCommand::Consumed(1024)));
+            let ev = queues.try_recv(true).unwrap();
+            assert!(matches!(ev.command, WriteCommand::Reset(_)));
+            assert!(queues.try_recv(true).is_none());
+        });
+    }
+
+    #[test]
+    fn headers_go_before_data() {
+        fluke_buffet::start(async move {
+            let queues = EventQueues::new();
+            let (one, three, five) = (
+                queues.stream(StreamId(1)),
+                queues.stream(StreamId(3)),
+                queues.stream(StreamId(5)),
+            );
+            for _ in 0..2 {
+                one.send(data()).await.unwrap();
+            }
+            three
+                .send(WriteCommand::Headers(Response::default()))
+                .await
+                .unwrap();
+            three.send(data()).await.unwrap();
+            five.send(WriteCommand::Headers(Response::default()))
+                .await
+                .unwrap();
+
+            // headers still get through when data has to wait
+            let ev = queues.try_recv(true).unwrap();
+            assert_eq!(ev.stream_id, StreamId(3));
+            assert!(matches!(ev.command, WriteCommand::Headers(_)));
+            let ev = queues.try_recv(true).unwrap();
+            assert_eq!(ev.stream_id, StreamId(5));
+            assert!(queues.try_recv(true).is_none());
+
+            let order: Vec<u32> = (0..3)
+                .map(|_| queues.try_recv(false).unwrap().stream_id.0)
+                .collect();
+            assert_eq!(order, [1, 3, 1]);
+            assert!(queues.try_recv(false).is_none());
+        });
+    }
+
+    #[test]
+    fn full_stream_queue_waits_alone() {
+        fluke_buffet::start(async move {
+            let queues = EventQueues::new();
+            let one = queues.stream(StreamId(1));
+            for _ in 0..STREAM_QUEUE_LEN {
+                one.send(data()).await.unwrap();
+            }
+
+            let sent = Rc::new(Cell::new(false));
+            let waiter = fluke_buffet::spawn({
+                let sent = sent.clone();
+                async move {
+                    one.send(data()).await.unwrap();
+                    sent.set(true);
+                    one
+                }
+            });
+            tokio::task::yield_now().await;
+            assert!(!sent.get());
+
+            // other streams aren't affected
+            let three = queues.stream(StreamId(3));
+            three.send(data()).await.unwrap();
+
+            queues.recv(false).await;
+            let one = waiter.await.unwrap();
+            assert!(sent.get());
+
+            drop(queues);
+            assert!(one.send(data()).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,68 @@
+//! Extension frames (<https://httpwg.org/specs/rfc9113.html#extensibility>):
+//! frame types RFC 9113 doesn't define, like ORIGIN (RFC 8336) or
+//! experimental ones. The server ignores them, but hands them to
+//! [crate::ServerDriver::on_extension_frame] first, and drivers may send
+//! their own through [ExtensionFrames].
+
+use std::fmt;
+
+use fluke_buffet::Piece;
+use fluke_h2_parse::{RawFrameType, StreamId};
+
+use super::{events::StreamEvents, types::WriteCommand};
+
+/// A frame of a type RFC 9113 doesn't define
+pub struct ExtensionFrame {
+    pub frame_type: u8,
+    pub flags: u8,
+    pub stream_id: StreamId,
+    pub payload: Piece,
+}
+
+impl fmt::Debug for ExtensionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtensionFrame")
+            .field("frame_type", &format_args!("0x{:x}", self.frame_type))
+            .field("flags", &format_args!("0x{:x}", self.flags))
+            .field("stream_id", &self.stream_id)
+            .field("payload_len", &self.payload.len())
+            .finish()
+    }
+}
+
+/// Returned when an extension frame can't be sent
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ExtensionFrameError {
+    /// Frames of the types RFC 9113 defines are the server's business
+    #[error("frame type 0x{0:x} isn't an extension frame type")]
+    NotAnExtension(u8),
+
+    /// The connection is gone
+    #[error("the connection is closed")]
+    ConnectionClosed,
+}
+
+/// Sends extension frames on an HTTP/2 connection. Frames are written in
+/// the order they're sent, ahead of queued response data, and are dropped
+/// if they're larger than the peer's max frame size.
+#[derive(Clone)]
+pub struct ExtensionFrames {
+    events: StreamEvents,
+}
+
+impl ExtensionFrames {
+    pub(crate) fn new(events: StreamEvents) -> Self {
+        Self { events }
+    }
+
+    pub fn send(&self, frame: ExtensionFrame) -> Result<(), ExtensionFrameError> {
+        if RawFrameType::from_repr(frame.frame_type).is_some() {
+            return Err(ExtensionFrameError::NotAnExtension(frame.frame_type));
+        }
+
+        self.events
+            .send_control(WriteCommand::Extension(frame))
+            .map_err(|_| ExtensionFrameError::ConnectionClosed)
+    }
+}
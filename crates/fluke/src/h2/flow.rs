@@ -0,0 +1,155 @@
+//! Receive windows: how much the peer may send us before it has to wait for
+//! a WINDOW_UPDATE frame.
+//!
+//! A fixed window caps throughput at `window / rtt`, which is plenty on a
+//! LAN and not nearly enough across an ocean. With `adaptive_window` set in
+//! [crate::h2::ServerConf], the window follows the bandwidth-delay product
+//! instead: each PING frame starts a sample, every DATA byte received until
+//! it's acknowledged counts towards it, and if the peer managed to send
+//! close to a full window in that time, the window was the bottleneck, so
+//! it grows.
+
+use std::time::Duration;
+
+/// Once less than this fraction of a window is left, it's replenished
+pub(crate) const REPLENISH_BELOW: i64 = 2;
+
+/// Estimates the bandwidth-delay product of a connection, see the module
+/// docs
+#[derive(Debug)]
+pub(crate) struct BdpEstimator {
+    /// DATA bytes received since the sampling PING went out
+    bytes: u64,
+
+    /// The window never grows past that
+    max_window: u32,
+}
+
+impl BdpEstimator {
+    pub(crate) fn new(max_window: u32) -> Self {
+        Self {
+            bytes: 0,
+            max_window,
+        }
+    }
+
+    /// Whether it's worth sampling at all with the current `window`
+    pub(crate) fn wants_sample(&self, window: u32) -> bool {
+        window < self.max_window
+    }
+
+    /// A PING went out: the sample starts now
+    pub(crate) fn on_ping_sent(&mut self) {
+        self.bytes = 0;
+    }
+
+    pub(crate) fn on_data(&mut self, len: usize) {
+        self.bytes += len as u64;
+    }
+
+    /// The PING was acknowledged after `rtt`, `srtt` is the smoothed
+    /// estimate including that sample. Returns the new window, if it
+    /// should grow.
+    pub(crate) fn on_ping_ack(
+        &mut self,
+        rtt: Duration,
+        srtt: Duration,
+        window: u32,
+    ) -> Option<u32> {
+        let bytes = std::mem::take(&mut self.bytes);
+        if bytes == 0 || rtt.is_zero() {
+            return None;
+        }
+
+        // what we received during one round trip, scaled to the typical one
+        let bandwidth = bytes as f64 / rtt.as_secs_f64();
+        let bdp = bandwidth * srtt.as_secs_f64().max(rtt.as_secs_f64());
+
+        // the peer sent (nearly) all it could: the window held it back
+        if bdp < window as f64 * 2.0 / 3.0 {
+            return None;
+        }
+
+        let next = (bdp * 2.0).min(self.max_window as f64) as u32;
+        (next > window).then_some(next)
+    }
+}
+
+/// How much to give back to the peer, if anything, for a window that has
+/// `capacity` bytes left out of `window`
+pub(crate) fn replenish(capacity: i64, window: u32) -> Option<u32> {
+    (capacity < window as i64 / REPLENISH_BELOW).then(|| (window as i64 - capacity) as u32)
+}
+
+/// When stream receive windows are given back to the peer, see
+/// [crate::h2::ServerConf::window_updates]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WindowUpdates {
+    /// Once the handler has read the data from the request body: a handler
+    /// that doesn't keep up stops the peer from sending more on its stream.
+    #[default]
+    OnRead,
+
+    /// As soon as the data is received, whether the handler reads it or not.
+    /// Unread data piles up until the memory budget runs out, then the
+    /// connection window stops being given back.
+    OnReceipt,
+}
+
+/// How much to give back to the peer, if anything, when only `released`
+/// bytes (read by the handler) may be, for a window that has `capacity`
+/// bytes left out of `window`. Like [replenish], waits for the window to run
+/// low, and for enough to be read that small reads don't each get their own
+/// WINDOW_UPDATE.
+pub(crate) fn release(capacity: i64, released: u32, window: u32) -> Option<u32> {
+    let low = capacity < window as i64 / REPLENISH_BELOW;
+    let enough = released as i64 >= window as i64 / (2 * REPLENISH_BELOW);
+    (released > 0 && low && enough).then_some(released)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{release, replenish, BdpEstimator};
+
+    #[test]
+    fn window_grows_when_it_is_the_bottleneck() {
+        let rtt = Duration::from_millis(100);
+        let mut bdp = BdpEstimator::new(1024 * 1024);
+
+        // a full window per round trip: grow
+        bdp.on_ping_sent();
+        bdp.on_data(65_535);
+        assert_eq!(bdp.on_ping_ack(rtt, rtt, 65_535), Some(131_070));
+
+        // a trickle: leave it be
+        bdp.on_ping_sent();
+        bdp.on_data(1_000);
+        assert_eq!(bdp.on_ping_ack(rtt, rtt, 131_070), None);
+
+        // never past the max
+        bdp.on_ping_sent();
+        bdp.on_data(1_000_000);
+        assert_eq!(bdp.on_ping_ack(rtt, rtt, 900_000), Some(1024 * 1024));
+        assert!(!bdp.wants_sample(1024 * 1024));
+    }
+
+    #[test]
+    fn replenish_below_half() {
+        assert_eq!(replenish(40_000, 65_535), None);
+        assert_eq!(replenish(30_000, 65_535), Some(35_535));
+        assert_eq!(replenish(0, 65_535), Some(65_535));
+    }
+
+    #[test]
+    fn release_what_was_read() {
+        // plenty of window left
+        assert_eq!(release(40_000, 25_535, 65_535), None);
+        // running low, but the handler barely read anything
+        assert_eq!(release(1_000, 4_000, 65_535), None);
+        assert_eq!(release(1_000, 20_000, 65_535), Some(20_000));
+        // everything buffered was read
+        assert_eq!(release(0, 65_535, 65_535), Some(65_535));
+    }
+}
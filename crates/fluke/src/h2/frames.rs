@@ -0,0 +1,313 @@
+//! Raw HTTP/2 frames, for when [serve](super::serve) does too much: testing
+//! tools, protocol extensions, anything that needs to say exactly what goes
+//! on the wire.
+//!
+//! [FrameSource] reads frames from any [ReadOwned], [FrameSink] writes them
+//! to any [WriteOwned]. Neither knows about streams, flow control or
+//! settings: that's up to the caller. Header blocks can be encoded and
+//! decoded with HPACK on the way, or handled as opaque payloads.
+
+use fluke_buffet::{Piece, PieceList, ReadOwned, Roll, RollMut, WriteOwned};
+use fluke_h2_parse::{
+    ContinuationFlags, Frame, FrameType, HeadersFlags, IntoPiece, PrioritySpec, Settings, StreamId,
+    PREFACE,
+};
+use nom::Finish;
+
+use super::{
+    read::{FrameReader, ReadEvent},
+    types::H2ConnectionError,
+};
+
+/// Reads frames off a connection
+pub struct FrameSource<R: ReadOwned> {
+    reader: FrameReader,
+    // only missing if the last read failed
+    buf: Option<RollMut>,
+    transport_r: R,
+    hpack_dec: fluke_hpack::Decoder<'static>,
+}
+
+impl<R: ReadOwned> FrameSource<R> {
+    /// Reads frames from the server side of a connection: the client
+    /// connection preface is expected (and skipped) first.
+    pub fn server(transport_r: R) -> eyre::Result<Self> {
+        Self::with_reader(
+            transport_r,
+            FrameReader::new(Self::default_max_frame_size()),
+        )
+    }
+
+    /// Reads frames from the client side of a connection, or from a server
+    /// connection whose preface was already read.
+    pub fn client(transport_r: R) -> eyre::Result<Self> {
+        Self::with_reader(
+            transport_r,
+            FrameReader::without_preface(Self::default_max_frame_size()),
+        )
+    }
+
+    fn with_reader(transport_r: R, reader: FrameReader) -> eyre::Result<Self> {
+        Ok(Self {
+            reader,
+            buf: Some(RollMut::alloc()?),
+            transport_r,
+            hpack_dec: fluke_hpack::Decoder::new(),
+        })
+    }
+
+    fn default_max_frame_size() -> u32 {
+        Settings::default().max_frame_size
+    }
+
+    /// Frames larger than this are a connection error. Should match the
+    /// `SETTINGS_MAX_FRAME_SIZE` we advertised.
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.reader.set_max_frame_size(max_frame_size);
+    }
+
+    /// Reads the next frame. Padding is stripped from the payload of DATA
+    /// and HEADERS frames. Returns `None` if the peer hung up cleanly.
+    pub async fn next_frame(&mut self) -> Result<Option<(Frame, Roll)>, H2ConnectionError> {
+        loop {
+            let buf = match self.buf.take() {
+                Some(buf) => buf,
+                None => RollMut::alloc_wait()
+                    .await
+                    .map_err(|e| H2ConnectionError::ReadError(e.into()))?,
+            };
+            let (buf, ev) = match self.reader.next_event(buf, &mut self.transport_r).await? {
+                Some(res) => res,
+                None => return Ok(None),
+            };
+            self.buf = Some(buf);
+            match ev {
+                ReadEvent::Preface => continue,
+                ReadEvent::Frame(frame, payload) => return Ok(Some((frame, payload))),
+            }
+        }
+    }
+
+    /// Reads the rest of the header block started by a HEADERS frame just
+    /// returned by [FrameSource::next_frame], and decodes it. The HPACK
+    /// state is kept across calls: if this is used at all, it has to be
+    /// used for every header block on the connection.
+    pub async fn read_header_block(
+        &mut self,
+        frame: Frame,
+        mut payload: Roll,
+    ) -> Result<Vec<(Piece, Piece)>, H2ConnectionError> {
+        let FrameType::Headers(flags) = frame.frame_type else {
+            return Err(eyre::eyre!("expected a HEADERS frame, got {:?}", frame.frame_type).into());
+        };
+        if flags.contains(HeadersFlags::Priority) {
+            (payload, _) = PrioritySpec::parse(payload)
+                .finish()
+                .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+        }
+
+        let mut block = payload[..].to_vec();
+        let mut end_headers = flags.contains(HeadersFlags::EndHeaders);
+        while !end_headers {
+            // the reader makes sure nothing but CONTINUATION frames for this
+            // stream come in until the block is done
+            let (frame, payload) = self.next_frame().await?.ok_or_else(|| {
+                H2ConnectionError::ExpectedContinuationFrame {
+                    stream_id: frame.stream_id,
+                    frame_type: None,
+                }
+            })?;
+            if let FrameType::Continuation(flags) = frame.frame_type {
+                end_headers = flags.contains(ContinuationFlags::EndHeaders);
+            }
+            block.extend_from_slice(&payload[..]);
+        }
+
+        let mut fields = vec![];
+        self.hpack_dec.decode_with_cb(&block, |name, value| {
+            fields.push((name.into_owned().into(), value.into_owned().into()))
+        })?;
+        Ok(fields)
+    }
+}
+
+/// Writes frames to a connection
+pub struct FrameSink<W: WriteOwned> {
+    transport_w: W,
+    scratch: RollMut,
+    hpack_enc: fluke_hpack::Encoder<'static>,
+
+    /// The peer's `SETTINGS_MAX_FRAME_SIZE`, used to split header blocks
+    max_frame_size: u32,
+}
+
+impl<W: WriteOwned> FrameSink<W> {
+    pub fn new(transport_w: W) -> eyre::Result<Self> {
+        Ok(Self {
+            transport_w,
+            scratch: RollMut::alloc()?,
+            hpack_enc: fluke_hpack::Encoder::new(),
+            max_frame_size: Settings::default().max_frame_size,
+        })
+    }
+
+    /// Header blocks written by [FrameSink::write_headers] are split into
+    /// frames of at most this size. Should match the peer's
+    /// `SETTINGS_MAX_FRAME_SIZE`.
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Writes the client connection preface. The client must follow it with
+    /// a SETTINGS frame.
+    pub async fn write_preface(&mut self) -> Result<(), H2ConnectionError> {
+        self.transport_w
+            .write_all_owned(PREFACE)
+            .await
+            .map_err(H2ConnectionError::WriteError)
+    }
+
+    /// Writes a frame as is: its length is set from `payload`, but nothing
+    /// else is checked, not even the peer's max frame size.
+    pub async fn write_frame(
+        &mut self,
+        frame: Frame,
+        payload: PieceList,
+    ) -> Result<(), H2ConnectionError> {
+        let frame_size =
+            payload
+                .len()
+                .try_into()
+                .map_err(|_| H2ConnectionError::FrameTooLarge {
+                    frame_type: frame.frame_type,
+                    frame_size: payload.len() as _,
+                    max_frame_size: u32::MAX,
+                })?;
+        let frame_roll = frame
+            .with_len(frame_size)
+            .into_piece(&mut self.scratch)
+            .map_err(H2ConnectionError::WriteError)?;
+
+        if payload.is_empty() {
+            self.transport_w.write_all_owned(frame_roll).await
+        } else {
+            self.transport_w
+                .writev_all_owned(payload.preceded_by(frame_roll))
+                .await
+        }
+        .map_err(H2ConnectionError::WriteError)
+    }
+
+    /// Encodes `headers` with HPACK and writes them as a HEADERS frame,
+    /// followed by as many CONTINUATION frames as needed. The HPACK state is
+    /// kept across calls: if this is used at all, it has to be used for
+    /// every header block on the connection.
+    pub async fn write_headers<'a>(
+        &mut self,
+        stream_id: StreamId,
+        headers: impl IntoIterator<Item = (&'a [u8], &'a [u8])>,
+        end_stream: bool,
+    ) -> Result<(), H2ConnectionError> {
+        self.hpack_enc
+            .encode_into(headers, &mut self.scratch)
+            .map_err(H2ConnectionError::WriteError)?;
+        let mut block = self.scratch.take_all();
+
+        let max_frame_size = self.max_frame_size as usize;
+        let mut first = true;
+        loop {
+            let last = block.len() <= max_frame_size;
+            let chunk;
+            let len = block.len().min(max_frame_size);
+            (chunk, block) = block.split_at(len);
+
+            let frame_type = if first {
+                let mut flags = Default::default();
+                if end_stream {
+                    flags |= HeadersFlags::EndStream;
+                }
+                if last {
+                    flags |= HeadersFlags::EndHeaders;
+                }
+                FrameType::Headers(flags)
+            } else {
+                let mut flags = Default::default();
+                if last {
+                    flags |= ContinuationFlags::EndHeaders;
+                }
+                FrameType::Continuation(flags)
+            };
+            self.write_frame(Frame::new(frame_type, stream_id), PieceList::single(chunk))
+                .await?;
+
+            if last {
+                return Ok(());
+            }
+            first = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fluke_buffet::{Piece, PieceList};
+    use fluke_h2_parse::{DataFlags, Frame, FrameType, StreamId};
+
+    use super::{FrameSink, FrameSource};
+
+    #[test]
+    fn round_trip() {
+        fluke_buffet::start(async move {
+            let (w, r) = fluke_buffet::pipe();
+            let mut source = FrameSource::server(r).unwrap();
+
+            // small enough that the header block needs CONTINUATION frames
+            let long_value = "x".repeat(40);
+            let writer = fluke_buffet::spawn({
+                let long_value = long_value.clone();
+                async move {
+                    let mut sink = FrameSink::new(w).unwrap();
+                    sink.set_max_frame_size(16);
+                    sink.write_preface().await.unwrap();
+                    sink.write_headers(
+                        StreamId(1),
+                        [
+                            (&b":path"[..], &b"/"[..]),
+                            (&b"x-long"[..], long_value.as_bytes()),
+                        ],
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                    sink.write_frame(
+                        Frame::new(FrameType::Data(DataFlags::EndStream.into()), StreamId(1)),
+                        PieceList::single(Piece::from("hello")),
+                    )
+                    .await
+                    .unwrap();
+                }
+            });
+
+            let (frame, payload) = source.next_frame().await.unwrap().unwrap();
+            assert!(matches!(frame.frame_type, FrameType::Headers(_)));
+            let fields = source.read_header_block(frame, payload).await.unwrap();
+            let fields: Vec<(&[u8], &[u8])> =
+                fields.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+            assert_eq!(
+                fields,
+                [
+                    (&b":path"[..], &b"/"[..]),
+                    (&b"x-long"[..], long_value.as_bytes())
+                ]
+            );
+
+            let (frame, payload) = source.next_frame().await.unwrap().unwrap();
+            assert!(matches!(frame.frame_type, FrameType::Data(_)));
+            assert_eq!(frame.stream_id, StreamId(1));
+            assert_eq!(&payload[..], b"hello");
+
+            writer.await.unwrap();
+            assert!(source.next_frame().await.unwrap().is_none());
+        });
+    }
+}
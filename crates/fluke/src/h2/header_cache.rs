@@ -0,0 +1,155 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use fluke_buffet::Roll;
+
+/// How many distinct header blocks we remember per connection
+const MAX_CACHED_BLOCKS: usize = 32;
+
+/// Remembers HPACK-encoded header blocks for header lists that repeat
+/// verbatim (think identical static-file responses), so they don't have
+/// to go through the encoder again.
+///
+/// A block is only valid for as long as the encoder's dynamic table is
+/// left untouched: its generation is recorded alongside the cached blocks,
+/// and they're all thrown away as soon as it changes.
+#[derive(Default)]
+pub(crate) struct HeaderBlockCache {
+    generation: u64,
+    blocks: HashMap<u64, CachedBlock>,
+}
+
+struct CachedBlock {
+    headers: Vec<(Vec<u8>, Vec<u8>)>,
+    block: Roll,
+}
+
+impl HeaderBlockCache {
+    /// Computes the cache key for a header list
+    pub(crate) fn key(headers: &[(&[u8], &[u8])]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        headers.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns a previously-encoded block for this exact header list, if
+    /// the encoder is still at the generation it was encoded at.
+    pub(crate) fn get(
+        &mut self,
+        generation: u64,
+        key: u64,
+        headers: &[(&[u8], &[u8])],
+    ) -> Option<Roll> {
+        if generation != self.generation {
+            self.blocks.clear();
+            self.generation = generation;
+            return None;
+        }
+
+        let cached = self.blocks.get(&key)?;
+        // guard against hash collisions
+        let same = cached.headers.len() == headers.len()
+            && cached
+                .headers
+                .iter()
+                .zip(headers)
+                .all(|((n1, v1), (n2, v2))| &n1[..] == *n2 && &v1[..] == *v2);
+        same.then(|| cached.block.clone())
+    }
+
+    /// Remembers a header block. The caller must make sure encoding it did
+    /// not modify the encoder's dynamic table, ie. that `generation` is the
+    /// encoder's generation both before and after encoding.
+    pub(crate) fn insert(
+        &mut self,
+        generation: u64,
+        key: u64,
+        headers: &[(&[u8], &[u8])],
+        block: Roll,
+    ) {
+        if generation != self.generation {
+            self.blocks.clear();
+            self.generation = generation;
+        }
+
+        if self.blocks.len() >= MAX_CACHED_BLOCKS && !self.blocks.contains_key(&key) {
+            // keep it simple: start over rather than track recency
+            self.blocks.clear();
+        }
+
+        let headers = headers
+            .iter()
+            .map(|(n, v)| (n.to_vec(), v.to_vec()))
+            .collect();
+        self.blocks.insert(key, CachedBlock { headers, block });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fluke_buffet::{Roll, RollMut};
+
+    use super::{HeaderBlockCache, MAX_CACHED_BLOCKS};
+
+    fn roll(bytes: &[u8]) -> Roll {
+        let mut buf = RollMut::alloc().unwrap();
+        buf.put(bytes).unwrap();
+        buf.filled()
+    }
+
+    const OK: &[(&[u8], &[u8])] = &[(b":status", b"200"), (b"content-type", b"text/css")];
+
+    #[test]
+    fn hits_and_misses() {
+        let mut cache = HeaderBlockCache::default();
+        let key = HeaderBlockCache::key(OK);
+        assert!(cache.get(0, key, OK).is_none());
+
+        cache.insert(0, key, OK, roll(b"block"));
+        assert_eq!(&cache.get(0, key, OK).unwrap()[..], b"block");
+
+        // a different list under the same key is a collision, not a hit
+        let other: &[(&[u8], &[u8])] = &[(b":status", b"404")];
+        assert!(cache.get(0, key, other).is_none());
+        assert!(cache.get(0, HeaderBlockCache::key(other), other).is_none());
+
+        // the dynamic table changed: every block is stale
+        assert!(cache.get(1, key, OK).is_none());
+        assert!(cache.get(0, key, OK).is_none());
+    }
+
+    #[test]
+    fn eviction() {
+        let mut cache = HeaderBlockCache::default();
+        let statuses: Vec<String> = (0..=MAX_CACHED_BLOCKS)
+            .map(|i| format!("{}", 200 + i))
+            .collect();
+        let lists: Vec<[(&[u8], &[u8]); 1]> = statuses
+            .iter()
+            .map(|status| [(&b":status"[..], status.as_bytes())])
+            .collect();
+
+        for list in &lists[..MAX_CACHED_BLOCKS] {
+            cache.insert(0, HeaderBlockCache::key(list), list, roll(list[0].1));
+        }
+        for list in &lists[..MAX_CACHED_BLOCKS] {
+            assert!(cache.get(0, HeaderBlockCache::key(list), list).is_some());
+        }
+
+        // replacing a block doesn't make room
+        let first = &lists[0];
+        cache.insert(0, HeaderBlockCache::key(first), first, roll(b"again"));
+        assert_eq!(
+            &cache.get(0, HeaderBlockCache::key(first), first).unwrap()[..],
+            b"again"
+        );
+
+        // one more block than fits: the cache starts over
+        let last = &lists[MAX_CACHED_BLOCKS];
+        cache.insert(0, HeaderBlockCache::key(last), last, roll(last[0].1));
+        assert!(cache.get(0, HeaderBlockCache::key(last), last).is_some());
+        assert!(cache.get(0, HeaderBlockCache::key(first), first).is_none());
+    }
+}
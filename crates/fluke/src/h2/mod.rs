@@ -5,5 +5,24 @@ mod server;
 pub use server::*;
 
 mod body;
+mod closed;
+mod cookie;
 mod encode;
+mod events;
+mod extension;
+pub use extension::{ExtensionFrame, ExtensionFrameError, ExtensionFrames};
+
+mod flow;
+pub use flow::WindowUpdates;
+mod frames;
+pub use frames::{FrameSink, FrameSource};
+
+mod header_cache;
+mod read;
 mod types;
+pub use types::{H2ConnectionError, StreamClosed};
+
+pub use fluke_h2_parse::{ErrorCode, KnownErrorCode, StreamId};
+
+/// Frame types and parsing, for use with [FrameSource] and [FrameSink]
+pub use fluke_h2_parse as parse;
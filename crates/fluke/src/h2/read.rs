@@ -0,0 +1,558 @@
+//! The read side of an HTTP/2 connection, as a state machine: it's handed
+//! whatever has been read so far, and either yields the next thing the
+//! client sent, or says it needs more. Frames come out well-formed (sized
+//! right, padding stripped, header blocks uninterrupted), and what they
+//! mean is left to the server.
+
+use fluke_buffet::{ReadOwned, Roll, RollMut};
+use fluke_h2_parse::{
+    self as parse, ContinuationFlags, DataFlags, Frame, FrameType, HeadersFlags, StreamId,
+};
+use tracing::{debug, trace};
+
+use super::types::H2ConnectionError;
+use crate::loans::Loans;
+
+/// How much to read at once while waiting for a frame header (which is only
+/// 9 bytes long, but small frames often come in batches)
+const FRAME_HEADER_READ_LEN: usize = 128;
+
+/// Where [FrameReader] is at
+#[derive(Debug)]
+pub(crate) enum ReadState {
+    /// Nothing read yet: the client must start with the connection preface
+    ExpectingPreface,
+
+    /// In between frames
+    FrameHeader,
+
+    /// Got the header of `frame`, waiting for its payload. If it came in
+    /// the middle of a header block, `header_block` is the stream that
+    /// block is for.
+    FramePayload {
+        frame: Frame,
+        header_block: Option<StreamId>,
+    },
+
+    /// The header block started on `stream_id` isn't done yet: only
+    /// CONTINUATION frames for that stream may come next, cf.
+    /// <https://httpwg.org/specs/rfc9113.html#CONTINUATION>
+    ContinuationExpected { stream_id: StreamId },
+}
+
+/// Something [FrameReader] read
+pub(crate) enum ReadEvent {
+    /// The client connection preface
+    Preface,
+
+    /// A whole frame. For DATA and HEADERS frames, padding is already
+    /// stripped from the payload.
+    Frame(Frame, Roll),
+}
+
+pub(crate) struct FrameReader {
+    state: ReadState,
+
+    /// Frames larger than this are a connection error
+    max_frame_size: u32,
+
+    /// Blocks lent out to handlers: reading into a new one waits for room
+    loans: Loans,
+}
+
+impl FrameReader {
+    pub(crate) fn new(max_frame_size: u32) -> Self {
+        Self {
+            state: ReadState::ExpectingPreface,
+            max_frame_size,
+            loans: Default::default(),
+        }
+    }
+
+    /// For when the preface was already read, or isn't coming: on the
+    /// client side of a connection
+    pub(crate) fn without_preface(max_frame_size: u32) -> Self {
+        Self {
+            state: ReadState::FrameHeader,
+            max_frame_size,
+            loans: Default::default(),
+        }
+    }
+
+    pub(crate) fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    pub(crate) fn set_loans(&mut self, loans: Loans) {
+        self.loans = loans;
+    }
+
+    /// How many bytes to have buffered before calling [FrameReader::step]
+    /// again
+    pub(crate) fn read_len(&self) -> usize {
+        match &self.state {
+            ReadState::ExpectingPreface => parse::PREFACE.len(),
+            ReadState::FrameHeader | ReadState::ContinuationExpected { .. } => {
+                FRAME_HEADER_READ_LEN
+            }
+            ReadState::FramePayload { frame, .. } => frame.len as usize,
+        }
+    }
+
+    /// Consumes as much of `input` as it needs to get to the next event.
+    /// Returns the rest of the input, along with the event, if there was
+    /// enough input for one.
+    pub(crate) fn step(
+        &mut self,
+        mut input: Roll,
+    ) -> Result<(Roll, Option<ReadEvent>), H2ConnectionError> {
+        loop {
+            match std::mem::replace(&mut self.state, ReadState::FrameHeader) {
+                ReadState::ExpectingPreface => match parse::preface(input.clone()) {
+                    Ok((rest, ())) => return Ok((rest, Some(ReadEvent::Preface))),
+                    Err(err) if err.is_incomplete() => {
+                        self.state = ReadState::ExpectingPreface;
+                        return Ok((input, None));
+                    }
+                    Err(err) => {
+                        return Err(H2ConnectionError::ReadError(eyre::eyre!(
+                            "invalid connection preface: {err}"
+                        )))
+                    }
+                },
+                ReadState::FrameHeader => {
+                    let (rest, frame) = match self.parse_header(input.clone())? {
+                        Some(res) => res,
+                        None => {
+                            self.state = ReadState::FrameHeader;
+                            return Ok((input, None));
+                        }
+                    };
+
+                    input = rest;
+                    self.state = ReadState::FramePayload {
+                        frame,
+                        header_block: None,
+                    };
+                }
+                ReadState::ContinuationExpected { stream_id } => {
+                    let (rest, frame) = match self.parse_header(input.clone())? {
+                        Some(res) => res,
+                        None => {
+                            self.state = ReadState::ContinuationExpected { stream_id };
+                            return Ok((input, None));
+                        }
+                    };
+
+                    input = rest;
+                    self.state = ReadState::FramePayload {
+                        frame,
+                        header_block: Some(stream_id),
+                    };
+                }
+                ReadState::FramePayload {
+                    frame,
+                    header_block,
+                } => {
+                    let frame_len = frame.len as usize;
+                    if input.len() < frame_len {
+                        self.state = ReadState::FramePayload {
+                            frame,
+                            header_block,
+                        };
+                        return Ok((input, None));
+                    }
+                    trace!("Reading payload of size {frame_len}... done!");
+
+                    // frames are only judged once they're complete: the peer
+                    // may still be writing the payload, and closing the
+                    // connection under it would lose our GOAWAY.
+                    check_sequence(&frame, header_block)?;
+
+                    let (payload, rest) = input.split_at(frame_len);
+                    let payload = strip_padding(&frame, payload)?;
+
+                    self.state = match frame.frame_type {
+                        FrameType::Headers(flags) if !flags.contains(HeadersFlags::EndHeaders) => {
+                            ReadState::ContinuationExpected {
+                                stream_id: frame.stream_id,
+                            }
+                        }
+                        FrameType::Continuation(flags)
+                            if !flags.contains(ContinuationFlags::EndHeaders) =>
+                        {
+                            ReadState::ContinuationExpected {
+                                stream_id: frame.stream_id,
+                            }
+                        }
+                        _ => ReadState::FrameHeader,
+                    };
+                    return Ok((rest, Some(ReadEvent::Frame(frame, payload))));
+                }
+            }
+        }
+    }
+
+    /// What the peer hanging up means, with `buffered` bytes left unread:
+    /// `Ok` if it was a clean place to stop.
+    pub(crate) fn eof(&self, buffered: usize) -> Result<(), H2ConnectionError> {
+        match &self.state {
+            ReadState::ExpectingPreface | ReadState::FrameHeader if buffered == 0 => Ok(()),
+            ReadState::ContinuationExpected { stream_id } if buffered == 0 => {
+                Err(H2ConnectionError::ExpectedContinuationFrame {
+                    stream_id: *stream_id,
+                    frame_type: None,
+                })
+            }
+            ReadState::FramePayload { frame, .. } => Err(H2ConnectionError::IncompleteFrame {
+                frame_type: frame.frame_type,
+                frame_size: frame.len,
+            }),
+            _ => Err(H2ConnectionError::ReadError(eyre::eyre!("unexpected EOF"))),
+        }
+    }
+
+    /// Reads from `transport_r` into `buf` until there's an event. Returns
+    /// `None` if the peer hung up cleanly.
+    pub(crate) async fn next_event(
+        &mut self,
+        mut buf: RollMut,
+        transport_r: &mut impl ReadOwned,
+    ) -> Result<Option<(RollMut, ReadEvent)>, H2ConnectionError> {
+        loop {
+            let (rest, ev) = self.step(buf.filled())?;
+            buf.keep(rest);
+            if let Some(ev) = ev {
+                return Ok(Some((buf, ev)));
+            }
+
+            if buf.cap() == 0 {
+                trace!("buf had zero cap, reserving");
+                self.loans.wait_for_room().await;
+                buf.reserve_wait()
+                    .await
+                    .map_err(|e| H2ConnectionError::ReadError(e.into()))?;
+            }
+            let read_limit = self.read_len() - buf.len();
+            trace!(state = ?self.state, len = %buf.len(), %read_limit, "reading");
+
+            let res;
+            (res, buf) = buf.read_into(read_limit, transport_r).await;
+            let n = res.map_err(|e| H2ConnectionError::ReadError(e.into()))?;
+            if n == 0 {
+                debug!("Peer hung up");
+                self.eof(buf.len())?;
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Parses a frame header, checking it against our settings. Returns
+    /// `None` if there isn't enough input yet.
+    fn parse_header(&self, input: Roll) -> Result<Option<(Roll, Frame)>, H2ConnectionError> {
+        let (rest, frame) = match Frame::parse(input) {
+            Ok(res) => res,
+            Err(err) if err.is_incomplete() => return Ok(None),
+            Err(err) => {
+                return Err(H2ConnectionError::ReadError(eyre::eyre!(
+                    "parsing error: {err}"
+                )))
+            }
+        };
+        debug!(?frame, "<");
+
+        if frame.len > self.max_frame_size {
+            return Err(H2ConnectionError::FrameTooLarge {
+                frame_type: frame.frame_type,
+                frame_size: frame.len,
+                max_frame_size: self.max_frame_size,
+            });
+        }
+        Ok(Some((rest, frame)))
+    }
+}
+
+/// Makes sure header blocks aren't interrupted, and that CONTINUATION
+/// frames only show up in header blocks.
+fn check_sequence(frame: &Frame, header_block: Option<StreamId>) -> Result<(), H2ConnectionError> {
+    match (header_block, frame.frame_type) {
+        (None, FrameType::Continuation(_)) => Err(H2ConnectionError::UnexpectedContinuationFrame {
+            stream_id: frame.stream_id,
+        }),
+        (None, _) => Ok(()),
+        (Some(stream_id), FrameType::Continuation(_)) if frame.stream_id != stream_id => {
+            Err(H2ConnectionError::ExpectedContinuationForStream {
+                stream_id,
+                continuation_stream_id: frame.stream_id,
+            })
+        }
+        (Some(_), FrameType::Continuation(_)) => Ok(()),
+        (Some(stream_id), frame_type) => Err(H2ConnectionError::ExpectedContinuationFrame {
+            stream_id,
+            frame_type: Some(frame_type),
+        }),
+    }
+}
+
+/// Removes the pad length and the padding from the payload of DATA and
+/// HEADERS frames that have the PADDED flag
+fn strip_padding(frame: &Frame, payload: Roll) -> Result<Roll, H2ConnectionError> {
+    let has_padding = match frame.frame_type {
+        FrameType::Data(flags) => flags.contains(DataFlags::Padded),
+        FrameType::Headers(flags) => flags.contains(HeadersFlags::Padded),
+        _ => false,
+    };
+    if !has_padding {
+        return Ok(payload);
+    }
+
+    if payload.is_empty() {
+        return Err(H2ConnectionError::PaddedFrameEmpty {
+            frame_type: frame.frame_type,
+        });
+    }
+
+    let (padding_length_roll, payload) = payload.split_at(1);
+    let padding_length = padding_length_roll[0] as usize;
+    if payload.len() < padding_length {
+        return Err(H2ConnectionError::PaddedFrameTooShort {
+            frame_type: frame.frame_type,
+            padding_length,
+            frame_size: frame.len,
+        });
+    }
+
+    // padding is on the end of the payload
+    let at = payload.len() - padding_length;
+    Ok(payload.split_at(at).0)
+}
+
+#[cfg(test)]
+mod tests {
+    use fluke_buffet::{Roll, RollMut};
+    use fluke_h2_parse::{
+        enumflags2::BitFlags, ContinuationFlags, DataFlags, Frame, FrameType, HeadersFlags,
+        StreamId, PREFACE,
+    };
+
+    use super::{FrameReader, ReadEvent, ReadState};
+    use crate::h2::H2ConnectionError;
+
+    fn roll(bytes: &[u8]) -> Roll {
+        let mut buf = RollMut::alloc().unwrap();
+        buf.put(bytes).unwrap();
+        buf.filled()
+    }
+
+    fn frame(frame_type: FrameType, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        Frame::new(frame_type, StreamId(stream_id))
+            .with_len(payload.len() as u32)
+            .write_into(&mut out)
+            .unwrap();
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// A reader that's past the preface
+    fn reader() -> FrameReader {
+        let mut reader = FrameReader::new(16384);
+        reader.state = ReadState::FrameHeader;
+        reader
+    }
+
+    /// Feeds the whole of `bytes`, expecting exactly one frame out of it
+    fn read_frame(reader: &mut FrameReader, bytes: &[u8]) -> (Frame, Roll) {
+        match reader.step(roll(bytes)).unwrap() {
+            (rest, Some(ReadEvent::Frame(frame, payload))) => {
+                assert!(rest.is_empty());
+                (frame, payload)
+            }
+            _ => panic!("expected a frame"),
+        }
+    }
+
+    #[test]
+    fn preface_then_frame_header() {
+        let mut reader = FrameReader::new(16384);
+        let (rest, ev) = reader.step(roll(&PREFACE[..10])).unwrap();
+        assert_eq!(rest.len(), 10);
+        assert!(ev.is_none());
+        assert!(matches!(reader.state, ReadState::ExpectingPreface));
+
+        let (rest, ev) = reader.step(roll(PREFACE)).unwrap();
+        assert!(rest.is_empty());
+        assert!(matches!(ev, Some(ReadEvent::Preface)));
+        assert!(matches!(reader.state, ReadState::FrameHeader));
+
+        let mut reader = FrameReader::new(16384);
+        assert!(reader.step(roll(b"GET / HTTP/1.1\r\n\r\n......")).is_err());
+    }
+
+    #[test]
+    fn frame_header_then_payload() {
+        let mut reader = reader();
+        let bytes = frame(FrameType::Data(Default::default()), 1, b"hello");
+
+        // a partial header isn't consumed
+        let (rest, ev) = reader.step(roll(&bytes[..4])).unwrap();
+        assert_eq!(rest.len(), 4);
+        assert!(ev.is_none());
+        assert!(matches!(reader.state, ReadState::FrameHeader));
+
+        // a complete header is, even if the payload isn't there yet
+        let (rest, ev) = reader.step(roll(&bytes[..11])).unwrap();
+        assert_eq!(&rest[..], b"he");
+        assert!(ev.is_none());
+        assert!(matches!(reader.state, ReadState::FramePayload { .. }));
+        assert_eq!(reader.read_len(), 5);
+
+        let (frame, payload) = read_frame(&mut reader, b"hello");
+        assert_eq!(frame.stream_id, StreamId(1));
+        assert_eq!(&payload[..], b"hello");
+        assert!(matches!(reader.state, ReadState::FrameHeader));
+    }
+
+    #[test]
+    fn frame_header_too_large() {
+        let mut reader = reader();
+        reader.set_max_frame_size(4);
+        let bytes = frame(FrameType::Data(Default::default()), 1, b"hello");
+        assert!(matches!(
+            reader.step(roll(&bytes)),
+            Err(H2ConnectionError::FrameTooLarge { frame_size: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn frame_payload_padding() {
+        let mut reader = reader();
+        let bytes = frame(FrameType::Data(DataFlags::Padded.into()), 1, b"\x02hi\0\0");
+        let (_frame, payload) = read_frame(&mut reader, &bytes);
+        assert_eq!(&payload[..], b"hi");
+
+        let bytes = frame(FrameType::Data(DataFlags::Padded.into()), 1, b"");
+        assert!(matches!(
+            reader.step(roll(&bytes)),
+            Err(H2ConnectionError::PaddedFrameEmpty { .. })
+        ));
+
+        let mut reader = self::reader();
+        let bytes = frame(
+            FrameType::Headers(HeadersFlags::Padded.into()),
+            1,
+            b"\x05hi",
+        );
+        assert!(matches!(
+            reader.step(roll(&bytes)),
+            Err(H2ConnectionError::PaddedFrameTooShort {
+                padding_length: 5,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn headers_then_continuation_expected() {
+        let mut reader = reader();
+        let bytes = frame(FrameType::Headers(Default::default()), 1, b"abc");
+        read_frame(&mut reader, &bytes);
+        assert!(matches!(
+            reader.state,
+            ReadState::ContinuationExpected {
+                stream_id: StreamId(1)
+            }
+        ));
+
+        let bytes = frame(FrameType::Continuation(Default::default()), 1, b"def");
+        read_frame(&mut reader, &bytes);
+        assert!(matches!(
+            reader.state,
+            ReadState::ContinuationExpected { .. }
+        ));
+
+        let flags: BitFlags<ContinuationFlags> = ContinuationFlags::EndHeaders.into();
+        let bytes = frame(FrameType::Continuation(flags), 1, b"ghi");
+        read_frame(&mut reader, &bytes);
+        assert!(matches!(reader.state, ReadState::FrameHeader));
+    }
+
+    #[test]
+    fn continuation_expected_rejects_other_frames() {
+        let mut reader = reader();
+        let bytes = frame(FrameType::Headers(Default::default()), 1, b"abc");
+        read_frame(&mut reader, &bytes);
+
+        // the frame is only rejected once it's complete
+        let bytes = frame(FrameType::Data(Default::default()), 1, b"x");
+        let (_rest, ev) = reader.step(roll(&bytes[..9])).unwrap();
+        assert!(ev.is_none());
+        assert!(matches!(
+            reader.state,
+            ReadState::FramePayload {
+                header_block: Some(StreamId(1)),
+                ..
+            }
+        ));
+        assert!(matches!(
+            reader.step(roll(&bytes[9..])),
+            Err(H2ConnectionError::ExpectedContinuationFrame {
+                frame_type: Some(FrameType::Data(_)),
+                ..
+            })
+        ));
+
+        let mut reader = self::reader();
+        let bytes = frame(FrameType::Headers(Default::default()), 1, b"abc");
+        read_frame(&mut reader, &bytes);
+
+        let bytes = frame(FrameType::Continuation(Default::default()), 3, b"");
+        assert!(matches!(
+            reader.step(roll(&bytes)),
+            Err(H2ConnectionError::ExpectedContinuationForStream {
+                stream_id: StreamId(1),
+                continuation_stream_id: StreamId(3),
+            })
+        ));
+    }
+
+    #[test]
+    fn continuation_outside_header_block() {
+        let mut reader = reader();
+        let bytes = frame(FrameType::Continuation(Default::default()), 1, b"");
+        assert!(matches!(
+            reader.step(roll(&bytes)),
+            Err(H2ConnectionError::UnexpectedContinuationFrame {
+                stream_id: StreamId(1)
+            })
+        ));
+    }
+
+    #[test]
+    fn eof_in_each_state() {
+        let reader = FrameReader::new(16384);
+        assert!(reader.eof(0).is_ok());
+        assert!(reader.eof(3).is_err());
+
+        let mut reader = self::reader();
+        assert!(reader.eof(0).is_ok());
+
+        let bytes = frame(FrameType::Data(Default::default()), 1, b"hello");
+        reader.step(roll(&bytes[..9])).unwrap();
+        assert!(matches!(
+            reader.eof(0),
+            Err(H2ConnectionError::IncompleteFrame { frame_size: 5, .. })
+        ));
+
+        let mut reader = self::reader();
+        let bytes = frame(FrameType::Headers(Default::default()), 1, b"abc");
+        read_frame(&mut reader, &bytes);
+        assert!(matches!(
+            reader.eof(0),
+            Err(H2ConnectionError::ExpectedContinuationFrame {
+                frame_type: None,
+                ..
+            })
+        ));
+    }
+}
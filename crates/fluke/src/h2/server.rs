@@ -2,18 +2,22 @@ use std::{
     borrow::Cow,
     collections::HashSet,
     io::Write,
+    panic::AssertUnwindSafe,
     rc::Rc,
     sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
 };
 
 use byteorder::{BigEndian, WriteBytesExt};
-use eyre::Context;
-use fluke_buffet::{Piece, PieceList, PieceStr, ReadOwned, Roll, RollMut, WriteOwned};
+use fluke_buffet::{
+    bufpool, intern, Piece, PieceList, PieceStr, ReadOwned, Roll, RollMut, WriteOwned,
+};
 use fluke_h2_parse::{
-    self as parse, enumflags2::BitFlags, nom::Finish, ContinuationFlags, DataFlags, Frame,
-    FrameType, HeadersFlags, PingFlags, PrioritySpec, Setting, SettingPairs, Settings,
-    SettingsFlags, StreamId, WindowUpdate,
+    self as parse, enumflags2::BitFlags, nom::Finish, ContinuationFlags, DataFlags,
+    EncodedFrameType, Frame, FrameType, HeadersFlags, PingFlags, PrioritySpec, RstStream, Setting,
+    SettingPairs, Settings, SettingsFlags, StreamId, WindowUpdate,
 };
+use futures_util::FutureExt;
 use http::{
     header,
     uri::{Authority, PathAndQuery, Scheme},
@@ -21,54 +25,196 @@ use http::{
 };
 use parse::IntoPiece;
 use smallvec::{smallvec, SmallVec};
-use tokio::sync::mpsc;
-use tracing::{debug, trace};
+use tokio::{sync::mpsc, time::Instant};
+use tracing::{debug, error, trace};
 
 use crate::{
+    body::once,
+    h1::parse::is_tchar,
     h2::{
         body::{H2Body, PieceOrTrailers, StreamIncoming, StreamIncomingItem},
+        closed::ClosedBy,
+        cookie,
         encode::H2Encoder,
+        events::EventQueues,
+        extension::{ExtensionFrame, ExtensionFrames},
+        flow::{self, BdpEstimator, WindowUpdates},
+        header_cache::HeaderBlockCache,
+        read::{FrameReader, ReadEvent},
         types::{
-            BodyOutgoing, ConnState, H2ConnectionError, H2Event, H2EventPayload, H2RequestError,
-            H2StreamError, HeadersOrTrailers, HeadersOutgoing, StreamOutgoing, StreamState,
+            BodyOutgoing, ConnState, H2ConnectionError, H2RequestError, H2StreamError,
+            HeadersOrTrailers, HeadersOutgoing, StreamCommand, StreamOutgoing, StreamState,
+            WriteCommand, WritePriority,
         },
     },
-    util::read_and_parse,
-    Headers, Method, Request, Responder, ServerDriver,
+    loans::Loans,
+    types::asterisk_decision,
+    util::panic_message,
+    CloseReason, ConnInfo, Deadline, ErrorPages, FieldDecision, HeaderDecision, Headers,
+    HeadersExt, LoanLimit, MemoryBudget, Method, Request, RequestTimings, Responder, ServeError,
+    ServerDriver,
 };
 
-use super::{body::SinglePieceBody, types::H2RequestOrConnectionError};
+use super::types::H2RequestOrConnectionError;
 
-pub const MAX_WINDOW_SIZE: i64 = u32::MAX as i64;
+pub const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
 
 /// HTTP/2 server configuration
+#[derive(Clone)]
 pub struct ServerConf {
     pub max_streams: Option<u32>,
+
+    /// Max number of bytes a single connection may hold in buffers. While
+    /// it's over, handlers are throttled until the peer catches up with
+    /// queued response data, and the connection receive window isn't given
+    /// back, so the peer can't send more request body data than it was
+    /// already allowed to. Header blocks going over it close the connection.
+    /// `None` means no limit. It also caps [ServerConf::adaptive_window].
+    pub memory_budget: Option<usize>,
+
+    /// How many read buffer blocks drivers may keep alive by holding on to
+    /// request body chunks, see [LoanLimit]. Reading frames stalls while
+    /// they're over it. `None` means no limit.
+    pub loan_limit: Option<LoanLimit>,
+
+    /// Max number of response body bytes queued for a single stream:
+    /// past that, writing body chunks waits until the peer has read some.
+    pub max_stream_backlog: usize,
+
+    /// Max length of the `:path` pseudo-header: requests with longer ones
+    /// get a 414 response.
+    pub max_uri_len: usize,
+
+    /// How often to send a PING frame to the peer, which keeps the
+    /// connection alive through middleboxes and measures its round-trip
+    /// time (see [crate::ConnInfo::rtt]). The first one goes out right
+    /// after the connection preface. `None` never pings.
+    pub ping_interval: Option<Duration>,
+
+    /// How long the peer has to acknowledge a PING before it's considered
+    /// gone, and the connection closed. Only applies when pinging, see
+    /// [ServerConf::ping_interval] and [ServerConf::adaptive_window].
+    /// `None` waits forever.
+    pub ping_timeout: Option<Duration>,
+
+    /// Upper bound for receive windows when they're tuned to the
+    /// connection's bandwidth-delay product, measured with PING frames.
+    /// `None` keeps them at their initial size (64KiB).
+    pub adaptive_window: Option<u32>,
+
+    /// How long the driver has to respond to each request, counting from
+    /// when its headers were read. See [crate::Deadline]. `None` means no
+    /// limit.
+    pub request_timeout: Option<Duration>,
+
+    /// Whether to serve frames a client sends before its SETTINGS frame,
+    /// using default settings for it until they arrive. The spec requires
+    /// SETTINGS to come right after the preface, but some clients open
+    /// streams first. When `false`, the default, those clients get a
+    /// PROTOCOL_ERROR.
+    pub lenient_settings: bool,
+
+    /// How long a connection may stay idle (no open streams, and nothing
+    /// but PING frames from the peer) before it's sent a GOAWAY with
+    /// NO_ERROR and closed. `None` keeps idle connections open.
+    pub idle_timeout: Option<Duration>,
+
+    /// Whether to join a request's `cookie` headers into one, with `"; "`
+    /// between values, before handing it to the driver. HTTP/2 clients may
+    /// send each cookie in its own field (RFC 9113, section 8.2.3).
+    pub join_cookies: bool,
+
+    /// Bodies for the error responses the server sends on its own, see
+    /// [ErrorPages]
+    pub error_pages: ErrorPages,
+
+    /// The `allow` header the server answers `OPTIONS *` requests with, see
+    /// [crate::h1::ServerConf::options_allow]
+    pub options_allow: Option<Cow<'static, str>>,
+
+    /// When the stream windows of request bodies are given back to the
+    /// peer, see [WindowUpdates]. The connection window is always given
+    /// back on receipt, so that a slow handler only holds back its own
+    /// stream.
+    pub window_updates: WindowUpdates,
 }
 
 impl Default for ServerConf {
     fn default() -> Self {
         Self {
             max_streams: Some(32),
+            memory_budget: Some(4 * 1024 * 1024),
+            loan_limit: Some(Default::default()),
+            max_stream_backlog: 256 * 1024,
+            max_uri_len: 8 * 1024,
+            ping_interval: None,
+            ping_timeout: Some(Duration::from_secs(20)),
+            adaptive_window: None,
+            request_timeout: None,
+            lenient_settings: false,
+            idle_timeout: None,
+            join_cookies: false,
+            error_pages: Default::default(),
+            options_allow: None,
+            window_updates: Default::default(),
         }
     }
 }
 
 pub async fn serve(
+    transport: (impl ReadOwned, impl WriteOwned),
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+) -> Result<CloseReason, ServeError> {
+    serve_with(transport, conf, client_buf, driver, Default::default()).await
+}
+
+/// Like [serve], for a connection the caller knows more about, e.g. what
+/// its TLS handshake established: see [ConnInfo::with_tls]. Requests get a
+/// handle to `conn`, which also records why the connection was closed, see
+/// [CloseReason].
+pub async fn serve_with(
     (transport_r, transport_w): (impl ReadOwned, impl WriteOwned),
     conf: Rc<ServerConf>,
     client_buf: RollMut,
     driver: Rc<impl ServerDriver + 'static>,
-) -> eyre::Result<()> {
+    conn: ConnInfo,
+) -> Result<CloseReason, ServeError> {
     let mut state = ConnState::default();
+    state.conn_info = conn.clone();
     state.self_settings.max_concurrent_streams = conf.max_streams;
-
-    let mut cx = ServerContext::new(driver.clone(), state, transport_w)?;
-    cx.work(client_buf, transport_r).await?;
-    cx.transport_w.shutdown().await?;
+    state.budget = MemoryBudget::new(conf.memory_budget);
+    state.loans = Loans::new(conf.loan_limit);
+    state.max_stream_backlog = conf.max_stream_backlog;
+    state.max_uri_len = conf.max_uri_len;
+    state.ping_interval = conf.ping_interval;
+    state.ping_timeout = conf.ping_timeout;
+    state.adaptive_window = conf.adaptive_window;
+    state.request_timeout = conf.request_timeout;
+    state.lenient_settings = conf.lenient_settings;
+    state.idle_timeout = conf.idle_timeout;
+    state.join_cookies = conf.join_cookies;
+    state.error_pages = conf.error_pages.clone();
+    state.options_allow = conf.options_allow.clone();
+    state.window_updates = conf.window_updates;
+    state.conn_info.set_max_streams(conf.max_streams);
+
+    let res = async {
+        let mut cx = ServerContext::new(driver.clone(), state, transport_w)?;
+        let reason = cx.work(client_buf, transport_r).await?;
+        cx.transport_w.shutdown().await?;
+        Ok::<_, ServeError>(reason)
+    }
+    .await;
+    let reason = match &res {
+        Ok(reason) => reason.clone(),
+        Err(e) => e.close_reason(),
+    };
+    crate::close::connection_closed(&driver, &conn, reason);
 
     debug!("finished serving");
-    Ok(())
+    res
 }
 
 /// Reads and processes h2 frames from the client.
@@ -78,6 +224,7 @@ pub(crate) struct ServerContext<D: ServerDriver + 'static, W: WriteOwned> {
 
     hpack_dec: fluke_hpack::Decoder<'static>,
     hpack_enc: fluke_hpack::Encoder<'static>,
+    hpack_cache: HeaderBlockCache,
     out_scratch: RollMut,
 
     /// Whether we've received a GOAWAY frame.
@@ -87,30 +234,61 @@ pub(crate) struct ServerContext<D: ServerDriver + 'static, W: WriteOwned> {
     /// allow direct access from context methods
     transport_w: W,
 
-    ev_tx: mpsc::Sender<H2Event>,
-    ev_rx: mpsc::Receiver<H2Event>,
+    events: EventQueues,
+
+    /// When to send the next PING frame, if we're pinging at all
+    next_ping: Option<Instant>,
+
+    /// The payload of the PING frame the peer hasn't acknowledged yet, and
+    /// when we sent it
+    ping_in_flight: Option<(u64, Instant)>,
+    pings_sent: u64,
+
+    /// When the peer last sent something other than a PING, or a handler
+    /// last wrote something, see [ServerConf::idle_timeout]
+    last_activity: Instant,
+
+    /// Only with `adaptive_window`
+    bdp: Option<BdpEstimator>,
 }
 
 impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
-    pub(crate) fn new(driver: Rc<D>, state: ConnState, transport_w: W) -> eyre::Result<Self> {
+    pub(crate) fn new(
+        driver: Rc<D>,
+        state: ConnState,
+        transport_w: W,
+    ) -> Result<Self, bufpool::Error> {
         let mut hpack_dec = fluke_hpack::Decoder::new();
         hpack_dec
             .set_max_allowed_table_size(Settings::default().header_table_size.try_into().unwrap());
 
         let hpack_enc = fluke_hpack::Encoder::new();
 
-        let (ev_tx, ev_rx) = tokio::sync::mpsc::channel::<H2Event>(32);
+        // a window the budget can't hold would only get the connection
+        // throttled
+        let bdp = state.adaptive_window.map(|max_window| {
+            let max_window = match state.budget.limit() {
+                Some(limit) => max_window.min(limit.try_into().unwrap_or(u32::MAX)),
+                None => max_window,
+            };
+            BdpEstimator::new(max_window)
+        });
 
         Ok(Self {
             driver,
-            ev_tx,
-            ev_rx,
+            events: EventQueues::new(),
             state,
             hpack_dec,
             hpack_enc,
+            hpack_cache: Default::default(),
             out_scratch: RollMut::alloc()?,
             goaway_recv: false,
             transport_w,
+            next_ping: None,
+            ping_in_flight: None,
+            pings_sent: 0,
+            last_activity: Instant::now(),
+            bdp,
         })
     }
 
@@ -119,21 +297,21 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         &mut self,
         mut client_buf: RollMut,
         mut transport_r: impl ReadOwned,
-    ) -> eyre::Result<()> {
+    ) -> Result<CloseReason, ServeError> {
+        let mut reader = FrameReader::new(self.state.self_settings.max_frame_size);
+        reader.set_loans(self.state.loans.clone());
+
         // first read the preface
         {
-            (client_buf, _) = match read_and_parse(
-                parse::preface,
-                &mut transport_r,
-                client_buf,
-                parse::PREFACE.len(),
-            )
-            .await?
+            (client_buf, _) = match reader
+                .next_event(client_buf, &mut transport_r)
+                .await
+                .map_err(|e| ServeError::Read(e.into()))?
             {
-                Some((client_buf, frame)) => (client_buf, frame),
+                Some((client_buf, ev)) => (client_buf, ev),
                 None => {
                     debug!("h2 client closed connection before sending preface");
-                    return Ok(());
+                    return Ok(CloseReason::ClientEof);
                 }
             };
         }
@@ -164,7 +342,10 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 .await?;
         }
 
+        self.driver.on_h2_connection(self.extension_frames());
+
         let mut goaway_err: Option<H2ConnectionError> = None;
+        let mut reason = CloseReason::ClientEof;
 
         {
             // read frames and send them into an mpsc buffer of size 1
@@ -175,6 +356,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             let max_frame_size = Rc::new(AtomicU32::new(self.state.self_settings.max_frame_size));
 
             let mut deframe_task = std::pin::pin!(Self::deframe_loop(
+                reader,
                 client_buf,
                 transport_r,
                 tx,
@@ -202,7 +384,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
 
                                 debug!(%should_ignore_err, "deciding whether or not to propagate deframer error");
                                 if !should_ignore_err {
-                                    return Err(e.wrap_err("h2 io"));
+                                    return Err(ServeError::Read(e.wrap_err("h2 io")));
                                 }
                             },
                             e => {
@@ -213,10 +395,13 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     }
 
                     if let Err(e) = (&mut process_task).await {
-                        // what about the GOAWAY?
-
                         debug!("h2 process task finished with error: {e}");
-                        return Err(e).wrap_err("h2 process");
+                        // the deframer's error came first, and is likely
+                        // what made the process task fail: that's the one
+                        // to report in the GOAWAY.
+                        if goaway_err.is_none() {
+                            return Err(e.into());
+                        }
                     }
                 }
                 res = &mut process_task => {
@@ -230,11 +415,20 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         }
 
         if let Some(err) = goaway_err {
+            reason = err.close_reason();
             let error_code = err.as_known_error_code();
             debug!("Connection error: {err} ({err:?}) (code {error_code:?})");
 
             // TODO: don't heap-allocate here
-            let additional_debug_data = format!("{err}").into_bytes();
+            let mut additional_debug_data = match err.resource_reason() {
+                Some(reason) => self
+                    .state
+                    .budget
+                    .close_summary(&reason, self.state.opened.elapsed()),
+                None => format!("{err}"),
+            }
+            .into_bytes();
+            additional_debug_data.truncate(self.state.peer_settings.max_frame_size as usize - 8);
 
             // TODO: figure out graceful shutdown: this would involve sending a goaway
             // before this point, and processing all the connections we've accepted
@@ -253,109 +447,29 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             self.write_frame(frame, PieceList::single(payload)).await?;
         }
 
-        Ok(())
+        Ok(reason)
     }
 
     async fn deframe_loop(
+        mut reader: FrameReader,
         mut client_buf: RollMut,
         mut transport_r: impl ReadOwned,
         tx: mpsc::Sender<(Frame, Roll)>,
         max_frame_size: Rc<AtomicU32>,
     ) -> Result<(), H2ConnectionError> {
-        'read_frames: loop {
-            const MAX_FRAME_HEADER_SIZE: usize = 128;
-            let frame;
-            trace!("Reading frame... Buffer length: {}", client_buf.len());
-            let frame_res = read_and_parse(
-                Frame::parse,
-                &mut transport_r,
-                client_buf,
-                MAX_FRAME_HEADER_SIZE,
-            )
-            .await;
-
-            let maybe_frame = match frame_res {
-                Ok(inner) => inner,
-                Err(e) => return Err(H2ConnectionError::ReadError(e)),
-            };
-            (client_buf, frame) = match maybe_frame {
-                Some((client_buf, frame)) => (client_buf, frame),
-                None => {
-                    debug!("Peer hung up");
-                    break 'read_frames;
-                }
-            };
-            trace!(
-                "Reading frame... done! New buffer length: {}",
-                client_buf.len()
-            );
-            debug!(?frame, "<");
-
-            let max_frame_size = max_frame_size.load(Ordering::Relaxed);
-            if frame.len > max_frame_size {
-                return Err(H2ConnectionError::FrameTooLarge {
-                    frame_type: frame.frame_type,
-                    frame_size: frame.len,
-                    max_frame_size,
-                });
-            }
+        loop {
+            reader.set_max_frame_size(max_frame_size.load(Ordering::Relaxed));
 
-            trace!(
-                "Reading payload of size {}... Buffer length: {}",
-                frame.len,
-                client_buf.len()
-            );
-            let mut payload;
-            (client_buf, payload) = match read_and_parse(
-                nom::bytes::streaming::take(frame.len as usize),
-                &mut transport_r,
-                client_buf,
-                frame.len as usize,
-            )
-            .await?
-            {
-                Some((client_buf, payload)) => (client_buf, payload),
-                None => {
-                    return Err(H2ConnectionError::IncompleteFrame {
-                        frame_type: frame.frame_type,
-                        frame_size: frame.len,
-                    })
-                }
+            let ev;
+            (client_buf, ev) = match reader.next_event(client_buf, &mut transport_r).await? {
+                Some(res) => res,
+                None => break,
             };
-            trace!(
-                "Reading payload... done! New buffer length: {}",
-                client_buf.len()
-            );
-
-            let has_padding = match frame.frame_type {
-                FrameType::Data(flags) => flags.contains(DataFlags::Padded),
-                FrameType::Headers(flags) => flags.contains(HeadersFlags::Padded),
-                _ => false,
+            let (frame, payload) = match ev {
+                ReadEvent::Frame(frame, payload) => (frame, payload),
+                ReadEvent::Preface => unreachable!("the preface was read before"),
             };
 
-            if has_padding {
-                if payload.is_empty() {
-                    return Err(H2ConnectionError::PaddedFrameEmpty {
-                        frame_type: frame.frame_type,
-                    });
-                }
-
-                let padding_length_roll;
-                (padding_length_roll, payload) = payload.split_at(1);
-                let padding_length = padding_length_roll[0] as usize;
-                if payload.len() < padding_length {
-                    return Err(H2ConnectionError::PaddedFrameTooShort {
-                        frame_type: frame.frame_type,
-                        padding_length,
-                        frame_size: frame.len,
-                    });
-                }
-
-                // padding is on the end of the payload
-                let at = payload.len() - padding_length;
-                (payload, _) = payload.split_at(at);
-            }
-
             if tx.send((frame, payload)).await.is_err() {
                 debug!("h2 deframer: receiver dropped, closing connection");
                 return Ok(());
@@ -369,12 +483,33 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         &mut self,
         mut rx: mpsc::Receiver<(Frame, Roll)>,
     ) -> Result<(), H2ConnectionError> {
+        self.next_ping = self.state.ping_interval.map(|_| Instant::now());
+
         loop {
+            // handlers spawned by the last frame see themselves counted
+            self.state
+                .conn_info
+                .set_open_streams(self.state.streams.len() as u32);
+
+            let idle_deadline = self
+                .state
+                .idle_timeout
+                .filter(|_| self.state.streams.is_empty())
+                .map(|timeout| self.last_activity + timeout);
+            let ping_deadline = self
+                .state
+                .ping_timeout
+                .zip(self.ping_in_flight)
+                .map(|(timeout, (_, sent_at))| sent_at + timeout);
+
             tokio::select! {
                 biased;
 
                 maybe_frame = rx.recv() => {
                     if let Some((frame, payload)) = maybe_frame {
+                        if !matches!(frame.frame_type, FrameType::Ping(_)) {
+                            self.last_activity = Instant::now();
+                        }
                         self.process_frame(frame, payload, &mut rx).await?;
                     } else {
                         debug!("h2 process task: peer hung up");
@@ -382,22 +517,164 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     }
                 }
 
-                ev = self.ev_rx.recv() => {
-                    match ev {
-                        Some(ev) => self.handle_event(ev).await?,
-                        None => unreachable!("the context owns a copy of the sender, and this method has &mut self, so the sender can't be dropped while this method is running"),
-                    }
+                // when over budget, leave handlers blocked on sending us more
+                // body chunks until the peer has read enough of what's queued.
+                // resets still go through, they only free things up.
+                ev = self.events.recv(self.state.budget.is_exceeded()) => {
+                    self.last_activity = Instant::now();
+                    self.handle_event(ev).await?;
                 }
 
                 _ = self.state.send_data_maybe.notified() => {
                     self.send_data_maybe().await?;
                 }
+
+                _ = self.state.budget.back_under() => {
+                    self.replenish_conn_window().await?;
+                }
+
+                _ = tokio::time::sleep_until(self.next_ping.unwrap_or_else(Instant::now)), if self.next_ping.is_some() => {
+                    self.send_ping().await?;
+                }
+
+                _ = tokio::time::sleep_until(ping_deadline.unwrap_or_else(Instant::now)), if ping_deadline.is_some() => {
+                    // whether the peer is gone or stuck, there's no point
+                    // in keeping its streams around
+                    return Err(H2ConnectionError::PingTimeout {
+                        timeout: self.state.ping_timeout.unwrap_or_default(),
+                    });
+                }
+
+                _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                    // goes out as a GOAWAY with NO_ERROR
+                    return Err(H2ConnectionError::Idle {
+                        timeout: self.state.idle_timeout.unwrap_or_default(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A handle for the driver to send extension frames with
+    fn extension_frames(&self) -> ExtensionFrames {
+        ExtensionFrames::new(self.events.stream(StreamId::CONNECTION))
+    }
+
+    async fn send_ping(&mut self) -> Result<(), H2ConnectionError> {
+        let now = Instant::now();
+        self.next_ping = self.state.ping_interval.map(|interval| now + interval);
+
+        // only one at a time, so that each ack tells us a round-trip time
+        if self.ping_in_flight.is_some() {
+            debug!("previous PING not acknowledged yet, not sending another");
+            return Ok(());
+        }
+
+        self.pings_sent += 1;
+        let payload = self.pings_sent;
+        self.ping_in_flight = Some((payload, now));
+        if let Some(bdp) = self.bdp.as_mut() {
+            bdp.on_ping_sent();
+        }
+
+        let frame =
+            Frame::new(FrameType::Ping(Default::default()), StreamId::CONNECTION).with_len(8);
+        let payload = Piece::from(payload.to_be_bytes().to_vec());
+        self.write_frame(frame, PieceList::single(payload)).await?;
+        Ok(())
+    }
+
+    /// Raises the receive window of the connection and of every stream,
+    /// see [BdpEstimator]
+    async fn grow_recv_windows(&mut self, window: u32) -> Result<(), H2ConnectionError> {
+        let delta = window - self.state.self_settings.initial_window_size;
+        debug!(%window, %delta, "growing receive windows");
+
+        // the peer applies the new initial window size to open streams too
+        self.state.self_settings.initial_window_size = window;
+        for ss in self.state.streams.values_mut() {
+            if let Some(incoming) = ss.incoming_mut() {
+                incoming.capacity += delta as i64;
             }
         }
+        let payload = SettingPairs(&[(Setting::InitialWindowSize, window)])
+            .into_piece(&mut self.out_scratch)
+            .map_err(|e| eyre::eyre!(e))?;
+        let frame = Frame::new(
+            FrameType::Settings(Default::default()),
+            StreamId::CONNECTION,
+        );
+        self.write_frame(frame, PieceList::single(payload)).await?;
+
+        // ...but not to the connection's
+        self.state.incoming_capacity += delta as i64;
+        self.send_window_update(StreamId::CONNECTION, delta).await
+    }
 
+    /// Gives the peer its connection receive window back once it runs low,
+    /// unless the connection is over its memory budget: then it's held back
+    /// until the budget frees up, see [ServerConf::memory_budget]
+    async fn replenish_conn_window(&mut self) -> Result<(), H2ConnectionError> {
+        if self.state.budget.is_exceeded() {
+            return Ok(());
+        }
+        let window = self.state.self_settings.initial_window_size;
+        if let Some(increment) = flow::replenish(self.state.incoming_capacity, window) {
+            self.state.incoming_capacity += increment as i64;
+            self.send_window_update(StreamId::CONNECTION, increment)
+                .await?;
+        }
         Ok(())
     }
 
+    /// HPACK-encodes a header block, or reuses the encoding of an identical
+    /// one, see [HeaderBlockCache]
+    fn encode_header_block(
+        &mut self,
+        headers: &[(&[u8], &[u8])],
+    ) -> Result<Roll, H2ConnectionError> {
+        let generation = self.hpack_enc.generation();
+        let key = HeaderBlockCache::key(headers);
+        if let Some(payload) = self.hpack_cache.get(generation, key, headers) {
+            return Ok(payload);
+        }
+
+        assert_eq!(self.out_scratch.len(), 0);
+        self.hpack_enc
+            .encode_into(headers.iter().copied(), &mut self.out_scratch)
+            .map_err(H2ConnectionError::WriteError)?;
+        let payload = self.out_scratch.take_all();
+
+        // blocks that added entries to the dynamic table can't be
+        // replayed, but the next identical one will be indexed-only.
+        if self.hpack_enc.generation() == generation {
+            self.hpack_cache
+                .insert(generation, key, headers, payload.clone());
+        }
+        Ok(payload)
+    }
+
+    async fn send_window_update(
+        &mut self,
+        stream_id: StreamId,
+        increment: u32,
+    ) -> Result<(), H2ConnectionError> {
+        debug!(%stream_id, %increment, "Sending WindowUpdate");
+        let payload = self
+            .out_scratch
+            .put_to_roll(4, |mut slice| {
+                slice.write_u32::<BigEndian>(increment)?;
+                Ok(())
+            })
+            .unwrap();
+
+        let frame = Frame::new(FrameType::WindowUpdate, stream_id)
+            .with_len((payload.len()).try_into().unwrap());
+        self.write_frame(frame, PieceList::single(payload)).await
+    }
+
     async fn send_data_maybe(&mut self) -> Result<(), H2ConnectionError> {
         let mut not_pending: HashSet<StreamId> = Default::default();
 
@@ -405,6 +682,10 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         // borrow self mutably twice in 'each_stream
         // TODO: merge those frames! do a single writev_all call!
         let mut frames: Vec<(Frame, PieceList)> = vec![];
+        // trailers end their stream: they go after everything else
+        let mut trailer_frames: Vec<(Frame, PieceList)> = vec![];
+        // whether some stream could have written more this round
+        let mut more_data = false;
 
         let max_fram = self.state.peer_settings.max_frame_size as usize;
 
@@ -416,11 +697,6 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             .collect();
 
         'each_stream: for id in streams_with_pending_data {
-            if self.state.outgoing_capacity <= 0 {
-                // that's all we can do
-                break 'each_stream;
-            }
-
             let outgoing = self
                 .state
                 .streams
@@ -428,6 +704,12 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 .and_then(|ss| ss.outgoing_mut())
                 .expect("stream should not be in streams_with_pending_data if it's already closed / not in an outgoing state");
 
+            if self.state.outgoing_capacity <= 0 && outgoing.trailers.is_none() {
+                // that's all we can do for this one, trailers don't need
+                // any window
+                continue 'each_stream;
+            }
+
             debug!(conn_cap = %self.state.outgoing_capacity, strm_cap = %outgoing.capacity, %max_fram, "ready to write");
 
             if outgoing.headers.has_more_to_write() {
@@ -441,156 +723,147 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     continue 'each_stream;
                 }
 
-                'queue_header_frames: loop {
-                    debug!("writing headers...");
-
-                    let is_continuation =
-                        matches!(&outgoing.headers, HeadersOutgoing::WroteSome(_));
-                    let piece = outgoing.headers.take_piece();
-                    let piece_len = piece.len();
-
-                    if piece_len > max_fram {
-                        let write_size = max_fram;
-                        let (written, requeued) = piece.split_at(write_size);
-                        debug!(%write_size, requeued_len = %requeued.len(), "splitting headers");
-                        let frame_type = if is_continuation {
-                            FrameType::Continuation(Default::default())
-                        } else {
-                            FrameType::Headers(Default::default())
-                        };
-                        outgoing.headers = HeadersOutgoing::WroteSome(requeued);
-
-                        let frame = Frame::new(frame_type, id);
-                        frames.push((frame, PieceList::single(written)));
-                    } else {
-                        let frame_type = if is_continuation {
-                            FrameType::Continuation(
-                                BitFlags::<ContinuationFlags>::default()
-                                    | ContinuationFlags::EndHeaders,
-                            )
-                        } else {
-                            FrameType::Headers(
-                                BitFlags::<HeadersFlags>::default() | HeadersFlags::EndHeaders,
-                            )
-                        };
-
-                        let frame = Frame::new(frame_type, id);
-                        frames.push((frame, PieceList::single(piece)));
-
-                        break 'queue_header_frames;
-                    }
-                }
+                let block = outgoing.headers.take_piece();
+                queue_header_block(&mut frames, id, block, max_fram, false);
             }
 
-            let capacity = self.state.outgoing_capacity.min(outgoing.capacity) as usize;
-            // bytes written this turn, possibly over multiple frames
-            let mut total_bytes_written = 0;
-
-            if outgoing.body.has_more_to_write() {
-                'queue_body_frames: while total_bytes_written < capacity {
-                    // send as much body data as we can, respecting max frame size and
-                    // connection / stream capacity
-                    let mut plist = PieceList::default();
-                    let mut frame_len = 0;
-
-                    'build_frame: loop {
-                        let piece = match outgoing.body.pop_front() {
-                            None => break 'build_frame,
-                            Some(piece) => piece,
-                        };
-
-                        // do we need to split the piece because we don't have
-                        // enough capacity left / we hit the max frame size?
-                        let piece_len = piece.len();
-                        debug!(%piece_len, "popped a piece");
-
-                        let fram_size_if_full_piece = frame_len + piece_len;
+            let capacity = self.state.outgoing_capacity.min(outgoing.capacity).max(0) as usize;
 
-                        let cap_left = capacity - total_bytes_written;
-                        let max_this_fram = max_fram.min(cap_left);
-
-                        if fram_size_if_full_piece > max_this_fram {
-                            // we can't fit this piece in the current frame, so
-                            // we have to split it
-                            let write_size = max_this_fram - frame_len;
-                            let (written, requeued) = piece.split_at(write_size);
-                            frame_len += write_size;
-                            debug!(written_len = %written.len(), requeued_len = %requeued.len(), "splitting piece");
-
-                            plist.push_back(written);
-                            outgoing.body.push_front(requeued);
-
-                            break 'build_frame;
-                        } else {
-                            // we can write the full piece
-                            let write_size = piece_len;
-                            frame_len += write_size;
+            // a single DATA frame per stream and per round: the connection
+            // gets to answer whatever the peer sent (PING, SETTINGS) before we
+            // write more, and other streams get their turn.
+            'queue_body_frame: {
+                if capacity == 0 || !outgoing.body.has_more_to_write() {
+                    break 'queue_body_frame;
+                }
 
-                            plist.push_back(piece);
-                        }
+                // send as much body data as we can, respecting max frame size and
+                // connection / stream capacity
+                let plist = outgoing.body.take_front(max_fram.min(capacity));
+                let frame_len = plist.len();
+
+                let mut flags: BitFlags<DataFlags> = Default::default();
+                if outgoing.body.might_receive_more() || outgoing.trailers.is_some() {
+                    if frame_len == 0 {
+                        // the only time we want to send a zero-length frame
+                        // is if we have to send END_STREAM separately from
+                        // the last chunk (and trailers carry it themselves).
+                        break 'queue_body_frame;
                     }
+                } else {
+                    flags |= DataFlags::EndStream;
+                }
 
-                    let mut flags: BitFlags<DataFlags> = Default::default();
-                    if outgoing.body.might_receive_more() {
-                        if frame_len == 0 {
-                            // the only time we want to send a zero-length frame
-                            // is if we have to send END_STREAM separately from
-                            // the last chunk.
-                            break 'queue_body_frames;
-                        }
-                    } else {
-                        flags |= DataFlags::EndStream;
-                    }
+                let frame = Frame::new(FrameType::Data(flags), id);
+                debug!(?frame, %frame_len, "queuing");
+                frames.push((frame, plist));
+                outgoing.buffered.shrink(frame_len);
+                outgoing.backlog.dequeued(frame_len);
 
-                    let frame = Frame::new(FrameType::Data(flags), id);
-                    debug!(?frame, %frame_len, "queuing");
-                    frames.push((frame, plist));
-                    total_bytes_written += frame_len;
+                if !flags.contains(DataFlags::EndStream)
+                    && frame_len < capacity
+                    && outgoing.body.has_pieces()
+                {
+                    more_data = true;
+                }
+            }
 
-                    if flags.contains(DataFlags::EndStream) {
-                        break 'queue_body_frames;
-                    }
+            // trailers aren't flow-controlled, they only wait for the body
+            // to be out
+            let drained = match &outgoing.body {
+                BodyOutgoing::DoneReceiving(pieces) => pieces.is_empty(),
+                BodyOutgoing::DoneSending => true,
+                BodyOutgoing::StillReceiving(_) => false,
+            };
+            if drained {
+                if let Some(block) = outgoing.trailers.take() {
+                    outgoing.body = BodyOutgoing::DoneSending;
+                    queue_header_block(&mut trailer_frames, id, block, max_fram, true);
                 }
             }
         }
 
+        // HEADERS go before any DATA, so that a stream's response starts
+        // without waiting for other streams' bodies. the sort is stable, so
+        // CONTINUATION frames still follow their HEADERS.
+        frames.sort_by_key(|(frame, _)| WritePriority::of(&frame.frame_type));
+        frames.append(&mut trailer_frames);
+
+        // the round's frames leave together, in as few packets as possible
+        let corked = frames.len() > 1;
+        if corked {
+            self.transport_w
+                .cork()
+                .await
+                .map_err(H2ConnectionError::WriteError)?;
+        }
         for (frame, plist) in frames {
             debug!(?frame, plist_len = %plist.len(), "writing");
+            let ended = match frame.frame_type {
+                FrameType::Data(flags) => flags.contains(DataFlags::EndStream),
+                FrameType::Headers(flags) => flags.contains(HeadersFlags::EndStream),
+                _ => false,
+            };
+            let stream_id = frame.stream_id;
             self.write_frame(frame, plist).await?;
+
+            // the response is complete but nobody is going to read the rest
+            // of the request body: ask the peer to stop sending it, cf. RFC
+            // 9113, section 8.1
+            if ended
+                && matches!(
+                    self.state.streams.get(&stream_id),
+                    Some(StreamState::HalfClosedLocal { incoming }) if incoming.tx.is_closed()
+                )
+            {
+                self.rst(stream_id, H2StreamError::RequestBodyNotNeeded)
+                    .await?;
+            }
+        }
+
+        if corked {
+            self.transport_w
+                .uncork()
+                .await
+                .map_err(H2ConnectionError::WriteError)?;
         }
 
         for id in not_pending {
             self.state.streams_with_pending_data.remove(&id);
         }
 
+        if more_data {
+            // come back for the next round once incoming frames and events
+            // have been dealt with
+            self.state.send_data_maybe.notify_one();
+        }
+
         Ok(())
     }
 
-    async fn handle_event(&mut self, ev: H2Event) -> Result<(), H2ConnectionError> {
+    async fn handle_event(&mut self, ev: StreamCommand) -> Result<(), H2ConnectionError> {
         trace!(?ev, "handling event");
 
-        match ev.payload {
-            H2EventPayload::Headers(res) => {
-                let outgoing = match self
+        match ev.command {
+            WriteCommand::Headers(res) => {
+                match self
                     .state
                     .streams
                     .get_mut(&ev.stream_id)
                     .and_then(|s| s.outgoing_mut())
                 {
                     None => {
-                        // ignore the event then, but at this point we should
-                        // tell the sender to stop sending chunks, which is not
-                        // possible if they all share the same ev_tx
-                        // TODO: make it possible to propagate errors to the sender
+                        // the stream is gone: ignore the event, the encoder
+                        // finds out through its backlog handle on its next write
                         return Ok(());
                     }
-                    Some(outgoing) => outgoing,
+                    Some(outgoing) => {
+                        if !matches!(&outgoing.body, BodyOutgoing::StillReceiving(_)) {
+                            unreachable!("got headers too late")
+                        }
+                    }
                 };
 
-                if !matches!(&outgoing.body, BodyOutgoing::StillReceiving(_)) {
-                    unreachable!("got headers too late")
-                }
-
                 // TODO: don't allocate so much for headers. all `encode_into`
                 // wants is an `IntoIter`, we can definitely have a custom iterator
                 // that operates on all this instead of using a `Vec`.
@@ -608,12 +881,14 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     headers.push((name.as_str().as_bytes(), value));
                 }
 
-                assert_eq!(self.out_scratch.len(), 0);
-                self.hpack_enc
-                    .encode_into(headers, &mut self.out_scratch)
-                    .map_err(H2ConnectionError::WriteError)?;
-                let payload = self.out_scratch.take_all();
+                let payload = self.encode_header_block(&headers)?;
 
+                let outgoing = self
+                    .state
+                    .streams
+                    .get_mut(&ev.stream_id)
+                    .and_then(|s| s.outgoing_mut())
+                    .expect("stream was just checked");
                 outgoing.headers = HeadersOutgoing::WroteNone(payload.into());
                 self.state.streams_with_pending_data.insert(ev.stream_id);
                 if self.state.outgoing_capacity > 0 && outgoing.capacity > 0 {
@@ -621,7 +896,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     self.state.send_data_maybe.notify_one();
                 }
             }
-            H2EventPayload::BodyChunk(chunk) => {
+            WriteCommand::Data { piece, end } => {
                 let outgoing = match self
                     .state
                     .streams
@@ -629,53 +904,135 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     .and_then(|s| s.outgoing_mut())
                 {
                     None => {
-                        // ignore the event then, but at this point we should
-                        // tell the sender to stop sending chunks, which is not
-                        // possible if they all share the same ev_tx
-                        // TODO: make it possible to propagate errors to the sender
+                        // the stream is gone: ignore the event, the encoder
+                        // finds out through its backlog handle on its next write
                         return Ok(());
                     }
                     Some(outgoing) => outgoing,
                 };
 
-                // FIXME: this isn't great, because, due to biased polling, body pieces can pile
-                // up. when we've collected enough pieces for max frame size, we
-                // should really send them.
-                outgoing.body.push_back(chunk);
+                if !piece.is_empty() {
+                    // FIXME: this isn't great, because, due to biased polling, body pieces can pile
+                    // up. when we've collected enough pieces for max frame size, we
+                    // should really send them.
+                    outgoing.buffered.grow(piece.len());
+                    outgoing.backlog.queued(piece.len());
+                    outgoing.body.push_back(piece);
+
+                    self.state.streams_with_pending_data.insert(ev.stream_id);
+                    if self.state.outgoing_capacity > 0 && outgoing.capacity > 0 {
+                        // worth revisiting then!
+                        self.state.send_data_maybe.notify_one();
+                    }
+                }
 
-                self.state.streams_with_pending_data.insert(ev.stream_id);
-                if self.state.outgoing_capacity > 0 && outgoing.capacity > 0 {
-                    // worth revisiting then!
-                    self.state.send_data_maybe.notify_one();
+                if end {
+                    match &mut outgoing.body {
+                        BodyOutgoing::StillReceiving(pieces) => {
+                            let pieces = std::mem::take(pieces);
+                            if pieces.is_empty() {
+                                // we'll need to send a zero-length data frame
+                                self.state.send_data_maybe.notify_one();
+                            }
+                            outgoing.body = BodyOutgoing::DoneReceiving(pieces);
+                            debug!(stream_id = %ev.stream_id, outgoing_body = ?outgoing.body, "got body end");
+                        }
+                        BodyOutgoing::DoneReceiving(_) => {
+                            unreachable!("got body end twice")
+                        }
+                        BodyOutgoing::DoneSending => {
+                            unreachable!("got body end after we sent everything")
+                        }
+                    }
                 }
             }
-            H2EventPayload::BodyEnd => {
-                let outgoing = match self
+            WriteCommand::Trailers(trailers) => {
+                // encoding a block that never gets sent would throw the
+                // peer's HPACK decoder off, so check for the stream first
+                if self
                     .state
                     .streams
                     .get_mut(&ev.stream_id)
                     .and_then(|s| s.outgoing_mut())
+                    .is_none()
                 {
-                    None => return Ok(()),
-                    Some(outgoing) => outgoing,
-                };
+                    return Ok(());
+                }
+
+                let headers: Vec<(&[u8], &[u8])> = trailers
+                    .iter()
+                    .map(|(name, value)| (name.as_str().as_bytes(), value.as_bytes()))
+                    .collect();
+                let payload = self.encode_header_block(&headers)?;
 
+                let outgoing = self
+                    .state
+                    .streams
+                    .get_mut(&ev.stream_id)
+                    .and_then(|s| s.outgoing_mut())
+                    .expect("stream was just checked");
                 match &mut outgoing.body {
                     BodyOutgoing::StillReceiving(pieces) => {
-                        let pieces = std::mem::take(pieces);
-                        if pieces.is_empty() {
-                            // we'll need to send a zero-length data frame
-                            self.state.send_data_maybe.notify_one();
-                        }
-                        outgoing.body = BodyOutgoing::DoneReceiving(pieces);
-                        debug!(stream_id = %ev.stream_id, outgoing_body = ?outgoing.body, "got body end");
-                    }
-                    BodyOutgoing::DoneReceiving(_) => {
-                        unreachable!("got body end twice")
-                    }
-                    BodyOutgoing::DoneSending => {
-                        unreachable!("got body end after we sent everything")
+                        outgoing.body = BodyOutgoing::DoneReceiving(std::mem::take(pieces));
                     }
+                    _ => unreachable!("got trailers after the body end"),
+                }
+                outgoing.trailers = Some(payload.into());
+                debug!(stream_id = %ev.stream_id, outgoing_body = ?outgoing.body, "got trailers");
+
+                // trailers aren't flow-controlled: they may go as soon as
+                // the body is out, whatever the windows
+                self.state.streams_with_pending_data.insert(ev.stream_id);
+                self.state.send_data_maybe.notify_one();
+            }
+            WriteCommand::Extension(ext) => {
+                if ext.payload.len() > self.state.peer_settings.max_frame_size as usize {
+                    debug!(
+                        ?ext,
+                        "dropping extension frame larger than the peer's max frame size"
+                    );
+                    return Ok(());
+                }
+
+                let frame_type = FrameType::Unknown(EncodedFrameType {
+                    ty: ext.frame_type,
+                    flags: ext.flags,
+                });
+                self.write_frame(
+                    Frame::new(frame_type, ext.stream_id),
+                    PieceList::single(ext.payload),
+                )
+                .await?;
+            }
+            WriteCommand::Consumed(len) => {
+                let window = self.state.self_settings.initial_window_size;
+                let Some(incoming) = self
+                    .state
+                    .streams
+                    .get_mut(&ev.stream_id)
+                    .and_then(|ss| ss.incoming_mut())
+                else {
+                    // the peer is done sending, no need for more window
+                    return Ok(());
+                };
+
+                incoming.released += len;
+                if let Some(increment) = flow::release(incoming.capacity, incoming.released, window)
+                {
+                    incoming.capacity += increment as i64;
+                    incoming.released = 0;
+                    self.send_window_update(ev.stream_id, increment).await?;
+                }
+            }
+            WriteCommand::Reset(e) => {
+                if self
+                    .state
+                    .streams
+                    .get_mut(&ev.stream_id)
+                    .and_then(|s| s.outgoing_mut())
+                    .is_some()
+                {
+                    self.rst(ev.stream_id, e).await?;
                 }
             }
         }
@@ -732,32 +1089,13 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 }
 
                 if flags.contains(DataFlags::EndStream) {
-                    // we won't be sending any more data on this stream
-                    self.state
-                        .streams_with_pending_data
-                        .remove(&frame.stream_id);
-
-                    match ss.get_mut() {
-                        StreamState::Open { .. } => {
-                            let incoming = match std::mem::take(ss.get_mut()) {
-                                StreamState::Open { incoming, .. } => incoming,
-                                _ => unreachable!(),
-                            };
-                            // this avoid having to re-insert the stream in the map
-                            *ss.get_mut() = StreamState::HalfClosedLocal { incoming };
-                        }
-                        _ => {
-                            // transition to closed
-                            ss.remove();
-                            debug!(
-                                "Closed stream {} (wrote data w/EndStream), now have {} streams",
-                                frame.stream_id,
-                                self.state.streams.len()
-                            );
-                        }
-                    }
+                    self.end_stream_sent(frame.stream_id);
                 }
             }
+            FrameType::Headers(flags) if flags.contains(HeadersFlags::EndStream) => {
+                // trailers
+                self.end_stream_sent(frame.stream_id);
+            }
             FrameType::Settings(_) => {
                 // TODO: keep track of whether our new settings have been
                 // acknowledged
@@ -767,14 +1105,18 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             }
         };
 
-        // TODO: enforce max_frame_size from the peer settings, not just u32::max
+        // HEADERS and DATA are split to fit, extension frames are dropped if
+        // they don't, and everything else is small: this would be our bug.
+        let max_frame_size = self.state.peer_settings.max_frame_size;
         frame.len = payload
             .len()
             .try_into()
-            .map_err(|_| H2ConnectionError::FrameTooLarge {
+            .ok()
+            .filter(|len| *len <= max_frame_size)
+            .ok_or(H2ConnectionError::FrameTooLarge {
                 frame_type: frame.frame_type,
-                frame_size: payload.len() as _,
-                max_frame_size: u32::MAX,
+                frame_size: payload.len().try_into().unwrap_or(u32::MAX),
+                max_frame_size,
             })?;
         debug!(?frame, ">");
         let frame_roll = frame
@@ -798,12 +1140,57 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         Ok(())
     }
 
+    /// We wrote a frame with END_STREAM on `stream_id`: our side of it is
+    /// done
+    fn end_stream_sent(&mut self, stream_id: StreamId) {
+        // we won't be sending any more data on this stream
+        self.state.streams_with_pending_data.remove(&stream_id);
+
+        let std::collections::hash_map::Entry::Occupied(mut ss) =
+            self.state.streams.entry(stream_id)
+        else {
+            unreachable!("ending non-existent stream, this should never happen")
+        };
+        match ss.get_mut() {
+            StreamState::Open { .. } => {
+                let incoming = match std::mem::take(ss.get_mut()) {
+                    StreamState::Open { incoming, .. } => incoming,
+                    _ => unreachable!(),
+                };
+                // this avoid having to re-insert the stream in the map
+                *ss.get_mut() = StreamState::HalfClosedLocal { incoming };
+            }
+            _ => {
+                // transition to closed
+                ss.remove();
+                self.state
+                    .closed_streams
+                    .insert(stream_id, ClosedBy::EndStream);
+                debug!(
+                    "Closed stream {stream_id} (wrote END_STREAM), now have {} streams",
+                    self.state.streams.len()
+                );
+            }
+        }
+    }
+
     async fn process_frame(
         &mut self,
         frame: Frame,
         mut payload: Roll,
         rx: &mut mpsc::Receiver<(Frame, Roll)>,
     ) -> Result<(), H2ConnectionError> {
+        if !self.state.peer_settings_received
+            && !matches!(frame.frame_type, FrameType::Settings(s) if !s.contains(SettingsFlags::Ack))
+        {
+            if !self.state.lenient_settings {
+                return Err(H2ConnectionError::ExpectedSettingsFrame {
+                    frame_type: frame.frame_type,
+                });
+            }
+            debug!(frame_type = ?frame.frame_type, "frame arrived before the peer's SETTINGS, using defaults for now");
+        }
+
         match frame.frame_type {
             FrameType::Data(flags) => {
                 if frame.stream_id == StreamId::CONNECTION {
@@ -812,29 +1199,97 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     });
                 }
 
-                let ss = self.state.streams.get_mut(&frame.stream_id).ok_or(
-                    H2ConnectionError::StreamClosed {
-                        stream_id: frame.stream_id,
-                    },
-                )?;
+                // the whole frame counts against flow control windows, padding
+                // included, even though only the data makes it to the body
+                // (RFC 9113, section 6.9.1)
+                let flow_len = frame.len as i64;
+
+                // the connection window is shared by all streams, whatever
+                // state they're in
+                let next_cap = self.state.incoming_capacity - flow_len;
+                if next_cap < 0 {
+                    return Err(H2ConnectionError::WindowUnderflow {
+                        stream_id: StreamId::CONNECTION,
+                    });
+                }
+                self.state.incoming_capacity = next_cap;
+                self.replenish_conn_window().await?;
+                let window = self.state.self_settings.initial_window_size;
+                // data is flowing, it's a good time to start a sample
+                let sample = match self.bdp.as_mut() {
+                    Some(bdp) => {
+                        bdp.on_data(flow_len as usize);
+                        self.ping_in_flight.is_none() && bdp.wants_sample(window)
+                    }
+                    None => false,
+                };
+                if sample {
+                    self.send_ping().await?;
+                }
+
+                let ss = match self.state.streams.get_mut(&frame.stream_id) {
+                    Some(ss) => ss,
+                    None => {
+                        match self.state.closed_streams.get(frame.stream_id) {
+                            Some(ClosedBy::LocalReset) => {
+                                debug!(stream_id = %frame.stream_id, "Ignoring data for stream we reset");
+                            }
+                            Some(ClosedBy::PeerReset) => {
+                                self.rst(frame.stream_id, H2StreamError::StreamClosed)
+                                    .await?;
+                            }
+                            Some(ClosedBy::EndStream) | None => {
+                                return Err(H2ConnectionError::StreamClosed {
+                                    stream_id: frame.stream_id,
+                                });
+                            }
+                        }
+                        return Ok(());
+                    }
+                };
 
+                let mut stream_increment = None;
                 match ss {
                     StreamState::Open { incoming, .. }
                     | StreamState::HalfClosedLocal { incoming } => {
-                        let next_cap = incoming.capacity - payload.len() as i64;
+                        let next_cap = incoming.capacity - flow_len;
                         if next_cap < 0 {
                             return Err(H2ConnectionError::WindowUnderflow {
                                 stream_id: frame.stream_id,
                             });
                         }
                         incoming.capacity = next_cap;
-                        // TODO: give back capacity to peer at some point
+                        // no need to let the peer send more than the end of
+                        // the stream
+                        if !flags.contains(DataFlags::EndStream) {
+                            stream_increment = match self.state.window_updates {
+                                WindowUpdates::OnReceipt => flow::replenish(next_cap, window),
+                                WindowUpdates::OnRead => {
+                                    // the handler never sees padding, it can
+                                    // be given back right away
+                                    incoming.released += frame.len - payload.len() as u32;
+                                    flow::release(next_cap, incoming.released, window)
+                                }
+                            };
+                            if let Some(increment) = stream_increment {
+                                incoming.capacity += increment as i64;
+                                incoming.released = 0;
+                            }
+                        }
 
-                        if incoming
-                            .tx
-                            .send(Ok(PieceOrTrailers::Piece(payload.into())))
-                            .await
-                            .is_err()
+                        // this never blocks: handlers that don't read
+                        // their body must not stall the whole connection.
+                        // What they leave unread counts against the budget,
+                        // which holds back the connection window: the peer
+                        // stayed within what we advertised, that's not an
+                        // error. A frame that was all padding has nothing
+                        // for them.
+                        let charge = self.state.budget.charge(payload.len());
+                        if !payload.is_empty()
+                            && incoming
+                                .tx
+                                .send(Ok(PieceOrTrailers::Piece(payload.into(), charge)))
+                                .is_err()
                         {
                             debug!("TODO: The body is being ignored, we should reset the stream");
                         }
@@ -847,6 +1302,9 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                                 };
                                 *ss = StreamState::HalfClosedRemote { outgoing };
                             } else if self.state.streams.remove(&frame.stream_id).is_some() {
+                                self.state
+                                    .closed_streams
+                                    .insert(frame.stream_id, ClosedBy::EndStream);
                                 debug!(
                                     "Closed stream (read data w/EndStream) {}, now have {} streams",
                                     frame.stream_id,
@@ -865,6 +1323,10 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     }
                     StreamState::Transition => unreachable!(),
                 }
+
+                if let Some(increment) = stream_increment {
+                    self.send_window_update(frame.stream_id, increment).await?;
+                }
             }
             FrameType::Headers(flags) => {
                 if flags.contains(HeadersFlags::Priority) {
@@ -885,6 +1347,25 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 let mode;
 
                 match self.state.streams.get_mut(&frame.stream_id) {
+                    None if self
+                        .state
+                        .closed_streams
+                        .get(frame.stream_id)
+                        .is_some_and(|closed_by| closed_by != ClosedBy::EndStream) =>
+                    {
+                        // the header block still has to be decoded, it may
+                        // update the dynamic table
+                        headers_or_trailers = HeadersOrTrailers::Trailers;
+                        mode = ReadHeadersMode::Skip;
+                        if self.state.closed_streams.get(frame.stream_id)
+                            == Some(ClosedBy::PeerReset)
+                        {
+                            self.rst(frame.stream_id, H2StreamError::StreamClosed)
+                                .await?;
+                        } else {
+                            debug!(stream_id = %frame.stream_id, "Ignoring headers for stream we reset");
+                        }
+                    }
                     None => {
                         headers_or_trailers = HeadersOrTrailers::Headers;
                         debug!(
@@ -899,6 +1380,14 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                         }
 
                         match frame.stream_id.cmp(&self.state.last_stream_id) {
+                            std::cmp::Ordering::Less
+                                if self.state.closed_streams.get(frame.stream_id)
+                                    == Some(ClosedBy::EndStream) =>
+                            {
+                                return Err(H2ConnectionError::StreamClosed {
+                                    stream_id: frame.stream_id,
+                                });
+                            }
                             std::cmp::Ordering::Less => {
                                 // we're going back? we can't.
                                 return Err(
@@ -928,7 +1417,10 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                                 let num_streams_if_accept = self.state.streams.len() + 1;
 
                                 if num_streams_if_accept > max_concurrent_streams as _ {
-                                    // reset the stream, indicating we refused it
+                                    // reset the stream, indicating we refused it.
+                                    // last_stream_id stays where it is, so a
+                                    // GOAWAY tells the client it's safe to retry.
+                                    self.state.conn_info.record_refused_stream();
                                     self.rst(frame.stream_id, H2StreamError::RefusedStream)
                                         .await?;
 
@@ -977,31 +1469,60 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 {
                     match e {
                         H2RequestOrConnectionError::ConnectionError(e) => return Err(e),
+                        H2RequestOrConnectionError::RequestError(e)
+                            if matches!(headers_or_trailers, HeadersOrTrailers::Trailers)
+                                && !self.awaiting_response_headers(frame.stream_id) =>
+                        {
+                            // malformed trailers, but the handler already
+                            // started responding: all we can do is reset the
+                            // stream (RFC 9113, section 8.1.1)
+                            let stream_id = frame.stream_id;
+                            tracing::debug!(?e, %stream_id, "Resetting stream with malformed trailers");
+                            self.rst(stream_id, H2StreamError::MalformedTrailers)
+                                .await?;
+                        }
                         H2RequestOrConnectionError::RequestError(e) => {
                             let stream_id = frame.stream_id;
                             tracing::debug!(?e, %stream_id, "Responding to stream with error");
+                            if let Some(incoming) = self
+                                .state
+                                .streams
+                                .get_mut(&stream_id)
+                                .and_then(|ss| ss.incoming_mut())
+                            {
+                                // malformed trailers: the handler is still
+                                // reading the request body, let it know.
+                                _ = incoming
+                                    .tx
+                                    .send(Err(H2StreamError::MalformedTrailers.into()));
+                            }
                             // we need to insert it, otherwise `process_event` will ignore us
                             // sending headers, etc.
-                            self.state.streams.insert(
-                                stream_id,
-                                StreamState::HalfClosedRemote {
-                                    outgoing: self.state.mk_stream_outgoing(),
-                                },
-                            );
+                            let outgoing = self.state.mk_stream_outgoing();
+                            let backlog = outgoing.backlog.handle();
+                            self.state
+                                .streams
+                                .insert(stream_id, StreamState::HalfClosedRemote { outgoing });
                             // TODO: inserting/removing here is probably unnecessary.
 
                             // respond with status code
-                            let responder =
-                                Responder::new(H2Encoder::new(frame.stream_id, self.ev_tx.clone()));
+                            let responder = Responder::new(H2Encoder::new(
+                                self.events.stream(frame.stream_id),
+                                backlog,
+                                Default::default(),
+                                false,
+                            ));
+                            // the message is for debugging, an error page
+                            // is for users
+                            let (mut res, body) = self.state.error_pages.response(e.status);
+                            res.version = Version::HTTP_2;
+                            let body = if self.state.error_pages.get(e.status).is_none() {
+                                e.message
+                            } else {
+                                body
+                            };
                             responder
-                                .write_final_response_with_body(
-                                    crate::Response {
-                                        version: Version::HTTP_2,
-                                        status: e.status,
-                                        headers: Default::default(),
-                                    },
-                                    &mut SinglePieceBody::new(e.message),
-                                )
+                                .write_final_response_with_body(res, &mut crate::body::once(body))
                                 .await?;
 
                             // don't even store the stream state anywhere, just record the last
@@ -1048,30 +1569,50 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     .await?;
                     return Ok(());
                 }
-                // TODO: do something with the error code?
+                let (_, rst) = RstStream::parse(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                let error_code = rst.error_code;
 
                 match self.state.streams.remove(&frame.stream_id) {
                     None => {
+                        if let Some(closed_by) = self.state.closed_streams.get(frame.stream_id) {
+                            // it crossed our END_STREAM or RST_STREAM, or it's a
+                            // late one: never answer RST_STREAM with RST_STREAM
+                            debug!(stream_id = %frame.stream_id, ?closed_by, "Ignoring RstStream for closed stream");
+                            return Ok(());
+                        }
                         return Err(H2ConnectionError::RstStreamForUnknownStream {
                             stream_id: frame.stream_id,
-                        })
+                        });
                     }
                     Some(ss) => {
+                        self.state
+                            .closed_streams
+                            .insert(frame.stream_id, ClosedBy::PeerReset);
                         debug!(
                             "Closed stream (read RstStream) {}, now have {} streams",
                             frame.stream_id,
                             self.state.streams.len()
                         );
+                        self.state
+                            .streams_with_pending_data
+                            .remove(&frame.stream_id);
                         match ss {
-                            StreamState::Open { incoming, .. }
-                            | StreamState::HalfClosedLocal { incoming, .. } => {
+                            StreamState::Open { incoming, outgoing } => {
+                                outgoing.backlog.reset(error_code);
                                 _ = incoming
                                     .tx
-                                    .send(Err(H2StreamError::ReceivedRstStream.into()))
-                                    .await;
+                                    .send(Err(H2StreamError::ReceivedRstStream.into()));
                             }
-                            StreamState::HalfClosedRemote { .. } => {
-                                // good
+                            StreamState::HalfClosedLocal { incoming } => {
+                                _ = incoming
+                                    .tx
+                                    .send(Err(H2StreamError::ReceivedRstStream.into()));
+                            }
+                            StreamState::HalfClosedRemote { outgoing } => {
+                                // the handler may still be writing the response
+                                outgoing.backlog.reset(error_code);
                             }
                             StreamState::Transition => unreachable!(),
                         }
@@ -1115,6 +1656,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                         Ok(())
                     })
                     .map_err(H2ConnectionError::BadSettingValue)?;
+                    self.state.peer_settings_received = true;
 
                     let initial_window_size_delta =
                         (s.initial_window_size as i64) - (original_initial_window_size as i64);
@@ -1170,7 +1712,26 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 }
 
                 if flags.contains(PingFlags::Ack) {
-                    // TODO: check that payload matches the one we sent?
+                    match self.ping_in_flight {
+                        Some((sent, at)) if payload[..] == sent.to_be_bytes() => {
+                            self.ping_in_flight = None;
+                            let rtt = at.elapsed();
+                            self.state.conn_info.record_rtt(rtt);
+                            let srtt = self.state.conn_info.rtt().unwrap_or(rtt);
+                            debug!(?rtt, ?srtt, "PING acknowledged");
+
+                            let window = self.state.self_settings.initial_window_size;
+                            if let Some(window) = self
+                                .bdp
+                                .as_mut()
+                                .and_then(|bdp| bdp.on_ping_ack(rtt, srtt, window))
+                                .filter(|_| !self.state.budget.is_exceeded())
+                            {
+                                self.grow_recv_windows(window).await?;
+                            }
+                        }
+                        _ => debug!("ignoring acknowledgement for a PING we didn't send"),
+                    }
                     return Ok(());
                 }
 
@@ -1219,18 +1780,33 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     self.state.outgoing_capacity = new_capacity;
                     self.state.send_data_maybe.notify_one();
                 } else {
-                    let outgoing = match self
-                        .state
-                        .streams
-                        .get_mut(&frame.stream_id)
-                        .and_then(|ss| ss.outgoing_mut())
-                    {
-                        Some(ss) => ss,
-                        None => {
-                            return Err(H2ConnectionError::WindowUpdateForUnknownOrClosedStream {
-                                stream_id: frame.stream_id,
-                            });
-                        }
+                    let outgoing = match self.state.streams.get_mut(&frame.stream_id) {
+                        Some(ss) => match ss.outgoing_mut() {
+                            Some(outgoing) => outgoing,
+                            None => {
+                                // we're done sending, the peer doesn't know yet
+                                debug!(stream_id = %frame.stream_id, "Ignoring window update for half-closed (local) stream");
+                                return Ok(());
+                            }
+                        },
+                        None => match self.state.closed_streams.get(frame.stream_id) {
+                            Some(ClosedBy::EndStream | ClosedBy::LocalReset) => {
+                                debug!(stream_id = %frame.stream_id, "Ignoring window update for closed stream");
+                                return Ok(());
+                            }
+                            Some(ClosedBy::PeerReset) => {
+                                self.rst(frame.stream_id, H2StreamError::StreamClosed)
+                                    .await?;
+                                return Ok(());
+                            }
+                            None => {
+                                return Err(
+                                    H2ConnectionError::WindowUpdateForUnknownOrClosedStream {
+                                        stream_id: frame.stream_id,
+                                    },
+                                );
+                            }
+                        },
                     };
 
                     let new_capacity = outgoing.capacity + update.increment as i64;
@@ -1266,26 +1842,56 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             }
             FrameType::Unknown(ft) => {
                 trace!(
-                    "ignoring unknown frame with type 0x{:x}, flags 0x{:x}",
+                    "passing unknown frame with type 0x{:x}, flags 0x{:x} to the driver",
                     ft.ty,
                     ft.flags
                 );
+                let frame = ExtensionFrame {
+                    frame_type: ft.ty,
+                    flags: ft.flags,
+                    stream_id: frame.stream_id,
+                    payload: payload.into(),
+                };
+                self.driver
+                    .on_extension_frame(frame, &self.extension_frames());
             }
         }
 
         Ok(())
     }
 
+    /// Whether the handler for that stream has yet to give us response headers
+    fn awaiting_response_headers(&self, stream_id: StreamId) -> bool {
+        self.state
+            .streams
+            .get(&stream_id)
+            .and_then(|ss| ss.outgoing())
+            .is_some_and(|outgoing| matches!(outgoing.headers, HeadersOutgoing::WaitingForHeaders))
+    }
+
     /// Send a RST_STREAM frame to the peer.
     async fn rst(
         &mut self,
         stream_id: StreamId,
         e: H2StreamError,
     ) -> Result<(), H2ConnectionError> {
-        self.state.streams.remove(&stream_id);
-
         let error_code = e.as_known_error_code();
         debug!("Sending rst because: {e} (known error code: {error_code:?})");
+        self.state
+            .closed_streams
+            .insert(stream_id, ClosedBy::LocalReset);
+
+        if let Some(mut ss) = self.state.streams.remove(&stream_id) {
+            if let Some(outgoing) = ss.outgoing_mut() {
+                outgoing.backlog.reset(error_code.into());
+            }
+            if let Some(incoming) = ss.incoming_mut() {
+                // otherwise the request body would look complete
+                _ = incoming.tx.send(Err(e.into()));
+            }
+        }
+        self.state.streams_with_pending_data.remove(&stream_id);
+        self.events.discard(stream_id);
 
         debug!(%stream_id, ?error_code, "Sending RstStream");
         let payload = self
@@ -1312,6 +1918,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         payload: Roll,
         rx: &mut mpsc::Receiver<(Frame, Roll)>,
     ) -> Result<(), H2RequestOrConnectionError> {
+        let head_started = std::time::Instant::now();
         let end_stream = flags.contains(HeadersFlags::EndStream);
 
         enum Data {
@@ -1319,6 +1926,30 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             Multi(SmallVec<[Roll; 2]>),
         }
 
+        impl Data {
+            fn decode(
+                self,
+                hpack_dec: &mut fluke_hpack::Decoder<'static>,
+                cb: impl FnMut(Cow<[u8]>, Cow<[u8]>),
+            ) -> Result<(), H2ConnectionError> {
+                match self {
+                    Data::Single(payload) => hpack_dec.decode_with_cb(&payload[..], cb)?,
+                    Data::Multi(fragments) => {
+                        let total_len = fragments.iter().map(|f| f.len()).sum();
+                        // this is a slow path, let's do a little heap allocation. we could
+                        // be using `RollMut` for this, but it would probably need to resize
+                        // a bunch
+                        let mut payload = Vec::with_capacity(total_len);
+                        for frag in &fragments {
+                            payload.extend_from_slice(&frag[..]);
+                        }
+                        hpack_dec.decode_with_cb(&payload[..], cb)?
+                    }
+                }
+                Ok(())
+            }
+        }
+
         let data = if flags.contains(HeadersFlags::EndHeaders) {
             // good, no continuation frames needed
             Data::Single(payload)
@@ -1328,15 +1959,17 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             #[allow(unused, clippy::let_unit_value)]
             let flags = (); // don't accidentally use the `flags` variable
 
+            // continuation frames can go on forever, so account for them
+            let mut fragments_charge = self.state.budget.charge(payload.len());
             let mut fragments = smallvec![payload];
 
             loop {
+                // the frame reader only lets CONTINUATION frames for this
+                // stream through until the header block is done, and
+                // reports anything else as a connection error.
                 let (continuation_frame, continuation_payload) = match rx.recv().await {
                     Some(t) => t,
                     None => {
-                        // even though this error is "for a stream", it's a
-                        // connection error, because it means the peer doesn't
-                        // know how to speak HTTP/2.
                         return Err(H2ConnectionError::ExpectedContinuationFrame {
                             stream_id,
                             frame_type: None,
@@ -1344,27 +1977,22 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                         .into());
                     }
                 };
-
-                if stream_id != continuation_frame.stream_id {
-                    return Err(H2ConnectionError::ExpectedContinuationForStream {
-                        stream_id,
-                        continuation_stream_id: continuation_frame.stream_id,
-                    }
-                    .into());
-                }
-
-                let cont_flags = match continuation_frame.frame_type {
-                    FrameType::Continuation(flags) => flags,
-                    other => {
-                        return Err(H2ConnectionError::ExpectedContinuationFrame {
-                            stream_id,
-                            frame_type: Some(other),
-                        }
-                        .into())
-                    }
+                let FrameType::Continuation(cont_flags) = continuation_frame.frame_type else {
+                    unreachable!("frame reader let a non-CONTINUATION frame into a header block")
                 };
 
                 // add fragment
+                fragments_charge.grow(continuation_payload.len());
+                if let Some(limit) = self
+                    .state
+                    .budget
+                    .limit()
+                    .filter(|_| self.state.budget.is_exceeded())
+                {
+                    return Err(
+                        H2ConnectionError::HeaderBlockOverBudget { stream_id, limit }.into(),
+                    );
+                }
                 fragments.push(continuation_payload);
 
                 if cont_flags.contains(ContinuationFlags::EndHeaders) {
@@ -1377,8 +2005,10 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         };
 
         if matches!(mode, ReadHeadersMode::Skip) {
-            // that's all we need to do: we're not actually validating the
-            // headers, we already send a RST
+            // we're not validating the headers, we already sent a RST (or
+            // are ignoring the stream), but decoding them may update the
+            // dynamic table, which later header blocks rely on.
+            data.decode(&mut self.hpack_dec, |_, _| {})?;
             return Ok(());
         }
 
@@ -1396,6 +2026,8 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             // huffman decoder's state, etc.
             let mut req_error: Option<H2RequestError> = None;
             let mut saw_regular_header = false;
+            let max_uri_len = self.state.max_uri_len;
+            let driver = &self.driver;
 
             let on_header_pair = |key: Cow<[u8]>, value: Cow<[u8]>| {
                 if req_error.is_some() {
@@ -1411,7 +2043,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     std::str::from_utf8(&value).unwrap_or("<non-utf8-value>"),
                 );
 
-                if &key[..1] == b":" {
+                if key.first() == Some(&b':') {
                     if saw_regular_header {
                         req_error = Some(H2RequestError {
                             status: StatusCode::BAD_REQUEST,
@@ -1431,15 +2063,14 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     }
 
                     // it's a pseudo-header!
-                    // TODO: reject headers that occur after pseudo-headers
                     match &key[1..] {
                         b"method" => {
-                            let value: PieceStr = match Piece::from(value.to_vec()).to_str() {
-                                Ok(p) => p,
-                                Err(_) => {
+                            let value: PieceStr = match intern::piece(&value).to_str() {
+                                Ok(p) if !p.is_empty() && p.bytes().all(is_tchar) => p,
+                                _ => {
                                     req_error = Some(H2RequestError {
                                         status: StatusCode::BAD_REQUEST,
-                                        message: "bad request: invalid ':method' pseudo-header: not valid utf-8, so certainly not a valid method like POST, GET, OPTIONS, CONNECT, PROPFIND, etc.".into(),
+                                        message: "bad request: invalid ':method' pseudo-header: not a token, so certainly not a valid method like POST, GET, OPTIONS, CONNECT, PROPFIND, etc. (RFC 9110, section 9.1)".into(),
                                     });
                                     return;
                                 }
@@ -1452,7 +2083,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                             }
                         }
                         b"scheme" => {
-                            let value: PieceStr = match Piece::from(value.to_vec()).to_str() {
+                            let value: PieceStr = match intern::piece(&value).to_str() {
                                 Ok(p) => p,
                                 Err(_) => {
                                     req_error = Some(H2RequestError {
@@ -1471,7 +2102,17 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                             }
                         }
                         b"path" => {
-                            let value: PieceStr = match Piece::from(value.to_vec()).to_str() {
+                            if value.len() > max_uri_len {
+                                req_error = Some(H2RequestError {
+                                    status: StatusCode::URI_TOO_LONG,
+                                    message:
+                                        "':path' pseudo-header longer than the configured limit"
+                                            .into(),
+                                });
+                                return;
+                            }
+
+                            let value: PieceStr = match intern::piece(&value).to_str() {
                                 Ok(val) => val,
                                 Err(_) => {
                                     req_error = Some(H2RequestError {
@@ -1492,7 +2133,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                             }
                         }
                         b"authority" => {
-                            let value: PieceStr = match Piece::from(value.to_vec()).to_str() {
+                            let value: PieceStr = match intern::piece(&value).to_str() {
                                 Ok(p) => p,
                                 Err(_) => {
                                     req_error = Some(H2RequestError {
@@ -1520,11 +2161,17 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                                 });
                             }
                         }
+                        b"status" => {
+                            req_error = Some(H2RequestError {
+                                status: StatusCode::BAD_REQUEST,
+                                message: "bad request: ':status' is a response pseudo-header. Pseudo-header fields defined for responses MUST NOT appear in requests (RFC 9113, section 8.3)".into(),
+                            });
+                        }
                         _ => {
                             req_error = Some(H2RequestError {
                                 status: StatusCode::BAD_REQUEST,
                                 message:
-                                    "bad request: received invalid pseudo-header. the only defined pseudo-headers are: ':method', ':scheme', ':path', ':authority', ':status' (RFC 9113, section 8.1)"
+                                    "bad request: received invalid pseudo-header. the only defined request pseudo-headers are: ':method', ':scheme', ':path', ':authority' (RFC 9113, section 8.3)"
                                         .into(),
                             });
                         }
@@ -1572,7 +2219,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                         return;
                     }
 
-                    if name == http::header::TE && &value[..] != b"trailers" {
+                    if name == http::header::TE && !value.eq_ignore_ascii_case(b"trailers") {
                         req_error = Some(H2RequestError {
                                 status: StatusCode::BAD_REQUEST,
                                 message: "bad request: 'te' did not contain 'trailers'. cf. RFC9113, Section 8.2.2: The only exception to this is the TE header field, which MAY be present in an HTTP/2 request; when it is, it MUST NOT contain any value other than 'trailers'".into(),
@@ -1606,31 +2253,26 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                         return;
                     }
 
-                    let value: Piece = value.to_vec().into();
+                    if matches!(headers_or_trailers, HeadersOrTrailers::Headers) {
+                        match driver.on_header_field(&name, &value) {
+                            FieldDecision::Keep => {}
+                            FieldDecision::Skip => return,
+                            FieldDecision::Reject(status) => {
+                                req_error = Some(H2RequestError {
+                                    status,
+                                    message: Piece::empty(),
+                                });
+                                return;
+                            }
+                        }
+                    }
+
+                    let value: Piece = intern::piece(&value);
                     headers.append(name, value);
                 }
             };
 
-            match data {
-                Data::Single(payload) => {
-                    self.hpack_dec
-                        .decode_with_cb(&payload[..], on_header_pair)
-                        .map_err(|e| H2RequestOrConnectionError::ConnectionError(e.into()))?;
-                }
-                Data::Multi(fragments) => {
-                    let total_len = fragments.iter().map(|f| f.len()).sum();
-                    // this is a slow path, let's do a little heap allocation. we could
-                    // be using `RollMut` for this, but it would probably need to resize
-                    // a bunch
-                    let mut payload = Vec::with_capacity(total_len);
-                    for frag in &fragments {
-                        payload.extend_from_slice(&frag[..]);
-                    }
-                    self.hpack_dec
-                        .decode_with_cb(&payload[..], on_header_pair)
-                        .map_err(|e| H2RequestOrConnectionError::ConnectionError(e.into()))?;
-                }
-            };
+            data.decode(&mut self.hpack_dec, on_header_pair)?;
 
             if let Some(req_error) = req_error {
                 return Err(req_error.into());
@@ -1793,16 +2435,44 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     }
                 };
 
+                if self.state.join_cookies {
+                    cookie::join(&mut headers);
+                }
+
+                let deadline = Deadline::after(self.state.request_timeout);
+                let head_request = method == Method::Head;
                 let req = Request {
                     method,
                     uri,
                     version: Version::HTTP_2,
                     headers,
+                    deadline: deadline.clone(),
+                    stream_id: Some(stream_id),
+                    conn: self.state.conn_info.clone(),
+                    timings: RequestTimings {
+                        accepted: self.state.accepted.take(),
+                        head_started: Some(head_started),
+                        head_read: Some(std::time::Instant::now()),
+                    },
                 };
 
-                let responder = Responder::new(H2Encoder::new(stream_id, self.ev_tx.clone()));
+                let decision = asterisk_decision(&req, self.state.options_allow.as_deref())
+                    .unwrap_or_else(|| self.driver.on_headers(&req));
 
-                let (piece_tx, piece_rx) = mpsc::channel::<StreamIncomingItem>(1); // TODO: is 1 a sensible value here?
+                let outgoing: StreamOutgoing = self.state.mk_stream_outgoing();
+                let responder = Responder::new(
+                    H2Encoder::new(
+                        self.events.stream(stream_id),
+                        outgoing.backlog.handle(),
+                        deadline.clone(),
+                        head_request,
+                    )
+                    .with_error_pages(self.state.error_pages.clone()),
+                )
+                .with_deadline(deadline.clone())
+                .with_head_request(head_request);
+
+                let (piece_tx, piece_rx) = mpsc::unbounded_channel::<StreamIncomingItem>();
 
                 let req_body = H2Body {
                     // FIXME: that's not right. h2 requests can still specify
@@ -1810,13 +2480,18 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     content_length: if end_stream { Some(0) } else { None },
                     eof: end_stream,
                     rx: piece_rx,
+                    credit: (self.state.window_updates == WindowUpdates::OnRead)
+                        .then(|| self.events.stream(stream_id)),
+                    expected_len: req.headers.content_length(),
+                    read: 0,
+                    loans: self.state.loans.clone(),
                 };
 
                 let incoming = StreamIncoming {
                     capacity: self.state.self_settings.initial_window_size as _,
+                    released: 0,
                     tx: piece_tx,
                 };
-                let outgoing: StreamOutgoing = self.state.mk_stream_outgoing();
                 self.state.streams.insert(
                     stream_id,
                     if end_stream {
@@ -1841,38 +2516,82 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     async move {
                         let mut req_body = req_body;
                         let responder = responder;
-
-                        match driver.handle(req, &mut req_body, responder).await {
-                            Ok(_responder) => {
+                        let (method, uri) = (req.method.clone(), req.uri.clone());
+
+                        // if the handler panics or goes over its deadline, the
+                        // encoder gets dropped along with it, which replies with
+                        // a 500/504 or resets the stream: other streams are
+                        // unaffected.
+                        let handled = tokio::select! {
+                            res = AssertUnwindSafe(async {
+                                match decision {
+                                    HeaderDecision::Continue => driver.handle(req, &mut req_body, responder).await,
+                                    HeaderDecision::Respond { res, body } => {
+                                        debug!(%stream_id, %method, %uri, status = %res.status, "answered request before reading its body");
+                                        // dropping the body lets the stream be
+                                        // reset once the response is out
+                                        drop(req_body);
+                                        responder.write_final_response_with_body(res, &mut once(body)).await
+                                    }
+                                }
+                            })
+                            .catch_unwind() => res,
+                            _ = deadline.expired() => {
+                                debug!(%stream_id, %method, %uri, "request deadline exceeded, dropped handler");
+                                return;
+                            }
+                        };
+                        match handled {
+                            Ok(Ok(_responder)) => {
                                 debug!("Handler completed successfully, gave us a responder");
                             }
-                            Err(e) => {
+                            Ok(Err(e)) => {
                                 // TODO: actually handle that error.
                                 debug!("Handler returned an error: {e}")
                             }
+                            Err(payload) => {
+                                let message = panic_message(&*payload);
+                                error!(%stream_id, %method, %uri, panic = %message, "driver panicked while handling request");
+                            }
                         }
                     }
                 });
             }
             HeadersOrTrailers::Trailers => {
-                match self.state.streams.get_mut(&stream_id) {
-                    Some(StreamState::Open { incoming, .. }) => {
-                        if incoming
-                            .tx
-                            .send(Ok(PieceOrTrailers::Trailers(Box::new(headers))))
-                            .await
-                            .is_err()
-                        {
-                            // the body is being ignored, but there's no point
-                            // in resetting the
-                            // stream since we just got the end of it
-                        }
+                let Some(ss) = self.state.streams.get_mut(&stream_id) else {
+                    // the stream was reset while we were reading the trailers
+                    return Ok(());
+                };
+
+                if let Some(incoming) = ss.incoming_mut() {
+                    if incoming
+                        .tx
+                        .send(Ok(PieceOrTrailers::Trailers(Box::new(headers))))
+                        .is_err()
+                    {
+                        // the body is being ignored, but there's no point
+                        // in resetting the stream since we just got the end
+                        // of it
+                    }
+                }
+
+                // trailers always carry END_STREAM, so the request side is
+                // done, but the response may still be going out.
+                match std::mem::take(ss) {
+                    StreamState::Open { outgoing, .. } => {
+                        *ss = StreamState::HalfClosedRemote { outgoing };
                     }
                     _ => {
-                        unreachable!("stream state should be open when we receive trailers")
+                        self.state.streams.remove(&stream_id);
+                        self.state
+                            .closed_streams
+                            .insert(stream_id, ClosedBy::EndStream);
+                        debug!(
+                            "Closed stream (read trailers) {stream_id}, now have {} streams",
+                            self.state.streams.len()
+                        );
                     }
                 }
-                self.state.streams.remove(&stream_id);
             }
         }
 
@@ -1880,6 +2599,53 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
     }
 }
 
+/// Queues a header block as a HEADERS frame, followed by as many
+/// CONTINUATION frames as it takes to fit `max_frame_size`
+fn queue_header_block(
+    frames: &mut Vec<(Frame, PieceList)>,
+    stream_id: StreamId,
+    mut block: Piece,
+    max_frame_size: usize,
+    end_stream: bool,
+) {
+    let mut is_continuation = false;
+    loop {
+        let (piece, rest) = if block.len() > max_frame_size {
+            let (written, requeued) = block.split_at(max_frame_size);
+            debug!(write_size = %max_frame_size, requeued_len = %requeued.len(), "splitting headers");
+            (written, Some(requeued))
+        } else {
+            (block, None)
+        };
+
+        let frame_type = if is_continuation {
+            let mut flags = BitFlags::<ContinuationFlags>::default();
+            if rest.is_none() {
+                flags |= ContinuationFlags::EndHeaders;
+            }
+            FrameType::Continuation(flags)
+        } else {
+            let mut flags = BitFlags::<HeadersFlags>::default();
+            if rest.is_none() {
+                flags |= HeadersFlags::EndHeaders;
+            }
+            if end_stream {
+                flags |= HeadersFlags::EndStream;
+            }
+            FrameType::Headers(flags)
+        };
+        frames.push((Frame::new(frame_type, stream_id), PieceList::single(piece)));
+
+        match rest {
+            Some(rest) => {
+                block = rest;
+                is_continuation = true;
+            }
+            None => break,
+        }
+    }
+}
+
 enum ReadHeadersMode {
     // we're accepting the stream or processing trailers, we want to
     // process the headers we read.
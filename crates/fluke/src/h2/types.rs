@@ -1,17 +1,24 @@
 use std::{
+    borrow::Cow,
+    cell::Cell,
     collections::{HashMap, HashSet, VecDeque},
     fmt,
+    rc::Rc,
+    time::{Duration, Instant},
 };
 
-use fluke_buffet::Piece;
+use fluke_buffet::{Piece, PieceList};
 use fluke_hpack::decoder::DecoderError;
 use http::StatusCode;
-use tokio::sync::Notify;
+use tokio::sync::{Notify, Semaphore};
+use tracing::trace;
 
-use crate::Response;
+use crate::{budget::Charge, loans::Loans, ConnInfo, ErrorPages, Headers, MemoryBudget, Response};
 
-use super::body::StreamIncoming;
-use fluke_h2_parse::{FrameType, KnownErrorCode, Settings, SettingsError, StreamId};
+use super::{
+    body::StreamIncoming, closed::ClosedStreams, extension::ExtensionFrame, WindowUpdates,
+};
+use fluke_h2_parse::{ErrorCode, FrameType, KnownErrorCode, Settings, SettingsError, StreamId};
 
 pub(crate) struct ConnState {
     pub(crate) streams: HashMap<StreamId, StreamState>,
@@ -32,10 +39,73 @@ pub(crate) struct ConnState {
 
     pub(crate) incoming_capacity: i64,
     pub(crate) outgoing_capacity: i64,
+
+    /// bytes this connection holds in buffers, see [crate::MemoryBudget]
+    pub(crate) budget: MemoryBudget,
+
+    /// read buffer blocks lent out to handlers, see [crate::LoanLimit]
+    pub(crate) loans: Loans,
+
+    /// how many body bytes a handler may queue for a single stream
+    pub(crate) max_stream_backlog: usize,
+
+    /// max length of the `:path` pseudo-header
+    pub(crate) max_uri_len: usize,
+
+    /// how often to PING the peer, see [crate::h2::ServerConf]
+    pub(crate) ping_interval: Option<Duration>,
+
+    /// see [crate::h2::ServerConf::ping_timeout]
+    pub(crate) ping_timeout: Option<Duration>,
+
+    /// max receive window, if it's tuned at all, see [super::flow]
+    pub(crate) adaptive_window: Option<u32>,
+
+    /// round-trip time estimate, shared with requests
+    pub(crate) conn_info: ConnInfo,
+
+    /// when the connection started being served, see
+    /// [MemoryBudget::close_summary]
+    pub(crate) opened: Instant,
+
+    /// when the connection was accepted, until the first request takes it,
+    /// see [crate::RequestTimings::accepted]
+    pub(crate) accepted: Option<Instant>,
+
+    /// how long handlers have to respond, see [crate::Deadline]
+    pub(crate) request_timeout: Option<Duration>,
+
+    /// recently closed streams, see [super::closed]
+    pub(crate) closed_streams: ClosedStreams,
+
+    /// how long the connection may go without streams, see
+    /// [crate::h2::ServerConf::idle_timeout]
+    pub(crate) idle_timeout: Option<Duration>,
+
+    /// whether the peer's SETTINGS frame has arrived yet
+    pub(crate) peer_settings_received: bool,
+
+    /// whether frames may arrive before the peer's SETTINGS, see
+    /// [crate::h2::ServerConf::lenient_settings]
+    pub(crate) lenient_settings: bool,
+
+    /// whether to join `cookie` headers, see
+    /// [crate::h2::ServerConf::join_cookies]
+    pub(crate) join_cookies: bool,
+
+    /// see [crate::h2::ServerConf::error_pages]
+    pub(crate) error_pages: ErrorPages,
+
+    /// see [crate::h2::ServerConf::options_allow]
+    pub(crate) options_allow: Option<Cow<'static, str>>,
+
+    /// see [crate::h2::ServerConf::window_updates]
+    pub(crate) window_updates: WindowUpdates,
 }
 
 impl Default for ConnState {
     fn default() -> Self {
+        let opened = Instant::now();
         let mut s = Self {
             streams: Default::default(),
             last_stream_id: StreamId(0),
@@ -48,6 +118,26 @@ impl Default for ConnState {
 
             incoming_capacity: 0,
             outgoing_capacity: 0,
+
+            budget: Default::default(),
+            loans: Default::default(),
+            max_stream_backlog: 256 * 1024,
+            max_uri_len: 8 * 1024,
+            ping_interval: None,
+            ping_timeout: None,
+            adaptive_window: None,
+            conn_info: Default::default(),
+            opened,
+            accepted: Some(opened),
+            request_timeout: None,
+            closed_streams: Default::default(),
+            idle_timeout: None,
+            peer_settings_received: false,
+            lenient_settings: false,
+            join_cookies: false,
+            error_pages: Default::default(),
+            options_allow: None,
+            window_updates: Default::default(),
         };
         s.incoming_capacity = s.self_settings.initial_window_size as _;
         s.outgoing_capacity = s.peer_settings.initial_window_size as _;
@@ -62,7 +152,10 @@ impl ConnState {
         StreamOutgoing {
             headers: HeadersOutgoing::WaitingForHeaders,
             body: BodyOutgoing::StillReceiving(Default::default()),
+            trailers: None,
             capacity: self.peer_settings.initial_window_size as _,
+            buffered: self.budget.charge(0),
+            backlog: StreamBacklog::new(self.max_stream_backlog),
         }
     }
 }
@@ -134,6 +227,16 @@ pub(crate) enum StreamState {
 }
 
 impl StreamState {
+    /// Get the inner `StreamOutgoing` if the state is `Open` or
+    /// `HalfClosedRemote`.
+    pub(crate) fn outgoing(&self) -> Option<&StreamOutgoing> {
+        match self {
+            StreamState::Open { outgoing, .. } => Some(outgoing),
+            StreamState::HalfClosedRemote { outgoing, .. } => Some(outgoing),
+            _ => None,
+        }
+    }
+
     /// Get the inner `StreamOutgoing` if the state is `Open` or
     /// `HalfClosedRemote`.
     pub(crate) fn outgoing_mut(&mut self) -> Option<&mut StreamOutgoing> {
@@ -143,15 +246,151 @@ impl StreamState {
             _ => None,
         }
     }
+
+    /// Get the inner `StreamIncoming` if the state is `Open` or
+    /// `HalfClosedLocal`.
+    pub(crate) fn incoming_mut(&mut self) -> Option<&mut StreamIncoming> {
+        match self {
+            StreamState::Open { incoming, .. } => Some(incoming),
+            StreamState::HalfClosedLocal { incoming, .. } => Some(incoming),
+            _ => None,
+        }
+    }
 }
 
 pub(crate) struct StreamOutgoing {
     pub(crate) headers: HeadersOutgoing,
     pub(crate) body: BodyOutgoing,
 
+    // the encoded trailer block, if any: sent as HEADERS with END_STREAM
+    // once the body is out, instead of ending the last DATA frame
+    pub(crate) trailers: Option<Piece>,
+
     // window size of the stream, ie. how many bytes
     // we can send to the receiver before waiting.
     pub(crate) capacity: i64,
+
+    // body bytes queued for this stream, released as they're written
+    // out (or when the stream goes away)
+    pub(crate) buffered: Charge,
+
+    // makes the handler wait when too much body data is queued
+    pub(crate) backlog: StreamBacklog,
+}
+
+/// Bounds how many body bytes a handler can queue up for a stream: when the
+/// peer's window is closed (or it's just slow), the handler's
+/// `write_body_chunk` waits until enough of it has been sent.
+///
+/// Chunks larger than the cap count as exactly the cap, so they can always
+/// go through once the backlog is empty.
+///
+/// It also tells the encoder when the stream gets reset, so that the
+/// handler's writes fail with [StreamClosed].
+pub(crate) struct StreamBacklog {
+    sem: Rc<Semaphore>,
+    cap: usize,
+    /// permits taken by handlers that haven't been given back yet
+    held: usize,
+    /// set when the stream is reset, by either side
+    reset: Rc<Cell<Option<ErrorCode>>>,
+}
+
+impl StreamBacklog {
+    pub(crate) fn new(cap: usize) -> Self {
+        Self {
+            sem: Rc::new(Semaphore::new(cap)),
+            cap,
+            held: 0,
+            reset: Default::default(),
+        }
+    }
+
+    /// A handle for the encoder, to wait for room in the backlog
+    pub(crate) fn handle(&self) -> StreamBacklogHandle {
+        StreamBacklogHandle {
+            sem: self.sem.clone(),
+            cap: self.cap,
+            reset: self.reset.clone(),
+        }
+    }
+
+    /// Records that the stream was reset with `code`: the encoder's next
+    /// write will fail with it.
+    pub(crate) fn reset(&self, code: ErrorCode) {
+        if self.reset.get().is_none() {
+            self.reset.set(Some(code));
+        }
+    }
+
+    /// Called when a chunk of `len` bytes was queued (the handler already
+    /// took the permits for it)
+    pub(crate) fn queued(&mut self, len: usize) {
+        self.held += len.min(self.cap);
+    }
+
+    /// Called when `len` body bytes were taken out of the queue to be sent
+    pub(crate) fn dequeued(&mut self, len: usize) {
+        let n = len.min(self.held);
+        self.held -= n;
+        self.sem.add_permits(n);
+    }
+}
+
+impl Drop for StreamBacklog {
+    fn drop(&mut self) {
+        // wakes up any handler still waiting, so it can notice the stream
+        // is gone
+        self.sem.close();
+    }
+}
+
+/// The encoder's side of [StreamBacklog]
+pub(crate) struct StreamBacklogHandle {
+    sem: Rc<Semaphore>,
+    cap: usize,
+    reset: Rc<Cell<Option<ErrorCode>>>,
+}
+
+impl StreamBacklogHandle {
+    /// Errors out if the stream was reset or went away
+    pub(crate) fn check_open(&self) -> Result<(), StreamClosed> {
+        if self.reset.get().is_some() || self.sem.is_closed() {
+            return Err(self.closed());
+        }
+        Ok(())
+    }
+
+    /// Waits until a chunk of `len` bytes can be queued. Errors out if the
+    /// stream was reset or went away in the meantime.
+    pub(crate) async fn reserve(&self, len: usize) -> Result<(), StreamClosed> {
+        self.check_open()?;
+        let permits = len.min(self.cap) as u32;
+        if permits > 0 {
+            self.sem
+                .acquire_many(permits)
+                .await
+                .map_err(|_| self.closed())?
+                .forget();
+        }
+        Ok(())
+    }
+
+    fn closed(&self) -> StreamClosed {
+        StreamClosed {
+            // no code means the whole connection went away
+            error_code: self.reset.get().unwrap_or(KnownErrorCode::Cancel.into()),
+        }
+    }
+}
+
+/// Returned (wrapped in an [eyre::Report]) by response writes once the
+/// stream was reset, by the peer or by us, or once the connection went
+/// away, in which case the error code is `CANCEL`.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("stream closed with error code {error_code:?}")]
+pub struct StreamClosed {
+    pub error_code: ErrorCode,
 }
 
 #[derive(Default)]
@@ -162,9 +401,6 @@ pub(crate) enum HeadersOutgoing {
     // The user gave us headers to send, but we haven't started yet
     WroteNone(Piece),
 
-    // We have sent some headers, but not all (we're still sending CONTINUATION frames)
-    WroteSome(Piece),
-
     // We've sent everything
     #[default]
     WroteAll,
@@ -176,7 +412,6 @@ impl HeadersOutgoing {
         match self {
             HeadersOutgoing::WaitingForHeaders => true,
             HeadersOutgoing::WroteNone(_) => true,
-            HeadersOutgoing::WroteSome(_) => true,
             HeadersOutgoing::WroteAll => false,
         }
     }
@@ -185,7 +420,6 @@ impl HeadersOutgoing {
     pub(crate) fn take_piece(&mut self) -> Piece {
         match std::mem::take(self) {
             Self::WroteNone(piece) => piece,
-            Self::WroteSome(piece) => piece,
             _ => Piece::empty(),
         }
     }
@@ -238,6 +472,17 @@ impl BodyOutgoing {
         }
     }
 
+    /// There's something we could write right now: a piece, or the end of
+    /// the stream
+    #[inline(always)]
+    pub(crate) fn has_pieces(&self) -> bool {
+        match self {
+            BodyOutgoing::StillReceiving(pieces) => !pieces.is_empty(),
+            BodyOutgoing::DoneReceiving(_) => true,
+            BodyOutgoing::DoneSending => false,
+        }
+    }
+
     #[inline(always)]
     pub(crate) fn pop_front(&mut self) -> Option<Piece> {
         match self {
@@ -264,6 +509,31 @@ impl BodyOutgoing {
         }
     }
 
+    /// Takes up to `max_len` bytes off the front, for a single DATA frame.
+    /// The last piece is split if it doesn't fit: both halves share its
+    /// storage, nothing is copied.
+    pub(crate) fn take_front(&mut self, max_len: usize) -> PieceList {
+        let mut taken = PieceList::default();
+        let mut len = 0;
+        while len < max_len {
+            let Some(piece) = self.pop_front() else {
+                break;
+            };
+
+            let piece_len = piece.len();
+            if len + piece_len > max_len {
+                let (written, requeued) = piece.split_at(max_len - len);
+                trace!(written_len = %written.len(), requeued_len = %requeued.len(), "splitting piece");
+                taken.push_back(written);
+                self.push_front(requeued);
+                break;
+            }
+            len += piece_len;
+            taken.push_back(piece);
+        }
+        taken
+    }
+
     #[inline(always)]
     pub(crate) fn push_back(&mut self, piece: Piece) {
         match self {
@@ -307,8 +577,11 @@ impl fmt::Debug for H2RequestError {
     }
 }
 
+/// Something the peer did that's bad enough that we have to close the
+/// whole connection (with a GOAWAY frame, when possible).
 #[derive(Debug, thiserror::Error)]
-pub(crate) enum H2ConnectionError {
+#[non_exhaustive]
+pub enum H2ConnectionError {
     #[error("frame too large: {frame_type:?} frame of size {frame_size} exceeds max frame size of {max_frame_size}")]
     FrameTooLarge {
         frame_type: FrameType,
@@ -344,6 +617,15 @@ pub(crate) enum H2ConnectionError {
         frame_size: u32,
     },
 
+    #[error("connection was idle for {timeout:?}")]
+    Idle { timeout: Duration },
+
+    #[error("peer didn't acknowledge a PING within {timeout:?}")]
+    PingTimeout { timeout: Duration },
+
+    #[error("expected a SETTINGS frame right after the preface, but got {frame_type:?}")]
+    ExpectedSettingsFrame { frame_type: FrameType },
+
     #[error("on stream {stream_id}, expected continuation frame, but got {frame_type:?}")]
     ExpectedContinuationFrame {
         stream_id: StreamId,
@@ -418,10 +700,14 @@ pub(crate) enum H2ConnectionError {
 
     #[error("bad setting value: {0}")]
     BadSettingValue(SettingsError),
+
+    #[error("header block for stream {stream_id} made the connection go over its memory budget of {limit} bytes")]
+    HeaderBlockOverBudget { stream_id: StreamId, limit: usize },
 }
 
 impl H2ConnectionError {
-    pub(crate) fn as_known_error_code(&self) -> KnownErrorCode {
+    /// The error code to send along with GOAWAY for this error
+    pub fn as_known_error_code(&self) -> KnownErrorCode {
         match self {
             // frame size errors
             H2ConnectionError::FrameTooLarge { .. } => KnownErrorCode::FrameSizeError,
@@ -444,6 +730,11 @@ impl H2ConnectionError {
             H2ConnectionError::StreamClosed { .. } => KnownErrorCode::StreamClosed,
             // internal errors
             H2ConnectionError::Internal(_) => KnownErrorCode::InternalError,
+            // resource exhaustion
+            H2ConnectionError::HeaderBlockOverBudget { .. } => KnownErrorCode::EnhanceYourCalm,
+            // not an error on the peer's part
+            H2ConnectionError::Idle { .. } => KnownErrorCode::NoError,
+            H2ConnectionError::PingTimeout { .. } => KnownErrorCode::NoError,
             // protocol errors
             H2ConnectionError::PaddedFrameTooShort { .. } => KnownErrorCode::ProtocolError,
             H2ConnectionError::StreamSpecificFrameToConnection { .. } => {
@@ -452,6 +743,24 @@ impl H2ConnectionError {
             _ => KnownErrorCode::ProtocolError,
         }
     }
+
+    /// For errors that come down to the connection using up its memory or
+    /// time rather than breaking the protocol: a short, machine-readable
+    /// reason, see [MemoryBudget::close_summary]
+    pub(crate) fn resource_reason(&self) -> Option<String> {
+        match self {
+            H2ConnectionError::HeaderBlockOverBudget { stream_id, .. } => {
+                Some(format!("header_block_over_budget stream={stream_id}"))
+            }
+            H2ConnectionError::Idle { timeout } => {
+                Some(format!("idle timeout_ms={}", timeout.as_millis()))
+            }
+            H2ConnectionError::PingTimeout { timeout } => {
+                Some(format!("ping_timeout timeout_ms={}", timeout.as_millis()))
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -469,6 +778,9 @@ pub(crate) enum H2StreamError {
     #[error("trailers must have EndStream flag set")]
     TrailersNotEndStream,
 
+    #[error("received malformed trailers")]
+    MalformedTrailers,
+
     #[error("received RST_STREAM frame")]
     ReceivedRstStream,
 
@@ -483,6 +795,21 @@ pub(crate) enum H2StreamError {
 
     #[error("received WINDOW_UPDATE that made the window size overflow")]
     WindowUpdateOverflow,
+
+    #[error("handler went away in the middle of the response body")]
+    ResponseAbandoned,
+
+    #[error("handler reset the stream with {0:?}")]
+    ResetByHandler(KnownErrorCode),
+
+    #[error("request deadline passed in the middle of the response body")]
+    DeadlineExceeded,
+
+    #[error("handler wrote a body that didn't match the announced content-length")]
+    ContentLengthMismatch,
+
+    #[error("response is complete and the rest of the request body won't be read")]
+    RequestBodyNotNeeded,
 }
 
 impl H2StreamError {
@@ -500,6 +827,15 @@ impl H2StreamError {
             InvalidRstStreamFrameSize { .. } => Code::FrameSizeError,
             // flow control errors
             WindowUpdateOverflow => Code::FlowControlError,
+            // internal errors
+            ResponseAbandoned => Code::InternalError,
+            ContentLengthMismatch => Code::InternalError,
+            // whatever the handler asked for
+            ResetByHandler(code) => *code,
+            // the handler was cancelled
+            DeadlineExceeded => Code::Cancel,
+            // nothing went wrong
+            RequestBodyNotNeeded => Code::NoError,
             _ => Code::ProtocolError,
         }
     }
@@ -511,24 +847,122 @@ pub(crate) enum HeadersOrTrailers {
     Trailers,
 }
 
+/// How urgently a frame needs to go out. Control frames never wait behind
+/// bulk data:
+///
+///   * connection control (SETTINGS and PING acks, GOAWAY, WINDOW_UPDATE) is
+///     written as soon as the frame that calls for it is processed, and
+///     incoming frames are handled before anything else. Window updates and
+///     extension frames from handlers are served from the event queues
+///     first, see [super::events],
+///   * stream control (HEADERS, RST_STREAM) is served from the event queues
+///     before any body chunk, and written before any DATA frame,
+///   * data goes out last, one frame per stream and per round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum WritePriority {
+    ConnectionControl,
+    StreamControl,
+    Data,
+}
+
+impl WritePriority {
+    pub(crate) fn of(frame_type: &FrameType) -> Self {
+        match frame_type {
+            FrameType::Data(_) => Self::Data,
+            FrameType::Headers(_)
+            | FrameType::Continuation(_)
+            | FrameType::PushPromise
+            | FrameType::Priority
+            | FrameType::RstStream => Self::StreamControl,
+            FrameType::Settings(_)
+            | FrameType::Ping(_)
+            | FrameType::GoAway
+            | FrameType::WindowUpdate
+            | FrameType::Unknown(_) => Self::ConnectionControl,
+        }
+    }
+}
+
+/// A command from a handler to the connection, for a given stream
 #[derive(Debug)]
-pub(crate) struct H2Event {
+pub(crate) struct StreamCommand {
     pub(crate) stream_id: StreamId,
-    pub(crate) payload: H2EventPayload,
+    pub(crate) command: WriteCommand,
 }
 
-pub(crate) enum H2EventPayload {
+/// Something a handler needs written on its stream
+pub(crate) enum WriteCommand {
+    /// Response headers, sent as HEADERS (and CONTINUATION) frames
     Headers(Response),
-    BodyChunk(Piece),
-    BodyEnd,
+
+    /// A body chunk, possibly empty, and whether it's the last one
+    Data { piece: Piece, end: bool },
+
+    /// Ends the body with trailers, sent as HEADERS (and CONTINUATION)
+    /// frames once all queued data is out
+    Trailers(Box<Headers>),
+
+    /// Give up on the stream: sent as RST_STREAM
+    Reset(H2StreamError),
+
+    /// A frame of a type the server doesn't know about, sent by the driver
+    Extension(ExtensionFrame),
+
+    /// The handler read that many bytes of the request body, which may be
+    /// given back to the peer, see [super::WindowUpdates::OnRead]
+    Consumed(u32),
+}
+
+impl WriteCommand {
+    pub(crate) fn priority(&self) -> WritePriority {
+        match self {
+            Self::Headers(_) | Self::Reset(_) => WritePriority::StreamControl,
+            Self::Extension(_) | Self::Consumed(_) => WritePriority::ConnectionControl,
+            Self::Data { .. } | Self::Trailers(_) => WritePriority::Data,
+        }
+    }
 }
 
-impl fmt::Debug for H2EventPayload {
+impl fmt::Debug for WriteCommand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Headers(_) => f.debug_tuple("Headers").finish(),
-            Self::BodyChunk(_) => f.debug_tuple("BodyChunk").finish(),
-            Self::BodyEnd => write!(f, "BodyEnd"),
+            Self::Data { piece, end } => f
+                .debug_struct("Data")
+                .field("len", &piece.len())
+                .field("end", end)
+                .finish(),
+            Self::Trailers(_) => f.debug_tuple("Trailers").finish(),
+            Self::Reset(e) => f.debug_tuple("Reset").field(e).finish(),
+            Self::Extension(frame) => f.debug_tuple("Extension").field(frame).finish(),
+            Self::Consumed(len) => f.debug_tuple("Consumed").field(len).finish(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use fluke_buffet::Piece;
+
+    use super::BodyOutgoing;
+
+    #[test]
+    fn take_front_splits_without_copying() {
+        let first = Piece::from(b"hello ".to_vec());
+        let second = Piece::from(b"world".to_vec());
+        let second_ptr = second.as_ref().as_ptr();
+        let mut body = BodyOutgoing::DoneReceiving([first, second].into());
+
+        let frame = body.take_front(8);
+        assert_eq!(frame.len(), 8);
+        assert_eq!(frame.num_pieces(), 2);
+
+        // the rest is a slice of the same storage
+        let rest = body.pop_front().unwrap();
+        assert_eq!(&rest[..], b"rld");
+        assert_eq!(rest.as_ref().as_ptr(), second_ptr.wrapping_add(2));
+
+        assert!(body.take_front(8).is_empty());
+        assert!(!body.might_receive_more());
+    }
+}
@@ -0,0 +1,153 @@
+//! Zero-downtime upgrades: a running server hands its listening sockets over
+//! to its replacement, through a unix socket, then stops accepting and
+//! finishes serving the connections it already has.
+//!
+//! In the old process, once it's told an upgrade is coming (e.g. on a
+//! signal), [hand_over] waits for the new process to show up:
+//!
+//! ```no_run
+//! # async fn old(server: fluke::Server<impl fluke::ServerDriver + 'static>) -> std::io::Result<()> {
+//! let listener = server.listener();
+//! fluke::buffet::spawn(async move {
+//!     if let Err(e) = fluke::handover::hand_over("/run/app.sock", &[("http", &listener)]).await {
+//!         eprintln!("upgrade failed, carrying on: {e}");
+//!     }
+//! });
+//! // returns once the sockets are handed over and every connection is done
+//! server.run().await
+//! # }
+//! ```
+//!
+//! The new process takes the sockets, starts serving, and only then lets the
+//! old one stop accepting:
+//!
+//! ```no_run
+//! # async fn new(driver: impl fluke::ServerDriver + 'static) -> std::io::Result<()> {
+//! let mut takeover = fluke::handover::take_over("/run/app.sock").await?;
+//! let socket = takeover.take("http").expect("no http socket");
+//! let server = fluke::ServerBuilder::from_listener(socket).build(driver).await?;
+//! takeover.ready().await?;
+//! server.run().await
+//! # }
+//! ```
+//!
+//! The sockets stay open throughout: connections that come in during the
+//! upgrade wait in the kernel's backlog until either process accepts them.
+
+use std::{os::fd::AsFd, path::Path};
+
+use fluke_buffet::net::{recv_listeners, send_listeners, ListenFd};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+use tracing::debug;
+
+use crate::Listener;
+
+/// Sent by the new process once it's accepting connections
+const READY: u8 = b'R';
+
+/// Waits for a new process to connect to the unix socket at `path` (see
+/// [take_over]), sends it the listening sockets of `listeners`, and once it
+/// says it's ready, detaches them (see [Listener::detach]), so that the
+/// servers drain and their `run` calls return.
+///
+/// If anything goes wrong before the new process is ready, `listeners` are
+/// left alone, and this can be called again.
+pub async fn hand_over(
+    path: impl AsRef<Path>,
+    listeners: &[(&str, &Listener)],
+) -> std::io::Result<()> {
+    let path = path.as_ref();
+    // left behind by an upgrade that went wrong, most likely
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let unix_listener = UnixListener::bind(path)?;
+    let res = async {
+        let (stream, _) = unix_listener.accept().await?;
+        debug!(?path, "new process connected, handing sockets over");
+        hand_over_on(stream, listeners).await
+    }
+    .await;
+    _ = std::fs::remove_file(path);
+    res
+}
+
+async fn hand_over_on(
+    mut stream: UnixStream,
+    listeners: &[(&str, &Listener)],
+) -> std::io::Result<()> {
+    let mut sockets = vec![];
+    for (name, listener) in listeners {
+        let socket = listener.socket()?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                format!("listener {name:?} is closed"),
+            )
+        })?;
+        sockets.push((*name, socket));
+    }
+    let fds: Vec<_> = sockets
+        .iter()
+        .map(|(name, socket)| (*name, socket.as_fd()))
+        .collect();
+    send_listeners(&stream, &fds).await?;
+
+    let mut ready = [0u8];
+    match stream.read(&mut ready).await? {
+        1 if ready[0] == READY => {}
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "new process went away before it was ready",
+            ))
+        }
+    }
+
+    debug!("new process is ready, detaching listeners");
+    for (_, listener) in listeners {
+        listener.detach();
+    }
+    Ok(())
+}
+
+/// Connects to a process waiting in [hand_over] at `path` and receives its
+/// listening sockets. Once they're being accepted on, [TakeOver::ready] lets
+/// the old process stop.
+pub async fn take_over(path: impl AsRef<Path>) -> std::io::Result<TakeOver> {
+    let stream = UnixStream::connect(path).await?;
+    let listeners = recv_listeners(&stream).await?;
+    debug!(count = %listeners.len(), "took listening sockets over");
+    Ok(TakeOver { stream, listeners })
+}
+
+/// Listening sockets received from another process, see [take_over]
+pub struct TakeOver {
+    stream: UnixStream,
+    listeners: Vec<ListenFd>,
+}
+
+impl TakeOver {
+    /// Takes the socket the old process called `name`
+    pub fn take(&mut self, name: &str) -> Option<std::net::TcpListener> {
+        let index = self
+            .listeners
+            .iter()
+            .position(|l| l.name.as_deref() == Some(name))?;
+        Some(self.listeners.remove(index).listener)
+    }
+
+    /// Takes all the sockets that are left, in the order they were sent
+    pub fn take_all(&mut self) -> Vec<ListenFd> {
+        std::mem::take(&mut self.listeners)
+    }
+
+    /// Tells the old process we're accepting connections: it stops doing so
+    /// and drains. Sockets that weren't taken are closed.
+    pub async fn ready(mut self) -> std::io::Result<()> {
+        self.stream.write_all(&[READY]).await
+    }
+}
@@ -3,12 +3,60 @@ mod util;
 mod types;
 pub use types::*;
 
+mod error;
+pub use error::*;
+
+mod budget;
+pub use budget::*;
+
+mod loans;
+pub use loans::*;
+
+mod deadline;
+pub use deadline::*;
+
+mod conn_info;
+pub use conn_info::*;
+
+mod close;
+pub use close::*;
+
+mod timing;
+pub use timing::*;
+
+mod error_pages;
+pub use error_pages::*;
+
+mod builder;
+pub use builder::*;
+
+mod reload;
+pub use reload::*;
+
+pub mod handover;
+
 pub mod h1;
 pub mod h2;
 
 mod responder;
 pub use responder::*;
 
+pub mod body;
+
+pub mod route;
+
+pub mod upload;
+
+pub mod capture;
+
+pub mod watchdog;
+
+pub mod vhost;
+
+pub mod metrics;
+
+pub mod trace_context;
+
 pub use fluke_buffet as buffet;
 
 /// re-exported so consumers can use whatever forked version we use
@@ -22,4 +70,87 @@ pub trait ServerDriver {
         req_body: &mut impl Body,
         respond: Responder<E, ExpectResponseHeaders>,
     ) -> eyre::Result<Responder<E, ResponseDone>>;
+
+    /// Called as soon as a request's head is read, before any of its body
+    /// is (and, over HTTP/2, before its stream window grows). Requests
+    /// can be turned away here (failed auth, unknown route, etc.) without
+    /// making clients upload bodies nobody wants, or answered right away
+    /// (see [HeaderDecision::Respond]): over HTTP/1.1 the connection is then
+    /// closed if a body was coming, over HTTP/2 the stream is reset with
+    /// `NO_ERROR` once the response is out.
+    fn on_headers(&self, req: &Request) -> HeaderDecision {
+        _ = req;
+        HeaderDecision::Continue
+    }
+
+    /// Called for each regular header field of a request, before
+    /// [Self::on_headers]. Fields can be left out of [Request::headers], or
+    /// the request turned away early, without keeping all of them around.
+    ///
+    /// Over HTTP/2 this happens while the header block is decoded, so skipped
+    /// fields are never stored: useful for requests with huge headers (SSO
+    /// tokens and such) that the driver only needs a few of. Over HTTP/1.1
+    /// the head is already in the read buffer (see
+    /// [h1::ServerConf::max_http_header_len]), and fields are filtered once
+    /// it's parsed.
+    fn on_header_field(&self, name: &http::HeaderName, value: &[u8]) -> FieldDecision {
+        _ = (name, value);
+        FieldDecision::Keep
+    }
+
+    /// Called when an HTTP/2 connection starts, right after the server sent
+    /// its SETTINGS. `frames` may be kept to send extension frames on the
+    /// connection at any time.
+    fn on_h2_connection(&self, frames: h2::ExtensionFrames) {
+        _ = frames;
+    }
+
+    /// Called for every HTTP/2 frame of a type the server doesn't know
+    /// about, which it otherwise ignores, cf.
+    /// <https://httpwg.org/specs/rfc9113.html#extensibility>. Replies can go
+    /// through `frames`.
+    fn on_extension_frame(&self, frame: h2::ExtensionFrame, frames: &h2::ExtensionFrames) {
+        _ = (frame, frames);
+    }
+
+    /// Called once per connection, when it's closed, with the reason why
+    /// (see [CloseReason]): a good place for connection-level access logs
+    /// and metrics.
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        _ = (conn, reason);
+    }
+}
+
+impl<D> ServerDriver for std::rc::Rc<D>
+where
+    D: ServerDriver,
+{
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        (**self).handle(req, req_body, respond).await
+    }
+
+    fn on_headers(&self, req: &Request) -> HeaderDecision {
+        (**self).on_headers(req)
+    }
+
+    fn on_header_field(&self, name: &http::HeaderName, value: &[u8]) -> FieldDecision {
+        (**self).on_header_field(name, value)
+    }
+
+    fn on_h2_connection(&self, frames: h2::ExtensionFrames) {
+        (**self).on_h2_connection(frames)
+    }
+
+    fn on_extension_frame(&self, frame: h2::ExtensionFrame, frames: &h2::ExtensionFrames) {
+        (**self).on_extension_frame(frame, frames)
+    }
+
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        (**self).on_connection_closed(conn, reason)
+    }
 }
@@ -0,0 +1,197 @@
+//! Request body chunks are handed to drivers as-is: they point straight
+//! into the connection's read buffers, which are blocks of the buffer pool.
+//! A driver that holds on to chunks keeps those blocks from going back to
+//! the pool, however small the chunks are. This bounds how many blocks a
+//! connection's drivers may pin that way.
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::Duration,
+};
+
+use fluke_buffet::{
+    bufpool::{block_freed, BlockId},
+    Piece,
+};
+use tracing::debug;
+
+/// How many buffer pool blocks the drivers of a connection may hold onto
+/// through request body chunks.
+///
+/// When they hold that many, an HTTP/2 connection stops reading into new
+/// blocks (which stalls every stream of the connection) until they drop
+/// some, for up to `max_wait`. Past that, drivers are handed copies of the
+/// chunks instead, which don't pin anything, until they're back under the
+/// limit. Over HTTP/1.1, the driver is the one reading the body, so there's
+/// no one to wait for: it gets copies right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoanLimit {
+    /// Max number of blocks (4KiB each) lent out at once. The block the
+    /// connection is currently reading into counts too, as soon as a chunk
+    /// from it is handed out.
+    pub max_blocks: usize,
+
+    /// How long to stall reads for, waiting for drivers to drop chunks
+    /// (HTTP/2 only)
+    pub max_wait: Duration,
+}
+
+impl Default for LoanLimit {
+    fn default() -> Self {
+        Self {
+            max_blocks: 64,
+            max_wait: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Keeps track of the blocks lent out to a connection's drivers. Cloning it
+/// gives another handle to the same loans.
+#[derive(Clone, Default)]
+pub(crate) struct Loans {
+    inner: Rc<LoansInner>,
+}
+
+#[derive(Default)]
+struct LoansInner {
+    limit: Option<LoanLimit>,
+    lent: RefCell<Vec<BlockId>>,
+
+    /// set when waiting for room timed out, cleared once there's room again:
+    /// there's no point stalling every read for drivers that hold on to
+    /// their chunks for good
+    gave_up: Cell<bool>,
+}
+
+impl Loans {
+    /// `None` lends out as many blocks as drivers want
+    pub(crate) fn new(limit: Option<LoanLimit>) -> Self {
+        Self {
+            inner: Rc::new(LoansInner {
+                limit,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// How many lent blocks are still in use
+    fn outstanding(&self) -> usize {
+        let mut lent = self.inner.lent.borrow_mut();
+        lent.retain(|block| block.is_live());
+        lent.len()
+    }
+
+    /// How many more blocks may be lent out right now
+    pub(crate) fn room(&self) -> usize {
+        match self.inner.limit {
+            Some(limit) => limit.max_blocks.saturating_sub(self.outstanding()),
+            None => usize::MAX,
+        }
+    }
+
+    /// Hands `piece` over to a driver: as-is if that's within the limit,
+    /// as a copy otherwise.
+    pub(crate) fn lend(&self, piece: Piece) -> Piece {
+        let Some(block) = piece.block() else {
+            return piece;
+        };
+        let Some(limit) = self.inner.limit else {
+            return piece;
+        };
+
+        if self.inner.lent.borrow().contains(&block) {
+            return piece;
+        }
+        if self.outstanding() < limit.max_blocks {
+            self.inner.lent.borrow_mut().push(block);
+            return piece;
+        }
+
+        // a copy lets the block go back to the pool as soon as the
+        // connection is done reading into it
+        Piece::from(piece.to_vec())
+    }
+
+    /// Called before reading into a new block: waits for drivers to drop
+    /// enough chunks to be under the limit, see [LoanLimit].
+    pub(crate) async fn wait_for_room(&self) {
+        let Some(limit) = self.inner.limit else {
+            return;
+        };
+        if self.outstanding() < limit.max_blocks {
+            self.inner.gave_up.set(false);
+            return;
+        }
+        if self.inner.gave_up.get() {
+            return;
+        }
+
+        let room = async {
+            while self.outstanding() >= limit.max_blocks {
+                block_freed().await;
+            }
+        };
+        if tokio::time::timeout(limit.max_wait, room).await.is_err() {
+            debug!(
+                max_blocks = limit.max_blocks,
+                "drivers are holding on to body chunks, handing out copies"
+            );
+            self.inner.gave_up.set(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fluke_buffet::RollMut;
+
+    use super::{LoanLimit, Loans};
+
+    #[test]
+    fn loans() {
+        fluke_buffet::start(async move {
+            let loans = Loans::new(Some(LoanLimit {
+                max_blocks: 2,
+                max_wait: Duration::from_millis(20),
+            }));
+
+            let mut chunks = Vec::new();
+            for _ in 0..3 {
+                let mut buf = RollMut::alloc().unwrap();
+                buf.put(b"hello").unwrap();
+                chunks.push(loans.lend(buf.take_all().into()));
+            }
+            // the first two are lent as-is, the third is a copy
+            assert!(chunks[0].block().is_some());
+            assert!(chunks[1].block().is_some());
+            assert!(chunks[2].block().is_none());
+            assert_eq!(&chunks[2][..], b"hello");
+            assert_eq!(loans.room(), 0);
+
+            // pieces of a block that's already lent don't count twice
+            let again = chunks[0].clone();
+            assert!(loans.lend(again).block().is_some());
+
+            // dropping a chunk makes room, and wakes up a stalled reader
+            let dropped = chunks.remove(0);
+            fluke_buffet::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                drop(dropped);
+            });
+            loans.wait_for_room().await;
+            assert_eq!(loans.room(), 1);
+
+            // a reader that waited in vain doesn't stall again until there's
+            // room
+            let mut buf = RollMut::alloc().unwrap();
+            buf.put(b"world").unwrap();
+            chunks.push(loans.lend(buf.take_all().into()));
+            loans.wait_for_room().await;
+            assert!(loans.inner.gave_up.get());
+            loans.wait_for_room().await;
+        });
+    }
+}
@@ -0,0 +1,489 @@
+//! Where each request's time went, for metrics: a [Timed] driver wraps
+//! another one and reports every request it handles to a [Metrics]
+//! implementation, once the handler is done with it.
+//!
+//! ```no_run
+//! # async fn f(driver: impl fluke::ServerDriver + 'static) -> std::io::Result<()> {
+//! use fluke::metrics::{RequestSummary, Timed};
+//!
+//! let metrics = |summary: &RequestSummary| {
+//!     tracing::info!(ttfb = ?summary.phases.time_to_first_byte(), "request done");
+//! };
+//! let server = fluke::ServerBuilder::new("[::]:8080".parse().unwrap())
+//!     .build(Timed::new(driver, metrics).server_timing(true))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Time is split into phases, see [RequestPhases]. With
+//! [Timed::server_timing], they're also sent to the client, in
+//! `Server-Timing` fields (see [crate::ServerTiming]) for browser dev tools
+//! to show: `read` (getting the request head), `queue` and `app` (the
+//! handler, up to the response head) in the response head, and, for
+//! responses that end with trailers, `write` and `total` in the trailers.
+//! Drivers can add their own metrics next to those.
+//!
+//! [Metrics::on_connection_closed] also gets to see every connection the
+//! driver served close, along with its [CloseReason].
+//!
+//! [AccessLog] logs all of it, and [crate::ServerBuilder::build_with_metrics]
+//! wraps the driver of a server in a [Timed] one.
+
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+use fluke_buffet::{Piece, PieceList};
+use http::StatusCode;
+
+use crate::{
+    h1::body::BodyWriteMode, h2, h2::KnownErrorCode, Body, CloseReason, ConnInfo, Encoder,
+    ExpectResponseHeaders, FieldDecision, HeaderDecision, Headers, Method, Request,
+    RequestProtocol, Responder, Response, ResponseDone, ServerDriver, ServerTiming,
+};
+
+/// Receives the timings of every request a [Timed] driver handles
+pub trait Metrics {
+    /// Called once per request, when the handler returns, or when it gets
+    /// dropped (see [Outcome::Cancelled])
+    fn on_request(&self, summary: &RequestSummary);
+
+    /// Called once per connection, when it's closed, see
+    /// [ServerDriver::on_connection_closed]
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        _ = (conn, reason);
+    }
+}
+
+impl<F> Metrics for F
+where
+    F: Fn(&RequestSummary),
+{
+    fn on_request(&self, summary: &RequestSummary) {
+        self(summary)
+    }
+}
+
+/// Reports to both, e.g. an [AccessLog] next to an exporter
+impl<A, B> Metrics for (A, B)
+where
+    A: Metrics,
+    B: Metrics,
+{
+    fn on_request(&self, summary: &RequestSummary) {
+        self.0.on_request(summary);
+        self.1.on_request(summary);
+    }
+
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        self.0.on_connection_closed(conn, reason);
+        self.1.on_connection_closed(conn, reason);
+    }
+}
+
+/// Logs every request once it's handled, and every connection once it's
+/// closed, at the info level, with the `fluke::access` target
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccessLog;
+
+impl Metrics for AccessLog {
+    fn on_request(&self, summary: &RequestSummary) {
+        tracing::info!(
+            target: "fluke::access",
+            method = %summary.method,
+            protocol = ?summary.protocol,
+            status = summary.status.map(|s| s.as_u16()),
+            outcome = ?summary.outcome,
+            ttfb = ?summary.phases.time_to_first_byte(),
+            total = ?summary.phases.total,
+            rtt = ?summary.rtt,
+            "request"
+        );
+    }
+
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        tracing::info!(
+            target: "fluke::access",
+            close_reason = %reason,
+            rtt = ?conn.rtt(),
+            "connection closed"
+        );
+    }
+}
+
+/// A request, once handled
+#[derive(Debug, Clone)]
+pub struct RequestSummary {
+    pub method: Method,
+    pub protocol: RequestProtocol,
+
+    /// The status of the final response, if the handler got to write one
+    pub status: Option<StatusCode>,
+
+    pub outcome: Outcome,
+    pub phases: RequestPhases,
+
+    /// The connection's smoothed round-trip time once the request was
+    /// handled, if it was measured, see [ConnInfo::rtt]
+    pub rtt: Option<Duration>,
+}
+
+/// How a handler ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// It returned a finished response
+    Completed,
+
+    /// It returned an error
+    Failed,
+
+    /// It got dropped before returning: the client went away, the deadline
+    /// passed, the connection was closed...
+    Cancelled,
+}
+
+/// Where a request's time went, one phase after the other
+#[derive(Debug, Clone, Copy)]
+pub struct RequestPhases {
+    /// Getting the request head: from the connection being accepted for the
+    /// first request on a connection, from the head's first bytes coming
+    /// in for the others, see [crate::RequestTimings::time_to_head]
+    pub head: Duration,
+
+    /// Between the head being parsed and the handler starting
+    pub queued: Duration,
+
+    /// Between the handler starting and it handing the first response
+    /// (informational ones count) to be written. `None` if it never did.
+    pub first_byte: Option<Duration>,
+
+    /// How long the handler waited on the response (head, body and
+    /// trailers) to be written, overlapping with the other phases
+    pub write: Duration,
+
+    /// From the start of [RequestPhases::head] to the handler being done
+    pub total: Duration,
+}
+
+impl RequestPhases {
+    /// From the start of [RequestPhases::head] to the first response being
+    /// handed to be written
+    pub fn time_to_first_byte(&self) -> Option<Duration> {
+        Some(self.head + self.queued + self.first_byte?)
+    }
+}
+
+/// Reports request timings to a [Metrics] implementation, see the [module
+/// docs](self)
+pub struct Timed<D, M> {
+    inner: D,
+    metrics: M,
+    server_timing: bool,
+}
+
+impl<D, M> Timed<D, M> {
+    pub fn new(inner: D, metrics: M) -> Self {
+        Self {
+            inner,
+            metrics,
+            server_timing: false,
+        }
+    }
+
+    /// Whether to send phases to clients in `Server-Timing` fields, see the
+    /// [module docs](self). Off by default: it tells clients how busy the
+    /// server is.
+    pub fn server_timing(mut self, enabled: bool) -> Self {
+        self.server_timing = enabled;
+        self
+    }
+}
+
+impl<D, M> ServerDriver for Timed<D, M>
+where
+    D: ServerDriver,
+    M: Metrics,
+{
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let tracker = Tracker::new(&req, &self.metrics);
+        let respond = respond.map_encoder(|inner| TimedEncoder {
+            inner,
+            tracker: &tracker,
+            server_timing: self.server_timing,
+        });
+
+        let res = self.inner.handle(req, req_body, respond).await;
+        tracker.outcome.set(match &res {
+            Ok(_) => Outcome::Completed,
+            Err(_) => Outcome::Failed,
+        });
+        res.map(|respond| respond.map_encoder(|encoder| encoder.inner))
+    }
+
+    fn on_headers(&self, req: &Request) -> HeaderDecision {
+        self.inner.on_headers(req)
+    }
+
+    fn on_header_field(&self, name: &http::HeaderName, value: &[u8]) -> FieldDecision {
+        self.inner.on_header_field(name, value)
+    }
+
+    fn on_h2_connection(&self, frames: h2::ExtensionFrames) {
+        self.inner.on_h2_connection(frames)
+    }
+
+    fn on_extension_frame(&self, frame: h2::ExtensionFrame, frames: &h2::ExtensionFrames) {
+        self.inner.on_extension_frame(frame, frames)
+    }
+
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        self.metrics.on_connection_closed(conn, reason);
+        self.inner.on_connection_closed(conn, reason)
+    }
+}
+
+/// Follows a request through the handler, and reports it when dropped
+struct Tracker<'a, M: Metrics> {
+    metrics: &'a M,
+    method: Method,
+    protocol: RequestProtocol,
+    conn: ConnInfo,
+
+    started: Instant,
+    head: Duration,
+    queued: Duration,
+    handler_started: Instant,
+    first_byte: Cell<Option<Instant>>,
+    write: Cell<Duration>,
+    status: Cell<Option<StatusCode>>,
+    outcome: Cell<Outcome>,
+}
+
+impl<'a, M: Metrics> Tracker<'a, M> {
+    fn new(req: &Request, metrics: &'a M) -> Self {
+        let now = Instant::now();
+        let head_read = req.timings.head_read.unwrap_or(now);
+        let head = req.timings.time_to_head().unwrap_or_default();
+        Self {
+            metrics,
+            method: req.method.clone(),
+            protocol: req.protocol(),
+            conn: req.conn.clone(),
+
+            started: head_read.checked_sub(head).unwrap_or(head_read),
+            head,
+            queued: now.saturating_duration_since(head_read),
+            handler_started: now,
+            first_byte: Default::default(),
+            write: Default::default(),
+            status: Default::default(),
+            outcome: Cell::new(Outcome::Cancelled),
+        }
+    }
+
+    async fn time_write<T>(&self, f: impl std::future::Future<Output = T>) -> T {
+        let start = Instant::now();
+        let res = f.await;
+        self.write.set(self.write.get() + start.elapsed());
+        res
+    }
+}
+
+impl<M: Metrics> Drop for Tracker<'_, M> {
+    fn drop(&mut self) {
+        let summary = RequestSummary {
+            method: self.method.clone(),
+            protocol: self.protocol,
+            status: self.status.get(),
+            outcome: self.outcome.get(),
+            phases: RequestPhases {
+                head: self.head,
+                queued: self.queued,
+                first_byte: self
+                    .first_byte
+                    .get()
+                    .map(|at| at.saturating_duration_since(self.handler_started)),
+                write: self.write.get(),
+                total: self.started.elapsed(),
+            },
+            rtt: self.conn.rtt(),
+        };
+        self.metrics.on_request(&summary);
+    }
+}
+
+struct TimedEncoder<'a, 'm, E, M: Metrics> {
+    inner: E,
+    tracker: &'a Tracker<'m, M>,
+    server_timing: bool,
+}
+
+impl<E, M> Encoder for TimedEncoder<'_, '_, E, M>
+where
+    E: Encoder,
+    M: Metrics,
+{
+    async fn write_response(&mut self, mut res: Response) -> eyre::Result<()> {
+        if self.tracker.first_byte.get().is_none() {
+            self.tracker.first_byte.set(Some(Instant::now()));
+        }
+        if !res.status.is_informational() {
+            self.tracker.status.set(Some(res.status));
+            if self.server_timing {
+                let tracker = self.tracker;
+                ServerTiming::default()
+                    .metric("read", Some(tracker.head), None)
+                    .metric("queue", Some(tracker.queued), None)
+                    .metric("app", Some(tracker.handler_started.elapsed()), None)
+                    .append_to(&mut res.headers);
+            }
+        }
+        self.tracker
+            .time_write(self.inner.write_response(res))
+            .await
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()> {
+        let write = self.inner.write_body_chunk(chunk, mode);
+        self.tracker.time_write(write).await
+    }
+
+    async fn write_body_chunks(
+        &mut self,
+        chunks: PieceList,
+        mode: BodyWriteMode,
+    ) -> eyre::Result<()> {
+        let write = self.inner.write_body_chunks(chunks, mode);
+        self.tracker.time_write(write).await
+    }
+
+    async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()> {
+        self.tracker
+            .time_write(self.inner.write_body_end(mode))
+            .await
+    }
+
+    async fn write_trailers(&mut self, mut trailers: Box<Headers>) -> eyre::Result<()> {
+        if self.server_timing {
+            // the body is out: this is as late as metrics can be sent
+            let tracker = self.tracker;
+            ServerTiming::default()
+                .metric("write", Some(tracker.write.get()), None)
+                .metric("total", Some(tracker.started.elapsed()), None)
+                .append_to(&mut trailers);
+        }
+        self.tracker
+            .time_write(self.inner.write_trailers(trailers))
+            .await
+    }
+
+    async fn reset(&mut self, code: KnownErrorCode) -> eyre::Result<()> {
+        self.inner.reset(code).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        time::{Duration, Instant},
+    };
+
+    use fluke_buffet::Piece;
+    use http::StatusCode;
+
+    use super::{Outcome, RequestSummary, Timed};
+    use crate::{
+        body::once, h1::body::BodyWriteMode, Body, Encoder, ExpectResponseHeaders, Headers,
+        Request, RequestTimings, Responder, Response, ResponseDone, ServerDriver,
+    };
+
+    struct SlowDriver;
+
+    impl ServerDriver for SlowDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut res = Response::default();
+            res.status = StatusCode::CREATED;
+            respond.write_final_response_with_body(res, req_body).await
+        }
+    }
+
+    /// Keeps the `server-timing` header of the final response
+    #[derive(Default)]
+    struct ServerTimingHeader(Option<String>);
+
+    impl Encoder for ServerTimingHeader {
+        async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
+            self.0 = res
+                .headers
+                .get("server-timing")
+                .map(|v| String::from_utf8(v.to_vec()).unwrap());
+            Ok(())
+        }
+
+        async fn write_body_chunk(&mut self, _: Piece, _: BodyWriteMode) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        async fn write_body_end(&mut self, _: BodyWriteMode) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        async fn write_trailers(&mut self, _: Box<Headers>) -> eyre::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn request_phases() {
+        let summaries = RefCell::new(vec![]);
+        let metrics = |summary: &RequestSummary| summaries.borrow_mut().push(summary.clone());
+
+        let header = fluke_buffet::start(async {
+            let driver = Timed::new(SlowDriver, metrics).server_timing(true);
+
+            let now = Instant::now();
+            let req = Request {
+                timings: RequestTimings {
+                    accepted: Some(now - Duration::from_millis(30)),
+                    head_started: Some(now - Duration::from_millis(10)),
+                    head_read: Some(now),
+                },
+                ..Default::default()
+            };
+            let respond = Responder::new(ServerTimingHeader::default());
+            let respond = driver.handle(req, &mut once("hi"), respond).await.unwrap();
+            respond.into_inner().0
+        });
+
+        let summaries = summaries.into_inner();
+        let [summary] = &summaries[..] else {
+            panic!("expected one summary, got {summaries:?}")
+        };
+        assert_eq!(summary.status, Some(StatusCode::CREATED));
+        assert_eq!(summary.outcome, Outcome::Completed);
+
+        let phases = summary.phases;
+        assert_eq!(phases.head, Duration::from_millis(30));
+        assert!(phases.first_byte.unwrap() >= Duration::from_millis(20));
+        assert!(phases.time_to_first_byte().unwrap() >= Duration::from_millis(50));
+        assert!(phases.total >= phases.time_to_first_byte().unwrap());
+
+        let header = header.unwrap();
+        assert!(header.starts_with("read;dur=30, queue;dur="), "{header}");
+        assert!(header.contains(", app;dur="), "{header}");
+    }
+}
@@ -0,0 +1,110 @@
+//! Swapping configuration while a server runs. Connections read the
+//! configuration once, when they're accepted: reloading never affects
+//! connections that are already established.
+//!
+//! [ConfHandle] isn't tied to fluke's own configuration: whatever drivers
+//! want to reload, like the set of upstreams a proxy forwards to, can live
+//! in one too. Log levels are the exception, fluke doesn't read them: they
+//! belong to the `tracing` subscriber, which can reload its filter (see
+//! `tracing_subscriber::reload`).
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+
+/// A shared, swappable value. Cloning it gives another handle to the same
+/// value, and handles can be sent to other threads (a signal handler, an
+/// admin endpoint...) to reload configuration from there.
+pub struct ConfHandle<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    current: RwLock<Arc<T>>,
+    generation: AtomicU64,
+}
+
+impl<T> Clone for ConfHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> ConfHandle<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                current: RwLock::new(Arc::new(value)),
+                generation: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns the current value. It stays valid (and unchanged) even if
+    /// another value is stored afterwards.
+    pub fn load(&self) -> Arc<T> {
+        self.shared
+            .current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replaces the current value: only things that call [ConfHandle::load]
+    /// afterwards will see it.
+    pub fn store(&self, value: T) {
+        let mut current = self
+            .shared
+            .current
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        *current = Arc::new(value);
+        self.shared.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Stores a modified copy of the current value
+    pub fn update(&self, f: impl FnOnce(&mut T))
+    where
+        T: Clone,
+    {
+        let mut current = self
+            .shared
+            .current
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut value = T::clone(&current);
+        f(&mut value);
+        *current = Arc::new(value);
+        self.shared.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Incremented on every store, so callers can cheaply tell whether they
+    /// need to [ConfHandle::load] again.
+    pub fn generation(&self) -> u64 {
+        self.shared.generation.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfHandle;
+
+    #[test]
+    fn loaded_values_survive_stores() {
+        let handle = ConfHandle::new(1);
+        let before = handle.load();
+        assert_eq!(handle.generation(), 0);
+
+        let other = handle.clone();
+        std::thread::spawn(move || other.update(|v| *v += 1))
+            .join()
+            .unwrap();
+
+        assert_eq!(*before, 1);
+        assert_eq!(*handle.load(), 2);
+        assert_eq!(handle.generation(), 1);
+    }
+}
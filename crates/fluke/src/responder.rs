@@ -1,7 +1,12 @@
-use fluke_buffet::Piece;
+use fluke_buffet::{Piece, PieceList};
 use http::header;
 
-use crate::{h1::body::BodyWriteMode, Body, BodyChunk, Headers, HeadersExt, Response};
+use std::time::Instant;
+
+use crate::{
+    h1::body::BodyWriteMode, h2::KnownErrorCode, Body, BodyChunk, Deadline, Headers, HeadersExt,
+    InterimResponse, Response,
+};
 
 pub trait ResponseState {}
 
@@ -10,9 +15,18 @@ impl ResponseState for ExpectResponseHeaders {}
 
 pub struct ExpectResponseBody {
     mode: BodyWriteMode,
+
+    /// Chunks passed to [Responder::write_chunk_buffered] that haven't been
+    /// handed to the encoder yet
+    buffered: PieceList,
+    buffered_len: usize,
 }
 impl ResponseState for ExpectResponseBody {}
 
+/// How many bytes [Responder::write_chunk_buffered] accumulates before
+/// flushing on its own
+pub const BUFFERED_WRITE_THRESHOLD: usize = 16 * 1024;
+
 pub struct ResponseDone;
 impl ResponseState for ResponseDone {}
 
@@ -23,6 +37,8 @@ where
 {
     encoder: E,
     state: S,
+    deadline: Deadline,
+    head_request: bool,
 }
 
 impl<E> Responder<E, ExpectResponseHeaders>
@@ -33,17 +49,30 @@ where
         Self {
             encoder,
             state: ExpectResponseHeaders,
+            deadline: Default::default(),
+            head_request: false,
         }
     }
 
-    /// Send an informational status code, cf. <https://httpwg.org/specs/rfc9110.html#status.1xx>
-    /// Errors out if the response status is not 1xx
-    pub async fn write_interim_response(&mut self, res: Response) -> eyre::Result<()> {
-        if !res.status.is_informational() {
-            return Err(eyre::eyre!("interim response must have status code 1xx"));
-        }
+    /// Ties this responder to the deadline the server enforces for the
+    /// request, see [Responder::set_deadline]
+    pub fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = deadline;
+        self
+    }
 
-        self.encoder.write_response(res).await?;
+    /// Marks this as the response to a HEAD request: the final response
+    /// then goes out without a body, whatever its headers announce.
+    pub fn with_head_request(mut self, head_request: bool) -> Self {
+        self.head_request = head_request;
+        self
+    }
+
+    /// Send an informational status code, cf. <https://httpwg.org/specs/rfc9110.html#status.1xx>.
+    /// Any number of them may go out before the final response, none after
+    /// it.
+    pub async fn write_interim_response(&mut self, res: InterimResponse) -> eyre::Result<()> {
+        self.encoder.write_response(res.into()).await?;
         Ok(())
     }
 
@@ -58,7 +87,7 @@ where
             return Err(eyre::eyre!("final response must have status code >= 200"));
         }
 
-        let mode = if res.means_empty_body() {
+        let mode = if res.forbids_body(self.head_request) {
             // do nothing
             BodyWriteMode::Empty
         } else {
@@ -80,8 +109,14 @@ where
         self.encoder.write_response(res).await?;
 
         Ok(Responder {
-            state: ExpectResponseBody { mode },
+            state: ExpectResponseBody {
+                mode,
+                buffered: Default::default(),
+                buffered_len: 0,
+            },
             encoder: self.encoder,
+            deadline: self.deadline,
+            head_request: self.head_request,
         })
     }
 
@@ -110,8 +145,6 @@ where
                     this.write_chunk(chunk).await?;
                 }
                 BodyChunk::Done { trailers } => {
-                    // TODO: should we do something here in case of
-                    // content-length mismatches?
                     return this.finish_body(trailers).await;
                 }
             }
@@ -126,9 +159,36 @@ where
     /// Send a response body chunk. Errors out if sending more than the
     /// announced content-length.
     pub async fn write_chunk(&mut self, chunk: Piece) -> eyre::Result<()> {
+        self.flush().await?;
         self.encoder.write_body_chunk(chunk, self.state.mode).await
     }
 
+    /// Queue a response body chunk, to be sent along with the ones after it
+    /// on the next [Responder::flush] (or [Responder::write_chunk], or
+    /// [Responder::finish_body]), or as soon as
+    /// [BUFFERED_WRITE_THRESHOLD] bytes are queued. Saves a write for each
+    /// tiny chunk.
+    pub async fn write_chunk_buffered(&mut self, chunk: Piece) -> eyre::Result<()> {
+        self.state.buffered_len += chunk.len();
+        self.state.buffered.push_back(chunk);
+        if self.state.buffered_len >= BUFFERED_WRITE_THRESHOLD {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Send all chunks queued by [Responder::write_chunk_buffered]
+    pub async fn flush(&mut self) -> eyre::Result<()> {
+        if self.state.buffered.is_empty() {
+            return Ok(());
+        }
+        let chunks = std::mem::take(&mut self.state.buffered);
+        self.state.buffered_len = 0;
+        self.encoder
+            .write_body_chunks(chunks, self.state.mode)
+            .await
+    }
+
     /// Finish the body, with optional trailers, cf. <https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/TE>
     /// Errors out if the sent body doesn't match the announced content-length.
     /// Errors out if trailers that weren't announced are being sent, or if the
@@ -139,17 +199,66 @@ where
         mut self,
         trailers: Option<Box<Headers>>,
     ) -> eyre::Result<Responder<E, ResponseDone>> {
-        self.encoder.write_body_end(self.state.mode).await?;
+        self.flush().await?;
+        match trailers {
+            Some(trailers) => {
+                if self.state.mode != BodyWriteMode::Chunked {
+                    eyre::bail!("trailers can only follow a body of unknown length");
+                }
+                self.encoder.write_trailers(trailers).await?;
+            }
+            None => self.encoder.write_body_end(self.state.mode).await?,
+        }
+
+        Ok(Responder {
+            state: ResponseDone,
+            encoder: self.encoder,
+            deadline: self.deadline,
+            head_request: self.head_request,
+        })
+    }
+}
 
-        if let Some(trailers) = trailers {
-            self.encoder.write_trailers(trailers).await?;
+impl<E, S> Responder<E, S>
+where
+    E: Encoder,
+    S: ResponseState,
+{
+    /// Moves the deadline for this response: past it, the handler is
+    /// dropped and the response cut short (or replaced with a 504 if it
+    /// hadn't started). Also visible through [crate::Request::deadline].
+    pub fn set_deadline(&self, at: Instant) {
+        self.deadline.set(Some(at));
+    }
+
+    /// The current deadline for this response, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.get()
+    }
+
+    /// Swaps the encoder for another one, e.g. one wrapping it
+    pub(crate) fn map_encoder<F: Encoder>(self, f: impl FnOnce(E) -> F) -> Responder<F, S> {
+        Responder {
+            encoder: f(self.encoder),
+            state: self.state,
+            deadline: self.deadline,
+            head_request: self.head_request,
         }
+    }
 
-        // TODO: check announced content-length size vs actual, etc.
+    /// Abruptly ends the response, whether or not headers or part of the
+    /// body were sent. For HTTP/2, the stream is reset with `code` and the
+    /// rest of the connection is unaffected. HTTP/1.1 has no way to do that,
+    /// so this errors out: returning that error from the driver closes the
+    /// connection.
+    pub async fn reset(mut self, code: KnownErrorCode) -> eyre::Result<Responder<E, ResponseDone>> {
+        self.encoder.reset(code).await?;
 
         Ok(Responder {
             state: ResponseDone,
             encoder: self.encoder,
+            deadline: self.deadline,
+            head_request: self.head_request,
         })
     }
 }
@@ -163,10 +272,249 @@ where
     }
 }
 
+/// A [Responder] whose state is checked at runtime instead of being part of
+/// its type, for drivers that need to keep responders around in a collection,
+/// or hand them between code paths that don't agree on the state. Calls made
+/// in the wrong state error out.
+///
+/// If the encoder fails partway, the responder is poisoned: every call after
+/// that errors out too.
+pub struct DynResponder<E: Encoder> {
+    state: DynState<E>,
+}
+
+enum DynState<E: Encoder> {
+    Headers(Responder<E, ExpectResponseHeaders>),
+    Body(Responder<E, ExpectResponseBody>),
+    Done(Responder<E, ResponseDone>),
+    Poisoned,
+}
+
+impl<E: Encoder> DynState<E> {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Headers(_) => "expecting response headers",
+            Self::Body(_) => "expecting the response body",
+            Self::Done(_) => "done",
+            Self::Poisoned => "poisoned by an earlier error",
+        }
+    }
+}
+
+impl<E: Encoder> From<Responder<E, ExpectResponseHeaders>> for DynResponder<E> {
+    fn from(responder: Responder<E, ExpectResponseHeaders>) -> Self {
+        Self {
+            state: DynState::Headers(responder),
+        }
+    }
+}
+
+impl<E: Encoder> DynResponder<E> {
+    /// Whether the final response headers are still to be written
+    pub fn expects_headers(&self) -> bool {
+        matches!(self.state, DynState::Headers(_))
+    }
+
+    /// Whether the response is complete
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, DynState::Done(_))
+    }
+
+    fn wrong_state(&self, call: &str) -> eyre::Report {
+        eyre::eyre!("can't {call}: responder is {}", self.state.name())
+    }
+
+    /// See [Responder::write_interim_response]
+    pub async fn write_interim_response(&mut self, res: InterimResponse) -> eyre::Result<()> {
+        let DynState::Headers(responder) = &mut self.state else {
+            return Err(self.wrong_state("write an interim response"));
+        };
+        let res = responder.write_interim_response(res).await;
+        if res.is_err() {
+            self.state = DynState::Poisoned;
+        }
+        res
+    }
+
+    /// See [Responder::write_final_response]
+    pub async fn write_final_response(&mut self, res: Response) -> eyre::Result<()> {
+        let responder = match std::mem::replace(&mut self.state, DynState::Poisoned) {
+            DynState::Headers(responder) => responder,
+            state => {
+                self.state = state;
+                return Err(self.wrong_state("write the final response"));
+            }
+        };
+        self.state = DynState::Body(responder.write_final_response(res).await?);
+        Ok(())
+    }
+
+    /// See [Responder::write_chunk]
+    pub async fn write_chunk(&mut self, chunk: Piece) -> eyre::Result<()> {
+        let DynState::Body(responder) = &mut self.state else {
+            return Err(self.wrong_state("write a body chunk"));
+        };
+        let res = responder.write_chunk(chunk).await;
+        if res.is_err() {
+            self.state = DynState::Poisoned;
+        }
+        res
+    }
+
+    /// See [Responder::finish_body]
+    pub async fn finish_body(&mut self, trailers: Option<Box<Headers>>) -> eyre::Result<()> {
+        let responder = match std::mem::replace(&mut self.state, DynState::Poisoned) {
+            DynState::Body(responder) => responder,
+            state => {
+                self.state = state;
+                return Err(self.wrong_state("finish the body"));
+            }
+        };
+        self.state = DynState::Done(responder.finish_body(trailers).await?);
+        Ok(())
+    }
+
+    /// See [Responder::reset]. Works in any state but poisoned.
+    pub async fn reset(&mut self, code: KnownErrorCode) -> eyre::Result<()> {
+        let done = match std::mem::replace(&mut self.state, DynState::Poisoned) {
+            DynState::Headers(responder) => responder.reset(code).await?,
+            DynState::Body(responder) => responder.reset(code).await?,
+            DynState::Done(responder) => responder.reset(code).await?,
+            DynState::Poisoned => return Err(self.wrong_state("reset the response")),
+        };
+        self.state = DynState::Done(done);
+        Ok(())
+    }
+
+    /// Gets the typed responder back, to return from
+    /// [crate::ServerDriver::handle]. Errors out unless the response is done.
+    pub fn into_done(self) -> eyre::Result<Responder<E, ResponseDone>> {
+        match self.state {
+            DynState::Done(responder) => Ok(responder),
+            _ => Err(self.wrong_state("finish")),
+        }
+    }
+}
+
 #[allow(async_fn_in_trait)] // we never require Send
 pub trait Encoder {
     async fn write_response(&mut self, res: Response) -> eyre::Result<()>;
     async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()>;
+
+    /// Writes several body chunks at once, see
+    /// [Responder::write_chunk_buffered]. By default, they're written one
+    /// by one.
+    async fn write_body_chunks(
+        &mut self,
+        chunks: PieceList,
+        mode: BodyWriteMode,
+    ) -> eyre::Result<()> {
+        for chunk in chunks.into_vec_deque() {
+            self.write_body_chunk(chunk, mode).await?;
+        }
+        Ok(())
+    }
+
     async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()>;
+
+    /// Ends a chunked body with a trailer section: called instead of
+    /// [Encoder::write_body_end], not after it
     async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()>;
+
+    /// Abruptly ends the response, see [Responder::reset]
+    async fn reset(&mut self, code: KnownErrorCode) -> eyre::Result<()> {
+        Err(eyre::eyre!(
+            "can't reset a single response with {code:?} over this protocol"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fluke_buffet::Piece;
+    use http::StatusCode;
+
+    use super::{DynResponder, Encoder, Responder};
+    use crate::{h1::body::BodyWriteMode, Headers, InterimResponse, Response};
+
+    /// Records the status of every response written
+    #[derive(Default)]
+    struct Statuses(Vec<u16>);
+
+    impl Encoder for Statuses {
+        async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
+            self.0.push(res.status.as_u16());
+            Ok(())
+        }
+
+        async fn write_body_chunk(&mut self, _: Piece, _: BodyWriteMode) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        async fn write_body_end(&mut self, _: BodyWriteMode) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        async fn write_trailers(&mut self, _: Box<Headers>) -> eyre::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interim_responses() {
+        assert!(InterimResponse::new(StatusCode::OK).is_none());
+        assert!(InterimResponse::try_from(Response::default()).is_err());
+
+        fluke_buffet::start(async {
+            let mut respond = Responder::new(Statuses::default());
+            respond
+                .write_interim_response(InterimResponse::continue_100())
+                .await
+                .unwrap();
+            respond
+                .write_interim_response(InterimResponse::early_hints(Default::default()))
+                .await
+                .unwrap();
+            let respond = respond
+                .write_final_response(Response::default())
+                .await
+                .unwrap()
+                .finish_body(None)
+                .await
+                .unwrap();
+            assert_eq!(respond.into_inner().0, [100, 103, 200]);
+        });
+    }
+
+    #[test]
+    fn dyn_responder() {
+        fluke_buffet::start(async {
+            let mut respond = DynResponder::from(Responder::new(Statuses::default()));
+            assert!(respond.write_chunk("early".into()).await.is_err());
+            assert!(respond.expects_headers());
+
+            respond
+                .write_interim_response(InterimResponse::continue_100())
+                .await
+                .unwrap();
+            respond
+                .write_final_response(Response::default())
+                .await
+                .unwrap();
+            assert!(respond
+                .write_interim_response(InterimResponse::continue_100())
+                .await
+                .is_err());
+            assert!(respond
+                .write_final_response(Response::default())
+                .await
+                .is_err());
+            respond.write_chunk("body".into()).await.unwrap();
+            respond.finish_body(None).await.unwrap();
+            assert!(respond.is_done());
+
+            let respond = respond.into_done().unwrap();
+            assert_eq!(respond.into_inner().0, [100, 200]);
+        });
+    }
 }
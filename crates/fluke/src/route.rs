@@ -0,0 +1,327 @@
+//! Request routing, for drivers that serve more than a handful of paths.
+//!
+//! A [Router] maps a method and a path pattern to a value of your choosing,
+//! typically an enum naming the route, which the driver then matches on:
+//!
+//! ```
+//! use fluke::{route::Router, Method};
+//!
+//! enum Route {
+//!     User,
+//!     UserFiles,
+//! }
+//!
+//! let router = Router::new()
+//!     .route(Method::Get, "/users/:id", Route::User)
+//!     .route(Method::Get, "/users/:id/files/*path", Route::UserFiles);
+//!
+//! let m = router.find(&Method::Get, "/users/42/files/a/b.txt").unwrap();
+//! assert!(matches!(m.value, Route::UserFiles));
+//! assert_eq!(m.params.get("id"), Some("42"));
+//! assert_eq!(m.params.get("path"), Some("a/b.txt"));
+//! ```
+//!
+//! Patterns are made of `/`-separated segments: literals match themselves,
+//! `:name` matches any non-empty segment, and `*name`, which may only come
+//! last, matches the rest of the path (possibly nothing). Routes are tried in
+//! the order they were added.
+//!
+//! Matching doesn't allocate: parameters are slices of the request path,
+//! found again when asked for. They're not percent-decoded.
+
+use http::StatusCode;
+
+use crate::Method;
+
+/// A parsed path pattern, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+impl Pattern {
+    /// Parses a pattern like `/users/:id/*rest`.
+    ///
+    /// # Panics
+    ///
+    /// If the pattern doesn't start with `/`, has a parameter without a
+    /// name, or a `*` parameter that isn't last.
+    pub fn new(pattern: &str) -> Self {
+        let Some(rest) = pattern.strip_prefix('/') else {
+            panic!("route pattern {pattern:?} must start with '/'");
+        };
+
+        let mut segments = vec![];
+        let mut parts = rest.split('/').peekable();
+        while let Some(part) = parts.next() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "unnamed parameter in {pattern:?}");
+                Segment::Param(name.to_owned())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(!name.is_empty(), "unnamed parameter in {pattern:?}");
+                assert!(
+                    parts.peek().is_none(),
+                    "'*{name}' must be the last segment of {pattern:?}"
+                );
+                Segment::Rest(name.to_owned())
+            } else {
+                Segment::Literal(part.to_owned())
+            };
+            segments.push(segment);
+        }
+        Self { segments }
+    }
+
+    /// Whether `path` matches this pattern
+    pub fn matches(&self, path: &str) -> bool {
+        self.walk(path, |_, _| {})
+    }
+
+    /// Matches `path` against this pattern, calling `on_param` for every
+    /// parameter along the way.
+    fn walk<'p>(&self, path: &'p str, mut on_param: impl FnMut(&str, &'p str)) -> bool {
+        let Some(mut rest) = path.strip_prefix('/') else {
+            return false;
+        };
+
+        let mut segments = self.segments.iter();
+        loop {
+            let Some(segment) = segments.next() else {
+                // the path has more segments than the pattern
+                return false;
+            };
+
+            if let Segment::Rest(name) = segment {
+                on_param(name, rest);
+                return true;
+            }
+
+            let (part, after) = match rest.split_once('/') {
+                Some((part, after)) => (part, Some(after)),
+                None => (rest, None),
+            };
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Param(name) if !part.is_empty() => on_param(name, part),
+                _ => return false,
+            }
+
+            match after {
+                Some(after) => rest = after,
+                None => return segments.next().is_none(),
+            }
+        }
+    }
+}
+
+/// Maps requests to values of type `T`, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct Router<T> {
+    routes: Vec<Route<T>>,
+}
+
+#[derive(Debug, Clone)]
+struct Route<T> {
+    // `None` matches any method
+    method: Option<Method>,
+    pattern: Pattern,
+    value: T,
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Self { routes: vec![] }
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route for requests with the given method.
+    ///
+    /// # Panics
+    ///
+    /// If `pattern` is invalid, see [Pattern::new].
+    pub fn route(mut self, method: Method, pattern: &str, value: T) -> Self {
+        self.routes.push(Route {
+            method: Some(method),
+            pattern: Pattern::new(pattern),
+            value,
+        });
+        self
+    }
+
+    /// Adds a route for requests with any method.
+    ///
+    /// # Panics
+    ///
+    /// If `pattern` is invalid, see [Pattern::new].
+    pub fn any(mut self, pattern: &str, value: T) -> Self {
+        self.routes.push(Route {
+            method: None,
+            pattern: Pattern::new(pattern),
+            value,
+        });
+        self
+    }
+
+    /// Finds the first route matching `method` and `path` (as returned by
+    /// [http::Uri::path]).
+    pub fn find<'r, 'p>(
+        &'r self,
+        method: &Method,
+        path: &'p str,
+    ) -> Result<Match<'r, 'p, T>, NoRoute> {
+        let mut path_matched = false;
+        for route in &self.routes {
+            if !route.pattern.matches(path) {
+                continue;
+            }
+            if route.method.as_ref().map_or(true, |m| m == method) {
+                return Ok(Match {
+                    value: &route.value,
+                    params: Params {
+                        pattern: &route.pattern,
+                        path,
+                    },
+                });
+            }
+            path_matched = true;
+        }
+
+        Err(if path_matched {
+            NoRoute::MethodNotAllowed
+        } else {
+            NoRoute::NotFound
+        })
+    }
+}
+
+/// A route that matched, see [Router::find]
+#[derive(Debug)]
+pub struct Match<'r, 'p, T> {
+    /// The value the route was added with
+    pub value: &'r T,
+
+    /// The parameters captured from the path
+    pub params: Params<'r, 'p>,
+}
+
+/// Why [Router::find] came up empty
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoRoute {
+    /// No route matches the path
+    NotFound,
+
+    /// Some routes match the path, but not for this method
+    MethodNotAllowed,
+}
+
+impl NoRoute {
+    /// The status to respond with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            NoRoute::NotFound => StatusCode::NOT_FOUND,
+            NoRoute::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+        }
+    }
+}
+
+/// Parameters captured by a route's pattern
+#[derive(Debug, Clone, Copy)]
+pub struct Params<'r, 'p> {
+    pattern: &'r Pattern,
+    path: &'p str,
+}
+
+impl<'r, 'p> Params<'r, 'p> {
+    /// The value of the parameter called `name` (without `:` or `*`)
+    pub fn get(&self, name: &str) -> Option<&'p str> {
+        let mut found = None;
+        self.pattern.walk(self.path, |param, value| {
+            if found.is_none() && param == name {
+                found = Some(value);
+            }
+        });
+        found
+    }
+
+    /// Calls `f` with the name and value of every parameter, in order
+    pub fn for_each(&self, mut f: impl FnMut(&str, &'p str)) {
+        self.pattern.walk(self.path, |param, value| f(param, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NoRoute, Pattern, Router};
+    use crate::Method;
+
+    #[test]
+    fn patterns() {
+        let root = Pattern::new("/");
+        assert!(root.matches("/"));
+        assert!(!root.matches("/a"));
+        assert!(!root.matches(""));
+
+        let users = Pattern::new("/users/:id");
+        assert!(users.matches("/users/42"));
+        assert!(!users.matches("/users/"));
+        assert!(!users.matches("/users"));
+        assert!(!users.matches("/users/42/"));
+        assert!(!users.matches("/users/42/files"));
+        assert!(!users.matches("/people/42"));
+
+        let files = Pattern::new("/users/:id/*rest");
+        assert!(files.matches("/users/42/"));
+        assert!(files.matches("/users/42/a/b/"));
+        assert!(!files.matches("/users/42"));
+
+        let slash = Pattern::new("/dir/");
+        assert!(slash.matches("/dir/"));
+        assert!(!slash.matches("/dir"));
+    }
+
+    #[test]
+    fn router() {
+        let router = Router::new()
+            .route(Method::Get, "/users/:id", 1)
+            .route(Method::Delete, "/users/:id", 2)
+            .any("/users/:id/*rest", 3);
+
+        let m = router.find(&Method::Delete, "/users/42").unwrap();
+        assert_eq!(*m.value, 2);
+        assert_eq!(m.params.get("id"), Some("42"));
+        assert_eq!(m.params.get("rest"), None);
+
+        let m = router
+            .find(&Method::Other("PROPFIND".into()), "/users/42/a/b")
+            .unwrap();
+        assert_eq!(*m.value, 3);
+        let mut params = vec![];
+        m.params
+            .for_each(|name, value| params.push((name.to_owned(), value)));
+        assert_eq!(
+            params,
+            [("id".to_owned(), "42"), ("rest".to_owned(), "a/b")]
+        );
+
+        assert_eq!(
+            router.find(&Method::Post, "/users/42").unwrap_err(),
+            NoRoute::MethodNotAllowed
+        );
+        assert_eq!(
+            router.find(&Method::Get, "/groups/42").unwrap_err(),
+            NoRoute::NotFound
+        );
+    }
+}
@@ -0,0 +1,39 @@
+//! When things happened to a request on its way to the driver, see
+//! [crate::Request::timings].
+
+use std::time::{Duration, Instant};
+
+/// Set by the server as it reads a request. Requests that didn't come from
+/// a server (e.g. ones built for a client) have none of these.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestTimings {
+    /// When the connection was accepted (when serving it started), for the
+    /// first request on a connection only: later ones didn't wait on it
+    pub accepted: Option<Instant>,
+
+    /// When the first bytes of the request head came in: for HTTP/1.1, the
+    /// first read after the previous request (or right away, if it was
+    /// pipelined), for HTTP/2, the `HEADERS` frame
+    pub head_started: Option<Instant>,
+
+    /// When the request head was parsed
+    pub head_read: Option<Instant>,
+}
+
+impl RequestTimings {
+    /// How long it took to read the request head
+    pub fn read_head(&self) -> Option<Duration> {
+        Some(
+            self.head_read?
+                .saturating_duration_since(self.head_started?),
+        )
+    }
+
+    /// How long it took to get to a parsed request head: counting from the
+    /// connection being accepted for the first request on a connection,
+    /// like [RequestTimings::read_head] for the others
+    pub fn time_to_head(&self) -> Option<Duration> {
+        let start = self.accepted.or(self.head_started)?;
+        Some(self.head_read?.saturating_duration_since(start))
+    }
+}
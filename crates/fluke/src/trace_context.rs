@@ -0,0 +1,462 @@
+//! Taking part in distributed tracing: a [Traced] driver reads the trace
+//! context a request came with (W3C `traceparent` / `tracestate`,
+//! <https://www.w3.org/TR/trace-context/>, and optionally B3), runs the
+//! handler in a span that carries it, and passes a context naming that span
+//! as the parent along in the request headers.
+//!
+//! ```no_run
+//! # async fn f(driver: impl fluke::ServerDriver + 'static) -> std::io::Result<()> {
+//! use fluke::trace_context::Traced;
+//!
+//! let server = fluke::ServerBuilder::new("[::]:8080".parse().unwrap())
+//!     .build(Traced::new(driver).b3(true))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Proxies that forward request headers upstream propagate the trace
+//! without any code of their own: the upstream sees the request as a child
+//! of the proxy's span. Requests made from scratch can carry a context with
+//! [TraceContext::inject].
+//!
+//! The span is named `request`, with `trace_id`, `span_id` and
+//! `parent_span_id` fields, hex-encoded: that's enough to correlate logs
+//! across services. Exporting spans to a collector is up to the
+//! subscriber.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+};
+
+use http::header::HeaderName;
+use tracing::{info_span, Instrument};
+
+use crate::{
+    h2, Body, CloseReason, ConnInfo, Encoder, ExpectResponseHeaders, FieldDecision, HeaderDecision,
+    Headers, Request, Responder, ResponseDone, ServerDriver,
+};
+
+const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+const TRACESTATE: HeaderName = HeaderName::from_static("tracestate");
+const B3: HeaderName = HeaderName::from_static("b3");
+const B3_TRACE_ID: HeaderName = HeaderName::from_static("x-b3-traceid");
+const B3_SPAN_ID: HeaderName = HeaderName::from_static("x-b3-spanid");
+const B3_PARENT_SPAN_ID: HeaderName = HeaderName::from_static("x-b3-parentspanid");
+const B3_SAMPLED: HeaderName = HeaderName::from_static("x-b3-sampled");
+const B3_FLAGS: HeaderName = HeaderName::from_static("x-b3-flags");
+
+/// Where a request sits in a distributed trace
+#[derive(Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The whole trace's ID, never 0
+    pub trace_id: u128,
+
+    /// The ID of the span the request belongs to (on the caller's side,
+    /// for incoming requests), never 0
+    pub span_id: u64,
+
+    /// Trace flags, see [TraceContext::sampled]
+    pub flags: u8,
+
+    /// Vendor-specific data (`tracestate`), passed along as-is
+    pub state: Option<String>,
+}
+
+impl fmt::Debug for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceContext")
+            .field("trace_id", &format_args!("{:032x}", self.trace_id))
+            .field("span_id", &format_args!("{:016x}", self.span_id))
+            .field("flags", &self.flags)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl TraceContext {
+    /// The `sampled` flag: whether the caller may have recorded its span
+    pub const SAMPLED: u8 = 0x01;
+
+    /// Starts a new trace
+    pub fn new_root(sampled: bool) -> Self {
+        Self {
+            trace_id: (random_id() as u128) << 64 | random_id() as u128,
+            span_id: random_id(),
+            flags: if sampled { Self::SAMPLED } else { 0 },
+            state: None,
+        }
+    }
+
+    /// A context for a new span, child of this one, in the same trace
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: random_id(),
+            ..self.clone()
+        }
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & Self::SAMPLED != 0
+    }
+
+    /// Reads the W3C `traceparent` and `tracestate` headers. Invalid
+    /// `traceparent` headers are ignored, as the spec says, and so is
+    /// `tracestate` without a valid `traceparent`.
+    pub fn extract(headers: &Headers) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT)?;
+        let (trace_id, span_id, flags) = parse_traceparent(traceparent)?;
+
+        // several fields combine into one list
+        let state: Vec<&str> = headers
+            .get_all(TRACESTATE)
+            .iter()
+            .filter_map(|value| std::str::from_utf8(value).ok())
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .collect();
+        let state = (!state.is_empty()).then(|| state.join(","));
+
+        Some(Self {
+            trace_id,
+            span_id,
+            flags,
+            state,
+        })
+    }
+
+    /// Reads B3 headers (<https://github.com/openzipkin/b3-propagation>),
+    /// the single `b3` header or the `x-b3-*` ones. 64-bit trace IDs are
+    /// widened to 128 bits.
+    pub fn extract_b3(headers: &Headers) -> Option<Self> {
+        let str_of = |name: HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| std::str::from_utf8(value).ok())
+        };
+
+        let (trace_id, span_id, sampled) = match str_of(B3) {
+            Some(single) => {
+                let mut parts = single.split('-');
+                let trace_id = parts.next()?;
+                let span_id = parts.next()?;
+                (trace_id, span_id, parts.next())
+            }
+            None => (
+                str_of(B3_TRACE_ID)?,
+                str_of(B3_SPAN_ID)?,
+                match str_of(B3_FLAGS) {
+                    Some("1") => Some("d"),
+                    _ => str_of(B3_SAMPLED),
+                },
+            ),
+        };
+
+        if trace_id.len() != 16 && trace_id.len() != 32 {
+            return None;
+        }
+        let trace_id = parse_hex(trace_id, trace_id.len())?;
+        let span_id = parse_hex(span_id, 16)? as u64;
+        if trace_id == 0 || span_id == 0 {
+            return None;
+        }
+
+        // "d" is debug, which implies sampled
+        let sampled = matches!(sampled, Some("1" | "d" | "true"));
+        Some(Self {
+            trace_id,
+            span_id,
+            flags: if sampled { Self::SAMPLED } else { 0 },
+            state: None,
+        })
+    }
+
+    /// `00-{trace_id}-{span_id}-{flags}`
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.flags
+        )
+    }
+
+    /// Writes the context as W3C headers, replacing any trace context
+    /// headers (B3 ones included) that were there
+    pub fn inject(&self, headers: &mut Headers) {
+        remove_all(headers);
+        headers.insert(TRACEPARENT, self.traceparent().into_bytes().into());
+        if let Some(state) = &self.state {
+            headers.insert(TRACESTATE, state.clone().into_bytes().into());
+        }
+    }
+
+    /// Like [TraceContext::inject], plus the single `b3` header, for
+    /// services that only understand that
+    pub fn inject_with_b3(&self, headers: &mut Headers) {
+        self.inject(headers);
+        let b3 = format!(
+            "{:032x}-{:016x}-{}",
+            self.trace_id,
+            self.span_id,
+            if self.sampled() { 1 } else { 0 }
+        );
+        headers.insert(B3, b3.into_bytes().into());
+    }
+}
+
+/// `{version}-{trace_id}-{parent_id}-{flags}`, where versions after `00`
+/// may add fields after those
+fn parse_traceparent(value: &[u8]) -> Option<(u128, u64, u8)> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    if value.len() < 55 || !value.is_ascii() {
+        return None;
+    }
+    let (head, rest) = value.split_at(55);
+
+    let version = parse_hex(&head[..2], 2)?;
+    match version {
+        // forbidden
+        0xff => return None,
+        0 if !rest.is_empty() => return None,
+        _ if !rest.is_empty() && !rest.starts_with('-') => return None,
+        _ => {}
+    }
+    let bytes = head.as_bytes();
+    if bytes[2] != b'-' || bytes[35] != b'-' || bytes[52] != b'-' {
+        return None;
+    }
+
+    let trace_id = parse_hex(&head[3..35], 32)?;
+    let span_id = parse_hex(&head[36..52], 16)? as u64;
+    let flags = parse_hex(&head[53..55], 2)? as u8;
+    if trace_id == 0 || span_id == 0 {
+        return None;
+    }
+    Some((trace_id, span_id, flags))
+}
+
+/// Lowercase hex only, exactly `len` digits
+fn parse_hex(s: &str, len: usize) -> Option<u128> {
+    if s.len() != len || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    u128::from_str_radix(s, 16).ok()
+}
+
+fn remove_all(headers: &mut Headers) {
+    for name in [
+        TRACEPARENT,
+        TRACESTATE,
+        B3,
+        B3_TRACE_ID,
+        B3_SPAN_ID,
+        B3_PARENT_SPAN_ID,
+        B3_SAMPLED,
+        B3_FLAGS,
+    ] {
+        headers.remove(name);
+    }
+}
+
+/// A random, non-zero ID. Not cryptographically secure, which trace IDs
+/// don't need to be: `RandomState` is randomly seeded per thread, and
+/// every instance gets different keys.
+fn random_id() -> u64 {
+    loop {
+        let id = RandomState::new().build_hasher().finish();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+/// Propagates trace context, see the [module docs](self)
+pub struct Traced<D> {
+    inner: D,
+    b3: bool,
+    sample_new_traces: bool,
+}
+
+impl<D> Traced<D> {
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            b3: false,
+            sample_new_traces: true,
+        }
+    }
+
+    /// Whether to read B3 headers too, when there's no `traceparent`, and
+    /// to pass a `b3` header along next to the W3C ones. Off by default.
+    pub fn b3(mut self, enabled: bool) -> Self {
+        self.b3 = enabled;
+        self
+    }
+
+    /// Whether traces started here (for requests that came without a
+    /// context) are flagged as sampled. On by default.
+    pub fn sample_new_traces(mut self, sampled: bool) -> Self {
+        self.sample_new_traces = sampled;
+        self
+    }
+}
+
+impl<D> ServerDriver for Traced<D>
+where
+    D: ServerDriver,
+{
+    async fn handle<E: Encoder>(
+        &self,
+        mut req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let parent = TraceContext::extract(&req.headers).or_else(|| {
+            self.b3
+                .then(|| TraceContext::extract_b3(&req.headers))
+                .flatten()
+        });
+        let ctx = match &parent {
+            Some(parent) => parent.child(),
+            None => TraceContext::new_root(self.sample_new_traces),
+        };
+
+        let span = info_span!(
+            "request",
+            method = %req.method,
+            uri = %req.uri,
+            trace_id = %format!("{:032x}", ctx.trace_id),
+            span_id = %format!("{:016x}", ctx.span_id),
+            parent_span_id = parent
+                .as_ref()
+                .map(|p| tracing::field::display(format!("{:016x}", p.span_id))),
+        );
+
+        if self.b3 {
+            ctx.inject_with_b3(&mut req.headers);
+        } else {
+            ctx.inject(&mut req.headers);
+        }
+        self.inner
+            .handle(req, req_body, respond)
+            .instrument(span)
+            .await
+    }
+
+    fn on_headers(&self, req: &Request) -> HeaderDecision {
+        self.inner.on_headers(req)
+    }
+
+    fn on_header_field(&self, name: &http::HeaderName, value: &[u8]) -> FieldDecision {
+        self.inner.on_header_field(name, value)
+    }
+
+    fn on_h2_connection(&self, frames: h2::ExtensionFrames) {
+        self.inner.on_h2_connection(frames)
+    }
+
+    fn on_extension_frame(&self, frame: h2::ExtensionFrame, frames: &h2::ExtensionFrames) {
+        self.inner.on_extension_frame(frame, frames)
+    }
+
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        self.inner.on_connection_closed(conn, reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fluke_buffet::Piece;
+
+    use super::TraceContext;
+    use crate::Headers;
+
+    fn headers(fields: &[(&'static str, &'static str)]) -> Headers {
+        let mut headers = Headers::default();
+        for (name, value) in fields {
+            headers.append(*name, Piece::from(*value));
+        }
+        headers
+    }
+
+    #[test]
+    fn traceparent() {
+        let ctx = TraceContext::extract(&headers(&[
+            (
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            ),
+            ("tracestate", "congo=t61rcWkgMzE"),
+            ("tracestate", "rojo=00f067aa0ba902b7"),
+        ]))
+        .unwrap();
+        assert_eq!(ctx.trace_id, 0x0af7651916cd43dd8448eb211c80319c);
+        assert_eq!(ctx.span_id, 0xb7ad6b7169203331);
+        assert!(ctx.sampled());
+        assert_eq!(
+            ctx.state.as_deref(),
+            Some("congo=t61rcWkgMzE,rojo=00f067aa0ba902b7")
+        );
+
+        let child = ctx.child();
+        assert_eq!(child.trace_id, ctx.trace_id);
+        assert_ne!(child.span_id, ctx.span_id);
+
+        let mut out = headers(&[("b3", "whatever"), ("tracestate", "old=1")]);
+        child.inject(&mut out);
+        assert!(out.get("b3").is_none());
+        assert_eq!(out.get_all("tracestate").iter().count(), 1);
+        assert_eq!(TraceContext::extract(&out), Some(child));
+
+        // future versions may add fields, 00 may not
+        assert!(TraceContext::extract(&headers(&[(
+            "traceparent",
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+        )]))
+        .is_some());
+        for invalid in [
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00_0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        ] {
+            assert!(
+                TraceContext::extract(&headers(&[("traceparent", invalid)])).is_none(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn b3() {
+        let single = TraceContext::extract_b3(&headers(&[(
+            "b3",
+            "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1-05e3ac9a4f6e3b90",
+        )]))
+        .unwrap();
+        assert_eq!(single.trace_id, 0x80f198ee56343ba864fe8b2a57d3eff7);
+        assert_eq!(single.span_id, 0xe457b5a2e4d86bd1);
+        assert!(single.sampled());
+
+        let multi = TraceContext::extract_b3(&headers(&[
+            ("x-b3-traceid", "64fe8b2a57d3eff7"),
+            ("x-b3-spanid", "e457b5a2e4d86bd1"),
+            ("x-b3-sampled", "0"),
+        ]))
+        .unwrap();
+        assert_eq!(multi.trace_id, 0x64fe8b2a57d3eff7);
+        assert!(!multi.sampled());
+
+        let mut out = Headers::default();
+        single.inject_with_b3(&mut out);
+        assert_eq!(
+            &out["b3"][..],
+            b"80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1"
+        );
+        assert_eq!(TraceContext::extract(&out), Some(single));
+    }
+}
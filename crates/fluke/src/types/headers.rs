@@ -1,11 +1,188 @@
 //! Types for HTTP headers
 
-use http::{header, HeaderMap};
+use std::{borrow::Cow, collections::HashMap, fmt::Write, time::Duration};
+
+use http::{
+    header::{self, InvalidHeaderName},
+    HeaderMap, HeaderName,
+};
 
 use fluke_buffet::Piece;
 
+use crate::h1::parse::is_tchar;
+
 pub type Headers = HeaderMap<Piece>;
 
+/// What the HTTP/1.1 server does with requests that have more than one
+/// `host`, `content-length` or `authorization` header, which may only appear
+/// once.
+///
+/// `content-length` headers with different values are rejected no matter
+/// what: there's no telling where the body ends (RFC 9112, section 6.3).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateHeaderPolicy {
+    /// Reply with a 400 response
+    #[default]
+    Reject,
+
+    /// Keep the first one, ignore the others
+    FirstWins,
+
+    /// Keep the last one, ignore the others
+    LastWins,
+}
+
+impl DuplicateHeaderPolicy {
+    /// Headers the policy applies to
+    pub(crate) const SINGLETONS: [header::HeaderName; 3] =
+        [header::HOST, header::CONTENT_LENGTH, header::AUTHORIZATION];
+}
+
+/// How to spell header names when serializing HTTP/1.1 messages.
+///
+/// Field names are case-insensitive, and [Headers] stores them lowercase
+/// (HTTP/2 requires it), which is how they're written out by default. Some
+/// HTTP/1.1 peers only understand, say, `X-API-Key` though: when proxying
+/// HTTP/2 traffic to (or from) those, this restores the spelling they
+/// expect. See [crate::h1::ServerConf::header_case] and
+/// [crate::h1::ClientConf::header_case].
+#[derive(Debug, Clone, Default)]
+pub struct HeaderCaseMap {
+    spellings: HashMap<HeaderName, Cow<'static, str>>,
+
+    /// for names that aren't in `spellings`
+    title_case: bool,
+}
+
+impl HeaderCaseMap {
+    /// Writes names that weren't given a spelling with
+    /// [HeaderCaseMap::insert] in title case: `content-type` becomes
+    /// `Content-Type`, `x-api-key` becomes `X-Api-Key`.
+    pub fn title_case() -> Self {
+        Self {
+            title_case: true,
+            ..Default::default()
+        }
+    }
+
+    /// Writes the header named `spelling` (whatever its case) as
+    /// `spelling`, e.g. `X-API-Key`
+    pub fn insert(
+        &mut self,
+        spelling: impl Into<Cow<'static, str>>,
+    ) -> Result<(), InvalidHeaderName> {
+        let spelling = spelling.into();
+        let name = HeaderName::from_bytes(spelling.as_bytes())?;
+        self.spellings.insert(name, spelling);
+        Ok(())
+    }
+
+    /// How to write `name` out
+    pub fn spell<'a>(&'a self, name: &'a HeaderName) -> Cow<'a, str> {
+        if let Some(spelling) = self.spellings.get(name) {
+            return Cow::Borrowed(spelling);
+        }
+        if !self.title_case {
+            return Cow::Borrowed(name.as_str());
+        }
+
+        let mut upper = true;
+        let spelling = name
+            .as_str()
+            .chars()
+            .map(|c| {
+                let c = if upper { c.to_ascii_uppercase() } else { c };
+                upper = c == '-';
+                c
+            })
+            .collect();
+        Cow::Owned(spelling)
+    }
+}
+
+/// Builds a `Server-Timing` field (<https://www.w3.org/TR/server-timing/>):
+/// metrics about how the server handled a request, that browsers show in
+/// their dev tools.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use fluke::{Response, ServerTiming};
+///
+/// let mut res = Response::default();
+/// ServerTiming::default()
+///     .metric("db", Some(Duration::from_micros(53_200)), Some("primary"))
+///     .metric("cache", None, Some("no hit"))
+///     .append_to(&mut res.headers);
+/// assert_eq!(
+///     &res.headers["server-timing"][..],
+///     b"db;dur=53.2;desc=primary, cache;desc=\"no hit\""
+/// );
+/// ```
+///
+/// Fields from several places (a driver, the [crate::metrics::Timed]
+/// wrapper...) add up: each can append its own.
+#[derive(Debug, Clone, Default)]
+pub struct ServerTiming {
+    value: String,
+}
+
+impl ServerTiming {
+    /// Adds a metric. `name` must be a token: other characters are replaced
+    /// with `_`. Durations are sent in milliseconds, down to the
+    /// microsecond.
+    pub fn metric(&mut self, name: &str, dur: Option<Duration>, desc: Option<&str>) -> &mut Self {
+        if !self.value.is_empty() {
+            self.value.push_str(", ");
+        }
+        self.value.extend(name.bytes().map(|b| match b {
+            b if is_tchar(b) => b as char,
+            _ => '_',
+        }));
+
+        if let Some(dur) = dur {
+            _ = write!(self.value, ";dur={}", dur.as_micros() as f64 / 1000.0);
+        }
+        if let Some(desc) = desc {
+            self.value.push_str(";desc=");
+            if !desc.is_empty() && desc.bytes().all(is_tchar) {
+                self.value.push_str(desc);
+            } else {
+                self.value.push('"');
+                for c in desc.chars() {
+                    match c {
+                        '"' | '\\' => {
+                            self.value.push('\\');
+                            self.value.push(c);
+                        }
+                        // not allowed in quoted strings
+                        c if c.is_control() && c != '\t' => self.value.push(' '),
+                        c => self.value.push(c),
+                    }
+                }
+                self.value.push('"');
+            }
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// Adds a `server-timing` field with the metrics to `headers` (or
+    /// trailers), unless there are none
+    pub fn append_to(&self, headers: &mut Headers) {
+        if self.is_empty() {
+            return;
+        }
+        headers.append(
+            HeaderName::from_static("server-timing"),
+            self.value.clone().into_bytes().into(),
+        );
+    }
+}
+
 pub trait HeadersExt {
     /// Returns the content-length header
     fn content_length(&self) -> Option<u64>;
@@ -13,6 +190,10 @@ pub trait HeadersExt {
     /// Returns true if we have a `connection: close` header
     fn is_connection_close(&self) -> bool;
 
+    /// Returns true if the `connection` header lists `keep-alive`, which
+    /// HTTP/1.0 clients must send to reuse the connection
+    fn is_connection_keep_alive(&self) -> bool;
+
     /// Returns true if we have a `transfer-encoding: chunked` header
     fn is_chunked_transfer_encoding(&self) -> bool;
 
@@ -32,6 +213,14 @@ impl HeadersExt for HeaderMap<Piece> {
             .map_or(false, |value| value.eq_ignore_ascii_case(b"close"))
     }
 
+    fn is_connection_keep_alive(&self) -> bool {
+        self.get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| std::str::from_utf8(value).ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("keep-alive"))
+    }
+
     fn is_chunked_transfer_encoding(&self) -> bool {
         self.get(header::TRANSFER_ENCODING)
             .map_or(false, |value| value.eq_ignore_ascii_case(b"chunked"))
@@ -43,7 +232,7 @@ impl HeadersExt for HeaderMap<Piece> {
     }
 }
 
-fn from_digits(bytes: &[u8]) -> Option<u64> {
+pub(crate) fn from_digits(bytes: &[u8]) -> Option<u64> {
     // cannot use FromStr for u64, since it allows a signed prefix
     let mut result = 0u64;
     const RADIX: u64 = 10;
@@ -69,3 +258,48 @@ fn from_digits(bytes: &[u8]) -> Option<u64> {
 
     Some(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::{header, HeaderName};
+
+    use super::{HeaderCaseMap, Headers, ServerTiming};
+
+    #[test]
+    fn header_case_map() {
+        let custom = HeaderName::from_static("x-api-key");
+
+        let mut map = HeaderCaseMap::default();
+        map.insert("X-API-Key").unwrap();
+        assert_eq!(map.spell(&custom), "X-API-Key");
+        assert_eq!(map.spell(&header::CONTENT_TYPE), "content-type");
+
+        let mut map = HeaderCaseMap::title_case();
+        assert_eq!(map.spell(&custom), "X-Api-Key");
+        map.insert("ETag").unwrap();
+        assert_eq!(map.spell(&header::ETAG), "ETag");
+        assert_eq!(map.spell(&header::CONTENT_TYPE), "Content-Type");
+
+        assert!(map.insert("not a header").is_err());
+    }
+
+    #[test]
+    fn server_timing() {
+        let mut timing = ServerTiming::default();
+        assert!(timing.is_empty());
+        let mut headers = Headers::default();
+        timing.append_to(&mut headers);
+        assert!(headers.is_empty());
+
+        timing
+            .metric("total", Some(Duration::from_millis(12)), None)
+            .metric("bad name", None, Some("say \"hi\"\n"));
+        timing.append_to(&mut headers);
+        assert_eq!(
+            &headers["server-timing"][..],
+            b"total;dur=12, bad_name;desc=\"say \\\"hi\\\" \""
+        );
+    }
+}
@@ -2,6 +2,8 @@ use std::fmt;
 
 use fluke_buffet::{Piece, PieceStr};
 
+use crate::util::SemanticError;
+
 /// An HTTP method, see <https://httpwg.org/specs/rfc9110.html#methods>
 #[derive(Clone, PartialEq, Eq)]
 pub enum Method {
@@ -25,7 +27,13 @@ impl fmt::Debug for Method {
 
 impl fmt::Display for Method {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s = match self {
+        f.pad(self.as_str())
+    }
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
             Method::Get => "GET",
             Method::Head => "HEAD",
             Method::Post => "POST",
@@ -35,12 +43,9 @@ impl fmt::Display for Method {
             Method::Options => "OPTIONS",
             Method::Trace => "TRACE",
             Method::Other(s) => s,
-        };
-        f.pad(s)
+        }
     }
-}
 
-impl Method {
     pub fn into_chunk(self) -> Piece {
         let s = match self {
             Method::Get => "GET",
@@ -72,3 +77,123 @@ impl From<PieceStr> for Method {
         }
     }
 }
+
+/// Which request methods the HTTP/1.1 server accepts, and how they're read.
+///
+/// By default any token goes: methods [Method] doesn't know about come
+/// through as [Method::Other], as sent. Methods are case-sensitive (RFC 9110,
+/// section 9.1), so `get` is one of those too.
+#[derive(Debug, Clone, Default)]
+pub struct MethodPolicy {
+    /// Read known methods case-insensitively: `get` becomes [Method::Get].
+    /// Extension methods are still passed on as sent.
+    pub case_insensitive: bool,
+
+    /// Only accept methods made of uppercase letters, digits, `-` and `_`
+    /// (and lowercase letters, with `case_insensitive`), instead of any
+    /// token. Others get a 400 response.
+    pub strict_charset: bool,
+
+    /// Extension methods to accept, e.g. `PROPFIND` and `REPORT` for WebDAV.
+    /// `None` accepts any. Otherwise, methods that are neither known nor
+    /// listed get a 501 response.
+    pub extension_methods: Option<Vec<String>>,
+}
+
+impl MethodPolicy {
+    pub(crate) fn apply(&self, method: Method) -> Result<Method, SemanticError> {
+        let Method::Other(s) = method else {
+            return Ok(method);
+        };
+
+        if self.strict_charset {
+            let valid = |c: u8| {
+                c.is_ascii_uppercase()
+                    || c.is_ascii_digit()
+                    || c == b'-'
+                    || c == b'_'
+                    || (self.case_insensitive && c.is_ascii_lowercase())
+            };
+            if !s.bytes().all(valid) {
+                return Err(SemanticError::InvalidMethod);
+            }
+        }
+
+        if self.case_insensitive {
+            let known = Method::from(PieceStr::from(s.to_ascii_uppercase()));
+            if !matches!(known, Method::Other(_)) {
+                return Ok(known);
+            }
+        }
+
+        if let Some(allowed) = &self.extension_methods {
+            let eq = |a: &String| {
+                if self.case_insensitive {
+                    a.eq_ignore_ascii_case(&s)
+                } else {
+                    a[..] == s[..]
+                }
+            };
+            if !allowed.iter().any(eq) {
+                return Err(SemanticError::MethodNotImplemented);
+            }
+        }
+
+        Ok(Method::Other(s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fluke_buffet::PieceStr;
+
+    use super::{Method, MethodPolicy};
+    use crate::util::SemanticError;
+
+    fn method(s: &'static str) -> Method {
+        PieceStr::from(s).into()
+    }
+
+    #[test]
+    fn policy() {
+        let lax = MethodPolicy::default();
+        assert_eq!(lax.apply(method("GET")).unwrap(), Method::Get);
+        assert_eq!(lax.apply(method("get")).unwrap().as_str(), "get");
+        assert_eq!(
+            lax.apply(method("pRoP.fInD")).unwrap().as_str(),
+            "pRoP.fInD"
+        );
+
+        let webdav = MethodPolicy {
+            case_insensitive: true,
+            strict_charset: true,
+            extension_methods: Some(vec!["PROPFIND".into(), "REPORT".into()]),
+        };
+        assert_eq!(webdav.apply(method("get")).unwrap(), Method::Get);
+        assert_eq!(
+            webdav.apply(method("PropFind")).unwrap().as_str(),
+            "PropFind"
+        );
+        assert!(matches!(
+            webdav.apply(method("BREW")),
+            Err(SemanticError::MethodNotImplemented)
+        ));
+        assert!(matches!(
+            webdav.apply(method("PROP.FIND")),
+            Err(SemanticError::InvalidMethod)
+        ));
+
+        let strict = MethodPolicy {
+            strict_charset: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            strict.apply(method("get")),
+            Err(SemanticError::InvalidMethod)
+        ));
+        assert_eq!(
+            strict.apply(method("MKCALENDAR")).unwrap().as_str(),
+            "MKCALENDAR"
+        );
+    }
+}
@@ -1,16 +1,24 @@
 use std::fmt::{self, Debug};
 
-use http::{StatusCode, Uri, Version};
+use http::{header, StatusCode, Uri, Version};
 use tracing::debug;
 
 use fluke_buffet::Piece;
 
+use crate::{h2::StreamId, ConnInfo, Deadline, RequestTimings};
+
 mod headers;
 pub use headers::*;
 
 mod method;
 pub use method::*;
 
+mod target;
+pub use target::*;
+
+mod request_builder;
+pub use request_builder::*;
+
 /// An HTTP request
 #[derive(Clone)]
 pub struct Request {
@@ -24,6 +32,32 @@ pub struct Request {
 
     /// Request headers
     pub headers: Headers,
+
+    /// When the response must be complete: set by the server, see
+    /// [Deadline]
+    pub deadline: Deadline,
+
+    /// The stream this request came in on, for HTTP/2 requests
+    pub stream_id: Option<StreamId>,
+
+    /// The connection this request came in on, see [ConnInfo]
+    pub conn: ConnInfo,
+
+    /// When the server read this request, see [RequestTimings]
+    pub timings: RequestTimings,
+}
+
+/// Which protocol a request was made with, see [Request::protocol]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestProtocol {
+    /// HTTP/1.0 (or HTTP/0.9)
+    H1_0,
+
+    /// HTTP/1.1
+    H1_1,
+
+    /// HTTP/2
+    H2,
 }
 
 impl Default for Request {
@@ -33,6 +67,22 @@ impl Default for Request {
             uri: "/".parse().unwrap(),
             version: Version::HTTP_11,
             headers: Default::default(),
+            deadline: Default::default(),
+            stream_id: None,
+            conn: Default::default(),
+            timings: Default::default(),
+        }
+    }
+}
+
+impl Request {
+    /// Which protocol this request was made with. fluke only speaks
+    /// HTTP/1.x and HTTP/2, anything else is reported as HTTP/1.1.
+    pub fn protocol(&self) -> RequestProtocol {
+        match self.version {
+            Version::HTTP_09 | Version::HTTP_10 => RequestProtocol::H1_0,
+            Version::HTTP_2 => RequestProtocol::H2,
+            _ => RequestProtocol::H1_1,
         }
     }
 }
@@ -45,6 +95,8 @@ impl fmt::Debug for Request {
             .field("method", &self.method)
             .field("uri", &self.uri)
             .field("version", &self.version)
+            .field("stream_id", &self.stream_id)
+            .field("conn", &self.conn)
             .finish()?;
 
         for (name, value) in &self.headers {
@@ -93,6 +145,168 @@ impl Response {
             StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
         )
     }
+
+    /// Whether this response must go out without a body: 1xx, 204 and 304
+    /// responses, and any response to a HEAD request
+    pub(crate) fn forbids_body(&self, head_request: bool) -> bool {
+        head_request || self.status.is_informational() || self.means_empty_body()
+    }
+
+    /// Errors out if this response can't have a body, but announces
+    /// `transfer-encoding` anyway
+    pub(crate) fn check_bodyless(&self, head_request: bool) -> Result<(), BodyError> {
+        if self.forbids_body(head_request) && self.headers.contains_key(header::TRANSFER_ENCODING) {
+            return Err(BodyErrorReason::TransferEncodingOnBodylessResponse.with_cx(self.status));
+        }
+        Ok(())
+    }
+}
+
+/// An informational (1xx) response, cf.
+/// <https://httpwg.org/specs/rfc9110.html#status.1xx>. It can't be built
+/// with any other status, so it's the only thing
+/// [crate::Responder::write_interim_response] accepts.
+#[derive(Clone)]
+pub struct InterimResponse {
+    status: StatusCode,
+
+    /// Response headers
+    pub headers: Headers,
+}
+
+impl InterimResponse {
+    /// Returns `None` unless `status` is 1xx
+    pub fn new(status: StatusCode) -> Option<Self> {
+        status.is_informational().then(|| Self {
+            status,
+            headers: Default::default(),
+        })
+    }
+
+    /// `100 Continue`, for clients that sent `expect: 100-continue`
+    pub fn continue_100() -> Self {
+        Self {
+            status: StatusCode::CONTINUE,
+            headers: Default::default(),
+        }
+    }
+
+    /// `103 Early Hints`, with `link` headers (or others) the client may act
+    /// on before the final response, cf. RFC 8297
+    pub fn early_hints(headers: Headers) -> Self {
+        Self {
+            status: StatusCode::from_u16(103).unwrap(),
+            headers,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl TryFrom<Response> for InterimResponse {
+    type Error = Response;
+
+    /// Gives the response back unless it's 1xx
+    fn try_from(res: Response) -> Result<Self, Response> {
+        if !res.status.is_informational() {
+            return Err(res);
+        }
+        Ok(Self {
+            status: res.status,
+            headers: res.headers,
+        })
+    }
+}
+
+impl From<InterimResponse> for Response {
+    fn from(res: InterimResponse) -> Self {
+        Self {
+            status: res.status,
+            headers: res.headers,
+            ..Default::default()
+        }
+    }
+}
+
+/// What to do with a request whose head was just read, see
+/// [crate::ServerDriver::on_headers]
+pub enum HeaderDecision {
+    /// Carry on: the request goes to [crate::ServerDriver::handle]
+    Continue,
+
+    /// Send this response right away, without reading the request body:
+    /// to turn the request away, or to answer one there's nothing more to
+    /// read for, like `OPTIONS *`
+    Respond { res: Response, body: Piece },
+}
+
+impl HeaderDecision {
+    /// Rejects the request with an empty response of the given status
+    pub fn reject(status: StatusCode) -> Self {
+        Self::Respond {
+            res: Response {
+                status,
+                ..Default::default()
+            },
+            body: Piece::empty(),
+        }
+    }
+}
+
+/// What to do with a request header field that was just decoded, see
+/// [crate::ServerDriver::on_header_field]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldDecision {
+    /// Keep it in [Request::headers]
+    Keep,
+
+    /// Leave it out of [Request::headers]
+    Skip,
+
+    /// Stop there and reply with this status, without reading the request
+    /// body. The rest of the header block is decoded but not kept.
+    Reject(StatusCode),
+}
+
+/// Runs `headers` through [crate::ServerDriver::on_header_field], for
+/// protocols that can only do it once the whole head is parsed. Returns the
+/// status to reject the request with, if any. `headers` is only rebuilt
+/// if some field gets skipped.
+pub(crate) fn filter_fields(
+    driver: &impl crate::ServerDriver,
+    headers: &mut Headers,
+) -> Option<StatusCode> {
+    let mut kept: Option<Headers> = None;
+    for (i, (name, value)) in headers.iter().enumerate() {
+        let keep = match driver.on_header_field(name, value) {
+            FieldDecision::Keep => true,
+            FieldDecision::Skip => false,
+            FieldDecision::Reject(status) => return Some(status),
+        };
+        match &mut kept {
+            Some(kept) => {
+                if keep {
+                    kept.append(name.clone(), value.clone());
+                }
+            }
+            None => {
+                if !keep {
+                    // the first one to go: bring over those kept so far
+                    let mut fresh = Headers::with_capacity(headers.len());
+                    for (name, value) in headers.iter().take(i) {
+                        fresh.append(name.clone(), value.clone());
+                    }
+                    kept = Some(fresh);
+                }
+            }
+        }
+    }
+    if let Some(kept) = kept {
+        *headers = kept;
+    }
+    None
 }
 
 /// A body chunk
@@ -157,8 +371,19 @@ pub enum BodyErrorReason {
     InvalidChunkTerminator,
 
     // `write_chunk` was called but no content-length was announced, and
-    // no chunked transfer-encoding was announced
+    // no chunked transfer-encoding was announced (or the response can't
+    // have a body at all)
     CalledWriteBodyChunkWhenNoBodyWasExpected,
+
+    // a 1xx, 204 or 304 response, or a response to a HEAD request, was
+    // given a `transfer-encoding` header
+    TransferEncodingOnBodylessResponse,
+
+    // a body chunk would have gone past the announced content-length
+    WroteMoreThanContentLength,
+
+    // the body ended before reaching the announced content-length
+    WroteLessThanContentLength,
 }
 
 impl BodyErrorReason {
@@ -185,6 +410,58 @@ where
     fn content_len(&self) -> Option<u64>;
     fn eof(&self) -> bool;
     async fn next_chunk(&mut self) -> eyre::Result<BodyChunk>;
+
+    /// How many bytes [Body::next_chunk] handed out so far, for bodies that
+    /// keep count (request bodies do). `None` otherwise.
+    fn bytes_read(&self) -> Option<u64> {
+        None
+    }
+
+    /// How many bytes the body should have in all, if known: its content
+    /// length, or for HTTP/2 requests, the `content-length` header they may
+    /// carry even though their body is delimited by frames. Together with
+    /// [Body::bytes_read], that's enough for progress reports.
+    fn expected_len(&self) -> Option<u64> {
+        self.content_len()
+    }
+
+    /// Reads the whole body into a single piece, for small bodies (form
+    /// posts, JSON documents). Fails with [crate::body::LimitExceeded] if
+    /// it's larger than `max_bytes`. Trailers are discarded.
+    async fn collect(&mut self, max_bytes: u64) -> eyre::Result<Piece> {
+        let too_large = || crate::body::LimitExceeded { limit: max_bytes };
+        if self.content_len().is_some_and(|len| len > max_bytes) {
+            return Err(too_large().into());
+        }
+
+        let first = match self.next_chunk().await? {
+            BodyChunk::Chunk(piece) => piece,
+            BodyChunk::Done { .. } => return Ok(Piece::empty()),
+        };
+        if first.len() as u64 > max_bytes {
+            return Err(too_large().into());
+        }
+
+        // bodies that come in a single chunk don't need to be copied
+        let mut buf = match self.next_chunk().await? {
+            BodyChunk::Chunk(piece) => {
+                let mut buf = Vec::with_capacity(first.len() + piece.len());
+                buf.extend_from_slice(&first[..]);
+                buf.extend_from_slice(&piece[..]);
+                buf
+            }
+            BodyChunk::Done { .. } => return Ok(first),
+        };
+        loop {
+            if buf.len() as u64 > max_bytes {
+                return Err(too_large().into());
+            }
+            match self.next_chunk().await? {
+                BodyChunk::Chunk(piece) => buf.extend_from_slice(&piece[..]),
+                BodyChunk::Done { .. } => return Ok(buf.into()),
+            }
+        }
+    }
 }
 
 impl Body for () {
@@ -0,0 +1,140 @@
+use http::{header::HeaderName, Uri, Version};
+
+use fluke_buffet::Piece;
+
+use super::{Method, Request};
+use crate::body::{empty, once, Empty, Once};
+use crate::Body;
+
+/// Builds outgoing requests, for [crate::h1::request] and friends: see
+/// [Request::builder].
+///
+/// Errors (an unparsable URI, an invalid header name) are deferred until
+/// [RequestBuilder::build], so calls can be chained without `?` everywhere.
+#[derive(Debug)]
+pub struct RequestBuilder<B = Empty> {
+    req: Result<Request, http::Error>,
+    body: B,
+}
+
+impl Request {
+    /// Starts building a `GET /` HTTP/1.1 request with no headers and no
+    /// body
+    pub fn builder() -> RequestBuilder {
+        RequestBuilder {
+            req: Ok(Request::default()),
+            body: empty(),
+        }
+    }
+}
+
+impl<B: Body> RequestBuilder<B> {
+    pub fn method(self, method: Method) -> Self {
+        self.and_then(|req| {
+            req.method = method;
+            Ok(())
+        })
+    }
+
+    /// Origin-form (`/path?query`) or absolute (`https://host/path`):
+    /// HTTP/2 needs the latter, unless a `host` header is set.
+    pub fn uri<U>(self, uri: U) -> Self
+    where
+        U: TryInto<Uri>,
+        U::Error: Into<http::Error>,
+    {
+        self.and_then(|req| {
+            req.uri = uri.try_into().map_err(Into::into)?;
+            Ok(())
+        })
+    }
+
+    /// The version we'd like to speak. Whether it's honored is up to the
+    /// transport: [crate::h1::request] only does HTTP/1.x.
+    pub fn version(self, version: Version) -> Self {
+        self.and_then(|req| {
+            req.version = version;
+            Ok(())
+        })
+    }
+
+    /// Appends a header, keeping any previous value with the same name
+    pub fn header<K>(self, name: K, value: impl Into<Piece>) -> Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Into<http::Error>,
+    {
+        self.and_then(|req| {
+            let name = name.try_into().map_err(Into::into)?;
+            req.headers.append(name, value.into());
+            Ok(())
+        })
+    }
+
+    /// Sends `body`, streaming it if it doesn't know its length upfront
+    pub fn body<B2: Body>(self, body: B2) -> RequestBuilder<B2> {
+        RequestBuilder {
+            req: self.req,
+            body,
+        }
+    }
+
+    /// Sends a body that's already in memory, with a `content-length`
+    pub fn body_piece(self, piece: impl Into<Piece>) -> RequestBuilder<Once> {
+        self.body(once(piece))
+    }
+
+    /// Returns the request along with its body, ready to be passed to
+    /// [crate::h1::request]
+    pub fn build(self) -> Result<(Request, B), http::Error> {
+        Ok((self.req?, self.body))
+    }
+
+    fn and_then(mut self, f: impl FnOnce(&mut Request) -> Result<(), http::Error>) -> Self {
+        if let Ok(req) = &mut self.req {
+            if let Err(e) = f(req) {
+                self.req = Err(e);
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, Version};
+
+    use crate::{Body, Method, Request};
+
+    #[test]
+    fn build_requests() {
+        let (req, body) = Request::builder().build().unwrap();
+        assert_eq!(req.method, Method::Get);
+        assert_eq!(req.uri, "/");
+        assert_eq!(body.content_len(), Some(0));
+
+        let (req, body) = Request::builder()
+            .method(Method::Post)
+            .uri("https://example.org/upload?id=3")
+            .version(Version::HTTP_2)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header("x-tag", "a")
+            .header("x-tag", "b")
+            .body_piece("hello")
+            .build()
+            .unwrap();
+        assert_eq!(req.method, Method::Post);
+        assert_eq!(req.host().as_deref(), Some("example.org"));
+        assert_eq!(req.version, Version::HTTP_2);
+        assert_eq!(&req.headers[header::CONTENT_TYPE][..], b"text/plain");
+        assert_eq!(req.headers.get_all("x-tag").iter().count(), 2);
+        assert_eq!(body.content_len(), Some(5));
+
+        // the first error wins, and is only reported at the end
+        let res = Request::builder()
+            .uri("not a uri")
+            .header("bad header", "value")
+            .build();
+        assert!(res.unwrap_err().is::<http::uri::InvalidUri>());
+    }
+}
@@ -0,0 +1,251 @@
+//! Request targets, and how to rebuild them when a request is forwarded to
+//! an upstream that speaks another protocol than the client did.
+//!
+//! HTTP/1.1 carries the target on the request line
+//! (<https://httpwg.org/specs/rfc9112.html#request.target>) and the authority
+//! in the `host` header, HTTP/2 splits both across pseudo-headers
+//! (<https://httpwg.org/specs/rfc9113.html#HttpRequest>).
+
+use std::fmt;
+
+use fluke_buffet::Piece;
+use http::{
+    header,
+    uri::{Authority, PathAndQuery, Scheme},
+    StatusCode,
+};
+
+use crate::{HeaderDecision, Method, Request, Response};
+
+/// The form of a request's target, cf. [Request::target]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestTarget {
+    /// `/path?query`, what most requests use
+    Origin(PathAndQuery),
+
+    /// `host:port`, only for `CONNECT` requests
+    Authority(Authority),
+
+    /// `*`, only for server-wide `OPTIONS` requests
+    Asterisk,
+}
+
+impl fmt::Display for RequestTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestTarget::Origin(path) => f.write_str(path.as_str()),
+            RequestTarget::Authority(authority) => f.write_str(authority.as_str()),
+            RequestTarget::Asterisk => f.write_str("*"),
+        }
+    }
+}
+
+/// Returned when a request's target doesn't make sense for its method
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum TargetError {
+    /// `CONNECT` needs to know where to connect to
+    #[error("CONNECT request without an authority")]
+    ConnectWithoutAuthority,
+
+    /// Only `OPTIONS` may target the whole server
+    #[error("'*' request target for a method other than OPTIONS")]
+    AsteriskForMethod,
+}
+
+/// The pseudo-headers of an HTTP/2 request, cf. [Request::h2_pseudo_headers]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct H2PseudoHeaders {
+    pub method: Method,
+
+    /// Omitted for `CONNECT` requests
+    pub scheme: Option<Scheme>,
+
+    /// Required for `CONNECT` requests, optional otherwise
+    pub authority: Option<Authority>,
+
+    /// Omitted for `CONNECT` requests, `*` for server-wide `OPTIONS`
+    pub path: Option<PathAndQuery>,
+}
+
+impl Request {
+    /// The target of this request, whichever protocol it came in with. An
+    /// HTTP/2 request's URI is absolute, this only keeps the part that goes
+    /// on an HTTP/1.1 request line.
+    pub fn target(&self) -> Result<RequestTarget, TargetError> {
+        if self.method == Method::Connect {
+            return self
+                .authority()
+                .map(RequestTarget::Authority)
+                .ok_or(TargetError::ConnectWithoutAuthority);
+        }
+
+        match self.uri.path_and_query() {
+            Some(path) if path.as_str() == "*" => match self.method {
+                Method::Options => Ok(RequestTarget::Asterisk),
+                _ => Err(TargetError::AsteriskForMethod),
+            },
+            Some(path) => Ok(RequestTarget::Origin(path.clone())),
+            None => Ok(RequestTarget::Origin(PathAndQuery::from_static("/"))),
+        }
+    }
+
+    /// Who the request is for: the URI's authority if it has one (HTTP/2
+    /// requests, absolute-form HTTP/1.1 requests), the `host` header
+    /// otherwise.
+    pub fn authority(&self) -> Option<Authority> {
+        if let Some(authority) = self.uri.authority() {
+            return Some(authority.clone());
+        }
+        let host = self.headers.get(header::HOST)?;
+        Authority::try_from(&host[..]).ok()
+    }
+
+    /// The value of the `host` header to send to an HTTP/1.1 upstream: the
+    /// authority, minus any userinfo
+    pub fn host(&self) -> Option<String> {
+        let authority = self.authority()?;
+        Some(match authority.port() {
+            Some(port) => format!("{}:{port}", authority.host()),
+            None => authority.host().to_owned(),
+        })
+    }
+
+    /// The pseudo-headers to forward this request to an HTTP/2 upstream.
+    /// `default_scheme` is used when the request doesn't say, which is the
+    /// case for anything that came in over HTTP/1.1 in origin-form.
+    pub fn h2_pseudo_headers(
+        &self,
+        default_scheme: Scheme,
+    ) -> Result<H2PseudoHeaders, TargetError> {
+        let method = self.method.clone();
+        let authority = self.authority();
+
+        Ok(match self.target()? {
+            RequestTarget::Authority(authority) => H2PseudoHeaders {
+                method,
+                scheme: None,
+                authority: Some(authority),
+                path: None,
+            },
+            RequestTarget::Asterisk => H2PseudoHeaders {
+                method,
+                scheme: Some(self.uri.scheme().cloned().unwrap_or(default_scheme)),
+                authority,
+                path: Some(PathAndQuery::from_static("*")),
+            },
+            RequestTarget::Origin(path) => H2PseudoHeaders {
+                method,
+                scheme: Some(self.uri.scheme().cloned().unwrap_or(default_scheme)),
+                authority,
+                path: Some(path),
+            },
+        })
+    }
+}
+
+/// What the server makes of a `*` request target before the driver sees it:
+/// it's a bad request for anything but `OPTIONS`, and `OPTIONS *` gets an
+/// empty 200 with the configured `allow` header, if there is one. Other
+/// requests are left to the driver.
+pub(crate) fn asterisk_decision(req: &Request, allow: Option<&str>) -> Option<HeaderDecision> {
+    match req.target() {
+        Ok(RequestTarget::Asterisk) => {
+            let mut res = Response::default();
+            res.headers
+                .insert(header::ALLOW, Piece::from(allow?.as_bytes().to_vec()));
+            Some(HeaderDecision::Respond {
+                res,
+                body: Piece::empty(),
+            })
+        }
+        Err(TargetError::AsteriskForMethod) => {
+            Some(HeaderDecision::reject(StatusCode::BAD_REQUEST))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, uri::Scheme, StatusCode, Version};
+
+    use super::{RequestTarget, TargetError};
+    use crate::{HeaderDecision, Method, Request};
+
+    fn request(method: Method, uri: &str) -> Request {
+        Request {
+            method,
+            uri: uri.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn h2_to_h1_targets() {
+        let mut req = request(Method::Get, "https://user@example.org:8443/a/b?c=d");
+        req.version = Version::HTTP_2;
+        assert_eq!(req.target().unwrap().to_string(), "/a/b?c=d");
+        assert_eq!(req.host().unwrap(), "example.org:8443");
+
+        let req = request(Method::Connect, "https://example.org:443");
+        assert_eq!(req.target().unwrap().to_string(), "example.org:443");
+
+        let req = request(Method::Options, "*");
+        assert_eq!(req.target().unwrap(), RequestTarget::Asterisk);
+        assert!(matches!(
+            request(Method::Get, "*").target(),
+            Err(TargetError::AsteriskForMethod)
+        ));
+    }
+
+    #[test]
+    fn h1_to_h2_pseudo_headers() {
+        let mut req = request(Method::Get, "/a?b");
+        req.headers.insert(header::HOST, "example.org".into());
+        let pseudo = req.h2_pseudo_headers(Scheme::HTTPS).unwrap();
+        assert_eq!(pseudo.scheme, Some(Scheme::HTTPS));
+        assert_eq!(pseudo.authority.unwrap(), "example.org");
+        assert_eq!(pseudo.path.unwrap(), "/a?b");
+
+        let req = request(Method::Connect, "example.org:443");
+        let pseudo = req.h2_pseudo_headers(Scheme::HTTPS).unwrap();
+        assert_eq!(pseudo.scheme, None);
+        assert_eq!(pseudo.authority.unwrap(), "example.org:443");
+        assert_eq!(pseudo.path, None);
+
+        let mut req = request(Method::Options, "*");
+        req.headers.insert(header::HOST, "example.org".into());
+        let pseudo = req.h2_pseudo_headers(Scheme::HTTP).unwrap();
+        assert_eq!(pseudo.path.unwrap(), "*");
+
+        let req = request(Method::Connect, "/");
+        assert!(matches!(
+            req.h2_pseudo_headers(Scheme::HTTP),
+            Err(TargetError::ConnectWithoutAuthority)
+        ));
+    }
+
+    #[test]
+    fn asterisk_options() {
+        let allow = Some("GET, HEAD, OPTIONS");
+        let req = request(Method::Options, "*");
+        match super::asterisk_decision(&req, allow) {
+            Some(HeaderDecision::Respond { res, body }) => {
+                assert_eq!(res.status, StatusCode::OK);
+                assert_eq!(&res.headers[header::ALLOW][..], b"GET, HEAD, OPTIONS");
+                assert!(body.is_empty());
+            }
+            _ => panic!("OPTIONS * should be answered by the server"),
+        }
+        assert!(super::asterisk_decision(&req, None).is_none());
+
+        match super::asterisk_decision(&request(Method::Get, "*"), None) {
+            Some(HeaderDecision::Respond { res, .. }) => {
+                assert_eq!(res.status, StatusCode::BAD_REQUEST)
+            }
+            _ => panic!("GET * should be rejected"),
+        }
+        assert!(super::asterisk_decision(&request(Method::Options, "/"), allow).is_none());
+    }
+}
@@ -0,0 +1,379 @@
+//! Resumable uploads: large bodies sent over several requests, so that a
+//! client whose connection dropped can pick up where it left off instead of
+//! starting over.
+//!
+//! Two ways of saying where a request's body goes are understood:
+//!
+//!   * `content-range: bytes 100-199/1000` on a `PUT`, as done by object
+//!     stores. `bytes */1000` with an empty body asks how far along the
+//!     upload is.
+//!   * `upload-offset: 100`, optionally with `upload-length: 1000`, as done
+//!     by tus and the IETF resumable uploads draft (usually on a `PATCH`).
+//!
+//! Storage is up to the driver: it knows how many bytes it has for an
+//! upload, [Append::check] tells whether the request continues from there,
+//! and [UploadError::decision] turns the answer into a response, typically
+//! from [crate::ServerDriver::on_headers] so that a body that can't be used
+//! isn't read at all:
+//!
+//! ```
+//! use fluke::{upload, HeaderDecision, Request};
+//!
+//! fn on_headers(req: &Request, received: u64) -> HeaderDecision {
+//!     let checked = upload::parse(&req.headers).and_then(|upload| match upload {
+//!         Some(upload::Upload::Append(append)) => append.check(received),
+//!         _ => Ok(()),
+//!     });
+//!     match checked {
+//!         Ok(()) => HeaderDecision::Continue,
+//!         Err(e) => e.decision(received),
+//!     }
+//! }
+//! ```
+
+use http::{header, HeaderName, StatusCode};
+
+use crate::{types::from_digits, HeaderDecision, Headers, HeadersExt, Response};
+
+/// The offset a request's body starts at, tus-style
+pub const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+
+/// The length of the whole upload, tus-style
+pub const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+
+/// What an upload request is about, see [parse]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upload {
+    /// The body goes at some offset of the upload
+    Append(Append),
+
+    /// `content-range: bytes */total`: how much was received so far? Answer
+    /// with [incomplete], or a final response if the upload is complete.
+    Query { total: u64 },
+}
+
+/// Where a request's body goes in an upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Append {
+    /// Offset of the first byte of the body
+    pub offset: u64,
+
+    /// Length of the body, if the headers say
+    pub len: Option<u64>,
+
+    /// Length of the whole upload, if the headers say
+    pub total: Option<u64>,
+}
+
+/// Returned when an upload request doesn't add up
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UploadError {
+    /// Not a value we understand
+    #[error("invalid {0} header")]
+    InvalidHeader(HeaderName),
+
+    /// `content-range` and `content-length` disagree
+    #[error("content-range says {range} bytes, content-length says {content_length}")]
+    LengthMismatch { range: u64, content_length: u64 },
+
+    /// The body would go past the announced end of the upload
+    #[error("body ends at {end}, past the end of the upload ({total} bytes)")]
+    PastTotal { end: u64, total: u64 },
+
+    /// The body doesn't continue from what was received so far: bytes were
+    /// lost, or sent twice
+    #[error("body starts at {actual}, expected {expected}")]
+    OffsetMismatch { expected: u64, actual: u64 },
+}
+
+impl UploadError {
+    /// The status to respond with: 409 for an offset mismatch, as per the
+    /// tus protocol, 400 otherwise
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UploadError::OffsetMismatch { .. } => StatusCode::CONFLICT,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Rejects the request. The response says how many bytes were
+    /// `received`, so that the client knows where to resume from.
+    pub fn decision(&self, received: u64) -> HeaderDecision {
+        let mut decision = HeaderDecision::reject(self.status());
+        if let HeaderDecision::Respond { res, .. } = &mut decision {
+            res.headers
+                .insert(UPLOAD_OFFSET, received.to_string().into_bytes().into());
+        }
+        decision
+    }
+}
+
+/// Reads where the request's body goes, from `content-range` or
+/// `upload-offset`. `None` if the request has neither: it's not part of a
+/// resumable upload.
+pub fn parse(headers: &Headers) -> Result<Option<Upload>, UploadError> {
+    let (upload, header) = if let Some(range) = headers.get(header::CONTENT_RANGE) {
+        let invalid = || UploadError::InvalidHeader(header::CONTENT_RANGE);
+        let (range, total) = range
+            .strip_prefix(b"bytes ")
+            .and_then(|range| split_once(range, b'/'))
+            .ok_or_else(invalid)?;
+        let total = match total {
+            b"*" => None,
+            total => Some(from_digits(total).ok_or_else(invalid)?),
+        };
+
+        if range == b"*" {
+            return Ok(Some(Upload::Query {
+                total: total.ok_or_else(invalid)?,
+            }));
+        }
+        let (first, last) = split_once(range, b'-').ok_or_else(invalid)?;
+        let (first, last) = (
+            from_digits(first).ok_or_else(invalid)?,
+            from_digits(last).ok_or_else(invalid)?,
+        );
+        let len = last
+            .checked_sub(first)
+            .and_then(|len| len.checked_add(1))
+            .ok_or_else(invalid)?;
+        if let Some(content_length) = headers.content_length() {
+            if content_length != len {
+                return Err(UploadError::LengthMismatch {
+                    range: len,
+                    content_length,
+                });
+            }
+        }
+        (
+            Append {
+                offset: first,
+                len: Some(len),
+                total,
+            },
+            header::CONTENT_RANGE,
+        )
+    } else if let Some(offset) = headers.get(UPLOAD_OFFSET) {
+        let offset = from_digits(offset).ok_or(UploadError::InvalidHeader(UPLOAD_OFFSET))?;
+        let total = match headers.get(UPLOAD_LENGTH) {
+            Some(total) => {
+                Some(from_digits(total).ok_or(UploadError::InvalidHeader(UPLOAD_LENGTH))?)
+            }
+            None => None,
+        };
+        (
+            Append {
+                offset,
+                len: headers.content_length(),
+                total,
+            },
+            UPLOAD_OFFSET,
+        )
+    } else {
+        return Ok(None);
+    };
+
+    if let Some(len) = upload.len {
+        // the body would end past what an offset can say
+        if upload.offset.checked_add(len).is_none() {
+            return Err(UploadError::InvalidHeader(header));
+        }
+    }
+
+    if let (Some(end), Some(total)) = (upload.end(), upload.total) {
+        if end > total {
+            return Err(UploadError::PastTotal { end, total });
+        }
+    }
+    Ok(Some(Upload::Append(upload)))
+}
+
+impl Append {
+    /// Offset right after the last byte of the body, if its length is known
+    pub fn end(&self) -> Option<u64> {
+        self.len.and_then(|len| self.offset.checked_add(len))
+    }
+
+    /// Whether the body is the end of the upload
+    pub fn is_last(&self) -> bool {
+        self.total.is_some() && self.end() == self.total
+    }
+
+    /// Checks that the body continues from the `received` bytes of the
+    /// upload the driver already has
+    pub fn check(&self, received: u64) -> Result<(), UploadError> {
+        if self.offset != received {
+            return Err(UploadError::OffsetMismatch {
+                expected: received,
+                actual: self.offset,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The response for an upload that isn't complete yet, with `received`
+/// bytes so far: a 308 with a `range` header for `content-range` clients,
+/// and `upload-offset` for tus-style ones.
+pub fn incomplete(received: u64) -> Response {
+    let mut res = Response {
+        status: StatusCode::PERMANENT_REDIRECT,
+        ..Default::default()
+    };
+    if received > 0 {
+        res.headers.insert(
+            header::RANGE,
+            format!("bytes=0-{}", received - 1).into_bytes().into(),
+        );
+    }
+    res.headers
+        .insert(UPLOAD_OFFSET, received.to_string().into_bytes().into());
+    res
+}
+
+fn split_once(input: &[u8], delimiter: u8) -> Option<(&[u8], &[u8])> {
+    let at = input.iter().position(|&b| b == delimiter)?;
+    Some((&input[..at], &input[at + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, StatusCode};
+
+    use super::{incomplete, parse, Append, Upload, UploadError, UPLOAD_LENGTH, UPLOAD_OFFSET};
+    use crate::{HeaderDecision, Headers};
+
+    fn headers(fields: &[(http::HeaderName, &'static str)]) -> Headers {
+        let mut headers = Headers::default();
+        for (name, value) in fields {
+            headers.append(name.clone(), (*value).into());
+        }
+        headers
+    }
+
+    #[test]
+    fn content_range() {
+        let upload = parse(&headers(&[
+            (header::CONTENT_RANGE, "bytes 100-199/200"),
+            (header::CONTENT_LENGTH, "100"),
+        ]))
+        .unwrap();
+        let Some(Upload::Append(append)) = upload else {
+            panic!("expected an append, got {upload:?}");
+        };
+        assert_eq!(
+            append,
+            Append {
+                offset: 100,
+                len: Some(100),
+                total: Some(200)
+            }
+        );
+        assert!(append.is_last());
+        assert!(append.check(100).is_ok());
+        assert_eq!(
+            append.check(50),
+            Err(UploadError::OffsetMismatch {
+                expected: 50,
+                actual: 100
+            })
+        );
+
+        assert_eq!(
+            parse(&headers(&[(header::CONTENT_RANGE, "bytes */200")])).unwrap(),
+            Some(Upload::Query { total: 200 })
+        );
+
+        for (range, err) in [
+            (
+                "bytes 10-5/*",
+                UploadError::InvalidHeader(header::CONTENT_RANGE),
+            ),
+            (
+                "items 0-5/*",
+                UploadError::InvalidHeader(header::CONTENT_RANGE),
+            ),
+            (
+                "bytes */*",
+                UploadError::InvalidHeader(header::CONTENT_RANGE),
+            ),
+            (
+                "bytes 0-18446744073709551615/*",
+                UploadError::InvalidHeader(header::CONTENT_RANGE),
+            ),
+            (
+                "bytes 5-18446744073709551615/*",
+                UploadError::InvalidHeader(header::CONTENT_RANGE),
+            ),
+            (
+                "bytes 0-99/50",
+                UploadError::PastTotal {
+                    end: 100,
+                    total: 50,
+                },
+            ),
+        ] {
+            assert_eq!(parse(&headers(&[(header::CONTENT_RANGE, range)])), Err(err));
+        }
+        assert_eq!(
+            parse(&headers(&[
+                (header::CONTENT_RANGE, "bytes 0-99/*"),
+                (header::CONTENT_LENGTH, "10"),
+            ])),
+            Err(UploadError::LengthMismatch {
+                range: 100,
+                content_length: 10
+            })
+        );
+    }
+
+    #[test]
+    fn upload_offset() {
+        let upload = parse(&headers(&[
+            (UPLOAD_OFFSET, "10"),
+            (UPLOAD_LENGTH, "100"),
+            (header::CONTENT_LENGTH, "20"),
+        ]))
+        .unwrap();
+        let Some(Upload::Append(append)) = upload else {
+            panic!("expected an append, got {upload:?}");
+        };
+        assert_eq!(append.end(), Some(30));
+        assert!(!append.is_last());
+
+        assert_eq!(parse(&headers(&[])), Ok(None));
+        assert_eq!(
+            parse(&headers(&[(UPLOAD_OFFSET, "-1")])),
+            Err(UploadError::InvalidHeader(UPLOAD_OFFSET))
+        );
+        assert_eq!(
+            parse(&headers(&[
+                (UPLOAD_OFFSET, "18446744073709551615"),
+                (header::CONTENT_LENGTH, "1"),
+            ])),
+            Err(UploadError::InvalidHeader(UPLOAD_OFFSET))
+        );
+    }
+
+    #[test]
+    fn responses() {
+        let res = incomplete(100);
+        assert_eq!(res.status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(&res.headers[header::RANGE][..], b"bytes=0-99");
+        assert_eq!(&res.headers[UPLOAD_OFFSET][..], b"100");
+        assert!(!incomplete(0).headers.contains_key(header::RANGE));
+
+        let err = UploadError::OffsetMismatch {
+            expected: 100,
+            actual: 0,
+        };
+        match err.decision(100) {
+            HeaderDecision::Respond { res, .. } => {
+                assert_eq!(res.status, StatusCode::CONFLICT);
+                assert_eq!(&res.headers[UPLOAD_OFFSET][..], b"100");
+            }
+            HeaderDecision::Continue => panic!("expected a rejection"),
+        }
+    }
+}
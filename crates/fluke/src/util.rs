@@ -1,4 +1,7 @@
+use std::{any::Any, time::Instant};
+
 use eyre::Context;
+use http::StatusCode;
 use nom::IResult;
 use pretty_hex::PrettyHex;
 use tracing::{debug, trace};
@@ -9,13 +12,48 @@ use fluke_buffet::{ReadOwned, Roll, RollMut};
 pub(crate) async fn read_and_parse<Parser, Output>(
     parser: Parser,
     stream: &mut impl ReadOwned,
-    mut buf: RollMut,
+    buf: RollMut,
     max_len: usize,
     // TODO: proper error handling, no eyre::Result
 ) -> eyre::Result<Option<(RollMut, Output)>>
 where
     Parser: Fn(Roll) -> IResult<Roll, Output>,
 {
+    read_and_parse_timed(parser, stream, buf, max_len, &mut None)
+        .await
+        .map_err(|f| f.err)
+}
+
+/// Returned by [read_and_parse_timed]. When the input is malformed (as
+/// opposed to too large, or cut short), the buffer is handed back so that
+/// the caller can look for where the next message starts.
+pub(crate) struct ParseFailure {
+    pub(crate) err: eyre::Report,
+    pub(crate) buf: Option<RollMut>,
+}
+
+impl From<eyre::Report> for ParseFailure {
+    fn from(err: eyre::Report) -> Self {
+        Self { err, buf: None }
+    }
+}
+
+/// Like [read_and_parse], also setting `first_bytes` to when the message
+/// started coming in: right away if `buf` already has some of it, or when
+/// the first read returns.
+pub(crate) async fn read_and_parse_timed<Parser, Output>(
+    parser: Parser,
+    stream: &mut impl ReadOwned,
+    mut buf: RollMut,
+    max_len: usize,
+    first_bytes: &mut Option<Instant>,
+) -> Result<Option<(RollMut, Output)>, ParseFailure>
+where
+    Parser: Fn(Roll) -> IResult<Roll, Output>,
+{
+    if !buf.is_empty() {
+        *first_bytes = Some(Instant::now());
+    }
     loop {
         trace!("Running parser (len={}, cap={})", buf.len(), buf.cap());
         let filled = buf.filled();
@@ -37,12 +75,15 @@ where
                     let res;
                     let read_limit = max_len - buf.len();
                     if buf.len() >= max_len {
-                        return Err(SemanticError::BufferLimitReachedWhileParsing.into());
+                        return Err(eyre::Report::from(
+                            SemanticError::BufferLimitReachedWhileParsing,
+                        )
+                        .into());
                     }
 
                     if buf.cap() == 0 {
                         trace!("buf had zero cap, reserving");
-                        buf.reserve()?;
+                        buf.reserve_wait().await.map_err(eyre::Report::from)?;
                     }
                     trace!(
                         "Calling read_into (len={}, cap={}, read_limit={read_limit})",
@@ -59,19 +100,29 @@ where
                     })?;
                     if n == 0 {
                         if !buf.is_empty() {
-                            return Err(eyre::eyre!("unexpected EOF"));
+                            return Err(eyre::eyre!("unexpected EOF").into());
                         } else {
                             return Ok(None);
                         }
                     }
+                    first_bytes.get_or_insert_with(Instant::now);
 
                     continue;
                 } else {
+                    if let nom::Err::Failure(e) = &err {
+                        // only the request parser fails that way
+                        if let Some(se) = crate::h1::parse::rejection(e) {
+                            return Err(eyre::Report::from(se).into());
+                        }
+                    }
                     if let nom::Err::Error(e) = &err {
                         debug!(?err, "parsing error");
                         debug!(input = %e.input.to_string_lossy(), "input was");
                     }
-                    return Err(eyre::eyre!("parsing error: {err}"));
+                    return Err(ParseFailure {
+                        err: eyre::eyre!("parsing error: {err}"),
+                        buf: Some(buf),
+                    });
                 }
             }
         };
@@ -82,14 +133,52 @@ where
 pub(crate) enum SemanticError {
     #[error("buffering limit reached while parsing")]
     BufferLimitReachedWhileParsing,
+
+    #[error("HTTP/1.0 request with a transfer-encoding header")]
+    TransferEncodingInHttp10,
+
+    #[error("request target longer than the configured limit")]
+    UriTooLong,
+
+    #[error("more request headers than the configured limit")]
+    TooManyHeaders,
+
+    #[error("duplicate {0} header")]
+    DuplicateHeader(http::header::HeaderName),
+
+    #[error("request method with characters outside of the allowed set")]
+    InvalidMethod,
+
+    #[error("request method isn't one the server accepts")]
+    MethodNotImplemented,
 }
 
 impl SemanticError {
-    pub(crate) fn as_http_response(&self) -> &'static [u8] {
+    /// The status to reply with, before closing the connection
+    pub(crate) fn status(&self) -> StatusCode {
         match self {
-            Self::BufferLimitReachedWhileParsing => {
-                b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n"
+            Self::BufferLimitReachedWhileParsing | Self::TooManyHeaders => {
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+            }
+            Self::UriTooLong => StatusCode::URI_TOO_LONG,
+            Self::MethodNotImplemented => StatusCode::NOT_IMPLEMENTED,
+            Self::InvalidMethod | Self::DuplicateHeader(_) => StatusCode::BAD_REQUEST,
+            Self::TransferEncodingInHttp10 => {
+                // there's no telling where the body ends (RFC 9112, section 6.1)
+                StatusCode::BAD_REQUEST
             }
         }
     }
 }
+
+/// Extracts a human-readable message from a panic payload, as caught by
+/// `catch_unwind`.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&'static str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "(non-string panic payload)".to_string()
+    }
+}
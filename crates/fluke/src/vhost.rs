@@ -0,0 +1,261 @@
+//! Serving several hostnames from one listener, each with its own driver.
+//!
+//! ```no_run
+//! # async fn f<D: fluke::ServerDriver + 'static>(api: D, blog: D, www: D) -> std::io::Result<()> {
+//! use fluke::vhost::VirtualHosts;
+//!
+//! let hosts = VirtualHosts::new()
+//!     .host("api.example.org", api)
+//!     .host("*.blog.example.org", blog)
+//!     .fallback(www);
+//! let server = fluke::ServerBuilder::new("[::]:8080".parse().unwrap())
+//!     .build(hosts)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests are dispatched on their authority (see
+//! [crate::Request::authority]), port aside. Those for hosts nobody serves,
+//! when there's no fallback, get a `421 Misdirected Request`.
+//!
+//! Over TLS, the certificate is picked with SNI before any request comes in
+//! (see the TLS sample for a resolver that does it), and clients may then
+//! send requests for other hosts on the same connection, as long as the
+//! certificate covers them. Those are turned away with a 421 too when they'd
+//! go to a different driver than the server name, as reported in
+//! [crate::TlsInfo::server_name]: that driver's certificate wasn't the one
+//! presented.
+//!
+//! All drivers are of the same type: use an enum to mix different ones.
+//! Fields are filtered by the fallback driver only (see
+//! [crate::ServerDriver::on_header_field]), since they come in before the
+//! host is known, and so are HTTP/2 extension frames, which belong to the
+//! connection. Closed connections are reported to every driver, fallback
+//! included (see [crate::ServerDriver::on_connection_closed]): a connection
+//! may have carried requests for any number of them.
+
+use http::StatusCode;
+
+use crate::{
+    body, h2, Body, CloseReason, ConnInfo, Encoder, ExpectResponseHeaders, FieldDecision,
+    HeaderDecision, Request, Responder, Response, ResponseDone, ServerDriver,
+};
+
+/// Dispatches requests to drivers by host, see the [module docs](self)
+pub struct VirtualHosts<D> {
+    hosts: Vec<(HostPattern, D)>,
+    fallback: Option<D>,
+}
+
+#[derive(Debug)]
+enum HostPattern {
+    Exact(String),
+
+    /// `*.example.org`, holds `.example.org`
+    Wildcard(String),
+}
+
+impl HostPattern {
+    fn new(pattern: &str) -> Self {
+        match pattern.strip_prefix('*') {
+            Some(suffix) => {
+                assert!(
+                    suffix.starts_with('.') && suffix.len() > 1,
+                    "wildcard host {pattern:?} must look like '*.example.org'"
+                );
+                Self::Wildcard(suffix.to_ascii_lowercase())
+            }
+            None => Self::Exact(pattern.to_ascii_lowercase()),
+        }
+    }
+
+    /// `host` is lowercase already
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::Exact(exact) => host == exact,
+            // a single label, like certificates do it
+            Self::Wildcard(suffix) => host
+                .strip_suffix(suffix.as_str())
+                .map_or(false, |label| !label.is_empty() && !label.contains('.')),
+        }
+    }
+}
+
+impl<D> Default for VirtualHosts<D> {
+    fn default() -> Self {
+        Self {
+            hosts: Default::default(),
+            fallback: None,
+        }
+    }
+}
+
+impl<D> VirtualHosts<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends requests for `host` to `driver`. `host` is matched
+    /// case-insensitively, and may start with `*.` to match any single
+    /// label, e.g. `*.example.org` matches `a.example.org`, but neither
+    /// `example.org` nor `a.b.example.org`. Hosts are tried in the order
+    /// they were added.
+    ///
+    /// # Panics
+    ///
+    /// If `host` has a `*` anywhere else.
+    pub fn host(mut self, host: &str, driver: D) -> Self {
+        assert!(
+            !host.trim_start_matches('*').contains('*'),
+            "host {host:?} may only have a '*' in front"
+        );
+        self.hosts.push((HostPattern::new(host), driver));
+        self
+    }
+
+    /// Sends requests for any other host (or without a host at all) to
+    /// `driver`
+    pub fn fallback(mut self, driver: D) -> Self {
+        self.fallback = Some(driver);
+        self
+    }
+
+    /// The driver for `req`, `None` if it's misdirected
+    pub fn find(&self, req: &Request) -> Option<&D> {
+        let Some(authority) = req.authority() else {
+            return self.fallback.as_ref();
+        };
+        let target = self.position(authority.host());
+
+        let sni = req.conn.tls().and_then(|tls| tls.server_name.as_deref());
+        if let Some(sni) = sni {
+            if self.position(sni) != target {
+                return None;
+            }
+        }
+
+        match target {
+            Some(index) => Some(&self.hosts[index].1),
+            None => self.fallback.as_ref(),
+        }
+    }
+
+    /// Index of the first host `host` matches, `None` for the fallback
+    fn position(&self, host: &str) -> Option<usize> {
+        let host = host.to_ascii_lowercase();
+        self.hosts
+            .iter()
+            .position(|(pattern, _)| pattern.matches(&host))
+    }
+}
+
+impl<D> ServerDriver for VirtualHosts<D>
+where
+    D: ServerDriver,
+{
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        match self.find(&req) {
+            Some(driver) => driver.handle(req, req_body, respond).await,
+            // only if `on_headers` was skipped
+            None => {
+                let res = Response {
+                    status: StatusCode::MISDIRECTED_REQUEST,
+                    ..Default::default()
+                };
+                respond
+                    .write_final_response_with_body(res, &mut body::empty())
+                    .await
+            }
+        }
+    }
+
+    fn on_headers(&self, req: &Request) -> HeaderDecision {
+        match self.find(req) {
+            Some(driver) => driver.on_headers(req),
+            None => HeaderDecision::reject(StatusCode::MISDIRECTED_REQUEST),
+        }
+    }
+
+    fn on_header_field(&self, name: &http::HeaderName, value: &[u8]) -> FieldDecision {
+        match &self.fallback {
+            Some(driver) => driver.on_header_field(name, value),
+            None => FieldDecision::Keep,
+        }
+    }
+
+    fn on_h2_connection(&self, frames: h2::ExtensionFrames) {
+        if let Some(driver) = &self.fallback {
+            driver.on_h2_connection(frames)
+        }
+    }
+
+    fn on_extension_frame(&self, frame: h2::ExtensionFrame, frames: &h2::ExtensionFrames) {
+        if let Some(driver) = &self.fallback {
+            driver.on_extension_frame(frame, frames)
+        }
+    }
+
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        for (_, driver) in &self.hosts {
+            driver.on_connection_closed(conn, reason)
+        }
+        if let Some(driver) = &self.fallback {
+            driver.on_connection_closed(conn, reason)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+
+    use super::VirtualHosts;
+    use crate::{ConnInfo, Request, TlsInfo};
+
+    fn req(host: &str, sni: Option<&str>) -> Request {
+        let mut req = Request::default();
+        req.headers
+            .insert(header::HOST, host.to_owned().into_bytes().into());
+        if let Some(sni) = sni {
+            req.conn = ConnInfo::with_tls(TlsInfo {
+                server_name: Some(sni.to_owned()),
+                ..Default::default()
+            });
+        }
+        req
+    }
+
+    #[test]
+    fn virtual_hosts() {
+        let hosts = VirtualHosts::new()
+            .host("api.example.org", "api")
+            .host("*.example.org", "wildcard");
+        let find = |host, sni| hosts.find(&req(host, sni)).copied();
+
+        assert_eq!(find("API.example.org:8443", None), Some("api"));
+        assert_eq!(find("www.example.org", None), Some("wildcard"));
+        assert_eq!(find("a.b.example.org", None), None);
+        assert_eq!(find("example.org", None), None);
+        assert_eq!(find("example.net", None), None);
+
+        // connections are only reused across hosts of the same driver
+        assert_eq!(
+            find("a.example.org", Some("b.example.org")),
+            Some("wildcard")
+        );
+        assert_eq!(find("api.example.org", Some("www.example.org")), None);
+
+        let hosts = hosts.fallback("fallback");
+        assert_eq!(
+            hosts.find(&req("example.net", None)).copied(),
+            Some("fallback")
+        );
+        assert_eq!(hosts.find(&Request::default()).copied(), Some("fallback"));
+    }
+}
@@ -0,0 +1,408 @@
+//! Finds slow requests without logging every request: a [Watchdog] wraps a
+//! driver and emits a warning, with where the time went, for every request
+//! that takes longer than a threshold.
+//!
+//! ```no_run
+//! # async fn f(driver: impl fluke::ServerDriver + 'static) -> std::io::Result<()> {
+//! use std::time::Duration;
+//!
+//! let server = fluke::ServerBuilder::new("[::]:8080".parse().unwrap())
+//!     .build(fluke::watchdog::Watchdog::new(driver, Duration::from_millis(500)))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Time is split into phases:
+//!
+//!   * `read_head`: reading the request head, see [crate::RequestTimings]
+//!   * `queued`: between the head being read and the handler starting
+//!   * `body`: the handler waiting on the request body
+//!   * `write`: the handler waiting on the response to be written
+//!   * `handler`: everything else the handler did
+//!
+//! Handlers run in a `request` span (with the method and URI), and the
+//! warnings are emitted in it, with the `fluke::watchdog` target. A request
+//! that's still running when it crosses the threshold gets a warning right
+//! then, and another one when it's done: stuck requests show up too.
+
+use std::{
+    cell::Cell,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use fluke_buffet::{Piece, PieceList};
+use http::StatusCode;
+use tracing::{debug_span, warn, Instrument};
+
+use crate::{
+    h1::body::BodyWriteMode, h2, h2::KnownErrorCode, Body, BodyChunk, CloseReason, ConnInfo,
+    Encoder, ExpectResponseHeaders, FieldDecision, HeaderDecision, Headers, Request, Responder,
+    Response, ResponseDone, ServerDriver,
+};
+
+/// Warns about requests slower than a threshold, see the [module
+/// docs](self)
+pub struct Watchdog<D> {
+    inner: D,
+    threshold: Duration,
+}
+
+impl<D> Watchdog<D> {
+    /// Warns about requests that take longer than `threshold`, counting from
+    /// when their head started coming in
+    pub fn new(inner: D, threshold: Duration) -> Self {
+        Self { inner, threshold }
+    }
+}
+
+impl<D> ServerDriver for Watchdog<D>
+where
+    D: ServerDriver,
+{
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let span = debug_span!("request", method = %req.method, uri = %req.uri);
+        let phases = Phases::new(&req, self.threshold);
+        let slow_at = phases.started + self.threshold;
+
+        let mut req_body = TimedBody {
+            inner: req_body,
+            phases: &phases,
+        };
+        let respond = respond.map_encoder(|inner| TimedEncoder {
+            inner,
+            phases: &phases,
+        });
+        let handle = self.inner.handle(req, &mut req_body, respond);
+
+        let res = async {
+            let mut handle = std::pin::pin!(handle);
+            tokio::select! {
+                res = &mut handle => res,
+                _ = tokio::time::sleep_until(slow_at.into()) => {
+                    phases.report("request still running past threshold");
+                    handle.await
+                }
+            }
+        }
+        .instrument(span.clone())
+        .await;
+
+        phases.outcome.set(match &res {
+            Ok(_) => "completed",
+            Err(_) => "failed",
+        });
+        let res = res.map(|respond| respond.map_encoder(|encoder| encoder.inner));
+        let _entered = span.enter();
+        drop(phases);
+        res
+    }
+
+    fn on_headers(&self, req: &Request) -> HeaderDecision {
+        self.inner.on_headers(req)
+    }
+
+    fn on_header_field(&self, name: &http::HeaderName, value: &[u8]) -> FieldDecision {
+        self.inner.on_header_field(name, value)
+    }
+
+    fn on_h2_connection(&self, frames: h2::ExtensionFrames) {
+        self.inner.on_h2_connection(frames)
+    }
+
+    fn on_extension_frame(&self, frame: h2::ExtensionFrame, frames: &h2::ExtensionFrames) {
+        self.inner.on_extension_frame(frame, frames)
+    }
+
+    fn on_connection_closed(&self, conn: &ConnInfo, reason: &CloseReason) {
+        self.inner.on_connection_closed(conn, reason)
+    }
+}
+
+/// Where a request's time went so far. Reports itself when dropped, if the
+/// request was slow: handlers that get dropped (client gone, deadline
+/// passed) are reported as cancelled.
+struct Phases {
+    method: crate::Method,
+    uri: http::Uri,
+    protocol: crate::RequestProtocol,
+    stream_id: Option<h2::StreamId>,
+
+    started: Instant,
+    read_head: Duration,
+    queued: Duration,
+    handler_started: Instant,
+    body: Cell<Duration>,
+    write: Cell<Duration>,
+    status: Cell<Option<StatusCode>>,
+    outcome: Cell<&'static str>,
+    threshold: Duration,
+}
+
+impl Phases {
+    fn new(req: &Request, threshold: Duration) -> Self {
+        let now = Instant::now();
+        let timings = req.timings;
+        let head_read = timings.head_read.unwrap_or(now);
+        Self {
+            method: req.method.clone(),
+            uri: req.uri.clone(),
+            protocol: req.protocol(),
+            stream_id: req.stream_id,
+
+            started: timings.head_started.unwrap_or(head_read),
+            read_head: timings.read_head().unwrap_or_default(),
+            queued: now.saturating_duration_since(head_read),
+            handler_started: now,
+            body: Default::default(),
+            write: Default::default(),
+            status: Default::default(),
+            outcome: Cell::new("cancelled"),
+            threshold,
+        }
+    }
+
+    fn report(&self, message: &str) {
+        let elapsed = self.started.elapsed();
+        let handler = self
+            .handler_started
+            .elapsed()
+            .saturating_sub(self.body.get())
+            .saturating_sub(self.write.get());
+        warn!(
+            target: "fluke::watchdog",
+            method = %self.method,
+            uri = %self.uri,
+            protocol = ?self.protocol,
+            stream_id = ?self.stream_id.map(|id| id.0),
+            status = ?self.status.get().map(|s| s.as_u16()),
+            outcome = self.outcome.get(),
+            elapsed = ?elapsed,
+            read_head = ?self.read_head,
+            queued = ?self.queued,
+            body = ?self.body.get(),
+            handler = ?handler,
+            write = ?self.write.get(),
+            "{message}"
+        );
+    }
+
+    async fn time<T>(cell: &Cell<Duration>, f: impl std::future::Future<Output = T>) -> T {
+        let start = Instant::now();
+        let res = f.await;
+        cell.set(cell.get() + start.elapsed());
+        res
+    }
+}
+
+impl Drop for Phases {
+    fn drop(&mut self) {
+        if self.started.elapsed() >= self.threshold {
+            self.report("slow request");
+        }
+    }
+}
+
+struct TimedBody<'a, B> {
+    inner: &'a mut B,
+    phases: &'a Phases,
+}
+
+impl<B: Body> fmt::Debug for TimedBody<'_, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<B: Body> Body for TimedBody<'_, B> {
+    fn content_len(&self) -> Option<u64> {
+        self.inner.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        Phases::time(&self.phases.body, self.inner.next_chunk()).await
+    }
+
+    fn bytes_read(&self) -> Option<u64> {
+        self.inner.bytes_read()
+    }
+
+    fn expected_len(&self) -> Option<u64> {
+        self.inner.expected_len()
+    }
+}
+
+struct TimedEncoder<'a, E> {
+    inner: E,
+    phases: &'a Phases,
+}
+
+impl<E> Encoder for TimedEncoder<'_, E>
+where
+    E: Encoder,
+{
+    async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
+        if !res.status.is_informational() {
+            self.phases.status.set(Some(res.status));
+        }
+        Phases::time(&self.phases.write, self.inner.write_response(res)).await
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()> {
+        Phases::time(&self.phases.write, self.inner.write_body_chunk(chunk, mode)).await
+    }
+
+    async fn write_body_chunks(
+        &mut self,
+        chunks: PieceList,
+        mode: BodyWriteMode,
+    ) -> eyre::Result<()> {
+        Phases::time(
+            &self.phases.write,
+            self.inner.write_body_chunks(chunks, mode),
+        )
+        .await
+    }
+
+    async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()> {
+        Phases::time(&self.phases.write, self.inner.write_body_end(mode)).await
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()> {
+        Phases::time(&self.phases.write, self.inner.write_trailers(trailers)).await
+    }
+
+    async fn reset(&mut self, code: KnownErrorCode) -> eyre::Result<()> {
+        self.inner.reset(code).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use fluke_buffet::{Piece, PieceList};
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Level, Metadata, Subscriber,
+    };
+
+    use super::Watchdog;
+    use crate::{
+        body::once, h1::body::BodyWriteMode, Body, Encoder, ExpectResponseHeaders, Headers,
+        Request, Responder, Response, ResponseDone, ServerDriver,
+    };
+
+    struct SlowDriver;
+
+    impl ServerDriver for SlowDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            respond
+                .write_final_response_with_body(Response::default(), req_body)
+                .await
+        }
+    }
+
+    struct NullEncoder;
+
+    impl Encoder for NullEncoder {
+        async fn write_response(&mut self, _res: Response) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        async fn write_body_chunk(&mut self, _: Piece, _: BodyWriteMode) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        async fn write_body_chunks(&mut self, _: PieceList, _: BodyWriteMode) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        async fn write_body_end(&mut self, _: BodyWriteMode) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        async fn write_trailers(&mut self, _: Box<Headers>) -> eyre::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Collects the messages of the watchdog's warnings
+    #[derive(Clone, Default)]
+    struct Warnings(Arc<Mutex<Vec<String>>>);
+
+    impl Subscriber for Warnings {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.is_span() || metadata.target() == "fluke::watchdog"
+        }
+
+        fn event(&self, event: &Event<'_>) {
+            struct Message<'a>(&'a mut String);
+            impl Visit for Message<'_> {
+                fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                    if field.name() == "message" {
+                        *self.0 = format!("{value:?}");
+                    }
+                }
+            }
+
+            assert_eq!(*event.metadata().level(), Level::WARN);
+            let mut message = String::new();
+            event.record(&mut Message(&mut message));
+            self.0.lock().unwrap().push(message);
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    fn run(threshold: Duration) -> Vec<String> {
+        let warnings = Warnings::default();
+        tracing::subscriber::with_default(warnings.clone(), || {
+            fluke_buffet::start(async move {
+                let driver = Watchdog::new(SlowDriver, threshold);
+                let respond = Responder::new(NullEncoder);
+                driver
+                    .handle(Request::default(), &mut once("hi"), respond)
+                    .await
+                    .unwrap();
+            })
+        });
+        let warnings = warnings.0.lock().unwrap().clone();
+        warnings
+    }
+
+    #[test]
+    fn slow_requests() {
+        assert_eq!(run(Duration::from_secs(5)), Vec::<String>::new());
+        assert_eq!(
+            run(Duration::from_millis(10)),
+            ["request still running past threshold", "slow request"]
+        );
+    }
+}
@@ -1,6 +1,9 @@
 use std::rc::Rc;
 
-use fluke::{Body, BodyChunk, Encoder, ExpectResponseHeaders, Responder, Response, ResponseDone};
+use fluke::{
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, InterimResponse, Responder, Response,
+    ResponseDone,
+};
 use fluke_buffet::{IntoHalves, PipeRead, PipeWrite, ReadOwned, RollMut, WriteOwned};
 use http::StatusCode;
 use tracing::Level;
@@ -34,6 +37,9 @@ pub(crate) fn setup_tracing_and_error_reporting() {
         .init();
 }
 
+/// Larger than anything the suites send
+const MAX_REQ_BODY_LEN: u64 = 16 << 20;
+
 struct TestDriver;
 
 impl fluke::ServerDriver for TestDriver {
@@ -46,31 +52,25 @@ impl fluke::ServerDriver for TestDriver {
         // if the client sent `expect: 100-continue`, we must send a 100 status code
         if let Some(h) = _req.headers.get(http::header::EXPECT) {
             if &h[..] == b"100-continue" {
-                res.write_interim_response(Response {
-                    status: StatusCode::CONTINUE,
-                    ..Default::default()
-                })
-                .await?;
+                res.write_interim_response(InterimResponse::continue_100())
+                    .await?;
             }
         }
 
-        // then read the full request body
+        // then read the full request body, keeping its trailers: they're
+        // echoed back, for suites to check they made it through
         let mut req_body_len = 0;
-        loop {
-            let chunk = req_body.next_chunk().await?;
-            match chunk {
-                BodyChunk::Done { trailers } => {
-                    // yey
-                    if let Some(trailers) = trailers {
-                        tracing::debug!(trailers_len = %trailers.len(), "received trailers");
-                    }
-                    break;
-                }
+        let trailers = loop {
+            match req_body.next_chunk().await? {
                 BodyChunk::Chunk(chunk) => {
-                    req_body_len += chunk.len();
+                    req_body_len += chunk.len() as u64;
+                    if req_body_len > MAX_REQ_BODY_LEN {
+                        eyre::bail!("request body too large");
+                    }
                 }
+                BodyChunk::Done { trailers } => break trailers,
             }
-        }
+        };
         tracing::debug!(%req_body_len, "read request body");
 
         let mut res = res
@@ -83,7 +83,7 @@ impl fluke::ServerDriver for TestDriver {
         res.write_chunk("it's less dire to lose, than to lose oneself".into())
             .await?;
 
-        let res = res.finish_body(None).await?;
+        let res = res.finish_body(trailers).await?;
 
         Ok(res)
     }
@@ -99,17 +99,24 @@ impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W
     }
 }
 
-pub fn start_server() -> httpwg::Conn<TwoHalves<PipeWrite, PipeRead>> {
+pub fn start_server(
+    server_conf: fluke::h2::ServerConf,
+) -> httpwg::Conn<TwoHalves<PipeWrite, PipeRead>> {
+    start_server_with_driver(server_conf, TestDriver)
+}
+
+pub fn start_server_with_driver(
+    server_conf: fluke::h2::ServerConf,
+    driver: impl fluke::ServerDriver + 'static,
+) -> httpwg::Conn<TwoHalves<PipeWrite, PipeRead>> {
     let (server_write, client_read) = fluke::buffet::pipe();
     let (client_write, server_read) = fluke::buffet::pipe();
 
     let serve_fut = async move {
-        let server_conf = Rc::new(fluke::h2::ServerConf {
-            ..Default::default()
-        });
+        let server_conf = Rc::new(server_conf);
 
         let client_buf = RollMut::alloc()?;
-        let driver = Rc::new(TestDriver);
+        let driver = Rc::new(driver);
         let io = (server_read, server_write);
         fluke::h2::serve(io, server_conf, client_buf, driver).await?;
         tracing::debug!("http/2 server done");
@@ -124,13 +131,670 @@ pub fn start_server() -> httpwg::Conn<TwoHalves<PipeWrite, PipeRead>> {
     httpwg::Conn::new(config, TwoHalves(client_write, client_read))
 }
 
+// Server configurations every test runs against: some spec behavior only
+// shows up near a limit.
+
+fn default_conf() -> fluke::h2::ServerConf {
+    Default::default()
+}
+
+/// Hits the concurrent stream limit early
+fn few_streams() -> fluke::h2::ServerConf {
+    fluke::h2::ServerConf {
+        max_streams: Some(2),
+        ..Default::default()
+    }
+}
+
+/// Makes handlers wait for the peer to read almost every chunk
+fn small_backlog() -> fluke::h2::ServerConf {
+    fluke::h2::ServerConf {
+        max_stream_backlog: 16,
+        ..Default::default()
+    }
+}
+
 #[cfg(test)]
-httpwg_macros::tests! {{
-   crate::setup_tracing_and_error_reporting();
-
-   fluke_buffet::start(async move {
-       let conn = crate::start_server();
-       let result = test(conn).await;
-       result.unwrap()
-   });
-}}
+httpwg_macros::tests! {
+    |conf| [default_conf, few_streams, small_backlog] {
+       crate::setup_tracing_and_error_reporting();
+
+       fluke_buffet::start(async move {
+           let conn = crate::start_server(conf);
+           let result = test(conn).await;
+           result.unwrap()
+       });
+    }
+}
+
+/// Clients that don't wait for the SETTINGS exchange to complete
+mod settings_ordering {
+    use fluke_h2_parse::{StreamId, PREFACE};
+    use httpwg::{rfc9113::default_settings, ErrorC, FrameT};
+
+    #[test]
+    fn headers_before_client_settings() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server(fluke::h2::ServerConf {
+                lenient_settings: true,
+                ..Default::default()
+            });
+            conn.send(PREFACE).await.unwrap();
+            conn.send_empty_post_to_root(StreamId(1)).await.unwrap();
+
+            let (frame, _) = conn.wait_for_frame(FrameT::Settings).await.unwrap();
+            assert!(!frame.is_ack(), "server should send its settings first");
+
+            // served with default settings, then the real ones come in
+            conn.verify_headers_frame(StreamId(1)).await.unwrap();
+            conn.write_settings(default_settings()).await.unwrap();
+            let (frame, _) = conn.wait_for_frame(FrameT::Settings).await.unwrap();
+            assert!(frame.is_ack(), "server should acknowledge our settings");
+        });
+    }
+
+    #[test]
+    fn headers_before_server_settings_ack() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server(Default::default());
+            conn.send(PREFACE).await.unwrap();
+            conn.write_settings(default_settings()).await.unwrap();
+            // no waiting for the server's settings, and no ack
+            conn.send_empty_post_to_root(StreamId(1)).await.unwrap();
+            conn.send_empty_post_to_root(StreamId(3)).await.unwrap();
+
+            conn.verify_headers_frame(StreamId(1)).await.unwrap();
+            conn.verify_headers_frame(StreamId(3)).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn headers_before_client_settings_strict() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            // strict is the default
+            let mut conn = crate::start_server(Default::default());
+            conn.send(PREFACE).await.unwrap();
+            conn.send_empty_post_to_root(StreamId(1)).await.unwrap();
+
+            let goaway = conn.verify_goaway(ErrorC::ProtocolError).await.unwrap();
+            assert_eq!(
+                goaway.last_stream_id,
+                StreamId(0),
+                "stream 1 must not have been served"
+            );
+        });
+    }
+}
+
+/// Frames that were in flight when a stream got closed
+mod late_frames {
+    use fluke_h2_parse::{HeadersFlags, StreamId};
+    use httpwg::ErrorC;
+
+    #[test]
+    fn window_update_and_rst_after_response() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server(Default::default());
+            conn.handshake().await.unwrap();
+            conn.send_empty_post_to_root(StreamId(1)).await.unwrap();
+            conn.verify_stream_close(StreamId(1)).await.unwrap();
+
+            // both crossed our END_STREAM
+            conn.write_window_update(StreamId(1), 100).await.unwrap();
+            conn.write_rst_stream(StreamId(1), ErrorC::Cancel)
+                .await
+                .unwrap();
+            conn.verify_connection_still_alive().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn frames_for_refused_stream() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server(fluke::h2::ServerConf {
+                max_streams: Some(1),
+                ..Default::default()
+            });
+            conn.handshake().await.unwrap();
+
+            // stream 1 stays open, waiting for its body
+            let headers = conn.common_headers("POST");
+            let block = conn.encode_headers(&headers).unwrap();
+            conn.write_headers(StreamId(1), HeadersFlags::EndHeaders, block)
+                .await
+                .unwrap();
+
+            let block = conn.encode_headers(&headers).unwrap();
+            conn.write_headers(StreamId(3), HeadersFlags::EndHeaders, block)
+                .await
+                .unwrap();
+            conn.verify_stream_error(ErrorC::RefusedStream)
+                .await
+                .unwrap();
+
+            // sent before the client saw the RST_STREAM: all ignored
+            conn.write_data(StreamId(3), false, b"test").await.unwrap();
+            let block = conn.encode_headers(&conn.dummy_headers(1)).unwrap();
+            conn.write_headers(
+                StreamId(3),
+                HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                block,
+            )
+            .await
+            .unwrap();
+            conn.verify_connection_still_alive().await.unwrap();
+
+            // and the dynamic table is still in sync
+            conn.write_data(StreamId(1), true, b"test").await.unwrap();
+            conn.verify_stream_close(StreamId(1)).await.unwrap();
+            conn.send_empty_post_to_root(StreamId(5)).await.unwrap();
+            conn.verify_stream_close(StreamId(5)).await.unwrap();
+        });
+    }
+}
+
+mod idle_timeout {
+    use std::time::Duration;
+
+    use fluke_h2_parse::StreamId;
+    use httpwg::ErrorC;
+
+    #[test]
+    fn idle_connection_is_reaped() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server(fluke::h2::ServerConf {
+                idle_timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            });
+            conn.handshake().await.unwrap();
+            conn.send_empty_post_to_root(StreamId(1)).await.unwrap();
+            conn.verify_stream_close(StreamId(1)).await.unwrap();
+
+            // pings don't count as activity
+            conn.verify_connection_still_alive().await.unwrap();
+
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let goaway = conn.verify_goaway(ErrorC::NoError).await.unwrap();
+            assert_eq!(goaway.last_stream_id, StreamId(1));
+            conn.verify_connection_close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn open_streams_keep_connection_alive() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server(fluke::h2::ServerConf {
+                idle_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            });
+            conn.handshake().await.unwrap();
+
+            // the handler waits for a body that takes a while to come
+            let headers = conn.common_headers("POST");
+            let block = conn.encode_headers(&headers).unwrap();
+            conn.write_headers(StreamId(1), fluke_h2_parse::HeadersFlags::EndHeaders, block)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            conn.write_data(StreamId(1), true, b"late").await.unwrap();
+            conn.verify_stream_close(StreamId(1)).await.unwrap();
+        });
+    }
+}
+
+mod ping_timeout {
+    use std::time::Duration;
+
+    use fluke_h2_parse::StreamId;
+    use httpwg::ErrorC;
+
+    #[test]
+    fn unresponsive_peer_is_dropped() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server(fluke::h2::ServerConf {
+                ping_interval: Some(Duration::from_millis(50)),
+                ping_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            });
+            // the server's first PING goes out during the handshake, and
+            // is never acknowledged
+            conn.handshake().await.unwrap();
+
+            let goaway = conn.verify_goaway(ErrorC::NoError).await.unwrap();
+            assert_eq!(goaway.last_stream_id, StreamId(0));
+            conn.verify_connection_close().await.unwrap();
+        });
+    }
+}
+
+mod extension_frames {
+    use fluke::{
+        buffet::Piece,
+        h2::{ExtensionFrame, ExtensionFrameError, ExtensionFrames},
+        Body, Encoder, ExpectResponseHeaders, Responder, ResponseDone,
+    };
+    use fluke_h2_parse::{EncodedFrameType, Frame, FrameType, StreamId, PREFACE};
+    use httpwg::FrameT;
+
+    const ORIGIN: u8 = 0xc;
+
+    /// Announces itself with an ORIGIN frame, and answers every extension
+    /// frame with one of the next type up
+    struct EchoDriver;
+
+    impl fluke::ServerDriver for EchoDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: fluke::Request,
+            _req_body: &mut impl Body,
+            _respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            unreachable!()
+        }
+
+        fn on_h2_connection(&self, frames: ExtensionFrames) {
+            frames
+                .send(ExtensionFrame {
+                    frame_type: ORIGIN,
+                    flags: 0,
+                    stream_id: StreamId::CONNECTION,
+                    payload: Piece::from(&b"\x00\x12https://example.org"[..]),
+                })
+                .unwrap();
+
+            // the server's own frame types are off-limits
+            let err = frames
+                .send(ExtensionFrame {
+                    frame_type: 0x0,
+                    flags: 0,
+                    stream_id: StreamId(1),
+                    payload: Piece::empty(),
+                })
+                .unwrap_err();
+            assert!(matches!(err, ExtensionFrameError::NotAnExtension(0)));
+        }
+
+        fn on_extension_frame(&self, frame: ExtensionFrame, frames: &ExtensionFrames) {
+            frames
+                .send(ExtensionFrame {
+                    frame_type: frame.frame_type + 1,
+                    ..frame
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn driver_sees_and_sends_extension_frames() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server_with_driver(Default::default(), EchoDriver);
+
+            // the ORIGIN frame goes out right after the server's SETTINGS,
+            // which `handshake` would skip past
+            conn.send(PREFACE).await.unwrap();
+            conn.write_settings(httpwg::rfc9113::default_settings())
+                .await
+                .unwrap();
+            conn.wait_for_frame(FrameT::Settings).await.unwrap();
+            let (frame, payload) = conn.wait_for_frame(FrameT::Unknown).await.unwrap();
+            assert!(matches!(
+                frame.frame_type,
+                FrameType::Unknown(EncodedFrameType { ty: ORIGIN, .. })
+            ));
+            assert_eq!(frame.stream_id, StreamId::CONNECTION);
+            assert_eq!(&payload[2..], b"https://example.org");
+
+            let frame_type = FrameType::Unknown(EncodedFrameType {
+                ty: 0xf0,
+                flags: 0x3,
+            });
+            conn.write_frame(Frame::new(frame_type, StreamId(7)), &b"hello"[..])
+                .await
+                .unwrap();
+
+            let (frame, payload) = conn.wait_for_frame(FrameT::Unknown).await.unwrap();
+            assert!(matches!(
+                frame.frame_type,
+                FrameType::Unknown(EncodedFrameType {
+                    ty: 0xf1,
+                    flags: 0x3
+                })
+            ));
+            assert_eq!(frame.stream_id, StreamId(7));
+            assert_eq!(payload, &b"hello"[..]);
+
+            conn.verify_connection_still_alive().await.unwrap();
+        });
+    }
+}
+
+mod memory_budget {
+    use std::time::Duration;
+
+    use fluke::{
+        buffet::Piece, Body, Encoder, ExpectResponseHeaders, Method, Responder, Response,
+        ResponseDone,
+    };
+    use fluke_h2_parse::{DataFlags, FrameType, HeadersFlags, StreamId};
+    use httpwg::FrameT;
+
+    /// Answers GETs with a response far larger than the peer's window, and
+    /// POSTs by reading their body
+    struct BacklogDriver;
+
+    impl fluke::ServerDriver for BacklogDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            req: fluke::Request,
+            req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let chunks = match req.method {
+                Method::Get => 12,
+                _ => {
+                    req_body.collect(crate::MAX_REQ_BODY_LEN).await?;
+                    1
+                }
+            };
+            let mut respond = respond.write_final_response(Response::default()).await?;
+            for _ in 0..chunks {
+                respond
+                    .write_chunk(Piece::from(vec![b'x'; 16 * 1024]))
+                    .await?;
+            }
+            respond.finish_body(None).await
+        }
+    }
+
+    #[test]
+    fn request_body_within_window_while_over_budget() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server_with_driver(
+                fluke::h2::ServerConf {
+                    memory_budget: Some(32 * 1024),
+                    ..Default::default()
+                },
+                BacklogDriver,
+            );
+            conn.handshake().await.unwrap();
+
+            // fills the peer's windows, and queues more than the budget
+            let headers = conn.common_headers("GET");
+            conn.encode_and_write_headers(
+                StreamId(1),
+                HeadersFlags::EndStream | HeadersFlags::EndHeaders,
+                &headers,
+            )
+            .await
+            .unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            // well within the windows the server advertised
+            let headers = conn.common_headers("POST");
+            conn.encode_and_write_headers(StreamId(3), HeadersFlags::EndHeaders, &headers)
+                .await
+                .unwrap();
+            conn.write_data(StreamId(3), true, vec![b'y'; 16_000])
+                .await
+                .unwrap();
+
+            // once we catch up, both responses complete
+            conn.write_window_update(StreamId::CONNECTION, 1 << 20)
+                .await
+                .unwrap();
+            conn.write_window_update(StreamId(1), 1 << 20)
+                .await
+                .unwrap();
+
+            let mut done = vec![];
+            while done.len() < 2 {
+                let (frame, _payload) = conn
+                    .wait_for_frame(FrameT::Data | FrameT::Headers | FrameT::GoAway)
+                    .await
+                    .unwrap();
+                let end_stream = match frame.frame_type {
+                    FrameType::Data(flags) => flags.contains(DataFlags::EndStream),
+                    FrameType::Headers(flags) => flags.contains(HeadersFlags::EndStream),
+                    other => panic!("expected both responses, got {other:?}"),
+                };
+                if end_stream {
+                    done.push(frame.stream_id);
+                }
+            }
+            done.sort();
+            assert_eq!(done, [StreamId(1), StreamId(3)]);
+        });
+    }
+}
+
+mod content_digest {
+    use fluke::{
+        body::{once, with_content_digest},
+        Body, Encoder, ExpectResponseHeaders, Responder, Response, ResponseDone,
+    };
+    use fluke_h2_parse::{DataFlags, FrameType, HeadersFlags, StreamId};
+    use httpwg::FrameT;
+
+    // from RFC 9530, appendix D.1
+    const HELLO: &str = r#"{"hello": "world"}"#;
+    const HELLO_DIGEST: &str = "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:";
+
+    struct DigestDriver;
+
+    impl fluke::ServerDriver for DigestDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: fluke::Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            respond
+                .write_final_response_with_body(
+                    Response::default(),
+                    &mut with_content_digest(once(HELLO)),
+                )
+                .await
+        }
+    }
+
+    #[test]
+    fn digest_goes_out_in_trailers() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let mut conn = crate::start_server_with_driver(Default::default(), DigestDriver);
+            conn.handshake().await.unwrap();
+
+            let headers = conn.common_headers("GET");
+            conn.encode_and_write_headers(
+                StreamId(1),
+                HeadersFlags::EndStream | HeadersFlags::EndHeaders,
+                &headers,
+            )
+            .await
+            .unwrap();
+
+            let (frame, payload) = conn.wait_for_frame(FrameT::Headers).await.unwrap();
+            assert!(
+                matches!(frame.frame_type, FrameType::Headers(flags) if !flags.contains(HeadersFlags::EndStream))
+            );
+            let headers = conn.decode_headers(payload.into()).unwrap();
+            assert_eq!(&headers.get_first(&":status".into()).unwrap()[..], b"200");
+
+            let mut body = vec![];
+            let trailers = loop {
+                let (frame, payload) = conn
+                    .wait_for_frame(FrameT::Data | FrameT::Headers)
+                    .await
+                    .unwrap();
+                match frame.frame_type {
+                    FrameType::Data(flags) => {
+                        // the trailers end the stream
+                        assert!(!flags.contains(DataFlags::EndStream));
+                        body.extend_from_slice(&payload[..]);
+                    }
+                    FrameType::Headers(flags) => {
+                        assert!(flags.contains(HeadersFlags::EndStream));
+                        break conn.decode_headers(payload.into()).unwrap();
+                    }
+                    _ => unreachable!(),
+                }
+            };
+            assert_eq!(body, HELLO.as_bytes());
+            assert_eq!(
+                &trailers.get_first(&"content-digest".into()).unwrap()[..],
+                HELLO_DIGEST.as_bytes()
+            );
+
+            conn.verify_connection_still_alive().await.unwrap();
+        });
+    }
+}
+
+/// A handler that doesn't keep up with its request body holds the peer back
+mod read_backpressure {
+    use std::{rc::Rc, time::Duration};
+
+    use fluke::{
+        h2::WindowUpdates, Body, Encoder, ExpectResponseHeaders, Responder, Response, ResponseDone,
+    };
+    use fluke_buffet::IntoHalves;
+    use fluke_h2_parse::{HeadersFlags, StreamId};
+    use httpwg::{Conn, FrameT, FrameWaitOutcome};
+    use tokio::{sync::Notify, time::Instant};
+
+    /// Reads the request body once it's told to
+    struct SlowReader {
+        go: Rc<Notify>,
+    }
+
+    impl fluke::ServerDriver for SlowReader {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: fluke::Request,
+            req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            self.go.notified().await;
+            req_body.collect(crate::MAX_REQ_BODY_LEN).await?;
+            respond
+                .write_final_response_with_body(Response::default(), &mut fluke::body::empty())
+                .await
+        }
+    }
+
+    /// Sends a full stream window of request body on stream 1
+    async fn fill_stream_window(conn: &mut Conn<impl IntoHalves>) -> eyre::Result<()> {
+        conn.handshake().await?;
+        let headers = conn.common_headers("POST");
+        conn.encode_and_write_headers(StreamId(1), HeadersFlags::EndHeaders, &headers)
+            .await?;
+        for len in [16384, 16384, 16384, 16383] {
+            conn.write_data(StreamId(1), false, vec![b'y'; len]).await?;
+        }
+        Ok(())
+    }
+
+    /// The increment of the first WINDOW_UPDATE for stream 1 within
+    /// `timeout`, if any: connection-level ones are skipped
+    async fn stream_window_update(
+        conn: &mut Conn<impl IntoHalves>,
+        timeout: Duration,
+    ) -> eyre::Result<Option<u32>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match conn
+                .wait_for_frame_with_deadline(FrameT::WindowUpdate, deadline)
+                .await
+            {
+                FrameWaitOutcome::Success(frame, payload) => {
+                    if frame.stream_id == StreamId(1) {
+                        let increment = u32::from_be_bytes(payload[..4].try_into()?);
+                        return Ok(Some(increment & 0x7fff_ffff));
+                    }
+                }
+                FrameWaitOutcome::Timeout { .. } => return Ok(None),
+                outcome => return outcome.into_result().map(|_| None),
+            }
+        }
+    }
+
+    #[test]
+    fn window_given_back_as_the_handler_reads() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let go = Rc::new(Notify::new());
+            let mut conn =
+                crate::start_server_with_driver(Default::default(), SlowReader { go: go.clone() });
+            fill_stream_window(&mut conn).await.unwrap();
+
+            // the data was received, but nobody read it: the peer must wait
+            let update = stream_window_update(&mut conn, Duration::from_millis(200))
+                .await
+                .unwrap();
+            assert_eq!(update, None);
+
+            go.notify_one();
+            let update = stream_window_update(&mut conn, Duration::from_secs(1))
+                .await
+                .unwrap();
+            // what was read so far, possibly not all of it yet
+            assert!(matches!(update, Some(increment) if increment <= 65535));
+
+            conn.write_data(StreamId(1), true, Vec::new())
+                .await
+                .unwrap();
+            conn.verify_headers_frame(StreamId(1)).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn window_given_back_on_receipt() {
+        crate::setup_tracing_and_error_reporting();
+
+        fluke_buffet::start(async move {
+            let go = Rc::new(Notify::new());
+            let mut conn = crate::start_server_with_driver(
+                fluke::h2::ServerConf {
+                    window_updates: WindowUpdates::OnReceipt,
+                    ..Default::default()
+                },
+                SlowReader { go: go.clone() },
+            );
+            fill_stream_window(&mut conn).await.unwrap();
+
+            // whether the handler keeps up or not
+            let update = stream_window_update(&mut conn, Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert!(update.is_some());
+
+            go.notify_one();
+            conn.write_data(StreamId(1), true, Vec::new())
+                .await
+                .unwrap();
+            conn.verify_headers_frame(StreamId(1)).await.unwrap();
+        });
+    }
+}
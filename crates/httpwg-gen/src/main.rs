@@ -243,6 +243,12 @@ fn main() {
         w!("/// The `$body` argument is pasted inside those unit test, and");
         w!("/// in that scope, `test` is the `httpwg` function you can use");
         w!("/// to run the test (that takes a `mut conn: Conn<IO>`)");
+        w!("///");
+        w!("/// To run every test against several server configurations, list");
+        w!("/// functions that return them, and name a binding for `$body`:");
+        w!("/// `tests!(|conf| [default_conf, few_streams] {{ ... }})` generates");
+        w!("/// one module tree per function (`few_streams::rfc9113::...`), and");
+        w!("/// in each test, `conf` is what that function returned.");
         w!("#[macro_export]");
         w!("macro_rules! tests {{");
         {
@@ -286,7 +292,18 @@ fn main() {
                 }
                 w!("}}");
             }
+            w!("  }};");
+            w!("");
+            w!("  (|$conf: ident| [$($config: ident),+ $(,)?] $body: tt) => {{");
+            w!("$(");
+            w!("mod $config {{");
+            w!("$crate::tests! {{{{");
+            w!("let $conf = super::super::super::$config();");
+            w!("$body");
+            w!("}}}}");
             w!("}}");
+            w!(")+");
+            w!("  }};");
         }
         w!("}}");
 
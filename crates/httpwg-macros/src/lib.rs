@@ -7,10 +7,153 @@
 /// The `$body` argument is pasted inside those unit test, and
 /// in that scope, `test` is the `httpwg` function you can use
 /// to run the test (that takes a `mut conn: Conn<IO>`)
+///
+/// To run every test against several server configurations, list
+/// functions that return them, and name a binding for `$body`:
+/// `tests!(|conf| [default_conf, few_streams] { ... })` generates
+/// one module tree per function (`few_streams::rfc9113::...`), and
+/// in each test, `conf` is what that function returned.
 #[macro_export]
 macro_rules! tests {
   ($body: tt) => {
 
+/// RFC 7541 defines HPACK, a compression format for efficiently
+/// representing HTTP fields, to be used in HTTP/2.
+///
+/// These tests send header blocks encoded by hand, to exercise the corner
+/// cases of the peer's decoder: any decoding error must be treated as a
+/// connection error of type COMPRESSION_ERROR (RFC 9113, section 4.3).
+///
+/// cf. <https://httpwg.org/specs/rfc7541.html>
+#[cfg(test)]
+mod rfc7541 {
+use ::httpwg::rfc7541 as __suite;
+
+/// Section 2: Compression Process Overview
+mod _2_compression_process_overview {
+use super::__suite::_2_compression_process_overview as __group;
+
+/// Indices strictly greater than the sum of the lengths of both
+/// tables MUST be treated as a decoding error.
+#[test]
+fn sends_indexed_header_field_with_out_of_bounds_index() {
+use __group::sends_indexed_header_field_with_out_of_bounds_index as test;
+$body
+}
+
+/// Indices strictly greater than the sum of the lengths of both
+/// tables MUST be treated as a decoding error.
+#[test]
+fn sends_literal_header_field_with_out_of_bounds_name_index() {
+use __group::sends_literal_header_field_with_out_of_bounds_name_index as test;
+$body
+}
+}
+
+/// Section 4: Dynamic Table Management
+mod _4_dynamic_table_management {
+use super::__suite::_4_dynamic_table_management as __group;
+
+/// This dynamic table size update MUST occur at the beginning of
+/// the first header block following the change to the dynamic
+/// table size.
+#[test]
+fn sends_dynamic_table_size_update_after_header_field() {
+use __group::sends_dynamic_table_size_update_after_header_field as test;
+$body
+}
+
+/// Multiple updates to the maximum table size can occur between the
+/// transmission of two header blocks. In the case that this size is
+/// changed more than once in this interval, the smallest maximum
+/// table size that occurs in that interval MUST be signaled in a
+/// dynamic table size update. The final maximum size is always
+/// signaled, resulting in at most two dynamic table size updates.
+#[test]
+fn sends_multiple_dynamic_table_size_updates() {
+use __group::sends_multiple_dynamic_table_size_updates as test;
+$body
+}
+
+/// A change in the maximum size of the dynamic table can cause
+/// entries to be evicted: referencing them afterwards is an
+/// out-of-bounds index, which MUST be treated as a decoding error.
+#[test]
+fn sends_dynamic_table_size_update_evicting_entries() {
+use __group::sends_dynamic_table_size_update_evicting_entries as test;
+$body
+}
+}
+
+/// Section 5: Primitive Type Representations
+mod _5_primitive_type_representations {
+use super::__suite::_5_primitive_type_representations as __group;
+
+/// Integer encodings that exceed implementation limits -- in value
+/// or octet length -- MUST be treated as decoding errors.
+#[test]
+fn sends_indexed_header_field_with_integer_overflow() {
+use __group::sends_indexed_header_field_with_integer_overflow as test;
+$body
+}
+
+/// A padding strictly longer than 7 bits MUST be treated as a
+/// decoding error.
+#[test]
+fn sends_huffman_encoded_string_with_padding_longer_than_7_bits() {
+use __group::sends_huffman_encoded_string_with_padding_longer_than_7_bits as test;
+$body
+}
+
+/// A padding not corresponding to the most significant bits of the
+/// code for the EOS symbol MUST be treated as a decoding error.
+#[test]
+fn sends_huffman_encoded_string_with_padding_not_matching_eos() {
+use __group::sends_huffman_encoded_string_with_padding_not_matching_eos as test;
+$body
+}
+
+/// A Huffman-encoded string literal containing the EOS symbol MUST be
+/// treated as a decoding error.
+#[test]
+fn sends_huffman_encoded_string_with_eos_symbol() {
+use __group::sends_huffman_encoded_string_with_eos_symbol as test;
+$body
+}
+}
+
+/// Section 6: Binary Format
+mod _6_binary_format {
+use super::__suite::_6_binary_format as __group;
+
+/// The index value of 0 is not used. It MUST be treated as a
+/// decoding error if found in an indexed header field
+/// representation.
+#[test]
+fn sends_indexed_header_field_with_zero_index() {
+use __group::sends_indexed_header_field_with_zero_index as test;
+$body
+}
+
+/// A literal header field never-indexed representation results in
+/// appending a header field to the decoded header list without
+/// altering the dynamic table.
+#[test]
+fn sends_literal_header_field_never_indexed() {
+use __group::sends_literal_header_field_never_indexed as test;
+$body
+}
+
+/// The new maximum size MUST be lower than or equal to the limit
+/// determined by the protocol using HPACK. A value that exceeds this
+/// limit MUST be treated as a decoding error.
+#[test]
+fn sends_dynamic_table_size_update_larger_than_settings_header_table_size() {
+use __group::sends_dynamic_table_size_update_larger_than_settings_header_table_size as test;
+$body
+}
+}
+}
 /// RFC 9113 describes an optimized expression of the
 /// semantics of the Hypertext Transfer Protocol (HTTP), referred to as
 /// HTTP version 2 (HTTP/2).
@@ -267,6 +410,34 @@ use __group::closed_sends_continuation_frame as test;
 $body
 }
 
+/// Each stream has its own state: a stream's request is complete once its
+/// own DATA frame carries the END_STREAM flag, regardless of how frames
+/// for other streams are interleaved with it.
+#[test]
+fn interleaves_frames_across_multiple_streams() {
+use __group::interleaves_frames_across_multiple_streams as test;
+$body
+}
+
+/// An endpoint that sends a RST_STREAM frame on a stream moves it to the
+/// "closed" state: the other streams are unaffected.
+#[test]
+fn resets_one_of_multiple_open_streams() {
+use __group::resets_one_of_multiple_open_streams as test;
+$body
+}
+
+/// If an endpoint receives additional frames, other than
+/// WINDOW_UPDATE, PRIORITY, or RST_STREAM, for a stream that is in
+/// the "half-closed (remote)" state, it MUST respond with a stream
+/// error (Section 5.4.2) of type STREAM_CLOSED. Other streams stay
+/// usable.
+#[test]
+fn half_closed_remote_sends_data_frame_among_open_streams() {
+use __group::half_closed_remote_sends_data_frame_among_open_streams as test;
+$body
+}
+
 /// An endpoint that receives an unexpected stream identifier
 /// MUST respond with a connection error (Section 5.4.1) of
 /// type PROTOCOL_ERROR.
@@ -291,6 +462,15 @@ use __group::exceeds_concurrent_stream_limit as test;
 $body
 }
 
+/// Streams in the "closed" state don't count toward the limit: once
+/// one closes, a new stream can be opened in its place, even after a
+/// stream was refused for exceeding the limit.
+#[test]
+fn opens_stream_after_another_closes_at_concurrent_stream_limit() {
+use __group::opens_stream_after_another_closes_at_concurrent_stream_limit as test;
+$body
+}
+
 /// After sending the GOAWAY frame for an error condition,
 /// the endpoint MUST close the TCP connection.
 #[test]
@@ -349,6 +529,22 @@ use __group::sends_data_frame_with_invalid_pad_length as test;
 $body
 }
 
+/// The DATA frame can include padding: only the data that follows the
+/// pad length, and precedes the padding, is part of the request body.
+#[test]
+fn sends_data_frame_with_padding() {
+use __group::sends_data_frame_with_padding as test;
+$body
+}
+
+/// The entire DATA frame payload is included in flow control,
+/// including the Pad Length and Padding fields if present.
+#[test]
+fn sends_padded_data_frames_counting_against_flow_control() {
+use __group::sends_padded_data_frames_counting_against_flow_control as test;
+$body
+}
+
 /// HEADERS frames MUST be associated with a stream. If a HEADERS
 /// frame is received whose stream identifier field is 0x0, the
 /// recipient MUST respond with a connection error (Section 5.4.1)
@@ -610,6 +806,25 @@ use __group::sends_settings_frame_to_set_initial_window_size_to_1_and_sends_head
 $body
 }
 
+/// A sender MUST NOT send a flow-controlled frame with a length
+/// that exceeds the space available in either of the flow-control
+/// windows advertised by the receiver. Once the receiver sends
+/// WINDOW_UPDATE frames, the sender may resume.
+#[test]
+fn sends_window_update_frame_after_flow_control_window_is_exhausted() {
+use __group::sends_window_update_frame_after_flow_control_window_is_exhausted as test;
+$body
+}
+
+/// Flow control operates on each stream separately: a WINDOW_UPDATE
+/// frame for one stream doesn't let the sender use another stream's
+/// window.
+#[test]
+fn sends_window_update_frame_to_one_of_multiple_stalled_streams() {
+use __group::sends_window_update_frame_to_one_of_multiple_stalled_streams as test;
+$body
+}
+
 /// A sender MUST NOT allow a flow-control window to exceed 2^31-1
 /// octets. If a sender receives a WINDOW_UPDATE that causes a
 /// flow-control window to exceed this maximum, it MUST terminate
@@ -665,6 +880,36 @@ use __group::sends_settings_initial_window_size_with_exceeded_max_window_size_va
 $body
 }
 
+/// When the value of SETTINGS_INITIAL_WINDOW_SIZE changes,
+/// a receiver MUST adjust the size of all stream flow-control
+/// windows that it maintains by the difference between the new
+/// value and the old value.
+#[test]
+fn reduces_settings_initial_window_size_with_multiple_open_streams() {
+use __group::reduces_settings_initial_window_size_with_multiple_open_streams as test;
+$body
+}
+
+/// The flow-control window can go negative after a change to
+/// SETTINGS_INITIAL_WINDOW_SIZE: a WINDOW_UPDATE that brings it
+/// back under 2^31-1 is not an overflow, even if the increment
+/// alone is the maximum.
+#[test]
+fn sends_window_update_frame_with_max_increment_while_window_is_negative() {
+use __group::sends_window_update_frame_with_max_increment_while_window_is_negative as test;
+$body
+}
+
+/// An endpoint MUST treat a change to SETTINGS_INITIAL_WINDOW_SIZE
+/// that causes any flow-control window to exceed the maximum size
+/// as a connection error (Section 5.4.1) of type FLOW_CONTROL_ERROR.
+/// This applies to windows that were grown by WINDOW_UPDATE frames.
+#[test]
+fn sends_settings_initial_window_size_overflowing_open_stream_window() {
+use __group::sends_settings_initial_window_size_overflowing_open_stream_window as test;
+$body
+}
+
 /// The CONTINUATION frame (type=0x9) is used to continue a sequence
 /// of header block fragments (Section 4.3). Any number of
 /// CONTINUATION frames can be sent, as long as the preceding frame
@@ -761,6 +1006,19 @@ use __group::sends_second_headers_frame_without_end_stream as test;
 $body
 }
 
+/// An HTTP request/response exchange fully consumes a single stream. A
+/// request starts with the HEADERS frame that puts the stream into the "open"
+/// state. [...] A request or response [...] consists of [...] optionally, one
+/// HEADERS frame containing the trailer section, if present.
+///
+/// The server is expected to echo the request's trailers back in the
+/// response's.
+#[test]
+fn sends_trailers_after_data() {
+use __group::sends_trailers_after_data as test;
+$body
+}
+
 /// A field name MUST NOT contain characters in the ranges 0x00-0x20, 0x41-0x5a,
 /// or 0x7f-0xff (all ranges inclusive). This specifically excludes all
 /// non-visible ASCII characters, ASCII SP (0x20), and uppercase characters ('A'
@@ -1038,6 +1296,16 @@ use __group::sends_headers_frame_with_duplicate_pseudo_headers as test;
 $body
 }
 
+/// Pseudo-header fields are not HTTP header fields. Endpoints MUST NOT
+/// generate pseudo-header fields other than those defined in this document.
+/// [...] Endpoints MUST treat a request or response that contains undefined or
+/// invalid pseudo-header fields as malformed (Section 8.1.1).
+#[test]
+fn sends_headers_frame_with_undefined_pseudo_header() {
+use __group::sends_headers_frame_with_undefined_pseudo_header as test;
+$body
+}
+
 /// A server SHOULD treat a request as malformed if it contains a Host header
 /// field that identifies an entity that differs from the entity in the
 /// ":authority" pseudo-header field. The values of fields need to be normalized
@@ -1088,6 +1356,18 @@ use __group::sends_headers_frame_without_path as test;
 $body
 }
 
+/// Malformed requests or responses that are detected MUST be treated as a
+/// stream error (Section 5.4.2) of type PROTOCOL_ERROR. [...] For malformed
+/// requests, a server MAY send an HTTP response prior to closing or resetting
+/// the stream.
+///
+/// (the connection must stay usable for other requests)
+#[test]
+fn sends_valid_request_after_malformed_request() {
+use __group::sends_valid_request_after_malformed_request as test;
+$body
+}
+
 #[test]
 fn sends_headers_frame_without_status() {
 use __group::sends_headers_frame_without_status as test;
@@ -1146,5 +1426,16 @@ $body
 }
 }
 }
+  };
+
+  (|$conf: ident| [$($config: ident),+ $(,)?] $body: tt) => {
+$(
+mod $config {
+$crate::tests! {{
+let $conf = super::super::super::$config();
+$body
+}}
 }
+)+
+  };
 }
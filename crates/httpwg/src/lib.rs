@@ -16,6 +16,7 @@ use tracing::{debug, trace};
 
 use crate::rfc9113::default_settings;
 
+pub mod rfc7541;
 pub mod rfc9113;
 
 #[derive(Default)]
@@ -95,8 +96,13 @@ pub struct Conn<IO: IntoHalves> {
     hpack_dec: fluke_hpack::Decoder<'static>,
     /// the peer's settings
     pub settings: Settings,
+    /// the last frames sent and received, for failure messages
+    transcript: VecDeque<String>,
 }
 
+/// How many frames [Conn::transcript] remembers
+const TRANSCRIPT_LEN: usize = 32;
+
 pub enum Ev {
     Frame { frame: Frame, payload: Roll },
     IoError { error: std::io::Error },
@@ -108,41 +114,57 @@ pub enum FrameWaitOutcome {
         wanted: BitFlags<FrameT>,
         last_frame: Option<Frame>,
         waited: Duration,
+        transcript: String,
     },
     Eof {
         wanted: BitFlags<FrameT>,
         last_frame: Option<Frame>,
+        transcript: String,
     },
     IoError {
         wanted: BitFlags<FrameT>,
         last_frame: Option<Frame>,
         error: std::io::Error,
+        transcript: String,
     },
 }
 
 impl FrameWaitOutcome {
     pub fn unwrap(self) -> (Frame, Roll) {
+        match self.into_result() {
+            Ok(res) => res,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// The frame that was waited for, or an error saying what happened
+    /// instead, for tests to propagate with `?`
+    pub fn into_result(self) -> eyre::Result<(Frame, Roll)> {
         match self {
-            FrameWaitOutcome::Success(frame, payload) => (frame, payload),
+            FrameWaitOutcome::Success(frame, payload) => Ok((frame, payload)),
             FrameWaitOutcome::Timeout {
                 wanted,
                 last_frame,
                 waited,
-            } => {
-                panic!(
-                    "Wanted ({wanted:?}), timed out after {waited:?}. Last frame: {last_frame:?}"
-                );
-            }
-            FrameWaitOutcome::Eof { wanted, last_frame } => {
-                panic!("Wanted ({wanted:?}), peer hung up. Last frame: {last_frame:?}");
-            }
+                transcript,
+            } => Err(eyre!(
+                "Wanted ({wanted:?}), timed out after {waited:?}. Last frame: {last_frame:?}{transcript}"
+            )),
+            FrameWaitOutcome::Eof {
+                wanted,
+                last_frame,
+                transcript,
+            } => Err(eyre!(
+                "Wanted ({wanted:?}), peer hung up. Last frame: {last_frame:?}{transcript}"
+            )),
             FrameWaitOutcome::IoError {
                 wanted,
                 last_frame,
                 error,
-            } => {
-                panic!("Wanted ({wanted:?}), got I/O error {error}. Last frame: {last_frame:?}")
-            }
+                transcript,
+            } => Err(eyre!(
+                "Wanted ({wanted:?}), got I/O error {error}. Last frame: {last_frame:?}{transcript}"
+            )),
         }
     }
 }
@@ -331,13 +353,37 @@ impl<IO: IntoHalves> Conn<IO> {
                 max_frame_size: DEFAULT_FRAME_SIZE,
                 ..Default::default()
             },
+            transcript: Default::default(),
+        }
+    }
+
+    /// The last frames sent (`>`) and received (`<`) on this connection,
+    /// oldest first, one per line. Starts with a newline so it can be
+    /// tacked onto an error message, empty if nothing was recorded.
+    pub fn transcript(&self) -> String {
+        let mut out = String::new();
+        if !self.transcript.is_empty() {
+            out.push_str("\nFrame transcript:");
+        }
+        for line in &self.transcript {
+            out.push_str("\n  ");
+            out.push_str(line);
         }
+        out
+    }
+
+    fn record(&mut self, line: String) {
+        if self.transcript.len() == TRANSCRIPT_LEN {
+            self.transcript.pop_front();
+        }
+        self.transcript.push_back(line);
     }
 
     pub async fn write_frame(&mut self, frame: Frame, payload: impl IntoPiece) -> eyre::Result<()> {
         let payload = payload.into_piece(&mut self.scratch)?;
         let frame = frame.with_len(payload.len().try_into().unwrap());
 
+        self.record(format!("> {frame:?}"));
         let header = frame.into_piece(&mut self.scratch)?;
         self.w
             .writev_all_owned(PieceList::single(header).followed_by(payload))
@@ -409,6 +455,7 @@ impl<IO: IntoHalves> Conn<IO> {
     ) -> FrameWaitOutcome {
         let types = types.into();
         let mut last_frame: Option<Frame> = None;
+        let started = Instant::now();
 
         loop {
             match tokio::time::timeout_at(deadline, self.ev_rx.recv()).await {
@@ -416,7 +463,8 @@ impl<IO: IntoHalves> Conn<IO> {
                     return FrameWaitOutcome::Timeout {
                         wanted: types,
                         last_frame,
-                        waited: self.config.timeout,
+                        waited: started.elapsed(),
+                        transcript: self.transcript(),
                     };
                 }
                 Ok(maybe_ev) => match maybe_ev {
@@ -424,10 +472,12 @@ impl<IO: IntoHalves> Conn<IO> {
                         return FrameWaitOutcome::Eof {
                             wanted: types,
                             last_frame,
+                            transcript: self.transcript(),
                         }
                     }
                     Some(ev) => match ev {
                         Ev::Frame { frame, payload } => {
+                            self.record(format!("< {frame:?}"));
                             if types.contains(FrameT::from(frame.frame_type)) {
                                 return FrameWaitOutcome::Success(frame, payload);
                             } else {
@@ -439,6 +489,7 @@ impl<IO: IntoHalves> Conn<IO> {
                                 wanted: types,
                                 last_frame,
                                 error,
+                                transcript: self.transcript(),
                             }
                         }
                     },
@@ -518,8 +569,12 @@ impl<IO: IntoHalves> Conn<IO> {
                     "Expected GOAWAY with one of {codes:?}, but got {error_c:?}"
                 ))
             }
-            FrameWaitOutcome::Timeout { last_frame, .. } => Err(eyre!(
-                "Timed out while waiting for connection error, last frame: ({last_frame:?})"
+            FrameWaitOutcome::Timeout {
+                last_frame,
+                transcript,
+                ..
+            } => Err(eyre!(
+                "Timed out while waiting for connection error, last frame: ({last_frame:?}){transcript}"
             )),
             FrameWaitOutcome::Eof { .. } => {
                 // that's fine
@@ -538,8 +593,12 @@ impl<IO: IntoHalves> Conn<IO> {
                 // that's what we expected!
                 Ok(())
             }
-            FrameWaitOutcome::Timeout { last_frame, .. } => Err(eyre!(
-                "Timed out while waiting for connection close, last frame: ({last_frame:?})"
+            FrameWaitOutcome::Timeout {
+                last_frame,
+                transcript,
+                ..
+            } => Err(eyre!(
+                "Timed out while waiting for connection close, last frame: ({last_frame:?}){transcript}"
             )),
             FrameWaitOutcome::Eof { .. } => {
                 // that's fine
@@ -597,9 +656,13 @@ impl<IO: IntoHalves> Conn<IO> {
                     }
                     _ => panic!("unexpected frame type"),
                 },
-                FrameWaitOutcome::Timeout { last_frame, .. } => {
+                FrameWaitOutcome::Timeout {
+                    last_frame,
+                    transcript,
+                    ..
+                } => {
                     return Err(eyre!(
-                        "Timed out while waiting for stream close frame, last frame: ({:?})",
+                        "Timed out while waiting for stream close frame, last frame: ({:?}){transcript}",
                         last_frame.or(global_last_frame)
                     ));
                 }
@@ -675,8 +738,12 @@ impl<IO: IntoHalves> Conn<IO> {
                     _ => unreachable!(),
                 }
             }
-            FrameWaitOutcome::Timeout { last_frame, .. } => Err(eyre!(
-                "Timed out while waiting for stream error, last frame: ({last_frame:?})"
+            FrameWaitOutcome::Timeout {
+                last_frame,
+                transcript,
+                ..
+            } => Err(eyre!(
+                "Timed out while waiting for stream error, last frame: ({last_frame:?}){transcript}"
             )),
             FrameWaitOutcome::Eof { .. } => {
                 // that's fine
@@ -689,7 +756,109 @@ impl<IO: IntoHalves> Conn<IO> {
         }
     }
 
-    fn common_headers(&self, method: &'static str) -> Headers {
+    /// Waits for a GOAWAY frame with the given error code. Unlike
+    /// [Conn::verify_connection_error], the peer hanging up without
+    /// sending one is a failure.
+    pub async fn verify_goaway(&mut self, code: ErrorC) -> eyre::Result<GoAway> {
+        let (_frame, payload) = match self.wait_for_frame(FrameT::GoAway).await {
+            FrameWaitOutcome::Success(frame, payload) => (frame, payload),
+            outcome => return Err(self.unexpected_outcome("GOAWAY", outcome)),
+        };
+
+        let (_, goaway) = GoAway::parse(payload).finish().unwrap();
+        let actual = error_c(goaway.error_code);
+        if !matches!(actual, Ok(actual) if actual == code) {
+            return Err(eyre!(
+                "Expected GOAWAY with {code:?}, got {actual:?}{}",
+                self.transcript()
+            ));
+        }
+        Ok(goaway)
+    }
+
+    /// Waits for a RST_STREAM frame on `stream_id`, with the given error
+    /// code. Frames for other streams are skipped, a GOAWAY or the peer
+    /// hanging up is a failure.
+    pub async fn verify_stream_reset(
+        &mut self,
+        stream_id: StreamId,
+        code: ErrorC,
+    ) -> eyre::Result<()> {
+        let deadline = Instant::now() + self.config.timeout;
+
+        loop {
+            let (frame, payload) = match self
+                .wait_for_frame_with_deadline(FrameT::RstStream | FrameT::GoAway, deadline)
+                .await
+            {
+                FrameWaitOutcome::Success(frame, payload) => (frame, payload),
+                outcome => return Err(self.unexpected_outcome("RST_STREAM", outcome)),
+            };
+
+            if matches!(frame.frame_type, FrameType::GoAway) {
+                return Err(eyre!(
+                    "Expected RST_STREAM on stream {stream_id}, got GOAWAY{}",
+                    self.transcript()
+                ));
+            }
+            if frame.stream_id != stream_id {
+                continue;
+            }
+
+            let (_, rst_stream) = RstStream::parse(payload).finish().unwrap();
+            let actual = error_c(rst_stream.error_code);
+            if !matches!(actual, Ok(actual) if actual == code) {
+                return Err(eyre!(
+                    "Expected RST_STREAM on stream {stream_id} with {code:?}, got {actual:?}{}",
+                    self.transcript()
+                ));
+            }
+            return Ok(());
+        }
+    }
+
+    /// Makes sure the peer stays quiet (and connected) for `duration`
+    pub async fn expect_no_frames_for(&mut self, duration: Duration) -> eyre::Result<()> {
+        self.expect_no_frames_of(BitFlags::all(), duration).await
+    }
+
+    /// Makes sure the peer sends none of the given frame types (and stays
+    /// connected) for `duration`. Frames of other types are skipped.
+    pub async fn expect_no_frames_of(
+        &mut self,
+        types: impl Into<BitFlags<FrameT>>,
+        duration: Duration,
+    ) -> eyre::Result<()> {
+        let types = types.into();
+        let deadline = Instant::now() + duration;
+        match self.wait_for_frame_with_deadline(types, deadline).await {
+            FrameWaitOutcome::Timeout { .. } => Ok(()),
+            FrameWaitOutcome::Success(frame, _payload) => Err(eyre!(
+                "Expected no {types:?} frames for {duration:?}, got {frame:?}{}",
+                self.transcript()
+            )),
+            outcome => Err(self.unexpected_outcome("no frames", outcome)),
+        }
+    }
+
+    fn unexpected_outcome(&self, wanted: &str, outcome: FrameWaitOutcome) -> eyre::Report {
+        match outcome {
+            FrameWaitOutcome::Success(frame, _payload) => {
+                eyre!("Expected {wanted}, got {frame:?}{}", self.transcript())
+            }
+            FrameWaitOutcome::Timeout {
+                waited, transcript, ..
+            } => eyre!("Expected {wanted}, timed out after {waited:?}{transcript}"),
+            FrameWaitOutcome::Eof { transcript, .. } => {
+                eyre!("Expected {wanted}, peer hung up{transcript}")
+            }
+            FrameWaitOutcome::IoError {
+                error, transcript, ..
+            } => eyre!("Expected {wanted}, got I/O error {error}{transcript}"),
+        }
+    }
+
+    pub fn common_headers(&self, method: &'static str) -> Headers {
         let (scheme, default_port) = if self.config.tls {
             ("https", self.config.port == 443)
         } else {
@@ -794,6 +963,7 @@ impl<IO: IntoHalves> Conn<IO> {
 
         let priority_spec_piece = priority_spec.into_piece(&mut self.scratch)?;
 
+        self.record(format!("> {frame:?}"));
         let header = frame.into_piece(&mut self.scratch)?;
         self.w
             .writev_all_owned(
@@ -851,7 +1021,7 @@ impl<IO: IntoHalves> Conn<IO> {
     ///   `self.config.max_header_len`.
     /// - The total number of headers in the returned `Headers` map is equal to
     ///   `len`.
-    fn dummy_headers(&self, len: usize) -> Headers {
+    pub fn dummy_headers(&self, len: usize) -> Headers {
         let mut headers = Headers::default();
         let dummy = dummy_bytes(self.config.max_header_len);
 
@@ -874,7 +1044,7 @@ impl<IO: IntoHalves> Conn<IO> {
             .await
     }
 
-    async fn write_window_update(
+    pub async fn write_window_update(
         &mut self,
         stream_id: StreamId,
         increment: u32,
@@ -932,6 +1102,14 @@ impl<IO: IntoHalves> Conn<IO> {
     }
 }
 
+/// Maps an error code from a GOAWAY or RST_STREAM frame, keeping unknown
+/// codes around for error messages
+fn error_c(code: ErrorCode) -> Result<ErrorC, ErrorCode> {
+    KnownErrorCode::try_from(code)
+        .map(Into::into)
+        .map_err(|_| code)
+}
+
 /// Parameters for tests
 pub struct Config {
     /// which host to connect to
@@ -0,0 +1,43 @@
+//! Section 2: Compression Process Overview
+
+use fluke_buffet::IntoHalves;
+use fluke_h2_parse::StreamId;
+
+use super::{string_literal, write_raw_request};
+use crate::{Conn, ErrorC};
+
+//---- Section 2.3.3: Index Address Space
+
+/// Indices strictly greater than the sum of the lengths of both
+/// tables MUST be treated as a decoding error.
+pub async fn sends_indexed_header_field_with_out_of_bounds_index<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // index 191: the static table has 61 entries, the dynamic table is empty
+    write_raw_request(&mut conn, StreamId(1), &[], &[0xff, 0x40]).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
+
+/// Indices strictly greater than the sum of the lengths of both
+/// tables MUST be treated as a decoding error.
+pub async fn sends_literal_header_field_with_out_of_bounds_name_index<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // literal without indexing, name from index 70
+    let mut field = vec![0x0f, 70 - 15];
+    field.extend(string_literal("value"));
+    write_raw_request(&mut conn, StreamId(1), &[], &field).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
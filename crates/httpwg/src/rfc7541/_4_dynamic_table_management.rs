@@ -0,0 +1,73 @@
+//! Section 4: Dynamic Table Management
+
+use fluke_buffet::IntoHalves;
+use fluke_h2_parse::StreamId;
+
+use super::{string_literal, write_raw_request};
+use crate::{Conn, ErrorC};
+
+//---- Section 4.2: Maximum Table Size
+
+/// This dynamic table size update MUST occur at the beginning of
+/// the first header block following the change to the dynamic
+/// table size.
+pub async fn sends_dynamic_table_size_update_after_header_field<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // the pseudo-headers, then a size update to 0, then `user-agent: x`
+    let mut suffix = vec![0x20, 0x0f, 58 - 15];
+    suffix.extend(string_literal("x"));
+    write_raw_request(&mut conn, StreamId(1), &[], &suffix).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
+
+/// Multiple updates to the maximum table size can occur between the
+/// transmission of two header blocks. In the case that this size is
+/// changed more than once in this interval, the smallest maximum
+/// table size that occurs in that interval MUST be signaled in a
+/// dynamic table size update. The final maximum size is always
+/// signaled, resulting in at most two dynamic table size updates.
+pub async fn sends_multiple_dynamic_table_size_updates<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // size updates to 0, then 4096
+    write_raw_request(&mut conn, StreamId(1), &[0x20, 0x3f, 0xe1, 0x1f], &[]).await?;
+
+    conn.verify_headers_frame(StreamId(1)).await?;
+
+    Ok(())
+}
+
+//---- Section 4.4: Entry Eviction When Adding New Entries
+
+/// A change in the maximum size of the dynamic table can cause
+/// entries to be evicted: referencing them afterwards is an
+/// out-of-bounds index, which MUST be treated as a decoding error.
+pub async fn sends_dynamic_table_size_update_evicting_entries<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // `x-fluke: evicted`, with incremental indexing: it becomes index 62
+    let mut field = vec![0x40];
+    field.extend(string_literal("x-fluke"));
+    field.extend(string_literal("evicted"));
+    write_raw_request(&mut conn, StreamId(1), &[], &field).await?;
+    conn.verify_headers_frame(StreamId(1)).await?;
+
+    // a size update to 0 empties the dynamic table, then index 62
+    write_raw_request(&mut conn, StreamId(3), &[0x20], &[0xbe]).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! Section 5: Primitive Type Representations
+
+use fluke_buffet::IntoHalves;
+use fluke_h2_parse::StreamId;
+
+use super::{string_literal, write_raw_request};
+use crate::{Conn, ErrorC};
+
+//---- Section 5.1: Integer Representation
+
+/// Integer encodings that exceed implementation limits -- in value
+/// or octet length -- MUST be treated as decoding errors.
+pub async fn sends_indexed_header_field_with_integer_overflow<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // an index with way too many continuation octets
+    let mut field = vec![0xff; 10];
+    field.push(0x7f);
+    write_raw_request(&mut conn, StreamId(1), &[], &field).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
+
+//---- Section 5.2: String Literal Representation
+
+/// A padding strictly longer than 7 bits MUST be treated as a
+/// decoding error.
+pub async fn sends_huffman_encoded_string_with_padding_longer_than_7_bits<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // literal name "a" (00011), followed by 11 bits of padding
+    let mut field = vec![0x00, 0x82, 0x1f, 0xff];
+    field.extend(string_literal("value"));
+    write_raw_request(&mut conn, StreamId(1), &[], &field).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
+
+/// A padding not corresponding to the most significant bits of the
+/// code for the EOS symbol MUST be treated as a decoding error.
+pub async fn sends_huffman_encoded_string_with_padding_not_matching_eos<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // literal name "a" (00011), padded with zeroes
+    let mut field = vec![0x00, 0x81, 0x18];
+    field.extend(string_literal("value"));
+    write_raw_request(&mut conn, StreamId(1), &[], &field).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
+
+/// A Huffman-encoded string literal containing the EOS symbol MUST be
+/// treated as a decoding error.
+pub async fn sends_huffman_encoded_string_with_eos_symbol<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // literal name made of the EOS symbol (30 bits set), then padding
+    let mut field = vec![0x00, 0x84, 0xff, 0xff, 0xff, 0xff];
+    field.extend(string_literal("value"));
+    write_raw_request(&mut conn, StreamId(1), &[], &field).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! Section 6: Binary Format
+
+use fluke_buffet::IntoHalves;
+use fluke_h2_parse::StreamId;
+
+use super::{string_literal, write_raw_request};
+use crate::{Conn, ErrorC};
+
+//---- Section 6.1: Indexed Header Field Representation
+
+/// The index value of 0 is not used. It MUST be treated as a
+/// decoding error if found in an indexed header field
+/// representation.
+pub async fn sends_indexed_header_field_with_zero_index<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    write_raw_request(&mut conn, StreamId(1), &[], &[0x80]).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
+
+//---- Section 6.2.3: Literal Header Field Never Indexed
+
+/// A literal header field never-indexed representation results in
+/// appending a header field to the decoded header list without
+/// altering the dynamic table.
+pub async fn sends_literal_header_field_never_indexed<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // `x-secret: hunter2`, never indexed
+    let mut field = vec![0x10];
+    field.extend(string_literal("x-secret"));
+    field.extend(string_literal("hunter2"));
+    write_raw_request(&mut conn, StreamId(1), &[], &field).await?;
+    conn.verify_headers_frame(StreamId(1)).await?;
+
+    // so there's nothing at index 62
+    write_raw_request(&mut conn, StreamId(3), &[], &[0xbe]).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
+
+//---- Section 6.3: Dynamic Table Size Update
+
+/// The new maximum size MUST be lower than or equal to the limit
+/// determined by the protocol using HPACK. A value that exceeds this
+/// limit MUST be treated as a decoding error.
+pub async fn sends_dynamic_table_size_update_larger_than_settings_header_table_size<
+    IO: IntoHalves,
+>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // 4097, one more than the default SETTINGS_HEADER_TABLE_SIZE
+    write_raw_request(&mut conn, StreamId(1), &[0x3f, 0xe2, 0x1f], &[]).await?;
+
+    conn.verify_connection_error(ErrorC::CompressionError)
+        .await?;
+
+    Ok(())
+}
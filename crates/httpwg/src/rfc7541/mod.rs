@@ -0,0 +1,46 @@
+//! RFC 7541 defines HPACK, a compression format for efficiently
+//! representing HTTP fields, to be used in HTTP/2.
+//!
+//! These tests send header blocks encoded by hand, to exercise the corner
+//! cases of the peer's decoder: any decoding error must be treated as a
+//! connection error of type COMPRESSION_ERROR (RFC 9113, section 4.3).
+//!
+//! cf. <https://httpwg.org/specs/rfc7541.html>
+
+use fluke_buffet::IntoHalves;
+use fluke_h2_parse::{HeadersFlags, StreamId};
+
+use crate::Conn;
+
+pub mod _2_compression_process_overview;
+pub mod _4_dynamic_table_management;
+pub mod _5_primitive_type_representations;
+pub mod _6_binary_format;
+
+/// `:method: GET`, `:scheme: http` and `:path: /`, all indexed from the
+/// static table
+const GET_ROOT: &[u8] = &[0x82, 0x86, 0x84];
+
+/// Sends a GET request for `/` on `stream_id`, with `prefix` and `suffix`
+/// pasted as-is before and after the pseudo-headers in the header block.
+async fn write_raw_request<IO: IntoHalves>(
+    conn: &mut Conn<IO>,
+    stream_id: StreamId,
+    prefix: &[u8],
+    suffix: &[u8],
+) -> eyre::Result<()> {
+    let block = [prefix, GET_ROOT, suffix].concat();
+    conn.write_headers(
+        stream_id,
+        HeadersFlags::EndStream | HeadersFlags::EndHeaders,
+        block.into(),
+    )
+    .await
+}
+
+/// Encodes `s` as a string literal without Huffman encoding
+fn string_literal(s: &str) -> Vec<u8> {
+    let mut out = fluke_hpack::encoder::encode_integer(s.len(), 7);
+    out.extend_from_slice(s.as_bytes());
+    out
+}
@@ -3,10 +3,10 @@
 use enumflags2::BitFlags;
 use fluke_buffet::IntoHalves;
 use fluke_h2_parse::{
-    ContinuationFlags, EncodedFrameType, FrameType, HeadersFlags, Setting, StreamId,
+    ContinuationFlags, DataFlags, EncodedFrameType, FrameType, HeadersFlags, Setting, StreamId,
 };
 
-use crate::{dummy_bytes, Conn, ErrorC};
+use crate::{dummy_bytes, Conn, ErrorC, FrameT};
 
 //---- Section 5.1: Stream States
 
@@ -263,6 +263,111 @@ pub async fn closed_sends_continuation_frame<IO: IntoHalves>(
     Ok(())
 }
 
+// The following tests keep several streams open at once, each in its own
+// state, and check that frames on one stream don't affect the others.
+
+/// Each stream has its own state: a stream's request is complete once its
+/// own DATA frame carries the END_STREAM flag, regardless of how frames
+/// for other streams are interleaved with it.
+pub async fn interleaves_frames_across_multiple_streams<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    let streams = [StreamId(1), StreamId(3), StreamId(5)];
+    conn.handshake().await?;
+
+    // Skip this test case when the peer doesn't allow three streams at once.
+    if matches!(conn.settings.max_concurrent_streams, Some(max) if max < 3) {
+        return Ok(());
+    }
+
+    for stream_id in streams {
+        conn.encode_and_write_headers(
+            stream_id,
+            HeadersFlags::EndHeaders,
+            &conn.common_headers("POST"),
+        )
+        .await?;
+    }
+
+    conn.write_data(StreamId(3), false, dummy_bytes(16)).await?;
+    conn.write_data(StreamId(1), false, dummy_bytes(16)).await?;
+    conn.write_window_update(StreamId(5), 1).await?;
+    conn.write_data(StreamId(5), false, dummy_bytes(16)).await?;
+    conn.write_data(StreamId(1), true, dummy_bytes(16)).await?;
+    conn.write_data(StreamId(5), true, dummy_bytes(16)).await?;
+    conn.write_data(StreamId(3), true, dummy_bytes(16)).await?;
+
+    verify_streams_close(&mut conn, &streams).await?;
+    conn.verify_connection_still_alive().await?;
+
+    Ok(())
+}
+
+/// An endpoint that sends a RST_STREAM frame on a stream moves it to the
+/// "closed" state: the other streams are unaffected.
+pub async fn resets_one_of_multiple_open_streams<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // Skip this test case when the peer doesn't allow three streams at once.
+    if matches!(conn.settings.max_concurrent_streams, Some(max) if max < 3) {
+        return Ok(());
+    }
+
+    for stream_id in [StreamId(1), StreamId(3), StreamId(5)] {
+        conn.encode_and_write_headers(
+            stream_id,
+            HeadersFlags::EndHeaders,
+            &conn.common_headers("POST"),
+        )
+        .await?;
+    }
+
+    conn.write_rst_stream(StreamId(3), ErrorC::Cancel).await?;
+    conn.write_data(StreamId(5), true, dummy_bytes(16)).await?;
+    conn.write_data(StreamId(1), true, dummy_bytes(16)).await?;
+
+    verify_streams_close(&mut conn, &[StreamId(1), StreamId(5)]).await?;
+    conn.verify_connection_still_alive().await?;
+
+    Ok(())
+}
+
+/// If an endpoint receives additional frames, other than
+/// WINDOW_UPDATE, PRIORITY, or RST_STREAM, for a stream that is in
+/// the "half-closed (remote)" state, it MUST respond with a stream
+/// error (Section 5.4.2) of type STREAM_CLOSED. Other streams stay
+/// usable.
+pub async fn half_closed_remote_sends_data_frame_among_open_streams<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // keep the peer from completing its responses
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 0)])
+        .await?;
+
+    // stream 1 is half-closed (remote), stream 3 is open
+    conn.send_empty_post_to_root(StreamId(1)).await?;
+    conn.encode_and_write_headers(
+        StreamId(3),
+        HeadersFlags::EndHeaders,
+        &conn.common_headers("POST"),
+    )
+    .await?;
+
+    conn.write_data(StreamId(1), true, dummy_bytes(16)).await?;
+    conn.verify_stream_error(ErrorC::StreamClosed).await?;
+
+    conn.write_data(StreamId(3), true, dummy_bytes(16)).await?;
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 65535)])
+        .await?;
+    verify_streams_close(&mut conn, &[StreamId(3)]).await?;
+
+    Ok(())
+}
+
 //--- Section 5.1.1: Stream Identifiers
 
 /// An endpoint that receives an unexpected stream identifier
@@ -321,6 +426,59 @@ pub async fn exceeds_concurrent_stream_limit<IO: IntoHalves>(
     Ok(())
 }
 
+/// Streams in the "closed" state don't count toward the limit: once
+/// one closes, a new stream can be opened in its place, even after a
+/// stream was refused for exceeding the limit.
+pub async fn opens_stream_after_another_closes_at_concurrent_stream_limit<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    // Skip this test case when SETTINGS_MAX_CONCURRENT_STREAMS is unlimited.
+    let max_streams = match conn.settings.max_concurrent_streams {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+
+    // Set INITIAL_WINDOW_SIZE to zero to prevent the peer from closing the stream.
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 0)])
+        .await?;
+
+    for i in 0..max_streams {
+        conn.send_empty_post_to_root(StreamId(1 + i * 2)).await?;
+    }
+
+    // one too many
+    let refused = StreamId(1 + max_streams * 2);
+    conn.send_empty_post_to_root(refused).await?;
+    conn.verify_stream_error(ErrorC::ProtocolError | ErrorC::RefusedStream)
+        .await?;
+
+    // make room, then try again
+    conn.write_rst_stream(StreamId(1), ErrorC::Cancel).await?;
+    let accepted = StreamId(refused.0 + 2);
+    conn.send_empty_post_to_root(accepted).await?;
+
+    // let every open stream send a little: the new one must be among them
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 1)])
+        .await?;
+    loop {
+        let (frame, _payload) = conn
+            .wait_for_frame(FrameT::Data | FrameT::RstStream)
+            .await
+            .unwrap();
+        if frame.stream_id != accepted {
+            continue;
+        }
+        match frame.frame_type {
+            FrameType::Data(_) => break,
+            _ => return Err(eyre::eyre!("stream {accepted} was reset")),
+        }
+    }
+
+    Ok(())
+}
+
 // Note: In RFC9113, Section 5.3 mostly describes how prioritization in HTTP/2
 // was a failure, and is now deprecated. RFC9218 describes another scheme, cf.
 // https://www.rfc-editor.org/rfc/rfc9218.html
@@ -394,3 +552,32 @@ pub async fn unknown_extension_frame_in_header_block<IO: IntoHalves>(
 
     Ok(())
 }
+
+/// Waits until every stream of `stream_ids` has ended, in any order. A
+/// RST_STREAM frame for any stream is an error.
+async fn verify_streams_close<IO: IntoHalves>(
+    conn: &mut Conn<IO>,
+    stream_ids: &[StreamId],
+) -> eyre::Result<()> {
+    let mut pending = stream_ids.to_vec();
+    while !pending.is_empty() {
+        let (frame, _payload) = conn
+            .wait_for_frame(FrameT::Data | FrameT::Headers | FrameT::RstStream)
+            .await
+            .unwrap();
+        let end_stream = match frame.frame_type {
+            FrameType::Data(flags) => flags.contains(DataFlags::EndStream),
+            FrameType::Headers(flags) => flags.contains(HeadersFlags::EndStream),
+            _ => {
+                return Err(eyre::eyre!(
+                    "stream {} was reset while waiting for {pending:?} to close",
+                    frame.stream_id
+                ))
+            }
+        };
+        if end_stream {
+            pending.retain(|&id| id != frame.stream_id);
+        }
+    }
+    Ok(())
+}
@@ -1,13 +1,19 @@
 //! Section 6: Frame Definitions
 
+use std::time::Duration;
+
 use enumflags2::BitFlags;
 use fluke_buffet::{IntoHalves, Piece};
 use fluke_h2_parse::{
-    ContinuationFlags, Frame, FrameType, GoAway, HeadersFlags, IntoPiece, KnownErrorCode,
-    PrioritySpec, Setting, SettingPairs, SettingsFlags, StreamId,
+    ContinuationFlags, DataFlags, Frame, FrameType, GoAway, HeadersFlags, IntoPiece,
+    KnownErrorCode, PrioritySpec, Setting, SettingPairs, SettingsFlags, StreamId,
 };
 
-use crate::{dummy_bytes, Conn, ErrorC, FrameT};
+use crate::{
+    dummy_bytes,
+    rfc9113::{DEFAULT_FRAME_SIZE, DEFAULT_WINDOW_SIZE},
+    Conn, ErrorC, FrameT,
+};
 
 //---- Section 6.1: DATA
 
@@ -81,6 +87,90 @@ pub async fn sends_data_frame_with_invalid_pad_length<IO: IntoHalves>(
     Ok(())
 }
 
+/// A padded DATA frame: the pad length, `data`, then `pad_len` zeroes
+fn padded_data(data: &[u8], pad_len: u8) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + data.len() + pad_len as usize);
+    payload.push(pad_len);
+    payload.extend_from_slice(data);
+    payload.resize(payload.len() + pad_len as usize, 0);
+    payload
+}
+
+/// The DATA frame can include padding: only the data that follows the
+/// pad length, and precedes the padding, is part of the request body.
+pub async fn sends_data_frame_with_padding<IO: IntoHalves>(mut conn: Conn<IO>) -> eyre::Result<()> {
+    let stream_id = StreamId(1);
+
+    conn.handshake().await?;
+
+    let mut headers = conn.common_headers("POST");
+    headers.append("content-length", "4");
+    let block_fragment = conn.encode_headers(&headers)?;
+
+    conn.write_headers(stream_id, HeadersFlags::EndHeaders, block_fragment)
+        .await?;
+
+    // padding that takes up the whole frame is fine
+    conn.write_frame(
+        Frame::new(FrameType::Data(DataFlags::Padded.into()), stream_id),
+        padded_data(b"", 8),
+    )
+    .await?;
+    conn.write_frame(
+        Frame::new(
+            FrameType::Data(DataFlags::Padded | DataFlags::EndStream),
+            stream_id,
+        ),
+        padded_data(b"test", 6),
+    )
+    .await?;
+
+    // if padding was taken for data, the content-length wouldn't match
+    conn.verify_headers_frame(stream_id).await?;
+
+    Ok(())
+}
+
+/// The entire DATA frame payload is included in flow control,
+/// including the Pad Length and Padding fields if present.
+pub async fn sends_padded_data_frames_counting_against_flow_control<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    let stream_id = StreamId(1);
+
+    conn.handshake().await?;
+
+    let block_fragment = conn.encode_headers(&conn.common_headers("POST"))?;
+    conn.write_headers(stream_id, HeadersFlags::EndHeaders, block_fragment)
+        .await?;
+
+    // most of the connection window, in frames that are each
+    // 255 bytes of padding and a pad length away from being all data
+    let frame_len = DEFAULT_FRAME_SIZE as usize;
+    let data = dummy_bytes(frame_len - 256);
+    for _ in 0..3 {
+        conn.write_frame(
+            Frame::new(FrameType::Data(DataFlags::Padded.into()), stream_id),
+            padded_data(&data, 255),
+        )
+        .await?;
+    }
+
+    let (_, payload) = conn
+        .wait_for_frame(FrameT::WindowUpdate)
+        .await
+        .into_result()?;
+    let increment = u32::from_be_bytes(payload[..4].try_into().unwrap()) & 0x7fff_ffff;
+    // giving back only the data would make it a multiple of `data.len()`
+    assert_eq!(
+        increment as usize % frame_len,
+        0,
+        "WINDOW_UPDATE increment {increment} doesn't account for whole frames"
+    );
+
+    Ok(())
+}
+
 //---- Section 6.2: HEADERS
 
 /// HEADERS frames MUST be associated with a stream. If a HEADERS
@@ -682,6 +772,79 @@ pub async fn sends_settings_frame_to_set_initial_window_size_to_1_and_sends_head
     Ok(())
 }
 
+/// A sender MUST NOT send a flow-controlled frame with a length
+/// that exceeds the space available in either of the flow-control
+/// windows advertised by the receiver. Once the receiver sends
+/// WINDOW_UPDATE frames, the sender may resume.
+pub async fn sends_window_update_frame_after_flow_control_window_is_exhausted<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    let stream_id = StreamId(1);
+
+    conn.handshake().await?;
+
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 0)])
+        .await?;
+    conn.send_empty_post_to_root(stream_id).await?;
+
+    // the window is empty: the peer must stall
+    conn.expect_no_frames_of(
+        FrameT::Data | FrameT::WindowUpdate,
+        Duration::from_millis(100),
+    )
+    .await?;
+
+    conn.write_window_update(stream_id, 5).await?;
+    let (frame, _payload) = conn.wait_for_frame(FrameT::Data).await.into_result()?;
+    assert_eq!(frame.len, 5);
+
+    // and stall again once it's used up
+    conn.expect_no_frames_of(
+        FrameT::Data | FrameT::WindowUpdate,
+        Duration::from_millis(100),
+    )
+    .await?;
+
+    conn.write_window_update(stream_id, 1).await?;
+    let (frame, _payload) = conn.wait_for_frame(FrameT::Data).await.into_result()?;
+    assert_eq!(frame.len, 1);
+
+    Ok(())
+}
+
+/// Flow control operates on each stream separately: a WINDOW_UPDATE
+/// frame for one stream doesn't let the sender use another stream's
+/// window.
+pub async fn sends_window_update_frame_to_one_of_multiple_stalled_streams<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 0)])
+        .await?;
+    conn.send_empty_post_to_root(StreamId(1)).await?;
+    conn.send_empty_post_to_root(StreamId(3)).await?;
+
+    conn.write_window_update(StreamId(3), 5).await?;
+    let (frame, _payload) = conn.wait_for_frame(FrameT::Data).await.into_result()?;
+    assert_eq!(frame.stream_id, StreamId(3));
+    assert_eq!(frame.len, 5);
+
+    // stream 1 is still stalled, although its HEADERS frame may go out
+    conn.expect_no_frames_of(
+        FrameT::Data | FrameT::WindowUpdate,
+        Duration::from_millis(100),
+    )
+    .await?;
+
+    conn.write_window_update(StreamId(1), 5).await?;
+    let (frame, _payload) = conn.wait_for_frame(FrameT::Data).await.into_result()?;
+    assert_eq!(frame.stream_id, StreamId(1));
+    assert_eq!(frame.len, 5);
+
+    Ok(())
+}
+
 /// A sender MUST NOT allow a flow-control window to exceed 2^31-1
 /// octets. If a sender receives a WINDOW_UPDATE that causes a
 /// flow-control window to exceed this maximum, it MUST terminate
@@ -696,7 +859,9 @@ pub async fn sends_multiple_window_update_frames_increasing_flow_control_window_
 ) -> eyre::Result<()> {
     conn.handshake().await?;
 
-    conn.write_window_update(StreamId::CONNECTION, (1 << 31) - 1)
+    // the connection window starts at 65535 (SETTINGS don't apply to it),
+    // bring it to exactly 2^31-1, then over
+    conn.write_window_update(StreamId::CONNECTION, (1 << 31) - 1 - 65535)
         .await?;
     conn.write_window_update(StreamId::CONNECTION, (1 << 31) - 1)
         .await?;
@@ -830,7 +995,121 @@ pub async fn sends_settings_initial_window_size_with_exceeded_max_window_size_va
     Ok(())
 }
 
-// Note: 6.9.3 (reducing the stream window size) is really tricky to test.
+/// When the value of SETTINGS_INITIAL_WINDOW_SIZE changes,
+/// a receiver MUST adjust the size of all stream flow-control
+/// windows that it maintains by the difference between the new
+/// value and the old value.
+pub async fn reduces_settings_initial_window_size_with_multiple_open_streams<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 0)])
+        .await?;
+    for stream_id in [StreamId(1), StreamId(3)] {
+        conn.send_empty_post_to_root(stream_id).await?;
+    }
+
+    // both streams get to send 5 bytes
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 5)])
+        .await?;
+    let mut streams = vec![];
+    for _ in 0..2 {
+        let (frame, _payload) = conn.wait_for_frame(FrameT::Data).await.into_result()?;
+        assert_eq!(frame.len, 5);
+        streams.push(frame.stream_id);
+    }
+    streams.sort();
+    assert_eq!(streams, [StreamId(1), StreamId(3)]);
+
+    // both windows go from 0 to -2
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 3)])
+        .await?;
+
+    // back to 0 for stream 1, which still can't send anything
+    conn.write_window_update(StreamId(1), 2).await?;
+    conn.expect_no_frames_of(
+        FrameT::Data | FrameT::WindowUpdate,
+        Duration::from_millis(100),
+    )
+    .await?;
+
+    // back to 1 for stream 3
+    conn.write_window_update(StreamId(3), 3).await?;
+    let (frame, _payload) = conn.wait_for_frame(FrameT::Data).await.into_result()?;
+    assert_eq!(frame.stream_id, StreamId(3));
+    assert_eq!(frame.len, 1);
+
+    Ok(())
+}
+
+/// The flow-control window can go negative after a change to
+/// SETTINGS_INITIAL_WINDOW_SIZE: a WINDOW_UPDATE that brings it
+/// back under 2^31-1 is not an overflow, even if the increment
+/// alone is the maximum.
+pub async fn sends_window_update_frame_with_max_increment_while_window_is_negative<
+    IO: IntoHalves,
+>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    let stream_id = StreamId(1);
+
+    conn.handshake().await?;
+
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 3)])
+        .await?;
+    conn.send_empty_post_to_root(stream_id).await?;
+
+    let (frame, _payload) = conn.wait_for_frame(FrameT::Data).await.into_result()?;
+    assert_eq!(frame.len, 3);
+
+    // the window goes from 0 to -3
+    conn.write_and_ack_settings(&[(Setting::InitialWindowSize, 0)])
+        .await?;
+
+    // then to 2^31-4
+    conn.write_window_update(stream_id, (1 << 31) - 1).await?;
+
+    // the rest of the response comes through
+    conn.verify_stream_close(stream_id).await?;
+    conn.verify_connection_still_alive().await?;
+
+    Ok(())
+}
+
+/// An endpoint MUST treat a change to SETTINGS_INITIAL_WINDOW_SIZE
+/// that causes any flow-control window to exceed the maximum size
+/// as a connection error (Section 5.4.1) of type FLOW_CONTROL_ERROR.
+/// This applies to windows that were grown by WINDOW_UPDATE frames.
+pub async fn sends_settings_initial_window_size_overflowing_open_stream_window<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    let stream_id = StreamId(1);
+
+    conn.handshake().await?;
+
+    // no END_STREAM: the peer waits for the request body before it
+    // responds, so the stream window stays untouched
+    conn.encode_and_write_headers(
+        stream_id,
+        HeadersFlags::EndHeaders,
+        &conn.common_headers("POST"),
+    )
+    .await?;
+
+    // bring the stream window to exactly 2^31-1
+    conn.write_window_update(stream_id, (1 << 31) - 1 - DEFAULT_WINDOW_SIZE)
+        .await?;
+
+    // then push it one over with a settings change
+    conn.write_settings(&[(Setting::InitialWindowSize, DEFAULT_WINDOW_SIZE + 1)])
+        .await?;
+
+    conn.verify_connection_error(ErrorC::FlowControlError)
+        .await?;
+
+    Ok(())
+}
 
 //---- Section 6.10: CONTINUATION
 
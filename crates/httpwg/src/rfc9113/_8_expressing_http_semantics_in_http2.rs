@@ -35,6 +35,64 @@ pub async fn sends_second_headers_frame_without_end_stream<IO: IntoHalves>(
     Ok(())
 }
 
+/// An HTTP request/response exchange fully consumes a single stream. A
+/// request starts with the HEADERS frame that puts the stream into the "open"
+/// state. [...] A request or response [...] consists of [...] optionally, one
+/// HEADERS frame containing the trailer section, if present.
+///
+/// The server is expected to echo the request's trailers back in the
+/// response's.
+pub async fn sends_trailers_after_data<IO: IntoHalves>(mut conn: Conn<IO>) -> eyre::Result<()> {
+    let stream_id = StreamId(1);
+    conn.handshake().await?;
+
+    let headers_fragment = conn.encode_headers(&conn.common_headers("POST"))?;
+    conn.write_headers(stream_id, HeadersFlags::EndHeaders, headers_fragment)
+        .await?;
+    conn.write_data(stream_id, false, b"test").await?;
+
+    let mut trailers = Headers::default();
+    trailers.append("x-test", "ok");
+    let trailers_fragment = conn.encode_headers(&trailers)?;
+    conn.write_headers(
+        stream_id,
+        HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+        trailers_fragment,
+    )
+    .await?;
+
+    // wait for headers frame, expect 200 status
+    let (frame, payload) = conn.wait_for_frame(FrameT::Headers).await.unwrap();
+    assert_eq!(frame.stream_id, stream_id, "unexpected stream ID");
+    assert!(frame.is_end_headers(), "this test makes that assumption");
+    let headers = conn.decode_headers(payload.into())?;
+    let status = headers.get_first(&":status".into()).unwrap();
+    let status = std::str::from_utf8(status)?;
+    let status = status.parse::<u16>().unwrap();
+    assert_eq!(status, 200);
+    assert!(
+        !frame.is_end_stream(),
+        "the response trailers should end the stream"
+    );
+
+    // the body comes first, then the trailers
+    let (frame, payload) = conn.wait_for_frame(FrameT::Headers).await.unwrap();
+    assert_eq!(frame.stream_id, stream_id, "unexpected stream ID");
+    assert!(frame.is_end_headers(), "this test makes that assumption");
+    assert!(frame.is_end_stream(), "trailers should end the stream");
+    let trailers = conn.decode_headers(payload.into())?;
+    assert!(
+        trailers.get_first(&":status".into()).is_none(),
+        "trailers must not carry pseudo-headers"
+    );
+    assert_eq!(
+        trailers.get_first(&"x-test".into()).map(|v| &v[..]),
+        Some(&b"ok"[..])
+    );
+
+    Ok(())
+}
+
 //--- Section 8.2.1: Field Validity
 
 /// A field name MUST NOT contain characters in the ranges 0x00-0x20, 0x41-0x5a,
@@ -494,6 +552,23 @@ pub async fn sends_headers_frame_with_duplicate_pseudo_headers<IO: IntoHalves>(
     Ok(())
 }
 
+/// Pseudo-header fields are not HTTP header fields. Endpoints MUST NOT
+/// generate pseudo-header fields other than those defined in this document.
+/// [...] Endpoints MUST treat a request or response that contains undefined or
+/// invalid pseudo-header fields as malformed (Section 8.1.1).
+pub async fn sends_headers_frame_with_undefined_pseudo_header<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    let mut headers = conn.common_headers("POST");
+    headers.append(":undefined", "oh no");
+    conn.send_req_and_expect_status(StreamId(1), &headers, 400)
+        .await?;
+
+    Ok(())
+}
+
 /// A server SHOULD treat a request as malformed if it contains a Host header
 /// field that identifies an entity that differs from the entity in the
 /// ":authority" pseudo-header field. The values of fields need to be normalized
@@ -590,6 +665,29 @@ pub async fn sends_headers_frame_without_path<IO: IntoHalves>(
     Ok(())
 }
 
+/// Malformed requests or responses that are detected MUST be treated as a
+/// stream error (Section 5.4.2) of type PROTOCOL_ERROR. [...] For malformed
+/// requests, a server MAY send an HTTP response prior to closing or resetting
+/// the stream.
+///
+/// (the connection must stay usable for other requests)
+pub async fn sends_valid_request_after_malformed_request<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    let mut headers = conn.common_headers("POST");
+    headers.remove(&":method".into());
+    conn.send_req_and_expect_status(StreamId(1), &headers, 400)
+        .await?;
+
+    let headers = conn.common_headers("POST");
+    conn.send_req_and_expect_status(StreamId(3), &headers, 200)
+        .await?;
+
+    Ok(())
+}
+
 //---- Section 8.3.2: Response Pseudo-Header Fields
 
 pub async fn sends_headers_frame_without_status<IO: IntoHalves>(